@@ -0,0 +1,125 @@
+//! Derive macros for eventful. Use them through eventful's "derive" feature, i.e. `eventful::sqs::SqsEvent`.
+//!
+//! # Examples:
+//! (not compiled here, as this crate can't depend on eventful; the same example is compiled with eventful::sqs::SqsEvent)
+//! ```ignore
+//! #[derive(Serialize, Deserialize, SqsEvent)]
+//! #[eventful(queue_env = "ORDERS_QUEUE_URL", group_key = "customer_id")]
+//! struct OrderPlaced {
+//!     customer_id: String,
+//!     total_cents: u64,
+//! }
+//! ```
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitStr};
+
+
+/// Implement eventful::sqs::Event. Exactly one of these says where the queue is:
+/// - `#[eventful(queue_env = "ORDERS_QUEUE_URL")]` sets queue_env_var(), so the URL is read from the env var the first time
+///   it is needed, validated, then cached
+/// - `#[eventful(queue_url = "https://...")]` uses a literal URL
+///
+/// and `#[eventful(group_key = "field_name")]` makes group_id() the field's value (via Display), for FIFO queues.
+/// With queue_env, try_queue_url() returns an EventfulError::Config naming the variable if it is unset or not a queue URL
+/// (and the deprecated queue_url() is empty)
+#[proc_macro_derive(SqsEvent, attributes(eventful))]
+pub fn derive_sqs_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match sqs_event(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+
+enum Queue {
+    Env(LitStr),
+    Url(LitStr),
+}
+
+
+fn sqs_event(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut queue: Option<Queue> = None;
+    let mut group_key: Option<LitStr> = None;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("eventful")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("queue_env") || meta.path.is_ident("queue_url") {
+                if queue.is_some() {
+                    return Err(meta.error("set only one of queue_env and queue_url"))
+                }
+                let value: LitStr = meta.value()?.parse()?;
+                if value.value().is_empty() {
+                    return Err(syn::Error::new(value.span(), "must not be empty"))
+                }
+                queue = Some(match meta.path.is_ident("queue_env") {
+                    true => Queue::Env(value),
+                    false => Queue::Url(value),
+                });
+                Ok(())
+            } else if meta.path.is_ident("group_key") {
+                if group_key.is_some() {
+                    return Err(meta.error("group_key is set more than once"))
+                }
+                group_key = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected queue_env, queue_url, or group_key"))
+            }
+        })?;
+    }
+    let queue = queue.ok_or_else(|| syn::Error::new(
+        Span::call_site(),
+        "SqsEvent needs #[eventful(queue_env = \"VAR\")] or #[eventful(queue_url = \"...\")]",
+    ))?;
+
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let queue_fns = match queue {
+        Queue::Url(url) => quote! {
+            fn queue_url() -> &'static str {
+                #url
+            }
+        },
+        Queue::Env(var) => quote! {
+            fn queue_env_var() -> Option<&'static str> {
+                Some(#var)
+            }
+        },
+    };
+    let group_fn = match group_key {
+        Some(key) => {
+            let field = group_field(input, &key)?;
+            quote! {
+                fn group_id(&self) -> Option<String> {
+                    Some(self.#field.to_string())
+                }
+            }
+        },
+        None => quote! {},
+    };
+    Ok(quote! {
+        impl #impl_generics ::eventful::sqs::Event for #name #type_generics #where_clause {
+            #queue_fns
+            #group_fn
+        }
+    })
+}
+
+
+/// The named field group_key refers to, which must exist on a struct with named fields
+fn group_field(input: &DeriveInput, key: &LitStr) -> syn::Result<Ident> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields,
+            _ => return Err(syn::Error::new(key.span(), "group_key needs a struct with named fields")),
+        },
+        _ => return Err(syn::Error::new(key.span(), "group_key needs a struct with named fields")),
+    };
+    fields.named.iter()
+        .filter_map(|field| field.ident.clone())
+        .find(|ident| *ident == key.value())
+        .ok_or_else(|| syn::Error::new(key.span(), format!("{} has no field named {}", input.ident, key.value())))
+}
@@ -0,0 +1,37 @@
+//! Relay every message from an SQS queue onto an NSQ topic.
+//! Set SQS_QUEUE_URL and the usual AWS credential environment variables, and run nsqd as in examples/nsq.
+
+use std::{env, sync::Arc, time::Duration};
+use tokio::time::sleep;
+use eventful::{
+    bridge::{Bridge, SqsSource},
+    deadletter::NsqTopicSink,
+    err::EventfulError,
+    nsq::Daemon,
+    publisher::Destination,
+    retry::RetryPolicy,
+    sqs::ClientSQS,
+};
+
+
+#[tokio::main]
+async fn main() -> Result<(), EventfulError> {
+    let client = ClientSQS::new_with_region("us-east-1").await;
+    let source = SqsSource::new(client, &env::var("SQS_QUEUE_URL").unwrap());
+    let daemon = Arc::new(Daemon::new("127.0.0.1", 4151, 4150));
+
+    let bridge = Bridge::new(source.subscribe(), daemon.clone(), Destination::NsqTopic("from_sqs".to_string()))
+        .dead_letter(Arc::new(NsqTopicSink::new(daemon, "from_sqs_dlq")))
+        .retry(RetryPolicy::default().max_attempts(3).base_delay(Duration::from_secs(5)))
+        .concurrency(8);
+
+    let stats = bridge.stats();
+    tokio::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(10)).await;
+            println!("{:?}", stats.snapshot());
+        }
+    });
+
+    bridge.run_until(async { let _ = tokio::signal::ctrl_c().await; }).await
+}
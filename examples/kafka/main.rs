@@ -0,0 +1,65 @@
+use std::time::Duration;
+use tokio::{time::sleep};
+use rand::{Rng, distributions::{Alphanumeric, DistString}};
+use serde::{Serialize, Deserialize};
+use eventful::{consumer::ConsumerOptions, err::EventfulError, kafka::{EventKafka, GroupConsumer, ProducerKafka}};
+
+
+const BROKERS: &str = "127.0.0.1:9092";
+
+#[derive(Serialize, Deserialize)]
+struct UserClickedSomething {
+    pub user_id: i32,
+    pub clicked_on: String,
+}
+
+impl EventKafka for UserClickedSomething {
+    fn topic() -> &'static str {
+        "click"
+    }
+
+    fn key(&self) -> Option<String> {
+        Some(self.user_id.to_string())
+    }
+}
+
+pub struct ClickProcessor{}
+
+impl GroupConsumer<UserClickedSomething> for ClickProcessor {
+    fn group_id(&self) -> String {
+        format!("some_group")
+    }
+}
+
+
+async fn simulate_clicks() -> Result<(), EventfulError> {
+    let producer = ProducerKafka::new(BROKERS)?;
+    loop {
+        let millis: u64 = rand::thread_rng().gen_range(300..1200);
+        let count: u64 = rand::thread_rng().gen_range(1..4);
+        sleep(Duration::from_millis(millis)).await;
+        for _ in 0..count {
+            let user_id = rand::thread_rng().gen_range(0..1000);
+            let clicked_on: String = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+            let event = UserClickedSomething{user_id, clicked_on};
+            println!("PRODUCE: user_id={} clicked_on='{}'", &event.user_id, &event.clicked_on);
+            let _x = producer.publish(&event).await?;
+        }
+    }
+}
+
+
+#[tokio::main]
+async fn main() -> Result<(), EventfulError> {
+
+    tokio::spawn(async move {
+        let _ = simulate_clicks().await;
+    });
+
+    // let events accumulate in Kafka for a few seconds to illustrate the decoupled nature of the producer and the consumer
+    sleep(Duration::from_millis(2000u64)).await;
+    ClickProcessor{}.run(BROKERS, &ConsumerOptions::default(), |event: UserClickedSomething| async move {
+        println!("    CONSUME:  user_id={} clicked_on='{}'", &event.user_id, &event.clicked_on);
+        Ok(())
+    }).await
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+use tokio::{time::sleep};
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use eventful::{consumer::ConsumerOptions, err::EventfulError, mqtt::{EventMqtt, LastWill, MqttOptions, PublisherMqtt, QoS, TopicSubscriber, mqtt_options}};
+
+
+// run a broker with `docker run -p 1883:1883 eclipse-mosquitto:1.6`
+const HOST: &str = "127.0.0.1";
+const PORT: u16 = 1883;
+
+#[derive(Serialize, Deserialize)]
+struct Telemetry {
+    pub device_id: i32,
+    pub temperature: f64,
+}
+
+impl EventMqtt for Telemetry {
+    fn topic(&self) -> String {
+        format!("devices/{}/telemetry", self.device_id)
+    }
+
+    fn topic_filter() -> String {
+        "devices/+/telemetry".to_string()
+    }
+}
+
+pub struct TelemetryIngest{}
+
+impl TopicSubscriber<Telemetry> for TelemetryIngest {
+    fn options(&self) -> MqttOptions {
+        let mut options = mqtt_options("telemetry-ingest", HOST, PORT);
+        options.set_last_will(LastWill::new("services/telemetry-ingest/status", "offline", QoS::AtLeastOnce, true));
+        options
+    }
+}
+
+
+async fn simulate_devices() -> Result<(), EventfulError> {
+    let publisher = PublisherMqtt::connect(mqtt_options("device-simulator", HOST, PORT));
+    loop {
+        let millis: u64 = rand::thread_rng().gen_range(300..1200);
+        sleep(Duration::from_millis(millis)).await;
+        let device_id = rand::thread_rng().gen_range(0..10);
+        let temperature = rand::thread_rng().gen_range(15.0..30.0);
+        let event = Telemetry{device_id, temperature};
+        println!("PRODUCE: device_id={} temperature={:.1}", &event.device_id, &event.temperature);
+        publisher.publish(&event).await?;
+    }
+}
+
+
+#[tokio::main]
+async fn main() -> Result<(), EventfulError> {
+
+    tokio::spawn(async move {
+        let _ = simulate_devices().await;
+    });
+
+    TelemetryIngest{}.run(&ConsumerOptions::default(), |event: Telemetry| async move {
+        println!("    CONSUME:  device_id={} temperature={:.1}", &event.device_id, &event.temperature);
+        Ok(())
+    }).await
+}
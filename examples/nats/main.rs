@@ -0,0 +1,79 @@
+use std::time::Duration;
+use tokio::{time::sleep};
+use rand::{Rng, distributions::{Alphanumeric, DistString}};
+use serde::{Serialize, Deserialize};
+use eventful::{consumer::ConsumerOptions, err::EventfulError, nats::{DurableConsumer, EventNats, PublisherNats}, topology::Topology};
+
+
+const NATS_URL: &str = "nats://127.0.0.1:4222";
+
+#[derive(Serialize, Deserialize)]
+struct UserClickedSomething {
+    pub user_id: i32,
+    pub clicked_on: String,
+}
+
+impl EventNats for UserClickedSomething {
+    fn subject(&self) -> String {
+        format!("clicks.{}", self.user_id)
+    }
+
+    fn dedup_id(&self) -> Option<String> {
+        Some(format!("{}-{}", self.user_id, self.clicked_on))
+    }
+}
+
+pub struct ClickProcessor{}
+
+impl DurableConsumer<UserClickedSomething> for ClickProcessor {
+    fn stream_name(&self) -> String {
+        "CLICKS".to_string()
+    }
+
+    fn durable_name(&self) -> String {
+        "click_processor".to_string()
+    }
+}
+
+
+async fn simulate_clicks(publisher: PublisherNats) -> Result<(), EventfulError> {
+    loop {
+        let millis: u64 = rand::thread_rng().gen_range(300..1200);
+        let count: u64 = rand::thread_rng().gen_range(1..4);
+        sleep(Duration::from_millis(millis)).await;
+        for _ in 0..count {
+            let user_id = rand::thread_rng().gen_range(0..1000);
+            let clicked_on: String = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+            let event = UserClickedSomething{user_id, clicked_on};
+            println!("PRODUCE: user_id={} clicked_on='{}'", &event.user_id, &event.clicked_on);
+            let _x = publisher.publish(&event).await?;
+        }
+    }
+}
+
+
+#[tokio::main]
+async fn main() -> Result<(), EventfulError> {
+    // run a JetStream-enabled server with `docker run -p 4222:4222 nats -js`
+    let client = async_nats::connect(NATS_URL).await.map_err(|err| EventfulError::Nats(err.to_string()))?;
+    let context = async_nats::jetstream::new(client);
+
+    let mut topology = Topology::new();
+    topology.nats_stream("CLICKS").subject("clicks.>").consumer("click_processor");
+    let report = topology.apply_nats(&context).await;
+    for (resource, err) in &report.failed {
+        eprintln!("could not create {}: {:?}", resource, err);
+    }
+
+    let publisher = PublisherNats::new(context.clone());
+    tokio::spawn(async move {
+        let _ = simulate_clicks(publisher).await;
+    });
+
+    // let events accumulate in the stream for a few seconds to illustrate the decoupled nature of the producer and the consumer
+    sleep(Duration::from_millis(2000u64)).await;
+    ClickProcessor{}.run(&context, &ConsumerOptions::default(), |event: UserClickedSomething| async move {
+        println!("    CONSUME:  user_id={} clicked_on='{}'", &event.user_id, &event.clicked_on);
+        Ok(())
+    }).await
+}
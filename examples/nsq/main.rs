@@ -1,9 +1,7 @@
-use std::{time::Duration, collections::HashSet};
+use std::{sync::Arc, time::Duration};
 use tokio::{time::sleep};
-use tokio_nsq::{NSQTopic, NSQChannel, NSQConsumerConfig, NSQConsumerConfigSources, NSQConsumerLookupConfig};
 use rand::{Rng, distributions::{Alphanumeric, DistString}};
-use serde::{Serialize, Deserialize};
-use eventful::{err::GenericError, nsq::{DaemonNSQ, EventNSQ, ChannelConsumer}};
+use eventful::prelude::*;
 
 
 #[derive(Serialize, Deserialize)]
@@ -23,26 +21,26 @@ pub struct ClickProcessor{}
 
 impl eventful::nsq::ChannelConsumer<UserClickedSomething> for ClickProcessor {
     fn channel(&self) -> String {
-        format!("some_channel")
+        "some_channel".to_string()
     }
 }
 
 impl ClickProcessor {
-    async fn run(&self) -> Result<(), GenericError> {
-        let mut consumer = self.consumer();
+    async fn run(&self, daemon: &Daemon) -> Result<(), EventfulError> {
+        let mut consumer = self.consumer(&[daemon]);
         loop {
             let message = consumer.consume_filtered().await.unwrap();
             let event = self.deserialize_event(&message)?;
             println!("    CONSUME:  user_id={} clicked_on='{}'", &event.user_id, &event.clicked_on);
             message.finish().await;
         }
-        Ok(())
     }
 }
 
 
-async fn simulate_clicks() -> Result<(), GenericError> {
-    let nsqd = DaemonNSQ::new("http://127.0.0.1:4151");
+/// The producer only depends on `dyn Publisher`, so swapping NSQ for SQS (or a fake) is a one-line change in main()
+async fn simulate_clicks(publisher: Arc<dyn Publisher>) -> Result<(), EventfulError> {
+    let dest = Destination::NsqTopic(UserClickedSomething::topic().to_string());
     loop {
         let millis: u64 = rand::thread_rng().gen_range(300..1200);
         let count: u64 = rand::thread_rng().gen_range(1..4);
@@ -52,28 +50,29 @@ async fn simulate_clicks() -> Result<(), GenericError> {
             let clicked_on: String = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
             let event = UserClickedSomething{user_id, clicked_on};
             println!("PRODUCE: user_id={} clicked_on='{}'", &event.user_id, &event.clicked_on);
-            let _x = event.publish_to(&nsqd.host).await?;
+            let _x = publisher.publish_event(&dest, &event).await?;
         }
     }
-    Ok(())
 }
 
-async fn consume_events() -> Result<(), GenericError> {
+async fn consume_events(daemon: &Daemon) -> Result<(), EventfulError> {
     let cp = ClickProcessor{};
-    cp.run().await
+    cp.run(daemon).await
 }
 
 
 #[tokio::main]
-async fn main() -> Result<(), GenericError> {
-    
+async fn main() -> Result<(), EventfulError> {
+    let daemon = Daemon::new("127.0.0.1", 4151, 4150);
+    let publisher: Arc<dyn Publisher> = Arc::new(Daemon::new("127.0.0.1", 4151, 4150));
+
     tokio::spawn(async move {
-        let _ = simulate_clicks().await;
+        let _ = simulate_clicks(publisher).await;
     });
 
     // let events accumulate in NSQ for a few seconds to illustrate the decoupled nature of the producer and the consumer
     sleep(Duration::from_millis(2000u64)).await;
-    let _ = consume_events().await?;
+    consume_events(&daemon).await?;
 
     Ok(())
 }
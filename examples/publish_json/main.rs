@@ -0,0 +1,36 @@
+//! Publish one hand-written JSON payload, i.e. to poke a consumer from a shell.
+//!     cargo run --example publish_json -- nsq website_clicks '{"user_id": 5, "clicked_on": "buy"}'
+//!     cargo run --example publish_json -- sqs https://sqs.us-east-1.amazonaws.com/123456789012/orders.fifo '{"id": 1}' customer-42
+//! NSQ goes to nsqd on 127.0.0.1:4151 (as in examples/nsq); SQS uses the usual AWS credential environment variables.
+
+use std::env;
+use eventful::{err::EventfulError, nsq::{self, Daemon}, publisher::Receipt, sqs::ClientSQS};
+
+
+#[tokio::main]
+async fn main() -> Result<(), EventfulError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (backend, dest, payload) = match (args.first(), args.get(1), args.get(2)) {
+        (Some(backend), Some(dest), Some(payload)) => (backend.as_str(), dest.as_str(), payload.as_str()),
+        _ => {
+            eprintln!("usage: publish_json nsq|sqs <topic or queue url> '<json>' [sqs group id]");
+            return Ok(())
+        },
+    };
+    let value: serde_json::Value = serde_json::from_str(payload)?;
+
+    let receipt = match backend {
+        "nsq" => nsq::publish_json_value(&Daemon::new("127.0.0.1", 4151, 4150), dest, &value).await?,
+        "sqs" => {
+            let client = ClientSQS::builder().build().await?;
+            let receipt = client.publish_json_value(dest, &value, args.get(3).map(String::as_str)).await?;
+            Receipt{message_id: Some(receipt.message_id)}
+        },
+        other => {
+            eprintln!("unknown backend {}", other);
+            return Ok(())
+        },
+    };
+    println!("published to {} {}", dest, receipt.message_id.unwrap_or_default());
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! Triage the dead letters on an NSQ topic: list them, show one, then retry or discard it.
+//! Run nsqd as in examples/nsq, with a "quarantine" channel on the DLQ topic, then i.e.
+//!     cargo run --example quarantine -- list click_dlq
+//!     cargo run --example quarantine -- show click_dlq 0a1b2c3d4e5f6a7b
+//!     cargo run --example quarantine -- retry click_dlq 0a1b2c3d4e5f6a7b
+//!     cargo run --example quarantine -- discard click_dlq 0a1b2c3d4e5f6a7b
+
+use std::{env, sync::Arc};
+use eventful::{
+    bridge::NsqSource,
+    err::EventfulError,
+    nsq::Daemon,
+    quarantine::{Quarantine, QuarantineFilter},
+};
+
+
+#[tokio::main]
+async fn main() -> Result<(), EventfulError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, topic) = match (args.first(), args.get(1)) {
+        (Some(command), Some(topic)) => (command.as_str(), topic.as_str()),
+        _ => {
+            eprintln!("usage: quarantine list|show|retry|discard <dlq topic> [id]");
+            return Ok(())
+        },
+    };
+    let id = args.get(2).map(String::as_str).unwrap_or_default();
+
+    let daemon = Daemon::new("127.0.0.1", 4151, 4150);
+    let source = NsqSource::new(topic, "quarantine", vec![daemon.clone()]);
+    let quarantine = Quarantine::nsq(source, Arc::new(daemon));
+
+    match command {
+        "list" => {
+            for message in quarantine.list(&QuarantineFilter::default()).await? {
+                match &message.record {
+                    Some(record) => println!("{}  {}  attempts={}  {}", message.id, record.source, record.attempts, record.last_error),
+                    None => println!("{}  (not a dead letter record, {} bytes)", message.id, message.body.len()),
+                }
+            }
+        },
+        "show" => match quarantine.inspect(id).await? {
+            Some(message) => {
+                println!("{:#?}", message.record);
+                match message.payload {
+                    Some(payload) => println!("{}", serde_json::to_string_pretty(&payload)?),
+                    None => println!("(the payload is not JSON)"),
+                }
+            },
+            None => println!("no quarantined message has id {}", id),
+        },
+        "retry" => {
+            quarantine.retry(id).await?;
+            println!("retried {}", id);
+        },
+        "discard" => {
+            quarantine.discard(id).await?;
+            println!("discarded {}", id);
+        },
+        other => eprintln!("unknown command {}", other),
+    }
+    Ok(())
+}
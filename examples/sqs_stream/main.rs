@@ -0,0 +1,49 @@
+//! Handle orders from an SQS queue as a stream, eight at a time, until ctrl-c.
+//! Uses the region from AWS_REGION (or the profile) and the usual AWS credential environment variables;
+//! set EVENTFUL_SQS_ENDPOINT=http://localhost:4566 to run against localstack.
+
+use std::time::Duration;
+use futures::StreamExt;
+use tokio_util::sync::CancellationToken;
+use eventful::{prelude::*, sqs::ReceiveOptions};
+
+
+#[derive(Serialize, Deserialize)]
+struct OrderPlaced {
+    pub order_id: u64,
+    pub total_cents: u64,
+}
+
+impl Event for OrderPlaced {
+    fn queue_url() -> &'static str {
+        "orders"
+    }
+}
+
+
+#[tokio::main]
+async fn main() -> Result<(), EventfulError> {
+    let client = ClientSQS::from_env().await?;
+    let shutdown = CancellationToken::new();
+    let stop = shutdown.clone();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        stop.cancel();
+    });
+
+    let idle_backoff = Backoff::Exponential{base: Duration::from_secs(1), factor: 2.0, max: Duration::from_secs(30)};
+    client.stream::<OrderPlaced>(&ReceiveOptions::default(), idle_backoff, shutdown)
+        .for_each_concurrent(8, |received| async move {
+            match received {
+                Ok(order) => {
+                    println!("order {}: {} cents (receive {})", order.payload.order_id, order.payload.total_cents, order.receive_count);
+                    if let Err(err) = order.ack().await {
+                        eprintln!("could not ack: {}", err);
+                    }
+                },
+                Err(err) => eprintln!("receive failed: {}", err),
+            }
+        })
+        .await;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! A worker that runs unchanged against NSQ or SQS.
+//! Select the backend with EVENTFUL_BACKEND=nsq (the default) or EVENTFUL_BACKEND=sqs.
+//! For SQS, set SQS_QUEUE_URL and the usual AWS credential environment variables.
+
+use std::{env, time::Duration};
+use eventful::{prelude::*, subscriber::{Received, TypedSubscriber}};
+
+
+#[derive(Serialize, Deserialize)]
+struct UserClickedSomething {
+    pub user_id: i32,
+    pub clicked_on: String,
+}
+
+impl EventNSQ for UserClickedSomething {
+    fn topic() -> &'static str {
+        "click"
+    }
+}
+
+struct ClickChannel{}
+
+impl ChannelConsumer<UserClickedSomething> for ClickChannel {
+    fn channel(&self) -> String {
+        "worker".to_string()
+    }
+}
+
+
+/// The worker knows nothing about the backend it is consuming from
+async fn work(subscriber: Box<dyn Subscriber>) -> Result<(), EventfulError> {
+    let mut clicks = TypedSubscriber::<UserClickedSomething>::new(subscriber);
+    while let Some(received) = clicks.next().await? {
+        match received {
+            Received::Event(typed) => {
+                println!("CONSUME: user_id={} clicked_on='{}' attempt={}", &typed.event.user_id, &typed.event.clicked_on, typed.delivery.attempt);
+                typed.delivery.ack().await?;
+            },
+            Received::Undecodable{delivery, error} => {
+                println!("UNDECODABLE: {} (attempt {})", error, delivery.attempt);
+                delivery.nack(Duration::from_secs(30)).await?;
+            },
+        }
+    }
+    Ok(())
+}
+
+
+#[tokio::main]
+async fn main() -> Result<(), EventfulError> {
+    let backend = env::var("EVENTFUL_BACKEND").unwrap_or("nsq".to_string());
+    let subscriber: Box<dyn Subscriber> = match backend.as_str() {
+        "sqs" => {
+            let client = ClientSQS::new_with_region("us-east-1").await;
+            Box::new(client.subscribe(&env::var("SQS_QUEUE_URL").unwrap()))
+        },
+        _ => {
+            let daemon = Daemon::new("127.0.0.1", 4151, 4150);
+            Box::new(ClickChannel{}.subscribe(&[&daemon]))
+        },
+    };
+    work(subscriber).await
+}
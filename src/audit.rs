@@ -0,0 +1,428 @@
+//! The audit module keeps a complete record of every event a service emits, independent of broker retention.
+//! Wrap a publisher in an AuditedPublisher and every publish (successful or not) is handed to an AuditSink as an AuditEntry.
+//! To record exactly the bytes that were sent, audit inside any interceptors: `InterceptedPublisher::new(AuditedPublisher::new(inner, sink), interceptors)`.
+//!
+//! Entries are written as ndjson in the ArchivedEvent shape (plus an `outcome` field), so archives can be fed
+//! straight to replay::replay_ndjson.
+//!
+//! - NdjsonFileSink appends to a local file, rotating it once it reaches a size limit.
+//! - S3AuditSink (with the `s3` feature) buffers entries and writes them to S3 as one object per batch.
+//!
+//! When the sink fails, AuditFailure decides whether the publish fails too or the error is just logged.
+//! The sink is called after the publish, so with AuditFailure::FailPublish a successful publish can still
+//! return an error (and be published again if the caller retries).
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::audit::{AuditedPublisher, AuditFailure, NdjsonFileSink};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Click { user_id: u64 }
+//! # async fn demo(fleet: FleetNSQ, click: Click) -> Result<(), EventfulError> {
+//! let sink = Arc::new(NdjsonFileSink::new("/var/log/eventful/audit.ndjson").max_bytes(256 * 1024 * 1024));
+//! let publisher = AuditedPublisher::new(fleet, sink).on_failure(AuditFailure::FailPublish);
+//! publisher.publish_event(&Destination::NsqTopic("click".to_string()), &click).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{path::PathBuf, sync::Arc};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use crate::codec;
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::replay::ArchivedEvent;
+
+
+/// How a publish turned out
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Published { message_id: Option<String> },
+    Failed { error: String },
+}
+
+
+/// One audited publish. Serializes as an ArchivedEvent with an extra `outcome` field
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// The topic or queue it was published to
+    pub topic: Destination,
+    #[serde(with = "codec::base64_bytes")]
+    pub body: Bytes,
+    #[serde(default)]
+    pub metadata: Metadata,
+    /// When it was published
+    pub original_timestamp: DateTime<Utc>,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditEntry {
+    /// The entry as an ArchivedEvent, i.e. to republish it
+    pub fn archived(&self) -> ArchivedEvent {
+        ArchivedEvent{
+            topic: self.topic.clone(),
+            body: self.body.clone(),
+            metadata: self.metadata.clone(),
+            original_timestamp: self.original_timestamp,
+        }
+    }
+
+    /// Encode as one line of ndjson, including the trailing newline
+    pub fn to_line(&self) -> Result<Vec<u8>, EventfulError> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+
+/// Somewhere audit entries are kept
+#[async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn record(&self, entry: AuditEntry) -> Result<(), EventfulError>;
+}
+
+
+/// What to do when the AuditSink fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditFailure {
+    /// Return the audit error from the publish
+    FailPublish,
+    /// Log the audit error (with the `tracing` feature) and return the publish result as if nothing happened
+    LogAndContinue,
+}
+
+
+/// An AuditedPublisher hands every publish made through it to an AuditSink
+pub struct AuditedPublisher<P> {
+    inner: P,
+    sink: Arc<dyn AuditSink>,
+    on_failure: AuditFailure,
+}
+
+impl<P: Publisher> AuditedPublisher<P> {
+    pub fn new(inner: P, sink: Arc<dyn AuditSink>) -> Self {
+        AuditedPublisher{inner, sink, on_failure: AuditFailure::LogAndContinue}
+    }
+
+    /// What to do when the sink fails (default LogAndContinue)
+    pub fn on_failure(mut self, on_failure: AuditFailure) -> Self {
+        self.on_failure = on_failure;
+        self
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for AuditedPublisher<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let original_timestamp = Utc::now();
+        let result = self.inner.publish_bytes(dest, body.clone(), meta).await;
+        let outcome = match &result {
+            Ok(receipt) => AuditOutcome::Published{message_id: receipt.message_id.clone()},
+            Err(err) => AuditOutcome::Failed{error: err.to_string()},
+        };
+        let entry = AuditEntry{topic: dest.clone(), body, metadata: meta.clone(), original_timestamp, outcome};
+        if let Err(err) = self.sink.record(entry).await {
+            match self.on_failure {
+                AuditFailure::FailPublish => return Err(err),
+                AuditFailure::LogAndContinue => {
+                    #[cfg(feature = "tracing")]
+                    tracing::error!(dest = %dest, error = %err, "failed to audit publish");
+                },
+            }
+        }
+        result
+    }
+}
+
+
+struct OpenFile {
+    file: tokio::fs::File,
+    size: u64,
+}
+
+/// Appends entries to an ndjson file. Once the file reaches max_bytes it is renamed with a timestamp
+/// suffix (i.e. audit.ndjson.20230314T101500.123Z) and a new file is started
+pub struct NdjsonFileSink {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    fsync: bool,
+    open: Mutex<Option<OpenFile>>,
+}
+
+impl NdjsonFileSink {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        NdjsonFileSink{path: path.into(), max_bytes: None, fsync: false, open: Mutex::new(None)}
+    }
+
+    /// Rotate the file before it grows past this many bytes (default: never rotate)
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// When true every entry is fsynced before record returns (default false)
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    async fn open_file(&self) -> Result<OpenFile, EventfulError> {
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+        }
+        let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        let size = file.metadata().await?.len();
+        Ok(OpenFile{file, size})
+    }
+
+    async fn rotate(&self) -> Result<(), EventfulError> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", Utc::now().format("%Y%m%dT%H%M%S%.fZ")));
+        tokio::fs::rename(&self.path, rotated).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuditSink for NdjsonFileSink {
+    async fn record(&self, entry: AuditEntry) -> Result<(), EventfulError> {
+        let line = entry.to_line()?;
+        let mut open = self.open.lock().await;
+        if open.is_none() {
+            *open = Some(self.open_file().await?);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            let size = open.as_ref().map(|open| open.size).unwrap_or(0);
+            if size > 0 && size + line.len() as u64 > max_bytes {
+                *open = None;
+                self.rotate().await?;
+                *open = Some(self.open_file().await?);
+            }
+        }
+        let current = open.as_mut().unwrap();
+        current.file.write_all(&line).await?;
+        if self.fsync {
+            current.file.sync_data().await?;
+        }
+        current.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+
+#[cfg(feature = "s3")]
+pub use self::s3::S3AuditSink;
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use std::time::{Duration, Instant};
+    use async_trait::async_trait;
+    use aws_sdk_s3::{types::ByteStream, Client};
+    use chrono::Utc;
+    use rand::Rng;
+    use tokio::sync::Mutex;
+    use tokio_util::sync::CancellationToken;
+    use crate::err::EventfulError;
+    use super::{AuditEntry, AuditSink};
+
+    struct Buffer {
+        lines: Vec<u8>,
+        entries: usize,
+        oldest: Option<Instant>,
+    }
+
+    /// Buffers entries and writes each batch to S3 as one ndjson object, named
+    /// {prefix}{timestamp}-{random}.ndjson, once max_entries are buffered or the oldest is max_age old.
+    /// Age is only checked when an entry is recorded, so call run_flusher (or flush before shutdown) to
+    /// write out a batch which stops growing
+    pub struct S3AuditSink {
+        client: Client,
+        bucket: String,
+        prefix: String,
+        max_entries: usize,
+        max_age: Duration,
+        buffer: Mutex<Buffer>,
+    }
+
+    impl S3AuditSink {
+        pub fn new(client: Client, bucket: &str, prefix: &str) -> Self {
+            S3AuditSink{
+                client,
+                bucket: bucket.to_string(),
+                prefix: prefix.to_string(),
+                max_entries: 1000,
+                max_age: Duration::from_secs(60),
+                buffer: Mutex::new(Buffer{lines: Vec::new(), entries: 0, oldest: None}),
+            }
+        }
+
+        /// Write a batch once it has this many entries (default 1000)
+        pub fn max_entries(mut self, max_entries: usize) -> Self {
+            self.max_entries = max_entries.max(1);
+            self
+        }
+
+        /// Write a batch once its oldest entry is this old (default 60 seconds)
+        pub fn max_age(mut self, max_age: Duration) -> Self {
+            self.max_age = max_age;
+            self
+        }
+
+        /// Write whatever is buffered now
+        pub async fn flush(&self) -> Result<(), EventfulError> {
+            let mut buffer = self.buffer.lock().await;
+            self.write(&mut buffer).await
+        }
+
+        /// Flush batches which have reached max_age, checking every interval, until shutdown is cancelled.
+        /// Whatever is buffered at shutdown is flushed
+        pub async fn run_flusher(&self, interval: Duration, shutdown: CancellationToken) -> Result<(), EventfulError> {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => return self.flush().await,
+                    _ = ticker.tick() => {},
+                }
+                let mut buffer = self.buffer.lock().await;
+                if buffer.oldest.map(|oldest| oldest.elapsed() >= self.max_age).unwrap_or(false) {
+                    self.write(&mut buffer).await?;
+                }
+            }
+        }
+
+        async fn write(&self, buffer: &mut Buffer) -> Result<(), EventfulError> {
+            if buffer.entries == 0 {
+                return Ok(())
+            }
+            let suffix: u32 = rand::thread_rng().gen();
+            let key = format!("{}{}-{:08x}.ndjson", self.prefix, Utc::now().format("%Y%m%dT%H%M%S%.fZ"), suffix);
+            self.client.put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .content_type("application/x-ndjson")
+                .body(ByteStream::from(buffer.lines.clone()))
+                .send().await
+                .map_err(|err| EventfulError::S3(format!("{:?}", err)))?;
+            buffer.lines.clear();
+            buffer.entries = 0;
+            buffer.oldest = None;
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl AuditSink for S3AuditSink {
+        async fn record(&self, entry: AuditEntry) -> Result<(), EventfulError> {
+            let line = entry.to_line()?;
+            let mut buffer = self.buffer.lock().await;
+            buffer.lines.extend_from_slice(&line);
+            buffer.entries += 1;
+            buffer.oldest.get_or_insert_with(Instant::now);
+            let full = buffer.entries >= self.max_entries;
+            let stale = buffer.oldest.map(|oldest| oldest.elapsed() >= self.max_age).unwrap_or(false);
+            if full || stale {
+                self.write(&mut buffer).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::memory::MemoryBroker;
+    use crate::replay::{ReplayOptions, ReplayReport, replay_ndjson};
+    use crate::subscriber::Subscriber;
+
+    fn audit_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("eventful-audit-{}-{}", name, rand::random::<u32>()))
+    }
+
+    fn click() -> Destination {
+        Destination::NsqTopic("click".to_string())
+    }
+
+    struct FullDisk;
+
+    #[async_trait]
+    impl AuditSink for FullDisk {
+        async fn record(&self, _entry: AuditEntry) -> Result<(), EventfulError> {
+            Err(EventfulError::Config("no space left on the audit disk".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn the_audit_file_replays_what_was_published() {
+        let dir = audit_dir("replay");
+        let path = dir.join("audit.ndjson");
+        let publisher = AuditedPublisher::new(MemoryBroker::new(), Arc::new(NdjsonFileSink::new(&path)));
+        let meta = Metadata{headers: HashMap::from([("traceparent".to_string(), "00-abc-def-01".to_string())]), ..Default::default()};
+        for n in 0..3 {
+            publisher.publish_bytes(&click(), Bytes::from(n.to_string()), &meta).await.unwrap();
+        }
+
+        let replayed = MemoryBroker::new();
+        let mut clicks = replayed.subscribe(&click(), "audit");
+        let file = tokio::fs::File::open(&path).await.unwrap();
+        let report = replay_ndjson(file, &replayed, ReplayOptions::default()).await.unwrap();
+        assert_eq!(report, ReplayReport{sent: 3, failed: 0, skipped: 0});
+        assert_eq!(replayed.published(), publisher.inner().published());
+        assert_eq!(clicks.next().await.unwrap().unwrap().meta, meta);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotated_files_together_replay_every_entry() {
+        let dir = audit_dir("rotate");
+        let path = dir.join("audit.ndjson");
+        // every entry is bigger than this, so each one starts a new file
+        let publisher = AuditedPublisher::new(MemoryBroker::new(), Arc::new(NdjsonFileSink::new(&path).max_bytes(16)));
+        for n in 0..3 {
+            publisher.publish_bytes(&click(), Bytes::from(n.to_string()), &Metadata::default()).await.unwrap();
+        }
+
+        let replayed = MemoryBroker::new();
+        let mut files = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files.len(), 3);
+        for file in files {
+            let report = replay_ndjson(tokio::fs::File::open(&file).await.unwrap(), &replayed, ReplayOptions::default()).await.unwrap();
+            assert_eq!(report.sent, 1, "{}", file.display());
+        }
+        let mut bodies = replayed.published_to("click");
+        bodies.sort();
+        assert_eq!(bodies, vec![Bytes::from("0"), Bytes::from("1"), Bytes::from("2")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failing_sink_fails_the_publish_only_with_fail_publish() {
+        let lenient = AuditedPublisher::new(MemoryBroker::new(), Arc::new(FullDisk));
+        lenient.publish_bytes(&click(), Bytes::from("a"), &Metadata::default()).await.unwrap();
+
+        let strict = AuditedPublisher::new(MemoryBroker::new(), Arc::new(FullDisk)).on_failure(AuditFailure::FailPublish);
+        let err = strict.publish_bytes(&click(), Bytes::from("a"), &Metadata::default()).await.unwrap_err();
+        assert!(matches!(err, EventfulError::Config(_)));
+        // the sink runs after the publish, which went through regardless
+        assert_eq!(strict.inner().published_to("click"), vec![Bytes::from("a")]);
+    }
+}
@@ -0,0 +1,260 @@
+//! The blocking module publishes and polls from synchronous code (i.e. a rayon pipeline or a daemon without tokio).
+//! Each BlockingPublisher and BlockingSqs owns a single-threaded tokio runtime on its own background thread. A call
+//! sends its future to that thread over a channel and blocks until the result comes back, so it is safe from any
+//! thread which is not running async code, and fails with the same EventfulError the async API would.
+//!
+//! Calling from inside a tokio runtime would block one of its threads (or deadlock a current_thread runtime), so it is
+//! detected and rejected with EventfulError::Config instead. Use the async API there.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(all(feature = "nsq", feature = "sqs"))]
+//! # mod example {
+//! # use eventful::prelude::*;
+//! # use eventful::blocking::{BlockingPublisher, BlockingSqs};
+//! # const ORDERS_QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/orders";
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Click { user_id: u64 }
+//! # fn demo(clicks: Vec<Click>) -> Result<(), EventfulError> {
+//! let publisher = BlockingPublisher::new(FleetNSQ::new_from_env())?;
+//! clicks.iter().try_for_each(|click| {
+//!     publisher.publish_event(&Destination::NsqTopic("website_clicks".to_string()), click).map(|_| ())
+//! })?;
+//!
+//! let sqs = BlockingSqs::connect(ClientSQS::builder().region("us-east-1".to_string()))?;
+//! for message in sqs.poll(ORDERS_QUEUE_URL, 10, 20)? {
+//!     sqs.delete_message(ORDERS_QUEUE_URL, message.receipt_handle().unwrap_or_default())?;
+//! }
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{future::Future, sync::{Arc, mpsc as std_mpsc}, thread, time::Duration};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use crate::codec::{Codec, JsonCodec};
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+#[cfg(feature = "sqs")]
+use crate::sqs::{self, ClientSQS, ClientSQSBuilder, Event, Message, OutgoingSQS, PublishReceiptSqs, SentMessage, SqsApi, SqsApiExt};
+
+
+/// A runtime on a background thread which runs the futures it is sent
+struct Worker {
+    jobs: Option<mpsc::UnboundedSender<BoxFuture<'static, ()>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Worker {
+    fn start(name: &str) -> Result<Self, EventfulError> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let (jobs, mut receiver) = mpsc::unbounded_channel::<BoxFuture<'static, ()>>();
+        let thread = thread::Builder::new().name(name.to_string()).spawn(move || {
+            runtime.block_on(async move {
+                while let Some(job) = receiver.recv().await {
+                    tokio::spawn(job);
+                }
+            });
+        })?;
+        Ok(Worker{jobs: Some(jobs), thread: Some(thread)})
+    }
+
+    /// Run a future on the worker's runtime and block until it finishes
+    fn call<T, F>(&self, future: F) -> Result<T, EventfulError>
+    where
+        T: Send + 'static,
+        F: Future<Output = Result<T, EventfulError>> + Send + 'static,
+    {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(EventfulError::Config("a blocking eventful call was made from inside a tokio runtime, where it could deadlock; use the async API instead".to_string()))
+        }
+        let (reply, result) = std_mpsc::sync_channel(1);
+        let job = Box::pin(async move {
+            let _ = reply.send(future.await);
+        });
+        let stopped = || EventfulError::Destination("the blocking runtime has stopped".to_string());
+        self.jobs.as_ref().ok_or_else(stopped)?.send(job).map_err(|_| stopped())?;
+        result.recv().map_err(|_| stopped())?
+    }
+}
+
+impl Drop for Worker {
+    /// Stop taking jobs and wait for the thread, unless this is the worker thread itself
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+
+/// A BlockingPublisher publishes through any Publisher (i.e. a FleetNSQ or ClientSQS) from synchronous code
+pub struct BlockingPublisher<P> {
+    inner: Arc<P>,
+    worker: Worker,
+}
+
+impl<P: Publisher + 'static> BlockingPublisher<P> {
+    /// Start the background runtime. Fails if the thread or runtime can't be created
+    pub fn new(inner: P) -> Result<Self, EventfulError> {
+        Ok(BlockingPublisher{inner: Arc::new(inner), worker: Worker::start("eventful-blocking-publisher")?})
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let inner = self.inner.clone();
+        let (dest, meta) = (dest.clone(), meta.clone());
+        self.worker.call(async move { inner.publish_bytes(&dest, body, &meta).await })
+    }
+
+    pub fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        let inner = self.inner.clone();
+        let (dest, meta) = (dest.clone(), meta.clone());
+        self.worker.call(async move { inner.publish_delayed(&dest, body, &meta, delay).await })
+    }
+
+    /// Encode an event with the JsonCodec and publish it, as PublisherExt::publish_event does
+    pub fn publish_event<T: Serialize>(&self, dest: &Destination, event: &T) -> Result<Receipt, EventfulError> {
+        self.publish_bytes(dest, JsonCodec.encode(event)?, &Metadata::default())
+    }
+}
+
+
+/// A BlockingSqs publishes to and polls SQS queues from synchronous code
+#[cfg(feature = "sqs")]
+pub struct BlockingSqs {
+    api: Arc<dyn SqsApi>,
+    worker: Worker,
+}
+
+#[cfg(feature = "sqs")]
+impl BlockingSqs {
+    /// Build a ClientSQS on the background runtime, so its connections belong to that runtime
+    pub fn connect(builder: ClientSQSBuilder) -> Result<Self, EventfulError> {
+        let worker = Worker::start("eventful-blocking-sqs")?;
+        let client: ClientSQS = worker.call(builder.build())?;
+        Ok(BlockingSqs{api: Arc::new(client), worker})
+    }
+
+    /// Wrap any SqsApi, i.e. a testing::FakeSqs
+    pub fn from_api(api: Arc<dyn SqsApi>) -> Result<Self, EventfulError> {
+        Ok(BlockingSqs{api, worker: Worker::start("eventful-blocking-sqs")?})
+    }
+
+    /// Publish an event to its queue, checked and verified as SqsApiExt::publish does
+    pub fn publish<T: Event>(&self, event: &T) -> Result<PublishReceiptSqs, EventfulError> {
+        let (queue, body) = (sqs::queue_url_for(event)?, serde_json::to_string(event)?);
+        let (api, group_id, dedup_id) = (self.api.clone(), event.group_id(), event.dedup_id());
+        self.worker.call(async move { api.publish_body(&queue, body, group_id, dedup_id).await })
+    }
+
+    /// Send one message as it is (see SqsApi::send_message)
+    pub fn send_message(&self, queue_url: &str, message: OutgoingSQS) -> Result<SentMessage, EventfulError> {
+        let (api, queue_url) = (self.api.clone(), queue_url.to_string());
+        self.worker.call(async move { api.send_message(&queue_url, message).await })
+    }
+
+    /// Receive up to max_messages (1 to 10), waiting up to wait_time_seconds (0 to 20) for any to arrive.
+    /// Delete each message once it has been handled, or it is redelivered after the visibility timeout
+    pub fn poll(&self, queue_url: &str, max_messages: i32, wait_time_seconds: i32) -> Result<Vec<Message>, EventfulError> {
+        let (api, queue_url) = (self.api.clone(), queue_url.to_string());
+        self.worker.call(async move { api.receive_messages(&queue_url, max_messages, wait_time_seconds).await })
+    }
+
+    pub fn delete_message(&self, queue_url: &str, receipt_handle: &str) -> Result<(), EventfulError> {
+        let (api, queue_url, receipt_handle) = (self.api.clone(), queue_url.to_string(), receipt_handle.to_string());
+        self.worker.call(async move { api.delete_message(&queue_url, &receipt_handle).await })
+    }
+
+    /// Make a received message visible again in `seconds`
+    pub fn change_visibility(&self, queue_url: &str, receipt_handle: &str, seconds: i32) -> Result<(), EventfulError> {
+        let (api, queue_url, receipt_handle) = (self.api.clone(), queue_url.to_string(), receipt_handle.to_string());
+        self.worker.call(async move { api.change_visibility(&queue_url, &receipt_handle, seconds).await })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBroker;
+
+    fn clicks() -> Destination {
+        Destination::NsqTopic("clicks".to_string())
+    }
+
+    #[test]
+    fn publishes_from_plain_threads() {
+        let broker = MemoryBroker::new();
+        let publisher = Arc::new(BlockingPublisher::new(broker.clone()).unwrap());
+        let threads = (0..8).map(|t| {
+            let publisher = publisher.clone();
+            thread::spawn(move || {
+                for i in 0..10 {
+                    publisher.publish_event(&clicks(), &serde_json::json!({"thread": t, "i": i})).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(broker.published_to("clicks").len(), 80);
+    }
+
+    #[test]
+    fn returns_the_async_error() {
+        let broker = MemoryBroker::new();
+        let publisher = BlockingPublisher::new(broker.clone()).unwrap();
+        broker.fail_next_publish();
+        assert!(matches!(publisher.publish_bytes(&clicks(), Bytes::from_static(b"{}"), &Metadata::default()), Err(EventfulError::Http(_))));
+        publisher.publish_bytes(&clicks(), Bytes::from_static(b"{}"), &Metadata::default()).unwrap();
+        assert_eq!(broker.published_to("clicks").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_calls_from_inside_a_runtime() {
+        let broker = MemoryBroker::new();
+        let publisher = BlockingPublisher::new(broker.clone()).unwrap();
+        match publisher.publish_bytes(&clicks(), Bytes::from_static(b"{}"), &Metadata::default()) {
+            Err(EventfulError::Config(message)) => assert!(message.contains("inside a tokio runtime"), "{}", message),
+            other => panic!("expected a Config error, got {:?}", other),
+        }
+        assert!(broker.published_to("clicks").is_empty());
+
+        // a plain thread started from async code is fine
+        let publisher = Arc::new(publisher);
+        let published = {
+            let publisher = publisher.clone();
+            thread::spawn(move || publisher.publish_bytes(&clicks(), Bytes::from_static(b"{}"), &Metadata::default()))
+        };
+        published.join().unwrap().unwrap();
+        assert_eq!(broker.published_to("clicks").len(), 1);
+    }
+
+    #[cfg(all(feature = "testing", feature = "sqs"))]
+    #[test]
+    fn sends_polls_and_deletes_from_a_plain_thread() {
+        use crate::testing::FakeSqs;
+        let queue_url = "https://sqs.us-east-1.amazonaws.com/000000000000/orders";
+        let fake = FakeSqs::new();
+        let sqs = BlockingSqs::from_api(Arc::new(fake.clone())).unwrap();
+        let message = OutgoingSQS{body: r#"{"id":1}"#.to_string(), group_id: None, dedup_id: None};
+        let sent = sqs.send_message(queue_url, message).unwrap();
+        let received = sqs.poll(queue_url, 10, 0).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].message_id(), sent.message_id.as_deref());
+        assert_eq!(received[0].body(), Some(r#"{"id":1}"#));
+        sqs.delete_message(queue_url, received[0].receipt_handle().unwrap()).unwrap();
+        assert_eq!(fake.deleted(queue_url), 1);
+        assert!(sqs.poll(queue_url, 10, 0).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+//! The bridge module relays raw messages from one message bus to another, i.e. from an SQS queue to an NSQ topic.  
+//! Bodies are moved as-is (no decoding), and the source message is only acked once the sink publish succeeds.
+//! A message whose sink publish keeps failing is sent to a DeadLetterSink instead.
+
+use std::{future::Future, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::Duration};
+use tokio::sync::Semaphore;
+use crate::deadletter::{DeadLetterRecord, DeadLetterSink};
+use crate::err::EventfulError;
+#[cfg(feature = "nsq")]
+use crate::nsq::{self, Daemon, SubscriptionNSQ};
+use crate::observer::{self, DeadLettered, EventfulObserver, PublishFailed};
+use crate::publisher::{Destination, Publisher};
+use crate::retry::{Backoff, Jitter, RetryPolicy};
+#[cfg(feature = "sqs")]
+use crate::sqs::ClientSQS;
+use crate::subscriber::{Delivery, Subscriber};
+
+
+/// Relay messages from an SQS queue
+#[cfg(feature = "sqs")]
+pub struct SqsSource {
+    pub client: ClientSQS,
+    pub queue_url: String,
+    /// How long each poll waits for messages (0 to 20 seconds)
+    pub wait_time_seconds: i32,
+    /// The most messages received per poll (1 to 10)
+    pub max_messages: i32,
+}
+
+#[cfg(feature = "sqs")]
+impl SqsSource {
+    pub fn new(client: ClientSQS, queue_url: &str) -> Self {
+        SqsSource{client, queue_url: queue_url.to_string(), wait_time_seconds: 20, max_messages: 10}
+    }
+
+    pub fn subscribe(&self) -> Box<dyn Subscriber> {
+        let subscription = self.client.subscribe(&self.queue_url)
+            .wait_time_seconds(self.wait_time_seconds)
+            .max_messages(self.max_messages);
+        Box::new(subscription)
+    }
+}
+
+
+/// Relay messages from a channel on an NSQ topic
+#[cfg(feature = "nsq")]
+pub struct NsqSource {
+    pub topic: String,
+    pub channel: String,
+    pub daemons: Vec<Daemon>,
+}
+
+#[cfg(feature = "nsq")]
+impl NsqSource {
+    pub fn new(topic: &str, channel: &str, daemons: Vec<Daemon>) -> Self {
+        NsqSource{topic: topic.to_string(), channel: channel.to_string(), daemons}
+    }
+
+    pub fn subscribe(&self) -> Result<Box<dyn Subscriber>, EventfulError> {
+        let daemons: Vec<&Daemon> = self.daemons.iter().collect();
+        let consumer = nsq::consumer_for(&self.topic, &self.channel, &daemons)?;
+        Ok(Box::new(SubscriptionNSQ::new(consumer, &self.topic)))
+    }
+}
+
+
+/// Counters describing what a Bridge has done so far
+#[derive(Debug, Default)]
+pub struct BridgeStats {
+    relayed: AtomicU64,
+    failed: AtomicU64,
+    dead_lettered: AtomicU64,
+}
+
+/// A point-in-time copy of BridgeStats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BridgeStatsSnapshot {
+    /// Messages published to the sink and acked at the source
+    pub relayed: u64,
+    /// Sink publishes which failed (each failed attempt counts once)
+    pub failed: u64,
+    /// Messages sent to the DeadLetterSink
+    pub dead_lettered: u64,
+}
+
+impl BridgeStats {
+    pub fn snapshot(&self) -> BridgeStatsSnapshot {
+        BridgeStatsSnapshot{
+            relayed: self.relayed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dead_lettered: self.dead_lettered.load(Ordering::Relaxed),
+        }
+    }
+}
+
+
+/// The Bridge moves messages from a source Subscriber to a sink Destination
+pub struct Bridge {
+    source: Box<dyn Subscriber>,
+    sink: Arc<dyn Publisher>,
+    sink_dest: Destination,
+    dead_letter: Option<Arc<dyn DeadLetterSink>>,
+    retry: RetryPolicy,
+    concurrency: usize,
+    stats: Arc<BridgeStats>,
+    observer: Option<Arc<dyn EventfulObserver>>,
+}
+
+impl Bridge {
+    pub fn new(source: Box<dyn Subscriber>, sink: Arc<dyn Publisher>, sink_dest: Destination) -> Self {
+        Bridge{
+            source, sink, sink_dest,
+            dead_letter: None,
+            retry: RetryPolicy::default()
+                .max_attempts(5)
+                .backoff(Backoff::ExponentialJitter{base: Duration::from_secs(10), factor: 2.0, max: Duration::from_secs(300), jitter: Jitter::Equal}),
+            concurrency: 1,
+            stats: Arc::new(BridgeStats::default()),
+            observer: None,
+        }
+    }
+
+    /// Send messages which fail retry.max_attempts times to this sink
+    pub fn dead_letter(mut self, sink: Arc<dyn DeadLetterSink>) -> Self {
+        self.dead_letter = Some(sink);
+        self
+    }
+
+    /// retry.max_attempts is how many deliveries a message gets before it is dead-lettered,
+    /// and retry.delay_for(attempt) how long it waits at the source before it is redelivered.
+    /// The default allows 5 attempts, waiting 10 seconds doubling up to 5 minutes
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// How many messages can be relayed at once (default 1)
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Receive failed-publish and dead-letter callbacks here instead of the global observer
+    pub fn observer(mut self, observer: Arc<dyn EventfulObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// A handle to the counters, which stays valid while the bridge runs
+    pub fn stats(&self) -> Arc<BridgeStats> {
+        self.stats.clone()
+    }
+
+    /// Relay messages until the source ends
+    pub async fn run(self) -> Result<(), EventfulError> {
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Relay messages until the source ends, fails, or the shutdown future completes.
+    /// Messages already being relayed are finished before this returns, even when the source failed
+    pub async fn run_until<F: Future<Output = ()>>(mut self, shutdown: F) -> Result<(), EventfulError> {
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let mut result = Ok(());
+        tokio::pin!(shutdown);
+        loop {
+            let permit = permits.clone().acquire_owned().await
+                .map_err(|_| EventfulError::Config("bridge semaphore closed".to_string()))?;
+            let delivery = tokio::select! {
+                _ = &mut shutdown => break,
+                delivery = self.source.next() => match delivery {
+                    Ok(Some(delivery)) => delivery,
+                    Ok(None) => break,
+                    Err(err) => {
+                        result = Err(err);
+                        break
+                    },
+                },
+            };
+            let relay = Relay{
+                sink: self.sink.clone(),
+                sink_dest: self.sink_dest.clone(),
+                dead_letter: self.dead_letter.clone(),
+                retry: self.retry.clone(),
+                stats: self.stats.clone(),
+                observer: observer::or_global(&self.observer),
+            };
+            tokio::spawn(async move {
+                relay.relay(delivery).await;
+                drop(permit);
+            });
+        }
+        // wait for in-flight relays by taking every permit
+        let _ = permits.acquire_many(self.concurrency as u32).await;
+        result
+    }
+}
+
+
+/// Everything needed to relay one message, cloned into its task
+struct Relay {
+    sink: Arc<dyn Publisher>,
+    sink_dest: Destination,
+    dead_letter: Option<Arc<dyn DeadLetterSink>>,
+    retry: RetryPolicy,
+    stats: Arc<BridgeStats>,
+    observer: Arc<dyn EventfulObserver>,
+}
+
+impl Relay {
+    async fn relay(&self, delivery: Delivery) {
+        match self.sink.publish_bytes(&self.sink_dest, delivery.body.clone(), &delivery.meta).await {
+            Ok(_) => {
+                self.stats.relayed.fetch_add(1, Ordering::Relaxed);
+                let _ = delivery.ack().await;
+            },
+            Err(err) => {
+                self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                self.retry_or_dead_letter(delivery, err).await;
+            },
+        }
+    }
+
+    async fn retry_or_dead_letter(&self, delivery: Delivery, err: EventfulError) {
+        let retry_delay = self.retry.delay_for(delivery.attempt);
+        if delivery.attempt < self.retry.max_attempts {
+            let _ = delivery.nack(retry_delay).await;
+            return
+        }
+        self.observer.publish_failed(&PublishFailed{
+            dest: self.sink_dest.clone(),
+            attempts: delivery.attempt,
+            error: err.to_string(),
+        });
+        match &self.dead_letter {
+            Some(sink) => match sink.send(DeadLetterRecord::new(&delivery, &err)).await {
+                Ok(_) => {
+                    self.stats.dead_lettered.fetch_add(1, Ordering::Relaxed);
+                    self.observer.dead_lettered(&DeadLettered{
+                        source: delivery.source.clone(),
+                        message_id: delivery.message_id.clone(),
+                        attempts: delivery.attempt,
+                        error: err.to_string(),
+                    });
+                    let _ = delivery.ack().await;
+                },
+                Err(_) => {
+                    let _ = delivery.nack(retry_delay).await;
+                },
+            },
+            // with nowhere to put it, leave the message at the source rather than lose it
+            None => {
+                let _ = delivery.nack(retry_delay).await;
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::VecDeque, sync::Mutex};
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use crate::deadletter::NsqTopicSink;
+    use crate::memory::MemoryBroker;
+    use crate::publisher::Metadata;
+    use crate::subscriber::Ack;
+
+    /// Records how each delivery was settled
+    #[derive(Clone, Default)]
+    struct Settled(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Settled {
+        fn calls(&self) -> Vec<&'static str> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    struct RecordingAck(Settled);
+
+    #[async_trait]
+    impl Ack for RecordingAck {
+        async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+            self.0.0.lock().unwrap().push("ack");
+            Ok(())
+        }
+
+        async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+            self.0.0.lock().unwrap().push("nack");
+            Ok(())
+        }
+    }
+
+    /// A source which yields its scripted results, then ends
+    struct Scripted(VecDeque<Result<Delivery, EventfulError>>);
+
+    #[async_trait]
+    impl Subscriber for Scripted {
+        async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+            self.0.pop_front().transpose()
+        }
+    }
+
+    fn delivery(body: &'static str, attempt: u32, settled: &Settled) -> Delivery {
+        let source = Destination::SqsQueue("https://sqs.us-east-1.amazonaws.com/123456789012/in".to_string());
+        Delivery::new(source, Bytes::from_static(body.as_bytes()), Metadata::default(), None, attempt, Box::new(RecordingAck(settled.clone())))
+    }
+
+    /// Publishes to a MemoryBroker after a delay
+    struct SlowSink {
+        broker: MemoryBroker,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl Publisher for SlowSink {
+        async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<crate::publisher::Receipt, EventfulError> {
+            tokio::time::sleep(self.delay).await;
+            self.broker.publish_bytes(dest, body, meta).await
+        }
+    }
+
+    fn out() -> Destination {
+        Destination::NsqTopic("out".to_string())
+    }
+
+    #[tokio::test]
+    async fn relays_and_acks_until_the_source_ends() {
+        let (sink, settled) = (MemoryBroker::new(), Settled::default());
+        let source = Scripted(VecDeque::from(vec![Ok(delivery("a", 1, &settled)), Ok(delivery("b", 1, &settled))]));
+        let bridge = Bridge::new(Box::new(source), Arc::new(sink.clone()), out()).concurrency(2);
+        let stats = bridge.stats();
+        bridge.run().await.unwrap();
+        assert_eq!(sink.published_to("out").len(), 2);
+        assert_eq!(settled.calls(), vec!["ack", "ack"]);
+        assert_eq!(stats.snapshot().relayed, 2);
+    }
+
+    #[tokio::test]
+    async fn source_error_waits_for_relays_in_flight() {
+        let (broker, settled) = (MemoryBroker::new(), Settled::default());
+        let source = Scripted(VecDeque::from(vec![
+            Ok(delivery("a", 1, &settled)),
+            Err(EventfulError::Http("connection reset".to_string())),
+        ]));
+        let sink = SlowSink{broker: broker.clone(), delay: Duration::from_millis(100)};
+        let bridge = Bridge::new(Box::new(source), Arc::new(sink), out()).concurrency(4);
+        assert!(matches!(bridge.run_until(std::future::pending()).await, Err(EventfulError::Http(_))));
+        // the relay spawned before the error finished before run_until returned
+        assert_eq!(broker.published_to("out"), vec![Bytes::from_static(b"a")]);
+        assert_eq!(settled.calls(), vec!["ack"]);
+    }
+
+    #[tokio::test]
+    async fn failed_publish_is_nacked_then_dead_lettered() {
+        let (sink, dead_letters, settled) = (MemoryBroker::new(), MemoryBroker::new(), Settled::default());
+        sink.fail_next_publish();
+        sink.fail_next_publish();
+        let source = Scripted(VecDeque::from(vec![Ok(delivery("a", 1, &settled)), Ok(delivery("b", 3, &settled))]));
+        let bridge = Bridge::new(Box::new(source), Arc::new(sink.clone()), out())
+            .retry(RetryPolicy::default().max_attempts(3))
+            .dead_letter(Arc::new(NsqTopicSink::new(Arc::new(dead_letters.clone()), "out_dlq")));
+        let stats = bridge.stats();
+        bridge.run().await.unwrap();
+        assert!(sink.published_to("out").is_empty());
+        assert_eq!(dead_letters.published_to("out_dlq").len(), 1);
+        // the first attempt goes back to the source, the last one is acked once dead-lettered
+        assert_eq!(settled.calls(), vec!["nack", "ack"]);
+        assert_eq!(stats.snapshot(), BridgeStatsSnapshot{relayed: 0, failed: 2, dead_lettered: 1});
+    }
+}
+
+
+/// Needs localstack (LOCALSTACK_ENDPOINT, default http://localhost:4566) and docker for nsqd
+#[cfg(all(test, feature = "testcontainers", feature = "sqs"))]
+mod integration {
+    use super::*;
+    use bytes::Bytes;
+    use crate::publisher::Metadata;
+    use crate::testing::{LocalstackSqs, NsqContainer};
+
+    #[tokio::test]
+    async fn relays_from_localstack_sqs_to_nsqd() {
+        let localstack = LocalstackSqs::from_env().await.unwrap();
+        let queue = localstack.create_temp_queue("bridge").await.unwrap();
+        let nsqd = NsqContainer::builder().with_topic("from_sqs").start().await.unwrap();
+        let mut relayed = NsqSource::new("from_sqs", "test", vec![nsqd.daemon()]).subscribe().unwrap();
+
+        let body = Bytes::from_static(br#"{"id":1}"#);
+        localstack.client().publish_bytes(&Destination::SqsQueue(queue.url().to_string()), body.clone(), &Metadata::default()).await.unwrap();
+        let source = SqsSource{wait_time_seconds: 1, ..SqsSource::new(localstack.client().clone(), queue.url())};
+        let bridge = Bridge::new(source.subscribe(), Arc::new(nsqd.daemon()), Destination::NsqTopic("from_sqs".to_string()));
+        let stats = bridge.stats();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(bridge.run_until(async { let _ = stopped.await; }));
+
+        let delivery = tokio::time::timeout(Duration::from_secs(30), relayed.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(delivery.body, body);
+        delivery.ack().await.unwrap();
+        let _ = stop.send(());
+        running.await.unwrap().unwrap();
+        assert_eq!(stats.snapshot().relayed, 1);
+        let queue_stats = localstack.client().queue_stats(queue.url()).await.unwrap();
+        assert_eq!((queue_stats.visible, queue_stats.not_visible), (0, 0));
+    }
+}
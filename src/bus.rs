@@ -0,0 +1,390 @@
+//! The bus module decouples event types from where they are sent.  
+//! Rather than baking a topic or queue into each type, an EventBus holds a routing table
+//! mapping event types to one or more Destinations, and one Publisher per Backend.
+//! 
+//! # Examples:
+//! ```no_run
+//! # #[cfg(all(feature = "nsq", feature = "sqs"))]
+//! # mod example {
+//! # use std::{sync::Arc, time::Duration};
+//! # use eventful::prelude::*;
+//! # use eventful::bus::{Backend, EventBus};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct UserClickedSomething { user_id: i32 }
+//! # #[derive(Serialize, Deserialize)]
+//! # struct OrderPlaced { order_id: u64 }
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Reminder { order_id: u64 }
+//! # async fn demo(orders_url: String, click: UserClickedSomething, reminder: Reminder) -> Result<(), EventfulError> {
+//! let bus = EventBus::new()
+//!     .publisher(Backend::Nsq, Arc::new(FleetNSQ::new_from_env()))
+//!     .publisher(Backend::Sqs, Arc::new(ClientSQS::new_with_region("us-east-1").await))
+//!     .route::<UserClickedSomething>(Destination::NsqTopic("click".to_string()))
+//!     .route::<OrderPlaced>(Destination::SqsQueue(orders_url))
+//!     .require::<OrderPlaced>();
+//! bus.validate()?;
+//! bus.emit(&click).await?;
+//! bus.emit_after(&reminder, Duration::from_secs(300)).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use serde::{Serialize, Deserialize};
+use crate::codec::{Codec, JsonCodec};
+use crate::err::EventfulError;
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+
+
+/// The kind of message bus a Destination lives on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Backend {
+    Nsq,
+    Sqs,
+    Kafka,
+    Redis,
+    Nats,
+    Kinesis,
+    Sns,
+    Postgres,
+    Mqtt,
+    EventBridge,
+}
+
+impl Destination {
+    /// The Backend which can deliver to this destination
+    pub fn backend(&self) -> Backend {
+        match self {
+            Destination::NsqTopic(_) => Backend::Nsq,
+            Destination::SqsQueue(_) => Backend::Sqs,
+            Destination::KafkaTopic(_) => Backend::Kafka,
+            Destination::RedisStream(_) => Backend::Redis,
+            Destination::NatsSubject(_) => Backend::Nats,
+            Destination::KinesisStream(_) => Backend::Kinesis,
+            Destination::SnsTopic(_) => Backend::Sns,
+            Destination::PgChannel(_) | Destination::PgQueue(_) => Backend::Postgres,
+            Destination::MqttTopic(_) => Backend::Mqtt,
+            Destination::EventBus(_) => Backend::EventBridge,
+        }
+    }
+}
+
+
+/// Events are routed by name. The name of a type is the last segment of its path,
+/// i.e. `my_crate::events::UserClickedSomething` is routed as "UserClickedSomething".
+/// Two types with the same name can't both be routed by one EventBus; validate() reports it
+pub fn event_name<T: ?Sized>() -> &'static str {
+    let path = type_path::<T>();
+    match path.rsplit_once("::") {
+        Some((_, name)) => name,
+        None => path,
+    }
+}
+
+/// The full path of a type, without generic parameters
+fn type_path<T: ?Sized>() -> &'static str {
+    let full = std::any::type_name::<T>();
+    full.split('<').next().unwrap_or(full)
+}
+
+
+/// A RoutingConfig can be deserialized from a config file and loaded into an EventBus.
+/// # Examples:
+/// ```json
+/// {
+///     "default": {"NsqTopic": "misc_events"},
+///     "routes": {
+///         "UserClickedSomething": [{"NsqTopic": "click"}],
+///         "OrderPlaced": [{"NsqTopic": "orders"}, {"SqsQueue": "https://sqs.us-east-1.amazonaws.com/123456789012/orders"}]
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub default: Option<Destination>,
+    #[serde(default)]
+    pub routes: HashMap<String, Vec<Destination>>,
+}
+
+
+/// The EventBus looks up where an event should go, encodes it, and publishes it
+#[derive(Clone, Default)]
+pub struct EventBus {
+    publishers: HashMap<Backend, Arc<dyn Publisher>>,
+    routes: HashMap<String, Vec<Destination>>,
+    default_route: Option<Destination>,
+    required: Vec<&'static str>,
+    /// The full path of the type which claimed each name through route() or require()
+    types: HashMap<&'static str, &'static str>,
+    /// Names claimed by more than one type
+    collisions: Vec<String>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    /// Create an EventBus from a RoutingConfig. Publishers still need to be added
+    pub fn from_config(config: RoutingConfig) -> Self {
+        EventBus{routes: config.routes, default_route: config.default, ..Default::default()}
+    }
+
+    /// Set the publisher used for every destination on a given backend
+    pub fn publisher(mut self, backend: Backend, publisher: Arc<dyn Publisher>) -> Self {
+        self.publishers.insert(backend, publisher);
+        self
+    }
+
+    /// Route events of type T to a destination. Calling this more than once for the same type
+    /// sends each event to every destination given
+    pub fn route<T: ?Sized>(mut self, dest: Destination) -> Self {
+        let name = self.claim::<T>();
+        self.route_named(name, dest)
+    }
+
+    /// Route events by name, i.e. when the routing table comes from configuration
+    pub fn route_named(mut self, name: &str, dest: Destination) -> Self {
+        self.routes.entry(name.to_string()).or_default().push(dest);
+        self
+    }
+
+    /// Send any event without a route of its own to this destination
+    pub fn default_route(mut self, dest: Destination) -> Self {
+        self.default_route = Some(dest);
+        self
+    }
+
+    /// Declare that events of type T will be emitted, so validate() fails if T has no route
+    pub fn require<T: ?Sized>(mut self) -> Self {
+        let name = self.claim::<T>();
+        self.required.push(name);
+        self
+    }
+
+    /// Record that T is routed by its name, noting a collision if another type already claimed the name
+    fn claim<T: ?Sized>(&mut self) -> &'static str {
+        let (name, path) = (event_name::<T>(), type_path::<T>());
+        match self.types.get(name) {
+            Some(other) if *other != path => {
+                let collision = format!("'{}' is the name of both {} and {}", name, other, path);
+                if !self.collisions.contains(&collision) {
+                    self.collisions.push(collision);
+                }
+            },
+            Some(_) => {},
+            None => {
+                self.types.insert(name, path);
+            },
+        }
+        name
+    }
+
+    /// The name T is routed by, unless a different type with the same name claimed it
+    fn name_of<T: ?Sized>(&self) -> Result<&'static str, EventfulError> {
+        let (name, path) = (event_name::<T>(), type_path::<T>());
+        match self.types.get(name) {
+            Some(other) if *other != path => Err(EventfulError::Config(format!("{} can't be routed as '{}', which is the name of {}", path, name, other))),
+            _ => Ok(name),
+        }
+    }
+
+    /// Return the destinations an event with the given name would be sent to
+    pub fn destinations_for(&self, name: &str) -> Result<Vec<Destination>, EventfulError> {
+        if let Some(dests) = self.routes.get(name) {
+            if !dests.is_empty() {
+                return Ok(dests.clone())
+            }
+        }
+        match &self.default_route {
+            Some(dest) => Ok(vec![dest.clone()]),
+            None => Err(EventfulError::Unrouted(name.to_string())),
+        }
+    }
+
+    /// Check that every required event has a route and that every route has a publisher.
+    /// Call this at startup so misconfigurations fail fast rather than on the first emit
+    pub fn validate(&self) -> Result<(), EventfulError> {
+        let mut problems = self.collisions.clone();
+        for name in &self.required {
+            if self.destinations_for(name).is_err() {
+                problems.push(format!("no route for event '{}'", name));
+            }
+        }
+        let all_dests = self.routes.iter()
+            .flat_map(|(name, dests)| dests.iter().map(move |dest| (name.as_str(), dest)))
+            .chain(self.default_route.iter().map(|dest| ("<default>", dest)));
+        for (name, dest) in all_dests {
+            if !self.publishers.contains_key(&dest.backend()) {
+                problems.push(format!("route '{}' -> {} has no {:?} publisher", name, dest, dest.backend()));
+            }
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(EventfulError::Config(problems.join("; "))),
+        }
+    }
+
+    /// Encode an event and publish it to every destination it is routed to
+    pub async fn emit<T: Serialize + Sync>(&self, event: &T) -> Result<Vec<Receipt>, EventfulError> {
+        self.emit_with(event, &Metadata::default()).await
+    }
+
+    /// Encode an event and publish it, along with some metadata, to every destination it is routed to
+    pub async fn emit_with<T: Serialize + Sync>(&self, event: &T, meta: &Metadata) -> Result<Vec<Receipt>, EventfulError> {
+        let dests = self.destinations_for(self.name_of::<T>()?)?;
+        let body = JsonCodec.encode(event)?;
+        let mut receipts = Vec::with_capacity(dests.len());
+        for dest in &dests {
+            let publisher = self.publishers.get(&dest.backend())
+                .ok_or(EventfulError::Config(format!("no {:?} publisher for {}", dest.backend(), dest)))?;
+            receipts.push(publisher.publish_bytes(dest, body.clone(), meta).await?);
+        }
+        Ok(receipts)
+    }
+
+    /// Like emit(), but consumers don't receive the event until the delay has passed.
+    /// Fails with DelayUnsupported or DelayTooLong (before publishing anywhere) if any destination's publisher can't honor the delay;
+    /// wrap a publisher in a delay::RedeferPublisher to go past its backend's limit
+    pub async fn emit_after<T: Serialize + Sync>(&self, event: &T, delay: Duration) -> Result<Vec<Receipt>, EventfulError> {
+        self.emit_after_with(event, &Metadata::default(), delay).await
+    }
+
+    /// Like emit_with(), but consumers don't receive the event until the delay has passed
+    pub async fn emit_after_with<T: Serialize + Sync>(&self, event: &T, meta: &Metadata, delay: Duration) -> Result<Vec<Receipt>, EventfulError> {
+        let dests = self.destinations_for(self.name_of::<T>()?)?;
+        let body = JsonCodec.encode(event)?;
+        let mut targets = Vec::with_capacity(dests.len());
+        for dest in &dests {
+            let publisher = self.publishers.get(&dest.backend())
+                .ok_or(EventfulError::Config(format!("no {:?} publisher for {}", dest.backend(), dest)))?;
+            if !delay.is_zero() {
+                publisher::check_delay(publisher.max_delay(), dest, delay)?;
+            }
+            targets.push((dest, publisher));
+        }
+        let mut receipts = Vec::with_capacity(targets.len());
+        for (dest, publisher) in targets {
+            receipts.push(publisher.publish_delayed(dest, body.clone(), meta, delay).await?);
+        }
+        Ok(receipts)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use crate::memory::MemoryBroker;
+
+    #[derive(Serialize)]
+    struct Clicked {
+        id: u32,
+    }
+
+    #[derive(Serialize)]
+    struct OrderPlaced {
+        id: u32,
+    }
+
+    mod legacy {
+        #[derive(serde::Serialize)]
+        pub struct Clicked {
+            pub id: u32,
+        }
+    }
+
+    fn new_bus(broker: &MemoryBroker) -> EventBus {
+        EventBus::new()
+            .publisher(Backend::Nsq, Arc::new(broker.clone()))
+            .publisher(Backend::Sqs, Arc::new(broker.clone()))
+    }
+
+    #[tokio::test]
+    async fn unrouted_event_is_an_error() {
+        let broker = MemoryBroker::new();
+        let bus = new_bus(&broker).route::<Clicked>(Destination::NsqTopic("click".to_string()));
+        match bus.emit(&OrderPlaced{id: 1}).await {
+            Err(EventfulError::Unrouted(name)) => assert_eq!(name, "OrderPlaced"),
+            other => panic!("expected Unrouted, got {:?}", other),
+        }
+        assert!(broker.published().is_empty());
+    }
+
+    #[test]
+    fn validate_fails_for_required_event_without_route() {
+        let broker = MemoryBroker::new();
+        let bus = new_bus(&broker)
+            .route::<Clicked>(Destination::NsqTopic("click".to_string()))
+            .require::<Clicked>()
+            .require::<OrderPlaced>();
+        match bus.validate() {
+            Err(EventfulError::Config(problems)) => assert!(problems.contains("no route for event 'OrderPlaced'"), "{}", problems),
+            other => panic!("expected Config, got {:?}", other),
+        }
+        assert!(bus.default_route(Destination::NsqTopic("misc".to_string())).validate().is_ok());
+    }
+
+    #[test]
+    fn validate_fails_for_route_without_publisher() {
+        let bus = EventBus::new()
+            .publisher(Backend::Nsq, Arc::new(MemoryBroker::new()))
+            .route::<OrderPlaced>(Destination::KafkaTopic("orders".to_string()));
+        assert!(matches!(bus.validate(), Err(EventfulError::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn default_route_catches_unrouted_events() {
+        let broker = MemoryBroker::new();
+        let bus = new_bus(&broker)
+            .route::<Clicked>(Destination::NsqTopic("click".to_string()))
+            .default_route(Destination::NsqTopic("misc".to_string()));
+        bus.emit(&OrderPlaced{id: 1}).await.unwrap();
+        bus.emit(&Clicked{id: 2}).await.unwrap();
+        assert_eq!(broker.published_to("misc"), vec![Bytes::from(r#"{"id":1}"#)]);
+        assert_eq!(broker.published_to("click"), vec![Bytes::from(r#"{"id":2}"#)]);
+    }
+
+    #[tokio::test]
+    async fn event_is_sent_to_every_destination() {
+        let broker = MemoryBroker::new();
+        let queue = "https://sqs.us-east-1.amazonaws.com/123456789012/orders".to_string();
+        let bus = new_bus(&broker)
+            .route::<OrderPlaced>(Destination::NsqTopic("orders".to_string()))
+            .route::<OrderPlaced>(Destination::SqsQueue(queue.clone()));
+        bus.validate().unwrap();
+        let receipts = bus.emit(&OrderPlaced{id: 7}).await.unwrap();
+        assert_eq!(receipts.len(), 2);
+        let dests: Vec<Destination> = broker.published().into_iter().map(|(dest, _)| dest).collect();
+        assert_eq!(dests, vec![Destination::NsqTopic("orders".to_string()), Destination::SqsQueue(queue)]);
+    }
+
+    #[tokio::test]
+    async fn config_routes_by_name() {
+        let broker = MemoryBroker::new();
+        let config: RoutingConfig = serde_json::from_str(r#"{"routes": {"OrderPlaced": [{"NsqTopic": "orders"}, {"NsqTopic": "audit"}]}}"#).unwrap();
+        let bus = EventBus::from_config(config).publisher(Backend::Nsq, Arc::new(broker.clone()));
+        bus.emit(&OrderPlaced{id: 7}).await.unwrap();
+        assert_eq!(broker.published_to("orders").len(), 1);
+        assert_eq!(broker.published_to("audit").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn types_with_the_same_name_collide() {
+        let broker = MemoryBroker::new();
+        let bus = new_bus(&broker)
+            .route::<Clicked>(Destination::NsqTopic("click".to_string()))
+            .route::<legacy::Clicked>(Destination::NsqTopic("legacy_click".to_string()));
+        match bus.validate() {
+            Err(EventfulError::Config(problems)) => assert!(problems.contains("'Clicked' is the name of both"), "{}", problems),
+            other => panic!("expected Config, got {:?}", other),
+        }
+
+        let bus = new_bus(&broker).route::<Clicked>(Destination::NsqTopic("click".to_string()));
+        assert!(matches!(bus.emit(&legacy::Clicked{id: 1}).await, Err(EventfulError::Config(_))));
+        assert!(broker.published().is_empty());
+    }
+}
@@ -0,0 +1,481 @@
+//! The circuit module stops publishing to a destination which is failing, rather than piling more requests onto it,
+//! i.e. an SQS queue during an AWS incident or an nsqd which is down.
+//!
+//! CircuitBreaker wraps any Publisher and keeps a circuit per destination:
+//!
+//! - Closed: publishes go through. Once at least min_requests publishes have finished within the window and the fraction
+//!   of them which failed reaches failure_rate, the circuit opens.
+//! - Open: publishes fail immediately with EventfulError::CircuitOpen, until open_for has passed.
+//! - HalfOpen: up to half_open_probes publishes go through as probes. If that many succeed the circuit closes;
+//!   if any fails it opens again.
+//!
+//! Only retryable errors count as failures, so i.e. an unsupported destination never opens a circuit.
+//! Every transition is reported to the observer's circuit_changed callback.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "sqs")]
+//! # mod example {
+//! # use std::time::Duration;
+//! # use eventful::prelude::*;
+//! # use eventful::circuit::CircuitBreaker;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct OrderPlaced { order_id: u64 }
+//! # struct Outbox;
+//! # impl Outbox { async fn defer(&self, _order: OrderPlaced, _after: Duration) -> Result<(), EventfulError> { Ok(()) } }
+//! # async fn demo(sqs: ClientSQS, dest: Destination, order: OrderPlaced, outbox: Outbox) -> Result<(), EventfulError> {
+//! let publisher = CircuitBreaker::new(sqs)
+//!     .failure_rate(0.5)
+//!     .window(Duration::from_secs(30))
+//!     .open_for(Duration::from_secs(15));
+//! match publisher.publish_event(&dest, &order).await {
+//!     Err(EventfulError::CircuitOpen{retry_after, ..}) => outbox.defer(order, retry_after).await?,
+//!     other => { other?; },
+//! }
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use async_trait::async_trait;
+use bytes::Bytes;
+use crate::err::EventfulError;
+use crate::observer::{self, CircuitChanged, EventfulObserver};
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+
+
+/// The state of one destination's circuit
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+
+type Clock = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+struct Circuit {
+    state: CircuitState,
+    /// When each publish finished while closed, and whether it succeeded
+    outcomes: VecDeque<(Instant, bool)>,
+    opened_at: Instant,
+    probes_in_flight: u32,
+    probe_successes: u32,
+}
+
+impl Circuit {
+    fn new(now: Instant) -> Self {
+        Circuit{state: CircuitState::Closed, outcomes: VecDeque::new(), opened_at: now, probes_in_flight: 0, probe_successes: 0}
+    }
+}
+
+
+/// A CircuitBreaker is a Publisher which forwards to an inner publisher while each destination's circuit allows it
+pub struct CircuitBreaker<P> {
+    inner: P,
+    failure_rate: f64,
+    window: Duration,
+    min_requests: usize,
+    open_for: Duration,
+    half_open_probes: u32,
+    observer: Option<Arc<dyn EventfulObserver>>,
+    clock: Clock,
+    circuits: Mutex<HashMap<Destination, Circuit>>,
+}
+
+impl<P: Publisher> CircuitBreaker<P> {
+    /// Open when half of at least 10 publishes within 30 seconds fail, stay open for 30 seconds, and close after 1 successful probe
+    pub fn new(inner: P) -> Self {
+        CircuitBreaker{
+            inner,
+            failure_rate: 0.5,
+            window: Duration::from_secs(30),
+            min_requests: 10,
+            open_for: Duration::from_secs(30),
+            half_open_probes: 1,
+            observer: None,
+            clock: Arc::new(Instant::now),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// The fraction (0.0 to 1.0) of publishes within the window which must fail to open the circuit (default 0.5)
+    pub fn failure_rate(mut self, rate: f64) -> Self {
+        self.failure_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// How far back publishes count towards the failure rate (default 30 seconds)
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// The circuit can't open until this many publishes have finished within the window (default 10)
+    pub fn min_requests(mut self, min: usize) -> Self {
+        self.min_requests = min.max(1);
+        self
+    }
+
+    /// How long an open circuit rejects publishes before letting probes through (default 30 seconds)
+    pub fn open_for(mut self, duration: Duration) -> Self {
+        self.open_for = duration;
+        self
+    }
+
+    /// How many probes a half-open circuit lets through at once, all of which must succeed to close it (default 1)
+    pub fn half_open_probes(mut self, probes: u32) -> Self {
+        self.half_open_probes = probes.max(1);
+        self
+    }
+
+    /// Report transitions to this observer instead of the global one
+    pub fn observer(mut self, observer: Arc<dyn EventfulObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Read the time from this function instead of Instant::now, i.e. a manually advanced clock in tests
+    pub fn clock<F: Fn() -> Instant + Send + Sync + 'static>(mut self, clock: F) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The state of a destination's circuit. An open circuit whose open_for has passed is reported as HalfOpen
+    pub fn state(&self, dest: &Destination) -> CircuitState {
+        let now = (self.clock)();
+        match self.circuits.lock().unwrap().get(dest) {
+            Some(circuit) if circuit.state == CircuitState::Open && now >= circuit.opened_at + self.open_for => CircuitState::HalfOpen,
+            Some(circuit) => circuit.state,
+            None => CircuitState::Closed,
+        }
+    }
+
+    /// Decide whether a publish may go ahead, returning whether it is a probe
+    fn admit(&self, dest: &Destination) -> Result<bool, EventfulError> {
+        let now = (self.clock)();
+        let mut transition = None;
+        let admitted = {
+            let mut circuits = self.circuits.lock().unwrap();
+            let circuit = circuits.entry(dest.clone()).or_insert_with(|| Circuit::new(now));
+            if circuit.state == CircuitState::Open {
+                let reopens_at = circuit.opened_at + self.open_for;
+                if now < reopens_at {
+                    return Err(EventfulError::CircuitOpen{destination: dest.to_string(), retry_after: reopens_at - now})
+                }
+                circuit.state = CircuitState::HalfOpen;
+                circuit.probes_in_flight = 0;
+                circuit.probe_successes = 0;
+                transition = Some((CircuitState::Open, CircuitState::HalfOpen));
+            }
+            match circuit.state {
+                CircuitState::HalfOpen if circuit.probes_in_flight >= self.half_open_probes => {
+                    // every probe slot is taken; the answer is coming soon
+                    Err(EventfulError::CircuitOpen{destination: dest.to_string(), retry_after: Duration::ZERO})
+                },
+                CircuitState::HalfOpen => {
+                    circuit.probes_in_flight += 1;
+                    Ok(true)
+                },
+                _ => Ok(false),
+            }
+        };
+        self.report(dest, transition);
+        admitted
+    }
+
+    /// Record how a publish went, opening or closing the circuit as needed
+    fn record(&self, dest: &Destination, probe: bool, success: bool) {
+        let now = (self.clock)();
+        let mut transition = None;
+        {
+            let mut circuits = self.circuits.lock().unwrap();
+            let circuit = circuits.entry(dest.clone()).or_insert_with(|| Circuit::new(now));
+            match (circuit.state, probe) {
+                (CircuitState::HalfOpen, true) => {
+                    circuit.probes_in_flight = circuit.probes_in_flight.saturating_sub(1);
+                    if !success {
+                        circuit.state = CircuitState::Open;
+                        circuit.opened_at = now;
+                        transition = Some((CircuitState::HalfOpen, CircuitState::Open));
+                    } else {
+                        circuit.probe_successes += 1;
+                        if circuit.probe_successes >= self.half_open_probes {
+                            circuit.state = CircuitState::Closed;
+                            circuit.outcomes.clear();
+                            transition = Some((CircuitState::HalfOpen, CircuitState::Closed));
+                        }
+                    }
+                },
+                (CircuitState::Closed, false) => {
+                    circuit.outcomes.push_back((now, success));
+                    while circuit.outcomes.front().map(|(at, _)| now.duration_since(*at) > self.window).unwrap_or(false) {
+                        circuit.outcomes.pop_front();
+                    }
+                    let failures = circuit.outcomes.iter().filter(|(_, success)| !success).count();
+                    let total = circuit.outcomes.len();
+                    if total >= self.min_requests && failures as f64 >= self.failure_rate * total as f64 && failures > 0 {
+                        circuit.state = CircuitState::Open;
+                        circuit.opened_at = now;
+                        circuit.outcomes.clear();
+                        transition = Some((CircuitState::Closed, CircuitState::Open));
+                    }
+                },
+                // a publish admitted before the circuit changed state has nothing to say about the new state
+                _ => {},
+            }
+        }
+        self.report(dest, transition);
+    }
+
+    fn report(&self, dest: &Destination, transition: Option<(CircuitState, CircuitState)>) {
+        if let Some((from, to)) = transition {
+            observer::or_global(&self.observer).circuit_changed(&CircuitChanged{dest: dest.clone(), from, to});
+        }
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for CircuitBreaker<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let probe = self.admit(dest)?;
+        let result = self.inner.publish_bytes(dest, body, meta).await;
+        let failed = matches!(&result, Err(err) if err.is_retryable());
+        self.record(dest, probe, !failed);
+        result
+    }
+
+    fn max_delay(&self) -> Option<Duration> {
+        self.inner.max_delay()
+    }
+
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        let probe = self.admit(dest)?;
+        let result = self.inner.publish_delayed(dest, body, meta, delay).await;
+        let failed = matches!(&result, Err(err) if err.is_retryable());
+        self.record(dest, probe, !failed);
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBroker;
+
+    /// A clock which only moves when told to
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<Instant>>);
+
+    impl ManualClock {
+        fn new() -> Self {
+            ManualClock(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[derive(Default)]
+    struct Transitions(Mutex<Vec<(CircuitState, CircuitState)>>);
+
+    impl EventfulObserver for Transitions {
+        fn circuit_changed(&self, ctx: &CircuitChanged) {
+            self.0.lock().unwrap().push((ctx.from, ctx.to));
+        }
+    }
+
+    impl Transitions {
+        fn seen(&self) -> Vec<(CircuitState, CircuitState)> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    /// Fails every publish with an error which isn't worth retrying
+    struct Rejecting;
+
+    #[async_trait]
+    impl Publisher for Rejecting {
+        async fn publish_bytes(&self, dest: &Destination, _body: Bytes, _meta: &Metadata) -> Result<Receipt, EventfulError> {
+            Err(EventfulError::Destination(format!("{} is not allowed", dest)))
+        }
+    }
+
+    use CircuitState::{Closed, HalfOpen, Open};
+
+    fn orders() -> Destination {
+        Destination::SqsQueue("orders".to_string())
+    }
+
+    fn breaker<P: Publisher>(inner: P, clock: &ManualClock, transitions: &Arc<Transitions>) -> CircuitBreaker<P> {
+        let clock = clock.clone();
+        CircuitBreaker::new(inner)
+            .failure_rate(0.5)
+            .min_requests(4)
+            .window(Duration::from_secs(10))
+            .open_for(Duration::from_secs(5))
+            .observer(transitions.clone())
+            .clock(move || clock.now())
+    }
+
+    async fn publish<P: Publisher>(breaker: &CircuitBreaker<P>, dest: &Destination) -> Result<Receipt, EventfulError> {
+        let meta = Metadata::default();
+        breaker.publish_bytes(dest, Bytes::from_static(b"{}"), &meta).await
+    }
+
+    /// Publish `failures` failures then `successes` successes
+    async fn outcomes(broker: &MemoryBroker, breaker: &CircuitBreaker<MemoryBroker>, failures: usize, successes: usize) {
+        for _ in 0..failures {
+            broker.fail_next_publish();
+            assert!(matches!(publish(breaker, &orders()).await, Err(EventfulError::Http(_))));
+        }
+        for _ in 0..successes {
+            publish(breaker, &orders()).await.unwrap();
+        }
+    }
+
+    /// Open the orders circuit with 4 failures
+    async fn open(broker: &MemoryBroker, breaker: &CircuitBreaker<MemoryBroker>) {
+        outcomes(broker, breaker, 4, 0).await;
+        assert_eq!(breaker.state(&orders()), Open);
+    }
+
+    #[tokio::test]
+    async fn opens_once_the_failure_rate_is_reached() {
+        let (broker, clock, transitions) = (MemoryBroker::new(), ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(broker.clone(), &clock, &transitions);
+        outcomes(&broker, &breaker, 1, 2).await;
+        assert_eq!(breaker.state(&orders()), Closed);
+        // 2 of 4 is the failure rate
+        outcomes(&broker, &breaker, 1, 0).await;
+        assert_eq!(breaker.state(&orders()), Open);
+        assert_eq!(transitions.seen(), vec![(Closed, Open)]);
+
+        clock.advance(Duration::from_secs(2));
+        match publish(&breaker, &orders()).await {
+            Err(EventfulError::CircuitOpen{destination, retry_after}) => {
+                assert_eq!(destination, orders().to_string());
+                assert_eq!(retry_after, Duration::from_secs(3));
+            },
+            other => panic!("expected CircuitOpen, got {:?}", other),
+        }
+        // the rejected publish never reached the broker
+        assert_eq!(broker.published_to("orders").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stays_closed_below_min_requests() {
+        let (broker, clock, transitions) = (MemoryBroker::new(), ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(broker.clone(), &clock, &transitions);
+        outcomes(&broker, &breaker, 3, 0).await;
+        assert_eq!(breaker.state(&orders()), Closed);
+        assert!(transitions.seen().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failures_outside_the_window_are_forgotten() {
+        let (broker, clock, transitions) = (MemoryBroker::new(), ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(broker.clone(), &clock, &transitions);
+        outcomes(&broker, &breaker, 3, 0).await;
+        clock.advance(Duration::from_secs(11));
+        // 1 failure of 4 within the window
+        outcomes(&broker, &breaker, 1, 3).await;
+        assert_eq!(breaker.state(&orders()), Closed);
+        assert!(transitions.seen().is_empty());
+    }
+
+    #[tokio::test]
+    async fn errors_which_are_not_retryable_are_not_failures() {
+        let (clock, transitions) = (ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(Rejecting, &clock, &transitions);
+        for _ in 0..10 {
+            assert!(matches!(publish(&breaker, &orders()).await, Err(EventfulError::Destination(_))));
+        }
+        assert_eq!(breaker.state(&orders()), Closed);
+    }
+
+    #[tokio::test]
+    async fn a_successful_probe_closes_the_circuit() {
+        let (broker, clock, transitions) = (MemoryBroker::new(), ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(broker.clone(), &clock, &transitions);
+        open(&broker, &breaker).await;
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(breaker.state(&orders()), HalfOpen);
+        publish(&breaker, &orders()).await.unwrap();
+        assert_eq!(breaker.state(&orders()), Closed);
+        assert_eq!(transitions.seen(), vec![(Closed, Open), (Open, HalfOpen), (HalfOpen, Closed)]);
+        // closing forgets the failures which opened it
+        outcomes(&broker, &breaker, 3, 0).await;
+        assert_eq!(breaker.state(&orders()), Closed);
+    }
+
+    #[tokio::test]
+    async fn a_failed_probe_opens_the_circuit_again() {
+        let (broker, clock, transitions) = (MemoryBroker::new(), ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(broker.clone(), &clock, &transitions);
+        open(&broker, &breaker).await;
+        clock.advance(Duration::from_secs(6));
+        outcomes(&broker, &breaker, 1, 0).await;
+        assert_eq!(breaker.state(&orders()), Open);
+        assert_eq!(transitions.seen(), vec![(Closed, Open), (Open, HalfOpen), (HalfOpen, Open)]);
+        // open_for starts again from the failed probe
+        match publish(&breaker, &orders()).await {
+            Err(EventfulError::CircuitOpen{retry_after, ..}) => assert_eq!(retry_after, Duration::from_secs(5)),
+            other => panic!("expected CircuitOpen, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn half_open_admits_only_the_configured_probes() {
+        let (broker, clock, transitions) = (MemoryBroker::new(), ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(broker.clone(), &clock, &transitions).half_open_probes(2);
+        open(&broker, &breaker).await;
+        clock.advance(Duration::from_secs(5));
+        assert!(breaker.admit(&orders()).unwrap());
+        assert!(breaker.admit(&orders()).unwrap());
+        match breaker.admit(&orders()) {
+            Err(EventfulError::CircuitOpen{retry_after, ..}) => assert_eq!(retry_after, Duration::ZERO),
+            other => panic!("expected CircuitOpen, got {:?}", other),
+        }
+        breaker.record(&orders(), true, true);
+        assert_eq!(breaker.state(&orders()), HalfOpen);
+        breaker.record(&orders(), true, true);
+        assert_eq!(breaker.state(&orders()), Closed);
+        assert_eq!(transitions.seen(), vec![(Closed, Open), (Open, HalfOpen), (HalfOpen, Closed)]);
+    }
+
+    #[tokio::test]
+    async fn a_late_result_from_before_opening_is_ignored() {
+        let (broker, clock, transitions) = (MemoryBroker::new(), ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(broker.clone(), &clock, &transitions);
+        // admitted while closed, but only finished once the circuit was open
+        assert!(!breaker.admit(&orders()).unwrap());
+        open(&broker, &breaker).await;
+        breaker.record(&orders(), false, true);
+        assert_eq!(breaker.state(&orders()), Open);
+        assert_eq!(transitions.seen(), vec![(Closed, Open)]);
+    }
+
+    #[tokio::test]
+    async fn circuits_are_kept_per_destination() {
+        let (broker, clock, transitions) = (MemoryBroker::new(), ManualClock::new(), Arc::new(Transitions::default()));
+        let breaker = breaker(broker.clone(), &clock, &transitions);
+        open(&broker, &breaker).await;
+        let payments = Destination::SqsQueue("payments".to_string());
+        publish(&breaker, &payments).await.unwrap();
+        assert_eq!(breaker.state(&payments), Closed);
+        assert_eq!(broker.published_to("payments").len(), 1);
+    }
+}
@@ -0,0 +1,69 @@
+//! The codec module turns events into bytes and back again.
+//! Keeping encoding separate from transport lets the same event travel over NSQ, SQS, or anything else.
+
+use bytes::Bytes;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::err::EventfulError;
+
+
+/// A Codec knows how to encode a serializable struct into bytes and decode bytes back into a struct
+pub trait Codec: Send + Sync {
+    /// A MIME-style description of the encoding, i.e. "application/json"
+    fn content_type(&self) -> &'static str;
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Bytes, EventfulError>;
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, EventfulError>;
+}
+
+
+/// The JsonCodec is the default codec, and matches what EventNSQ and sqs::Event have always put on the wire
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Bytes, EventfulError> {
+        let body = serde_json::to_vec(value)?;
+        Ok(Bytes::from(body))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, EventfulError> {
+        let value: T = serde_json::from_slice(body)?;
+        Ok(value)
+    }
+}
+
+
+/// Serialize Bytes as a base64 string, so binary bodies survive a trip through JSON
+pub(crate) mod base64_bytes {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use bytes::Bytes;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let body = STANDARD.decode(encoded).map_err(serde::de::Error::custom)?;
+        Ok(Bytes::from(body))
+    }
+}
+
+
+/// Serialize a Duration as a whole number of milliseconds, which reads better than {secs, nanos} in config files
+pub(crate) mod duration_millis {
+    use std::time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis().min(u64::MAX as u128) as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+    }
+}
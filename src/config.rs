@@ -0,0 +1,487 @@
+//! The config module builds a service's whole messaging setup from one file instead of a pile of env vars.
+//! An EventfulConfig describes the NSQ fleet, SQS settings (region, an endpoint override for localstack, and queue
+//! names mapped to URLs), default consumer options, named retry policies, and a topic prefix. It is read from TOML,
+//! or from YAML with the "yaml" feature, and build() turns it into a FleetNSQ, a ClientSQS, and the defaults.
+//!
+//! Before parsing, `${VAR}` anywhere in the text (except comments) is replaced with the environment variable VAR,
+//! and `${VAR:-default}` falls back to default when VAR is unset. Durations are whole milliseconds, as they are for Backoff.
+//! Errors from parsing and from validate() name the offending key path, i.e. "nsq.daemons[1].http_port".
+//! See examples/config/eventful.toml for a complete file.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(all(feature = "nsq", feature = "sqs"))]
+//! # mod example {
+//! # use eventful::prelude::*;
+//! # use eventful::config::EventfulConfig;
+//! # use eventful::consumer;
+//! # use eventful::sqs::SubscriptionSQS;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Order { id: u64 }
+//! # async fn handle_order(order: Order) -> Result<(), EventfulError> { Ok(()) }
+//! # async fn demo(order: Order) -> Result<(), EventfulError> {
+//! let config = EventfulConfig::from_file("eventful.toml")?;
+//! let built = config.build().await?;
+//! let fleet = built.nsq.as_ref().expect("the config has an nsq section");
+//! fleet.publish_event(&Destination::NsqTopic(built.topic("orders")), &order).await?;
+//! let subscription = SubscriptionSQS::new(built.sqs.as_ref().unwrap().client().clone(), built.queue_url("orders")?);
+//! consumer::run(Box::new(subscription), &built.consumer, handle_order).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::HashMap, env, path::Path, time::Duration};
+use serde::{Serialize, Deserialize};
+use crate::consumer::ConsumerOptions;
+use crate::err::EventfulError;
+#[cfg(feature = "nsq")]
+use crate::nsq::{Daemon, FleetNSQ};
+use crate::retry::{Backoff, RetryPolicy};
+#[cfg(feature = "sqs")]
+use crate::sqs::ClientSQS;
+
+
+/// The whole messaging setup. Every section is optional
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventfulConfig {
+    /// Prepended to topic names by Built::topic(), i.e. "staging_"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nsq: Option<NsqConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sqs: Option<SqsConfig>,
+    #[serde(default)]
+    pub consumer: ConsumerConfig,
+    /// Retry policies by name, i.e. [retry.payments]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub retry: HashMap<String, RetryConfig>,
+}
+
+
+/// An NSQ fleet of exactly three daemons
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NsqConfig {
+    pub daemons: Vec<DaemonConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    pub host: String,
+    /// Where events are published, typically 4151
+    #[serde(default = "default_http_port")]
+    pub http_port: u16,
+    /// Where events are consumed from, typically 4150
+    #[serde(default = "default_tcp_port")]
+    pub tcp_port: u16,
+}
+
+fn default_http_port() -> u16 { 4151 }
+fn default_tcp_port() -> u16 { 4150 }
+
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SqsConfig {
+    pub region: String,
+    /// Send requests here instead of AWS, i.e. "http://localhost:4566" for localstack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    /// Queue URLs by name
+    #[serde(default)]
+    pub queues: HashMap<String, String>,
+}
+
+
+/// Defaults for ConsumerOptions. Fields left out keep ConsumerOptions::default()
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsumerConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<Backoff>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_handler_threshold_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handler_timeout_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_inflight_age_ms: Option<u64>,
+    #[serde(default)]
+    pub abort_stuck: bool,
+}
+
+
+/// A named RetryPolicy. Fields left out keep RetryPolicy::default()
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<Backoff>,
+}
+
+impl RetryConfig {
+    pub fn policy(&self) -> RetryPolicy {
+        let mut policy = RetryPolicy::default();
+        if let Some(max_attempts) = self.max_attempts {
+            policy = policy.max_attempts(max_attempts);
+        }
+        if let Some(backoff) = self.backoff {
+            policy = policy.backoff(backoff);
+        }
+        policy
+    }
+}
+
+
+/// What build() constructs from an EventfulConfig
+pub struct Built {
+    pub topic_prefix: String,
+    #[cfg(feature = "nsq")]
+    pub nsq: Option<FleetNSQ>,
+    #[cfg(feature = "sqs")]
+    pub sqs: Option<ClientSQS>,
+    pub queues: HashMap<String, String>,
+    pub consumer: ConsumerOptions,
+    pub retry: HashMap<String, RetryPolicy>,
+}
+
+impl Built {
+    /// The topic name with the configured prefix
+    pub fn topic(&self, name: &str) -> String {
+        format!("{}{}", self.topic_prefix, name)
+    }
+
+    /// The URL of a queue named in sqs.queues
+    pub fn queue_url(&self, name: &str) -> Result<&str, EventfulError> {
+        self.queues.get(name).map(|url| url.as_str())
+            .ok_or_else(|| EventfulError::Config(format!("sqs.queues.{}: no queue by that name is configured", name)))
+    }
+
+    /// The named retry policy, or RetryPolicy::default() if there is none by that name
+    pub fn retry_policy(&self, name: &str) -> RetryPolicy {
+        self.retry.get(name).cloned().unwrap_or_default()
+    }
+}
+
+
+impl EventfulConfig {
+    /// Interpolate env vars, then parse and validate TOML
+    pub fn from_toml_str(text: &str) -> Result<Self, EventfulError> {
+        let text = interpolate(text)?;
+        let config: EventfulConfig = serde_path_to_error::deserialize(toml::Deserializer::new(&text))
+            .map_err(|err| EventfulError::Config(format!("{}: {}", err.path(), err.inner().message())))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Interpolate env vars, then parse and validate YAML
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml_str(text: &str) -> Result<Self, EventfulError> {
+        let text = interpolate(text)?;
+        let config: EventfulConfig = serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(&text))
+            .map_err(|err| EventfulError::Config(format!("{}: {}", err.path(), err.inner())))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Read a config file, as YAML if it ends in .yaml or .yml (with the "yaml" feature) and as TOML otherwise
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, EventfulError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| EventfulError::Config(format!("could not read {}: {}", path.display(), err)))?;
+        #[cfg(feature = "yaml")]
+        if matches!(path.extension().and_then(|ext| ext.to_str()), Some("yaml") | Some("yml")) {
+            return EventfulConfig::from_yaml_str(&text)
+        }
+        EventfulConfig::from_toml_str(&text)
+    }
+
+    /// Write the config back out as TOML (without interpolation, so env var values are written as they are now)
+    pub fn to_toml_string(&self) -> Result<String, EventfulError> {
+        toml::to_string(self).map_err(|err| EventfulError::Config(err.to_string()))
+    }
+
+    /// Check the things parsing can't, naming the key path of the first problem found
+    pub fn validate(&self) -> Result<(), EventfulError> {
+        let invalid = |path: String, problem: &str| Err(EventfulError::Config(format!("{}: {}", path, problem)));
+        if let Some(nsq) = &self.nsq {
+            if cfg!(not(feature = "nsq")) {
+                return invalid("nsq".to_string(), "eventful was built without the nsq feature")
+            }
+            if nsq.daemons.len() != 3 {
+                return invalid("nsq.daemons".to_string(), &format!("a fleet needs exactly 3 daemons, not {}", nsq.daemons.len()))
+            }
+            for (i, daemon) in nsq.daemons.iter().enumerate() {
+                if daemon.host.trim().is_empty() {
+                    return invalid(format!("nsq.daemons[{}].host", i), "must not be empty")
+                }
+                if daemon.http_port == 0 {
+                    return invalid(format!("nsq.daemons[{}].http_port", i), "must not be 0")
+                }
+                if daemon.tcp_port == 0 {
+                    return invalid(format!("nsq.daemons[{}].tcp_port", i), "must not be 0")
+                }
+            }
+        }
+        if let Some(sqs) = &self.sqs {
+            if cfg!(not(feature = "sqs")) {
+                return invalid("sqs".to_string(), "eventful was built without the sqs feature")
+            }
+            if sqs.region.trim().is_empty() {
+                return invalid("sqs.region".to_string(), "must not be empty")
+            }
+            if let Some(endpoint) = &sqs.endpoint {
+                if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                    return invalid("sqs.endpoint".to_string(), "must start with http:// or https://")
+                }
+            }
+            for (name, url) in sqs.queues.iter() {
+                if !url.starts_with("http://") && !url.starts_with("https://") {
+                    return invalid(format!("sqs.queues.{}", name), "must be a queue URL")
+                }
+            }
+        }
+        if self.consumer.max_attempts == Some(0) {
+            return invalid("consumer.max_attempts".to_string(), "must be at least 1")
+        }
+        if let Some(backoff) = &self.consumer.backoff {
+            validate_backoff("consumer.backoff", backoff)?;
+        }
+        for (name, retry) in self.retry.iter() {
+            if retry.max_attempts == Some(0) {
+                return invalid(format!("retry.{}.max_attempts", name), "must be at least 1")
+            }
+            if let Some(backoff) = &retry.backoff {
+                validate_backoff(&format!("retry.{}.backoff", name), backoff)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The consumer section applied over ConsumerOptions::default()
+    pub fn consumer_options(&self) -> ConsumerOptions {
+        let defaults = ConsumerOptions::default();
+        let mut retry = defaults.retry.clone();
+        if let Some(max_attempts) = self.consumer.max_attempts {
+            retry = retry.max_attempts(max_attempts);
+        }
+        if let Some(backoff) = self.consumer.backoff {
+            retry = retry.backoff(backoff);
+        }
+        let mut options = defaults.retry(retry).abort_stuck(self.consumer.abort_stuck);
+        if let Some(ms) = self.consumer.slow_handler_threshold_ms {
+            options = options.slow_handler_threshold(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.consumer.handler_timeout_ms {
+            options = options.handler_timeout(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.consumer.max_inflight_age_ms {
+            options = options.max_inflight_age(Duration::from_millis(ms));
+        }
+        options
+    }
+
+    /// Construct the fleet, SQS client, and defaults the config describes
+    pub async fn build(&self) -> Result<Built, EventfulError> {
+        self.validate()?;
+        #[cfg(feature = "nsq")]
+        let nsq = self.nsq.as_ref().map(|nsq| {
+            let mut daemons = nsq.daemons.iter().map(|d| Daemon::new(&d.host, d.http_port, d.tcp_port));
+            // validate() checked there are exactly 3
+            FleetNSQ::new(daemons.next().unwrap(), daemons.next().unwrap(), daemons.next().unwrap())
+        });
+        #[cfg(feature = "sqs")]
+        let sqs = match &self.sqs {
+            Some(sqs) => {
+                let mut builder = ClientSQS::builder().region(sqs.region.clone());
+                if let Some(endpoint) = &sqs.endpoint {
+                    builder = builder.endpoint_url(endpoint.clone());
+                }
+                Some(builder.build().await?)
+            },
+            None => None,
+        };
+        Ok(Built{
+            topic_prefix: self.topic_prefix.clone().unwrap_or_default(),
+            #[cfg(feature = "nsq")]
+            nsq,
+            #[cfg(feature = "sqs")]
+            sqs,
+            queues: self.sqs.as_ref().map(|sqs| sqs.queues.clone()).unwrap_or_default(),
+            consumer: self.consumer_options(),
+            retry: self.retry.iter().map(|(name, retry)| (name.clone(), retry.policy())).collect(),
+        })
+    }
+}
+
+
+fn validate_backoff(path: &str, backoff: &Backoff) -> Result<(), EventfulError> {
+    let (base, factor, max) = match backoff {
+        Backoff::Fixed(_) => return Ok(()),
+        Backoff::Exponential{base, factor, max} | Backoff::ExponentialJitter{base, factor, max, ..} => (base, Some(*factor), max),
+        Backoff::Decorrelated{base, max} => (base, None, max),
+    };
+    if let Some(factor) = factor {
+        if factor.is_nan() || factor < 1.0 {
+            return Err(EventfulError::Config(format!("{}.factor: must be at least 1.0", path)))
+        }
+    }
+    if base > max {
+        return Err(EventfulError::Config(format!("{}.max: must not be less than base", path)))
+    }
+    Ok(())
+}
+
+
+/// Replace `${VAR}` with the value of env var VAR, and `${VAR:-default}` with default when VAR is unset.
+/// Comments (from a `#` outside a quoted string to the end of the line) are left alone.
+/// An unset VAR without a default is an error naming it
+pub fn interpolate(text: &str) -> Result<String, EventfulError> {
+    interpolate_with(text, |name| env::var(name).ok())
+}
+
+/// interpolate(), looking variables up with `lookup` instead of in the environment
+pub fn interpolate_with<F: Fn(&str) -> Option<String>>(text: &str, lookup: F) -> Result<String, EventfulError> {
+    let mut out = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let (code, comment) = line.split_at(comment_start(line).unwrap_or(line.len()));
+        interpolate_code(code, &lookup, &mut out)?;
+        out.push_str(comment);
+    }
+    Ok(out)
+}
+
+fn interpolate_code<F: Fn(&str) -> Option<String>>(code: &str, lookup: &F, out: &mut String) -> Result<(), EventfulError> {
+    let mut rest = code;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| {
+            let snippet: String = rest[start..].trim_end().chars().take(40).collect();
+            EventfulError::Config(format!("unclosed ${{ in \"{}\"", snippet))
+        })?;
+        let (name, default) = match after[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&after[..end], None),
+        };
+        match (lookup(name), default) {
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => return Err(EventfulError::Config(format!("env var {} is not set", name))),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(())
+}
+
+/// Where the comment on a line starts: the first `#` which isn't inside a '...' or "..." string
+fn comment_start(line: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue
+            },
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return Some(i),
+            _ => {},
+        }
+        escaped = false;
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(name: &str) -> Option<String> {
+        match name {
+            "NSQ_HOST" => Some("nsq.internal".to_string()),
+            "PREFIX" => Some("staging_".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn interpolates_vars_and_defaults() {
+        let text = "host = \"${NSQ_HOST}\"\nprefix = \"${PREFIX:-prod_}\"\nregion = \"${REGION:-us-east-1}\"\nempty = \"${UNSET:-}\"\n";
+        assert_eq!(
+            interpolate_with(text, vars).unwrap(),
+            "host = \"nsq.internal\"\nprefix = \"staging_\"\nregion = \"us-east-1\"\nempty = \"\"\n",
+        );
+    }
+
+    #[test]
+    fn an_unset_var_without_a_default_is_named() {
+        match interpolate_with("host = \"${NSQ_PORT}\"", vars) {
+            Err(EventfulError::Config(message)) => assert_eq!(message, "env var NSQ_PORT is not set"),
+            other => panic!("expected a Config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn an_unclosed_brace_is_an_error_even_after_multibyte_characters() {
+        let text = format!("name = \"${{{}", "é".repeat(30));
+        match interpolate_with(&text, vars) {
+            Err(EventfulError::Config(message)) => assert!(message.starts_with("unclosed ${ in \"${é")),
+            other => panic!("expected a Config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn comments_are_not_interpolated() {
+        let text = "# ${VAR} is replaced with the env var VAR\nhost = \"${NSQ_HOST}\" # not ${THIS}\nurl = \"http://x/#${PREFIX}\"\n";
+        assert_eq!(
+            interpolate_with(text, vars).unwrap(),
+            "# ${VAR} is replaced with the env var VAR\nhost = \"nsq.internal\" # not ${THIS}\nurl = \"http://x/#staging_\"\n",
+        );
+        assert_eq!(comment_start(r#"a = "quote \" # still a string" # comment"#), Some(32));
+        assert_eq!(comment_start("a = 'it''s' # comment"), Some(12));
+    }
+
+    // the example configures both backends
+    #[test]
+    #[cfg(all(feature = "nsq", feature = "sqs"))]
+    fn the_example_config_parses() {
+        let config = EventfulConfig::from_toml_str(include_str!("../examples/config/eventful.toml")).unwrap();
+        assert_eq!(config.nsq.as_ref().unwrap().daemons[1].http_port, 4251);
+        assert_eq!(config.consumer.max_attempts, Some(5));
+        assert_eq!(config.retry["payments"].max_attempts, Some(8));
+        assert_eq!(config.consumer_options().max_inflight_age, Some(Duration::from_millis(60000)));
+    }
+
+    #[test]
+    #[cfg(all(feature = "nsq", feature = "sqs"))]
+    fn round_trips_through_toml() {
+        let config = EventfulConfig::from_toml_str(include_str!("../examples/config/eventful.toml")).unwrap();
+        let text = config.to_toml_string().unwrap();
+        assert_eq!(EventfulConfig::from_toml_str(&text).unwrap(), config);
+    }
+
+    #[test]
+    #[cfg(feature = "nsq")]
+    fn errors_name_the_key_path() {
+        let text = "[nsq]\ndaemons = [{ host = \"a\" }, { host = \"b\", http_port = 0 }, { host = \"c\" }]\n";
+        match EventfulConfig::from_toml_str(text) {
+            Err(EventfulError::Config(message)) => assert_eq!(message, "nsq.daemons[1].http_port: must not be 0"),
+            other => panic!("expected a Config error, got {:?}", other),
+        }
+        match EventfulConfig::from_toml_str("[consumer]\nmax_attempts = \"five\"\n") {
+            Err(EventfulError::Config(message)) => assert!(message.starts_with("consumer.max_attempts: "), "{}", message),
+            other => panic!("expected a Config error, got {:?}", other),
+        }
+    }
+}
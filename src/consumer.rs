@@ -0,0 +1,951 @@
+//! The consumer module contains a handler run loop which works with any Subscriber.  
+//! Each delivery is decoded and passed to an async handler: success acks the message,
+//! failure nacks it for redelivery, and once a message has been delivered retry.max_attempts times
+//! it is handed to the DeadLetterSink (if one is configured) and acked.
+//! ConsumerStats counts outcomes and keeps a histogram of handler latency.
+//! Each handler gets its own watchdog timer (so tracking is O(1) per message and ends with the handler), set to
+//! max_inflight_age or by default twice handler_timeout: a handler still running when it fires is reported to the observer,
+//! and abandoned and requeued if abort_stuck is set.
+//! run_sharded() handles events concurrently on a workers::ShardedPool, keeping events with the same key in order.
+//! Its queues are bounded: when the next event's shard is full the loop stops receiving until there is room, leaving
+//! further messages with the backend (NSQ stops sending once max-in-flight messages are unacked; SQS keeps them on the queue).
+//! Queue depth, its high-water mark, and time spent full are kept in ConsumerStats and reported to the metrics,
+//! and the observer's backpressure_engaged and backpressure_released callbacks say when intake is being held back.
+
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex, OnceLock, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}}, time::{Duration, Instant}};
+use serde::de::DeserializeOwned;
+#[cfg(feature = "sqs")]
+use crate::codec::{Codec, JsonCodec};
+use crate::deadletter::{self, DeadLetterHook, DeadLetterRecord, DeadLetterSink};
+use crate::err::EventfulError;
+use crate::idempotency::IdempotencyGuard;
+use crate::metrics::{self, EventfulMetrics};
+use crate::observer::{self, Backpressure, ConsumerStarted, DeadLettered, EventfulObserver, SlowHandler, StuckMessage};
+use crate::publisher::Destination;
+use crate::retry::{Backoff, Jitter, RetryPolicy};
+use crate::subscriber::{Delivery, OnDrop, Received, Subscriber, TypedSubscriber};
+use crate::trace;
+use crate::workers::ShardedPool;
+
+
+/// What a consumer loop (i.e. sqs::QueueConsumer) does with a message whose body can't be decoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnDecodeError {
+    /// Send it to ConsumerOptions::dead_letter, or nack it with the retry backoff when there is no sink, as run() does
+    #[default]
+    DeadLetter,
+    /// Ack it, dropping the message
+    Delete,
+    /// Leave it unsettled until the backend's timeout redelivers it (i.e. so an SQS redrive policy moves it to a DLQ)
+    Leave,
+}
+
+
+/// Options controlling the handler run loop
+#[derive(Clone)]
+pub struct ConsumerOptions {
+    /// retry.max_attempts is how many deliveries a message gets before it is dead-lettered,
+    /// and retry.delay_for(attempt) is how long a failed message waits before it is redelivered.
+    /// The default allows 5 attempts, waiting 10 seconds doubling up to 5 minutes
+    pub retry: RetryPolicy,
+    /// Where messages go once they reach retry.max_attempts. Without a sink they are nacked indefinitely
+    pub dead_letter: Option<Arc<dyn DeadLetterSink>>,
+    /// Told about every dead-lettered message, in a background task with a timeout
+    pub on_dead_letter: Option<Arc<dyn DeadLetterHook>>,
+    /// Receives lifecycle callbacks. Without one, the global observer is used
+    pub observer: Option<Arc<dyn EventfulObserver>>,
+    /// Handlers which take longer than this are reported to the observer and counted in stats.slow_handlers
+    pub slow_handler_threshold: Option<Duration>,
+    /// How long the backend gives a handler before it redelivers the message: nsqd's --msg-timeout (60 seconds by default,
+    /// which is the default here too) or the SQS queue's visibility timeout. eventful doesn't enforce it;
+    /// it sets the default max_inflight_age
+    pub handler_timeout: Duration,
+    /// A handler still running after this long is reported to the observer's stuck_message callback and counted in
+    /// stats.stuck. Unlike slow_handler_threshold, this fires while the handler is running.
+    /// None (the default) means twice handler_timeout
+    pub max_inflight_age: Option<Duration>,
+    /// When a handler passes max_inflight_age, abandon it (drop its future) and requeue the message at once
+    pub abort_stuck: bool,
+    /// What happens to a delivery dropped without being settled, i.e. when the handler panics.
+    /// None keeps the backend's default (NSQ requeues, SQS waits for the visibility timeout)
+    pub on_drop: Option<OnDrop>,
+    /// Counters and handler latency. Clones of the options share the same stats
+    pub stats: Arc<ConsumerStats>,
+}
+
+impl Default for ConsumerOptions {
+    fn default() -> Self {
+        let retry = RetryPolicy::default()
+            .max_attempts(5)
+            .backoff(Backoff::ExponentialJitter{base: Duration::from_secs(10), factor: 2.0, max: Duration::from_secs(300), jitter: Jitter::Equal});
+        ConsumerOptions{
+            retry,
+            dead_letter: None,
+            on_dead_letter: None,
+            observer: None,
+            slow_handler_threshold: None,
+            handler_timeout: Duration::from_secs(60),
+            max_inflight_age: None,
+            abort_stuck: false,
+            on_drop: None,
+            stats: Arc::new(ConsumerStats::default()),
+        }
+    }
+}
+
+impl ConsumerOptions {
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn dead_letter(mut self, sink: Arc<dyn DeadLetterSink>) -> Self {
+        self.dead_letter = Some(sink);
+        self
+    }
+
+    pub fn on_dead_letter(mut self, hook: Arc<dyn DeadLetterHook>) -> Self {
+        self.on_dead_letter = Some(hook);
+        self
+    }
+
+    pub fn observer(mut self, observer: Arc<dyn EventfulObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    pub fn slow_handler_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_handler_threshold = Some(threshold);
+        self
+    }
+
+    /// Set to the backend's message timeout, so the watchdog's default max_inflight_age (twice this) fits it
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = timeout;
+        self
+    }
+
+    pub fn max_inflight_age(mut self, age: Duration) -> Self {
+        self.max_inflight_age = Some(age);
+        self
+    }
+
+    /// How long a handler may run before the watchdog reports it: max_inflight_age, or twice handler_timeout
+    pub fn inflight_age_limit(&self) -> Duration {
+        self.max_inflight_age.unwrap_or(self.handler_timeout.saturating_mul(2))
+    }
+
+    pub fn abort_stuck(mut self, abort: bool) -> Self {
+        self.abort_stuck = abort;
+        self
+    }
+
+    pub fn on_drop(mut self, on_drop: OnDrop) -> Self {
+        self.on_drop = Some(on_drop);
+        self
+    }
+
+    /// Apply on_drop (if set) to a delivery
+    pub(crate) fn guard(&self, delivery: Delivery) -> Delivery {
+        match self.on_drop {
+            Some(on_drop) => delivery.on_drop(on_drop),
+            None => delivery,
+        }
+    }
+
+    /// A handle to the counters, which stays valid while the consumer runs
+    pub fn stats(&self) -> Arc<ConsumerStats> {
+        self.stats.clone()
+    }
+}
+
+
+/// The upper bounds of the latency histogram's buckets. Anything slower lands in a final, unbounded bucket
+pub const LATENCY_BUCKETS: [Duration; 12] = [
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(25),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_millis(2500),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+];
+
+
+/// A histogram of handler latency with fixed buckets, so recording doesn't allocate
+#[derive(Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    total_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, elapsed: Duration) {
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| elapsed <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let mut counts = [0u64; LATENCY_BUCKETS.len() + 1];
+        for (count, bucket) in counts.iter_mut().zip(self.buckets.iter()) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        LatencySnapshot{counts, total: Duration::from_micros(self.total_micros.load(Ordering::Relaxed))}
+    }
+}
+
+
+/// A point-in-time copy of a LatencyHistogram
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// How many handlers finished within each of LATENCY_BUCKETS (but above the previous bound), then how many took longer
+    pub counts: [u64; LATENCY_BUCKETS.len() + 1],
+    /// The sum of every recorded latency
+    pub total: Duration,
+}
+
+impl LatencySnapshot {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(Duration::from_micros(self.total.as_micros() as u64 / count)),
+        }
+    }
+
+    /// The upper bound of the bucket holding quantile q (0.0 to 1.0), i.e. 0.99 for p99.
+    /// None if nothing was recorded, or Duration::MAX if the quantile lies in the unbounded bucket
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, bucket_count) in self.counts.iter().enumerate() {
+            seen += bucket_count;
+            if seen >= rank {
+                return Some(LATENCY_BUCKETS.get(i).copied().unwrap_or(Duration::MAX))
+            }
+        }
+        Some(Duration::MAX)
+    }
+}
+
+
+/// Counters kept by the run loop
+#[derive(Default)]
+pub struct ConsumerStats {
+    consumed: AtomicU64,
+    failed: AtomicU64,
+    dead_lettered: AtomicU64,
+    filtered: AtomicU64,
+    idle_receives: AtomicU64,
+    idle_wait_micros: AtomicU64,
+    slow_handlers: AtomicU64,
+    stuck: AtomicU64,
+    intake_depth: AtomicU64,
+    intake_high_water: AtomicU64,
+    backpressure_micros: AtomicU64,
+    latency: LatencyHistogram,
+}
+
+/// A point-in-time copy of ConsumerStats' counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConsumerStatsSnapshot {
+    /// Events handled successfully and acked
+    pub consumed: u64,
+    /// Handler failures and undecodable bodies (each failed attempt counts once)
+    pub failed: u64,
+    /// Messages sent to the DeadLetterSink
+    pub dead_lettered: u64,
+    /// Messages received but kept from the handler by a receive filter (see sqs::ReceiveFilter)
+    pub filtered: u64,
+    /// How many receives in a row have come back empty, for polling consumers (i.e. sqs::QueueConsumer). Zero once a message arrives
+    pub idle_receives: u64,
+    /// The wait before a polling consumer's next receive, which grows while its queue stays empty
+    pub idle_wait: Duration,
+    /// Handlers which took longer than slow_handler_threshold
+    pub slow_handlers: u64,
+    /// Handlers which were still running after max_inflight_age
+    pub stuck: u64,
+    /// Events run_sharded() has received whose handlers haven't finished
+    pub intake_depth: u64,
+    /// The most events ever received by run_sharded() but not yet handled
+    pub intake_high_water: u64,
+    /// How long intake has spent waiting for room in a full queue
+    pub time_full: Duration,
+}
+
+impl ConsumerStats {
+    pub fn snapshot(&self) -> ConsumerStatsSnapshot {
+        ConsumerStatsSnapshot{
+            consumed: self.consumed.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            dead_lettered: self.dead_lettered.load(Ordering::Relaxed),
+            filtered: self.filtered.load(Ordering::Relaxed),
+            idle_receives: self.idle_receives.load(Ordering::Relaxed),
+            idle_wait: Duration::from_micros(self.idle_wait_micros.load(Ordering::Relaxed)),
+            slow_handlers: self.slow_handlers.load(Ordering::Relaxed),
+            stuck: self.stuck.load(Ordering::Relaxed),
+            intake_depth: self.intake_depth.load(Ordering::Relaxed),
+            intake_high_water: self.intake_high_water.load(Ordering::Relaxed),
+            time_full: Duration::from_micros(self.backpressure_micros.load(Ordering::Relaxed)),
+        }
+    }
+
+    #[cfg(feature = "sqs")]
+    pub(crate) fn add_filtered(&self, count: u64) {
+        self.filtered.fetch_add(count, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "sqs")]
+    pub(crate) fn set_idle(&self, empty_receives: u32, wait: Duration) {
+        self.idle_receives.store(empty_receives as u64, Ordering::Relaxed);
+        self.idle_wait_micros.store(wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Handler latency so far
+    pub fn latency_snapshot(&self) -> LatencySnapshot {
+        self.latency.snapshot()
+    }
+}
+
+
+/// Consume from a subscriber until it ends, passing each decoded event to the handler
+pub async fn run<T, H, Fut>(subscriber: Box<dyn Subscriber>, options: &ConsumerOptions, handler: H) -> Result<(), EventfulError>
+where
+    T: DeserializeOwned,
+    H: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), EventfulError>>,
+{
+    let mut typed = TypedSubscriber::<T>::new(subscriber);
+    observer::or_global(&options.observer).consumer_started(&ConsumerStarted{source: typed.describe()});
+    while let Some(received) = typed.next().await? {
+        match received {
+            Received::Event(typed) => handle_event(typed.event, options.guard(typed.delivery), options, &handler).await?,
+            Received::Undecodable{delivery, error} => handle_undecodable(options.guard(delivery), error, options).await?,
+        }
+    }
+    Ok(())
+}
+
+
+/// Like run(), but events are handled concurrently by a workers::ShardedPool of `shards` shards,
+/// each queueing up to queue_bound events. Events for which key returns the same key are handled one at a time,
+/// in the order they were received; events without a key go to any shard.
+/// Receiving waits while the next event's shard is full. Once the subscriber ends, the events already queued are handled
+/// before this returns. Errors settling a message inside the pool are not returned, so they can't stop the loop
+pub async fn run_sharded<T, K, H, Fut>(subscriber: Box<dyn Subscriber>, options: &ConsumerOptions, shards: usize, queue_bound: usize, key: K, handler: H) -> Result<(), EventfulError>
+where
+    T: DeserializeOwned + Send + 'static,
+    K: Fn(&T, &Delivery) -> Option<String>,
+    H: Fn(T) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), EventfulError>> + Send + 'static,
+{
+    let mut typed = TypedSubscriber::<T>::new(subscriber);
+    observer::or_global(&options.observer).consumer_started(&ConsumerStarted{source: typed.describe()});
+    let shared = Arc::new(options.clone());
+    let handler = Arc::new(handler);
+    let intake = Arc::new(Intake{
+        source: typed.describe(),
+        options: shared.clone(),
+        metrics: metrics::global(),
+        capacity: shards.max(1) * queue_bound.max(1),
+        depth: AtomicUsize::new(0),
+        engaged: AtomicBool::new(false),
+        waited_micros: AtomicU64::new(0),
+    });
+    let pool = ShardedPool::new(shards, queue_bound, move |(event, delivery, queued): (T, Delivery, Queued)| {
+        let options = shared.clone();
+        let handler = handler.clone();
+        async move {
+            let _ = handle_event(event, delivery, &options, handler.as_ref()).await;
+            // dropping this (here, or while unwinding from a panicking handler) is what can release backpressure,
+            // so it is released even while the subscriber has nothing new to receive
+            drop(queued);
+        }
+    });
+    let result = loop {
+        let received = match typed.next().await {
+            Ok(Some(received)) => received,
+            Ok(None) => break Ok(()),
+            Err(err) => break Err(err),
+        };
+        match received {
+            Received::Event(typed) => {
+                let topic = typed.delivery.source.name().to_string();
+                let shard = pool.pick(key(&typed.event, &typed.delivery).as_deref());
+                let queued = Intake::queue(&intake, topic.clone());
+                let sent = match pool.try_send_to(shard, (typed.event, options.guard(typed.delivery), queued)) {
+                    Ok(None) => Ok(()),
+                    // the shard is full, so stop receiving until it has room
+                    Ok(Some(item)) => {
+                        intake.engage();
+                        let waiting = Instant::now();
+                        let sent = pool.send_to(shard, item).await;
+                        intake.waited(&topic, waiting.elapsed());
+                        sent
+                    },
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = sent {
+                    break Err(err)
+                }
+            },
+            Received::Undecodable{delivery, error} => {
+                if let Err(err) = handle_undecodable(options.guard(delivery), error, options).await {
+                    break Err(err)
+                }
+            },
+        }
+    };
+    pool.join().await;
+    result
+}
+
+
+/// Tracks how many events run_sharded() has taken in but not finished handling, reporting backpressure with hysteresis:
+/// engaged when intake first has to wait for room, released once handlers bring the depth down to half the pool's capacity
+struct Intake {
+    source: String,
+    options: Arc<ConsumerOptions>,
+    metrics: Arc<dyn EventfulMetrics>,
+    capacity: usize,
+    depth: AtomicUsize,
+    engaged: AtomicBool,
+    waited_micros: AtomicU64,
+}
+
+impl Intake {
+    /// Count an event as taken in until the returned guard is dropped
+    fn queue(intake: &Arc<Intake>, topic: String) -> Queued {
+        let depth = intake.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        intake.options.stats.intake_high_water.fetch_max(depth as u64, Ordering::Relaxed);
+        intake.record(&topic, depth);
+        Queued{intake: intake.clone(), topic}
+    }
+
+    fn engage(&self) {
+        if self.engaged.swap(true, Ordering::Relaxed) {
+            return
+        }
+        self.waited_micros.store(0, Ordering::Relaxed);
+        observer::or_global(&self.options.observer).backpressure_engaged(&self.context());
+    }
+
+    fn waited(&self, topic: &str, waited: Duration) {
+        let micros = waited.as_micros() as u64;
+        self.waited_micros.fetch_add(micros, Ordering::Relaxed);
+        self.options.stats.backpressure_micros.fetch_add(micros, Ordering::Relaxed);
+        self.metrics.add_backpressure_time(topic, waited);
+    }
+
+    fn finished(&self, topic: &str) {
+        let depth = self.depth.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.record(topic, depth);
+        if depth <= self.capacity / 2 && self.engaged.compare_exchange(true, false, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            observer::or_global(&self.options.observer).backpressure_released(&self.context());
+        }
+    }
+
+    fn record(&self, topic: &str, depth: usize) {
+        self.options.stats.intake_depth.store(depth as u64, Ordering::Relaxed);
+        self.metrics.set_intake_depth(topic, depth);
+    }
+
+    fn context(&self) -> Backpressure {
+        Backpressure{
+            source: self.source.clone(),
+            depth: self.depth.load(Ordering::Relaxed),
+            capacity: self.capacity,
+            waited: Duration::from_micros(self.waited_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// An event counted in Intake's depth, until its handler is done with it
+struct Queued {
+    intake: Arc<Intake>,
+    topic: String,
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.intake.finished(&self.topic);
+    }
+}
+
+
+/// Run the handler on one decoded event, then ack, nack, or dead-letter its delivery
+async fn handle_event<T, H, Fut>(event: T, delivery: Delivery, options: &ConsumerOptions, handler: &H) -> Result<(), EventfulError>
+where
+    H: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), EventfulError>>,
+{
+    let metrics = metrics::global();
+    let topic = delivery.source.name().to_string();
+    let handle = async {
+        let result = handler(event).await;
+        trace::record_outcome(&result);
+        result
+    };
+    let inflight = Inflight::start(&topic, metrics.clone());
+    let started = Instant::now();
+    let result = {
+        let handle = trace::in_consume_span(&delivery, handle);
+        watch(handle, &delivery.source, delivery.message_id.as_deref(), delivery.attempt, options).await
+    };
+    let elapsed = started.elapsed();
+    drop(inflight);
+    let result = match result {
+        Some(result) => result,
+        // the handler was abandoned by the watchdog
+        None => return delivery.nack(Duration::ZERO).await,
+    };
+    options.stats.latency.record(elapsed);
+    if options.slow_handler_threshold.map(|threshold| elapsed > threshold).unwrap_or(false) {
+        options.stats.slow_handlers.fetch_add(1, Ordering::Relaxed);
+        observer::or_global(&options.observer).slow_handler(&SlowHandler{
+            source: delivery.source.clone(),
+            message_id: delivery.message_id.clone(),
+            attempt: delivery.attempt,
+            elapsed,
+        });
+    }
+    match result {
+        Ok(()) => {
+            metrics.inc_consumed(&topic);
+            options.stats.consumed.fetch_add(1, Ordering::Relaxed);
+            delivery.ack().await
+        },
+        Err(err) => {
+            metrics.inc_failed(&topic);
+            options.stats.failed.fetch_add(1, Ordering::Relaxed);
+            settle_failure(delivery, err, options).await
+        },
+    }
+}
+
+
+/// How many handlers are running for each topic, across every consumer in the process
+static INFLIGHT: OnceLock<Mutex<HashMap<String, Arc<Mutex<usize>>>>> = OnceLock::new();
+
+/// Counts one running handler towards its topic's inflight gauge until dropped. The count is changed and reported
+/// under the topic's lock, so concurrent handlers (i.e. run_sharded's shards) leave the gauge at the true count
+struct Inflight {
+    topic: String,
+    count: Arc<Mutex<usize>>,
+    metrics: Arc<dyn EventfulMetrics>,
+}
+
+impl Inflight {
+    fn start(topic: &str, metrics: Arc<dyn EventfulMetrics>) -> Self {
+        let count = INFLIGHT.get_or_init(Default::default).lock().unwrap().entry(topic.to_string()).or_default().clone();
+        let inflight = Inflight{topic: topic.to_string(), count, metrics};
+        inflight.change(|count| *count += 1);
+        inflight
+    }
+
+    fn change(&self, change: impl FnOnce(&mut usize)) {
+        let mut count = self.count.lock().unwrap();
+        change(&mut count);
+        self.metrics.set_inflight(&self.topic, *count);
+    }
+}
+
+impl Drop for Inflight {
+    fn drop(&mut self) {
+        self.change(|count| *count = count.saturating_sub(1));
+    }
+}
+
+
+/// Await a handler, reporting it once it has been running for options.inflight_age_limit().
+/// Returns None if it was abandoned because options.abort_stuck is set
+async fn watch<F: Future>(handle: F, source: &Destination, message_id: Option<&str>, attempt: u32, options: &ConsumerOptions) -> Option<F::Output> {
+    let max_age = options.inflight_age_limit();
+    tokio::pin!(handle);
+    tokio::select! {
+        result = &mut handle => return Some(result),
+        _ = tokio::time::sleep(max_age) => {},
+    }
+    options.stats.stuck.fetch_add(1, Ordering::Relaxed);
+    observer::or_global(&options.observer).stuck_message(&StuckMessage{
+        source: source.clone(),
+        message_id: message_id.map(str::to_string),
+        attempt,
+        elapsed: max_age,
+        aborted: options.abort_stuck,
+    });
+    match options.abort_stuck {
+        true => None,
+        false => Some(handle.await),
+    }
+}
+
+
+/// Decode one delivery and handle it as run() does, applying on_decode_error to a body which can't be decoded.
+/// For loops which receive deliveries themselves, i.e. sqs::QueueConsumer
+#[cfg(feature = "sqs")]
+pub(crate) async fn process<T, H, Fut>(delivery: Delivery, options: &ConsumerOptions, on_decode_error: OnDecodeError, handler: &H) -> Result<(), EventfulError>
+where
+    T: DeserializeOwned,
+    H: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), EventfulError>>,
+{
+    let delivery = options.guard(delivery);
+    let error = match JsonCodec.decode::<T>(&delivery.body) {
+        Ok(event) => return handle_event(event, delivery, options, handler).await,
+        Err(error) => error,
+    };
+    if on_decode_error == OnDecodeError::DeadLetter {
+        return handle_undecodable(delivery, error, options).await
+    }
+    trace::in_consume_span(&delivery, async { trace::record_error(&error) }).await;
+    metrics::global().inc_failed(delivery.source.name());
+    options.stats.failed.fetch_add(1, Ordering::Relaxed);
+    match on_decode_error {
+        OnDecodeError::Delete => delivery.ack().await,
+        _ => {
+            delivery.release();
+            Ok(())
+        },
+    }
+}
+
+
+/// A body which can't be decoded won't decode next time either, so it counts as a final attempt
+async fn handle_undecodable(delivery: Delivery, error: EventfulError, options: &ConsumerOptions) -> Result<(), EventfulError> {
+    trace::in_consume_span(&delivery, async { trace::record_error(&error) }).await;
+    metrics::global().inc_failed(delivery.source.name());
+    options.stats.failed.fetch_add(1, Ordering::Relaxed);
+    match &options.dead_letter {
+        Some(sink) => dead_letter(delivery, error, sink.as_ref(), options).await,
+        None => {
+            let delay = options.retry.delay_for(delivery.attempt);
+            delivery.nack(delay).await
+        },
+    }
+}
+
+
+/// Like run(), but each event is passed through an IdempotencyGuard keyed by `key(&event)`.  
+/// Events whose key has already been processed are acked without calling the handler
+pub async fn run_idempotent<T, K, H, Fut>(subscriber: Box<dyn Subscriber>, options: &ConsumerOptions, guard: &IdempotencyGuard, key: K, handler: H) -> Result<(), EventfulError>
+where
+    T: DeserializeOwned,
+    K: Fn(&T) -> String,
+    H: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), EventfulError>>,
+{
+    run(subscriber, options, |event: T| {
+        let key = key(&event);
+        let fut = handler(event);
+        async move {
+            guard.execute(&key, || fut).await?;
+            Ok(())
+        }
+    }).await
+}
+
+
+/// Nack a failed delivery, or dead-letter it if it has used up its attempts
+pub(crate) async fn settle_failure(delivery: Delivery, err: EventfulError, options: &ConsumerOptions) -> Result<(), EventfulError> {
+    match &options.dead_letter {
+        Some(sink) if delivery.attempt >= options.retry.max_attempts => dead_letter(delivery, err, sink.as_ref(), options).await,
+        _ => {
+            let delay = options.retry.delay_for(delivery.attempt);
+            delivery.nack(delay).await
+        },
+    }
+}
+
+
+async fn dead_letter(delivery: Delivery, err: EventfulError, sink: &dyn DeadLetterSink, options: &ConsumerOptions) -> Result<(), EventfulError> {
+    let record = DeadLetterRecord::new(&delivery, &err);
+    match sink.send(record.clone()).await {
+        Ok(()) => {
+            if let Some(hook) = &options.on_dead_letter {
+                deadletter::spawn_hook(hook.clone(), record);
+            }
+            metrics::global().inc_dead_lettered(delivery.source.name());
+            options.stats.dead_lettered.fetch_add(1, Ordering::Relaxed);
+            observer::or_global(&options.observer).dead_lettered(&DeadLettered{
+                source: delivery.source.clone(),
+                message_id: delivery.message_id.clone(),
+                attempts: delivery.attempt,
+                error: err.to_string(),
+            });
+            delivery.ack().await
+        },
+        // if the sink is down, leave the message where it is rather than lose it
+        Err(_) => delivery.nack(Duration::ZERO).await,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use crate::publisher::{Destination, Metadata};
+    use crate::subscriber::Ack;
+
+    #[derive(Clone, Default)]
+    struct Settled(Arc<Mutex<Vec<(&'static str, tokio::time::Instant)>>>);
+
+    impl Settled {
+        fn calls(&self) -> Vec<&'static str> {
+            self.0.lock().unwrap().iter().map(|(call, _)| *call).collect()
+        }
+    }
+
+    struct RecordingAck(Settled);
+
+    #[async_trait]
+    impl Ack for RecordingAck {
+        async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+            self.0.0.lock().unwrap().push(("ack", tokio::time::Instant::now()));
+            Ok(())
+        }
+
+        async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+            self.0.0.lock().unwrap().push(("nack", tokio::time::Instant::now()));
+            Ok(())
+        }
+    }
+
+    /// Yields its deliveries, then ends
+    struct Scripted(VecDeque<Delivery>);
+
+    #[async_trait]
+    impl Subscriber for Scripted {
+        async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    fn subscriber(settled: &Settled, bodies: &[&'static str]) -> Box<dyn Subscriber> {
+        let deliveries = bodies.iter().copied().enumerate().map(|(i, body)| {
+            let ack = Box::new(RecordingAck(settled.clone()));
+            Delivery::new(Destination::NsqTopic("click".to_string()), Bytes::from_static(body.as_bytes()), Metadata::default(), Some(i.to_string()), 1, ack)
+        });
+        Box::new(Scripted(deliveries.collect()))
+    }
+
+    #[derive(Default)]
+    struct Stuck(Mutex<Vec<(Option<String>, Duration, bool)>>);
+
+    impl EventfulObserver for Stuck {
+        fn stuck_message(&self, ctx: &StuckMessage) {
+            self.0.lock().unwrap().push((ctx.message_id.clone(), ctx.elapsed, ctx.aborted));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_abandons_a_hung_handler() {
+        let (settled, stuck) = (Settled::default(), Arc::new(Stuck::default()));
+        let options = ConsumerOptions::default().max_inflight_age(Duration::from_secs(30)).abort_stuck(true).observer(stuck.clone());
+        let started = tokio::time::Instant::now();
+        // the first handler never finishes; the second is fine
+        run(subscriber(&settled, &["1", "2"]), &options, |n: u32| async move {
+            if n == 1 {
+                std::future::pending::<()>().await;
+            }
+            Ok(())
+        }).await.unwrap();
+        assert_eq!(*stuck.0.lock().unwrap(), vec![(Some("0".to_string()), Duration::from_secs(30), true)]);
+        assert_eq!(settled.calls(), vec!["nack", "ack"]);
+        assert_eq!(settled.0.lock().unwrap()[0].1 - started, Duration::from_secs(30));
+        let stats = options.stats().snapshot();
+        assert_eq!((stats.stuck, stats.consumed), (1, 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_reports_a_slow_handler_and_lets_it_finish() {
+        let (settled, stuck) = (Settled::default(), Arc::new(Stuck::default()));
+        let options = ConsumerOptions::default().max_inflight_age(Duration::from_secs(30)).observer(stuck.clone());
+        let started = tokio::time::Instant::now();
+        run(subscriber(&settled, &["1"]), &options, |_: u32| async move {
+            tokio::time::sleep(Duration::from_secs(45)).await;
+            Ok(())
+        }).await.unwrap();
+        assert_eq!(*stuck.0.lock().unwrap(), vec![(Some("0".to_string()), Duration::from_secs(30), false)]);
+        assert_eq!(settled.calls(), vec!["ack"]);
+        assert_eq!(settled.0.lock().unwrap()[0].1 - started, Duration::from_secs(45));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_ignores_handlers_which_finish_in_time() {
+        let (settled, stuck) = (Settled::default(), Arc::new(Stuck::default()));
+        let options = ConsumerOptions::default().max_inflight_age(Duration::from_secs(30)).abort_stuck(true).observer(stuck.clone());
+        run(subscriber(&settled, &["1", "2", "3"]), &options, |_: u32| async move {
+            tokio::time::sleep(Duration::from_secs(29)).await;
+            Ok(())
+        }).await.unwrap();
+        assert!(stuck.0.lock().unwrap().is_empty());
+        assert_eq!(settled.calls(), vec!["ack", "ack", "ack"]);
+        assert_eq!(options.stats().snapshot().stuck, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_defaults_to_twice_the_handler_timeout() {
+        let (settled, stuck) = (Settled::default(), Arc::new(Stuck::default()));
+        let options = ConsumerOptions::default().handler_timeout(Duration::from_secs(10)).abort_stuck(true).observer(stuck.clone());
+        assert_eq!(ConsumerOptions::default().inflight_age_limit(), Duration::from_secs(120));
+        run(subscriber(&settled, &["1"]), &options, |_: u32| async move {
+            std::future::pending::<()>().await;
+            Ok(())
+        }).await.unwrap();
+        assert_eq!(*stuck.0.lock().unwrap(), vec![(Some("0".to_string()), Duration::from_secs(20), true)]);
+        assert_eq!(settled.calls(), vec!["nack"]);
+    }
+
+    /// Records every value the inflight gauge is set to
+    #[derive(Default)]
+    struct Gauge(Mutex<Vec<usize>>);
+
+    impl EventfulMetrics for Gauge {
+        fn set_inflight(&self, _topic: &str, count: usize) {
+            self.0.lock().unwrap().push(count);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn inflight_gauge_tracks_concurrent_handlers() {
+        let gauge = Arc::new(Gauge::default());
+        let running = Arc::new(tokio::sync::Barrier::new(16));
+        let tasks = (0..16).map(|_| {
+            let (gauge, running) = (gauge.clone(), running.clone());
+            tokio::spawn(async move {
+                let inflight = Inflight::start("inflight-gauge", gauge);
+                // every handler starts before any finishes
+                running.wait().await;
+                drop(inflight);
+            })
+        }).collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let expected = (1..=16).chain((0..16).rev()).collect::<Vec<usize>>();
+        assert_eq!(*gauge.0.lock().unwrap(), expected);
+    }
+
+    /// Yields its deliveries, then waits forever without ending, like a quiet topic
+    struct Stalling(VecDeque<Delivery>);
+
+    #[async_trait]
+    impl Subscriber for Stalling {
+        async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+            match self.0.pop_front() {
+                Some(delivery) => Ok(Some(delivery)),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Pressure(Mutex<Vec<(&'static str, usize)>>);
+
+    impl EventfulObserver for Pressure {
+        fn backpressure_engaged(&self, ctx: &Backpressure) {
+            self.0.lock().unwrap().push(("engaged", ctx.depth));
+        }
+
+        fn backpressure_released(&self, ctx: &Backpressure) {
+            self.0.lock().unwrap().push(("released", ctx.depth));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backpressure_is_released_while_the_subscriber_is_quiet() {
+        let (settled, pressure) = (Settled::default(), Arc::new(Pressure::default()));
+        let bodies = ["1"; 40];
+        let deliveries = bodies.iter().enumerate().map(|(i, body)| {
+            let ack = Box::new(RecordingAck(settled.clone()));
+            Delivery::new(Destination::NsqTopic("click".to_string()), Bytes::from_static(body.as_bytes()), Metadata::default(), Some(i.to_string()), 1, ack)
+        });
+        let subscriber = Box::new(Stalling(deliveries.collect()));
+        let options = ConsumerOptions::default().observer(pressure.clone());
+        let (shards, queue_bound) = (2, 3);
+        let consuming = {
+            let options = options.clone();
+            tokio::spawn(async move {
+                run_sharded(subscriber, &options, shards, queue_bound, |_: &u32, _: &Delivery| None, |_: u32| async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    Ok(())
+                }).await
+            })
+        };
+        while settled.calls().len() < bodies.len() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // the last events are handled after the subscriber has gone quiet, and it's their handlers that release intake
+        tokio::task::yield_now().await;
+        let reports = pressure.0.lock().unwrap().clone();
+        assert_eq!(reports.first().map(|report| report.0), Some("engaged"));
+        assert_eq!(reports.last(), Some(&("released", shards * queue_bound / 2)));
+        assert!(reports.windows(2).all(|pair| pair[0].0 != pair[1].0));
+        let stats = options.stats().snapshot();
+        // each shard's queue, its handler, and the one event intake is waiting to queue
+        assert!(stats.intake_high_water <= (shards * queue_bound + shards + 1) as u64, "{}", stats.intake_high_water);
+        assert_eq!(stats.intake_depth, 0);
+        assert!(stats.time_full > Duration::ZERO);
+        consuming.abort();
+    }
+
+    fn histogram(latencies_ms: &[u64]) -> LatencySnapshot {
+        let histogram = LatencyHistogram::default();
+        for ms in latencies_ms {
+            histogram.record(Duration::from_millis(*ms));
+        }
+        histogram.snapshot()
+    }
+
+    #[test]
+    fn histogram_places_latencies_in_their_buckets() {
+        // bounds are inclusive: 5ms lands in the first bucket, 6ms in the second
+        let snapshot = histogram(&[1, 5, 6, 10, 700, 30_000, 30_001, 90_000]);
+        assert_eq!(snapshot.counts, [2, 2, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 2]);
+        assert_eq!(snapshot.count(), 8);
+        assert_eq!(snapshot.total, Duration::from_millis(1 + 5 + 6 + 10 + 700 + 30_000 + 30_001 + 90_000));
+        assert_eq!(snapshot.mean(), Some(Duration::from_micros(18_840_375)));
+    }
+
+    #[test]
+    fn histogram_quantiles_are_bucket_upper_bounds() {
+        // 90 fast handlers, 9 slower ones and one very slow one
+        let latencies = [3; 90].into_iter().chain([200; 9]).chain([4_000]).collect::<Vec<u64>>();
+        let snapshot = histogram(&latencies);
+        assert_eq!(snapshot.quantile(0.0), Some(Duration::from_millis(5)));
+        assert_eq!(snapshot.quantile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(snapshot.quantile(0.9), Some(Duration::from_millis(5)));
+        assert_eq!(snapshot.quantile(0.91), Some(Duration::from_millis(250)));
+        assert_eq!(snapshot.quantile(0.99), Some(Duration::from_millis(250)));
+        assert_eq!(snapshot.quantile(1.0), Some(Duration::from_secs(5)));
+        // out of range quantiles are clamped
+        assert_eq!(snapshot.quantile(2.0), Some(Duration::from_secs(5)));
+        assert_eq!(snapshot.quantile(-1.0), Some(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn histogram_quantiles_of_nothing_or_the_overflow_bucket() {
+        let empty = histogram(&[]);
+        assert_eq!((empty.count(), empty.mean(), empty.quantile(0.5)), (0, None, None));
+        let slow = histogram(&[10, 60_000]);
+        assert_eq!(slow.quantile(0.5), Some(Duration::from_millis(10)));
+        assert_eq!(slow.quantile(0.99), Some(Duration::MAX));
+    }
+}
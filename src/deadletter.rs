@@ -0,0 +1,532 @@
+//! The deadletter module gives every backend the same place to put messages which could not be processed.
+//! A DeadLetterSink receives a DeadLetterRecord describing the message and why it failed;
+//! sinks are provided for an NSQ topic, an SQS queue, and a local ndjson file.
+//! Dead letters can be pushed back to where they came from with replay(), or triaged one at a time with a quarantine::Quarantine.
+//! A DeadLetterHook (i.e. the DeadLetterNotifier, which publishes rate-limited DeadLetterNotice events to an ops topic)
+//! can be set on ConsumerOptions so that someone finds out when messages start landing in the sink.
+
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines}, sync::Mutex};
+use crate::codec::{self, Codec, JsonCodec};
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, PublisherExt};
+use crate::subscriber::{Delivery, Subscriber};
+
+
+/// Everything known about a message which could not be processed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetterRecord {
+    /// Where the message was consumed from, and where replay() sends it back to
+    pub source: Destination,
+    /// The body exactly as it was received (base64 encoded when serialized)
+    #[serde(with = "codec::base64_bytes")]
+    pub original_body: Bytes,
+    /// How many times the message was delivered before it was dead-lettered
+    pub attempts: u32,
+    /// The error from the final attempt
+    pub last_error: String,
+    /// When the message was dead-lettered
+    pub first_seen: DateTime<Utc>,
+    pub metadata: Metadata,
+}
+
+impl DeadLetterRecord {
+    pub fn new(delivery: &Delivery, last_error: &EventfulError) -> Self {
+        DeadLetterRecord{
+            source: delivery.source.clone(),
+            original_body: delivery.body.clone(),
+            attempts: delivery.attempt,
+            last_error: last_error.to_string(),
+            first_seen: Utc::now(),
+            metadata: delivery.meta.clone(),
+        }
+    }
+}
+
+
+/// A DeadLetterSink is anywhere a DeadLetterRecord can be stored
+#[async_trait]
+pub trait DeadLetterSink: Send + Sync {
+    async fn send(&self, record: DeadLetterRecord) -> Result<(), EventfulError>;
+}
+
+
+/// Publish dead letters, encoded as JSON, to an NSQ topic such as "click_dlq"
+pub struct NsqTopicSink {
+    publisher: Arc<dyn Publisher>,
+    topic: String,
+}
+
+impl NsqTopicSink {
+    /// The publisher would typically be a Daemon or FleetNSQ
+    pub fn new(publisher: Arc<dyn Publisher>, topic: &str) -> Self {
+        NsqTopicSink{publisher, topic: topic.to_string()}
+    }
+}
+
+#[async_trait]
+impl DeadLetterSink for NsqTopicSink {
+    async fn send(&self, record: DeadLetterRecord) -> Result<(), EventfulError> {
+        let body = JsonCodec.encode(&record)?;
+        self.publisher.publish_bytes(&Destination::NsqTopic(self.topic.clone()), body, &Metadata::default()).await?;
+        Ok(())
+    }
+}
+
+
+/// Publish dead letters, encoded as JSON, to an SQS queue.
+/// This is an explicit move; queues with a redrive policy are dead-lettered by SQS itself
+pub struct SqsQueueSink {
+    publisher: Arc<dyn Publisher>,
+    queue_url: String,
+}
+
+impl SqsQueueSink {
+    /// The publisher would typically be a ClientSQS
+    pub fn new(publisher: Arc<dyn Publisher>, queue_url: &str) -> Self {
+        SqsQueueSink{publisher, queue_url: queue_url.to_string()}
+    }
+}
+
+#[async_trait]
+impl DeadLetterSink for SqsQueueSink {
+    async fn send(&self, record: DeadLetterRecord) -> Result<(), EventfulError> {
+        let body = JsonCodec.encode(&record)?;
+        let meta = Metadata{group_id: record.metadata.group_id.clone(), ..Default::default()};
+        self.publisher.publish_bytes(&Destination::SqsQueue(self.queue_url.clone()), body, &meta).await?;
+        Ok(())
+    }
+}
+
+
+/// Append dead letters to a local file, one JSON record per line
+pub struct FileSink {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl FileSink {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FileSink{path: path.as_ref().to_path_buf(), file: Mutex::new(None)}
+    }
+}
+
+#[async_trait]
+impl DeadLetterSink for FileSink {
+    async fn send(&self, record: DeadLetterRecord) -> Result<(), EventfulError> {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        if file.is_none() {
+            *file = Some(OpenOptions::new().create(true).append(true).open(&self.path).await?);
+        }
+        let handle = file.as_mut().unwrap();
+        handle.write_all(&line).await?;
+        handle.flush().await?;
+        Ok(())
+    }
+}
+
+
+/// A DeadLetterHook is told about every message once it has been dead-lettered.
+/// The consumer loop runs it in its own task with a timeout, so a slow or panicking hook can't stall consumption
+#[async_trait]
+pub trait DeadLetterHook: Send + Sync {
+    async fn on_dead_letter(&self, record: &DeadLetterRecord);
+}
+
+/// How long a DeadLetterHook may run before it is abandoned
+pub(crate) const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run a hook in the background, abandoning it after HOOK_TIMEOUT
+pub(crate) fn spawn_hook(hook: Arc<dyn DeadLetterHook>, record: DeadLetterRecord) {
+    tokio::spawn(async move {
+        let _ = tokio::time::timeout(HOOK_TIMEOUT, hook.on_dead_letter(&record)).await;
+    });
+}
+
+
+/// A compact summary of dead letters from one source, published by the DeadLetterNotifier
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetterNotice {
+    pub source: Destination,
+    /// How many messages from the source were dead-lettered since the previous notice (including this one)
+    pub count: u64,
+    /// The last_error of the message which triggered this notice
+    pub sample_error: String,
+    /// When the first of the counted messages was dead-lettered
+    pub since: DateTime<Utc>,
+    pub notified_at: DateTime<Utc>,
+}
+
+
+struct SourceWindow {
+    /// When the last notice for the source was sent
+    notified_at: Option<DateTime<Utc>>,
+    /// Dead letters since that notice
+    count: u64,
+    since: DateTime<Utc>,
+}
+
+/// Decides when to send a notice: at most one per source per window.
+/// Dead letters which arrive while a source is quiet are counted and reported in its next notice
+pub struct NoticeWindow {
+    window: chrono::Duration,
+    sources: HashMap<Destination, SourceWindow>,
+}
+
+impl NoticeWindow {
+    pub fn new(window: Duration) -> Self {
+        NoticeWindow{window: chrono::Duration::from_std(window).unwrap_or(chrono::Duration::MAX), sources: HashMap::new()}
+    }
+
+    /// Count a dead letter from record.source at `now`, returning a notice if one is due
+    pub fn observe(&mut self, record: &DeadLetterRecord, now: DateTime<Utc>) -> Option<DeadLetterNotice> {
+        let source = self.sources.entry(record.source.clone()).or_insert(SourceWindow{notified_at: None, count: 0, since: now});
+        if source.count == 0 {
+            source.since = now;
+        }
+        source.count += 1;
+        let due = match source.notified_at {
+            Some(notified_at) => now - notified_at >= self.window,
+            None => true,
+        };
+        if !due {
+            return None
+        }
+        let notice = DeadLetterNotice{
+            source: record.source.clone(),
+            count: source.count,
+            sample_error: record.last_error.clone(),
+            since: source.since,
+            notified_at: now,
+        };
+        source.notified_at = Some(now);
+        source.count = 0;
+        Some(notice)
+    }
+}
+
+
+/// A DeadLetterHook which publishes a DeadLetterNotice (as JSON) to an ops destination,
+/// at most once per window per source so a burst of failures doesn't become a burst of alerts
+pub struct DeadLetterNotifier {
+    publisher: Arc<dyn Publisher>,
+    dest: Destination,
+    window: std::sync::Mutex<NoticeWindow>,
+}
+
+impl DeadLetterNotifier {
+    /// Notify at most once per 5 minutes per source
+    pub fn new(publisher: Arc<dyn Publisher>, dest: Destination) -> Self {
+        DeadLetterNotifier{publisher, dest, window: std::sync::Mutex::new(NoticeWindow::new(Duration::from_secs(300)))}
+    }
+
+    /// Notify at most once per this long per source (default 5 minutes)
+    pub fn window(self, window: Duration) -> Self {
+        DeadLetterNotifier{window: std::sync::Mutex::new(NoticeWindow::new(window)), ..self}
+    }
+}
+
+#[async_trait]
+impl DeadLetterHook for DeadLetterNotifier {
+    async fn on_dead_letter(&self, record: &DeadLetterRecord) {
+        let notice = self.window.lock().unwrap().observe(record, Utc::now());
+        if let Some(notice) = notice {
+            let _ = self.publisher.publish_event(&self.dest, &notice).await;
+        }
+    }
+}
+
+
+/// A DeadLetterReader yields dead letters back out of wherever a sink put them
+#[async_trait]
+pub trait DeadLetterReader: Send {
+    async fn next_record(&mut self) -> Result<Option<DeadLetterRecord>, EventfulError>;
+
+    /// Called once the record most recently returned by next_record has been replayed
+    async fn replayed(&mut self) -> Result<(), EventfulError> {
+        Ok(())
+    }
+
+    /// Called when publishing the record most recently returned by next_record failed
+    async fn replay_failed(&mut self, _error: &EventfulError) -> Result<(), EventfulError> {
+        Ok(())
+    }
+}
+
+
+/// Read the dead letters written by a FileSink
+pub struct FileReader {
+    lines: Lines<BufReader<File>>,
+}
+
+impl FileReader {
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, EventfulError> {
+        let file = File::open(path).await?;
+        Ok(FileReader{lines: BufReader::new(file).lines()})
+    }
+}
+
+#[async_trait]
+impl DeadLetterReader for FileReader {
+    async fn next_record(&mut self) -> Result<Option<DeadLetterRecord>, EventfulError> {
+        while let Some(line) = self.lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue
+            }
+            return Ok(Some(serde_json::from_str(&line)?))
+        }
+        Ok(None)
+    }
+}
+
+
+/// Read the dead letters published by an NsqTopicSink or SqsQueueSink, by subscribing to that topic or queue.
+/// Each message is acked only after its record has been replayed, and nacked if replaying it fails
+pub struct SubscriberReader {
+    subscriber: Box<dyn Subscriber>,
+    pending: Option<Delivery>,
+    retry_delay: Duration,
+}
+
+impl SubscriberReader {
+    pub fn new(subscriber: Box<dyn Subscriber>) -> Self {
+        SubscriberReader{subscriber, pending: None, retry_delay: Duration::from_secs(30)}
+    }
+
+    /// How long a dead letter whose replay failed waits before it is redelivered (default 30 seconds)
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+}
+
+#[async_trait]
+impl DeadLetterReader for SubscriberReader {
+    async fn next_record(&mut self) -> Result<Option<DeadLetterRecord>, EventfulError> {
+        let delivery = match self.subscriber.next().await? {
+            Some(delivery) => delivery,
+            None => return Ok(None),
+        };
+        let record: DeadLetterRecord = JsonCodec.decode(&delivery.body)?;
+        self.pending = Some(delivery);
+        Ok(Some(record))
+    }
+
+    async fn replayed(&mut self) -> Result<(), EventfulError> {
+        match self.pending.take() {
+            Some(delivery) => delivery.ack().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn replay_failed(&mut self, error: &EventfulError) -> Result<(), EventfulError> {
+        crate::trace::record_error(error);
+        match self.pending.take() {
+            Some(delivery) => delivery.nack(self.retry_delay).await,
+            None => Ok(()),
+        }
+    }
+}
+
+
+/// How a call to replay() went
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplaySummary {
+    pub replayed: u64,
+    pub failed: u64,
+}
+
+/// Push every dead letter from a reader back to its original destination
+pub async fn replay(reader: &mut dyn DeadLetterReader, publisher: &dyn Publisher) -> Result<ReplaySummary, EventfulError> {
+    let mut summary = ReplaySummary::default();
+    while let Some(record) = reader.next_record().await? {
+        match publisher.publish_bytes(&record.source, record.original_body.clone(), &record.metadata).await {
+            Ok(_) => {
+                reader.replayed().await?;
+                summary.replayed += 1;
+            },
+            Err(err) => {
+                reader.replay_failed(&err).await?;
+                summary.failed += 1;
+            },
+        }
+    }
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBroker;
+    use crate::subscriber::Ack;
+
+    struct Settled;
+
+    #[async_trait]
+    impl Ack for Settled {
+        async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+            Ok(())
+        }
+
+        async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+            Ok(())
+        }
+    }
+
+    fn clicks() -> Destination {
+        Destination::NsqTopic("click".to_string())
+    }
+
+    fn record(source: Destination, body: &'static str) -> DeadLetterRecord {
+        let meta = Metadata{group_id: Some("user-5".to_string()), ..Default::default()};
+        let delivery = Delivery::new(source, Bytes::from_static(body.as_bytes()), meta, Some("m1".to_string()), 5, Box::new(Settled));
+        DeadLetterRecord::new(&delivery, &EventfulError::Config("bad click".to_string()))
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("eventful-deadletter-{}-{}.ndjson", name, rand::random::<u32>()))
+    }
+
+    #[tokio::test]
+    async fn file_sink_records_read_back_unchanged() {
+        let path = temp_path("file");
+        let sink = FileSink::new(&path);
+        let records = [record(clicks(), "{\"user_id\": 5}"), record(clicks(), "not json \u{1F980}")];
+        for record in records.iter() {
+            sink.send(record.clone()).await.unwrap();
+        }
+        let mut reader = FileReader::open(&path).await.unwrap();
+        assert_eq!(reader.next_record().await.unwrap().as_ref(), Some(&records[0]));
+        assert_eq!(reader.next_record().await.unwrap().as_ref(), Some(&records[1]));
+        assert_eq!(reader.next_record().await.unwrap(), None);
+        assert_eq!(records[0].attempts, 5);
+        assert!(records[0].last_error.contains("bad click"), "{}", records[0].last_error);
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn topic_and_queue_sinks_publish_json_records() {
+        let broker = MemoryBroker::new();
+        NsqTopicSink::new(Arc::new(broker.clone()), "click_dlq").send(record(clicks(), "a")).await.unwrap();
+        let queue = "https://sqs.us-east-1.amazonaws.com/123456789012/click_dlq.fifo";
+        SqsQueueSink::new(Arc::new(broker.clone()), queue).send(record(clicks(), "b")).await.unwrap();
+
+        let published = broker.published();
+        assert_eq!(published[0].0, Destination::NsqTopic("click_dlq".to_string()));
+        assert_eq!(published[1].0, Destination::SqsQueue(queue.to_string()));
+        let sent: Vec<DeadLetterRecord> = published.iter().map(|(_, body)| JsonCodec.decode(body).unwrap()).collect();
+        assert_eq!((sent[0].original_body.clone(), sent[1].original_body.clone()), (Bytes::from_static(b"a"), Bytes::from_static(b"b")));
+
+        // the queue sink keeps the group id, so a FIFO dead-letter queue accepts the record
+        let mut queue_subscription = broker.subscribe(&Destination::SqsQueue(queue.to_string()), "replay");
+        let delivery = queue_subscription.next().await.unwrap().unwrap();
+        assert_eq!(delivery.meta.group_id.as_deref(), Some("user-5"));
+        delivery.ack().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn replay_sends_file_records_back_to_their_source() {
+        let path = temp_path("replay");
+        let sink = FileSink::new(&path);
+        sink.send(record(clicks(), "a")).await.unwrap();
+        sink.send(record(Destination::NsqTopic("view".to_string()), "b")).await.unwrap();
+        sink.send(record(clicks(), "c")).await.unwrap();
+
+        let broker = MemoryBroker::new();
+        broker.fail_next_publish();
+        let summary = replay(&mut FileReader::open(&path).await.unwrap(), &broker).await.unwrap();
+        assert_eq!(summary, ReplaySummary{replayed: 2, failed: 1});
+        assert_eq!(broker.published_to("click"), vec![Bytes::from_static(b"c")]);
+        assert_eq!(broker.published_to("view"), vec![Bytes::from_static(b"b")]);
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    /// Ends after `left` deliveries, so replay() returns
+    struct Take {
+        inner: Box<dyn Subscriber>,
+        left: usize,
+    }
+
+    #[async_trait]
+    impl Subscriber for Take {
+        async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+            if self.left == 0 {
+                return Ok(None)
+            }
+            self.left -= 1;
+            self.inner.next().await
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_acks_what_it_sends_and_nacks_what_fails() {
+        let (dlq, target) = (MemoryBroker::new(), MemoryBroker::new());
+        let sink = NsqTopicSink::new(Arc::new(dlq.clone()), "click_dlq");
+        let subscription = dlq.subscribe(&Destination::NsqTopic("click_dlq".to_string()), "replay");
+        sink.send(record(clicks(), "a")).await.unwrap();
+        sink.send(record(clicks(), "b")).await.unwrap();
+
+        target.fail_next_publish();
+        let mut reader = SubscriberReader::new(Box::new(Take{inner: Box::new(subscription), left: 3})).retry_delay(Duration::ZERO);
+        let summary = replay(&mut reader, &target).await.unwrap();
+        // a failed, was nacked, and came back after b
+        assert_eq!(summary, ReplaySummary{replayed: 2, failed: 1});
+        assert_eq!(target.published_to("click"), vec![Bytes::from_static(b"b"), Bytes::from_static(b"a")]);
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        DateTime::<Utc>::from_timestamp(1_700_000_000 + seconds, 0).unwrap()
+    }
+
+    #[test]
+    fn the_first_dead_letter_from_a_source_is_notified_at_once() {
+        let mut window = NoticeWindow::new(Duration::from_secs(300));
+        let first = record(clicks(), "a");
+        let notice = window.observe(&first, at(0)).unwrap();
+        assert_eq!(notice, DeadLetterNotice{source: clicks(), count: 1, sample_error: first.last_error, since: at(0), notified_at: at(0)});
+    }
+
+    #[test]
+    fn dead_letters_within_the_window_are_counted_into_the_next_notice() {
+        let mut window = NoticeWindow::new(Duration::from_secs(300));
+        window.observe(&record(clicks(), "a"), at(0)).unwrap();
+        assert_eq!(window.observe(&record(clicks(), "b"), at(10)), None);
+        assert_eq!(window.observe(&record(clicks(), "c"), at(299)), None);
+        let notice = window.observe(&record(clicks(), "d"), at(300)).unwrap();
+        assert_eq!((notice.count, notice.since, notice.notified_at), (3, at(10), at(300)));
+
+        // a quiet spell resets since to the next dead letter
+        assert_eq!(window.observe(&record(clicks(), "e"), at(400)), None);
+        let notice = window.observe(&record(clicks(), "f"), at(1000)).unwrap();
+        assert_eq!((notice.count, notice.since), (2, at(400)));
+    }
+
+    #[test]
+    fn sources_have_separate_windows() {
+        let mut window = NoticeWindow::new(Duration::from_secs(300));
+        let views = Destination::NsqTopic("view".to_string());
+        assert!(window.observe(&record(clicks(), "a"), at(0)).is_some());
+        assert!(window.observe(&record(views.clone(), "a"), at(1)).is_some());
+        assert!(window.observe(&record(clicks(), "b"), at(2)).is_none());
+        assert!(window.observe(&record(views, "b"), at(3)).is_none());
+    }
+
+    #[tokio::test]
+    async fn the_notifier_publishes_notices_to_its_destination() {
+        let broker = MemoryBroker::new();
+        let ops = Destination::NsqTopic("ops_alerts".to_string());
+        let notifier = DeadLetterNotifier::new(Arc::new(broker.clone()), ops).window(Duration::from_secs(3600));
+        notifier.on_dead_letter(&record(clicks(), "a")).await;
+        notifier.on_dead_letter(&record(clicks(), "b")).await;
+        let notices: Vec<DeadLetterNotice> = broker.published_events("ops_alerts").unwrap();
+        assert_eq!(notices.len(), 1);
+        assert_eq!((notices[0].source.clone(), notices[0].count), (clicks(), 1));
+    }
+}
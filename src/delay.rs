@@ -0,0 +1,288 @@
+//! The delay module stretches delayed delivery past what a backend allows (an hour on NSQ, 15 minutes on SQS).
+//! A RedeferPublisher wraps the backend's publisher. Delays within the backend's max_delay are passed straight through;
+//! longer ones are wrapped in a DeferredEnvelope, which records the final destination and when to deliver, and published
+//! to a holding destination with as much delay as the backend allows. run() consumes the holding destination and keeps
+//! re-deferring each envelope until its time comes, then publishes the original message to its destination.
+//!
+//! Each envelope is acked only after its next hop has been published, so a crash loses nothing: the envelope is
+//! redelivered and handled again. A crash between publishing and acking can publish the next hop twice, so the final
+//! message is delivered at least once. deliver_at is wall-clock time, so an envelope whose time passed while nothing
+//! was consuming the holding destination is delivered as soon as run() sees it.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use std::{sync::Arc, time::Duration};
+//! # use eventful::prelude::*;
+//! # use eventful::bus::{Backend, EventBus};
+//! # use eventful::delay::RedeferPublisher;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Reminder { order_id: u64 }
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Deferred {}
+//! # impl EventNSQ for Deferred { fn topic() -> &'static str { "eventful_deferred" } }
+//! # struct DeferredChannel;
+//! # impl ChannelConsumer<Deferred> for DeferredChannel { fn channel(&self) -> String { "redefer".to_string() } }
+//! # async fn demo(daemon: Daemon, reminders: Destination, reminder: Reminder, deferred_channel: DeferredChannel) -> Result<(), EventfulError> {
+//! let holding = Destination::NsqTopic("eventful_deferred".to_string());
+//! let publisher = Arc::new(RedeferPublisher::new(FleetNSQ::new_from_env(), holding));
+//! let bus = EventBus::new().publisher(Backend::Nsq, publisher.clone()).route::<Reminder>(reminders);
+//! bus.emit_after(&reminder, Duration::from_secs(24 * 3600)).await?;
+//! // somewhere, one consumer of the holding topic
+//! publisher.run(Box::new(deferred_channel.subscribe(&[&daemon]))).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::time::Duration;
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use crate::codec::{self, Codec, JsonCodec};
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::subscriber::Subscriber;
+
+
+/// How long a failed hop waits before the envelope is redelivered
+const RETRY_HOP_AFTER: Duration = Duration::from_secs(5);
+
+
+/// A message waiting in the holding destination for its delivery time
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeferredEnvelope {
+    pub dest: Destination,
+    #[serde(with = "codec::base64_bytes")]
+    pub body: Bytes,
+    pub metadata: Metadata,
+    pub deliver_at: DateTime<Utc>,
+}
+
+
+/// A RedeferPublisher accepts any delay, re-deferring through a holding destination when the inner publisher can't
+pub struct RedeferPublisher<P> {
+    inner: P,
+    holding: Destination,
+}
+
+impl<P: Publisher> RedeferPublisher<P> {
+    /// holding must be a destination the inner publisher can delay messages on, i.e. a dedicated NSQ topic
+    pub fn new(inner: P, holding: Destination) -> Self {
+        RedeferPublisher{inner, holding}
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    pub fn holding(&self) -> &Destination {
+        &self.holding
+    }
+
+    /// Publish an envelope to the holding destination, deferred by as much of `remaining` as the inner publisher allows
+    async fn hold(&self, envelope: &DeferredEnvelope, remaining: Duration) -> Result<Receipt, EventfulError> {
+        let max = self.inner.max_delay().ok_or_else(|| EventfulError::DelayUnsupported(self.holding.to_string()))?;
+        let body = JsonCodec.encode(envelope)?;
+        self.inner.publish_delayed(&self.holding, body, &Metadata::default(), remaining.min(max)).await
+    }
+
+    /// Consume envelopes from the holding destination until the subscriber ends, re-deferring each until it is due
+    /// and then publishing it to its destination. Bodies which aren't envelopes are acked and dropped
+    pub async fn run(&self, mut subscriber: Box<dyn Subscriber>) -> Result<(), EventfulError> {
+        while let Some(delivery) = subscriber.next().await? {
+            let envelope: DeferredEnvelope = match JsonCodec.decode(&delivery.body) {
+                Ok(envelope) => envelope,
+                Err(_) => {
+                    delivery.ack().await?;
+                    continue
+                },
+            };
+            let remaining = (envelope.deliver_at - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+            let hop = match remaining.is_zero() {
+                true => self.inner.publish_bytes(&envelope.dest, envelope.body.clone(), &envelope.metadata).await,
+                false => self.hold(&envelope, remaining).await,
+            };
+            match hop {
+                Ok(_) => delivery.ack().await?,
+                Err(_) => delivery.nack(RETRY_HOP_AFTER).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for RedeferPublisher<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        self.inner.publish_bytes(dest, body, meta).await
+    }
+
+    /// Any delay can be honored, as long as the inner publisher can delay at all.
+    /// (A delay reaching past the last time chrono can represent fails with DelayTooLong)
+    fn max_delay(&self) -> Option<Duration> {
+        self.inner.max_delay().map(|_| Duration::MAX)
+    }
+
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        match self.inner.max_delay() {
+            Some(max) if delay <= max => self.inner.publish_delayed(dest, body, meta, delay).await,
+            Some(_) => {
+                let now = Utc::now();
+                let deliver_at = chrono::Duration::from_std(delay).ok()
+                    .and_then(|delay| now.checked_add_signed(delay))
+                    .ok_or_else(|| EventfulError::DelayTooLong{
+                        destination: dest.to_string(),
+                        requested: delay,
+                        max: (DateTime::<Utc>::MAX_UTC - now).to_std().unwrap_or(Duration::ZERO),
+                    })?;
+                let envelope = DeferredEnvelope{dest: dest.clone(), body, metadata: meta.clone(), deliver_at};
+                self.hold(&envelope, delay).await
+            },
+            None => Err(EventfulError::DelayUnsupported(dest.to_string())),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::memory::{MemoryBroker, MemoryConfig, MemorySubscription};
+    use crate::subscriber::{Delivery, OnDrop};
+
+    const MAX: Duration = Duration::from_secs(3600);
+
+    /// Delays up to an hour, recording each delay but publishing straight away so tests don't wait
+    #[derive(Clone, Default)]
+    struct Deferring {
+        broker: MemoryBroker,
+        delays: Arc<Mutex<Vec<Duration>>>,
+    }
+
+    #[async_trait]
+    impl Publisher for Deferring {
+        async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+            self.broker.publish_bytes(dest, body, meta).await
+        }
+
+        fn max_delay(&self) -> Option<Duration> {
+            Some(MAX)
+        }
+
+        async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+            self.delays.lock().unwrap().push(delay);
+            self.broker.publish_bytes(dest, body, meta).await
+        }
+    }
+
+    /// Ends after `left` deliveries, so run() returns
+    struct Take {
+        inner: MemorySubscription,
+        left: usize,
+    }
+
+    #[async_trait]
+    impl Subscriber for Take {
+        async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+            if self.left == 0 {
+                return Ok(None)
+            }
+            self.left -= 1;
+            self.inner.next().await
+        }
+    }
+
+    fn holding() -> Destination {
+        Destination::NsqTopic("eventful_deferred".to_string())
+    }
+
+    fn reminders() -> Destination {
+        Destination::NsqTopic("reminders".to_string())
+    }
+
+    fn envelope(deliver_at: DateTime<Utc>) -> DeferredEnvelope {
+        DeferredEnvelope{dest: reminders(), body: Bytes::from_static(b"remind"), metadata: Metadata::default(), deliver_at}
+    }
+
+    async fn hold(broker: &MemoryBroker, envelope: &DeferredEnvelope) {
+        broker.publish_bytes(&holding(), JsonCodec.encode(envelope).unwrap(), &Metadata::default()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_delays_are_held_and_short_ones_passed_through() {
+        let inner = Deferring::default();
+        let publisher = RedeferPublisher::new(inner.clone(), holding());
+        assert_eq!(publisher.max_delay(), Some(Duration::MAX));
+        publisher.publish_delayed(&reminders(), Bytes::from_static(b"soon"), &Metadata::default(), Duration::from_secs(60)).await.unwrap();
+        publisher.publish_delayed(&reminders(), Bytes::from_static(b"remind"), &Metadata::default(), 3 * MAX).await.unwrap();
+
+        assert_eq!(*inner.delays.lock().unwrap(), vec![Duration::from_secs(60), MAX]);
+        assert_eq!(inner.broker.published_to("reminders"), vec![Bytes::from_static(b"soon")]);
+        let held: Vec<DeferredEnvelope> = inner.broker.published_events("eventful_deferred").unwrap();
+        assert_eq!((held[0].dest.clone(), held[0].body.clone()), (reminders(), Bytes::from_static(b"remind")));
+        let remaining = held[0].deliver_at - Utc::now();
+        assert!(remaining > chrono::Duration::hours(2) && remaining <= chrono::Duration::hours(3));
+    }
+
+    #[tokio::test]
+    async fn a_delay_beyond_what_chrono_can_represent_is_too_long() {
+        let publisher = RedeferPublisher::new(Deferring::default(), holding());
+        let result = publisher.publish_delayed(&reminders(), Bytes::from_static(b"never"), &Metadata::default(), Duration::MAX).await;
+        assert!(matches!(result, Err(EventfulError::DelayTooLong{requested: Duration::MAX, ..})));
+    }
+
+    #[tokio::test]
+    async fn envelopes_are_redeferred_until_due_then_delivered() {
+        let inner = Deferring::default();
+        let publisher = RedeferPublisher::new(inner.clone(), holding());
+        let subscription = inner.broker.subscribe(&holding(), "deferred");
+        let deliver_at = Utc::now() + chrono::Duration::hours(2);
+        hold(&inner.broker, &envelope(deliver_at)).await;
+
+        publisher.run(Box::new(Take{inner: subscription, left: 1})).await.unwrap();
+        assert!(inner.broker.published_to("reminders").is_empty());
+        assert_eq!(*inner.delays.lock().unwrap(), vec![MAX]);
+        let held: Vec<DeferredEnvelope> = inner.broker.published_events("eventful_deferred").unwrap();
+        assert_eq!(held.last(), Some(&envelope(deliver_at)));
+
+        let subscription = inner.broker.subscribe(&holding(), "deferred");
+        hold(&inner.broker, &envelope(Utc::now() - chrono::Duration::seconds(1))).await;
+        // the re-deferred envelope is still first in line, so take both
+        publisher.run(Box::new(Take{inner: subscription, left: 2})).await.unwrap();
+        assert_eq!(inner.broker.published_to("reminders"), vec![Bytes::from_static(b"remind")]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_hop_is_redelivered_rather_than_lost() {
+        let inner = Deferring::default();
+        let publisher = RedeferPublisher::new(inner.clone(), holding());
+        let subscription = inner.broker.subscribe(&holding(), "deferred");
+        hold(&inner.broker, &envelope(Utc::now())).await;
+        inner.broker.fail_next_publish();
+
+        publisher.run(Box::new(Take{inner: subscription, left: 2})).await.unwrap();
+        assert_eq!(inner.broker.published_to("reminders"), vec![Bytes::from_static(b"remind")]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_envelope_is_redelivered_after_a_crash_before_its_ack() {
+        let broker = MemoryBroker::with_config(MemoryConfig{visibility_timeout: Some(Duration::from_secs(30)), ..Default::default()});
+        let inner = Deferring{broker, ..Default::default()};
+        let publisher = RedeferPublisher::new(inner.clone(), holding());
+        let mut subscription = inner.broker.subscribe(&holding(), "deferred");
+        hold(&inner.broker, &envelope(Utc::now())).await;
+
+        // the process dies holding the envelope, before its hop is published or it is acked
+        let delivery = subscription.next().await.unwrap().unwrap();
+        drop(delivery.on_drop(OnDrop::Nothing));
+        assert!(inner.broker.published_to("reminders").is_empty());
+
+        // after a restart, the envelope comes back once its visibility timeout passes
+        publisher.run(Box::new(Take{inner: subscription, left: 1})).await.unwrap();
+        assert_eq!(inner.broker.published_to("reminders"), vec![Bytes::from_static(b"remind")]);
+    }
+}
@@ -3,9 +3,10 @@
 
 use std::{error::Error, fmt};
 
+#[cfg(feature = "sqs")]
 use aws_sdk_sqs::types::{SdkError};
-
-use hyperactive::err::{HypErr};
+#[cfg(feature = "sqs")]
+use aws_smithy_types::retry::ProvideErrorKind;
 
 // The GenericError encompasses almost every possible error type that could be passed.
 // Asynchronous functions that return Result<T, GenericError> can call other functions and use the "?" operator to return the Err() variant as needed.
@@ -18,16 +19,244 @@ pub enum MessageErrSQS {
     Delete(DeleteMessageError),
 } // use fmt::Debug instead */
 
+/// Which part of an AMQP connection an error came from
+#[derive(fmt::Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmqpFailure {
+    /// The TCP connection or the AMQP connection was lost or closed by the broker; reconnect
+    Connection,
+    /// A channel was closed (i.e. by a soft protocol error such as publishing to a missing exchange); the connection may be fine
+    Channel,
+    /// Anything else, i.e. a nacked publish
+    Other,
+}
+
+/// Why an SQS request failed, which decides whether it is worth trying again
+#[cfg(feature = "sqs")]
+#[derive(fmt::Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqsFailure {
+    /// SQS is shedding load (RequestThrottled, ThrottlingException and the like); retryable
+    Throttled,
+    /// No response arrived in time; retryable
+    Timeout,
+    /// SQS answered with a 5xx; retryable
+    Server,
+    /// The request couldn't be sent or its response couldn't be read, i.e. a connection was reset; retryable
+    Dispatch,
+    /// SQS rejected the request itself, i.e. access denied or a malformed request
+    Client,
+    /// The request couldn't be built, so it was never sent
+    Construction,
+}
+
+#[cfg(feature = "sqs")]
+impl SqsFailure {
+    /// Classify an error response by its code and HTTP status
+    pub fn from_response(code: Option<&str>, status: u16) -> Self {
+        match code {
+            Some("RequestThrottled" | "ThrottlingException" | "Throttling" | "ThrottledException" | "RequestLimitExceeded") => SqsFailure::Throttled,
+            Some("InternalError" | "InternalFailure" | "ServiceUnavailable") => SqsFailure::Server,
+            _ if status == 429 => SqsFailure::Throttled,
+            _ if status >= 500 => SqsFailure::Server,
+            _ => SqsFailure::Client,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, SqsFailure::Client | SqsFailure::Construction)
+    }
+}
+
+
 /// The EventError is ergonomic to instantiate and contains a simple error message
 #[derive(fmt::Debug)]
 pub enum EventfulError {
+    #[cfg(feature = "nsq")]
     NSQ,
+    #[cfg(feature = "sqs")]
     SQS(String),
-    Hyperactive(HypErr),
+    /// A string is not the URL of an SQS queue
+    #[cfg(feature = "sqs")]
+    InvalidQueueUrl(String),
+    /// A message is missing a group id for a FIFO queue, or has a group or deduplication id for a standard queue
+    #[cfg(feature = "sqs")]
+    FifoMismatch(String),
+    /// Message attributes SQS would reject: a reserved or malformed name, a bad number, or more than 10 of them
+    #[cfg(feature = "sqs")]
+    InvalidAttributes(String),
+    /// A queue already exists with attributes other than the ones asked for, listed in the message
+    #[cfg(feature = "sqs")]
+    QueueConflict(String),
+    /// The queue was purged in the last 60 seconds, and can't be purged again until then
+    #[cfg(feature = "sqs")]
+    PurgeInProgress(String),
+    /// The queue (named in the message) does not exist, i.e. it was deleted or never created
+    #[cfg(feature = "sqs")]
+    QueueDoesNotExist(String),
+    /// A received message points at a body stored in S3 (named in the message) which is missing, or can't be fetched
+    /// because the client has no S3Offload
+    #[cfg(feature = "sqs")]
+    OffloadMissing(String),
+    /// An SQS response lacked a field (named in the message) it should always have
+    #[cfg(feature = "sqs")]
+    MissingField(String),
+    /// The MD5 digest SQS returned for a sent body is not the digest of the body eventful sent, so what SQS stored is corrupt
+    #[cfg(feature = "sqs")]
+    ChecksumMismatch {
+        message_id: String,
+        expected: String,
+        returned: String,
+    },
+    /// An SQS (or SNS) request failed, with the error code SQS returned if it answered at all
+    #[cfg(feature = "sqs")]
+    SqsRequest {
+        failure: SqsFailure,
+        code: Option<String>,
+        message: String,
+    },
     SerdeJSON(serde_json::Error),
+    /// An HTTP request (i.e. to an nsqd daemon) failed or returned a non-success status
+    Http(String),
+    /// A publisher or subscriber was asked to use a destination it does not support
+    Destination(String),
+    /// An event was emitted but there is no route for its type
+    Unrouted(String),
+    /// Something is misconfigured
+    Config(String),
+    /// A FanoutPublisher can't publish at all, i.e. it has no targets or has been shut down
+    Fanout(String),
+    /// A fan-out publish did not satisfy its FanoutPolicy: fewer than `required` targets succeeded.
+    /// The report has every target's outcome
+    FanoutUnsatisfied {
+        required: usize,
+        report: crate::fanout::FanoutReport,
+    },
+    IO(std::io::Error),
+    /// A database used for the outbox or similar failed
+    Database(String),
+    /// Some, but not all, of a MultiPublish succeeded
+    PartialPublish(String),
+    /// A Kafka producer or consumer failed
+    Kafka(String),
+    /// An SNS request failed
+    SNS(String),
+    /// An S3 request failed
+    S3(String),
+    /// An EventBridge request or entry failed
+    EventBridge(String),
+    /// A Kinesis request failed
+    Kinesis(String),
+    /// An MQTT client request or connection failed
+    Mqtt(String),
+    /// A NATS or JetStream operation failed
+    Nats(String),
+    /// An AMQP (RabbitMQ) connection, channel, or operation failed
+    Amqp {
+        failure: AmqpFailure,
+        message: String,
+    },
+    /// A health check found a component which is not working
+    Unhealthy(String),
+    /// Something did not happen before its deadline
+    Timeout(String),
+    /// A CircuitBreaker refused to publish because the destination has been failing. Try again after retry_after
+    CircuitOpen {
+        destination: String,
+        retry_after: std::time::Duration,
+    },
+    /// A publisher was asked to delay a message but its backend can't delay messages
+    DelayUnsupported(String),
+    /// A publisher was asked to delay a message for longer than its backend allows
+    DelayTooLong {
+        destination: String,
+        requested: std::time::Duration,
+        max: std::time::Duration,
+    },
+    /// A RateLimitedPublisher has no budget left for the destination. Try again after retry_after
+    RateLimited {
+        destination: String,
+        retry_after: std::time::Duration,
+    },
+    /// A subscriber fell so far behind that `count` events were discarded before it could receive them
+    MessagesDropped {
+        topic: String,
+        count: u64,
+    },
+    /// An operation was retried as many times as its RetryPolicy allows
+    RetriesExhausted {
+        attempts: u32,
+        last_error: Box<EventfulError>,
+    },
 }
 
-impl Error for EventfulError {}
+impl EventfulError {
+    /// Might the same operation succeed if it is tried again?  
+    /// Network, broker, and database failures are retryable; encoding, routing, and configuration mistakes are not
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            #[cfg(feature = "nsq")]
+            EventfulError::NSQ => true,
+            #[cfg(feature = "sqs")]
+            EventfulError::SQS(_) => true,
+            EventfulError::SNS(_) => true,
+            EventfulError::S3(_) => true,
+            EventfulError::Http(_) => true,
+            EventfulError::IO(_) => true,
+            EventfulError::Database(_) => true,
+            EventfulError::Fanout(_) => true,
+            EventfulError::FanoutUnsatisfied{..} => true,
+            EventfulError::PartialPublish(_) => true,
+            EventfulError::Kafka(_) => true,
+            EventfulError::Amqp{..} => true,
+            EventfulError::Nats(_) => true,
+            EventfulError::Mqtt(_) => true,
+            EventfulError::Kinesis(_) => true,
+            EventfulError::EventBridge(_) => true,
+            EventfulError::Unhealthy(_) => true,
+            EventfulError::Timeout(_) => true,
+            EventfulError::CircuitOpen{..} => true,
+            EventfulError::RateLimited{..} => true,
+            EventfulError::SerdeJSON(_) => false,
+            EventfulError::Destination(_) => false,
+            EventfulError::Unrouted(_) => false,
+            EventfulError::Config(_) => false,
+            #[cfg(feature = "sqs")]
+            EventfulError::InvalidQueueUrl(_) => false,
+            #[cfg(feature = "sqs")]
+            EventfulError::FifoMismatch(_) => false,
+            #[cfg(feature = "sqs")]
+            EventfulError::InvalidAttributes(_) => false,
+            #[cfg(feature = "sqs")]
+            EventfulError::QueueConflict(_) => false,
+            #[cfg(feature = "sqs")]
+            EventfulError::PurgeInProgress(_) => true,
+            #[cfg(feature = "sqs")]
+            EventfulError::QueueDoesNotExist(_) => false,
+            #[cfg(feature = "sqs")]
+            EventfulError::SqsRequest{failure, ..} => failure.is_retryable(),
+            #[cfg(feature = "sqs")]
+            EventfulError::OffloadMissing(_) => false,
+            #[cfg(feature = "sqs")]
+            EventfulError::MissingField(_) => false,
+            #[cfg(feature = "sqs")]
+            EventfulError::ChecksumMismatch{..} => false,
+            EventfulError::DelayUnsupported(_) => false,
+            EventfulError::DelayTooLong{..} => false,
+            EventfulError::RetriesExhausted{..} => false,
+            EventfulError::MessagesDropped{..} => false,
+        }
+    }
+}
+
+impl Error for EventfulError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EventfulError::IO(err) => Some(err),
+            EventfulError::SerdeJSON(err) => Some(err),
+            EventfulError::RetriesExhausted{last_error, ..} => Some(last_error.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl fmt::Display for EventfulError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -36,23 +265,64 @@ impl fmt::Display for EventfulError {
 }
 
 
-impl<T: fmt::Debug> From<SdkError<T>> for EventfulError {
+#[cfg(feature = "sqs")]
+impl<T: ProvideErrorKind + fmt::Debug> From<SdkError<T>> for EventfulError {
     fn from(err: SdkError<T>) -> Self {
-        EventfulError::SQS(format!("{:?}", err))
+        let (failure, code) = match &err {
+            SdkError::ConstructionFailure(_) => (SqsFailure::Construction, None),
+            SdkError::TimeoutError(_) => (SqsFailure::Timeout, None),
+            SdkError::DispatchFailure(dispatch) if dispatch.is_timeout() => (SqsFailure::Timeout, None),
+            SdkError::DispatchFailure(dispatch) if dispatch.is_user() => (SqsFailure::Construction, None),
+            SdkError::ResponseError(context) if context.raw().http().status().is_server_error() => (SqsFailure::Server, None),
+            SdkError::ServiceError(context) => {
+                let code = context.err().code();
+                (SqsFailure::from_response(code, context.raw().http().status().as_u16()), code.map(str::to_string))
+            },
+            _ => (SqsFailure::Dispatch, None),
+        };
+        EventfulError::SqsRequest{failure, code, message: format!("{:?}", err)}
+    }
+}
+
+impl From<serde_json::Error> for EventfulError {
+    fn from(err: serde_json::Error) -> Self {
+        EventfulError::SerdeJSON(err)
     }
 }
 
-impl From<HypErr> for EventfulError {
-    fn from(err: HypErr) -> Self {
-        EventfulError::Hyperactive(err)
+
+impl From<std::io::Error> for EventfulError {
+    fn from(err: std::io::Error) -> Self {
+        EventfulError::IO(err)
     }
 }
 
 
-impl From<serde_json::Error> for EventfulError {
-    fn from(err: serde_json::Error) -> Self {
-        EventfulError::SerdeJSON(err)
+impl From<hyper::Error> for EventfulError {
+    fn from(err: hyper::Error) -> Self {
+        EventfulError::Http(format!("{:?}", err))
+    }
+}
+
+
+impl From<hyper::http::Error> for EventfulError {
+    fn from(err: hyper::http::Error) -> Self {
+        EventfulError::Http(format!("{:?}", err))
     }
 }
 
 
+#[cfg(feature = "postgres")]
+impl From<sqlx::Error> for EventfulError {
+    fn from(err: sqlx::Error) -> Self {
+        EventfulError::Database(format!("{:?}", err))
+    }
+}
+
+
+#[cfg(feature = "redis")]
+impl From<redis::RedisError> for EventfulError {
+    fn from(err: redis::RedisError) -> Self {
+        EventfulError::Database(format!("{:?}", err))
+    }
+}
@@ -0,0 +1,369 @@
+//! The eventbridge module publishes events to [Amazon EventBridge](https://aws.amazon.com/eventbridge/) buses,
+//! where rules (possibly in other AWS accounts) route them onwards. It is enabled by the `eventbridge` feature.
+//! 
+//! publish_batch splits entries into PutEvents calls of at most 10 entries (and 256 KB), rejects entries over the
+//! 256 KB entry limit without sending them, and retries entries which failed with a throttling or internal error.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use eventful::prelude::*;
+//! # use eventful::eventbridge::{ClientEventBridge, EventBridgeEvent};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct OrderPlaced { order_id: u64 }
+//! impl EventBridgeEvent for OrderPlaced {
+//!     fn event_bus_name() -> &'static str {
+//!         "orders"
+//!     }
+//!     fn detail_type() -> &'static str {
+//!         "OrderPlaced"
+//!     }
+//!     fn source() -> &'static str {
+//!         "com.example.checkout"
+//!     }
+//! }
+//!
+//! # async fn demo(orders: Vec<OrderPlaced>) -> Result<(), EventfulError> {
+//! let eventbridge = ClientEventBridge::new("us-east-1").await;
+//! let results = eventbridge.publish_batch(&orders).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use async_trait::async_trait;
+pub use aws_sdk_eventbridge::Client;
+use aws_sdk_eventbridge::{Region, model::PutEventsRequestEntry};
+use bytes::Bytes;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::err::EventfulError;
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+use crate::retry::{RetryPolicy, execute_with_retry};
+
+
+/// PutEvents accepts at most this many entries
+pub const MAX_BATCH_ENTRIES: usize = 10;
+/// The most bytes one entry (or one PutEvents request) may be
+pub const MAX_ENTRY_BYTES: usize = 256 * 1024;
+/// The header the Publisher implementation reads the detail-type from
+pub const DETAIL_TYPE_HEADER: &str = "detail-type";
+
+/// Entry error codes worth retrying
+const RETRYABLE_CODES: [&str; 2] = ["ThrottlingException", "InternalFailure"];
+
+
+/// Implement EventBridgeEvent on a struct to put it on an event bus
+pub trait EventBridgeEvent: Serialize + DeserializeOwned {
+    fn event_bus_name() -> &'static str;
+    fn detail_type() -> &'static str;
+    fn source() -> &'static str;
+}
+
+
+/// One entry to put: the event serialized as the entry's detail
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryEventBridge {
+    pub event_bus_name: String,
+    pub detail_type: String,
+    pub source: String,
+    /// A JSON object
+    pub detail: String,
+}
+
+impl EntryEventBridge {
+    /// The size EventBridge counts against the 256 KB limit
+    pub fn size(&self) -> usize {
+        self.source.len() + self.detail_type.len() + self.detail.len()
+    }
+}
+
+
+/// The outcome of one entry, in the same order as the input
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntryResult {
+    Published { event_id: String },
+    Failed { code: String, message: String },
+}
+
+impl EntryResult {
+    pub fn is_published(&self) -> bool {
+        matches!(self, EntryResult::Published{..})
+    }
+}
+
+
+#[derive(Clone)]
+pub struct ClientEventBridge {
+    client: Client,
+    retry: RetryPolicy,
+    source: String,
+}
+
+impl ClientEventBridge {
+    pub async fn new(region: &'static str) -> Self {
+        let config = aws_config::from_env().region(Region::new(region)).load().await;
+        ClientEventBridge::from_client(Client::new(&config))
+    }
+
+    pub fn from_client(client: Client) -> Self {
+        ClientEventBridge{client, retry: RetryPolicy::default(), source: "eventful".to_string()}
+    }
+
+    /// How throttled requests and entries are retried (default: RetryPolicy::default())
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The source used by the Publisher implementation (default "eventful")
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = source.to_string();
+        self
+    }
+
+    /// The underlying aws_sdk_eventbridge Client, for operations eventful does not wrap
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Serialize an event and put it on its bus, returning its event id
+    pub async fn publish<T: EventBridgeEvent>(&self, event: &T) -> Result<String, EventfulError> {
+        let mut results = self.publish_batch(std::slice::from_ref(event)).await?;
+        match results.remove(0) {
+            EntryResult::Published{event_id} => Ok(event_id),
+            EntryResult::Failed{code, message} => Err(EventfulError::EventBridge(format!("{}: {}", code, message))),
+        }
+    }
+
+    /// Serialize events and put them on their bus, returning one result per event
+    pub async fn publish_batch<T: EventBridgeEvent>(&self, events: &[T]) -> Result<Vec<EntryResult>, EventfulError> {
+        let mut entries = Vec::with_capacity(events.len());
+        for event in events {
+            entries.push(EntryEventBridge{
+                event_bus_name: <T as EventBridgeEvent>::event_bus_name().to_string(),
+                detail_type: <T as EventBridgeEvent>::detail_type().to_string(),
+                source: <T as EventBridgeEvent>::source().to_string(),
+                detail: serde_json::to_string(event)?,
+            });
+        }
+        self.put_entries(entries).await
+    }
+
+    /// Put entries, returning one result per entry in the same order.
+    /// A request which fails outright (after retries) is returned as an error
+    pub async fn put_entries(&self, entries: Vec<EntryEventBridge>) -> Result<Vec<EntryResult>, EventfulError> {
+        let mut results: Vec<Option<EntryResult>> = vec![None; entries.len()];
+        let mut batch: Vec<(usize, EntryEventBridge)> = Vec::new();
+        let mut batch_bytes = 0;
+        for (i, entry) in entries.into_iter().enumerate() {
+            if entry.size() > MAX_ENTRY_BYTES {
+                let message = format!("entry is {} bytes, over the {} byte limit", entry.size(), MAX_ENTRY_BYTES);
+                results[i] = Some(EntryResult::Failed{code: "EntryTooLarge".to_string(), message});
+                continue
+            }
+            if batch.len() >= MAX_BATCH_ENTRIES || batch_bytes + entry.size() > MAX_ENTRY_BYTES {
+                self.put_batch(std::mem::take(&mut batch), &mut results).await?;
+                batch_bytes = 0;
+            }
+            batch_bytes += entry.size();
+            batch.push((i, entry));
+        }
+        if !batch.is_empty() {
+            self.put_batch(batch, &mut results).await?;
+        }
+        Ok(results.into_iter().map(|result| result.expect("every entry has a result")).collect())
+    }
+
+    async fn put_batch(&self, mut pending: Vec<(usize, EntryEventBridge)>, results: &mut [Option<EntryResult>]) -> Result<(), EventfulError> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let request_entries = pending.iter()
+                .map(|(_, entry)| PutEventsRequestEntry::builder()
+                    .event_bus_name(&entry.event_bus_name)
+                    .detail_type(&entry.detail_type)
+                    .source(&entry.source)
+                    .detail(&entry.detail)
+                    .build())
+                .collect::<Vec<_>>();
+            let output = execute_with_retry(&self.retry, || {
+                let request = self.client.put_events().set_entries(Some(request_entries.clone()));
+                async move {
+                    request.send().await.map_err(|err| EventfulError::EventBridge(format!("{:?}", err)))
+                }
+            }).await?;
+            let mut retry = Vec::new();
+            // result entries are in the same order as the request
+            for ((i, entry), result) in pending.into_iter().zip(output.entries.unwrap_or_default()) {
+                match (result.event_id, result.error_code) {
+                    (Some(event_id), None) => results[i] = Some(EntryResult::Published{event_id}),
+                    (_, code) => {
+                        let code = code.unwrap_or_else(|| "Unknown".to_string());
+                        let message = result.error_message.unwrap_or_default();
+                        if RETRYABLE_CODES.contains(&code.as_str()) && attempt < self.retry.max_attempts {
+                            retry.push((i, entry));
+                        }
+                        results[i] = Some(EntryResult::Failed{code, message});
+                    },
+                }
+            }
+            if retry.is_empty() {
+                return Ok(())
+            }
+            tokio::time::sleep(self.retry.delay_for(attempt)).await;
+            pending = retry;
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for ClientEventBridge {
+    /// The detail-type comes from the "detail-type" header (default "event") and the source from ClientEventBridge::source
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let event_bus_name = match dest {
+            Destination::EventBus(name) => name,
+            _ => return Err(publisher::unsupported("ClientEventBridge", dest)),
+        };
+        let detail = String::from_utf8(body.to_vec())
+            .map_err(|_| EventfulError::EventBridge("EventBridge details must be valid UTF-8 JSON".to_string()))?;
+        let entry = EntryEventBridge{
+            event_bus_name: event_bus_name.clone(),
+            detail_type: meta.headers.get(DETAIL_TYPE_HEADER).cloned().unwrap_or_else(|| "event".to_string()),
+            source: self.source.clone(),
+            detail,
+        };
+        match self.put_entries(vec![entry]).await?.remove(0) {
+            EntryResult::Published{event_id} => Ok(Receipt{message_id: Some(event_id)}),
+            EntryResult::Failed{code, message} => Err(EventfulError::EventBridge(format!("{}: {}", code, message))),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use crate::httpstub::HttpStub;
+    use crate::retry::Backoff;
+
+    #[derive(Serialize, Deserialize)]
+    struct OrderPlaced {
+        order_id: u32,
+    }
+
+    impl EventBridgeEvent for OrderPlaced {
+        fn event_bus_name() -> &'static str {
+            "orders"
+        }
+        fn detail_type() -> &'static str {
+            "OrderPlaced"
+        }
+        fn source() -> &'static str {
+            "com.example.checkout"
+        }
+    }
+
+    /// A client sending PutEvents to the stub, retrying quickly
+    fn client(stub: &HttpStub) -> ClientEventBridge {
+        let config = aws_sdk_eventbridge::Config::builder()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(aws_sdk_eventbridge::Credentials::new("test", "test", None, None, "eventful-test"))
+            .endpoint_url(stub.url())
+            .build();
+        let retry = RetryPolicy::default().max_attempts(3).backoff(Backoff::Fixed(std::time::Duration::from_millis(1)));
+        ClientEventBridge::from_client(Client::from_conf(config)).retry(retry)
+    }
+
+    fn put_events(entries: &[&str]) -> (u16, String) {
+        let failed = entries.iter().filter(|entry| entry.contains("ErrorCode")).count();
+        (200, format!(r#"{{"FailedEntryCount":{},"Entries":[{}]}}"#, failed, entries.join(",")))
+    }
+
+    fn entry(detail: String) -> EntryEventBridge {
+        EntryEventBridge{event_bus_name: "orders".to_string(), detail_type: "OrderPlaced".to_string(), source: "test".to_string(), detail}
+    }
+
+    #[tokio::test]
+    async fn throttled_entries_are_retried_alone() {
+        let stub = HttpStub::start(vec![
+            put_events(&[r#"{"EventId":"e1"}"#, r#"{"ErrorCode":"ThrottlingException","ErrorMessage":"slow down"}"#, r#"{"ErrorCode":"MalformedDetail","ErrorMessage":"bad"}"#]),
+            put_events(&[r#"{"EventId":"e2"}"#]),
+        ]).await;
+        let orders = [OrderPlaced{order_id: 1}, OrderPlaced{order_id: 2}, OrderPlaced{order_id: 3}];
+        let results = client(&stub).publish_batch(&orders).await.unwrap();
+        assert_eq!(results, vec![
+            EntryResult::Published{event_id: "e1".to_string()},
+            EntryResult::Published{event_id: "e2".to_string()},
+            EntryResult::Failed{code: "MalformedDetail".to_string(), message: "bad".to_string()},
+        ]);
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].headers.get("x-amz-target").map(String::as_str), Some("AWSEvents.PutEvents"));
+        let retried: serde_json::Value = serde_json::from_slice(&requests[1].body).unwrap();
+        assert_eq!(retried["Entries"].as_array().map(Vec::len), Some(1));
+        assert_eq!(retried["Entries"][0]["Detail"], r#"{"order_id":2}"#);
+        assert_eq!(retried["Entries"][0]["Source"], "com.example.checkout");
+    }
+
+    #[tokio::test]
+    async fn entries_are_batched_by_count_and_oversized_ones_rejected_unsent() {
+        let stub = HttpStub::start(vec![put_events(&[r#"{"EventId":"e"}"#; MAX_BATCH_ENTRIES])]).await;
+        let mut entries = (0..12).map(|i| entry(format!(r#"{{"n":{}}}"#, i))).collect::<Vec<_>>();
+        entries[5] = entry(format!(r#"{{"padding":"{}"}}"#, "x".repeat(MAX_ENTRY_BYTES)));
+        let results = client(&stub).put_entries(entries).await.unwrap();
+        assert!(matches!(&results[5], EntryResult::Failed{code, ..} if code == "EntryTooLarge"));
+        assert_eq!(results.iter().filter(|result| result.is_published()).count(), 11);
+        // 11 entries to send: a full batch of 10, then 1
+        let sizes = stub.requests().iter()
+            .map(|request| serde_json::from_slice::<serde_json::Value>(&request.body).unwrap()["Entries"].as_array().map(Vec::len))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![Some(10), Some(1)]);
+    }
+
+    #[tokio::test]
+    async fn the_publisher_takes_the_detail_type_from_a_header() {
+        let stub = HttpStub::start(vec![put_events(&[r#"{"EventId":"e1"}"#])]).await;
+        let publisher = client(&stub).source("com.example.billing");
+        let mut meta = Metadata::default();
+        meta.headers.insert(DETAIL_TYPE_HEADER.to_string(), "InvoicePaid".to_string());
+        let receipt = publisher.publish_bytes(&Destination::EventBus("billing".to_string()), Bytes::from_static(br#"{"id":1}"#), &meta).await.unwrap();
+        assert_eq!(receipt.message_id.as_deref(), Some("e1"));
+        let sent: serde_json::Value = serde_json::from_slice(&stub.requests()[0].body).unwrap();
+        assert_eq!(sent["Entries"][0]["DetailType"], "InvoicePaid");
+        assert_eq!(sent["Entries"][0]["Source"], "com.example.billing");
+        assert_eq!(sent["Entries"][0]["EventBusName"], "billing");
+        let wrong = publisher.publish_bytes(&Destination::NsqTopic("click".to_string()), Bytes::new(), &meta).await;
+        assert!(matches!(wrong, Err(EventfulError::Destination(_))));
+    }
+}
+
+
+/// Needs localstack (LOCALSTACK_ENDPOINT, default http://localhost:4566). Run with `cargo test --features eventbridge -- --ignored`
+#[cfg(test)]
+mod integration {
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs localstack"]
+    async fn puts_events_on_a_localstack_bus() {
+        let endpoint = std::env::var("LOCALSTACK_ENDPOINT").unwrap_or_else(|_| "http://localhost:4566".to_string());
+        let config = aws_sdk_eventbridge::Config::builder()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(aws_sdk_eventbridge::Credentials::new("test", "test", None, None, "eventful-localstack"))
+            .endpoint_url(endpoint)
+            .build();
+        let client = Client::from_conf(config);
+        let bus = format!("eventful-test-{}", rand::random::<u32>());
+        client.create_event_bus().name(&bus).send().await.unwrap();
+        let eventbridge = ClientEventBridge::from_client(client.clone());
+        let entries = (0..15).map(|n| EntryEventBridge{
+            event_bus_name: bus.clone(),
+            detail_type: "Numbered".to_string(),
+            source: "eventful.test".to_string(),
+            detail: format!(r#"{{"n":{}}}"#, n),
+        }).collect();
+        let results = eventbridge.put_entries(entries).await.unwrap();
+        assert_eq!(results.len(), 15);
+        assert!(results.iter().all(EntryResult::is_published), "{:?}", results);
+        client.delete_event_bus().name(&bus).send().await.unwrap();
+    }
+}
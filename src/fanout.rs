@@ -0,0 +1,307 @@
+//! The fanout module publishes every event to several Publishers at once,
+//! i.e. to both NSQ and SQS while migrating from one to the other.
+
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::join_all;
+use tokio::{sync::{mpsc, Mutex}, task::JoinHandle};
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+
+
+/// The FanoutPolicy decides when a fan-out publish counts as a success
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FanoutPolicy {
+    /// Publish to every target concurrently; fail if any target fails
+    AllMustSucceed,
+    /// Publish to every target concurrently; fail only if fewer than min_success targets succeed
+    BestEffort { min_success: usize },
+    /// Await the first target, then hand the rest to a background task without waiting for them.
+    /// If the first target fails nothing is handed off. Failures on the other targets are reported to the on_async_error callback, if any
+    PrimaryThenAsync,
+}
+
+
+/// What happened when publishing to one target
+#[derive(Debug)]
+pub enum TargetOutcome {
+    Published(Receipt),
+    Failed(EventfulError),
+    /// Handed to the background task (PrimaryThenAsync only)
+    Queued,
+    /// Not attempted, because the primary target failed (PrimaryThenAsync only)
+    Skipped,
+}
+
+
+/// The outcome for each target, in the order the targets were given to FanoutPublisher::new.
+/// It is returned when the policy is satisfied, and carried by EventfulError::FanoutUnsatisfied when it isn't
+#[derive(Debug)]
+pub struct FanoutReport {
+    pub outcomes: Vec<TargetOutcome>,
+}
+
+impl FanoutReport {
+    pub fn successes(&self) -> usize {
+        self.outcomes.iter().filter(|outcome| matches!(outcome, TargetOutcome::Published(_))).count()
+    }
+
+    pub fn failures(&self) -> usize {
+        self.outcomes.iter().filter(|outcome| matches!(outcome, TargetOutcome::Failed(_))).count()
+    }
+
+    /// The receipt of the first target which published successfully
+    pub fn first_receipt(&self) -> Option<&Receipt> {
+        self.outcomes.iter().find_map(|outcome| match outcome {
+            TargetOutcome::Published(receipt) => Some(receipt),
+            _ => None,
+        })
+    }
+}
+
+
+struct Job {
+    dest: Destination,
+    body: Bytes,
+    meta: Metadata,
+}
+
+type AsyncErrorCallback = Arc<dyn Fn(usize, &Destination, &EventfulError) + Send + Sync>;
+
+
+/// The FanoutPublisher implements Publisher by publishing to each of its targets
+pub struct FanoutPublisher {
+    targets: Vec<Arc<dyn Publisher>>,
+    policy: FanoutPolicy,
+    queue: Mutex<Option<mpsc::UnboundedSender<Job>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    shut_down: AtomicBool,
+    on_async_error: Option<AsyncErrorCallback>,
+}
+
+impl FanoutPublisher {
+    /// Create a FanoutPublisher with the AllMustSucceed policy
+    pub fn new(targets: Vec<Box<dyn Publisher>>) -> Self {
+        FanoutPublisher::with_policy(targets, FanoutPolicy::AllMustSucceed)
+    }
+
+    /// Create a FanoutPublisher with a given policy.
+    /// PrimaryThenAsync spawns a background task, so it must be called from within a tokio runtime
+    pub fn with_policy(targets: Vec<Box<dyn Publisher>>, policy: FanoutPolicy) -> Self {
+        let targets: Vec<Arc<dyn Publisher>> = targets.into_iter().map(Arc::from).collect();
+        FanoutPublisher{targets, policy, queue: Mutex::new(None), worker: Mutex::new(None), shut_down: AtomicBool::new(false), on_async_error: None}
+    }
+
+    /// Set a callback invoked with (target index, destination, error) when a background publish fails
+    pub fn on_async_error<F: Fn(usize, &Destination, &EventfulError) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_async_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Publish to the targets according to the policy and report what happened to each.  
+    /// The report is returned as long as the policy is satisfied; otherwise EventfulError::FanoutUnsatisfied carries it
+    pub async fn publish_fanout(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<FanoutReport, EventfulError> {
+        if self.targets.is_empty() {
+            return Err(EventfulError::Fanout("FanoutPublisher has no targets".to_string()))
+        }
+        let report = match self.policy {
+            FanoutPolicy::AllMustSucceed | FanoutPolicy::BestEffort{..} => self.publish_all(dest, &body, meta).await,
+            FanoutPolicy::PrimaryThenAsync => self.publish_primary(dest, body, meta).await?,
+        };
+        let required = match self.policy {
+            FanoutPolicy::AllMustSucceed => self.targets.len(),
+            FanoutPolicy::BestEffort{min_success} => min_success,
+            FanoutPolicy::PrimaryThenAsync => 1,
+        };
+        match report.successes() >= required {
+            true => Ok(report),
+            false => Err(EventfulError::FanoutUnsatisfied{required, report}),
+        }
+    }
+
+    async fn publish_all(&self, dest: &Destination, body: &Bytes, meta: &Metadata) -> FanoutReport {
+        let futures = self.targets.iter().map(|target| target.publish_bytes(dest, body.clone(), meta));
+        let outcomes = join_all(futures).await.into_iter()
+            .map(|result| match result {
+                Ok(receipt) => TargetOutcome::Published(receipt),
+                Err(err) => TargetOutcome::Failed(err),
+            })
+            .collect();
+        FanoutReport{outcomes}
+    }
+
+    async fn publish_primary(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<FanoutReport, EventfulError> {
+        if self.targets.len() > 1 && self.shut_down.load(Ordering::SeqCst) {
+            return Err(EventfulError::Fanout("FanoutPublisher has been shut down".to_string()))
+        }
+        let mut outcomes = Vec::with_capacity(self.targets.len());
+        match self.targets[0].publish_bytes(dest, body.clone(), meta).await {
+            Ok(receipt) => outcomes.push(TargetOutcome::Published(receipt)),
+            // the caller is told the publish failed, so the other targets mustn't receive it either
+            Err(err) => {
+                outcomes.push(TargetOutcome::Failed(err));
+                outcomes.extend((1..self.targets.len()).map(|_| TargetOutcome::Skipped));
+                return Ok(FanoutReport{outcomes})
+            },
+        }
+        if self.targets.len() > 1 {
+            let queue = self.queue().await?;
+            let job = Job{dest: dest.clone(), body, meta: meta.clone()};
+            queue.send(job).map_err(|_| EventfulError::Fanout("FanoutPublisher has been shut down".to_string()))?;
+            outcomes.extend((1..self.targets.len()).map(|_| TargetOutcome::Queued));
+        }
+        Ok(FanoutReport{outcomes})
+    }
+
+    /// Return the sender for the background queue, spawning the worker on first use
+    async fn queue(&self) -> Result<mpsc::UnboundedSender<Job>, EventfulError> {
+        let mut queue = self.queue.lock().await;
+        if let Some(sender) = queue.as_ref() {
+            return Ok(sender.clone())
+        }
+        if self.shut_down.load(Ordering::SeqCst) {
+            return Err(EventfulError::Fanout("FanoutPublisher has been shut down".to_string()))
+        }
+        let mut worker = self.worker.lock().await;
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Job>();
+        let targets = self.targets.clone();
+        let on_async_error = self.on_async_error.clone();
+        *worker = Some(tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                for (i, target) in targets.iter().enumerate().skip(1) {
+                    if let Err(err) = target.publish_bytes(&job.dest, job.body.clone(), &job.meta).await {
+                        if let Some(callback) = &on_async_error {
+                            callback(i, &job.dest, &err);
+                        }
+                    }
+                }
+            }
+        }));
+        *queue = Some(sender.clone());
+        Ok(sender)
+    }
+
+    /// Stop accepting new events and wait until every queued background publish has been attempted.
+    /// Only meaningful for PrimaryThenAsync; for the other policies it returns immediately
+    pub async fn shutdown(&self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+        let sender = self.queue.lock().await.take();
+        drop(sender);
+        let worker = self.worker.lock().await.take();
+        if let Some(worker) = worker {
+            let _ = worker.await;
+        }
+    }
+}
+
+
+#[async_trait]
+impl Publisher for FanoutPublisher {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let report = self.publish_fanout(dest, body, meta).await?;
+        Ok(report.first_receipt().cloned().unwrap_or_default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+    use crate::memory::MemoryBroker;
+
+    fn dest() -> Destination {
+        Destination::NsqTopic("click".to_string())
+    }
+
+    async fn publish(fanout: &FanoutPublisher) -> Result<FanoutReport, EventfulError> {
+        fanout.publish_fanout(&dest(), Bytes::from_static(b"{}"), &Metadata::default()).await
+    }
+
+    /// Two targets, the second of which fails its next publish
+    fn targets() -> (MemoryBroker, MemoryBroker, Vec<Box<dyn Publisher>>) {
+        let (primary, failing) = (MemoryBroker::new(), MemoryBroker::new());
+        failing.fail_next_publish();
+        let targets: Vec<Box<dyn Publisher>> = vec![Box::new(primary.clone()), Box::new(failing.clone())];
+        (primary, failing, targets)
+    }
+
+    #[tokio::test]
+    async fn all_must_succeed_fails_when_one_target_fails() {
+        let (primary, failing, targets) = targets();
+        let fanout = FanoutPublisher::new(targets);
+        match publish(&fanout).await {
+            Err(EventfulError::FanoutUnsatisfied{required, report}) => {
+                assert_eq!((required, report.successes(), report.failures()), (2, 1, 1));
+                assert!(matches!(report.outcomes[0], TargetOutcome::Published(_)));
+                assert!(matches!(report.outcomes[1], TargetOutcome::Failed(EventfulError::Http(_))));
+            },
+            other => panic!("expected FanoutUnsatisfied, got {:?}", other),
+        }
+        // the healthy target was still published to, which the error reports
+        assert_eq!(primary.published_to("click").len(), 1);
+        assert!(failing.published_to("click").is_empty());
+        assert_eq!(publish(&fanout).await.unwrap().successes(), 2);
+    }
+
+    #[tokio::test]
+    async fn best_effort_reports_partial_failure() {
+        let fanout = FanoutPublisher::with_policy(targets().2, FanoutPolicy::BestEffort{min_success: 1});
+        let report = publish(&fanout).await.unwrap();
+        assert_eq!((report.successes(), report.failures()), (1, 1));
+        assert!(matches!(report.outcomes[0], TargetOutcome::Published(_)));
+        assert!(matches!(report.outcomes[1], TargetOutcome::Failed(_)));
+
+        let fanout = FanoutPublisher::with_policy(targets().2, FanoutPolicy::BestEffort{min_success: 2});
+        assert!(matches!(publish(&fanout).await, Err(EventfulError::FanoutUnsatisfied{required: 2, ..})));
+    }
+
+    #[tokio::test]
+    async fn primary_then_async_reports_secondary_failures_to_callback() {
+        let (primary, failing, targets) = targets();
+        let errors = Arc::new(StdMutex::new(Vec::new()));
+        let seen = errors.clone();
+        let fanout = FanoutPublisher::with_policy(targets, FanoutPolicy::PrimaryThenAsync)
+            .on_async_error(move |i, _, _| seen.lock().unwrap().push(i));
+        let report = publish(&fanout).await.unwrap();
+        assert!(matches!(report.outcomes[1], TargetOutcome::Queued));
+        publish(&fanout).await.unwrap();
+        fanout.shutdown().await;
+        assert_eq!(primary.published_to("click").len(), 2);
+        assert_eq!(failing.published_to("click").len(), 1);
+        assert_eq!(*errors.lock().unwrap(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn primary_failure_queues_nothing() {
+        let (primary, secondary) = (MemoryBroker::new(), MemoryBroker::new());
+        primary.fail_next_publish();
+        let targets: Vec<Box<dyn Publisher>> = vec![Box::new(primary.clone()), Box::new(secondary.clone())];
+        let fanout = FanoutPublisher::with_policy(targets, FanoutPolicy::PrimaryThenAsync);
+        match publish(&fanout).await {
+            Err(EventfulError::FanoutUnsatisfied{required: 1, report}) => {
+                assert!(matches!(report.outcomes[0], TargetOutcome::Failed(_)));
+                assert!(matches!(report.outcomes[1], TargetOutcome::Skipped));
+            },
+            other => panic!("expected FanoutUnsatisfied, got {:?}", other),
+        }
+        fanout.shutdown().await;
+        assert!(primary.published_to("click").is_empty());
+        assert!(secondary.published_to("click").is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_the_queue_then_refuses_new_events() {
+        let (primary, secondary) = (MemoryBroker::new(), MemoryBroker::new());
+        let targets: Vec<Box<dyn Publisher>> = vec![Box::new(primary.clone()), Box::new(secondary.clone())];
+        let fanout = FanoutPublisher::with_policy(targets, FanoutPolicy::PrimaryThenAsync);
+        for _ in 0..50 {
+            publish(&fanout).await.unwrap();
+        }
+        fanout.shutdown().await;
+        assert_eq!(secondary.published_to("click").len(), 50);
+        assert!(matches!(publish(&fanout).await, Err(EventfulError::Fanout(_))));
+        assert_eq!(primary.published_to("click").len(), 50);
+    }
+}
@@ -0,0 +1,389 @@
+//! The file module is a broker-free backend for local development and demos: nothing to install.  
+//! Each topic is an append-only ndjson file in a directory, one ArchivedEvent per line (the same format the replay module reads),
+//! so a topic file can be replayed into a real bus later.
+//! 
+//! Each channel keeps its own offset file, so several channels consume a topic independently, like NSQ channels.
+//! Acking a delivery advances the channel's offset; nacking rewinds to the delivery so it is read again after the delay.
+//! Appends take an exclusive advisory lock on the topic file, so several processes can publish to the same directory.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::consumer;
+//! # use eventful::file::FileBroker;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Click { user_id: u64 }
+//! # async fn handle_click(click: Click) -> Result<(), EventfulError> { Ok(()) }
+//! # async fn demo(click: Click) -> Result<(), EventfulError> {
+//! let broker = FileBroker::new("./.eventful");
+//! let publisher: Arc<dyn Publisher> = Arc::new(broker.clone());
+//! publisher.publish_event(&Destination::NsqTopic("click".to_string()), &click).await?;
+//!
+//! let subscription = broker.subscribe(&Destination::NsqTopic("click".to_string()), "analytics").await?;
+//! consumer::run(Box::new(subscription), &ConsumerOptions::default(), handle_click).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::{BTreeMap, HashMap}, io::{SeekFrom, Write}, path::PathBuf, sync::{Arc, Mutex}, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::Utc;
+use fs2::FileExt;
+use tokio::{io::{AsyncBufReadExt, AsyncSeekExt, BufReader}, time::Instant};
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::replay::{self, ArchivedEvent};
+use crate::subscriber::{Ack, Delivery, Subscriber};
+
+
+/// The FileBroker publishes to and subscribes from topic files in one directory.
+/// Any Destination can be published to; its name (sanitized) is the topic, so a service routing to NSQ
+/// or SQS can use a FileBroker locally without changing its routes
+#[derive(Clone, Debug)]
+pub struct FileBroker {
+    dir: PathBuf,
+    fsync: bool,
+    poll_interval: Duration,
+}
+
+impl FileBroker {
+    /// Topic files are created in `dir`, which is created if needed
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileBroker{dir: dir.into(), fsync: false, poll_interval: Duration::from_millis(200)}
+    }
+
+    /// fsync the topic file after every append (default false). Slower, but survives a power cut
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// How often a subscriber at the end of a topic file checks for new lines (default 200ms)
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// The topic file for a destination
+    pub fn topic_path(&self, dest: &Destination) -> PathBuf {
+        self.dir.join(format!("{}.ndjson", sanitize(dest.name())))
+    }
+
+    fn offset_path(&self, dest: &Destination, channel: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}.offset", sanitize(dest.name()), sanitize(channel)))
+    }
+
+    /// Append one record to a topic file under an exclusive advisory lock
+    pub async fn append(&self, record: &ArchivedEvent) -> Result<(), EventfulError> {
+        let line = record.to_line()?;
+        let path = self.topic_path(&record.topic);
+        let dir = self.dir.clone();
+        let fsync = self.fsync;
+        tokio::task::spawn_blocking(move || -> Result<(), EventfulError> {
+            std::fs::create_dir_all(&dir)?;
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+            file.lock_exclusive()?;
+            // one write_all of a whole line, so readers never see two records interleaved
+            let result = file.write_all(&line).and_then(|_| if fsync { file.sync_data() } else { Ok(()) });
+            let _ = file.unlock();
+            Ok(result?)
+        }).await.map_err(|err| EventfulError::IO(std::io::Error::other(err)))?
+    }
+
+    /// Subscribe to a topic as a channel, resuming from the channel's saved offset (or the start of the file)
+    pub async fn subscribe(&self, dest: &Destination, channel: &str) -> Result<FileSubscription, EventfulError> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let offset_path = self.offset_path(dest, channel);
+        let committed = replay::read_checkpoint(&offset_path).await?;
+        let state = ChannelState{committed, read_pos: committed, not_before: None, attempts: HashMap::new(), in_flight: BTreeMap::new()};
+        Ok(FileSubscription{
+            topic_path: self.topic_path(dest),
+            offset_path,
+            poll_interval: self.poll_interval,
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+}
+
+#[async_trait]
+impl Publisher for FileBroker {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let record = ArchivedEvent{topic: dest.clone(), body, metadata: meta.clone(), original_timestamp: Utc::now()};
+        self.append(&record).await?;
+        Ok(Receipt::default())
+    }
+}
+
+
+/// Topic and channel names become file names, so anything but [A-Za-z0-9_-.] is replaced with '_'
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' { c } else { '_' }).collect()
+}
+
+
+struct ChannelState {
+    /// The offset saved to the offset file: everything before it has been acked
+    committed: u64,
+    /// Where the next read starts
+    read_pos: u64,
+    /// Set by nack: don't read again until then
+    not_before: Option<Instant>,
+    /// Delivery counts for lines which have been nacked, by offset
+    attempts: HashMap<u64, u32>,
+    /// Lines read but not yet committed, by offset: (end, acked)
+    in_flight: BTreeMap<u64, (u64, bool)>,
+}
+
+impl ChannelState {
+    /// Mark the line at `start` acked and return the new committed offset, if it moved.
+    /// The offset only moves past a run of acked lines, so acking a line early never commits past one still unsettled
+    fn settle(&mut self, start: u64) -> Option<u64> {
+        self.attempts.remove(&start);
+        match self.in_flight.get_mut(&start) {
+            Some((_, acked)) => *acked = true,
+            // rewound by a nack since it was read, so it will be delivered again
+            None => return None,
+        }
+        let before = self.committed;
+        while let Some(entry) = self.in_flight.first_entry() {
+            match *entry.get() {
+                (end, true) => {
+                    self.committed = end;
+                    entry.remove();
+                },
+                (_, false) => break,
+            }
+        }
+        (self.committed > before).then_some(self.committed)
+    }
+}
+
+
+/// A FileSubscription tails a topic file as one channel.
+/// Deliveries may be settled in any order: the saved offset only moves past lines which have all been acked
+pub struct FileSubscription {
+    topic_path: PathBuf,
+    offset_path: PathBuf,
+    poll_interval: Duration,
+    state: Arc<Mutex<ChannelState>>,
+}
+
+impl FileSubscription {
+    /// Read the complete line at `pos`, if there is one yet
+    async fn read_line(&self, pos: u64) -> Result<Option<Vec<u8>>, EventfulError> {
+        let mut file = match tokio::fs::File::open(&self.topic_path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        file.seek(SeekFrom::Start(pos)).await?;
+        // bytes rather than a String, so a line which is not UTF-8 can be skipped instead of failing every read
+        let mut line = Vec::new();
+        BufReader::new(file).read_until(b'\n', &mut line).await?;
+        // a line without its newline is still being written
+        match line.ends_with(b"\n") {
+            true => Ok(Some(line)),
+            false => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl Subscriber for FileSubscription {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        loop {
+            let (pos, not_before) = {
+                let state = self.state.lock().unwrap();
+                (state.read_pos, state.not_before)
+            };
+            if let Some(at) = not_before {
+                tokio::time::sleep_until(at).await;
+            }
+            let line = match self.read_line(pos).await? {
+                Some(line) => line,
+                None => {
+                    tokio::time::sleep(self.poll_interval).await;
+                    continue
+                },
+            };
+            let end = pos + line.len() as u64;
+            let attempt = {
+                let mut state = self.state.lock().unwrap();
+                state.read_pos = end;
+                state.not_before = None;
+                state.in_flight.insert(pos, (end, false));
+                state.attempts.get(&pos).copied().unwrap_or(0) + 1
+            };
+            let record: ArchivedEvent = match serde_json::from_slice(&line) {
+                Ok(record) => record,
+                // not an ArchivedEvent (i.e. edited by hand, or not UTF-8), so skip over it
+                Err(_) => {
+                    FileAck{state: self.state.clone(), offset_path: self.offset_path.clone(), start: pos}.commit().await?;
+                    continue
+                },
+            };
+            let ack = FileAck{state: self.state.clone(), offset_path: self.offset_path.clone(), start: pos};
+            let message_id = Some(pos.to_string());
+            return Ok(Some(Delivery::new(record.topic, record.body, record.metadata, message_id, attempt, Box::new(ack))))
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.topic_path.display().to_string()
+    }
+}
+
+
+/// ack() saves the offset past the line (once every line before it is acked too), nack(delay) rewinds to the start of the line
+struct FileAck {
+    state: Arc<Mutex<ChannelState>>,
+    offset_path: PathBuf,
+    start: u64,
+}
+
+impl FileAck {
+    async fn commit(self) -> Result<(), EventfulError> {
+        let committed = self.state.lock().unwrap().settle(self.start);
+        match committed {
+            Some(committed) => replay::write_checkpoint(&self.offset_path, committed).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Ack for FileAck {
+    async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+        self.commit().await
+    }
+
+    async fn nack(self: Box<Self>, delay: Duration) -> Result<(), EventfulError> {
+        let mut state = self.state.lock().unwrap();
+        let attempts = state.attempts.entry(self.start).or_insert(0);
+        *attempts += 1;
+        // everything from here on is read again, so lines after it which are still in flight are forgotten
+        state.in_flight.split_off(&self.start);
+        state.read_pos = self.start.min(state.read_pos);
+        state.not_before = Some(Instant::now() + delay);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("eventful-file-{}-{}", name, rand::random::<u32>()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn topic() -> Destination {
+        Destination::NsqTopic("click".to_string())
+    }
+
+    async fn publish(broker: &FileBroker, bodies: &[&str]) {
+        for body in bodies {
+            broker.publish_bytes(&topic(), Bytes::from(body.to_string()), &Metadata::default()).await.unwrap();
+        }
+    }
+
+    async fn next(subscription: &mut FileSubscription) -> Delivery {
+        tokio::time::timeout(Duration::from_secs(5), subscription.next()).await.unwrap().unwrap().unwrap()
+    }
+
+    async fn committed(broker: &FileBroker) -> u64 {
+        replay::read_checkpoint(&broker.offset_path(&topic(), "analytics")).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn channels_resume_from_their_own_offsets() {
+        let dir = test_dir("resume");
+        let broker = FileBroker::new(&dir).poll_interval(Duration::from_millis(10));
+        publish(&broker, &["a", "b", "c"]).await;
+        let mut analytics = broker.subscribe(&topic(), "analytics").await.unwrap();
+        next(&mut analytics).await.ack().await.unwrap();
+        next(&mut analytics).await.ack().await.unwrap();
+        drop(analytics);
+
+        let mut analytics = broker.subscribe(&topic(), "analytics").await.unwrap();
+        assert_eq!(next(&mut analytics).await.body, Bytes::from("c"));
+        let mut billing = broker.subscribe(&topic(), "billing").await.unwrap();
+        assert_eq!(next(&mut billing).await.body, Bytes::from("a"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_nacked_line_is_read_again_with_its_attempt_counted() {
+        let dir = test_dir("nack");
+        let broker = FileBroker::new(&dir).poll_interval(Duration::from_millis(10));
+        publish(&broker, &["a", "b"]).await;
+        let mut subscription = broker.subscribe(&topic(), "analytics").await.unwrap();
+        let first = next(&mut subscription).await;
+        assert_eq!(first.attempt, 1);
+        first.nack(Duration::from_millis(10)).await.unwrap();
+        let again = next(&mut subscription).await;
+        assert_eq!((again.body.clone(), again.attempt), (Bytes::from("a"), 2));
+        again.ack().await.unwrap();
+        assert_eq!(next(&mut subscription).await.attempt, 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn acking_out_of_order_never_commits_past_an_unacked_line() {
+        let dir = test_dir("out-of-order");
+        let broker = FileBroker::new(&dir).poll_interval(Duration::from_millis(10));
+        publish(&broker, &["a", "b", "c"]).await;
+        let mut subscription = broker.subscribe(&topic(), "analytics").await.unwrap();
+        let (a, b, c) = (next(&mut subscription).await, next(&mut subscription).await, next(&mut subscription).await);
+
+        c.ack().await.unwrap();
+        b.ack().await.unwrap();
+        assert_eq!(committed(&broker).await, 0);
+        a.ack().await.unwrap();
+        let file_len = std::fs::metadata(broker.topic_path(&topic())).unwrap().len();
+        assert_eq!(committed(&broker).await, file_len);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_crash_before_the_first_ack_redelivers_everything() {
+        let dir = test_dir("crash");
+        let broker = FileBroker::new(&dir).poll_interval(Duration::from_millis(10));
+        publish(&broker, &["a", "b"]).await;
+        let mut subscription = broker.subscribe(&topic(), "analytics").await.unwrap();
+        let a = next(&mut subscription).await;
+        next(&mut subscription).await.ack().await.unwrap();
+        a.release();
+        drop(subscription);
+
+        let mut subscription = broker.subscribe(&topic(), "analytics").await.unwrap();
+        assert_eq!(next(&mut subscription).await.body, Bytes::from("a"));
+        assert_eq!(next(&mut subscription).await.body, Bytes::from("b"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lines_which_are_not_utf8_or_not_events_are_skipped() {
+        let dir = test_dir("garbage");
+        let broker = FileBroker::new(&dir).poll_interval(Duration::from_millis(10));
+        publish(&broker, &["a"]).await;
+        {
+            let mut file = std::fs::OpenOptions::new().append(true).open(broker.topic_path(&topic())).unwrap();
+            file.write_all(b"\xff\xfe not utf-8\n{\"edited\": \"by hand\"}\n").unwrap();
+        }
+        publish(&broker, &["b"]).await;
+        let mut subscription = broker.subscribe(&topic(), "analytics").await.unwrap();
+        next(&mut subscription).await.ack().await.unwrap();
+        let b = next(&mut subscription).await;
+        assert_eq!(b.body, Bytes::from("b"));
+        b.ack().await.unwrap();
+        let file_len = std::fs::metadata(broker.topic_path(&topic())).unwrap().len();
+        assert_eq!(committed(&broker).await, file_len);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
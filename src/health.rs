@@ -0,0 +1,307 @@
+//! The health module aggregates "am I connected to my brokers" into one report for a readiness probe.
+//! Register each component with a HealthCheck (NSQ daemons are pinged, SQS queues are asked for an attribute,
+//! a SupervisorHealth reports whether every consumer is running), then call check() from your /healthz handler.
+//! Checks run concurrently, each with its own timeout, so one dead broker can't stall the probe.
+//! Non-critical components appear in the report but don't make it unhealthy.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(all(feature = "nsq", feature = "sqs"))]
+//! # mod example {
+//! # use std::{sync::Arc, time::Duration};
+//! # use eventful::{health::{HealthCheck, SqsCanary}, nsq::FleetNSQ, sqs::ClientSQS, supervisor::Supervisor};
+//! # const ORDERS_QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/orders";
+//! # async fn demo(fleet: FleetNSQ, sqs_client: ClientSQS, supervisor: Supervisor) -> Result<(), eventful::err::EventfulError> {
+//! let health = HealthCheck::new()
+//!     .timeout(Duration::from_secs(2))
+//!     .add("nsqd1", Arc::new(fleet.d1.clone()))
+//!     .add("orders-queue", Arc::new(SqsCanary::new(sqs_client.clone(), ORDERS_QUEUE_URL)))
+//!     .add("consumers", Arc::new(supervisor.health()))
+//!     .add_non_critical("nsqd2", Arc::new(fleet.d2.clone()));
+//!
+//! // in your /healthz handler:
+//! let report = health.check().await;
+//! let status = if report.is_healthy() { 200 } else { 503 };
+//! let body = serde_json::to_string(&report)?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{sync::Arc, time::{Duration, Instant}};
+use async_trait::async_trait;
+#[cfg(feature = "sqs")]
+use aws_sdk_sqs::model::QueueAttributeName;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::err::EventfulError;
+#[cfg(feature = "nsq")]
+use crate::nsq::{self, Daemon};
+#[cfg(feature = "sqs")]
+use crate::sqs::ClientSQS;
+use crate::supervisor::{ConsumerState, SupervisorHealth};
+
+
+/// Something whose health can be checked
+#[async_trait]
+pub trait Checkable: Send + Sync {
+    async fn check(&self) -> Result<(), EventfulError>;
+}
+
+/// An nsqd daemon is healthy when it answers /ping
+#[cfg(feature = "nsq")]
+#[async_trait]
+impl Checkable for Daemon {
+    async fn check(&self) -> Result<(), EventfulError> {
+        nsq::ping(self).await
+    }
+}
+
+/// SQS is healthy when GetQueueAttributes succeeds on a canary queue
+#[cfg(feature = "sqs")]
+pub struct SqsCanary {
+    client: ClientSQS,
+    queue_url: String,
+}
+
+#[cfg(feature = "sqs")]
+impl SqsCanary {
+    pub fn new(client: ClientSQS, queue_url: &str) -> Self {
+        SqsCanary{client, queue_url: queue_url.to_string()}
+    }
+}
+
+#[cfg(feature = "sqs")]
+#[async_trait]
+impl Checkable for SqsCanary {
+    async fn check(&self) -> Result<(), EventfulError> {
+        self.client.client().get_queue_attributes()
+            .queue_url(&self.queue_url)
+            .attribute_names(QueueAttributeName::QueueArn)
+            .send().await?;
+        Ok(())
+    }
+}
+
+/// Supervised consumers are healthy when every one of them is Running
+#[async_trait]
+impl Checkable for SupervisorHealth {
+    async fn check(&self) -> Result<(), EventfulError> {
+        if self.healthy() {
+            return Ok(())
+        }
+        let mut not_running: Vec<String> = self.all().into_iter()
+            .filter(|(_, health)| health.state != ConsumerState::Running)
+            .map(|(name, health)| format!("{} is {:?}", name, health.state))
+            .collect();
+        not_running.sort();
+        match not_running.is_empty() {
+            true => Err(EventfulError::Unhealthy("no consumers are supervised".to_string())),
+            false => Err(EventfulError::Unhealthy(not_running.join(", "))),
+        }
+    }
+}
+
+
+/// The result of checking one component
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentStatus {
+    Healthy,
+    Unhealthy,
+    /// The check did not finish within the timeout
+    TimedOut,
+}
+
+/// How one component fared
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ComponentReport {
+    pub name: String,
+    pub critical: bool,
+    pub status: ComponentStatus,
+    /// How long the check took (up to the timeout)
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Every component's status, in the order they were added
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub checked_at: DateTime<Utc>,
+    pub healthy: bool,
+    pub components: Vec<ComponentReport>,
+}
+
+impl HealthReport {
+    /// True when every critical component is healthy
+    pub fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ComponentReport> {
+        self.components.iter().find(|component| component.name == name)
+    }
+
+    /// The components which are not healthy, critical or not
+    pub fn failing(&self) -> impl Iterator<Item = &ComponentReport> {
+        self.components.iter().filter(|component| component.status != ComponentStatus::Healthy)
+    }
+}
+
+
+struct Component {
+    name: String,
+    critical: bool,
+    check: Arc<dyn Checkable>,
+}
+
+/// Register components, then check() them all at once
+pub struct HealthCheck {
+    components: Vec<Component>,
+    timeout: Duration,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck::new()
+    }
+}
+
+impl HealthCheck {
+    pub fn new() -> Self {
+        HealthCheck{components: Vec::new(), timeout: Duration::from_secs(5)}
+    }
+
+    /// How long each component's check may take before it is reported as TimedOut (default 5 seconds)
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Add a critical component: the report is unhealthy whenever it is
+    pub fn add(mut self, name: &str, check: Arc<dyn Checkable>) -> Self {
+        self.components.push(Component{name: name.to_string(), critical: true, check});
+        self
+    }
+
+    /// Add a component which is reported but doesn't affect is_healthy()
+    pub fn add_non_critical(mut self, name: &str, check: Arc<dyn Checkable>) -> Self {
+        self.components.push(Component{name: name.to_string(), critical: false, check});
+        self
+    }
+
+    /// Check every component concurrently
+    pub async fn check(&self) -> HealthReport {
+        let checks = self.components.iter().map(|component| self.check_one(component));
+        let components = futures::future::join_all(checks).await;
+        let healthy = components.iter().all(|component| !component.critical || component.status == ComponentStatus::Healthy);
+        HealthReport{checked_at: Utc::now(), healthy, components}
+    }
+
+    async fn check_one(&self, component: &Component) -> ComponentReport {
+        let started = Instant::now();
+        let (status, error) = match tokio::time::timeout(self.timeout, component.check.check()).await {
+            Ok(Ok(())) => (ComponentStatus::Healthy, None),
+            Ok(Err(err)) => (ComponentStatus::Unhealthy, Some(err.to_string())),
+            Err(_) => (ComponentStatus::TimedOut, Some(format!("no response within {:?}", self.timeout))),
+        };
+        ComponentReport{
+            name: component.name.clone(),
+            critical: component.critical,
+            status,
+            latency_ms: started.elapsed().as_millis() as u64,
+            error,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers after `delay` with `result`
+    struct Fake {
+        delay: Duration,
+        result: Result<(), String>,
+    }
+
+    impl Fake {
+        fn healthy(delay_ms: u64) -> Arc<Self> {
+            Arc::new(Fake{delay: Duration::from_millis(delay_ms), result: Ok(())})
+        }
+
+        fn failing(message: &str) -> Arc<Self> {
+            Arc::new(Fake{delay: Duration::ZERO, result: Err(message.to_string())})
+        }
+    }
+
+    #[async_trait]
+    impl Checkable for Fake {
+        async fn check(&self) -> Result<(), EventfulError> {
+            tokio::time::sleep(self.delay).await;
+            self.result.clone().map_err(EventfulError::Unhealthy)
+        }
+    }
+
+    fn statuses(report: &HealthReport) -> Vec<(&str, ComponentStatus)> {
+        report.components.iter().map(|component| (component.name.as_str(), component.status)).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn components_are_checked_concurrently_each_with_its_own_timeout() {
+        let health = HealthCheck::new()
+            .timeout(Duration::from_secs(2))
+            .add("nsqd1", Fake::healthy(1500))
+            .add("orders-queue", Fake::healthy(1500))
+            .add_non_critical("nsqd2", Fake::healthy(60_000));
+        let started = tokio::time::Instant::now();
+        let report = health.check().await;
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        assert_eq!(statuses(&report), vec![
+            ("nsqd1", ComponentStatus::Healthy),
+            ("orders-queue", ComponentStatus::Healthy),
+            ("nsqd2", ComponentStatus::TimedOut),
+        ]);
+        assert!(report.is_healthy());
+        assert_eq!(report.failing().map(|component| component.name.as_str()).collect::<Vec<_>>(), vec!["nsqd2"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn one_failing_critical_component_makes_the_report_unhealthy() {
+        let health = HealthCheck::new()
+            .add("nsqd1", Fake::healthy(10))
+            .add("orders-queue", Fake::failing("AccessDenied"))
+            .add_non_critical("nsqd2", Fake::failing("connection refused"));
+        let report = health.check().await;
+        assert!(!report.is_healthy());
+        let queue = report.get("orders-queue").unwrap();
+        assert_eq!((queue.status, queue.critical), (ComponentStatus::Unhealthy, true));
+        assert!(queue.error.as_deref().unwrap().contains("AccessDenied"));
+        assert_eq!(report.failing().count(), 2);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["healthy"], false);
+        assert_eq!(json["components"][1]["status"], "unhealthy");
+        assert_eq!(json["components"][0]["error"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn a_supervisor_with_no_consumers_is_unhealthy() {
+        let report = HealthCheck::new().add("consumers", Arc::new(SupervisorHealth::default())).check().await;
+        assert_eq!(report.get("consumers").unwrap().error.as_deref(), Some(EventfulError::Unhealthy("no consumers are supervised".to_string()).to_string().as_str()));
+    }
+
+    #[cfg(feature = "nsq")]
+    #[tokio::test]
+    async fn nsq_daemons_are_pinged() {
+        let stub = crate::httpstub::HttpStub::start(vec![(200, "OK".to_string())]).await;
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let health = HealthCheck::new()
+            .add("nsqd1", Arc::new(Daemon::new("127.0.0.1", stub.port(), 0)))
+            .add("nsqd2", Arc::new(Daemon::new("127.0.0.1", port, 0)));
+        let report = health.check().await;
+        assert_eq!(statuses(&report), vec![("nsqd1", ComponentStatus::Healthy), ("nsqd2", ComponentStatus::Unhealthy)]);
+        assert_eq!(stub.requests()[0].path, "/ping");
+    }
+}
@@ -0,0 +1,116 @@
+//! A bare HTTP/1.1 server for tests, answering every request with canned responses in turn
+//! and keeping what it was sent. It understands just enough HTTP for hyper and the AWS SDK:
+//! keep-alive connections and Content-Length bodies
+
+use std::{collections::HashMap, net::SocketAddr, sync::{Arc, Mutex}};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, task::JoinHandle};
+
+
+/// One request the stub received
+#[derive(Clone, Debug)]
+pub(crate) struct StubRequest {
+    pub method: String,
+    pub path: String,
+    /// Lowercased header names
+    #[cfg_attr(not(feature = "sqs"), allow(dead_code))]
+    pub headers: HashMap<String, String>,
+    #[cfg_attr(not(feature = "sqs"), allow(dead_code))]
+    pub body: Vec<u8>,
+}
+
+impl StubRequest {
+    #[cfg(feature = "sqs")]
+    pub fn body_str(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap_or_default()
+    }
+}
+
+
+pub(crate) struct HttpStub {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<StubRequest>>>,
+    server: JoinHandle<()>,
+}
+
+impl HttpStub {
+    /// Answer with each (status, body) in turn, repeating the last once they run out.
+    /// Bodies are sent as application/x-amz-json-1.1 unless they look like something else
+    pub async fn start(responses: Vec<(u16, String)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let responses = Arc::new(responses);
+        let server = {
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, requests.clone(), responses.clone()));
+                }
+            })
+        };
+        HttpStub{addr, requests, server}
+    }
+
+    /// i.e. "http://127.0.0.1:43567"
+    #[cfg(any(feature = "eventbridge", feature = "sqs"))]
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    #[cfg(feature = "nsq")]
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    pub fn requests(&self) -> Vec<StubRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for HttpStub {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn serve(stream: TcpStream, requests: Arc<Mutex<Vec<StubRequest>>>, responses: Arc<Vec<(u16, String)>>) {
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return
+        }
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or_default().to_string(), parts.next().unwrap_or_default().to_string());
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return
+            }
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => headers.insert(name.trim().to_lowercase(), value.trim().to_string()),
+                None => break,
+            };
+        }
+        let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+        let mut body = vec![0; length];
+        if stream.read_exact(&mut body).await.is_err() {
+            return
+        }
+        let (status, response) = {
+            let mut requests = requests.lock().unwrap();
+            requests.push(StubRequest{method, path, headers, body});
+            let answer = responses.get(requests.len() - 1).or(responses.last());
+            answer.cloned().unwrap_or((404, String::new()))
+        };
+        let content_type = match response.trim_start().starts_with('<') {
+            true => "text/xml",
+            false => "application/x-amz-json-1.1",
+        };
+        let head = format!("HTTP/1.1 {} Stub\r\ncontent-type: {}\r\ncontent-length: {}\r\n\r\n", status, content_type, response.len());
+        if stream.get_mut().write_all(head.as_bytes()).await.is_err() || stream.get_mut().write_all(response.as_bytes()).await.is_err() {
+            return
+        }
+    }
+}
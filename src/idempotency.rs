@@ -0,0 +1,448 @@
+//! The idempotency module helps consumers process each message (roughly) exactly once, despite at-least-once delivery.  
+//! An IdempotencyGuard runs a handler only if its key has not been seen, and records the key after the handler succeeds.
+//! 
+//! Within one process, two in-flight deliveries with the same key are serialized by a per-key lock,
+//! so exactly one of them executes and the other is skipped.
+//! Across processes there is no such lock: two replicas receiving the same key at the same moment can both execute
+//! before either records it. Handlers which can't tolerate that should also guard with a database constraint.
+//!
+//! Stores own their TTLs: the MemoryDedupStore measures them with the process's monotonic clock and the RedisDedupStore
+//! lets Redis expire keys, so neither depends on the wall clock agreeing between replicas.
+//! Both count hits (the key was already marked) and misses.
+//!
+//! # Examples:
+//! ```no_run
+//! # use std::{sync::Arc, time::Duration};
+//! # use eventful::prelude::*;
+//! # use eventful::idempotency::{DedupStore, IdempotencyGuard, MemoryDedupStore};
+//! # use tokio_util::sync::CancellationToken;
+//! # struct Order { id: String }
+//! # async fn charge(order: &Order) -> Result<(), EventfulError> { Ok(()) }
+//! # async fn demo(shutdown: CancellationToken, order: Order) -> Result<(), EventfulError> {
+//! let store = Arc::new(MemoryDedupStore::new().sweep_every(Duration::from_secs(30)));
+//! tokio::spawn({ let store = store.clone(); let shutdown = shutdown.clone(); async move { store.run_until(shutdown).await } });
+//! let guard = IdempotencyGuard::new(store.clone(), Duration::from_secs(3600));
+//! guard.execute(&order.id, || charge(&order)).await?;
+//! println!("{:?}", store.stats());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex, Weak, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+use async_trait::async_trait;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio_util::sync::CancellationToken;
+use crate::err::EventfulError;
+
+
+/// A DedupStore remembers which keys have been processed
+#[async_trait]
+pub trait DedupStore: Send + Sync {
+    /// Has the key been marked (and not yet expired)?
+    async fn seen(&self, key: &str) -> Result<bool, EventfulError>;
+    /// Record the key for `ttl`, measured by the store. Returns false if it was already marked.
+    /// When many callers mark the same key at once, exactly one of them gets true
+    async fn mark(&self, key: &str, ttl: Duration) -> Result<bool, EventfulError>;
+    /// How often seen and mark found the key already marked
+    fn stats(&self) -> DedupStatsSnapshot {
+        DedupStatsSnapshot::default()
+    }
+}
+
+
+/// Counters describing how often a DedupStore found keys
+#[derive(Debug, Default)]
+pub struct DedupStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// A point-in-time copy of DedupStats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DedupStatsSnapshot {
+    /// seen() returned true, or mark() returned false
+    pub hits: u64,
+    /// seen() returned false, or mark() returned true
+    pub misses: u64,
+}
+
+impl DedupStats {
+    pub fn snapshot(&self) -> DedupStatsSnapshot {
+        DedupStatsSnapshot{
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a lookup which found (hit) or didn't find the key
+    pub fn record(&self, hit: bool) {
+        match hit {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+
+/// A DedupStore kept in memory. Expired keys are ignored straight away and removed by sweep(),
+/// which run_until() calls periodically so the map doesn't grow without bound
+pub struct MemoryDedupStore {
+    keys: Mutex<HashMap<String, Instant>>,
+    sweep_every: Duration,
+    stats: DedupStats,
+}
+
+impl Default for MemoryDedupStore {
+    fn default() -> Self {
+        MemoryDedupStore{keys: Mutex::new(HashMap::new()), sweep_every: Duration::from_secs(60), stats: DedupStats::default()}
+    }
+}
+
+impl MemoryDedupStore {
+    pub fn new() -> Self {
+        MemoryDedupStore::default()
+    }
+
+    /// How often run_until() removes expired keys (default 60 seconds)
+    pub fn sweep_every(mut self, interval: Duration) -> Self {
+        self.sweep_every = interval;
+        self
+    }
+
+    /// Remove expired keys, returning how many were removed
+    pub fn sweep(&self) -> usize {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().unwrap();
+        let before = keys.len();
+        keys.retain(|_, expires| *expires > now);
+        before - keys.len()
+    }
+
+    /// How many keys are held, including expired ones not yet swept
+    pub fn len(&self) -> usize {
+        self.keys.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sweep every sweep_every until shutdown is cancelled
+    pub async fn run_until(&self, shutdown: CancellationToken) {
+        let mut ticker = tokio::time::interval(self.sweep_every);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = ticker.tick() => {},
+            }
+            self.sweep();
+        }
+    }
+}
+
+#[async_trait]
+impl DedupStore for MemoryDedupStore {
+    async fn seen(&self, key: &str) -> Result<bool, EventfulError> {
+        let seen = self.keys.lock().unwrap().get(key).map(|expires| *expires > Instant::now()).unwrap_or(false);
+        self.stats.record(seen);
+        Ok(seen)
+    }
+
+    async fn mark(&self, key: &str, ttl: Duration) -> Result<bool, EventfulError> {
+        let now = Instant::now();
+        let marked = {
+            let mut keys = self.keys.lock().unwrap();
+            match keys.get(key) {
+                Some(expires) if *expires > now => false,
+                _ => {
+                    keys.insert(key.to_string(), now + ttl);
+                    true
+                },
+            }
+        };
+        self.stats.record(!marked);
+        Ok(marked)
+    }
+
+    fn stats(&self) -> DedupStatsSnapshot {
+        self.stats.snapshot()
+    }
+}
+
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisDedupStore;
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::{sync::Arc, time::Duration};
+    use async_trait::async_trait;
+    use redis::aio::ConnectionManager;
+    use crate::err::EventfulError;
+    use super::{DedupStats, DedupStatsSnapshot, DedupStore};
+
+    /// A DedupStore kept in Redis, so keys survive restarts and are shared between replicas.
+    /// mark is a single SET NX PX, so Redis decides the winner and expires the key; no client clock is involved.
+    /// Clones share their stats
+    #[derive(Clone)]
+    pub struct RedisDedupStore {
+        conn: ConnectionManager,
+        prefix: String,
+        stats: Arc<DedupStats>,
+    }
+
+    impl RedisDedupStore {
+        /// Keys are stored as `{prefix}{key}`
+        pub fn new(conn: ConnectionManager, prefix: &str) -> Self {
+            RedisDedupStore{conn, prefix: prefix.to_string(), stats: Arc::new(DedupStats::default())}
+        }
+
+        /// Connect to a Redis URL, i.e. "redis://127.0.0.1/"
+        pub async fn connect(url: &str, prefix: &str) -> Result<Self, EventfulError> {
+            let client = redis::Client::open(url)?;
+            Ok(RedisDedupStore::new(ConnectionManager::new(client).await?, prefix))
+        }
+    }
+
+    #[async_trait]
+    impl DedupStore for RedisDedupStore {
+        async fn seen(&self, key: &str) -> Result<bool, EventfulError> {
+            let mut conn = self.conn.clone();
+            let exists: bool = redis::cmd("EXISTS").arg(format!("{}{}", self.prefix, key)).query_async(&mut conn).await?;
+            self.stats.record(exists);
+            Ok(exists)
+        }
+
+        async fn mark(&self, key: &str, ttl: Duration) -> Result<bool, EventfulError> {
+            let mut conn = self.conn.clone();
+            let set: Option<String> = redis::cmd("SET")
+                .arg(format!("{}{}", self.prefix, key))
+                .arg(1)
+                .arg("NX")
+                .arg("PX")
+                // PX 0 is an error, so round short TTLs up to a millisecond
+                .arg((ttl.as_millis() as u64).max(1))
+                .query_async(&mut conn).await?;
+            self.stats.record(set.is_none());
+            Ok(set.is_some())
+        }
+
+        fn stats(&self) -> DedupStatsSnapshot {
+            self.stats.snapshot()
+        }
+    }
+}
+
+
+/// The outcome of IdempotencyGuard::execute
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome<R> {
+    /// The key had already been processed, so the handler was not run
+    Skipped,
+    /// The handler ran and returned this
+    Executed(R),
+}
+
+
+/// Below this many entries the lock map isn't pruned
+const MIN_PRUNE_AT: usize = 64;
+
+/// The per-key locks of in-flight executions. An entry outlives its lock until the next prune, which happens once
+/// the map has doubled since the last one, so pruning costs O(1) per key on average
+#[derive(Default)]
+struct KeyLocks {
+    locks: HashMap<String, Weak<AsyncMutex<()>>>,
+    prune_at: usize,
+}
+
+/// The IdempotencyGuard runs handlers at most once per key (within the TTL)
+pub struct IdempotencyGuard {
+    store: Arc<dyn DedupStore>,
+    ttl: Duration,
+    locks: Mutex<KeyLocks>,
+}
+
+impl IdempotencyGuard {
+    /// Keys are remembered for ttl after the handler succeeds
+    pub fn new(store: Arc<dyn DedupStore>, ttl: Duration) -> Self {
+        IdempotencyGuard{store, ttl, locks: Mutex::new(KeyLocks{locks: HashMap::new(), prune_at: MIN_PRUNE_AT})}
+    }
+
+    fn lock_for(&self, key: &str) -> Arc<AsyncMutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        if let Some(lock) = locks.locks.get(key).and_then(Weak::upgrade) {
+            return lock
+        }
+        if locks.locks.len() >= locks.prune_at {
+            locks.locks.retain(|_, lock| lock.strong_count() > 0);
+            let live = locks.locks.len();
+            locks.prune_at = (live * 2).max(MIN_PRUNE_AT);
+        }
+        let lock = Arc::new(AsyncMutex::new(()));
+        locks.locks.insert(key.to_string(), Arc::downgrade(&lock));
+        lock
+    }
+
+    /// Run the handler unless the key has already been processed.
+    /// If the handler fails, the key is not recorded, so a redelivery will run it again
+    pub async fn execute<R, F, Fut>(&self, key: &str, handler: F) -> Result<Outcome<R>, EventfulError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<R, EventfulError>>,
+    {
+        let lock = self.lock_for(key);
+        let _held = lock.lock().await;
+        if self.store.seen(key).await? {
+            return Ok(Outcome::Skipped)
+        }
+        let result = handler().await?;
+        self.store.mark(key, self.ttl).await?;
+        Ok(Outcome::Executed(result))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lock_map_is_pruned_once_it_doubles() {
+        let guard = IdempotencyGuard::new(Arc::new(MemoryDedupStore::new()), Duration::from_secs(60));
+        for i in 0..1000 {
+            let key = format!("order-{}", i);
+            assert_eq!(guard.execute(&key, || async { Ok(()) }).await.unwrap(), Outcome::Executed(()));
+        }
+        // every lock has been dropped, so at most one doubling's worth of dead entries remain
+        let locks = guard.locks.lock().unwrap();
+        assert!(locks.locks.len() <= MIN_PRUNE_AT, "{} entries", locks.locks.len());
+    }
+
+    #[tokio::test]
+    async fn held_locks_survive_a_prune() {
+        let guard = IdempotencyGuard::new(Arc::new(MemoryDedupStore::new()), Duration::from_secs(60));
+        let held = guard.lock_for("held");
+        for i in 0..(MIN_PRUNE_AT * 4) {
+            guard.lock_for(&format!("order-{}", i));
+        }
+        assert!(Arc::ptr_eq(&held, &guard.lock_for("held")));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn exactly_one_concurrent_mark_wins() {
+        let store = Arc::new(MemoryDedupStore::new());
+        let start = Arc::new(tokio::sync::Barrier::new(64));
+        let tasks = (0..64).map(|_| {
+            let (store, start) = (store.clone(), start.clone());
+            tokio::spawn(async move {
+                start.wait().await;
+                store.mark("order-1", Duration::from_secs(60)).await.unwrap()
+            })
+        }).collect::<Vec<_>>();
+        let mut winners = 0;
+        for task in tasks {
+            winners += task.await.unwrap() as usize;
+        }
+        assert_eq!(winners, 1);
+        assert_eq!(store.stats(), DedupStatsSnapshot{hits: 63, misses: 1});
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn concurrent_deliveries_execute_once() {
+        let guard = Arc::new(IdempotencyGuard::new(Arc::new(MemoryDedupStore::new()), Duration::from_secs(60)));
+        let executed = Arc::new(AtomicU64::new(0));
+        let start = Arc::new(tokio::sync::Barrier::new(32));
+        let tasks = (0..32).map(|_| {
+            let (guard, executed, start) = (guard.clone(), executed.clone(), start.clone());
+            tokio::spawn(async move {
+                start.wait().await;
+                guard.execute("order-1", || async {
+                    executed.fetch_add(1, Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                    Ok(())
+                }).await.unwrap()
+            })
+        }).collect::<Vec<_>>();
+        let mut skipped = 0;
+        for task in tasks {
+            if task.await.unwrap() == Outcome::Skipped {
+                skipped += 1;
+            }
+        }
+        assert_eq!(executed.load(Ordering::SeqCst), 1);
+        assert_eq!(skipped, 31);
+    }
+
+    #[tokio::test]
+    async fn a_failed_handler_leaves_the_key_unmarked() {
+        let store = Arc::new(MemoryDedupStore::new());
+        let guard = IdempotencyGuard::new(store.clone(), Duration::from_secs(60));
+        let failed = guard.execute("order-1", || async { Err::<(), _>(EventfulError::Destination("down".to_string())) }).await;
+        assert!(failed.is_err());
+        assert!(!store.seen("order-1").await.unwrap());
+        assert_eq!(guard.execute("order-1", || async { Ok(1) }).await.unwrap(), Outcome::Executed(1));
+        assert_eq!(guard.execute("order-1", || async { Ok(2) }).await.unwrap(), Outcome::Skipped);
+    }
+
+    #[tokio::test]
+    async fn expired_keys_can_be_marked_again_and_are_swept() {
+        let store = MemoryDedupStore::new();
+        // a zero TTL has expired as soon as it is marked
+        assert!(store.mark("order-1", Duration::ZERO).await.unwrap());
+        assert!(!store.seen("order-1").await.unwrap());
+        assert!(store.mark("order-1", Duration::ZERO).await.unwrap());
+        assert!(store.mark("order-2", Duration::from_secs(60)).await.unwrap());
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.sweep(), 1);
+        assert_eq!(store.len(), 1);
+        assert!(store.seen("order-2").await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_until_sweeps_until_shutdown() {
+        let store = Arc::new(MemoryDedupStore::new().sweep_every(Duration::from_secs(30)));
+        let shutdown = CancellationToken::new();
+        let sweeper = tokio::spawn({
+            let (store, shutdown) = (store.clone(), shutdown.clone());
+            async move { store.run_until(shutdown).await }
+        });
+        // let the sweeper take its first, immediate tick
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        store.mark("order-1", Duration::ZERO).await.unwrap();
+        assert_eq!(store.len(), 1);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        assert!(store.is_empty());
+        shutdown.cancel();
+        sweeper.await.unwrap();
+    }
+}
+
+
+/// Needs redis (REDIS_URL, default redis://localhost:6379)
+#[cfg(all(test, feature = "redis"))]
+mod redis_integration {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[ignore = "needs redis"]
+    async fn exactly_one_concurrent_redis_mark_wins() {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string());
+        let prefix = format!("eventful-test-{}:", std::process::id());
+        let store = Arc::new(RedisDedupStore::connect(&url, &prefix).await.unwrap());
+        let start = Arc::new(tokio::sync::Barrier::new(64));
+        let tasks = (0..64).map(|_| {
+            let (store, start) = (store.clone(), start.clone());
+            tokio::spawn(async move {
+                start.wait().await;
+                store.mark("order-1", Duration::from_secs(5)).await.unwrap()
+            })
+        }).collect::<Vec<_>>();
+        let mut winners = 0;
+        for task in tasks {
+            winners += task.await.unwrap() as usize;
+        }
+        assert_eq!(winners, 1);
+        assert!(store.seen("order-1").await.unwrap());
+        assert_eq!(store.stats(), DedupStatsSnapshot{hits: 64, misses: 1});
+    }
+}
@@ -0,0 +1,290 @@
+//! The interceptor module lets cross-cutting concerns (tracing, metrics, compression, encryption...)
+//! wrap the byte pipeline without bespoke hooks in every publisher and subscriber.  
+//! Interceptors are applied in order on publish and in reverse order on consume, so a chain of
+//! [compress, encrypt] encrypts the compressed body when publishing and decrypts before decompressing when consuming.
+//! An interceptor returning an error stops the chain: on publish nothing is sent,
+//! and on consume the delivery is nacked (and the error logged) and next() moves on to the following delivery.
+
+use std::{sync::Arc, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::subscriber::{Delivery, Subscriber};
+
+
+/// The context passed to Interceptor::on_publish
+pub struct PublishCtx {
+    pub dest: Destination,
+    pub body: Bytes,
+    pub meta: Metadata,
+}
+
+
+/// The context passed to Interceptor::on_consume
+pub struct ConsumeCtx {
+    pub source: Destination,
+    pub body: Bytes,
+    pub meta: Metadata,
+    pub message_id: Option<String>,
+    pub attempt: u32,
+}
+
+
+/// An Interceptor can inspect and modify every message published or consumed.
+/// Both methods default to doing nothing, so implement whichever you need
+pub trait Interceptor: Send + Sync {
+    fn on_publish(&self, _ctx: &mut PublishCtx) -> Result<(), EventfulError> {
+        Ok(())
+    }
+
+    fn on_consume(&self, _ctx: &mut ConsumeCtx) -> Result<(), EventfulError> {
+        Ok(())
+    }
+}
+
+
+/// An InterceptedPublisher runs each interceptor's on_publish, in order, before publishing
+pub struct InterceptedPublisher<P> {
+    inner: P,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+}
+
+impl<P: Publisher> InterceptedPublisher<P> {
+    pub fn new(inner: P, interceptors: Vec<Arc<dyn Interceptor>>) -> Self {
+        InterceptedPublisher{inner, interceptors}
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for InterceptedPublisher<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let mut ctx = PublishCtx{dest: dest.clone(), body, meta: meta.clone()};
+        for interceptor in &self.interceptors {
+            interceptor.on_publish(&mut ctx)?;
+        }
+        self.inner.publish_bytes(&ctx.dest, ctx.body, &ctx.meta).await
+    }
+}
+
+
+/// An InterceptedSubscriber runs each interceptor's on_consume, in reverse order, on every delivery
+pub struct InterceptedSubscriber<S> {
+    inner: S,
+    interceptors: Vec<Arc<dyn Interceptor>>,
+    /// How long a delivery rejected by an interceptor waits before it is redelivered
+    reject_delay: Duration,
+}
+
+impl<S: Subscriber> InterceptedSubscriber<S> {
+    pub fn new(inner: S, interceptors: Vec<Arc<dyn Interceptor>>) -> Self {
+        InterceptedSubscriber{inner, interceptors, reject_delay: Duration::from_secs(10)}
+    }
+
+    /// Set how long a delivery rejected by an interceptor waits before it is redelivered (default 10 seconds)
+    pub fn reject_delay(mut self, delay: Duration) -> Self {
+        self.reject_delay = delay;
+        self
+    }
+}
+
+#[async_trait]
+impl<S: Subscriber> Subscriber for InterceptedSubscriber<S> {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        loop {
+            let mut delivery = match self.inner.next().await? {
+                Some(delivery) => delivery,
+                None => return Ok(None),
+            };
+            let mut ctx = ConsumeCtx{
+                source: delivery.source.clone(),
+                body: delivery.body.clone(),
+                meta: delivery.meta.clone(),
+                message_id: delivery.message_id.clone(),
+                attempt: delivery.attempt,
+            };
+            let rejected = self.interceptors.iter().rev()
+                .find_map(|interceptor| interceptor.on_consume(&mut ctx).err());
+            match rejected {
+                // one bad message mustn't stop the consumer: nack it and move on to the next
+                Some(err) => {
+                    crate::trace::record_error(&err);
+                    delivery.nack(self.reject_delay).await?;
+                },
+                None => {
+                    delivery.body = ctx.body;
+                    delivery.meta = ctx.meta;
+                    return Ok(Some(delivery))
+                },
+            }
+        }
+    }
+}
+
+
+/// A ready-made interceptor which adds fixed headers to the metadata of every published message
+pub struct StaticHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl StaticHeaders {
+    pub fn new(headers: &[(&str, &str)]) -> Self {
+        let headers = headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        StaticHeaders{headers}
+    }
+}
+
+impl Interceptor for StaticHeaders {
+    fn on_publish(&self, ctx: &mut PublishCtx) -> Result<(), EventfulError> {
+        for (key, value) in &self.headers {
+            ctx.meta.headers.entry(key.clone()).or_insert(value.clone());
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, atomic::{AtomicBool, Ordering}};
+    use crate::memory::MemoryBroker;
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    /// Appends its name to the body on publish and strips it on consume, logging each call
+    struct Suffix {
+        name: &'static str,
+        calls: Calls,
+    }
+
+    impl Interceptor for Suffix {
+        fn on_publish(&self, ctx: &mut PublishCtx) -> Result<(), EventfulError> {
+            self.calls.lock().unwrap().push(format!("publish {}", self.name));
+            ctx.body = Bytes::from([&ctx.body[..], self.name.as_bytes()].concat());
+            Ok(())
+        }
+
+        fn on_consume(&self, ctx: &mut ConsumeCtx) -> Result<(), EventfulError> {
+            self.calls.lock().unwrap().push(format!("consume {}", self.name));
+            match ctx.body.strip_suffix(self.name.as_bytes()) {
+                Some(body) => {
+                    ctx.body = Bytes::copy_from_slice(body);
+                    Ok(())
+                },
+                None => Err(EventfulError::Destination(format!("body doesn't end in {}", self.name))),
+            }
+        }
+    }
+
+    /// Fails the first call of each kind
+    #[derive(Default)]
+    struct FailOnce {
+        publish_failed: AtomicBool,
+        consume_failed: AtomicBool,
+    }
+
+    impl Interceptor for FailOnce {
+        fn on_publish(&self, _ctx: &mut PublishCtx) -> Result<(), EventfulError> {
+            match self.publish_failed.swap(true, Ordering::SeqCst) {
+                false => Err(EventfulError::Config("rejected".to_string())),
+                true => Ok(()),
+            }
+        }
+
+        fn on_consume(&self, _ctx: &mut ConsumeCtx) -> Result<(), EventfulError> {
+            match self.consume_failed.swap(true, Ordering::SeqCst) {
+                false => Err(EventfulError::Config("rejected".to_string())),
+                true => Ok(()),
+            }
+        }
+    }
+
+    fn dest() -> Destination {
+        Destination::NsqTopic("click".to_string())
+    }
+
+    fn chain(calls: &Calls) -> Vec<Arc<dyn Interceptor>> {
+        vec![
+            Arc::new(Suffix{name: "-a", calls: calls.clone()}),
+            Arc::new(Suffix{name: "-b", calls: calls.clone()}),
+        ]
+    }
+
+    #[tokio::test]
+    async fn applied_in_order_on_publish_and_reverse_order_on_consume() {
+        let (broker, calls) = (MemoryBroker::new(), Calls::default());
+        let mut subscriber = InterceptedSubscriber::new(broker.subscribe(&dest(), "test"), chain(&calls));
+        let publisher = InterceptedPublisher::new(broker.clone(), chain(&calls));
+        publisher.publish_bytes(&dest(), Bytes::from_static(b"body"), &Metadata::default()).await.unwrap();
+        assert_eq!(broker.published_to("click"), vec![Bytes::from_static(b"body-a-b")]);
+
+        let delivery = subscriber.next().await.unwrap().unwrap();
+        assert_eq!(delivery.body, Bytes::from_static(b"body"));
+        delivery.ack().await.unwrap();
+        assert_eq!(*calls.lock().unwrap(), vec!["publish -a", "publish -b", "consume -b", "consume -a"]);
+    }
+
+    #[tokio::test]
+    async fn interceptors_can_change_metadata() {
+        let broker = MemoryBroker::new();
+        let mut subscriber = broker.subscribe(&dest(), "test");
+        let publisher = InterceptedPublisher::new(broker.clone(), vec![Arc::new(StaticHeaders::new(&[("team", "web"), ("env", "test")]))]);
+        let mut meta = Metadata::default();
+        meta.headers.insert("env".to_string(), "prod".to_string());
+        publisher.publish_bytes(&dest(), Bytes::from_static(b"{}"), &meta).await.unwrap();
+        let delivery = subscriber.next().await.unwrap().unwrap();
+        assert_eq!(delivery.meta.headers.get("team").map(String::as_str), Some("web"));
+        // headers already set are left alone
+        assert_eq!(delivery.meta.headers.get("env").map(String::as_str), Some("prod"));
+        delivery.ack().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn publish_error_stops_the_chain_and_sends_nothing() {
+        let (broker, calls) = (MemoryBroker::new(), Calls::default());
+        let mut interceptors: Vec<Arc<dyn Interceptor>> = vec![Arc::new(FailOnce::default())];
+        interceptors.extend(chain(&calls));
+        let publisher = InterceptedPublisher::new(broker.clone(), interceptors);
+        assert!(matches!(publisher.publish_bytes(&dest(), Bytes::from_static(b"body"), &Metadata::default()).await, Err(EventfulError::Config(_))));
+        assert!(broker.published().is_empty());
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn consume_error_nacks_the_delivery() {
+        let (broker, calls) = (MemoryBroker::new(), Calls::default());
+        let mut interceptors = chain(&calls);
+        interceptors.push(Arc::new(FailOnce::default()));
+        let mut subscriber = InterceptedSubscriber::new(broker.subscribe(&dest(), "test"), interceptors).reject_delay(Duration::ZERO);
+        broker.publish_bytes(&dest(), Bytes::from_static(b"body-a-b"), &Metadata::default()).await.unwrap();
+
+        // FailOnce runs first on consume and rejects the first attempt, so next() returns the redelivery
+        let delivery = subscriber.next().await.unwrap().unwrap();
+        assert_eq!((delivery.attempt, delivery.body.clone()), (2, Bytes::from_static(b"body")));
+        assert_eq!(*calls.lock().unwrap(), vec!["consume -b", "consume -a"]);
+        delivery.ack().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_rejected_message_does_not_stop_the_consumer() {
+        let (broker, calls) = (MemoryBroker::new(), Calls::default());
+        // a long reject delay keeps the rejected message out of the way while the others are handled
+        let mut subscriber = InterceptedSubscriber::new(broker.subscribe(&dest(), "test"), chain(&calls)).reject_delay(Duration::from_secs(3600));
+        for body in [&b"first-a-b"[..], b"unsigned", b"second-a-b", b"third-a-b"] {
+            broker.publish_bytes(&dest(), Bytes::copy_from_slice(body), &Metadata::default()).await.unwrap();
+        }
+
+        let mut handled = Vec::new();
+        for _ in 0..3 {
+            let delivery = subscriber.next().await.unwrap().unwrap();
+            handled.push(delivery.body.clone());
+            delivery.ack().await.unwrap();
+        }
+        assert_eq!(handled, vec![Bytes::from_static(b"first"), Bytes::from_static(b"second"), Bytes::from_static(b"third")]);
+    }
+}
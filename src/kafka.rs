@@ -0,0 +1,419 @@
+//! The kafka module makes it easy to produce and consume events using [Apache Kafka](https://kafka.apache.org/),
+//! with the same ergonomics as the nsq module. It is enabled by the `kafka` feature.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use eventful::prelude::*;
+//! # use eventful::kafka::{EventKafka, ProducerKafka};
+//! #[derive(Serialize, Deserialize)]
+//! struct UserClickedSomething {
+//!     user_id: i32,
+//!     clicked_on: String,
+//! }
+//!
+//! impl EventKafka for UserClickedSomething {
+//!     fn topic() -> &'static str {
+//!         "website_clicks"
+//!     }
+//!     fn key(&self) -> Option<String> {
+//!         Some(self.user_id.to_string())
+//!     }
+//! }
+//!
+//! # async fn demo(click: UserClickedSomething) -> Result<(), EventfulError> {
+//! let producer = ProducerKafka::new("127.0.0.1:9092")?;
+//! producer.publish(&click).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{future::Future, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::Utc;
+use rdkafka::{
+    ClientConfig, Message,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    error::KafkaError,
+    producer::{FutureProducer, FutureRecord},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::codec::{Codec, JsonCodec};
+use crate::consumer::ConsumerOptions;
+use crate::deadletter::DeadLetterRecord;
+use crate::err::EventfulError;
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+use crate::retry::execute_with_retry;
+
+
+/// Implement EventKafka on a struct to publish it to a Kafka topic
+pub trait EventKafka: Serialize + DeserializeOwned {
+    fn topic() -> &'static str;
+
+    /// Events with the same key go to the same partition, and so are consumed in order
+    fn key(&self) -> Option<String> {
+        None
+    }
+}
+
+
+/// Where a published event landed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeliveryKafka {
+    pub partition: i32,
+    pub offset: i64,
+}
+
+
+/// The ProducerKafka publishes events and waits for the broker to confirm delivery
+#[derive(Clone)]
+pub struct ProducerKafka {
+    producer: FutureProducer,
+    timeout: Duration,
+}
+
+impl ProducerKafka {
+    /// brokers is a comma-separated list, i.e. "kafka1:9092,kafka2:9092"
+    pub fn new(brokers: &str) -> Result<Self, EventfulError> {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "30000")
+            .create()?;
+        Ok(ProducerKafka{producer, timeout: Duration::from_secs(30)})
+    }
+
+    /// Use a producer configured some other way
+    pub fn from_producer(producer: FutureProducer) -> Self {
+        ProducerKafka{producer, timeout: Duration::from_secs(30)}
+    }
+
+    /// Publish raw bytes to a topic, waiting for delivery confirmation
+    pub async fn publish_raw(&self, topic: &str, key: Option<&str>, body: &[u8]) -> Result<DeliveryKafka, EventfulError> {
+        let mut record = FutureRecord::<str, [u8]>::to(topic).payload(body);
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+        let (partition, offset) = self.producer.send(record, self.timeout).await
+            .map_err(|(err, _message)| EventfulError::from(err))?;
+        Ok(DeliveryKafka{partition, offset})
+    }
+
+    /// Serialize an event and publish it to its topic
+    pub async fn publish<T: EventKafka>(&self, event: &T) -> Result<DeliveryKafka, EventfulError> {
+        let body = JsonCodec.encode(event)?;
+        let key = event.key();
+        self.publish_raw(<T as EventKafka>::topic(), key.as_deref(), &body).await
+    }
+}
+
+
+/// The ProducerKafka can act as a Publisher for Kafka topics.
+/// meta.group_id, when set, is used as the message key
+#[async_trait]
+impl Publisher for ProducerKafka {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let topic = match dest {
+            Destination::KafkaTopic(topic) => topic,
+            _ => return Err(publisher::unsupported("ProducerKafka", dest)),
+        };
+        let delivery = self.publish_raw(topic, meta.group_id.as_deref(), &body).await?;
+        Ok(Receipt{message_id: Some(format!("{}:{}", delivery.partition, delivery.offset))})
+    }
+}
+
+
+/// The GroupConsumer trait mirrors ChannelConsumer: a consumer group id takes the place of the channel.
+/// Offsets are committed manually, and only after the handler succeeds (or the message is dead-lettered)
+#[async_trait]
+pub trait GroupConsumer<T: EventKafka> {
+
+    /// This method must be implemented to set the consumer group
+    fn group_id(&self) -> String;
+
+    /// For most use cases, this default implementation would likely not be overwritten
+    fn consumer(&self, brokers: &str) -> Result<StreamConsumer, EventfulError> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", self.group_id())
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()?;
+        consumer.subscribe(&[<T as EventKafka>::topic()])?;
+        Ok(consumer)
+    }
+
+    fn deserialize_event(&self, body: &[u8]) -> Result<T, EventfulError> {
+        JsonCodec.decode(body)
+    }
+
+    /// Consume events and pass them to the handler.  
+    /// Kafka has no per-message nack, so a failing handler is retried in place according to options.retry;
+    /// once the retries are exhausted the message goes to options.dead_letter and its offset is committed.
+    /// A message which can't be decoded goes straight to options.dead_letter. Without a dead-letter sink, the error is returned and the offset is left uncommitted
+    async fn run<H, Fut>(&self, brokers: &str, options: &ConsumerOptions, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        let consumer = self.consumer(brokers)?;
+        let source = Destination::KafkaTopic(<T as EventKafka>::topic().to_string());
+        loop {
+            let message = consumer.recv().await?;
+            handle_message(self, &source, message.payload().unwrap_or_default(), message.key(), options, &handler).await?;
+            consumer.commit_message(&message, CommitMode::Async)?;
+        }
+    }
+}
+
+
+/// Decode one message and run the handler on it, retrying in place, then dead-letter it if that fails.
+/// A body which can't be decoded is dead-lettered after one attempt, since retrying won't change it.
+/// Ok means the offset can be committed
+async fn handle_message<T, C, H, Fut>(consumer: &C, source: &Destination, body: &[u8], key: Option<&[u8]>, options: &ConsumerOptions, handler: &H) -> Result<(), EventfulError>
+where
+    T: EventKafka,
+    C: GroupConsumer<T> + ?Sized,
+    H: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), EventfulError>>,
+{
+    let (attempts, err) = match consumer.deserialize_event(body) {
+        Err(err) => (1, err),
+        Ok(event) => {
+            let mut decoded = Some(event);
+            let mut attempts = 0;
+            let result = execute_with_retry(&options.retry, || {
+                attempts += 1;
+                // the first attempt uses the event already decoded; retries decode the body again for a fresh one
+                let event = decoded.take().map(Ok).unwrap_or_else(|| consumer.deserialize_event(body));
+                async move { handler(event?).await }
+            }).await;
+            match result {
+                Ok(()) => return Ok(()),
+                Err(err) => (attempts, err),
+            }
+        },
+    };
+    let sink = match &options.dead_letter {
+        Some(sink) => sink,
+        None => return Err(err),
+    };
+    let record = DeadLetterRecord{
+        source: source.clone(),
+        original_body: Bytes::copy_from_slice(body),
+        attempts,
+        last_error: err.to_string(),
+        first_seen: Utc::now(),
+        metadata: Metadata{group_id: key.map(|key| String::from_utf8_lossy(key).to_string()), ..Default::default()},
+    };
+    sink.send(record).await
+}
+
+
+/// Publish an event to its topic
+pub async fn publish_to<T: EventKafka>(event: &T, producer: &ProducerKafka) -> Result<DeliveryKafka, EventfulError> {
+    producer.publish(event).await
+}
+
+
+impl From<KafkaError> for EventfulError {
+    fn from(err: KafkaError) -> Self {
+        EventfulError::Kafka(format!("{:?}", err))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}};
+    use serde::Deserialize;
+    use crate::deadletter::DeadLetterSink;
+    use crate::retry::{Backoff, RetryPolicy};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Click {
+        user_id: i32,
+    }
+
+    impl EventKafka for Click {
+        fn topic() -> &'static str {
+            "click"
+        }
+    }
+
+    struct Clicks;
+
+    impl GroupConsumer<Click> for Clicks {
+        fn group_id(&self) -> String {
+            "test".to_string()
+        }
+    }
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<DeadLetterRecord>>);
+
+    #[async_trait]
+    impl DeadLetterSink for Collect {
+        async fn send(&self, record: DeadLetterRecord) -> Result<(), EventfulError> {
+            self.0.lock().unwrap().push(record);
+            Ok(())
+        }
+    }
+
+    fn options(dead_letters: &Arc<Collect>) -> ConsumerOptions {
+        let retry = RetryPolicy::default().max_attempts(3).backoff(Backoff::Fixed(Duration::from_secs(1)));
+        ConsumerOptions::default().retry(retry).dead_letter(dead_letters.clone())
+    }
+
+    fn source() -> Destination {
+        Destination::KafkaTopic("click".to_string())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failing_handler_is_dead_lettered_with_its_real_attempt_count() {
+        let dead_letters = Arc::new(Collect::default());
+        let calls = AtomicU32::new(0);
+        let handler = |_: Click| async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Err(EventfulError::Http("flaky".to_string()))
+        };
+        handle_message(&Clicks, &source(), br#"{"user_id":5}"#, Some(b"user-5"), &options(&dead_letters), &handler).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        let records = dead_letters.0.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].attempts, records[0].metadata.group_id.as_deref()), (3, Some("user-5")));
+        assert_eq!(records[0].original_body, Bytes::from_static(br#"{"user_id":5}"#));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_non_retryable_failure_records_one_attempt() {
+        let dead_letters = Arc::new(Collect::default());
+        let handler = |_: Click| async { Err(EventfulError::Config("bad click".to_string())) };
+        handle_message(&Clicks, &source(), br#"{"user_id":5}"#, None, &options(&dead_letters), &handler).await.unwrap();
+        assert_eq!(dead_letters.0.lock().unwrap()[0].attempts, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_undecodable_body_goes_straight_to_the_dead_letter_sink() {
+        let dead_letters = Arc::new(Collect::default());
+        let calls = AtomicU32::new(0);
+        let handler = |_: Click| async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        };
+        let started = tokio::time::Instant::now();
+        handle_message(&Clicks, &source(), b"not json", None, &options(&dead_letters), &handler).await.unwrap();
+        // no retry delay was waited out, and the handler never ran
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+        let records = dead_letters.0.lock().unwrap();
+        assert_eq!((records.len(), records[0].attempts), (1, 1));
+        assert_eq!(records[0].original_body, Bytes::from_static(b"not json"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recovered_handlers_and_missing_sinks() {
+        let dead_letters = Arc::new(Collect::default());
+        let calls = AtomicU32::new(0);
+        // fails once, then succeeds with a freshly decoded event
+        let handler = |click: Click| async {
+            assert_eq!(click.user_id, 5);
+            match calls.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(EventfulError::Http("flaky".to_string())),
+                _ => Ok(()),
+            }
+        };
+        handle_message(&Clicks, &source(), br#"{"user_id":5}"#, None, &options(&dead_letters), &handler).await.unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(dead_letters.0.lock().unwrap().is_empty());
+        // without a sink the error comes back, so the offset isn't committed
+        let no_sink = ConsumerOptions::default().retry(RetryPolicy::default().max_attempts(1));
+        let result = handle_message(&Clicks, &source(), b"not json", None, &no_sink, &handler).await;
+        assert!(matches!(result, Err(EventfulError::SerdeJSON(_))), "{:?}", result);
+    }
+}
+
+
+/// Needs kafka from examples/kafka/docker-compose.yml (KAFKA_BROKERS, default 127.0.0.1:9092):
+/// `docker compose -f examples/kafka/docker-compose.yml up -d`, then `cargo test --features kafka -- --ignored`
+#[cfg(test)]
+mod integration {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use serde::Deserialize;
+    use crate::deadletter::DeadLetterSink;
+    use crate::retry::RetryPolicy;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Numbered {
+        n: u32,
+    }
+
+    impl EventKafka for Numbered {
+        fn topic() -> &'static str {
+            "eventful_integration"
+        }
+        fn key(&self) -> Option<String> {
+            Some("numbers".to_string())
+        }
+    }
+
+    struct Group(String);
+
+    impl GroupConsumer<Numbered> for Group {
+        fn group_id(&self) -> String {
+            self.0.clone()
+        }
+    }
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<DeadLetterRecord>>);
+
+    #[async_trait]
+    impl DeadLetterSink for Collect {
+        async fn send(&self, record: DeadLetterRecord) -> Result<(), EventfulError> {
+            self.0.lock().unwrap().push(record);
+            Ok(())
+        }
+    }
+
+    fn brokers() -> String {
+        std::env::var("KAFKA_BROKERS").unwrap_or_else(|_| "127.0.0.1:9092".to_string())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs kafka"]
+    async fn publishes_and_consumes_in_key_order_dead_lettering_bad_bodies() {
+        // the group starts from the earliest offset, so this run's events are told apart from earlier runs' by number
+        let n = rand::random::<u32>() / 2;
+        let producer = ProducerKafka::new(&brokers()).unwrap();
+        let first = producer.publish(&Numbered{n}).await.unwrap();
+        producer.publish_raw(Numbered::topic(), Some("numbers"), b"not json").await.unwrap();
+        let last = producer.publish(&Numbered{n: n + 1}).await.unwrap();
+        assert_eq!(first.partition, last.partition);
+
+        let group = Group(format!("eventful-test-{}", n));
+        let dead_letters = Arc::new(Collect::default());
+        let options = ConsumerOptions::default().retry(RetryPolicy::default().max_attempts(1)).dead_letter(dead_letters.clone());
+        let seen = Mutex::new(Vec::new());
+        let consuming = group.run(&brokers(), &options, |numbered: Numbered| {
+            seen.lock().unwrap().push(numbered.n);
+            async { Ok(()) }
+        });
+        tokio::time::timeout(Duration::from_secs(30), async {
+            tokio::select! {
+                result = consuming => panic!("the consumer stopped: {:?}", result),
+                _ = async {
+                    while !seen.lock().unwrap().contains(&(n + 1)) {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                } => {},
+            }
+        }).await.expect("consumed this run's events within 30 seconds");
+        assert!(seen.lock().unwrap().ends_with(&[n, n + 1]));
+        let records = dead_letters.0.lock().unwrap();
+        assert_eq!(records.last().map(|record| (record.attempts, record.original_body.clone())), Some((1, Bytes::from_static(b"not json"))));
+    }
+}
@@ -0,0 +1,638 @@
+//! The kinesis module produces and consumes events with [AWS Kinesis Data Streams](https://aws.amazon.com/kinesis/data-streams/).
+//! It is enabled by the `kinesis` feature.
+//! 
+//! put_records splits batches at the PutRecords limits (500 records or 5 MB) and retries only the records which failed.
+//! ShardPoller is a simple consumer: it polls every open shard with GetRecords and checkpoints
+//! each shard's last processed sequence number through a CheckpointStore, so a restart resumes where it left off.
+//! It is not a replacement for the KCL: there is no lease coordination, so run one poller per stream.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use eventful::prelude::*;
+//! # use eventful::kinesis::{ClientKinesis, EventKinesis};
+//! # use eventful::publisher::Keyed;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct PageView { session_id: String }
+//! impl Keyed for PageView {
+//!     fn partition_key(&self) -> String {
+//!         self.session_id.clone()
+//!     }
+//! }
+//!
+//! impl EventKinesis for PageView {
+//!     fn stream_name() -> &'static str {
+//!         "page_views"
+//!     }
+//! }
+//!
+//! # async fn demo(views: Vec<PageView>) -> Result<(), EventfulError> {
+//! let kinesis = ClientKinesis::new("us-east-1").await;
+//! kinesis.put_records(&views).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::{HashMap, HashSet}, future::Future, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use async_trait::async_trait;
+use aws_sdk_kinesis::{Client, Region, model::{PutRecordsRequestEntry, ShardIteratorType}, types::Blob};
+use bytes::Bytes;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::codec::{Codec, JsonCodec};
+use crate::err::EventfulError;
+use crate::publisher::{self, Destination, Keyed, Metadata, Publisher, Receipt};
+use crate::retry::{RetryPolicy, execute_with_retry};
+
+
+/// PutRecords accepts at most this many records
+pub const MAX_BATCH_RECORDS: usize = 500;
+/// PutRecords accepts at most this many bytes (data plus partition keys)
+pub const MAX_BATCH_BYTES: usize = 5 * 1024 * 1024;
+
+
+/// Implement EventKinesis (and Keyed, for the partition key) on a struct to put it to a stream
+pub trait EventKinesis: Serialize + DeserializeOwned + Keyed {
+    fn stream_name() -> &'static str;
+}
+
+
+/// Where a record landed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordKinesis {
+    pub shard_id: String,
+    pub sequence_number: String,
+}
+
+
+/// What put_records did. Records are identified by their index in the input
+#[derive(Debug, Default)]
+pub struct PutRecordsReport {
+    pub succeeded: Vec<(usize, RecordKinesis)>,
+    /// Records which still failed once the retry policy was exhausted, with the last error code
+    pub failed: Vec<(usize, String)>,
+}
+
+impl PutRecordsReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+
+#[derive(Clone)]
+pub struct ClientKinesis {
+    client: Client,
+    retry: RetryPolicy,
+}
+
+impl ClientKinesis {
+    pub async fn new(region: &'static str) -> Self {
+        let config = aws_config::from_env().region(Region::new(region)).load().await;
+        ClientKinesis::from_client(Client::new(&config))
+    }
+
+    pub fn from_client(client: Client) -> Self {
+        ClientKinesis{client, retry: RetryPolicy::default()}
+    }
+
+    /// How failed records in a put_records batch are retried (default: RetryPolicy::default())
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// The underlying aws_sdk_kinesis Client, for operations eventful does not wrap
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Put one record
+    pub async fn put_record_raw(&self, stream_name: &str, partition_key: &str, body: Bytes) -> Result<RecordKinesis, EventfulError> {
+        let output = self.client.put_record()
+            .stream_name(stream_name)
+            .partition_key(partition_key)
+            .data(Blob::new(body.to_vec()))
+            .send().await.map_err(kinesis_err)?;
+        Ok(RecordKinesis{
+            shard_id: output.shard_id.unwrap_or_default(),
+            sequence_number: output.sequence_number.unwrap_or_default(),
+        })
+    }
+
+    /// Serialize an event and put it to its stream
+    pub async fn put_record<T: EventKinesis>(&self, event: &T) -> Result<RecordKinesis, EventfulError> {
+        let body = JsonCodec.encode(event)?;
+        self.put_record_raw(<T as EventKinesis>::stream_name(), &event.partition_key(), body).await
+    }
+
+    /// Serialize events and put them to their stream in as few PutRecords calls as the limits allow
+    pub async fn put_records<T: EventKinesis>(&self, events: &[T]) -> Result<PutRecordsReport, EventfulError> {
+        let mut records = Vec::with_capacity(events.len());
+        for event in events {
+            records.push((event.partition_key(), JsonCodec.encode(event)?));
+        }
+        self.put_records_raw(<T as EventKinesis>::stream_name(), records).await
+    }
+
+    /// Put (partition key, body) records, splitting them into batches within the PutRecords limits.
+    /// Records rejected individually (i.e. ProvisionedThroughputExceededException) are retried per the retry policy.
+    /// A request-level failure is returned as an error
+    pub async fn put_records_raw(&self, stream_name: &str, records: Vec<(String, Bytes)>) -> Result<PutRecordsReport, EventfulError> {
+        let mut report = PutRecordsReport::default();
+        let indexed: Vec<(usize, String, Bytes)> = records.into_iter().enumerate().map(|(i, (key, body))| (i, key, body)).collect();
+        for batch in split_batches(indexed) {
+            self.put_batch(stream_name, batch, &mut report).await?;
+        }
+        report.succeeded.sort_by_key(|(i, _)| *i);
+        report.failed.sort_by_key(|(i, _)| *i);
+        Ok(report)
+    }
+
+    async fn put_batch(&self, stream_name: &str, mut pending: Vec<(usize, String, Bytes)>, report: &mut PutRecordsReport) -> Result<(), EventfulError> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let entries = pending.iter()
+                .map(|(_, key, body)| PutRecordsRequestEntry::builder().partition_key(key).data(Blob::new(body.to_vec())).build())
+                .collect::<Vec<_>>();
+            let output = self.client.put_records()
+                .stream_name(stream_name)
+                .set_records(Some(entries))
+                .send().await.map_err(kinesis_err)?;
+            let mut retry = Vec::new();
+            // results are in the same order as the request
+            for (record, result) in pending.into_iter().zip(output.records.unwrap_or_default()) {
+                match (result.error_code, result.sequence_number) {
+                    (None, Some(sequence_number)) => {
+                        report.succeeded.push((record.0, RecordKinesis{shard_id: result.shard_id.unwrap_or_default(), sequence_number}));
+                    },
+                    (code, _) => retry.push((record, code.unwrap_or_else(|| "UnknownError".to_string()))),
+                }
+            }
+            if retry.is_empty() {
+                return Ok(())
+            }
+            if attempt >= self.retry.max_attempts {
+                report.failed.extend(retry.into_iter().map(|((i, _, _), code)| (i, code)));
+                return Ok(())
+            }
+            tokio::time::sleep(self.retry.delay_for(attempt)).await;
+            pending = retry.into_iter().map(|(record, _)| record).collect();
+        }
+    }
+}
+
+
+/// Split records into batches of at most MAX_BATCH_RECORDS records and MAX_BATCH_BYTES bytes
+fn split_batches(records: Vec<(usize, String, Bytes)>) -> Vec<Vec<(usize, String, Bytes)>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    for record in records {
+        let size = record.1.len() + record.2.len();
+        if !batch.is_empty() && (batch.len() >= MAX_BATCH_RECORDS || batch_bytes + size > MAX_BATCH_BYTES) {
+            batches.push(std::mem::take(&mut batch));
+            batch_bytes = 0;
+        }
+        batch_bytes += size;
+        batch.push(record);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+
+#[async_trait]
+impl Publisher for ClientKinesis {
+    /// The partition key is meta.group_id, falling back to a random key
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let stream_name = match dest {
+            Destination::KinesisStream(name) => name,
+            _ => return Err(publisher::unsupported("ClientKinesis", dest)),
+        };
+        let partition_key = meta.group_id.clone().unwrap_or_else(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
+        let record = self.put_record_raw(stream_name, &partition_key, body).await?;
+        Ok(Receipt{message_id: Some(record.sequence_number)})
+    }
+}
+
+
+/// A CheckpointStore remembers the last processed sequence number of each shard
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    async fn get(&self, stream_name: &str, shard_id: &str) -> Result<Option<String>, EventfulError>;
+    async fn set(&self, stream_name: &str, shard_id: &str, sequence_number: &str) -> Result<(), EventfulError>;
+}
+
+
+/// A CheckpointStore kept in memory, so a restarted poller starts over from its StartPosition
+#[derive(Default)]
+pub struct MemoryCheckpointStore {
+    checkpoints: Mutex<HashMap<(String, String), String>>,
+}
+
+impl MemoryCheckpointStore {
+    pub fn new() -> Self {
+        MemoryCheckpointStore::default()
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for MemoryCheckpointStore {
+    async fn get(&self, stream_name: &str, shard_id: &str) -> Result<Option<String>, EventfulError> {
+        let checkpoints = self.checkpoints.lock().unwrap();
+        Ok(checkpoints.get(&(stream_name.to_string(), shard_id.to_string())).cloned())
+    }
+
+    async fn set(&self, stream_name: &str, shard_id: &str, sequence_number: &str) -> Result<(), EventfulError> {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        checkpoints.insert((stream_name.to_string(), shard_id.to_string()), sequence_number.to_string());
+        Ok(())
+    }
+}
+
+
+/// A CheckpointStore kept in a DynamoDB table with the string partition key "shard" ("{stream_name}/{shard_id}")
+#[derive(Clone)]
+pub struct DynamoCheckpointStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoCheckpointStore {
+    pub fn new(client: aws_sdk_dynamodb::Client, table: &str) -> Self {
+        DynamoCheckpointStore{client, table: table.to_string()}
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for DynamoCheckpointStore {
+    async fn get(&self, stream_name: &str, shard_id: &str) -> Result<Option<String>, EventfulError> {
+        use aws_sdk_dynamodb::model::AttributeValue;
+        let output = self.client.get_item()
+            .table_name(&self.table)
+            .key("shard", AttributeValue::S(format!("{}/{}", stream_name, shard_id)))
+            .consistent_read(true)
+            .send().await.map_err(|err| EventfulError::Database(format!("{:?}", err)))?;
+        Ok(output.item
+            .and_then(|item| item.get("sequence_number").cloned())
+            .and_then(|value| value.as_s().ok().cloned()))
+    }
+
+    async fn set(&self, stream_name: &str, shard_id: &str, sequence_number: &str) -> Result<(), EventfulError> {
+        use aws_sdk_dynamodb::model::AttributeValue;
+        self.client.put_item()
+            .table_name(&self.table)
+            .item("shard", AttributeValue::S(format!("{}/{}", stream_name, shard_id)))
+            .item("sequence_number", AttributeValue::S(sequence_number.to_string()))
+            .send().await.map_err(|err| EventfulError::Database(format!("{:?}", err)))?;
+        Ok(())
+    }
+}
+
+
+/// Where to start reading a shard which has no checkpoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartPosition {
+    /// The oldest record still in the shard
+    TrimHorizon,
+    /// Only records put after the poller starts
+    Latest,
+}
+
+
+/// Shards opening and closing, reported to ShardPoller::on_shard_event
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShardEvent {
+    /// A shard was found (at startup or after resharding) and is now being polled
+    Opened(String),
+    /// A shard was fully read after being closed by resharding
+    Closed(String),
+}
+
+
+/// The ShardPoller polls every shard of a stream, passing each event to a handler and checkpointing as it goes
+pub struct ShardPoller {
+    client: ClientKinesis,
+    stream_name: String,
+    checkpoints: Arc<dyn CheckpointStore>,
+    start: StartPosition,
+    limit: i32,
+    poll_interval: Duration,
+    refresh_interval: Duration,
+    retry: RetryPolicy,
+    on_shard_event: Option<Box<dyn Fn(ShardEvent) + Send + Sync>>,
+}
+
+impl ShardPoller {
+    pub fn new(client: ClientKinesis, stream_name: &str, checkpoints: Arc<dyn CheckpointStore>) -> Self {
+        ShardPoller{
+            client,
+            stream_name: stream_name.to_string(),
+            checkpoints,
+            start: StartPosition::TrimHorizon,
+            limit: 1000,
+            poll_interval: Duration::from_secs(1),
+            refresh_interval: Duration::from_secs(60),
+            retry: RetryPolicy::default(),
+            on_shard_event: None,
+        }
+    }
+
+    pub fn start(mut self, start: StartPosition) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// The most records fetched by each GetRecords call (default 1000)
+    pub fn limit(mut self, limit: i32) -> Self {
+        self.limit = limit.clamp(1, 10_000);
+        self
+    }
+
+    /// How long to wait after a round of GetRecords calls (default 1 second; each shard allows 5 calls per second)
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// How often the shard list is refreshed to pick up resharding (default 60 seconds)
+    pub fn refresh_interval(mut self, refresh_interval: Duration) -> Self {
+        self.refresh_interval = refresh_interval;
+        self
+    }
+
+    /// How a failing handler is retried. Once exhausted, run returns the error without checkpointing past the record
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn on_shard_event<F: Fn(ShardEvent) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_shard_event = Some(Box::new(callback));
+        self
+    }
+
+    fn report(&self, event: ShardEvent) {
+        if let Some(callback) = &self.on_shard_event {
+            callback(event);
+        }
+    }
+
+    /// Open shards not yet being polled. A child shard waits until its parent has been read to the end,
+    /// so records for a key stay in order across a reshard
+    async fn refresh_shards(&self, iterators: &mut HashMap<String, Option<String>>, finished: &HashSet<String>) -> Result<(), EventfulError> {
+        let mut shards = Vec::new();
+        let mut next_token: Option<String> = None;
+        loop {
+            let request = match &next_token {
+                Some(token) => self.client.client.list_shards().next_token(token),
+                None => self.client.client.list_shards().stream_name(&self.stream_name),
+            };
+            let output = request.send().await.map_err(kinesis_err)?;
+            shards.extend(output.shards.unwrap_or_default());
+            next_token = output.next_token;
+            if next_token.is_none() {
+                break
+            }
+        }
+        let shards = shards.into_iter().filter_map(|shard| Some((shard.shard_id?, shard.parent_shard_id))).collect();
+        for shard_id in shards_to_open(shards, iterators, finished) {
+            iterators.insert(shard_id.clone(), None);
+            self.report(ShardEvent::Opened(shard_id));
+        }
+        Ok(())
+    }
+
+    async fn shard_iterator(&self, shard_id: &str) -> Result<Option<String>, EventfulError> {
+        let mut request = self.client.client.get_shard_iterator()
+            .stream_name(&self.stream_name)
+            .shard_id(shard_id);
+        request = match self.checkpoints.get(&self.stream_name, shard_id).await? {
+            Some(sequence_number) => request.shard_iterator_type(ShardIteratorType::AfterSequenceNumber).starting_sequence_number(sequence_number),
+            None => match self.start {
+                StartPosition::TrimHorizon => request.shard_iterator_type(ShardIteratorType::TrimHorizon),
+                StartPosition::Latest => request.shard_iterator_type(ShardIteratorType::Latest),
+            },
+        };
+        let output = request.send().await.map_err(kinesis_err)?;
+        Ok(output.shard_iterator)
+    }
+
+    /// Poll forever. Undecodable records are skipped (and checkpointed past)
+    pub async fn run<T, H, Fut>(&self, handler: H) -> Result<(), EventfulError>
+    where
+        T: DeserializeOwned,
+        H: Fn(T) -> Fut,
+        Fut: Future<Output = Result<(), EventfulError>>,
+    {
+        // shard id -> iterator, None until one is fetched (or after it expires)
+        let mut iterators: HashMap<String, Option<String>> = HashMap::new();
+        let mut finished: HashSet<String> = HashSet::new();
+        let mut last_refresh: Option<Instant> = None;
+        loop {
+            if last_refresh.map(|at| at.elapsed() >= self.refresh_interval).unwrap_or(true) {
+                self.refresh_shards(&mut iterators, &finished).await?;
+                last_refresh = Some(Instant::now());
+            }
+            let shard_ids: Vec<String> = iterators.keys().cloned().collect();
+            for shard_id in shard_ids {
+                let iterator = match iterators.get(&shard_id).cloned().flatten() {
+                    Some(iterator) => iterator,
+                    None => match self.shard_iterator(&shard_id).await? {
+                        Some(iterator) => iterator,
+                        None => continue,
+                    },
+                };
+                let output = match self.client.client.get_records().shard_iterator(&iterator).limit(self.limit).send().await {
+                    Ok(output) => output,
+                    Err(_) => {
+                        // usually an expired iterator or throttling; fetch a fresh iterator from the checkpoint next round
+                        iterators.insert(shard_id, None);
+                        continue
+                    },
+                };
+                for record in output.records.unwrap_or_default() {
+                    let sequence_number = record.sequence_number.unwrap_or_default();
+                    let data = record.data.map(|blob| blob.into_inner()).unwrap_or_default();
+                    if JsonCodec.decode::<T>(&data).is_ok() {
+                        let (data, handler) = (&data, &handler);
+                        // the handler takes the event by value, so each attempt decodes it again
+                        execute_with_retry(&self.retry, || async move {
+                            let event = JsonCodec.decode::<T>(data)?;
+                            handler(event).await
+                        }).await?;
+                    }
+                    self.checkpoints.set(&self.stream_name, &shard_id, &sequence_number).await?;
+                }
+                match output.next_shard_iterator {
+                    Some(next) => {
+                        iterators.insert(shard_id, Some(next));
+                    },
+                    // the shard was closed by a reshard and has been read to the end
+                    None => {
+                        iterators.remove(&shard_id);
+                        finished.insert(shard_id.clone());
+                        self.report(ShardEvent::Closed(shard_id));
+                        last_refresh = None;
+                    },
+                }
+            }
+            tokio::time::sleep(self.poll_interval).await;
+        }
+    }
+}
+
+
+/// Of the listed (shard id, parent shard id) pairs, the shards which are neither being polled nor finished,
+/// leaving out those whose parent is still listed and not yet read to the end
+fn shards_to_open(shards: Vec<(String, Option<String>)>, polling: &HashMap<String, Option<String>>, finished: &HashSet<String>) -> Vec<String> {
+    let known: HashSet<&String> = shards.iter().map(|(shard_id, _)| shard_id).collect();
+    shards.iter()
+        .filter(|(shard_id, _)| !polling.contains_key(shard_id) && !finished.contains(shard_id))
+        .filter(|(_, parent)| parent.as_ref().map(|parent| !known.contains(parent) || finished.contains(parent)).unwrap_or(true))
+        .map(|(shard_id, _)| shard_id.clone())
+        .collect()
+}
+
+
+/// aws-sdk-kinesis errors would otherwise convert into EventfulError::SQS
+fn kinesis_err<E: std::fmt::Debug>(err: E) -> EventfulError {
+    EventfulError::Kinesis(format!("{:?}", err))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(sizes: &[usize]) -> Vec<(usize, String, Bytes)> {
+        sizes.iter().enumerate().map(|(i, size)| (i, "k".to_string(), Bytes::from(vec![b'x'; *size]))).collect()
+    }
+
+    fn indexes(batches: &[Vec<(usize, String, Bytes)>]) -> Vec<Vec<usize>> {
+        batches.iter().map(|batch| batch.iter().map(|(i, _, _)| *i).collect()).collect()
+    }
+
+    #[test]
+    fn batches_split_at_the_record_limit() {
+        let batches = split_batches(records(&[10; 1001]));
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), vec![500, 500, 1]);
+        // in order, with nothing lost
+        assert_eq!(indexes(&batches).concat(), (0..1001).collect::<Vec<_>>());
+        assert!(split_batches(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn batches_split_at_the_byte_limit_counting_partition_keys() {
+        let mb = 1024 * 1024;
+        // 2 + 2 + 1 MB minus the 3 partition key bytes fills a batch exactly; the next byte starts a new one
+        let batches = split_batches(records(&[2 * mb, 2 * mb, mb - 3, 1]));
+        assert_eq!(indexes(&batches), vec![vec![0, 1, 2], vec![3]]);
+        // a record over the limit on its own still goes, alone, for Kinesis to reject
+        let batches = split_batches(records(&[10, 6 * mb, 10]));
+        assert_eq!(indexes(&batches), vec![vec![0], vec![1], vec![2]]);
+    }
+
+    fn shard(id: &str, parent: Option<&str>) -> (String, Option<String>) {
+        (id.to_string(), parent.map(str::to_string))
+    }
+
+    #[test]
+    fn child_shards_wait_for_their_parent_to_finish() {
+        let listed = || vec![shard("parent", None), shard("child-a", Some("parent")), shard("child-b", Some("parent")), shard("orphan", Some("expired"))];
+        let (mut polling, mut finished) = (HashMap::new(), HashSet::new());
+        // a parent no longer listed (past retention) doesn't hold its child back
+        assert_eq!(shards_to_open(listed(), &polling, &finished), vec!["parent", "orphan"]);
+        polling.insert("parent".to_string(), None);
+        polling.insert("orphan".to_string(), None);
+        assert!(shards_to_open(listed(), &polling, &finished).is_empty());
+        polling.remove("parent");
+        finished.insert("parent".to_string());
+        assert_eq!(shards_to_open(listed(), &polling, &finished), vec!["child-a", "child-b"]);
+    }
+
+    #[tokio::test]
+    async fn memory_checkpoints_are_per_stream_and_shard() {
+        let store = MemoryCheckpointStore::new();
+        assert_eq!(store.get("views", "shard-0").await.unwrap(), None);
+        store.set("views", "shard-0", "100").await.unwrap();
+        store.set("views", "shard-0", "200").await.unwrap();
+        store.set("clicks", "shard-0", "5").await.unwrap();
+        assert_eq!(store.get("views", "shard-0").await.unwrap().as_deref(), Some("200"));
+        assert_eq!(store.get("views", "shard-1").await.unwrap(), None);
+        assert_eq!(store.get("clicks", "shard-0").await.unwrap().as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn reports_are_ok_only_without_failures() {
+        let mut report = PutRecordsReport::default();
+        report.succeeded.push((0, RecordKinesis{shard_id: "shard-0".to_string(), sequence_number: "1".to_string()}));
+        assert!(report.is_ok());
+        report.failed.push((1, "ProvisionedThroughputExceededException".to_string()));
+        assert!(!report.is_ok());
+    }
+}
+
+
+/// Needs localstack (LOCALSTACK_ENDPOINT, default http://localhost:4566). Run with `cargo test --features kinesis -- --ignored`
+#[cfg(test)]
+mod integration {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct View {
+        n: u32,
+    }
+
+    fn client() -> Client {
+        let endpoint = std::env::var("LOCALSTACK_ENDPOINT").unwrap_or_else(|_| "http://localhost:4566".to_string());
+        let config = aws_sdk_kinesis::Config::builder()
+            .region(Region::new("us-east-1"))
+            .credentials_provider(aws_sdk_kinesis::Credentials::new("test", "test", None, None, "eventful-localstack"))
+            .endpoint_url(endpoint)
+            .build();
+        Client::from_conf(config)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs localstack"]
+    async fn a_restarted_poller_resumes_from_its_checkpoint() {
+        let client = client();
+        let stream_name = format!("eventful-test-{}", rand::random::<u32>());
+        client.create_stream().stream_name(&stream_name).shard_count(1).send().await.unwrap();
+        loop {
+            let described = client.describe_stream_summary().stream_name(&stream_name).send().await.unwrap();
+            if described.stream_description_summary.and_then(|summary| summary.stream_status).map(|status| status.as_str() == "ACTIVE").unwrap_or(false) {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        let kinesis = ClientKinesis::from_client(client.clone());
+        let records = (0..3).map(|n| ("views".to_string(), JsonCodec.encode(&View{n}).unwrap())).collect();
+        let report = kinesis.put_records_raw(&stream_name, records).await.unwrap();
+        assert!(report.is_ok());
+        let last = report.succeeded.last().unwrap().1.clone();
+
+        let checkpoints = Arc::new(MemoryCheckpointStore::new());
+        let seen = Mutex::new(Vec::new());
+        let poller = ShardPoller::new(kinesis.clone(), &stream_name, checkpoints.clone()).poll_interval(Duration::from_millis(100));
+        let handler = |view: View| {
+            seen.lock().unwrap().push(view.n);
+            async { Ok(()) }
+        };
+        let _ = tokio::time::timeout(Duration::from_secs(5), poller.run(handler)).await;
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1, 2]);
+        assert_eq!(checkpoints.get(&stream_name, &last.shard_id).await.unwrap(), Some(last.sequence_number));
+
+        // a new poller sharing the checkpoints only sees what was put since
+        kinesis.put_records_raw(&stream_name, vec![("views".to_string(), JsonCodec.encode(&View{n: 3}).unwrap())]).await.unwrap();
+        let restarted = ShardPoller::new(kinesis, &stream_name, checkpoints).poll_interval(Duration::from_millis(100));
+        let _ = tokio::time::timeout(Duration::from_secs(5), restarted.run(handler)).await;
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1, 2, 3]);
+        client.delete_stream().stream_name(&stream_name).send().await.unwrap();
+    }
+}
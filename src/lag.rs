@@ -0,0 +1,319 @@
+//! The lag module reports how many messages are waiting in each topic channel and queue, for alerting.  
+//! A LagReporter polls NSQ /stats and SQS GetQueueAttributes on an interval and hands each LagSnapshot
+//! to the metrics facade (EventfulMetrics::set_lag) and, optionally, a callback.
+//! A source which can't be reached is recorded in the snapshot's errors; the others are still reported.
+//! An NSQ channel read from only some of the daemons is still reported, listing the daemons it is missing in `unread`.
+//! 
+//! # Examples:
+//! ```no_run
+//! # #[cfg(all(feature = "nsq", feature = "sqs"))]
+//! # mod example {
+//! # use std::time::Duration;
+//! # use eventful::{lag::LagReporter, nsq::FleetNSQ, sqs::ClientSQS};
+//! # use tokio_util::sync::CancellationToken;
+//! # const ORDERS_QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/orders";
+//! # fn demo(fleet: FleetNSQ, sqs_client: ClientSQS, shutdown: CancellationToken) {
+//! let reporter = LagReporter::new(Duration::from_secs(30))
+//!     .daemons(vec![fleet.d1.clone(), fleet.d2.clone(), fleet.d3.clone()])
+//!     .nsq_channel("click", "analytics")
+//!     .sqs(sqs_client.clone())
+//!     .sqs_queue(ORDERS_QUEUE_URL)
+//!     .on_snapshot(|snapshot| {
+//!         if let Some(worst) = snapshot.worst() {
+//!             if worst.waiting > 10_000 {
+//!                 eprintln!("{} is backed up: {} waiting", worst.source, worst.waiting);
+//!             }
+//!         }
+//!     });
+//! tokio::spawn(async move { reporter.run_until(shutdown).await });
+//! # }
+//! # }
+//! ```
+
+use std::{fmt, time::Duration};
+use chrono::{DateTime, Utc};
+use tokio_util::sync::CancellationToken;
+use crate::err::EventfulError;
+use crate::metrics;
+#[cfg(feature = "nsq")]
+use crate::nsq::{self, Daemon, StatsNSQ};
+#[cfg(feature = "sqs")]
+use crate::sqs::ClientSQS;
+
+
+/// Something whose backlog can be measured
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LagSource {
+    /// A channel of an NSQ topic, summed across every daemon
+    NsqChannel { topic: String, channel: String },
+    SqsQueue { queue_url: String },
+}
+
+impl fmt::Display for LagSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LagSource::NsqChannel{topic, channel} => write!(f, "{}/{}", topic, channel),
+            LagSource::SqsQueue{queue_url} => write!(f, "{}", queue_url),
+        }
+    }
+}
+
+
+/// The backlog of one source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LagReading {
+    pub source: LagSource,
+    /// Messages waiting to be delivered (NSQ depth, SQS ApproximateNumberOfMessages)
+    pub waiting: u64,
+    /// Messages delivered but not yet finished (NSQ in_flight_count, SQS ApproximateNumberOfMessagesNotVisible)
+    pub in_flight: u64,
+    /// NSQ daemons (by pub_url) whose stats could not be read, with the error. When not empty the counts are too low
+    pub unread: Vec<(String, String)>,
+}
+
+
+/// Every reading taken in one round
+#[derive(Clone, Debug)]
+pub struct LagSnapshot {
+    pub taken_at: DateTime<Utc>,
+    pub readings: Vec<LagReading>,
+    /// Sources which could not be read this round
+    pub errors: Vec<(LagSource, String)>,
+}
+
+impl LagSnapshot {
+    /// The reading with the most messages waiting
+    pub fn worst(&self) -> Option<&LagReading> {
+        self.readings.iter().max_by_key(|reading| reading.waiting)
+    }
+
+    pub fn get(&self, source: &LagSource) -> Option<&LagReading> {
+        self.readings.iter().find(|reading| &reading.source == source)
+    }
+}
+
+
+type OnSnapshot = Box<dyn Fn(&LagSnapshot) + Send + Sync>;
+
+/// The LagReporter measures its sources every interval
+pub struct LagReporter {
+    interval: Duration,
+    #[cfg(feature = "nsq")]
+    daemons: Vec<Daemon>,
+    #[cfg(feature = "sqs")]
+    sqs: Option<ClientSQS>,
+    sources: Vec<LagSource>,
+    on_snapshot: Option<OnSnapshot>,
+    report_metrics: bool,
+}
+
+impl LagReporter {
+    pub fn new(interval: Duration) -> Self {
+        LagReporter{
+            interval,
+            #[cfg(feature = "nsq")]
+            daemons: Vec::new(),
+            #[cfg(feature = "sqs")]
+            sqs: None,
+            sources: Vec::new(),
+            on_snapshot: None,
+            report_metrics: true,
+        }
+    }
+
+    /// The daemons NSQ channel depths are summed across
+    #[cfg(feature = "nsq")]
+    pub fn daemons(mut self, daemons: Vec<Daemon>) -> Self {
+        self.daemons = daemons;
+        self
+    }
+
+    /// The client SQS queues are measured with
+    #[cfg(feature = "sqs")]
+    pub fn sqs(mut self, client: ClientSQS) -> Self {
+        self.sqs = Some(client);
+        self
+    }
+
+    pub fn nsq_channel(mut self, topic: &str, channel: &str) -> Self {
+        self.sources.push(LagSource::NsqChannel{topic: topic.to_string(), channel: channel.to_string()});
+        self
+    }
+
+    pub fn sqs_queue(mut self, queue_url: &str) -> Self {
+        self.sources.push(LagSource::SqsQueue{queue_url: queue_url.to_string()});
+        self
+    }
+
+    pub fn on_snapshot<F: Fn(&LagSnapshot) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_snapshot = Some(Box::new(callback));
+        self
+    }
+
+    /// Report each reading through the global EventfulMetrics (default true)
+    pub fn report_metrics(mut self, report_metrics: bool) -> Self {
+        self.report_metrics = report_metrics;
+        self
+    }
+
+    /// Measure every source once
+    pub async fn snapshot(&self) -> LagSnapshot {
+        #[cfg(feature = "nsq")]
+        let mut stats: Vec<(String, Result<StatsNSQ, EventfulError>)> = Vec::with_capacity(self.daemons.len());
+        #[cfg(feature = "nsq")]
+        if self.sources.iter().any(|source| matches!(source, LagSource::NsqChannel{..})) {
+            for daemon in &self.daemons {
+                stats.push((daemon.pub_url.clone(), nsq::stats(daemon).await));
+            }
+        }
+        let mut snapshot = LagSnapshot{taken_at: Utc::now(), readings: Vec::new(), errors: Vec::new()};
+        for source in &self.sources {
+            let reading = match source {
+                #[cfg(feature = "nsq")]
+                LagSource::NsqChannel{topic, channel} => nsq_reading(&stats, topic, channel),
+                #[cfg(not(feature = "nsq"))]
+                LagSource::NsqChannel{..} => Err(EventfulError::Config("measuring NSQ lag needs the nsq feature".to_string())),
+                #[cfg(feature = "sqs")]
+                LagSource::SqsQueue{queue_url} => self.sqs_reading(queue_url).await,
+                #[cfg(not(feature = "sqs"))]
+                LagSource::SqsQueue{..} => Err(EventfulError::Config("measuring SQS lag needs the sqs feature".to_string())),
+            };
+            match reading {
+                Ok((waiting, in_flight, unread)) => snapshot.readings.push(LagReading{source: source.clone(), waiting, in_flight, unread}),
+                Err(err) => snapshot.errors.push((source.clone(), err.to_string())),
+            }
+        }
+        snapshot
+    }
+
+    #[cfg(feature = "sqs")]
+    async fn sqs_reading(&self, queue_url: &str) -> Result<(u64, u64, Vec<(String, String)>), EventfulError> {
+        let client = self.sqs.as_ref().ok_or(EventfulError::Config("LagReporter has SQS queues but no SQS client".to_string()))?;
+        let stats = client.queue_stats(queue_url).await?;
+        Ok((stats.visible, stats.not_visible, Vec::new()))
+    }
+
+    /// Measure every source each interval until shutdown is cancelled
+    pub async fn run_until(&self, shutdown: CancellationToken) {
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = ticker.tick() => {},
+            }
+            let snapshot = self.snapshot().await;
+            if self.report_metrics {
+                let metrics = metrics::global();
+                for reading in &snapshot.readings {
+                    metrics.set_lag(&reading.source.to_string(), reading.waiting, reading.in_flight);
+                }
+            }
+            if let Some(callback) = &self.on_snapshot {
+                callback(&snapshot);
+            }
+        }
+    }
+}
+
+
+/// (waiting, in flight, the daemons which could not be read with why)
+#[cfg(feature = "nsq")]
+type NsqReading = (u64, u64, Vec<(String, String)>);
+
+/// Sum a channel's depth and in-flight count across daemons, listing the daemons which could not be read.
+/// Fails if no daemon could be read, or if none of those read has the channel
+#[cfg(feature = "nsq")]
+fn nsq_reading(stats: &[(String, Result<StatsNSQ, EventfulError>)], topic: &str, channel: &str) -> Result<NsqReading, EventfulError> {
+    let (mut waiting, mut in_flight, mut found) = (0u64, 0u64, false);
+    let mut unread = Vec::new();
+    for (daemon, result) in stats {
+        match result {
+            Ok(stats) => {
+                if let Some(channel) = stats.topic(topic).and_then(|topic| topic.channel(channel)) {
+                    found = true;
+                    waiting += channel.depth.max(0) as u64;
+                    in_flight += channel.in_flight_count.max(0) as u64;
+                }
+            },
+            Err(err) => unread.push((daemon.clone(), err.to_string())),
+        }
+    }
+    if unread.len() == stats.len() {
+        let reason = match unread.pop() {
+            Some((daemon, err)) => format!("no NSQ daemon could be read ({}: {})", daemon, err),
+            None => "no NSQ daemons configured".to_string(),
+        };
+        return Err(EventfulError::Http(reason))
+    }
+    if !found {
+        return Err(EventfulError::Config(format!("channel '{}/{}' does not exist on any daemon which was read", topic, channel)))
+    }
+    Ok((waiting, in_flight, unread))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worst_is_the_reading_with_the_most_waiting() {
+        let reading = |queue_url: &str, waiting| LagReading{source: LagSource::SqsQueue{queue_url: queue_url.to_string()}, waiting, in_flight: 0, unread: Vec::new()};
+        let snapshot = LagSnapshot{taken_at: Utc::now(), readings: vec![reading("a", 3), reading("b", 70), reading("c", 9)], errors: Vec::new()};
+        assert_eq!(snapshot.worst().map(|reading| reading.waiting), Some(70));
+        assert_eq!(snapshot.get(&LagSource::SqsQueue{queue_url: "c".to_string()}).map(|reading| reading.waiting), Some(9));
+        assert_eq!(LagSource::NsqChannel{topic: "click".to_string(), channel: "analytics".to_string()}.to_string(), "click/analytics");
+    }
+
+    #[cfg(feature = "nsq")]
+    mod nsq_daemons {
+        use super::*;
+        use crate::httpstub::HttpStub;
+
+        fn stats(depth: i64, in_flight_count: i64) -> String {
+            format!(r#"{{"topics": [{{"topic_name": "click", "channels": [
+                {{"channel_name": "analytics", "depth": {}, "in_flight_count": {}}},
+                {{"channel_name": "billing", "depth": 1, "in_flight_count": 0}}
+            ]}}]}}"#, depth, in_flight_count)
+        }
+
+        fn unreachable_daemon() -> Daemon {
+            let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            Daemon::new("127.0.0.1", port, 0)
+        }
+
+        #[tokio::test]
+        async fn channels_are_summed_across_the_daemons_which_could_be_read() {
+            let (first, second) = (HttpStub::start(vec![(200, stats(10, 2))]).await, HttpStub::start(vec![(200, stats(5, 1))]).await);
+            let down = unreachable_daemon();
+            let reporter = LagReporter::new(Duration::from_secs(30))
+                .daemons(vec![Daemon::new("127.0.0.1", first.port(), 0), down.clone(), Daemon::new("127.0.0.1", second.port(), 0)])
+                .nsq_channel("click", "analytics")
+                .nsq_channel("click", "search")
+                .report_metrics(false);
+            let snapshot = reporter.snapshot().await;
+
+            let analytics = snapshot.get(&LagSource::NsqChannel{topic: "click".to_string(), channel: "analytics".to_string()}).unwrap();
+            assert_eq!((analytics.waiting, analytics.in_flight), (15, 3));
+            assert_eq!(analytics.unread.len(), 1);
+            assert_eq!(analytics.unread[0].0, down.pub_url);
+            assert_eq!(snapshot.readings.len(), 1);
+            assert_eq!(snapshot.errors.len(), 1);
+            let (source, err) = &snapshot.errors[0];
+            assert_eq!(source.to_string(), "click/search");
+            assert!(err.contains("does not exist"), "{}", err);
+        }
+
+        #[tokio::test]
+        async fn a_channel_is_an_error_when_no_daemon_can_be_read() {
+            let reporter = LagReporter::new(Duration::from_secs(30))
+                .daemons(vec![unreachable_daemon(), unreachable_daemon()])
+                .nsq_channel("click", "analytics")
+                .report_metrics(false);
+            let snapshot = reporter.snapshot().await;
+            assert!(snapshot.readings.is_empty());
+            assert!(snapshot.errors[0].1.contains("no NSQ daemon could be read"), "{}", snapshot.errors[0].1);
+        }
+    }
+}
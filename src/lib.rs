@@ -1,8 +1,78 @@
 //! In a microservice architecture, it is the responsibility of microservices to emit events
 //! This module is intended to help abstract functinality associate with emitting and consuming events.
 //! Making the production and consumption of events simple across various message queues.
+//! The NSQ and SQS backends are the `nsq` and `sqs` features, both on by default. Turn off default features to build with
+//! just one of them (i.e. `default-features = false, features = ["nsq", "tracing"]`).
 //! 
 
+#[cfg(feature = "amqp")]
+pub mod amqp;
+pub mod audit;
+pub mod blocking;
+pub mod bridge;
+pub mod bus;
+pub mod circuit;
+pub mod codec;
+pub mod config;
+pub mod consumer;
+pub mod deadletter;
+pub mod delay;
 pub mod err;
+#[cfg(feature = "eventbridge")]
+pub mod eventbridge;
+pub mod fanout;
+pub mod file;
+pub mod health;
+#[cfg(all(test, any(feature = "eventbridge", feature = "nsq", feature = "sqs")))]
+mod httpstub;
+pub mod idempotency;
+pub mod interceptor;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "kinesis")]
+pub mod kinesis;
+pub mod lag;
+pub mod local;
+pub mod memory;
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod multipublish;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "nsq")]
 pub mod nsq;
+pub mod observer;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod outbox;
+#[cfg(feature = "postgres")]
+pub mod pg;
+pub mod prelude;
+pub mod publisher;
+pub mod pubstats;
+pub mod quarantine;
+pub mod ratelimit;
+pub mod recording;
+pub mod replay;
+pub mod retry;
+pub mod rng;
+pub mod shutdown;
+#[cfg(feature = "sqs")]
+pub mod sns;
+pub mod spool;
+#[cfg(feature = "sqs")]
 pub mod sqs;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+#[cfg(feature = "redis")]
+pub mod streams;
+pub mod subscriber;
+pub mod supervisor;
+pub mod tap;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod topology;
+pub mod trace;
+pub mod workers;
+mod writer;
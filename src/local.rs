@@ -0,0 +1,245 @@
+//! The local module is an in-process message bus for modular monoliths, built on `tokio::sync::broadcast`.
+//! Code written against Publisher and Subscriber can use a LocalBus today and move to NSQ (or anything else) later
+//! without changing its handlers.
+//! 
+//! Semantics differ from a broker in ways worth knowing:
+//! - Only subscriptions which exist when an event is published receive it. Events published to a topic
+//!   with no subscribers are dropped, so subscribe at startup, before anything publishes.
+//! - Every subscription receives every event on its topic, like separate NSQ channels (there is no load sharing).
+//! - Nothing is persisted, and ack and nack do nothing: a failed event is not redelivered.
+//! - Each topic buffers `capacity` events. A subscription which falls further behind than that loses the oldest events;
+//!   the loss is reported to the on_dropped callback, or else returned from next() as EventfulError::MessagesDropped.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::{consumer, local::LocalBus};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct UserSignedUp { user_id: i32 }
+//! // the handler and the publisher are the same whichever bus is behind them
+//! async fn handle_signup(event: UserSignedUp) -> Result<(), EventfulError> {
+//!     // ...
+//! #   Ok(())
+//! }
+//!
+//! # async fn demo(options: ConsumerOptions, signup: UserSignedUp) -> Result<(), EventfulError> {
+//! let bus = LocalBus::new();
+//! let subscription = bus.subscribe(&Destination::NsqTopic("signups".to_string()));
+//! let publisher: Arc<dyn Publisher> = Arc::new(bus.clone()); // later: Arc::new(Daemon::new("127.0.0.1", 4151, 4150))
+//!
+//! tokio::spawn(async move { consumer::run(Box::new(subscription), &options, handle_signup).await });
+//! publisher.publish_event(&Destination::NsqTopic("signups".to_string()), &signup).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::sync::broadcast::{self, error::RecvError};
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::subscriber::{Ack, Delivery, Subscriber};
+
+
+#[derive(Clone, Debug)]
+struct LocalMessage {
+    dest: Destination,
+    body: Bytes,
+    meta: Metadata,
+}
+
+type OnDropped = Arc<dyn Fn(&Destination, u64) + Send + Sync>;
+
+
+/// The LocalBus delivers events between tasks in one process. Clones share the same topics
+#[derive(Clone)]
+pub struct LocalBus {
+    topics: Arc<Mutex<HashMap<Destination, broadcast::Sender<LocalMessage>>>>,
+    capacity: usize,
+    on_dropped: Option<OnDropped>,
+}
+
+impl Default for LocalBus {
+    fn default() -> Self {
+        LocalBus{topics: Arc::new(Mutex::new(HashMap::new())), capacity: 1024, on_dropped: None}
+    }
+}
+
+impl LocalBus {
+    pub fn new() -> Self {
+        LocalBus::default()
+    }
+
+    /// How many events each topic buffers for its slowest subscriber (default 1024).
+    /// Only affects topics created after it is set
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Called with the topic and the number of events lost whenever a subscription falls too far behind.
+    /// Without it, next() returns EventfulError::MessagesDropped instead
+    pub fn on_dropped<F: Fn(&Destination, u64) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_dropped = Some(Arc::new(callback));
+        self
+    }
+
+    fn sender(&self, dest: &Destination) -> broadcast::Sender<LocalMessage> {
+        let mut topics = self.topics.lock().unwrap();
+        topics.entry(dest.clone()).or_insert_with(|| broadcast::channel(self.capacity).0).clone()
+    }
+
+    /// Subscribe to every event published to a destination from now on
+    pub fn subscribe(&self, dest: &Destination) -> LocalSubscription {
+        LocalSubscription{dest: dest.clone(), receiver: self.sender(dest).subscribe(), on_dropped: self.on_dropped.clone()}
+    }
+
+    /// How many subscriptions a destination currently has
+    pub fn subscriber_count(&self, dest: &Destination) -> usize {
+        self.topics.lock().unwrap().get(dest).map(|sender| sender.receiver_count()).unwrap_or(0)
+    }
+}
+
+#[async_trait]
+impl Publisher for LocalBus {
+    /// Any destination can be published to. With no subscribers the event is dropped
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let message = LocalMessage{dest: dest.clone(), body, meta: meta.clone()};
+        // send only fails when there are no subscribers
+        let _ = self.sender(dest).send(message);
+        Ok(Receipt::default())
+    }
+}
+
+
+/// A LocalSubscription receives events from one destination of a LocalBus
+pub struct LocalSubscription {
+    dest: Destination,
+    receiver: broadcast::Receiver<LocalMessage>,
+    on_dropped: Option<OnDropped>,
+}
+
+#[async_trait]
+impl Subscriber for LocalSubscription {
+    /// Returns None once every LocalBus clone has been dropped
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(message) => return Ok(Some(Delivery::new(message.dest, message.body, message.meta, None, 1, Box::new(AckLocal)))),
+                Err(RecvError::Closed) => return Ok(None),
+                Err(RecvError::Lagged(count)) => match &self.on_dropped {
+                    Some(callback) => callback(&self.dest, count),
+                    None => return Err(EventfulError::MessagesDropped{topic: self.dest.to_string(), count}),
+                },
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("local {}", self.dest)
+    }
+}
+
+
+/// There is nothing to acknowledge in-process, so ack and nack do nothing
+struct AckLocal;
+
+#[async_trait]
+impl Ack for AckLocal {
+    async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+        Ok(())
+    }
+
+    async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use crate::consumer::{self, ConsumerOptions};
+    use crate::publisher::PublisherExt;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct UserSignedUp {
+        user_id: u64,
+    }
+
+    /// Written against Publisher, as a service would be, so the bus behind it can change
+    struct SignupService {
+        publisher: Arc<dyn Publisher>,
+    }
+
+    impl SignupService {
+        async fn sign_up(&self, user_id: u64) -> Result<(), EventfulError> {
+            self.publisher.publish_event(&signups(), &UserSignedUp{user_id}).await?;
+            Ok(())
+        }
+    }
+
+    fn signups() -> Destination {
+        Destination::NsqTopic("signups".to_string())
+    }
+
+    #[cfg(feature = "nsq")]
+    #[test]
+    fn the_service_takes_an_nsq_daemon_as_well() {
+        let _production = SignupService{publisher: Arc::new(crate::nsq::Daemon::new("127.0.0.1", 4151, 4150))};
+    }
+
+    #[tokio::test]
+    async fn a_local_bus_stands_in_for_nsq() {
+        let bus = LocalBus::new();
+        let subscription = bus.subscribe(&signups());
+        let service = SignupService{publisher: Arc::new(bus.clone())};
+        for user_id in 1..=3 {
+            service.sign_up(user_id).await.unwrap();
+        }
+        // once every clone of the bus is gone the subscription ends, and so does the consumer
+        drop((service, bus));
+
+        let handled = Mutex::new(Vec::new());
+        consumer::run(Box::new(subscription), &ConsumerOptions::default(), |event: UserSignedUp| {
+            handled.lock().unwrap().push(event.user_id);
+            async { Ok(()) }
+        }).await.unwrap();
+        assert_eq!(*handled.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn only_current_subscribers_receive_events() {
+        let bus = LocalBus::new();
+        bus.publish_bytes(&signups(), Bytes::from("nobody"), &Metadata::default()).await.unwrap();
+        let (mut first, mut second) = (bus.subscribe(&signups()), bus.subscribe(&signups()));
+        assert_eq!(bus.subscriber_count(&signups()), 2);
+        bus.publish_bytes(&signups(), Bytes::from("both"), &Metadata::default()).await.unwrap();
+        assert_eq!(first.next().await.unwrap().unwrap().body, Bytes::from("both"));
+        assert_eq!(second.next().await.unwrap().unwrap().body, Bytes::from("both"));
+    }
+
+    #[tokio::test]
+    async fn a_subscription_which_falls_behind_reports_what_it_lost() {
+        let bus = LocalBus::new().capacity(2);
+        let mut strict = bus.subscribe(&signups());
+        for n in 0..5 {
+            bus.publish_bytes(&signups(), Bytes::from(n.to_string()), &Metadata::default()).await.unwrap();
+        }
+        assert!(matches!(strict.next().await, Err(EventfulError::MessagesDropped{count: 3, ..})));
+        assert_eq!(strict.next().await.unwrap().unwrap().body, Bytes::from("3"));
+
+        let lost = Arc::new(Mutex::new(0));
+        let counted = lost.clone();
+        let bus = LocalBus::new().capacity(2).on_dropped(move |_, count| *counted.lock().unwrap() += count);
+        let mut lenient = bus.subscribe(&signups());
+        for n in 0..5 {
+            bus.publish_bytes(&signups(), Bytes::from(n.to_string()), &Metadata::default()).await.unwrap();
+        }
+        assert_eq!(lenient.next().await.unwrap().unwrap().body, Bytes::from("3"));
+        assert_eq!(*lost.lock().unwrap(), 3);
+    }
+}
@@ -0,0 +1,373 @@
+//! The memory module provides a MemoryBroker: an in-process message bus implementing Publisher and Subscriber,
+//! so unit tests don't need a real nsqd or SQS.  
+//! Every Destination behaves like an NSQ topic: each named channel receives its own copy of every message,
+//! and subscribers sharing a channel share its messages. Messages published before any channel exists
+//! are held and handed to the first channel, as nsqd does. An SQS queue is simply a destination with one channel.
+//! 
+//! # Examples:
+//! ```
+//! # use eventful::prelude::*;
+//! # use eventful::memory::MemoryBroker;
+//! # use eventful::subscriber::Subscriber;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Click { user_id: u64 }
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> Result<(), EventfulError> {
+//! # let click = Click{user_id: 5};
+//! let broker = MemoryBroker::new();
+//! let mut clicks = broker.subscribe(&Destination::NsqTopic("click".to_string()), "analytics");
+//! broker.publish_event(&Destination::NsqTopic("click".to_string()), &click).await?;
+//! assert_eq!(broker.published_to("click").len(), 1);
+//! let delivery = clicks.next().await?.unwrap();
+//! delivery.ack().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use crate::codec::{Codec, JsonCodec};
+use crate::err::EventfulError;
+#[cfg(feature = "nsq")]
+use crate::nsq::{ChannelConsumer, EventNSQ};
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::subscriber::{Ack, Delivery, Subscriber};
+
+
+/// Options for simulating a real broker
+#[derive(Clone, Debug, Default)]
+pub struct MemoryConfig {
+    /// Every publish waits this long before completing
+    pub latency: Duration,
+    /// The fraction (0.0 to 1.0) of publishes which report success but are silently discarded
+    pub drop_rate: f64,
+    /// When set, a delivery which is neither acked nor nacked within this long is redelivered, as with SQS
+    pub visibility_timeout: Option<Duration>,
+}
+
+
+#[derive(Clone)]
+struct MemoryMessage {
+    id: String,
+    body: Bytes,
+    meta: Metadata,
+    attempt: u32,
+}
+
+type Receiver = Arc<AsyncMutex<mpsc::UnboundedReceiver<MemoryMessage>>>;
+
+struct Channel {
+    sender: mpsc::UnboundedSender<MemoryMessage>,
+    receiver: Receiver,
+}
+
+#[derive(Default)]
+struct Topic {
+    channels: HashMap<String, Channel>,
+    /// messages published before any channel existed
+    backlog: Vec<MemoryMessage>,
+}
+
+#[derive(Default)]
+struct State {
+    topics: HashMap<Destination, Topic>,
+    published: Vec<(Destination, Bytes)>,
+    fail_next: u32,
+}
+
+
+/// The MemoryBroker is cheap to clone; clones share the same topics
+#[derive(Clone, Default)]
+pub struct MemoryBroker {
+    state: Arc<Mutex<State>>,
+    config: Arc<MemoryConfig>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl MemoryBroker {
+    pub fn new() -> Self {
+        MemoryBroker::default()
+    }
+
+    pub fn with_config(config: MemoryConfig) -> Self {
+        MemoryBroker{config: Arc::new(config), ..Default::default()}
+    }
+
+    /// Make the next publish fail with an error. Calling this n times fails the next n publishes
+    pub fn fail_next_publish(&self) {
+        self.state.lock().unwrap().fail_next += 1;
+    }
+
+    /// Every body successfully published to a destination whose topic or queue is named `name`, oldest first.
+    /// Bodies discarded by the drop_rate are included, since the publisher believed they were sent
+    pub fn published_to(&self, name: &str) -> Vec<Bytes> {
+        self.state.lock().unwrap().published.iter()
+            .filter(|(dest, _)| dest.name() == name)
+            .map(|(_, body)| body.clone())
+            .collect()
+    }
+
+    /// Every body successfully published, with its destination, oldest first
+    pub fn published(&self) -> Vec<(Destination, Bytes)> {
+        self.state.lock().unwrap().published.clone()
+    }
+
+    /// Decode every body published to `name` into T
+    pub fn published_events<T: DeserializeOwned>(&self, name: &str) -> Result<Vec<T>, EventfulError> {
+        self.published_to(name).iter().map(|body| JsonCodec.decode(body)).collect()
+    }
+
+    /// Subscribe to a channel of a destination
+    pub fn subscribe(&self, dest: &Destination, channel: &str) -> MemorySubscription {
+        let mut state = self.state.lock().unwrap();
+        let topic = state.topics.entry(dest.clone()).or_default();
+        let is_first = topic.channels.is_empty();
+        let channel = topic.channels.entry(channel.to_string()).or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            Channel{sender, receiver: Arc::new(AsyncMutex::new(receiver))}
+        });
+        if is_first {
+            for message in topic.backlog.drain(..) {
+                let _ = channel.sender.send(message);
+            }
+        }
+        MemorySubscription{
+            source: dest.clone(),
+            sender: channel.sender.clone(),
+            receiver: channel.receiver.clone(),
+            visibility_timeout: self.config.visibility_timeout,
+        }
+    }
+
+    /// Publish an EventNSQ to its topic, as EventNSQ::publish_to would
+    #[cfg(feature = "nsq")]
+    pub async fn publish_nsq<T: EventNSQ + Sync>(&self, event: &T) -> Result<(), EventfulError> {
+        let dest = Destination::NsqTopic(<T as EventNSQ>::topic().to_string());
+        self.publish_bytes(&dest, JsonCodec.encode(event)?, &Metadata::default()).await?;
+        Ok(())
+    }
+
+    /// Subscribe to the topic and channel a ChannelConsumer would consume from
+    #[cfg(feature = "nsq")]
+    pub fn subscribe_nsq<T: EventNSQ, C: ChannelConsumer<T>>(&self, consumer: &C) -> MemorySubscription {
+        let dest = Destination::NsqTopic(<T as EventNSQ>::topic().to_string());
+        self.subscribe(&dest, &consumer.channel())
+    }
+}
+
+
+#[async_trait]
+impl Publisher for MemoryBroker {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        if !self.config.latency.is_zero() {
+            tokio::time::sleep(self.config.latency).await;
+        }
+        let dropped = self.config.drop_rate > 0.0 && rand::thread_rng().gen::<f64>() < self.config.drop_rate;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let mut state = self.state.lock().unwrap();
+        if state.fail_next > 0 {
+            state.fail_next -= 1;
+            return Err(EventfulError::Http(format!("MemoryBroker: injected failure publishing to {}", dest)))
+        }
+        state.published.push((dest.clone(), body.clone()));
+        if !dropped {
+            let message = MemoryMessage{id: id.clone(), body, meta: meta.clone(), attempt: 1};
+            let topic = state.topics.entry(dest.clone()).or_default();
+            match topic.channels.is_empty() {
+                true => topic.backlog.push(message),
+                false => for channel in topic.channels.values() {
+                    let _ = channel.sender.send(message.clone());
+                },
+            }
+        }
+        Ok(Receipt{message_id: Some(id)})
+    }
+}
+
+
+/// A MemorySubscription yields the messages of one channel
+pub struct MemorySubscription {
+    source: Destination,
+    sender: mpsc::UnboundedSender<MemoryMessage>,
+    receiver: Receiver,
+    visibility_timeout: Option<Duration>,
+}
+
+#[async_trait]
+impl Subscriber for MemorySubscription {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        let message = match self.receiver.lock().await.recv().await {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let settled = Arc::new(AtomicBool::new(false));
+        if let Some(timeout) = self.visibility_timeout {
+            let (settled, sender, message) = (settled.clone(), self.sender.clone(), message.clone());
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                if !settled.swap(true, Ordering::SeqCst) {
+                    let _ = sender.send(MemoryMessage{attempt: message.attempt + 1, ..message});
+                }
+            });
+        }
+        let ack = AckMemory{sender: self.sender.clone(), message: message.clone(), settled};
+        Ok(Some(Delivery::new(self.source.clone(), message.body, message.meta, Some(message.id), message.attempt, Box::new(ack))))
+    }
+
+    fn describe(&self) -> String {
+        format!("memory {}", self.source)
+    }
+}
+
+
+/// ack() discards the message, nack(delay) redelivers it to the same channel after the delay.
+/// Settling after the visibility timeout has already redelivered the message does nothing, like a stale SQS receipt handle
+struct AckMemory {
+    sender: mpsc::UnboundedSender<MemoryMessage>,
+    message: MemoryMessage,
+    settled: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl Ack for AckMemory {
+    async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+        self.settled.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn nack(self: Box<Self>, delay: Duration) -> Result<(), EventfulError> {
+        if self.settled.swap(true, Ordering::SeqCst) {
+            return Ok(())
+        }
+        let message = MemoryMessage{attempt: self.message.attempt + 1, ..self.message};
+        let sender = self.sender;
+        match delay.is_zero() {
+            true => {
+                let _ = sender.send(message);
+            },
+            false => {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = sender.send(message);
+                });
+            },
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use crate::publisher::PublisherExt;
+
+    fn click() -> Destination {
+        Destination::NsqTopic("click".to_string())
+    }
+
+    async fn publish(broker: &MemoryBroker, body: &'static str) {
+        broker.publish_bytes(&click(), Bytes::from(body), &Metadata::default()).await.unwrap();
+    }
+
+    async fn next(subscription: &mut MemorySubscription) -> Delivery {
+        subscription.next().await.unwrap().unwrap()
+    }
+
+    /// The next delivery's body, acked so it isn't redelivered
+    async fn acked(subscription: &mut MemorySubscription) -> Bytes {
+        let delivery = next(subscription).await;
+        let body = delivery.body.clone();
+        delivery.ack().await.unwrap();
+        body
+    }
+
+    /// Nothing is delivered within `within`
+    async fn quiet(subscription: &mut MemorySubscription, within: Duration) -> bool {
+        tokio::time::timeout(within, subscription.next()).await.is_err()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn each_channel_gets_a_copy_and_a_shared_channel_splits_them() {
+        let broker = MemoryBroker::new();
+        let (mut first, mut second) = (broker.subscribe(&click(), "analytics"), broker.subscribe(&click(), "analytics"));
+        let mut billing = broker.subscribe(&click(), "billing");
+        publish(&broker, "a").await;
+        publish(&broker, "b").await;
+
+        assert_eq!(acked(&mut first).await, Bytes::from("a"));
+        assert_eq!(acked(&mut second).await, Bytes::from("b"));
+        assert!(quiet(&mut first, Duration::from_secs(1)).await);
+        assert_eq!(acked(&mut billing).await, Bytes::from("a"));
+        assert_eq!(acked(&mut billing).await, Bytes::from("b"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_published_before_any_channel_go_to_the_first() {
+        let broker = MemoryBroker::new();
+        publish(&broker, "early").await;
+        let mut analytics = broker.subscribe(&click(), "analytics");
+        let mut billing = broker.subscribe(&click(), "billing");
+        assert_eq!(next(&mut analytics).await.body, Bytes::from("early"));
+        assert!(quiet(&mut billing, Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_nacked_message_comes_back_after_the_delay_with_its_attempt_counted() {
+        let broker = MemoryBroker::new();
+        let mut analytics = broker.subscribe(&click(), "analytics");
+        publish(&broker, "a").await;
+        let delivery = next(&mut analytics).await;
+        assert_eq!(delivery.attempt, 1);
+        delivery.nack(Duration::from_secs(5)).await.unwrap();
+
+        assert!(quiet(&mut analytics, Duration::from_secs(4)).await);
+        let again = next(&mut analytics).await;
+        assert_eq!((again.body.clone(), again.attempt), (Bytes::from("a"), 2));
+        again.nack(Duration::ZERO).await.unwrap();
+        assert_eq!(next(&mut analytics).await.attempt, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_unsettled_message_is_redelivered_after_the_visibility_timeout() {
+        let broker = MemoryBroker::with_config(MemoryConfig{visibility_timeout: Some(Duration::from_secs(30)), ..Default::default()});
+        let mut analytics = broker.subscribe(&click(), "analytics");
+        publish(&broker, "slow").await;
+        publish(&broker, "fast").await;
+        let slow = next(&mut analytics).await;
+        next(&mut analytics).await.ack().await.unwrap();
+
+        assert!(quiet(&mut analytics, Duration::from_secs(29)).await);
+        let redelivered = next(&mut analytics).await;
+        assert_eq!((redelivered.body.clone(), redelivered.attempt), (Bytes::from("slow"), 2));
+        // like a stale receipt handle, settling the first delivery now does nothing
+        slow.nack(Duration::ZERO).await.unwrap();
+        redelivered.ack().await.unwrap();
+        assert!(quiet(&mut analytics, Duration::from_secs(60)).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn injected_failures_and_drops() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Click {
+            x: u32,
+        }
+        let broker = MemoryBroker::with_config(MemoryConfig{drop_rate: 1.0, latency: Duration::from_millis(50), ..Default::default()});
+        let mut analytics = broker.subscribe(&click(), "analytics");
+        broker.fail_next_publish();
+        let started = tokio::time::Instant::now();
+        assert!(matches!(broker.publish_event(&click(), &Click{x: 1}).await, Err(EventfulError::Http(_))));
+        broker.publish_event(&click(), &Click{x: 2}).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_millis(100));
+
+        // the dropped publish looked successful, so it is listed but never delivered
+        assert_eq!(broker.published_events::<Click>("click").unwrap(), vec![Click{x: 2}]);
+        assert_eq!(broker.published().len(), 1);
+        assert!(quiet(&mut analytics, Duration::from_secs(1)).await);
+    }
+}
@@ -0,0 +1,187 @@
+//! The metrics module is a small facade for publish and consume metrics.  
+//! eventful reports through the global EventfulMetrics (a no-op unless configured): NSQ and SQS publishes,
+//! the consumer run loops, and the SQS poller all emit through it.
+//! Labels are limited to the topic or queue name, so cardinality stays bounded by your topology.
+//! 
+//! - With the `metrics` feature, the default global forwards to the [metrics](https://docs.rs/metrics) crate macros,
+//!   so whichever recorder your service installs receives eventful's counters.
+//! - With the `prometheus` feature, install_prometheus() installs a ready-made recorder and render()
+//!   returns the Prometheus exposition format, to be served from your existing HTTP server.
+//! - With the `statsd` feature, statsd::StatsdMetrics pushes DogStatsD lines over UDP instead.
+//! 
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "prometheus")]
+//! # mod example {
+//! # fn demo() -> Result<(), eventful::err::EventfulError> {
+//! eventful::metrics::install_prometheus()?;
+//! // in your /metrics handler:
+//! let body = eventful::metrics::render();
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{sync::{Arc, RwLock}, time::Duration};
+#[cfg(any(feature = "nsq", feature = "sqs"))]
+use std::{future::Future, time::Instant};
+#[cfg(any(feature = "nsq", feature = "sqs", feature = "prometheus"))]
+use crate::err::EventfulError;
+
+
+/// Receives eventful's metrics. Every method defaults to doing nothing, so implement only what you need
+pub trait EventfulMetrics: Send + Sync {
+    /// An event was published to topic
+    fn inc_published(&self, _topic: &str) {}
+    /// How long a publish to topic took (whether or not it succeeded)
+    fn observe_publish_latency(&self, _topic: &str, _duration: Duration) {}
+    /// One attempt to publish to topic took this long, whether or not it succeeded (reported by PublisherStats, see the pubstats module).
+    /// Unlike observe_publish_latency, a publish which is retried is reported once per attempt
+    fn observe_publish_attempt(&self, _topic: &str, _duration: Duration) {}
+    /// The body of a successful publish to topic was this many bytes (reported by PublisherStats, see the pubstats module)
+    fn add_published_bytes(&self, _topic: &str, _bytes: u64) {}
+    /// An event from topic was handled successfully
+    fn inc_consumed(&self, _topic: &str) {}
+    /// A publish to, or an event from, topic failed
+    fn inc_failed(&self, _topic: &str) {}
+    /// An event from topic was sent to a dead-letter sink
+    fn inc_dead_lettered(&self, _topic: &str) {}
+    /// A message from topic was received but kept from the handler by a receive filter
+    fn inc_filtered(&self, _topic: &str) {}
+    /// How many events from topic are being handled right now
+    fn set_inflight(&self, _topic: &str, _count: usize) {}
+    /// How many messages are waiting in, and in flight from, a topic channel or queue (see the lag module)
+    fn set_lag(&self, _source: &str, _waiting: u64, _in_flight: u64) {}
+    /// A publish to topic was delayed or rejected by a RateLimitedPublisher
+    fn inc_throttled(&self, _topic: &str) {}
+    /// How many events from topic are queued between intake and the handlers of a sharded consumer
+    fn set_intake_depth(&self, _topic: &str, _depth: usize) {}
+    /// A sharded consumer of topic stopped receiving for this long because its queue was full
+    fn add_backpressure_time(&self, _topic: &str, _waited: Duration) {}
+}
+
+
+/// Discards everything
+pub struct NoopMetrics;
+
+impl EventfulMetrics for NoopMetrics {}
+
+
+/// Forwards to the metrics crate: eventful_published_total, eventful_publish_latency_seconds, eventful_publish_attempt_seconds, eventful_published_bytes_total, eventful_consumed_total,
+/// eventful_failed_total, eventful_dead_lettered_total, eventful_filtered_total, eventful_throttled_total, eventful_backpressure_seconds, and the gauges
+/// eventful_inflight, eventful_intake_depth, eventful_lag_waiting, and eventful_lag_in_flight, each labelled with topic
+#[cfg(feature = "metrics")]
+pub struct MetricsCrate;
+
+#[cfg(feature = "metrics")]
+impl EventfulMetrics for MetricsCrate {
+    fn inc_published(&self, topic: &str) {
+        ::metrics::increment_counter!("eventful_published_total", "topic" => topic.to_string());
+    }
+
+    fn observe_publish_latency(&self, topic: &str, duration: Duration) {
+        ::metrics::histogram!("eventful_publish_latency_seconds", duration.as_secs_f64(), "topic" => topic.to_string());
+    }
+
+    fn observe_publish_attempt(&self, topic: &str, duration: Duration) {
+        ::metrics::histogram!("eventful_publish_attempt_seconds", duration.as_secs_f64(), "topic" => topic.to_string());
+    }
+
+    fn add_published_bytes(&self, topic: &str, bytes: u64) {
+        ::metrics::counter!("eventful_published_bytes_total", bytes, "topic" => topic.to_string());
+    }
+
+    fn inc_consumed(&self, topic: &str) {
+        ::metrics::increment_counter!("eventful_consumed_total", "topic" => topic.to_string());
+    }
+
+    fn inc_failed(&self, topic: &str) {
+        ::metrics::increment_counter!("eventful_failed_total", "topic" => topic.to_string());
+    }
+
+    fn inc_dead_lettered(&self, topic: &str) {
+        ::metrics::increment_counter!("eventful_dead_lettered_total", "topic" => topic.to_string());
+    }
+
+    fn inc_filtered(&self, topic: &str) {
+        ::metrics::increment_counter!("eventful_filtered_total", "topic" => topic.to_string());
+    }
+
+    fn set_inflight(&self, topic: &str, count: usize) {
+        ::metrics::gauge!("eventful_inflight", count as f64, "topic" => topic.to_string());
+    }
+
+    fn set_lag(&self, source: &str, waiting: u64, in_flight: u64) {
+        ::metrics::gauge!("eventful_lag_waiting", waiting as f64, "topic" => source.to_string());
+        ::metrics::gauge!("eventful_lag_in_flight", in_flight as f64, "topic" => source.to_string());
+    }
+
+    fn inc_throttled(&self, topic: &str) {
+        ::metrics::increment_counter!("eventful_throttled_total", "topic" => topic.to_string());
+    }
+
+    fn set_intake_depth(&self, topic: &str, depth: usize) {
+        ::metrics::gauge!("eventful_intake_depth", depth as f64, "topic" => topic.to_string());
+    }
+
+    fn add_backpressure_time(&self, topic: &str, waited: Duration) {
+        ::metrics::histogram!("eventful_backpressure_seconds", waited.as_secs_f64(), "topic" => topic.to_string());
+    }
+}
+
+
+static GLOBAL: RwLock<Option<Arc<dyn EventfulMetrics>>> = RwLock::new(None);
+
+/// Replace the global EventfulMetrics, i.e. with your own implementation
+pub fn set_global(metrics: Arc<dyn EventfulMetrics>) {
+    *GLOBAL.write().unwrap() = Some(metrics);
+}
+
+/// The global EventfulMetrics: whatever was set with set_global, else MetricsCrate with the `metrics` feature, else NoopMetrics
+pub fn global() -> Arc<dyn EventfulMetrics> {
+    if let Some(metrics) = GLOBAL.read().unwrap().as_ref() {
+        return metrics.clone()
+    }
+    #[cfg(feature = "metrics")]
+    return Arc::new(MetricsCrate);
+    #[cfg(not(feature = "metrics"))]
+    return Arc::new(NoopMetrics);
+}
+
+
+/// Time a publish to topic, counting it as published or failed
+#[cfg(any(feature = "nsq", feature = "sqs"))]
+pub(crate) async fn time_publish<T, F: Future<Output = Result<T, EventfulError>>>(topic: &str, publish: F) -> Result<T, EventfulError> {
+    let metrics = global();
+    let started = Instant::now();
+    let result = publish.await;
+    metrics.observe_publish_latency(topic, started.elapsed());
+    match &result {
+        Ok(_) => metrics.inc_published(topic),
+        Err(_) => metrics.inc_failed(topic),
+    }
+    result
+}
+
+
+#[cfg(feature = "prometheus")]
+static PROMETHEUS: std::sync::OnceLock<metrics_exporter_prometheus::PrometheusHandle> = std::sync::OnceLock::new();
+
+/// Install a Prometheus recorder as the metrics crate's global recorder. Calling it again does nothing
+#[cfg(feature = "prometheus")]
+pub fn install_prometheus() -> Result<(), EventfulError> {
+    if PROMETHEUS.get().is_some() {
+        return Ok(())
+    }
+    let handle = metrics_exporter_prometheus::PrometheusBuilder::new()
+        .install_recorder()
+        .map_err(|err| EventfulError::Config(format!("could not install the Prometheus recorder: {}", err)))?;
+    let _ = PROMETHEUS.set(handle);
+    Ok(())
+}
+
+/// Every metric in the Prometheus exposition format, or an empty string if install_prometheus has not been called
+#[cfg(feature = "prometheus")]
+pub fn render() -> String {
+    PROMETHEUS.get().map(|handle| handle.render()).unwrap_or_default()
+}
@@ -0,0 +1,355 @@
+//! The mqtt module produces and consumes events over [MQTT](https://mqtt.org/) (3.1.1), i.e. telemetry from a device fleet.
+//! It is enabled by the `mqtt` feature.
+//! 
+//! MQTT has no nack, so delivery maps onto the handler loop like this:
+//! - QoS 1 subscriptions use manual acks. The PUBACK is only sent once the handler succeeds.
+//!   A failed message is left unacknowledged; the broker redelivers it when the session resumes after a reconnect
+//!   (which requires clean_session to be false, as it is for options from mqtt_options).
+//! - QoS 0 messages are fire-and-forget: ack and nack do nothing, and a failed message is lost.
+//! 
+//! The event loop reconnects automatically after a broker disconnect, and every subscription is renewed on reconnect.
+//! Publishing queues the message for the event loop; QoS 1 messages are retried by the client until the broker acks them.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use eventful::prelude::*;
+//! # use eventful::mqtt::{EventMqtt, LastWill, QoS, mqtt_options};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Telemetry { device_id: String }
+//! impl EventMqtt for Telemetry {
+//!     fn topic(&self) -> String {
+//!         format!("devices/{}/telemetry", self.device_id)
+//!     }
+//!     fn topic_filter() -> String {
+//!         "devices/+/telemetry".to_string()
+//!     }
+//! }
+//!
+//! let mut options = mqtt_options("telemetry-ingest", "127.0.0.1", 1883);
+//! options.set_last_will(LastWill::new("services/telemetry-ingest/status", "offline", QoS::AtLeastOnce, true));
+//! ```
+
+use std::{future::Future, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+pub use rumqttc::{LastWill, MqttOptions, QoS};
+use rumqttc::{AsyncClient, ClientError, ConnectionError, Event, EventLoop, Packet, Publish};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::codec::{Codec, JsonCodec};
+use crate::consumer::{self, ConsumerOptions};
+use crate::err::EventfulError;
+use crate::idempotency::IdempotencyGuard;
+use crate::observer::{self, ConsumerReconnected};
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+use crate::subscriber::{Ack, Delivery, Subscriber};
+
+
+/// Implement EventMqtt on a struct to publish and subscribe to it
+pub trait EventMqtt: Serialize + DeserializeOwned {
+    /// The topic this event is published to, i.e. "devices/42/telemetry"
+    fn topic(&self) -> String;
+
+    /// The filter consumers subscribe with, which may contain + and # wildcards, i.e. "devices/+/telemetry"
+    fn topic_filter() -> String;
+
+    /// QoS 0 (at most once) or 1 (at least once, the default)
+    fn qos() -> QoS {
+        QoS::AtLeastOnce
+    }
+
+    /// Should the broker retain this as the topic's last message for new subscribers? (default false)
+    fn retain(&self) -> bool {
+        false
+    }
+}
+
+
+/// MqttOptions with a 30 second keep alive and a persistent session (clean_session false), so QoS 1 messages
+/// which weren't acked are redelivered after a reconnect. client_id must be unique per connection
+pub fn mqtt_options(client_id: &str, host: &str, port: u16) -> MqttOptions {
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_clean_session(false);
+    options
+}
+
+
+/// The PublisherMqtt owns a connection, and polls its event loop on a background task
+#[derive(Clone)]
+pub struct PublisherMqtt {
+    client: AsyncClient,
+}
+
+impl PublisherMqtt {
+    /// Connect with options (see mqtt_options), which is where a last will is configured
+    pub fn connect(options: MqttOptions) -> Self {
+        let (client, mut eventloop) = AsyncClient::new(options, 100);
+        tokio::spawn(async move {
+            loop {
+                // errors are followed by a reconnect on the next poll, so just back off briefly
+                if eventloop.poll().await.is_err() {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        });
+        PublisherMqtt{client}
+    }
+
+    pub fn client(&self) -> &AsyncClient {
+        &self.client
+    }
+
+    /// Publish raw bytes to a topic
+    pub async fn publish_raw(&self, topic: &str, qos: QoS, retain: bool, body: &[u8]) -> Result<(), EventfulError> {
+        self.client.publish(topic, qos, retain, body.to_vec()).await?;
+        Ok(())
+    }
+
+    /// Serialize an event and publish it to its topic with its QoS and retain flag
+    pub async fn publish<T: EventMqtt>(&self, event: &T) -> Result<(), EventfulError> {
+        let body = JsonCodec.encode(event)?;
+        self.publish_raw(&event.topic(), <T as EventMqtt>::qos(), event.retain(), &body).await
+    }
+
+    /// Clear a topic's retained message
+    pub async fn clear_retained(&self, topic: &str) -> Result<(), EventfulError> {
+        self.publish_raw(topic, QoS::AtLeastOnce, true, &[]).await
+    }
+}
+
+#[async_trait]
+impl Publisher for PublisherMqtt {
+    /// Publishes with QoS 1. The header "retain" set to "true" publishes a retained message
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let topic = match dest {
+            Destination::MqttTopic(topic) => topic,
+            _ => return Err(publisher::unsupported("PublisherMqtt", dest)),
+        };
+        let retain = meta.headers.get("retain").map(|val| val == "true").unwrap_or(false);
+        self.publish_raw(topic, QoS::AtLeastOnce, retain, &body).await?;
+        Ok(Receipt::default())
+    }
+}
+
+
+/// A SubscriptionMqtt owns a connection with manual acks and implements the Subscriber trait
+pub struct SubscriptionMqtt {
+    client: AsyncClient,
+    eventloop: EventLoop,
+    filters: Vec<(String, QoS)>,
+    connected_once: bool,
+    failed_attempts: u32,
+}
+
+impl SubscriptionMqtt {
+    /// Connect and subscribe to topic filters. Subscriptions are renewed whenever the connection is re-established
+    pub fn connect(mut options: MqttOptions, filters: Vec<(String, QoS)>) -> Self {
+        options.set_manual_acks(true);
+        let (client, eventloop) = AsyncClient::new(options, 100);
+        SubscriptionMqtt{client, eventloop, filters, connected_once: false, failed_attempts: 0}
+    }
+
+    async fn resubscribe(&self) -> Result<(), EventfulError> {
+        for (filter, qos) in &self.filters {
+            self.client.subscribe(filter, *qos).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Subscriber for SubscriptionMqtt {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        loop {
+            match self.eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    if self.connected_once {
+                        // the first error was the connection dropping, not a failed attempt
+                        let failed_attempts = self.failed_attempts.saturating_sub(1);
+                        observer::global().consumer_reconnected(&ConsumerReconnected{source: self.describe(), failed_attempts});
+                    }
+                    self.connected_once = true;
+                    self.failed_attempts = 0;
+                    self.resubscribe().await?
+                },
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let ack = AckMqtt{client: self.client.clone(), publish: publish.clone()};
+                    return Ok(Some(delivery(publish, Box::new(ack))))
+                },
+                Ok(_) => {},
+                // the next poll reconnects
+                Err(_) => {
+                    self.failed_attempts += 1;
+                    tokio::time::sleep(Duration::from_secs(1)).await
+                },
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        let filters: Vec<&str> = self.filters.iter().map(|(filter, _)| filter.as_str()).collect();
+        format!("mqtt://{}", filters.join(","))
+    }
+}
+
+
+/// A received PUBLISH as a Delivery. A retained message gets the header "retain" set to "true"
+fn delivery(publish: Publish, ack: Box<dyn Ack>) -> Delivery {
+    let source = Destination::MqttTopic(publish.topic.clone());
+    let mut meta = Metadata::default();
+    if publish.retain {
+        meta.headers.insert("retain".to_string(), "true".to_string());
+    }
+    // dup is set when the broker redelivers after a reconnect
+    let attempt = if publish.dup { 2 } else { 1 };
+    let message_id = Some(publish.pkid.to_string());
+    Delivery::new(source, publish.payload, meta, message_id, attempt, ack)
+}
+
+
+/// ack() sends the PUBACK (for QoS 1). nack() does nothing: the message is redelivered when the session resumes
+struct AckMqtt {
+    client: AsyncClient,
+    publish: Publish,
+}
+
+#[async_trait]
+impl Ack for AckMqtt {
+    async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+        self.client.ack(&self.publish).await?;
+        Ok(())
+    }
+
+    async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+        Ok(())
+    }
+}
+
+
+/// The TopicSubscriber trait mirrors ChannelConsumer for an MQTT topic filter
+#[async_trait]
+pub trait TopicSubscriber<T: EventMqtt> {
+
+    /// The connection options, including a client id unique to this consumer
+    fn options(&self) -> MqttOptions;
+
+    /// Connect and subscribe to the event's topic filter with its QoS
+    fn subscribe(&self) -> SubscriptionMqtt {
+        SubscriptionMqtt::connect(self.options(), vec![(<T as EventMqtt>::topic_filter(), <T as EventMqtt>::qos())])
+    }
+
+    /// Run the handler loop from the consumer module over the subscription
+    async fn run<H, Fut>(&self, options: &ConsumerOptions, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        consumer::run(Box::new(self.subscribe()), options, handler).await
+    }
+
+    /// Like run(), but events whose key has already been processed are acked without calling the handler
+    async fn run_idempotent<K, H, Fut>(&self, options: &ConsumerOptions, guard: &IdempotencyGuard, key: K, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        K: Fn(&T) -> String + Send + Sync,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        consumer::run_idempotent(Box::new(self.subscribe()), options, guard, key, handler).await
+    }
+}
+
+
+impl From<ClientError> for EventfulError {
+    fn from(err: ClientError) -> Self {
+        EventfulError::Mqtt(format!("{:?}", err))
+    }
+}
+
+impl From<ConnectionError> for EventfulError {
+    fn from(err: ConnectionError) -> Self {
+        EventfulError::Mqtt(format!("{:?}", err))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Unacked;
+
+    #[async_trait]
+    impl Ack for Unacked {
+        async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+            Ok(())
+        }
+
+        async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn options_keep_the_session_so_unacked_messages_come_back() {
+        let options = mqtt_options("telemetry-ingest", "broker", 1883);
+        assert_eq!((options.client_id(), options.broker_address()), ("telemetry-ingest".to_string(), ("broker".to_string(), 1883)));
+        assert_eq!((options.keep_alive(), options.clean_session()), (Duration::from_secs(30), false));
+    }
+
+    #[test]
+    fn publishes_become_deliveries() {
+        let mut publish = Publish::new("devices/42/telemetry", QoS::AtLeastOnce, br#"{"celsius":21}"#.to_vec());
+        publish.pkid = 7;
+        let delivery = delivery(publish.clone(), Box::new(Unacked));
+        assert_eq!(delivery.source, Destination::MqttTopic("devices/42/telemetry".to_string()));
+        assert_eq!((delivery.body.as_ref(), delivery.message_id.as_deref(), delivery.attempt), (&br#"{"celsius":21}"#[..], Some("7"), 1));
+        assert!(delivery.meta.headers.is_empty());
+        // a retained message redelivered after a reconnect
+        publish.retain = true;
+        publish.dup = true;
+        let delivery = super::delivery(publish, Box::new(Unacked));
+        assert_eq!(delivery.attempt, 2);
+        assert_eq!(delivery.meta.headers.get("retain").map(String::as_str), Some("true"));
+    }
+
+    #[tokio::test]
+    async fn only_mqtt_topics_can_be_published_to() {
+        // nothing is sent before the publish is checked, so no broker is needed
+        let publisher = PublisherMqtt::connect(mqtt_options("eventful-test", "127.0.0.1", 1));
+        let result = publisher.publish_bytes(&Destination::NsqTopic("click".to_string()), Bytes::new(), &Metadata::default()).await;
+        assert!(matches!(result, Err(EventfulError::Destination(_))), "{:?}", result);
+    }
+}
+
+
+/// Needs an MQTT broker (MQTT_HOST, default 127.0.0.1, on port 1883), i.e. `docker run -p 1883:1883 eclipse-mosquitto:1.6`.
+/// Run with `cargo test --features mqtt -- --ignored`
+#[cfg(test)]
+mod integration {
+    use super::*;
+
+    fn options(client_id: &str) -> MqttOptions {
+        let host = std::env::var("MQTT_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+        mqtt_options(client_id, &host, 1883)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an mqtt broker"]
+    async fn retained_messages_reach_later_subscribers() {
+        let run = rand::random::<u32>();
+        let topic = format!("eventful-test/{}/status", run);
+        let publisher = PublisherMqtt::connect(options(&format!("eventful-test-pub-{}", run)));
+        publisher.publish_raw(&topic, QoS::AtLeastOnce, true, b"online").await.unwrap();
+
+        let mut subscription = SubscriptionMqtt::connect(options(&format!("eventful-test-sub-{}", run)), vec![(topic.clone(), QoS::AtLeastOnce)]);
+        let delivery = tokio::time::timeout(Duration::from_secs(10), subscription.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!((delivery.source.clone(), delivery.body.as_ref()), (Destination::MqttTopic(topic.clone()), &b"online"[..]));
+        assert_eq!(delivery.meta.headers.get("retain").map(String::as_str), Some("true"));
+        delivery.ack().await.unwrap();
+        publisher.clear_retained(&topic).await.unwrap();
+    }
+}
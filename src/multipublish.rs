@@ -0,0 +1,316 @@
+//! The multipublish module publishes several related events and reports exactly which ones made it.  
+//! **This is not a transaction.** Message buses can't un-publish an event, so if some publishes fail the
+//! ones that succeeded stay published. MultiPublishError lists both sides so the caller can compensate
+//! (see attempt_rollback), or stage the failures in the outbox to be retried.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::multipublish::{Compensation, MultiPublish, Succeeded};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct OrderPlaced { order_id: u64 }
+//! # #[derive(Serialize, Deserialize)]
+//! # struct StockReserved { order_id: u64 }
+//! # #[derive(Serialize, Deserialize)]
+//! # struct InvoiceDue { order_id: u64 }
+//! # fn compensation_for(_succeeded: &Succeeded) -> Option<Compensation> { None }
+//! # async fn demo(publisher: Arc<dyn Publisher>, order_placed: OrderPlaced, stock_reserved: StockReserved, invoice_due: InvoiceDue, invoices_url: String) {
+//! let receipts = MultiPublish::new()
+//!     .add(&order_placed, Destination::NsqTopic("orders".to_string()))
+//!     .add(&stock_reserved, Destination::NsqTopic("stock".to_string()))
+//!     .add(&invoice_due, Destination::SqsQueue(invoices_url))
+//!     .concurrent(true)
+//!     .execute(&publisher).await;
+//! if let Err(partial) = receipts {
+//!     partial.attempt_rollback(&publisher, |succeeded| compensation_for(succeeded)).await;
+//! }
+//! # }
+//! ```
+
+use std::{error::Error, fmt};
+use bytes::Bytes;
+use futures::future::join_all;
+use serde::Serialize;
+use crate::codec::{Codec, JsonCodec};
+use crate::err::EventfulError;
+use crate::outbox::PendingEvent;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+
+
+struct Entry {
+    dest: Destination,
+    body: Result<Bytes, EventfulError>,
+    meta: Metadata,
+}
+
+
+/// A publish which succeeded
+#[derive(Clone, Debug)]
+pub struct Succeeded {
+    /// The position of the event in the order it was added
+    pub index: usize,
+    pub dest: Destination,
+    pub body: Bytes,
+    pub receipt: Receipt,
+}
+
+
+/// A publish which failed
+#[derive(Debug)]
+pub struct Failed {
+    /// The position of the event in the order it was added
+    pub index: usize,
+    pub dest: Destination,
+    /// The encoded body, or None if the event could not be encoded
+    pub body: Option<Bytes>,
+    pub meta: Metadata,
+    pub error: EventfulError,
+}
+
+
+/// A compensating event to publish in place of a successful publish which should be undone
+pub struct Compensation {
+    pub dest: Destination,
+    pub body: Bytes,
+}
+
+impl Compensation {
+    pub fn event<T: Serialize>(dest: Destination, event: &T) -> Result<Self, EventfulError> {
+        Ok(Compensation{dest, body: JsonCodec.encode(event)?})
+    }
+}
+
+
+/// Returned by MultiPublish::execute when at least one publish failed
+#[derive(Debug)]
+pub struct MultiPublishError {
+    pub succeeded: Vec<Succeeded>,
+    pub failed: Vec<Failed>,
+}
+
+impl Error for MultiPublishError {}
+
+impl fmt::Display for MultiPublishError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MultiPublishError: {} succeeded, {} failed", self.succeeded.len(), self.failed.len())?;
+        for failed in &self.failed {
+            write!(f, "; #{} to {}: {}", failed.index, failed.dest, failed.error)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<MultiPublishError> for EventfulError {
+    fn from(err: MultiPublishError) -> Self {
+        EventfulError::PartialPublish(err.to_string())
+    }
+}
+
+/// How attempt_rollback went
+#[derive(Debug, Default)]
+pub struct RollbackReport {
+    /// Indexes of successful publishes which were compensated
+    pub compensated: Vec<usize>,
+    /// Indexes of successful publishes for which no compensation was given
+    pub skipped: Vec<usize>,
+    /// Indexes of successful publishes whose compensation failed to publish
+    pub failed: Vec<(usize, EventfulError)>,
+}
+
+impl MultiPublishError {
+    /// The failed publishes which can be staged in an outbox to be retried.
+    /// Events which could not be encoded are left out, since retrying won't help them
+    pub fn failed_events(&self) -> Vec<PendingEvent> {
+        self.failed.iter()
+            .filter_map(|failed| failed.body.as_ref().map(|body| PendingEvent::new(failed.dest.clone(), body.clone(), failed.meta.clone())))
+            .collect()
+    }
+
+    /// For each successful publish, ask the callback for a compensating event and publish it.
+    /// Return None from the callback for destinations where compensation makes no sense
+    pub async fn attempt_rollback<F>(&self, publisher: &dyn Publisher, compensate: F) -> RollbackReport
+    where
+        F: Fn(&Succeeded) -> Option<Compensation>,
+    {
+        let mut report = RollbackReport::default();
+        for succeeded in &self.succeeded {
+            match compensate(succeeded) {
+                Some(compensation) => match publisher.publish_bytes(&compensation.dest, compensation.body, &Metadata::default()).await {
+                    Ok(_) => report.compensated.push(succeeded.index),
+                    Err(err) => report.failed.push((succeeded.index, err)),
+                },
+                None => report.skipped.push(succeeded.index),
+            }
+        }
+        report
+    }
+}
+
+
+/// MultiPublish collects events and publishes them together
+#[derive(Default)]
+pub struct MultiPublish {
+    entries: Vec<Entry>,
+    concurrent: bool,
+}
+
+impl MultiPublish {
+    pub fn new() -> Self {
+        MultiPublish::default()
+    }
+
+    /// Add an event to publish to a destination. The event is encoded immediately
+    pub fn add<T: Serialize>(self, event: &T, dest: Destination) -> Self {
+        self.add_with(event, dest, Metadata::default())
+    }
+
+    /// Add an event, along with some metadata, to publish to a destination
+    pub fn add_with<T: Serialize>(mut self, event: &T, dest: Destination, meta: Metadata) -> Self {
+        self.entries.push(Entry{dest, body: JsonCodec.encode(event), meta});
+        self
+    }
+
+    /// Publish all events at once rather than one after another (default false)
+    pub fn concurrent(mut self, concurrent: bool) -> Self {
+        self.concurrent = concurrent;
+        self
+    }
+
+    /// Attempt every publish, even after one fails.
+    /// Returns the receipts in the order events were added, or a MultiPublishError if any failed
+    pub async fn execute(self, publisher: &dyn Publisher) -> Result<Vec<Receipt>, MultiPublishError> {
+        let attempts = self.entries.into_iter().enumerate().map(|(index, entry)| async move {
+            let result = match &entry.body {
+                Ok(body) => publisher.publish_bytes(&entry.dest, body.clone(), &entry.meta).await,
+                Err(err) => Err(EventfulError::Config(format!("could not encode event: {}", err))),
+            };
+            (index, entry, result)
+        });
+        let results = match self.concurrent {
+            true => join_all(attempts).await,
+            false => {
+                let mut results = Vec::new();
+                for attempt in attempts {
+                    results.push(attempt.await);
+                }
+                results
+            },
+        };
+        let mut succeeded = Vec::new();
+        let mut failed = Vec::new();
+        for (index, entry, result) in results {
+            match result {
+                Ok(receipt) => succeeded.push(Succeeded{index, dest: entry.dest, body: entry.body.unwrap_or_default(), receipt}),
+                Err(error) => failed.push(Failed{index, dest: entry.dest, body: entry.body.ok(), meta: entry.meta, error}),
+            }
+        }
+        match failed.is_empty() {
+            true => Ok(succeeded.into_iter().map(|s| s.receipt).collect()),
+            false => Err(MultiPublishError{succeeded, failed}),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use async_trait::async_trait;
+    use serde::ser::{Error as _, Serializer};
+    use crate::memory::MemoryBroker;
+
+    /// Fails every publish to the named destinations
+    struct FailFor {
+        broker: MemoryBroker,
+        failing: HashSet<String>,
+    }
+
+    #[async_trait]
+    impl Publisher for FailFor {
+        async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+            match self.failing.contains(dest.name()) {
+                true => Err(EventfulError::Http(format!("{} is down", dest))),
+                false => self.broker.publish_bytes(dest, body, meta).await,
+            }
+        }
+    }
+
+    struct Unencodable;
+
+    impl Serialize for Unencodable {
+        fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(S::Error::custom("can't encode this"))
+        }
+    }
+
+    const NAMES: [&str; 3] = ["orders", "stock", "invoices"];
+
+    fn three_events() -> MultiPublish {
+        NAMES.iter().enumerate().fold(MultiPublish::new(), |multi, (i, name)| multi.add(&i, Destination::NsqTopic(name.to_string())))
+    }
+
+    #[tokio::test]
+    async fn every_failure_permutation_is_reported() {
+        for concurrent in [false, true] {
+            for mask in 0..8usize {
+                let failing: HashSet<String> = (0..3).filter(|i| mask & (1 << i) != 0).map(|i| NAMES[i].to_string()).collect();
+                let publisher = FailFor{broker: MemoryBroker::new(), failing};
+                let result = three_events().concurrent(concurrent).execute(&publisher).await;
+                let published: Vec<usize> = (0..3).filter(|i| mask & (1 << i) == 0).collect();
+                for (i, name) in NAMES.iter().enumerate() {
+                    assert_eq!(publisher.broker.published_to(name).len(), published.contains(&i) as usize, "mask {} concurrent {}", mask, concurrent);
+                }
+                match result {
+                    Ok(receipts) => {
+                        assert_eq!(mask, 0);
+                        assert_eq!(receipts.len(), 3);
+                    },
+                    Err(err) => {
+                        let succeeded: Vec<usize> = err.succeeded.iter().map(|s| s.index).collect();
+                        let failed: Vec<usize> = err.failed.iter().map(|f| f.index).collect();
+                        let expected_failed: Vec<usize> = (0..3).filter(|i| mask & (1 << i) != 0).collect();
+                        assert_eq!(succeeded, published, "mask {} concurrent {}", mask, concurrent);
+                        assert_eq!(failed, expected_failed, "mask {} concurrent {}", mask, concurrent);
+                        assert_eq!(err.failed_events().len(), failed.len());
+                    },
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unencodable_event_fails_without_blocking_the_rest() {
+        let broker = MemoryBroker::new();
+        let err = MultiPublish::new()
+            .add(&1, Destination::NsqTopic("orders".to_string()))
+            .add(&Unencodable, Destination::NsqTopic("stock".to_string()))
+            .execute(&broker).await.unwrap_err();
+        assert_eq!(err.succeeded.len(), 1);
+        assert_eq!((err.failed[0].index, err.failed[0].body.is_none()), (1, true));
+        // retrying can't help an event which won't encode, so it isn't staged
+        assert!(err.failed_events().is_empty());
+        assert!(broker.published_to("stock").is_empty());
+    }
+
+    #[tokio::test]
+    async fn rollback_compensates_skips_and_reports_failures() {
+        let publisher = FailFor{broker: MemoryBroker::new(), failing: HashSet::from(["invoices".to_string()])};
+        let err = three_events().execute(&publisher).await.unwrap_err();
+        assert_eq!(err.succeeded.len(), 2);
+
+        let publisher = FailFor{broker: MemoryBroker::new(), failing: HashSet::from(["stock_undo".to_string()])};
+        let report = err.attempt_rollback(&publisher, |succeeded| match succeeded.index {
+            0 => Some(Compensation{dest: Destination::NsqTopic("orders_undo".to_string()), body: succeeded.body.clone()}),
+            _ => Compensation::event(Destination::NsqTopic("stock_undo".to_string()), &succeeded.index).ok(),
+        }).await;
+        assert_eq!(report.compensated, vec![0]);
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.failed.iter().map(|(i, _)| *i).collect::<Vec<usize>>(), vec![1]);
+        assert_eq!(publisher.broker.published_to("orders_undo").len(), 1);
+
+        let report = err.attempt_rollback(&publisher, |_| None).await;
+        assert_eq!(report.skipped, vec![0, 1]);
+    }
+}
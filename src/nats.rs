@@ -0,0 +1,350 @@
+//! The nats module produces and consumes events with [NATS JetStream](https://docs.nats.io/nats-concepts/jetstream).
+//! It is enabled by the `nats` feature.
+//! 
+//! Publishing waits for JetStream's ack, so publish only returns once the stream has stored the message.
+//! An event's dedup_id is sent as the Nats-Msg-Id header, so republishing it within the stream's duplicate window is a no-op.
+//! 
+//! Consumers are durable pull consumers. With the handler run loop, success acks the message,
+//! failure naks it with the retry delay, and the consumer's max_deliver is set to options.retry.max_attempts
+//! so JetStream stops redelivering once the message has been dead-lettered.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use eventful::prelude::*;
+//! # use eventful::nats::{EventNats, PublisherNats};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct UserClickedSomething { user_id: i32 }
+//! impl EventNats for UserClickedSomething {
+//!     fn subject(&self) -> String {
+//!         format!("clicks.{}", self.user_id)
+//!     }
+//! }
+//!
+//! # async fn demo(click: UserClickedSomething) -> Result<(), Box<dyn std::error::Error>> {
+//! let client = async_nats::connect("nats://127.0.0.1:4222").await?;
+//! let publisher = PublisherNats::new(async_nats::jetstream::new(client));
+//! publisher.publish(&click).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{future::Future, time::Duration};
+use async_nats::{HeaderMap, jetstream::{self, AckKind, Context, consumer::{AckPolicy, PullConsumer, pull}}};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::codec::{Codec, JsonCodec};
+use crate::consumer::{self, ConsumerOptions};
+use crate::err::EventfulError;
+use crate::idempotency::IdempotencyGuard;
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+use crate::subscriber::{Ack, Delivery, Subscriber};
+use crate::topology::NatsStreamSpec;
+
+
+/// The header JetStream uses to deduplicate publishes
+pub const MSG_ID_HEADER: &str = "Nats-Msg-Id";
+
+
+/// Implement EventNats on a struct to publish it to JetStream
+pub trait EventNats: Serialize + DeserializeOwned {
+    /// The subject to publish on, which must be captured by some stream's subjects
+    fn subject(&self) -> String;
+
+    /// The Nats-Msg-Id for this event (default None). Returning the same key used with an IdempotencyGuard
+    /// deduplicates on both sides: JetStream drops the republish, and consumers skip a redelivery
+    fn dedup_id(&self) -> Option<String> {
+        None
+    }
+}
+
+
+/// The PublisherNats publishes to JetStream and waits for the stream's ack
+#[derive(Clone)]
+pub struct PublisherNats {
+    context: Context,
+}
+
+impl PublisherNats {
+    pub fn new(context: Context) -> Self {
+        PublisherNats{context}
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Publish raw bytes to a subject, returning "{stream}:{sequence}" from the ack
+    pub async fn publish_raw(&self, subject: &str, body: Bytes, meta: &Metadata) -> Result<String, EventfulError> {
+        let ack = self.context.publish_with_headers(subject.to_string(), headers(meta), body).await.map_err(nats_err)?
+            .await.map_err(nats_err)?;
+        Ok(format!("{}:{}", ack.stream, ack.sequence))
+    }
+
+    /// Serialize an event and publish it to its subject
+    pub async fn publish<T: EventNats>(&self, event: &T) -> Result<String, EventfulError> {
+        let body = JsonCodec.encode(event)?;
+        let meta = Metadata{dedup_id: event.dedup_id(), ..Default::default()};
+        self.publish_raw(&event.subject(), body, &meta).await
+    }
+}
+
+/// The message headers for a publish: meta.headers, plus meta.dedup_id as the Nats-Msg-Id
+fn headers(meta: &Metadata) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (key, value) in &meta.headers {
+        headers.insert(key.as_str(), value.as_str());
+    }
+    if let Some(dedup_id) = &meta.dedup_id {
+        headers.insert(MSG_ID_HEADER, dedup_id.as_str());
+    }
+    headers
+}
+
+#[async_trait]
+impl Publisher for PublisherNats {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let subject = match dest {
+            Destination::NatsSubject(subject) => subject,
+            _ => return Err(publisher::unsupported("PublisherNats", dest)),
+        };
+        let id = self.publish_raw(subject, body, meta).await?;
+        Ok(Receipt{message_id: Some(id)})
+    }
+}
+
+
+/// Create or update a stream and its durable consumers, i.e. from a Topology
+pub async fn declare_stream(context: &Context, spec: &NatsStreamSpec) -> Result<(), EventfulError> {
+    let config = jetstream::stream::Config{
+        name: spec.name.clone(),
+        subjects: spec.subjects.clone(),
+        duplicate_window: spec.duplicate_window,
+        ..Default::default()
+    };
+    let stream = context.get_or_create_stream(config).await.map_err(nats_err)?;
+    for durable in &spec.consumers {
+        stream.get_or_create_consumer(durable, pull_config(durable, None, None)).await.map_err(nats_err)?;
+    }
+    Ok(())
+}
+
+fn pull_config(durable: &str, max_deliver: Option<i64>, ack_wait: Option<Duration>) -> pull::Config {
+    let mut config = pull::Config{
+        durable_name: Some(durable.to_string()),
+        ack_policy: AckPolicy::Explicit,
+        ..Default::default()
+    };
+    if let Some(max_deliver) = max_deliver {
+        config.max_deliver = max_deliver;
+    }
+    if let Some(ack_wait) = ack_wait {
+        config.ack_wait = ack_wait;
+    }
+    config
+}
+
+
+/// A SubscriptionNats pulls from a durable consumer and implements the Subscriber trait
+pub struct SubscriptionNats {
+    messages: pull::Stream,
+}
+
+impl SubscriptionNats {
+    /// Bind to (creating if needed) a durable pull consumer on a stream
+    pub async fn new(context: &Context, stream_name: &str, durable: &str, max_deliver: Option<i64>, ack_wait: Option<Duration>) -> Result<Self, EventfulError> {
+        let stream = context.get_stream(stream_name).await.map_err(nats_err)?;
+        let consumer: PullConsumer = stream.get_or_create_consumer(durable, pull_config(durable, max_deliver, ack_wait)).await.map_err(nats_err)?;
+        let messages = consumer.messages().await.map_err(nats_err)?;
+        Ok(SubscriptionNats{messages})
+    }
+}
+
+#[async_trait]
+impl Subscriber for SubscriptionNats {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        let message = match self.messages.next().await {
+            Some(message) => message.map_err(nats_err)?,
+            None => return Ok(None),
+        };
+        let info = message.info().map_err(nats_err)?;
+        let attempt = info.delivered.max(1) as u32;
+        let message_id = Some(format!("{}:{}", info.stream, info.stream_sequence));
+        let meta = Metadata{
+            dedup_id: message.headers.as_ref()
+                .and_then(|headers| headers.get(MSG_ID_HEADER))
+                .map(|value| value.as_str().to_string()),
+            ..Default::default()
+        };
+        let body = message.payload.clone();
+        let source = Destination::NatsSubject(message.subject.to_string());
+        Ok(Some(Delivery::new(source, body, meta, message_id, attempt, Box::new(AckNats{message}))))
+    }
+}
+
+
+/// ack() acks the message, nack(delay) naks it so JetStream redelivers after the delay
+struct AckNats {
+    message: jetstream::Message,
+}
+
+#[async_trait]
+impl Ack for AckNats {
+    async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+        self.message.ack().await.map_err(nats_err)
+    }
+
+    async fn nack(self: Box<Self>, delay: Duration) -> Result<(), EventfulError> {
+        self.message.ack_with(AckKind::Nak(Some(delay))).await.map_err(nats_err)
+    }
+}
+
+
+/// The DurableConsumer trait mirrors ChannelConsumer for a JetStream durable pull consumer
+#[async_trait]
+pub trait DurableConsumer<T: EventNats> {
+
+    /// The stream to consume from
+    fn stream_name(&self) -> String;
+
+    /// The durable consumer name, shared by every replica of the service
+    fn durable_name(&self) -> String;
+
+    /// How long JetStream waits for an ack before redelivering (default 30 seconds)
+    fn ack_wait(&self) -> Duration {
+        Duration::from_secs(30)
+    }
+
+    /// Bind to the durable consumer. max_deliver is unlimited; run() sets it from the ConsumerOptions
+    async fn subscribe(&self, context: &Context) -> Result<SubscriptionNats, EventfulError>
+    where
+        Self: Sync,
+    {
+        SubscriptionNats::new(context, &self.stream_name(), &self.durable_name(), None, Some(self.ack_wait())).await
+    }
+
+    /// Run the handler loop from the consumer module over this consumer.
+    /// Messages which fail options.retry.max_attempts times go to options.dead_letter
+    async fn run<H, Fut>(&self, context: &Context, options: &ConsumerOptions, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        let subscription = SubscriptionNats::new(context, &self.stream_name(), &self.durable_name(), max_deliver(options), Some(self.ack_wait())).await?;
+        consumer::run(Box::new(subscription), options, handler).await
+    }
+
+    /// Like run(), but events whose key has already been processed are acked without calling the handler
+    async fn run_idempotent<K, H, Fut>(&self, context: &Context, options: &ConsumerOptions, guard: &IdempotencyGuard, key: K, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        K: Fn(&T) -> String + Send + Sync,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        let subscription = SubscriptionNats::new(context, &self.stream_name(), &self.durable_name(), max_deliver(options), Some(self.ack_wait())).await?;
+        consumer::run_idempotent(Box::new(subscription), options, guard, key, handler).await
+    }
+}
+
+
+/// With a dead-letter sink, JetStream stops redelivering once the consumer loop has given up and dead-lettered a message.
+/// Without one, redelivery is unlimited so failures are never dropped
+fn max_deliver(options: &ConsumerOptions) -> Option<i64> {
+    options.dead_letter.as_ref().map(|_| options.retry.max_attempts as i64)
+}
+
+
+/// async-nats has a distinct error type per operation, so they are all mapped through this
+fn nats_err<E: std::fmt::Display>(err: E) -> EventfulError {
+    EventfulError::Nats(err.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::deadletter::FileSink;
+    use crate::retry::RetryPolicy;
+
+    #[test]
+    fn dedup_ids_are_sent_as_the_msg_id_header() {
+        let mut meta = Metadata{dedup_id: Some("order-7".to_string()), ..Default::default()};
+        meta.headers.insert("traceparent".to_string(), "00-abc-01".to_string());
+        let headers = headers(&meta);
+        assert_eq!(headers.get(MSG_ID_HEADER).map(|value| value.as_str()), Some("order-7"));
+        assert_eq!(headers.get("traceparent").map(|value| value.as_str()), Some("00-abc-01"));
+        assert!(super::headers(&Metadata::default()).get(MSG_ID_HEADER).is_none());
+    }
+
+    #[test]
+    fn pull_consumers_ack_explicitly_and_only_override_what_is_given() {
+        let defaults = pull::Config::default();
+        let config = pull_config("billing", None, None);
+        assert_eq!((config.durable_name.as_deref(), config.ack_policy), (Some("billing"), AckPolicy::Explicit));
+        assert_eq!((config.max_deliver, config.ack_wait), (defaults.max_deliver, defaults.ack_wait));
+        let config = pull_config("billing", Some(5), Some(Duration::from_secs(45)));
+        assert_eq!((config.max_deliver, config.ack_wait), (5, Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn redelivery_is_capped_only_when_there_is_somewhere_to_dead_letter() {
+        let options = ConsumerOptions::default().retry(RetryPolicy::default().max_attempts(4));
+        assert_eq!(max_deliver(&options), None);
+        let options = options.dead_letter(Arc::new(FileSink::new("unused.ndjson")));
+        assert_eq!(max_deliver(&options), Some(4));
+    }
+
+    #[test]
+    fn async_nats_errors_keep_their_message() {
+        assert!(matches!(nats_err("stream not found"), EventfulError::Nats(message) if message == "stream not found"));
+    }
+}
+
+
+/// Needs a NATS server with JetStream (NATS_URL, default nats://127.0.0.1:4222), i.e. `docker run -p 4222:4222 nats -js`.
+/// Run with `cargo test --features nats -- --ignored`
+#[cfg(test)]
+mod integration {
+    use super::*;
+
+    async fn context() -> Context {
+        let url = std::env::var("NATS_URL").unwrap_or_else(|_| "nats://127.0.0.1:4222".to_string());
+        jetstream::new(async_nats::connect(url).await.unwrap())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs nats with jetstream"]
+    async fn republishes_are_deduplicated_and_naks_redelivered() {
+        let context = context().await;
+        let name = format!("eventful_test_{}", rand::random::<u32>());
+        let spec = NatsStreamSpec{
+            name: name.clone(),
+            subjects: vec![format!("{}.>", name)],
+            duplicate_window: Duration::from_secs(60),
+            consumers: vec!["test".to_string()],
+        };
+        declare_stream(&context, &spec).await.unwrap();
+        let publisher = PublisherNats::new(context.clone());
+        let subject = format!("{}.orders", name);
+        let meta = Metadata{dedup_id: Some("order-7".to_string()), ..Default::default()};
+        let first = publisher.publish_raw(&subject, Bytes::from_static(b"7"), &meta).await.unwrap();
+        let again = publisher.publish_raw(&subject, Bytes::from_static(b"7"), &meta).await.unwrap();
+        assert_eq!(first, again);
+
+        let mut subscription = SubscriptionNats::new(&context, &name, "test", None, Some(Duration::from_secs(30))).await.unwrap();
+        let delivery = subscription.next().await.unwrap().unwrap();
+        assert_eq!((delivery.body.as_ref(), delivery.attempt, delivery.message_id.as_deref()), (&b"7"[..], 1, Some(first.as_str())));
+        assert_eq!(delivery.meta.dedup_id.as_deref(), Some("order-7"));
+        delivery.nack(Duration::from_millis(100)).await.unwrap();
+        let redelivered = tokio::time::timeout(Duration::from_secs(10), subscription.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!((redelivered.body.as_ref(), redelivered.attempt), (&b"7"[..], 2));
+        redelivered.ack().await.unwrap();
+        context.delete_stream(&name).await.unwrap();
+    }
+}
@@ -1,25 +1,47 @@
 //! The NSQ module make it easy to produce and consume events using the [NSQ messaging platform](https://nsq.io/)
  
-use std::env;
-use rand::Rng;
-use rand::seq::SliceRandom; // 0.7.2
+use std::{env, future::Future, time::Duration};
+use rand::{RngCore, seq::SliceRandom}; // 0.7.2
 use async_trait::async_trait;
+use bytes::Bytes;
 use serde::{Serialize, de::DeserializeOwned};
-use tokio_nsq;
-use hyperactive;
+use serde_json::Value;
+pub use tokio_nsq;
+use crate::codec::{Codec, JsonCodec};
+use crate::consumer::{self, ConsumerOptions};
 use crate::err::EventfulError;
+use crate::idempotency::IdempotencyGuard;
+use crate::metrics;
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+use crate::rng::SharedRng;
+use crate::subscriber::{Ack, Delivery, Subscriber};
+use crate::trace;
+
+
+/// The message type a ChannelConsumer's consumer() yields
+pub type NSQMessage = tokio_nsq::NSQMessage;
 
 
 /// let urls be a list of NSQD instances, separated by commas (,)
 /// pick one at random to post an event to 
 pub fn rand_nsqd_url(urls: &str) -> String {
+    rand_nsqd_url_with(urls, &SharedRng::default())
+}
+
+/// Like rand_nsqd_url(), picking with the given SharedRng
+pub fn rand_nsqd_url_with(urls: &str, rng: &SharedRng) -> String {
 	let sp = urls.split(",").collect::<Vec<&str>>();
-    sp.choose(&mut rand::thread_rng()).unwrap().to_string()
+    rng.with(|rng| sp.choose(rng).unwrap().to_string())
 }
 
 
 pub fn rand_nsq_url() -> String {
-    let i = rand::thread_rng().gen_range(1..4);
+    rand_nsq_url_with(&SharedRng::default())
+}
+
+/// Like rand_nsq_url(), picking with the given SharedRng
+pub fn rand_nsq_url_with(rng: &SharedRng) -> String {
+    let i = rng.gen_range(1..4);
     match i {
         1 => format!("http://{}:{}", env::var("NSQ1_HOST").unwrap(), env::var("NSQ1_HTTP_PORT").unwrap() ),
         2 => format!("http://{}:{}", env::var("NSQ2_HOST").unwrap(), env::var("NSQ2_HTTP_PORT").unwrap() ),
@@ -32,6 +54,7 @@ pub fn rand_nsq_url() -> String {
 /// when providing address data:
 /// 1) The ports for production and consumption may not be the same
 /// 2) NSQConsumerConfigSources need to be prefixed with http:// whereas NSQProducerConfig::new() does not 
+#[derive(Clone, Debug)]
 pub struct Daemon {
     /// The host where the Daemon worker runs: typically 127.0.0.1 for localhost or nsq-nsqd1,2,3 etc. for docker deployments
     pub host: String,
@@ -67,18 +90,40 @@ pub struct FleetNSQ {
     pub d1: Daemon,
     pub d2: Daemon,
     pub d3: Daemon,
+    rng: SharedRng,
 }
 
 impl FleetNSQ {
+    pub fn new(d1: Daemon, d2: Daemon, d3: Daemon) -> Self {
+        FleetNSQ::new_with_rng(d1, d2, d3, SharedRng::default())
+    }
+
+    /// Like new(), picking daemons with the given SharedRng, which may be shared with a RetryPolicy
+    pub fn new_with_rng(d1: Daemon, d2: Daemon, d3: Daemon, rng: SharedRng) -> Self {
+        FleetNSQ{d1, d2, d3, rng}
+    }
+
     pub fn new_from_env() -> Self {
         let d1 = Daemon::new_from_env("NSQ1_HOST", "NSQ1_HTTP_PORT", "NSQ1_TCP_PORT");
         let d2 = Daemon::new_from_env("NSQ2_HOST", "NSQ2_HTTP_PORT", "NSQ2_TCP_PORT");
         let d3 = Daemon::new_from_env("NSQ3_HOST", "NSQ3_HTTP_PORT", "NSQ3_TCP_PORT");
-        FleetNSQ{d1, d2, d3}
+        FleetNSQ::new(d1, d2, d3)
+    }
+
+    /// Pick daemons with this generator instead of thread_rng, i.e. StdRng::seed_from_u64(42) for a repeatable sequence in tests
+    pub fn with_rng<R: RngCore + Send + 'static>(mut self, rng: R) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+
+    /// Pick daemons with a generator seeded with `seed`, so the same daemons are picked on every run
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = SharedRng::seeded(seed);
+        self
     }
 
     pub fn rand(&self) -> &Daemon {
-        let i = rand::thread_rng().gen_range(1..4);
+        let i = self.rng.gen_range(1..4);
         match i {
             1 => &self.d1,
             2 => &self.d2,
@@ -86,7 +131,7 @@ impl FleetNSQ {
         }
     }
 
-    pub fn as_refs<'a>(&'a self) -> [&'a Daemon; 3] {
+    pub fn as_refs(&self) -> [&Daemon; 3] {
         [&self.d1, &self.d2, &self.d3]
     }
 }
@@ -95,10 +140,10 @@ impl FleetNSQ {
 /// This elegant trait makes it super simple to send a struct as an event.  
 /// If a struct implements Serialize + DeserializeOwned, 
 /// all you have to do is define a topic to publish the message to NSQ.  
-/// Then you can call .publish_to(host) asynchronously to publish the event.
+/// Then you can call .publish_to(&daemon) asynchronously to publish the event.
 /// # Examples:
-/// ```
-/// use serde::{Serialize, Deserialize};
+/// ```no_run
+/// use eventful::prelude::*;
 /// 
 /// #[derive(Serialize, Deserialize)]
 /// struct UserClickedSomething {
@@ -112,16 +157,21 @@ impl FleetNSQ {
 ///     }
 /// }
 /// 
+/// # async fn demo() -> Result<(), EventfulError> {
 /// let click = UserClickedSomething{user_id: 5, clicked_on: "some_button".to_string()};
-/// click.publish_to_url("http://127.0.0.1:4151").await.unwrwap();
+/// click.publish_to_url("http://127.0.0.1:4151").await?;
+/// # Ok(())
+/// # }
 /// ```
 #[async_trait]
 pub trait EventNSQ: Serialize + DeserializeOwned {
     fn topic() -> &'static str;
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.publish", skip(self), fields(backend = "nsq", topic = <Self as EventNSQ>::topic(), payload_bytes = tracing::field::Empty, attempts = 1, outcome = tracing::field::Empty)))]
     async fn publish_to_url(&self, host: &str) -> Result<(), EventfulError>  {
         let topic =  <Self as EventNSQ>::topic();
-        let _x = post_json(host, &topic, &self).await?;
-        Ok(())
+        let result = send_json(host, topic, &self).await;
+        trace::record_outcome(&result);
+        result
     }
     async fn publish_to(&self, daemon: &Daemon) -> Result<(), EventfulError> {
         self.publish_to_url(&daemon.pub_url).await
@@ -139,8 +189,8 @@ pub trait EventNSQ: Serialize + DeserializeOwned {
 /// A common use case might be to implement ChannelConsumer<T: EventNSQ>
 /// Then implement a custom async fn run(&self) -> Result<(), EventfulError> or similar.
 /// # Examples:
-/// ```
-/// use serde::{Serialize, Deserialize};
+/// ```no_run
+/// use eventful::prelude::*;
 /// 
 /// #[derive(Serialize, Deserialize)]
 /// struct UserClickedSomething {
@@ -156,17 +206,16 @@ pub trait EventNSQ: Serialize + DeserializeOwned {
 /// 
 /// struct ClickConsumer{}
 /// 
-/// impl ChannelConsumer<UserClickedSomething> for ClickConsumer P
-///     fn channel(&sefl) -> String {
-///         "first_chanel".to_string()
+/// impl ChannelConsumer<UserClickedSomething> for ClickConsumer {
+///     fn channel(&self) -> String {
+///         "first_channel".to_string()
 ///     }
 /// }
 /// 
-/// impl ClickProcessor {
-///     async fn run(&self) -> Result<(), EventfulError> {
-///         let mut consumer = self.consumer();
-///         loop {
-///             let message = consumer.consume_filtered().await.unwrap();
+/// impl ClickConsumer {
+///     async fn run_forever(&self, daemon: &Daemon) -> Result<(), EventfulError> {
+///         let mut consumer = self.consumer(&[daemon]);
+///         while let Some(message) = consumer.consume_filtered().await {
 ///             let event = self.deserialize_event(&message)?;
 ///             println!("    CONSUME:  user_id={} clicked_on='{}'", &event.user_id, &event.clicked_on);
 ///             message.finish().await;
@@ -175,6 +224,7 @@ pub trait EventNSQ: Serialize + DeserializeOwned {
 ///     }
 /// }
 /// ```
+#[async_trait]
 pub trait ChannelConsumer<T: EventNSQ> {
 
     /// This method must be implemented to set the channel 
@@ -194,7 +244,7 @@ pub trait ChannelConsumer<T: EventNSQ> {
     /// For most use cases, this defaul implementation would likely not be overwritten 
     fn consumer(&self, daemons: &[&Daemon]) -> tokio_nsq::NSQConsumer {
         let topic = tokio_nsq::NSQTopic::new(<T as EventNSQ>::topic()).unwrap();
-        let channel = tokio_nsq::NSQChannel::new(&self.channel()).unwrap();
+        let channel = tokio_nsq::NSQChannel::new(self.channel()).unwrap();
         let config_source = self.config_source(daemons);
         let config = tokio_nsq::NSQConsumerConfig::new(topic, channel)
             .set_max_in_flight(10)
@@ -202,17 +252,135 @@ pub trait ChannelConsumer<T: EventNSQ> {
         config.build()
     }
 
-    fn deserialize_event(&self, message: &tokio_nsq::NSQMessage) -> Result<T, serde_json::Error> {
+    fn deserialize_event(&self, message: &NSQMessage) -> Result<T, serde_json::Error> {
         let event: T = serde_json::from_slice(&message.body)?;
         Ok(event)
     }
+
+    /// Wrap the consumer in a SubscriptionNSQ, which implements the transport-agnostic Subscriber trait
+    fn subscribe(&self, daemons: &[&Daemon]) -> SubscriptionNSQ {
+        SubscriptionNSQ::new(self.consumer(daemons), <T as EventNSQ>::topic())
+    }
+
+    /// Run the handler loop from the consumer module over this channel.
+    /// Messages which fail options.retry.max_attempts times go to options.dead_letter
+    async fn run<H, Fut>(&self, daemons: &[&Daemon], options: &ConsumerOptions, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        consumer::run(Box::new(self.subscribe(daemons)), options, handler).await
+    }
+
+    /// Like run(), but events whose key has already been processed are acked without calling the handler
+    async fn run_idempotent<K, H, Fut>(&self, daemons: &[&Daemon], options: &ConsumerOptions, guard: &IdempotencyGuard, key: K, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        K: Fn(&T) -> String + Send + Sync,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        consumer::run_idempotent(Box::new(self.subscribe(daemons)), options, guard, key, handler).await
+    }
+}
+
+
+/// Build a consumer for a topic and channel known only at runtime, i.e. for a Bridge.
+/// ChannelConsumer::consumer is the more convenient option when the topic comes from an EventNSQ
+pub fn consumer_for(topic: &str, channel: &str, daemons: &[&Daemon]) -> Result<tokio_nsq::NSQConsumer, EventfulError> {
+    let nsq_topic = tokio_nsq::NSQTopic::new(topic)
+        .ok_or(EventfulError::Config(format!("'{}' is not a valid NSQ topic name", topic)))?;
+    let nsq_channel = tokio_nsq::NSQChannel::new(channel)
+        .ok_or(EventfulError::Config(format!("'{}' is not a valid NSQ channel name", channel)))?;
+    let addresses = daemons.iter().map(|daemon| daemon.cons_address.to_string()).collect();
+    let config = tokio_nsq::NSQConsumerConfig::new(nsq_topic, nsq_channel)
+        .set_max_in_flight(10)
+        .set_sources(tokio_nsq::NSQConsumerConfigSources::Daemons(addresses));
+    Ok(config.build())
+}
+
+
+/// A SubscriptionNSQ yields a Delivery for each message consumed from an NSQ channel
+pub struct SubscriptionNSQ {
+    consumer: tokio_nsq::NSQConsumer,
+    topic: String,
+}
+
+impl SubscriptionNSQ {
+    pub fn new(consumer: tokio_nsq::NSQConsumer, topic: &str) -> Self {
+        SubscriptionNSQ{consumer, topic: topic.to_string()}
+    }
+}
+
+#[async_trait]
+impl Subscriber for SubscriptionNSQ {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        let message = match self.consumer.consume_filtered().await {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        let body = Bytes::from(message.body.clone());
+        let attempt = message.attempt as u32;
+        let source = Destination::NsqTopic(self.topic.clone());
+        // nsqd ids are 16 hex characters
+        let message_id = String::from_utf8_lossy(&message.id).to_string();
+        Ok(Some(Delivery::new(source, body, Metadata::default(), Some(message_id), attempt, Box::new(AckNSQ{message}))))
+    }
+
+    fn describe(&self) -> String {
+        Destination::NsqTopic(self.topic.clone()).to_string()
+    }
 }
 
 
+/// ack() sends FIN, nack(delay) sends REQ with the delay
+struct AckNSQ {
+    message: tokio_nsq::NSQMessage,
+}
+
+#[async_trait]
+impl Ack for AckNSQ {
+    async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+        self.message.finish().await;
+        Ok(())
+    }
+
+    async fn nack(self: Box<Self>, delay: Duration) -> Result<(), EventfulError> {
+        let delay = match delay.is_zero() {
+            true => tokio_nsq::NSQRequeueDelay::NoDelay,
+            false => tokio_nsq::NSQRequeueDelay::CustomDelay(delay),
+        };
+        self.message.requeue(delay).await;
+        Ok(())
+    }
+}
+
+
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.publish", skip(body), fields(backend = "nsq", payload_bytes = tracing::field::Empty, attempts = 1, outcome = tracing::field::Empty)))]
 pub async fn post_json<T: Serialize>(host: &str, topic: &str, body: &T) -> Result<(), EventfulError> {
+    let result = send_json(host, topic, body).await;
+    trace::record_outcome(&result);
+    result
+}
+
+/// The body of post_json and publish_to_url, left uninstrumented so each publish makes one eventful.publish span
+async fn send_json<T: Serialize>(host: &str, topic: &str, body: &T) -> Result<(), EventfulError> {
     let url = format!("{}/pub?topic={}", &host, topic);
-    let _x: () = hyperactive::client::post_noback(&url, &body, None).await?;
-    Ok(())
+    metrics::time_publish(topic, async {
+        let body = serde_json::to_vec(body)?;
+        trace::record_payload(body.len());
+        let req = hyper::Request::post(&url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(hyper::Body::from(body))?;
+        let resp = hyper::Client::new().request(req).await?;
+        if !resp.status().is_success() {
+            return Err(EventfulError::Http(format!("nsqd at {} responded with {}", host, resp.status())))
+        }
+        Ok(())
+    }).await
 }
 
 
@@ -225,3 +393,314 @@ pub async fn post_to<T: EventNSQ>(event: &T, daemon: &Daemon) -> Result<(), Even
     post_event(&daemon.pub_url, event).await
 }
 
+
+/// Post raw bytes to a topic on an nsqd daemon.
+/// Unlike post_json, the body is sent exactly as given, so it works for any Codec
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.publish", skip(body), fields(backend = "nsq", payload_bytes = body.len(), attempts = 1, outcome = tracing::field::Empty)))]
+pub async fn post_bytes(host: &str, topic: &str, body: Bytes) -> Result<(), EventfulError> {
+    let result = send_bytes(host, topic, body, Duration::ZERO).await;
+    trace::record_outcome(&result);
+    result
+}
+
+/// Like post_bytes, but nsqd holds the message for defer before consumers receive it (DPUB).
+/// nsqd rejects defers longer than its --max-req-timeout (one hour by default)
+#[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.publish", skip(body), fields(backend = "nsq", payload_bytes = body.len(), attempts = 1, defer_ms = defer.as_millis() as u64, outcome = tracing::field::Empty)))]
+pub async fn post_bytes_deferred(host: &str, topic: &str, body: Bytes, defer: Duration) -> Result<(), EventfulError> {
+    let result = send_bytes(host, topic, body, defer).await;
+    trace::record_outcome(&result);
+    result
+}
+
+/// The body of post_bytes and post_bytes_deferred, left uninstrumented like send_json
+async fn send_bytes(host: &str, topic: &str, body: Bytes, defer: Duration) -> Result<(), EventfulError> {
+    metrics::time_publish(topic, async {
+        let url = match defer.is_zero() {
+            true => format!("{}/pub?topic={}", &host, topic),
+            false => format!("{}/pub?topic={}&defer={}", &host, topic, defer.as_millis()),
+        };
+        let req = hyper::Request::post(&url).body(hyper::Body::from(body))?;
+        let resp = hyper::Client::new().request(req).await?;
+        if !resp.status().is_success() {
+            return Err(EventfulError::Http(format!("nsqd at {} responded with {}", host, resp.status())))
+        }
+        Ok(())
+    }).await
+}
+
+
+/// nsqd's default --max-req-timeout, the longest a message can be deferred
+pub const MAX_DEFER: Duration = Duration::from_secs(3600);
+
+/// nsqd's default --max-msg-size, the largest body it accepts
+pub const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
+
+
+/// Check a topic name the way nsqd does: 1 to 64 of [.a-zA-Z0-9_-], optionally ending in #ephemeral
+pub fn validate_topic(topic: &str) -> Result<(), EventfulError> {
+    tokio_nsq::NSQTopic::new(topic)
+        .map(|_| ())
+        .ok_or(EventfulError::Config(format!("'{}' is not a valid NSQ topic name", topic)))
+}
+
+/// Publish a hand-written JSON value (i.e. from an ops script) without defining an event type.
+/// The topic name and body size are checked before anything is sent, then it is published like any other event
+pub async fn publish_json_value(daemon: &Daemon, topic: &str, value: &Value) -> Result<Receipt, EventfulError> {
+    validate_topic(topic)?;
+    let body = JsonCodec.encode(value)?;
+    if body.len() > MAX_MESSAGE_BYTES {
+        return Err(EventfulError::Config(format!("the body is {} bytes, but nsqd accepts at most {}", body.len(), MAX_MESSAGE_BYTES)))
+    }
+    daemon.publish_bytes(&Destination::NsqTopic(topic.to_string()), body, &Metadata::default()).await
+}
+
+
+/// A single Daemon can act as a Publisher for NSQ topics
+#[async_trait]
+impl Publisher for Daemon {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, _meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let topic = match dest {
+            Destination::NsqTopic(topic) => topic,
+            _ => return Err(publisher::unsupported("Daemon", dest)),
+        };
+        post_bytes(&self.pub_url, topic, body).await?;
+        Ok(Receipt::default())
+    }
+
+    fn max_delay(&self) -> Option<Duration> {
+        Some(MAX_DEFER)
+    }
+
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, _meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        let topic = match dest {
+            Destination::NsqTopic(topic) => topic,
+            _ => return Err(publisher::unsupported("Daemon", dest)),
+        };
+        publisher::check_delay(self.max_delay(), dest, delay)?;
+        post_bytes_deferred(&self.pub_url, topic, body, delay).await?;
+        Ok(Receipt::default())
+    }
+}
+
+
+/// The FleetNSQ publishes each event to a randomly selected Daemon
+#[async_trait]
+impl Publisher for FleetNSQ {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        self.rand().publish_bytes(dest, body, meta).await
+    }
+
+    fn max_delay(&self) -> Option<Duration> {
+        Some(MAX_DEFER)
+    }
+
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        self.rand().publish_delayed(dest, body, meta, delay).await
+    }
+}
+
+
+/// Send a request to an nsqd HTTP endpoint, i.e. "/topic/create?topic=click", and return the response body
+async fn http_request(daemon: &Daemon, method: hyper::Method, path_and_query: &str) -> Result<Bytes, EventfulError> {
+    let url = format!("{}{}", &daemon.pub_url, path_and_query);
+    let req = hyper::Request::builder().method(method).uri(&url).body(hyper::Body::empty())?;
+    let resp = hyper::Client::new().request(req).await?;
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await?;
+    if !status.is_success() {
+        return Err(EventfulError::Http(format!("{} responded with {}: {}", url, status, String::from_utf8_lossy(&body))))
+    }
+    Ok(body)
+}
+
+
+/// Check that a daemon is up with its /ping endpoint
+pub async fn ping(daemon: &Daemon) -> Result<(), EventfulError> {
+    http_request(daemon, hyper::Method::GET, "/ping").await?;
+    Ok(())
+}
+
+
+/// Create a topic on a daemon. Creating a topic which already exists is not an error
+pub async fn create_topic(daemon: &Daemon, topic: &str) -> Result<(), EventfulError> {
+    http_request(daemon, hyper::Method::POST, &format!("/topic/create?topic={}", topic)).await?;
+    Ok(())
+}
+
+
+/// Create a channel on a topic. Creating a channel which already exists is not an error
+pub async fn create_channel(daemon: &Daemon, topic: &str, channel: &str) -> Result<(), EventfulError> {
+    http_request(daemon, hyper::Method::POST, &format!("/channel/create?topic={}&channel={}", topic, channel)).await?;
+    Ok(())
+}
+
+
+/// The depth of one channel, from the nsqd /stats endpoint
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct ChannelStats {
+    pub channel_name: String,
+    #[serde(default)]
+    pub depth: i64,
+    #[serde(default)]
+    pub in_flight_count: i64,
+}
+
+/// The depth of one topic and its channels, from the nsqd /stats endpoint
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct TopicStats {
+    pub topic_name: String,
+    #[serde(default)]
+    pub depth: i64,
+    #[serde(default)]
+    pub channels: Vec<ChannelStats>,
+}
+
+/// The parts of the nsqd /stats response eventful uses
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct StatsNSQ {
+    #[serde(default)]
+    pub topics: Vec<TopicStats>,
+}
+
+impl StatsNSQ {
+    /// Parse the body of /stats?format=json. nsqd before v1.0 wraps the stats in a "data" field
+    pub fn from_json(body: &[u8]) -> Result<Self, EventfulError> {
+        let value: serde_json::Value = serde_json::from_slice(body)?;
+        let stats = match value.get("data") {
+            Some(data) => serde_json::from_value(data.clone())?,
+            None => serde_json::from_value(value)?,
+        };
+        Ok(stats)
+    }
+
+    pub fn topic(&self, topic: &str) -> Option<&TopicStats> {
+        self.topics.iter().find(|stats| stats.topic_name == topic)
+    }
+}
+
+impl TopicStats {
+    pub fn channel(&self, channel: &str) -> Option<&ChannelStats> {
+        self.channels.iter().find(|stats| stats.channel_name == channel)
+    }
+}
+
+
+/// Fetch topic and channel stats from a daemon
+pub async fn stats(daemon: &Daemon) -> Result<StatsNSQ, EventfulError> {
+    let body = http_request(daemon, hyper::Method::GET, "/stats?format=json").await?;
+    StatsNSQ::from_json(&body)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    fn fleet() -> FleetNSQ {
+        FleetNSQ::new(Daemon::new("nsqd1", 4151, 4150), Daemon::new("nsqd2", 4151, 4150), Daemon::new("nsqd3", 4151, 4150))
+    }
+
+    fn picks(fleet: &FleetNSQ) -> Vec<String> {
+        (0..32).map(|_| fleet.rand().host.clone()).collect()
+    }
+
+    #[test]
+    fn identically_seeded_fleets_pick_the_same_daemons() {
+        let first = picks(&fleet().seed(42));
+        assert_eq!(first, picks(&fleet().seed(42)));
+        assert_eq!(first, picks(&fleet().with_rng(StdRng::seed_from_u64(42))));
+        assert_eq!(first, picks(&FleetNSQ::new_with_rng(fleet().d1, fleet().d2, fleet().d3, SharedRng::seeded(42))));
+        assert_ne!(first, picks(&fleet().seed(43)));
+        for host in ["nsqd1", "nsqd2", "nsqd3"] {
+            assert!(first.iter().any(|picked| picked == host), "{} is never picked", host);
+        }
+    }
+
+    #[test]
+    fn identically_seeded_urls_are_picked_in_the_same_order() {
+        let urls = "http://nsqd1:4151,http://nsqd2:4151,http://nsqd3:4151";
+        let picks = |rng: SharedRng| (0..32).map(|_| rand_nsqd_url_with(urls, &rng)).collect::<Vec<_>>();
+        assert_eq!(picks(SharedRng::seeded(7)), picks(SharedRng::seeded(7)));
+        assert_ne!(picks(SharedRng::seeded(7)), picks(SharedRng::seeded(8)));
+    }
+
+    #[cfg(feature = "tracing")]
+    mod spans {
+        use super::*;
+        use crate::httpstub::HttpStub;
+
+        #[derive(Serialize, serde::Deserialize)]
+        struct Click {
+            user_id: i32,
+        }
+
+        impl EventNSQ for Click {
+            fn topic() -> &'static str {
+                "click"
+            }
+        }
+
+        mod capture {
+            use std::{collections::HashMap, fmt, sync::{Arc, Mutex}};
+            use tracing::{field::{Field, Visit}, span::{Attributes, Id, Record}, Subscriber};
+            use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+            type Span = (String, HashMap<String, String>);
+
+            /// The name and fields of every span made while the layer is installed
+            #[derive(Clone, Default)]
+            pub struct Spans(pub Arc<Mutex<Vec<Span>>>);
+
+            struct Fields<'a>(&'a mut HashMap<String, String>);
+
+            impl Visit for Fields<'_> {
+                fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                    self.0.insert(field.name().to_string(), format!("{:?}", value));
+                }
+
+                fn record_str(&mut self, field: &Field, value: &str) {
+                    self.0.insert(field.name().to_string(), value.to_string());
+                }
+            }
+
+            impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Spans {
+                fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+                    let mut fields = HashMap::new();
+                    attrs.record(&mut Fields(&mut fields));
+                    let mut spans = self.0.lock().unwrap();
+                    ctx.span(id).unwrap().extensions_mut().insert(spans.len());
+                    spans.push((attrs.metadata().name().to_string(), fields));
+                }
+
+                fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+                    let index = *ctx.span(id).unwrap().extensions().get::<usize>().unwrap();
+                    values.record(&mut Fields(&mut self.0.lock().unwrap()[index].1));
+                }
+            }
+        }
+
+        #[tokio::test(flavor = "current_thread")]
+        async fn each_publish_makes_one_span_with_its_size_attempts_and_outcome() {
+            use tracing_subscriber::layer::SubscriberExt;
+            let spans = capture::Spans::default();
+            let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+            let stub = HttpStub::start(vec![(200, "OK".to_string()), (500, "E_FAILED".to_string())]).await;
+            let host = format!("http://127.0.0.1:{}", stub.port());
+
+            Click{user_id: 5}.publish_to_url(&host).await.unwrap();
+            assert!(post_bytes(&host, "click", Bytes::from_static(b"{}")).await.is_err());
+
+            let spans = spans.0.lock().unwrap();
+            let publishes = spans.iter().filter(|(name, _)| name == "eventful.publish").map(|(_, fields)| fields).collect::<Vec<_>>();
+            assert_eq!(publishes.len(), 2, "publish_to_url and post_bytes don't nest a second span: {:?}", publishes);
+            assert_eq!(publishes[0]["topic"], "click");
+            assert_eq!(publishes[0]["payload_bytes"], r#"{"user_id":5}"#.len().to_string());
+            assert_eq!(publishes[0]["attempts"], "1");
+            assert_eq!(publishes[0]["outcome"], "ok");
+            assert_eq!(publishes[1]["payload_bytes"], "2");
+            assert_eq!(publishes[1]["attempts"], "1");
+            assert_eq!(publishes[1]["outcome"], "error");
+        }
+    }
+}
@@ -0,0 +1,409 @@
+//! The observer module gives services one consistent set of lifecycle callbacks to log from:
+//! a consumer starting or reconnecting, a publish failing for good, a message being dead-lettered, a handler running slowly or getting stuck,
+//! a circuit breaker changing state, a spool holding events for too long, a delivery dropped without being settled,
+//! a visibility heartbeat failing to extend a message, received messages failing to be deleted on receipt,
+//! a sharded consumer's intake being held back by full queues, and a polling consumer going dormant on an idle queue.  
+//! Each callback receives a typed context. The default implementation of every method emits a `tracing` event
+//! (when the `tracing` feature is enabled), so installing nothing still yields uniform log lines.
+//! 
+//! An observer can be set globally with set_global, or per component (i.e. ConsumerOptions::observer, Bridge::observer).
+//! 
+//! # Examples:
+//! ```
+//! # use std::sync::Arc;
+//! # use eventful::observer::{DeadLettered, EventfulObserver};
+//! # fn page_on_call(message: String) {}
+//! struct PagerObserver;
+//!
+//! impl EventfulObserver for PagerObserver {
+//!     fn dead_lettered(&self, ctx: &DeadLettered) {
+//!         page_on_call(format!("{} dead-lettered from {}", ctx.message_id.as_deref().unwrap_or("?"), ctx.source));
+//!     }
+//! }
+//!
+//! eventful::observer::set_global(Arc::new(PagerObserver));
+//! ```
+
+use std::{sync::{Arc, RwLock}, time::Duration};
+use crate::circuit::CircuitState;
+use crate::publisher::Destination;
+use crate::subscriber::OnDrop;
+
+
+/// A consumer loop started consuming
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerStarted {
+    /// What is being consumed, i.e. "nsq://click" or a queue URL
+    pub source: String,
+}
+
+/// A consumer lost its connection and re-established it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerReconnected {
+    pub source: String,
+    /// How many connection attempts failed before this one succeeded
+    pub failed_attempts: u32,
+}
+
+/// A publish failed and will not be retried again automatically
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublishFailed {
+    pub dest: Destination,
+    pub attempts: u32,
+    pub error: String,
+}
+
+/// A message was handed to a dead-letter sink
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadLettered {
+    pub source: Destination,
+    pub message_id: Option<String>,
+    pub attempts: u32,
+    pub error: String,
+}
+
+/// A handler took longer than ConsumerOptions::slow_handler_threshold (reported once it finishes)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SlowHandler {
+    pub source: Destination,
+    pub message_id: Option<String>,
+    pub attempt: u32,
+    pub elapsed: Duration,
+}
+
+/// A handler has been running for longer than ConsumerOptions::max_inflight_age (reported while it is still running)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StuckMessage {
+    pub source: Destination,
+    pub message_id: Option<String>,
+    pub attempt: u32,
+    /// How long the handler had been running
+    pub elapsed: Duration,
+    /// Whether the handler was abandoned and the message requeued (ConsumerOptions::abort_stuck)
+    pub aborted: bool,
+}
+
+/// A sharded consumer stopped receiving because its queues were full (engaged),
+/// or its queues drained back down to half their capacity (released)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backpressure {
+    /// What is being consumed, i.e. "nsq://click"
+    pub source: String,
+    /// How many events were queued at the time
+    pub depth: usize,
+    pub capacity: usize,
+    /// When released, how long intake was held back in total while engaged
+    pub waited: Duration,
+}
+
+/// A polling consumer (i.e. sqs::QueueConsumer) found its queue empty so many times in a row that its wait between receives
+/// reached the idle backoff's cap (dormant), or received a message again after being dormant (awake)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerIdle {
+    pub source: String,
+    /// How many receives in a row came back empty
+    pub empty_receives: u32,
+    /// When dormant, the wait before the next receive. Zero when awake
+    pub wait: Duration,
+}
+
+/// A CircuitBreaker's circuit for a destination changed state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CircuitChanged {
+    pub dest: Destination,
+    pub from: CircuitState,
+    pub to: CircuitState,
+}
+
+/// A SpoolingPublisher has had events waiting for a destination for longer than SpoolConfig::alert_after
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpoolStalled {
+    pub dest: Destination,
+    /// How many events are waiting for the destination
+    pub depth: u64,
+    /// How long events have been waiting
+    pub waiting: Duration,
+}
+
+/// A Delivery was dropped without ack() or nack(), i.e. a handler returned early or panicked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AckLeaked {
+    pub source: Destination,
+    pub message_id: Option<String>,
+    pub attempt: u32,
+    /// What was done with the message instead
+    pub action: OnDrop,
+}
+
+/// A visibility heartbeat (i.e. sqs::ReceivedEvent::start_heartbeat) failed to extend a message and stopped,
+/// so the message may be redelivered while it is still being handled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VisibilityExtensionFailed {
+    pub source: Destination,
+    pub message_id: Option<String>,
+    /// How many extensions succeeded before this one failed
+    pub extensions: u32,
+    pub error: String,
+}
+
+/// Messages received with delete_on_receipt (i.e. sqs::ClientSQS::poll_messages) were returned but could not be deleted,
+/// so they will be received again once their visibility timeout expires
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceiptsNotDeleted {
+    pub source: Destination,
+    pub receipt_handles: Vec<String>,
+    pub error: String,
+}
+
+
+
+/// Implement EventfulObserver to receive lifecycle callbacks. Each method defaults to emitting a tracing event
+pub trait EventfulObserver: Send + Sync {
+    #[allow(unused_variables)]
+    fn consumer_started(&self, ctx: &ConsumerStarted) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(source = %ctx.source, "consumer started");
+    }
+
+    #[allow(unused_variables)]
+    fn consumer_reconnected(&self, ctx: &ConsumerReconnected) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(source = %ctx.source, failed_attempts = ctx.failed_attempts, "consumer reconnected");
+    }
+
+    #[allow(unused_variables)]
+    fn publish_failed(&self, ctx: &PublishFailed) {
+        #[cfg(feature = "tracing")]
+        tracing::error!(dest = %ctx.dest, attempts = ctx.attempts, error = %ctx.error, "publish failed after retries");
+    }
+
+    #[allow(unused_variables)]
+    fn dead_lettered(&self, ctx: &DeadLettered) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(source = %ctx.source, message_id = ctx.message_id.as_deref().unwrap_or(""), attempts = ctx.attempts, error = %ctx.error, "message dead-lettered");
+    }
+
+    #[allow(unused_variables)]
+    fn slow_handler(&self, ctx: &SlowHandler) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(source = %ctx.source, message_id = ctx.message_id.as_deref().unwrap_or(""), attempt = ctx.attempt, elapsed_ms = ctx.elapsed.as_millis() as u64, "slow handler");
+    }
+
+    #[allow(unused_variables)]
+    fn stuck_message(&self, ctx: &StuckMessage) {
+        #[cfg(feature = "tracing")]
+        tracing::error!(source = %ctx.source, message_id = ctx.message_id.as_deref().unwrap_or(""), attempt = ctx.attempt, elapsed_ms = ctx.elapsed.as_millis() as u64, aborted = ctx.aborted, "message stuck in flight");
+    }
+
+    #[allow(unused_variables)]
+    fn backpressure_engaged(&self, ctx: &Backpressure) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(source = %ctx.source, depth = ctx.depth, capacity = ctx.capacity, "consumer intake is waiting on full queues");
+    }
+
+    #[allow(unused_variables)]
+    fn backpressure_released(&self, ctx: &Backpressure) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(source = %ctx.source, depth = ctx.depth, capacity = ctx.capacity, waited_ms = ctx.waited.as_millis() as u64, "consumer intake is flowing again");
+    }
+
+    #[allow(unused_variables)]
+    fn consumer_dormant(&self, ctx: &ConsumerIdle) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(source = %ctx.source, empty_receives = ctx.empty_receives, wait_ms = ctx.wait.as_millis() as u64, "consumer is dormant on an idle queue");
+    }
+
+    #[allow(unused_variables)]
+    fn consumer_awake(&self, ctx: &ConsumerIdle) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(source = %ctx.source, empty_receives = ctx.empty_receives, "consumer received messages again");
+    }
+
+    #[allow(unused_variables)]
+    fn circuit_changed(&self, ctx: &CircuitChanged) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(dest = %ctx.dest, from = ?ctx.from, to = ?ctx.to, "circuit changed state");
+    }
+
+    #[allow(unused_variables)]
+    fn spool_stalled(&self, ctx: &SpoolStalled) {
+        #[cfg(feature = "tracing")]
+        tracing::error!(dest = %ctx.dest, depth = ctx.depth, waiting_secs = ctx.waiting.as_secs(), "spooled events are not draining");
+    }
+
+    #[allow(unused_variables)]
+    fn ack_leaked(&self, ctx: &AckLeaked) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(source = %ctx.source, message_id = ctx.message_id.as_deref().unwrap_or(""), attempt = ctx.attempt, action = ?ctx.action, "delivery dropped without being settled");
+    }
+
+    #[allow(unused_variables)]
+    fn visibility_extension_failed(&self, ctx: &VisibilityExtensionFailed) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(source = %ctx.source, message_id = ctx.message_id.as_deref().unwrap_or(""), extensions = ctx.extensions, error = %ctx.error, "visibility heartbeat stopped");
+    }
+
+    #[allow(unused_variables)]
+    fn receipts_not_deleted(&self, ctx: &ReceiptsNotDeleted) {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(source = %ctx.source, messages = ctx.receipt_handles.len(), error = %ctx.error, "received messages were not deleted");
+    }
+}
+
+
+/// An observer which only uses the default (tracing) implementations
+pub struct TracingObserver;
+
+impl EventfulObserver for TracingObserver {}
+
+
+static GLOBAL: RwLock<Option<Arc<dyn EventfulObserver>>> = RwLock::new(None);
+
+/// Replace the global observer, used by components without an observer of their own
+pub fn set_global(observer: Arc<dyn EventfulObserver>) {
+    *GLOBAL.write().unwrap() = Some(observer);
+}
+
+/// The global observer: whatever was set with set_global, else TracingObserver
+pub fn global() -> Arc<dyn EventfulObserver> {
+    match GLOBAL.read().unwrap().as_ref() {
+        Some(observer) => observer.clone(),
+        None => Arc::new(TracingObserver),
+    }
+}
+
+/// A component's own observer if it has one, else the global observer
+pub(crate) fn or_global(observer: &Option<Arc<dyn EventfulObserver>>) -> Arc<dyn EventfulObserver> {
+    observer.clone().unwrap_or_else(global)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::VecDeque, sync::Mutex};
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use serde_json::Value;
+    use crate::bridge::Bridge;
+    use crate::circuit::CircuitBreaker;
+    use crate::consumer::{self, ConsumerOptions};
+    use crate::deadletter::NsqTopicSink;
+    use crate::err::EventfulError;
+    use crate::memory::MemoryBroker;
+    use crate::publisher::{Metadata, Publisher, Receipt};
+    use crate::retry::RetryPolicy;
+    use crate::subscriber::{Ack, Delivery, Subscriber};
+
+    /// Records each callback as one line
+    #[derive(Default)]
+    struct Recording(Mutex<Vec<String>>);
+
+    impl Recording {
+        fn push(&self, line: String) {
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    impl EventfulObserver for Recording {
+        fn consumer_started(&self, ctx: &ConsumerStarted) {
+            self.push(format!("consumer_started {}", ctx.source));
+        }
+
+        fn publish_failed(&self, ctx: &PublishFailed) {
+            self.push(format!("publish_failed {} after {}", ctx.dest, ctx.attempts));
+        }
+
+        fn dead_lettered(&self, ctx: &DeadLettered) {
+            self.push(format!("dead_lettered {} from {} after {}", ctx.message_id.as_deref().unwrap_or("?"), ctx.source, ctx.attempts));
+        }
+
+        fn circuit_changed(&self, ctx: &CircuitChanged) {
+            self.push(format!("circuit_changed {} {:?} -> {:?}", ctx.dest, ctx.from, ctx.to));
+        }
+    }
+
+    struct Settle;
+
+    #[async_trait]
+    impl Ack for Settle {
+        async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+            Ok(())
+        }
+
+        async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+            Ok(())
+        }
+    }
+
+    /// Yields its deliveries, then ends
+    struct Scripted(VecDeque<Delivery>);
+
+    #[async_trait]
+    impl Subscriber for Scripted {
+        async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+            Ok(self.0.pop_front())
+        }
+
+        fn describe(&self) -> String {
+            "nsq://orders".to_string()
+        }
+    }
+
+    fn scripted(deliveries: &[(&str, u32)]) -> Box<dyn Subscriber> {
+        let source = Destination::NsqTopic("orders".to_string());
+        Box::new(Scripted(deliveries.iter().map(|(id, attempt)| {
+            Delivery::new(source.clone(), Bytes::from_static(b"{}"), Metadata::default(), Some(id.to_string()), *attempt, Box::new(Settle))
+        }).collect()))
+    }
+
+    /// A downstream which is down
+    struct Down;
+
+    #[async_trait]
+    impl Publisher for Down {
+        async fn publish_bytes(&self, _dest: &Destination, _body: Bytes, _meta: &Metadata) -> Result<Receipt, EventfulError> {
+            Err(EventfulError::Http("downstream responded with 503".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn an_outage_downstream_is_reported_in_order() {
+        let recording = Arc::new(Recording::default());
+        let downstream = Destination::NsqTopic("shipments".to_string());
+        let breaker = Arc::new(CircuitBreaker::new(Down).min_requests(2).failure_rate(0.5).open_for(Duration::from_secs(60)).observer(recording.clone()));
+        let dead_letters = MemoryBroker::new();
+        let dead_letter = Arc::new(NsqTopicSink::new(Arc::new(dead_letters.clone()), "orders_dlq"));
+        let retry = RetryPolicy::default().max_attempts(2);
+
+        // a consumer forwarding orders downstream: m1 fails, then fails again and opens the circuit
+        let options = ConsumerOptions::default().observer(recording.clone()).dead_letter(dead_letter.clone()).retry(retry.clone());
+        consumer::run(scripted(&[("m1", 1), ("m1", 2)]), &options, |_: Value| {
+            let (breaker, downstream) = (breaker.clone(), downstream.clone());
+            async move {
+                breaker.publish_bytes(&downstream, Bytes::from_static(b"{}"), &Metadata::default()).await?;
+                Ok(())
+            }
+        }).await.unwrap();
+
+        // a bridge relaying through the open circuit gives up on m2's last attempt
+        Bridge::new(scripted(&[("m2", 2)]), breaker.clone(), downstream.clone())
+            .observer(recording.clone())
+            .dead_letter(dead_letter)
+            .retry(retry)
+            .run().await.unwrap();
+
+        assert_eq!(recording.calls(), vec![
+            "consumer_started nsq://orders",
+            "circuit_changed nsq://shipments Closed -> Open",
+            "dead_lettered m1 from nsq://orders after 2",
+            "publish_failed nsq://shipments after 2",
+            "dead_lettered m2 from nsq://orders after 2",
+        ]);
+        assert_eq!(dead_letters.published_to("orders_dlq").len(), 2);
+    }
+}
@@ -0,0 +1,321 @@
+//! The otel module connects eventful to [OpenTelemetry](https://opentelemetry.io/), so traces continue across the queue
+//! and eventful's counters land in the same pipeline as the rest of your service. It is enabled by the `otel` feature.
+//!
+//! - OtelPublisher wraps any Publisher: each publish runs in a PRODUCER span, and the span's context is injected
+//!   into the message headers with the global text map propagator (i.e. `traceparent` for W3C trace context).
+//! - The consumer run loops extract that context from each delivery's headers and handle it in a CONSUMER span
+//!   which is a child of the producer span.
+//! - OtelMetrics implements EventfulMetrics with OTel instruments; install it with metrics::set_global.
+//!
+//! Spans carry the messaging.* semantic-convention attributes: messaging.system, messaging.destination.name,
+//! messaging.message.body.size (and messaging.message.id when the backend assigns one).
+//! eventful only uses the global tracer, meter, and propagator, so configure the exporter as usual.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::otel::{OtelMetrics, OtelPublisher};
+//! # use opentelemetry_sdk::propagation::TraceContextPropagator;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct Click { user_id: u64 }
+//! # async fn demo(click: Click) -> Result<(), EventfulError> {
+//! opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+//! eventful::metrics::set_global(Arc::new(OtelMetrics::new()));
+//!
+//! let publisher = OtelPublisher::new(Daemon::new("127.0.0.1", 4151, 4150));
+//! publisher.publish_event(&Destination::NsqTopic("click".to_string()), &click).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::HashMap, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use opentelemetry::{
+    global, Context, KeyValue,
+    metrics::{Counter, Histogram, Meter},
+    propagation::{Extractor, Injector},
+    trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+};
+use crate::err::EventfulError;
+use crate::metrics::EventfulMetrics;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::subscriber::Delivery;
+
+
+/// The instrumentation name used for eventful's tracer and meter
+const INSTRUMENTATION: &str = "eventful";
+
+
+/// The messaging.system semantic-convention value for a destination
+pub fn messaging_system(dest: &Destination) -> &'static str {
+    match dest {
+        Destination::NsqTopic(_) => "nsq",
+        Destination::SqsQueue(_) => "aws_sqs",
+        Destination::KafkaTopic(_) => "kafka",
+        Destination::RedisStream(_) => "redis",
+        Destination::NatsSubject(_) => "nats",
+        Destination::KinesisStream(_) => "aws_kinesis",
+        Destination::SnsTopic(_) => "aws_sns",
+        Destination::PgChannel(_) => "postgresql",
+        Destination::PgQueue(_) => "postgresql",
+        Destination::MqttTopic(_) => "mqtt",
+        Destination::EventBus(_) => "aws_eventbridge",
+    }
+}
+
+fn attributes(dest: &Destination, body_size: usize) -> Vec<KeyValue> {
+    vec![
+        KeyValue::new("messaging.system", messaging_system(dest)),
+        KeyValue::new("messaging.destination.name", dest.name().to_string()),
+        KeyValue::new("messaging.message.body.size", body_size as i64),
+    ]
+}
+
+
+/// Lets the propagator write into Metadata headers
+struct HeaderInjector<'a>(&'a mut HashMap<String, String>);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+/// Lets the propagator read from Metadata headers
+struct HeaderExtractor<'a>(&'a HashMap<String, String>);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|value| value.as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Write a context into the headers of some metadata with the global propagator
+pub fn inject(cx: &Context, meta: &mut Metadata) {
+    global::get_text_map_propagator(|propagator| propagator.inject_context(cx, &mut HeaderInjector(&mut meta.headers)));
+}
+
+/// Read the context a producer injected into the headers of some metadata (an empty context if there is none)
+pub fn extract(meta: &Metadata) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(&meta.headers)))
+}
+
+
+/// An OtelPublisher publishes inside a PRODUCER span and injects the span's context into the message headers
+pub struct OtelPublisher<P> {
+    inner: P,
+}
+
+impl<P: Publisher> OtelPublisher<P> {
+    pub fn new(inner: P) -> Self {
+        OtelPublisher{inner}
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for OtelPublisher<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let tracer = global::tracer(INSTRUMENTATION);
+        let span = tracer.span_builder(format!("{} publish", dest.name()))
+            .with_kind(SpanKind::Producer)
+            .with_attributes(attributes(dest, body.len()))
+            .start(&tracer);
+        let cx = Context::current_with_span(span);
+        let mut meta = meta.clone();
+        inject(&cx, &mut meta);
+        let result = self.inner.publish_bytes(dest, body, &meta).with_context(cx.clone()).await;
+        let span = cx.span();
+        match &result {
+            Ok(receipt) => {
+                if let Some(message_id) = &receipt.message_id {
+                    span.set_attribute(KeyValue::new("messaging.message.id", message_id.clone()));
+                }
+            },
+            Err(err) => span.set_status(Status::error(err.to_string())),
+        }
+        span.end();
+        result
+    }
+}
+
+
+/// The context a consumer loop handles a delivery in: a CONSUMER span whose parent is the producer's context
+pub(crate) fn consumer_context(delivery: &Delivery) -> Context {
+    let parent = extract(&delivery.meta);
+    let tracer = global::tracer(INSTRUMENTATION);
+    let mut attributes = attributes(&delivery.source, delivery.body.len());
+    if let Some(message_id) = &delivery.message_id {
+        attributes.push(KeyValue::new("messaging.message.id", message_id.clone()));
+    }
+    let span = tracer.span_builder(format!("{} process", delivery.source.name()))
+        .with_kind(SpanKind::Consumer)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    parent.with_span(span)
+}
+
+/// Mark the current OTel span as failed
+pub(crate) fn record_error(err: &EventfulError) {
+    Context::current().span().set_status(Status::error(err.to_string()));
+}
+
+
+/// Implements EventfulMetrics with OTel instruments: eventful.published, eventful.published.bytes, eventful.consumed, eventful.failed,
+/// eventful.dead_lettered, eventful.throttled, and eventful.backpressure.ms counters and eventful.publish.duration and eventful.publish.attempt.duration histograms (in seconds),
+/// each with a messaging.destination.name attribute. The inflight, intake depth, and lag gauges are not reported
+pub struct OtelMetrics {
+    published: Counter<u64>,
+    published_bytes: Counter<u64>,
+    consumed: Counter<u64>,
+    failed: Counter<u64>,
+    dead_lettered: Counter<u64>,
+    throttled: Counter<u64>,
+    backpressure_ms: Counter<u64>,
+    publish_duration: Histogram<f64>,
+    publish_attempt_duration: Histogram<f64>,
+}
+
+impl OtelMetrics {
+    /// Create the instruments with the global meter provider
+    pub fn new() -> Self {
+        Self::with_meter(&global::meter(INSTRUMENTATION))
+    }
+
+    /// Create the instruments with a particular meter
+    pub fn with_meter(meter: &Meter) -> Self {
+        OtelMetrics{
+            published: meter.u64_counter("eventful.published").init(),
+            published_bytes: meter.u64_counter("eventful.published.bytes").with_unit(opentelemetry::metrics::Unit::new("By")).init(),
+            consumed: meter.u64_counter("eventful.consumed").init(),
+            failed: meter.u64_counter("eventful.failed").init(),
+            dead_lettered: meter.u64_counter("eventful.dead_lettered").init(),
+            throttled: meter.u64_counter("eventful.throttled").init(),
+            backpressure_ms: meter.u64_counter("eventful.backpressure.ms").init(),
+            publish_duration: meter.f64_histogram("eventful.publish.duration").with_unit(opentelemetry::metrics::Unit::new("s")).init(),
+            publish_attempt_duration: meter.f64_histogram("eventful.publish.attempt.duration").with_unit(opentelemetry::metrics::Unit::new("s")).init(),
+        }
+    }
+}
+
+impl Default for OtelMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn labels(topic: &str) -> [KeyValue; 1] {
+    [KeyValue::new("messaging.destination.name", topic.to_string())]
+}
+
+impl EventfulMetrics for OtelMetrics {
+    fn inc_published(&self, topic: &str) {
+        self.published.add(1, &labels(topic));
+    }
+
+    fn observe_publish_latency(&self, topic: &str, duration: Duration) {
+        self.publish_duration.record(duration.as_secs_f64(), &labels(topic));
+    }
+
+    fn observe_publish_attempt(&self, topic: &str, duration: Duration) {
+        self.publish_attempt_duration.record(duration.as_secs_f64(), &labels(topic));
+    }
+
+    fn add_published_bytes(&self, topic: &str, bytes: u64) {
+        self.published_bytes.add(bytes, &labels(topic));
+    }
+
+    fn inc_consumed(&self, topic: &str) {
+        self.consumed.add(1, &labels(topic));
+    }
+
+    fn inc_failed(&self, topic: &str) {
+        self.failed.add(1, &labels(topic));
+    }
+
+    fn inc_dead_lettered(&self, topic: &str) {
+        self.dead_lettered.add(1, &labels(topic));
+    }
+
+    fn inc_throttled(&self, topic: &str) {
+        self.throttled.add(1, &labels(topic));
+    }
+
+    fn add_backpressure_time(&self, topic: &str, waited: Duration) {
+        self.backpressure_ms.add(waited.as_millis() as u64, &labels(topic));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use opentelemetry::Value;
+    use opentelemetry_sdk::{export::trace::SpanData, propagation::TraceContextPropagator, testing::trace::InMemorySpanExporter, trace::TracerProvider};
+    use crate::consumer::{self, ConsumerOptions};
+    use crate::memory::MemoryBroker;
+    use crate::subscriber::Subscriber;
+
+    /// Yields its deliveries, then ends
+    struct Scripted(VecDeque<Delivery>);
+
+    #[async_trait]
+    impl Subscriber for Scripted {
+        async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a Value> {
+        span.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| &kv.value)
+    }
+
+    #[tokio::test]
+    async fn a_consumer_span_is_the_child_of_the_producer_span() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder().with_simple_exporter(exporter.clone()).build();
+        global::set_tracer_provider(provider.clone());
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let broker = MemoryBroker::new();
+        let dest = Destination::NsqTopic("otel_clicks".to_string());
+        let mut subscription = broker.subscribe(&dest, "otel_test");
+        let receipt = OtelPublisher::new(broker.clone()).publish_bytes(&dest, Bytes::from_static(b"{\"user_id\":5}"), &Metadata::default()).await.unwrap();
+        let delivery = subscription.next().await.unwrap().unwrap();
+        assert!(delivery.meta.headers.contains_key("traceparent"));
+        consumer::run(Box::new(Scripted(VecDeque::from([delivery]))), &ConsumerOptions::default(), |_: serde_json::Value| async { Ok(()) }).await.unwrap();
+
+        for result in provider.force_flush() {
+            result.unwrap();
+        }
+        let spans = exporter.get_finished_spans().unwrap().into_iter()
+            .filter(|span| attribute(span, "messaging.destination.name") == Some(&Value::from("otel_clicks")))
+            .collect::<Vec<_>>();
+        let producer = spans.iter().find(|span| span.span_kind == SpanKind::Producer).expect("a PRODUCER span");
+        let consumer = spans.iter().find(|span| span.span_kind == SpanKind::Consumer).expect("a CONSUMER span");
+        assert_eq!(spans.len(), 2);
+
+        assert_eq!(producer.name, "otel_clicks publish");
+        assert_eq!(consumer.name, "otel_clicks process");
+        for span in [producer, consumer] {
+            assert_eq!(attribute(span, "messaging.system"), Some(&Value::from("nsq")));
+            assert_eq!(attribute(span, "messaging.message.body.size"), Some(&Value::I64(13)));
+            assert_eq!(attribute(span, "messaging.message.id"), Some(&Value::from(receipt.message_id.clone().unwrap())));
+        }
+        assert_eq!(consumer.span_context.trace_id(), producer.span_context.trace_id());
+        assert_eq!(consumer.parent_span_id, producer.span_context.span_id());
+    }
+}
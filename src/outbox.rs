@@ -0,0 +1,576 @@
+//! The outbox module implements the transactional outbox pattern.  
+//! Rather than publishing straight from a request handler (and losing the event if the publish fails after the
+//! database commit), the event is staged in an outbox table inside the same database transaction as the business data.
+//! An OutboxRelay then polls the outbox and publishes staged events through any Publisher.
+//! 
+//! Delivery is at-least-once: a relay which crashes after publishing but before marking a row sent will publish it again.
+//! Rows are claimed with a lease; rows stuck in "sending" (i.e. the relay holding them died) are retried once the lease expires.
+//! Rows sharing a partition key are published strictly in the order they were staged:
+//! only the oldest unsent row of each partition is ever claimed.
+//! 
+//! # Examples:
+//! ```no_run
+//! # #[cfg(all(feature = "postgres", feature = "nsq"))]
+//! # mod example {
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::bus::EventBus;
+//! # use eventful::outbox::{OutboxRelay, PostgresOutbox};
+//! # use sqlx::PgPool;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct OrderPlaced { order_id: u64 }
+//! # async fn demo(pool: PgPool, bus: EventBus) -> Result<(), Box<dyn std::error::Error>> {
+//! let outbox = PostgresOutbox::new(pool.clone(), bus.clone());
+//! let mut tx = pool.begin().await?;
+//! sqlx::query("INSERT INTO orders ...").execute(&mut *tx).await?;
+//! outbox.stage(&mut tx, &OrderPlaced{order_id: 7}).await?;
+//! tx.commit().await?;
+//!
+//! let relay = OutboxRelay::new(Arc::new(outbox.store()), Arc::new(FleetNSQ::new_from_env()));
+//! tokio::spawn(relay.run());
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::{BTreeMap, HashMap, HashSet}, future::Future, sync::{Arc, Mutex}, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher};
+use crate::retry::{Backoff, Jitter, RetryPolicy};
+
+
+/// An event waiting to be inserted into the outbox
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingEvent {
+    pub dest: Destination,
+    pub body: Bytes,
+    pub meta: Metadata,
+    /// Events with the same partition key are published in order. Defaults to meta.group_id
+    pub partition_key: Option<String>,
+}
+
+impl PendingEvent {
+    pub fn new(dest: Destination, body: Bytes, meta: Metadata) -> Self {
+        let partition_key = meta.group_id.clone();
+        PendingEvent{dest, body, meta, partition_key}
+    }
+}
+
+
+/// A row claimed from the outbox by fetch_batch
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutboxRow {
+    pub id: i64,
+    pub event: PendingEvent,
+    /// How many times this row has been claimed, including this time
+    pub attempts: u32,
+}
+
+
+/// An OutboxStore persists staged events until they have been published
+#[async_trait]
+pub trait OutboxStore: Send + Sync {
+    /// Insert an event outside of any transaction, returning its id
+    async fn insert(&self, event: PendingEvent) -> Result<i64, EventfulError>;
+
+    /// Claim up to `limit` rows which are due: pending rows whose retry time has passed, and "sending" rows
+    /// whose lease has expired. Only the oldest unsent row of each partition key may be claimed.
+    /// Claimed rows are marked "sending" until `lease` from now
+    async fn fetch_batch(&self, limit: usize, lease: Duration) -> Result<Vec<OutboxRow>, EventfulError>;
+
+    /// The row was published and can be forgotten
+    async fn mark_sent(&self, id: i64) -> Result<(), EventfulError>;
+
+    /// Publishing the row failed; make it due again at retry_at
+    async fn mark_failed(&self, id: i64, error: &str, retry_at: DateTime<Utc>) -> Result<(), EventfulError>;
+}
+
+
+#[derive(Clone, Debug)]
+enum RowStatus {
+    Pending { due: DateTime<Utc> },
+    Sending { lease_until: DateTime<Utc> },
+}
+
+#[derive(Clone, Debug)]
+struct MemoryRow {
+    event: PendingEvent,
+    status: RowStatus,
+    attempts: u32,
+    last_error: Option<String>,
+}
+
+
+/// An OutboxStore kept in memory. Nothing survives a restart, so this is for tests and examples
+#[derive(Clone, Default)]
+pub struct MemoryOutboxStore {
+    rows: Arc<Mutex<(i64, BTreeMap<i64, MemoryRow>)>>,
+}
+
+impl MemoryOutboxStore {
+    pub fn new() -> Self {
+        MemoryOutboxStore::default()
+    }
+
+    /// How many rows have not been marked sent
+    pub fn len(&self) -> usize {
+        self.rows.lock().unwrap().1.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The error from the most recent failed attempt of a row
+    pub fn last_error(&self, id: i64) -> Option<String> {
+        self.rows.lock().unwrap().1.get(&id).and_then(|row| row.last_error.clone())
+    }
+}
+
+#[async_trait]
+impl OutboxStore for MemoryOutboxStore {
+    async fn insert(&self, event: PendingEvent) -> Result<i64, EventfulError> {
+        let mut rows = self.rows.lock().unwrap();
+        rows.0 += 1;
+        let id = rows.0;
+        rows.1.insert(id, MemoryRow{event, status: RowStatus::Pending{due: Utc::now()}, attempts: 0, last_error: None});
+        Ok(id)
+    }
+
+    async fn fetch_batch(&self, limit: usize, lease: Duration) -> Result<Vec<OutboxRow>, EventfulError> {
+        let now = Utc::now();
+        let lease_until = now + chrono::Duration::from_std(lease).unwrap_or(chrono::Duration::zero());
+        let mut rows = self.rows.lock().unwrap();
+        let mut seen_keys = HashSet::new();
+        let mut batch = Vec::new();
+        for (id, row) in rows.1.iter_mut() {
+            if batch.len() >= limit {
+                break
+            }
+            // rows are visited oldest first, so the first row seen for a key is the head of its partition
+            if let Some(key) = &row.event.partition_key {
+                if !seen_keys.insert(key.clone()) {
+                    continue
+                }
+            }
+            let due = match row.status {
+                RowStatus::Pending{due} => due <= now,
+                RowStatus::Sending{lease_until} => lease_until < now,
+            };
+            if due {
+                row.status = RowStatus::Sending{lease_until};
+                row.attempts += 1;
+                batch.push(OutboxRow{id: *id, event: row.event.clone(), attempts: row.attempts});
+            }
+        }
+        Ok(batch)
+    }
+
+    async fn mark_sent(&self, id: i64) -> Result<(), EventfulError> {
+        self.rows.lock().unwrap().1.remove(&id);
+        Ok(())
+    }
+
+    async fn mark_failed(&self, id: i64, error: &str, retry_at: DateTime<Utc>) -> Result<(), EventfulError> {
+        if let Some(row) = self.rows.lock().unwrap().1.get_mut(&id) {
+            row.status = RowStatus::Pending{due: retry_at};
+            row.last_error = Some(error.to_string());
+        }
+        Ok(())
+    }
+}
+
+
+/// The OutboxRelay polls an OutboxStore and publishes what it finds
+pub struct OutboxRelay {
+    store: Arc<dyn OutboxStore>,
+    publisher: Arc<dyn Publisher>,
+    batch_size: usize,
+    lease: Duration,
+    poll_interval: Duration,
+    backoff: RetryPolicy,
+}
+
+impl OutboxRelay {
+    pub fn new(store: Arc<dyn OutboxStore>, publisher: Arc<dyn Publisher>) -> Self {
+        OutboxRelay{
+            store, publisher,
+            batch_size: 100,
+            lease: Duration::from_secs(30),
+            poll_interval: Duration::from_secs(1),
+            backoff: RetryPolicy::default()
+                .backoff(Backoff::ExponentialJitter{base: Duration::from_secs(1), factor: 2.0, max: Duration::from_secs(300), jitter: Jitter::Equal}),
+        }
+    }
+
+    /// How many rows are claimed per poll (default 100)
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// How long a claimed row is reserved before another relay may retry it (default 30 seconds)
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// How long to wait after a poll which found nothing (default 1 second)
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// A failed row is retried after the policy's delay for its attempt count (default 1 second doubling up to 5 minutes).
+    /// Rows are retried indefinitely, so max_attempts and retry_on are ignored. run_until() backs off the same way
+    /// after polls which fail
+    pub fn backoff(mut self, policy: RetryPolicy) -> Self {
+        self.backoff = policy;
+        self
+    }
+
+    /// Claim and publish one batch, returning how many rows were published
+    pub async fn relay_once(&self) -> Result<usize, EventfulError> {
+        let rows = self.store.fetch_batch(self.batch_size, self.lease).await?;
+        // keep rows of the same partition in order, but let different partitions go concurrently
+        let mut partitions: HashMap<Option<String>, Vec<OutboxRow>> = HashMap::new();
+        for row in rows {
+            let key = row.event.partition_key.clone();
+            partitions.entry(key).or_default().push(row);
+        }
+        let results = futures::future::join_all(partitions.into_values().map(|rows| self.relay_partition(rows))).await;
+        let mut sent = 0;
+        for result in results {
+            sent += result?;
+        }
+        Ok(sent)
+    }
+
+    async fn relay_partition(&self, rows: Vec<OutboxRow>) -> Result<usize, EventfulError> {
+        let mut sent = 0;
+        for row in rows {
+            let event = &row.event;
+            match self.publisher.publish_bytes(&event.dest, event.body.clone(), &event.meta).await {
+                Ok(_) => {
+                    self.store.mark_sent(row.id).await?;
+                    sent += 1;
+                },
+                Err(err) => {
+                    let delay = chrono::Duration::from_std(self.backoff.delay_for(row.attempts)).unwrap_or(chrono::Duration::zero());
+                    self.store.mark_failed(row.id, &err.to_string(), Utc::now() + delay).await?;
+                    // a later row of the same partition must not overtake this one
+                    if event.partition_key.is_some() {
+                        break
+                    }
+                },
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Relay forever
+    pub async fn run(self) -> Result<(), EventfulError> {
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Relay until the shutdown future completes. A batch in progress is finished first.
+    /// A failing store (i.e. the database is briefly unreachable) doesn't stop the relay: the error is logged and
+    /// the next poll waits out the backoff policy's delay for the number of consecutive failures
+    pub async fn run_until<F: Future<Output = ()>>(self, shutdown: F) -> Result<(), EventfulError> {
+        tokio::pin!(shutdown);
+        let mut failures = 0;
+        loop {
+            let wait = match self.relay_once().await {
+                Ok(sent) => {
+                    failures = 0;
+                    match sent {
+                        0 => self.poll_interval,
+                        _ => continue,
+                    }
+                },
+                Err(err) => {
+                    crate::trace::record_error(&err);
+                    failures += 1;
+                    self.backoff.delay_for(failures)
+                },
+            };
+            tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                _ = tokio::time::sleep(wait) => {},
+            }
+        }
+    }
+}
+
+
+#[cfg(feature = "postgres")]
+pub use self::postgres::{PostgresOutbox, PostgresOutboxStore};
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use chrono::{DateTime, Utc};
+    use serde::Serialize;
+    use sqlx::{Executor, PgPool, Postgres, Row, Transaction};
+    use crate::bus::{event_name, EventBus};
+    use crate::codec::{Codec, JsonCodec};
+    use crate::err::EventfulError;
+    use crate::publisher::Metadata;
+    use super::{OutboxRow, OutboxStore, PendingEvent};
+
+    /// The SQL which creates the outbox table. Run it in a migration, or call PostgresOutboxStore::migrate
+    pub const CREATE_TABLE: &str = "
+        CREATE TABLE IF NOT EXISTS eventful_outbox (
+            id BIGSERIAL PRIMARY KEY,
+            destination JSONB NOT NULL,
+            body BYTEA NOT NULL,
+            metadata JSONB NOT NULL,
+            partition_key TEXT,
+            -- 'pending' or 'sending'; rows are deleted once sent
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INT NOT NULL DEFAULT 0,
+            next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            lease_until TIMESTAMPTZ,
+            last_error TEXT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        CREATE INDEX IF NOT EXISTS eventful_outbox_partition ON eventful_outbox (partition_key, id) WHERE partition_key IS NOT NULL;";
+
+    const INSERT: &str = "INSERT INTO eventful_outbox (destination, body, metadata, partition_key) VALUES ($1, $2, $3, $4) RETURNING id";
+
+    /// An OutboxStore backed by the eventful_outbox table
+    #[derive(Clone)]
+    pub struct PostgresOutboxStore {
+        pool: PgPool,
+    }
+
+    impl PostgresOutboxStore {
+        pub fn new(pool: PgPool) -> Self {
+            PostgresOutboxStore{pool}
+        }
+
+        /// Create the outbox table if it does not exist
+        pub async fn migrate(&self) -> Result<(), EventfulError> {
+            // executing a bare string runs it as a simple query, which allows several statements
+            self.pool.execute(CREATE_TABLE).await?;
+            Ok(())
+        }
+    }
+
+    fn bind_insert<'q>(event: &'q PendingEvent) -> Result<sqlx::query::Query<'q, Postgres, sqlx::postgres::PgArguments>, EventfulError> {
+        Ok(sqlx::query(INSERT)
+            .bind(serde_json::to_value(&event.dest)?)
+            .bind(event.body.to_vec())
+            .bind(serde_json::to_value(&event.meta)?)
+            .bind(event.partition_key.clone()))
+    }
+
+    #[async_trait]
+    impl OutboxStore for PostgresOutboxStore {
+        async fn insert(&self, event: PendingEvent) -> Result<i64, EventfulError> {
+            let row = bind_insert(&event)?.fetch_one(&self.pool).await?;
+            Ok(row.try_get("id")?)
+        }
+
+        async fn fetch_batch(&self, limit: usize, lease: Duration) -> Result<Vec<OutboxRow>, EventfulError> {
+            let rows = sqlx::query("
+                UPDATE eventful_outbox SET status = 'sending', attempts = attempts + 1,
+                    lease_until = now() + ($2::BIGINT * interval '1 millisecond')
+                WHERE id IN (
+                    SELECT o.id FROM eventful_outbox o
+                    WHERE ((o.status = 'pending' AND o.next_attempt_at <= now()) OR (o.status = 'sending' AND o.lease_until < now()))
+                      AND (o.partition_key IS NULL OR NOT EXISTS (
+                          SELECT 1 FROM eventful_outbox e
+                          WHERE e.partition_key = o.partition_key AND e.id < o.id))
+                    ORDER BY o.id
+                    LIMIT $1
+                    FOR UPDATE SKIP LOCKED)
+                RETURNING id, destination, body, metadata, partition_key, attempts")
+                .bind(limit as i64)
+                .bind(lease.as_millis() as i64)
+                .fetch_all(&self.pool).await?;
+            let mut batch = Vec::with_capacity(rows.len());
+            for row in rows {
+                let body: Vec<u8> = row.try_get("body")?;
+                let attempts: i32 = row.try_get("attempts")?;
+                let event = PendingEvent{
+                    dest: serde_json::from_value(row.try_get("destination")?)?,
+                    body: Bytes::from(body),
+                    meta: serde_json::from_value(row.try_get("metadata")?)?,
+                    partition_key: row.try_get("partition_key")?,
+                };
+                batch.push(OutboxRow{id: row.try_get("id")?, event, attempts: attempts as u32});
+            }
+            batch.sort_by_key(|row| row.id);
+            Ok(batch)
+        }
+
+        async fn mark_sent(&self, id: i64) -> Result<(), EventfulError> {
+            sqlx::query("DELETE FROM eventful_outbox WHERE id = $1").bind(id).execute(&self.pool).await?;
+            Ok(())
+        }
+
+        async fn mark_failed(&self, id: i64, error: &str, retry_at: DateTime<Utc>) -> Result<(), EventfulError> {
+            sqlx::query("UPDATE eventful_outbox SET status = 'pending', lease_until = NULL, last_error = $2, next_attempt_at = $3 WHERE id = $1")
+                .bind(id)
+                .bind(error)
+                .bind(retry_at)
+                .execute(&self.pool).await?;
+            Ok(())
+        }
+    }
+
+
+    /// PostgresOutbox stages events inside your own transactions, routing them with an EventBus
+    #[derive(Clone)]
+    pub struct PostgresOutbox {
+        pool: PgPool,
+        bus: EventBus,
+    }
+
+    impl PostgresOutbox {
+        /// Only the bus's routes are used; its publishers are ignored
+        pub fn new(pool: PgPool, bus: EventBus) -> Self {
+            PostgresOutbox{pool, bus}
+        }
+
+        /// The store the OutboxRelay should poll
+        pub fn store(&self) -> PostgresOutboxStore {
+            PostgresOutboxStore::new(self.pool.clone())
+        }
+
+        /// Stage an event in the outbox as part of a transaction.
+        /// Nothing is published unless and until the transaction commits
+        pub async fn stage<T: Serialize>(&self, tx: &mut Transaction<'_, Postgres>, event: &T) -> Result<(), EventfulError> {
+            self.stage_with(tx, event, &Metadata::default()).await
+        }
+
+        /// Stage an event along with some metadata. meta.group_id becomes the partition key
+        pub async fn stage_with<T: Serialize>(&self, tx: &mut Transaction<'_, Postgres>, event: &T, meta: &Metadata) -> Result<(), EventfulError> {
+            let body = JsonCodec.encode(event)?;
+            for dest in self.bus.destinations_for(event_name::<T>())? {
+                let pending = PendingEvent::new(dest, body.clone(), meta.clone());
+                bind_insert(&pending)?.execute(&mut **tx).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use crate::memory::MemoryBroker;
+
+    /// A MemoryOutboxStore whose fetch_batch fails while `failing` is above zero
+    #[derive(Clone, Default)]
+    struct FlakyStore {
+        store: MemoryOutboxStore,
+        failing: Arc<AtomicU32>,
+    }
+
+    #[async_trait]
+    impl OutboxStore for FlakyStore {
+        async fn insert(&self, event: PendingEvent) -> Result<i64, EventfulError> {
+            self.store.insert(event).await
+        }
+
+        async fn fetch_batch(&self, limit: usize, lease: Duration) -> Result<Vec<OutboxRow>, EventfulError> {
+            if self.failing.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(EventfulError::Database("connection reset".to_string()))
+            }
+            self.store.fetch_batch(limit, lease).await
+        }
+
+        async fn mark_sent(&self, id: i64) -> Result<(), EventfulError> {
+            self.store.mark_sent(id).await
+        }
+
+        async fn mark_failed(&self, id: i64, error: &str, retry_at: DateTime<Utc>) -> Result<(), EventfulError> {
+            self.store.mark_failed(id, error, retry_at).await
+        }
+    }
+
+    fn event(body: &str, key: Option<&str>) -> PendingEvent {
+        let meta = Metadata{group_id: key.map(str::to_string), ..Default::default()};
+        PendingEvent::new(Destination::NsqTopic("orders".to_string()), Bytes::from(body.to_string()), meta)
+    }
+
+    fn quick() -> RetryPolicy {
+        RetryPolicy::default().backoff(Backoff::Fixed(Duration::from_millis(10)))
+    }
+
+    #[tokio::test]
+    async fn relays_each_partition_in_order_and_retries_failures() {
+        let (store, broker) = (MemoryOutboxStore::new(), MemoryBroker::new());
+        for (body, key) in [("a1", Some("a")), ("b1", Some("b")), ("a2", Some("a")), ("x", None)] {
+            store.insert(event(body, key)).await.unwrap();
+        }
+        let relay = OutboxRelay::new(Arc::new(store.clone()), Arc::new(broker.clone())).backoff(quick());
+
+        // only the head of each partition is claimed at a time
+        assert_eq!(relay.relay_once().await.unwrap(), 3);
+        assert_eq!(relay.relay_once().await.unwrap(), 1);
+        assert!(store.is_empty());
+        let sent = broker.published_to("orders");
+        let position = |body: &str| sent.iter().position(|sent| sent == body).unwrap();
+        assert!(position("a1") < position("a2"));
+
+        let id = store.insert(event("c1", Some("c"))).await.unwrap();
+        store.insert(event("c2", Some("c"))).await.unwrap();
+        broker.fail_next_publish();
+        assert_eq!(relay.relay_once().await.unwrap(), 0);
+        assert!(store.last_error(id).unwrap().contains("injected failure"));
+        // c2 waits behind the failed c1 until its retry time
+        assert_eq!(relay.relay_once().await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(relay.relay_once().await.unwrap(), 1);
+        assert_eq!(relay.relay_once().await.unwrap(), 1);
+        assert_eq!(broker.published_to("orders")[4..], [Bytes::from("c1"), Bytes::from("c2")]);
+    }
+
+    #[tokio::test]
+    async fn rows_left_sending_by_a_crashed_relay_are_retried_after_the_lease() {
+        let (store, broker) = (MemoryOutboxStore::new(), MemoryBroker::new());
+        store.insert(event("o1", Some("o"))).await.unwrap();
+        store.insert(event("o2", Some("o"))).await.unwrap();
+        // a relay claimed o1 and died before publishing it
+        let claimed = store.fetch_batch(10, Duration::from_millis(50)).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+
+        let relay = OutboxRelay::new(Arc::new(store.clone()), Arc::new(broker.clone())).lease(Duration::from_millis(50));
+        assert_eq!(relay.relay_once().await.unwrap(), 0);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(relay.relay_once().await.unwrap(), 1);
+        assert_eq!(relay.relay_once().await.unwrap(), 1);
+        assert_eq!(broker.published_to("orders"), vec![Bytes::from("o1"), Bytes::from("o2")]);
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failing_store_does_not_stop_the_relay() {
+        let (store, broker) = (FlakyStore::default(), MemoryBroker::new());
+        store.insert(event("a", None)).await.unwrap();
+        store.failing.store(3, Ordering::SeqCst);
+        let relay = OutboxRelay::new(Arc::new(store.clone()), Arc::new(broker.clone())).backoff(quick()).poll_interval(Duration::from_millis(5));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn(relay.run_until(async move { let _ = stopped.await; }));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !store.store.is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await.unwrap();
+        assert_eq!(store.failing.load(Ordering::SeqCst), 0);
+        assert_eq!(broker.published_to("orders"), vec![Bytes::from("a")]);
+        stop.send(()).unwrap();
+        running.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+//! The prelude brings in what most services need to publish and consume events with one import:
+//! the event traits, the core client and option types, and the serde and async_trait items their impls need.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! use eventful::prelude::*;
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct UserClickedSomething {
+//!     user_id: i32,
+//!     clicked_on: String,
+//! }
+//!
+//! impl EventNSQ for UserClickedSomething {
+//!     fn topic() -> &'static str {
+//!         "website_clicks"
+//!     }
+//! }
+//!
+//! # async fn demo() -> Result<(), EventfulError> {
+//! let daemon = Daemon::new("127.0.0.1", 4151, 4150);
+//! UserClickedSomething{user_id: 5, clicked_on: "some_button".to_string()}.publish_to(&daemon).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+pub use async_trait::async_trait;
+pub use serde::{Deserialize, Serialize};
+pub use crate::consumer::{ConsumerOptions, OnDecodeError};
+pub use crate::err::EventfulError;
+#[cfg(feature = "nsq")]
+pub use crate::nsq::{ChannelConsumer, Daemon, EventNSQ, FleetNSQ, NSQMessage};
+pub use crate::publisher::{Destination, Metadata, Publisher, PublisherExt};
+pub use crate::retry::{Backoff, RetryPolicy};
+#[cfg(feature = "sqs")]
+pub use crate::sqs::{ClientSQS, Event, QueueConsumer};
+#[cfg(all(feature = "sqs", feature = "derive"))]
+pub use crate::sqs::SqsEvent;
+pub use crate::subscriber::{Delivery, Subscriber};
@@ -0,0 +1,217 @@
+//! The publisher module defines a transport-agnostic Publisher trait.
+//! Services can depend on `Arc<dyn Publisher>` and choose NSQ or SQS (or a fake in tests) at startup.
+//! 
+//! # Examples:
+//! ```no_run
+//! use std::sync::Arc;
+//! use eventful::prelude::*;
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct UserClickedSomething {
+//!     user_id: i32,
+//!     clicked_on: String,
+//! }
+//!
+//! async fn emit_click(publisher: Arc<dyn Publisher>) -> Result<(), EventfulError> {
+//!     let click = UserClickedSomething{user_id: 5, clicked_on: "some_button".to_string()};
+//!     let dest = Destination::NsqTopic("website_clicks".to_string());
+//!     publisher.publish_event(&dest, &click).await?;
+//!     Ok(())
+//! }
+//!
+//! # #[cfg(feature = "nsq")]
+//! # async fn demo() -> Result<(), EventfulError> {
+//! // production publishes to nsqd, and tests pass a fake instead
+//! emit_click(Arc::new(Daemon::new("127.0.0.1", 4151, 4150))).await
+//! # }
+//! ```
+
+use std::{collections::HashMap, fmt, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Serialize, Deserialize};
+use crate::codec::{Codec, JsonCodec};
+use crate::err::EventfulError;
+
+
+/// A Destination is where a publisher should send an event
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Destination {
+    /// An NSQ topic, i.e. "website_clicks"
+    NsqTopic(String),
+    /// The URL of an SQS queue
+    SqsQueue(String),
+    /// A Kafka topic
+    KafkaTopic(String),
+    /// A Redis stream key
+    RedisStream(String),
+    /// A NATS subject captured by a JetStream stream
+    NatsSubject(String),
+    /// A Kinesis data stream name
+    KinesisStream(String),
+    /// The ARN of an SNS topic
+    SnsTopic(String),
+    /// A Postgres NOTIFY channel (at-most-once)
+    PgChannel(String),
+    /// A channel of the durable Postgres queue table (at-least-once)
+    PgQueue(String),
+    /// An MQTT topic
+    MqttTopic(String),
+    /// The name (or ARN) of an EventBridge event bus
+    EventBus(String),
+}
+
+impl Destination {
+    /// The topic name or queue URL inside the destination
+    pub fn name(&self) -> &str {
+        match self {
+            Destination::NsqTopic(name) => name,
+            Destination::SqsQueue(name) => name,
+            Destination::KafkaTopic(name) => name,
+            Destination::RedisStream(name) => name,
+            Destination::NatsSubject(name) => name,
+            Destination::KinesisStream(name) => name,
+            Destination::SnsTopic(arn) => arn,
+            Destination::PgChannel(channel) => channel,
+            Destination::PgQueue(channel) => channel,
+            Destination::MqttTopic(topic) => topic,
+            Destination::EventBus(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Destination::NsqTopic(topic) => write!(f, "nsq://{}", topic),
+            Destination::SqsQueue(url) => write!(f, "sqs://{}", url),
+            Destination::KafkaTopic(topic) => write!(f, "kafka://{}", topic),
+            Destination::RedisStream(key) => write!(f, "redis://{}", key),
+            Destination::NatsSubject(subject) => write!(f, "nats://{}", subject),
+            Destination::KinesisStream(name) => write!(f, "kinesis://{}", name),
+            Destination::SnsTopic(arn) => write!(f, "sns://{}", arn),
+            Destination::PgChannel(channel) => write!(f, "pg-notify://{}", channel),
+            Destination::PgQueue(channel) => write!(f, "pg-queue://{}", channel),
+            Destination::MqttTopic(topic) => write!(f, "mqtt://{}", topic),
+            Destination::EventBus(name) => write!(f, "eventbridge://{}", name),
+        }
+    }
+}
+
+
+/// Metadata travels alongside the body of an event.
+/// Backends use the fields they understand and ignore the rest
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The message group id used by SQS FIFO queues
+    pub group_id: Option<String>,
+    /// The deduplication id used by SQS FIFO queues
+    pub dedup_id: Option<String>,
+    /// Free-form key/value pairs
+    pub headers: HashMap<String, String>,
+}
+
+
+/// Keyed is implemented by events which carry their own partition key, i.e. for Kinesis.
+/// Events with the same key land on the same shard or partition, so they stay in order
+pub trait Keyed {
+    fn partition_key(&self) -> String;
+}
+
+
+/// A Receipt is returned after a successful publish
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Receipt {
+    /// The id assigned by the backend, if the backend assigns one (SQS does, NSQ does not)
+    pub message_id: Option<String>,
+}
+
+
+/// The Publisher trait is the object-safe core of publishing: it only deals in bytes.
+/// Encoding structs into bytes is handled by PublisherExt::publish_event
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError>;
+
+    /// The longest delay publish_delayed can honor, or None if this publisher can't delay messages
+    /// (NSQ defers up to nsqd's --max-req-timeout, one hour by default; SQS up to 15 minutes)
+    fn max_delay(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Publish a message which consumers won't receive until the delay has passed.
+    /// Fails with DelayUnsupported or DelayTooLong when max_delay() can't cover the delay; a zero delay always publishes
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        if delay.is_zero() {
+            return self.publish_bytes(dest, body, meta).await
+        }
+        check_delay(self.max_delay(), dest, delay)?;
+        // only reached by a publisher which reports a max_delay without overriding this method
+        Err(EventfulError::DelayUnsupported(dest.to_string()))
+    }
+}
+
+
+/// PublisherExt is implemented for every Publisher (including `dyn Publisher`) and handles encoding
+#[async_trait]
+pub trait PublisherExt: Publisher {
+    /// Encode an event with the JsonCodec and publish it to a destination
+    async fn publish_event<T: Serialize + Sync>(&self, dest: &Destination, event: &T) -> Result<Receipt, EventfulError> {
+        self.publish_event_with(dest, event, &Metadata::default()).await
+    }
+
+    /// Encode an event with the JsonCodec and publish it to a destination along with some metadata
+    async fn publish_event_with<T: Serialize + Sync>(&self, dest: &Destination, event: &T, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let body = JsonCodec.encode(event)?;
+        self.publish_bytes(dest, body, meta).await
+    }
+}
+
+impl<P: Publisher + ?Sized> PublisherExt for P {}
+
+
+/// Return an error explaining that a publisher can't deliver to a given destination
+#[cfg(any(feature = "eventbridge", feature = "kafka", feature = "kinesis", feature = "mqtt", feature = "nats", feature = "nsq", feature = "postgres", feature = "redis", feature = "sqs"))]
+pub(crate) fn unsupported(publisher: &str, dest: &Destination) -> EventfulError {
+    EventfulError::Destination(format!("{} cannot publish to {}", publisher, dest))
+}
+
+/// Check a requested delay against a publisher's max_delay
+pub(crate) fn check_delay(max: Option<Duration>, dest: &Destination, delay: Duration) -> Result<(), EventfulError> {
+    match max {
+        None => Err(EventfulError::DelayUnsupported(dest.to_string())),
+        Some(max) if delay > max => Err(EventfulError::DelayTooLong{destination: dest.to_string(), requested: delay, max}),
+        Some(_) => Ok(()),
+    }
+}
+
+
+#[async_trait]
+impl<P: Publisher + ?Sized> Publisher for std::sync::Arc<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        (**self).publish_bytes(dest, body, meta).await
+    }
+
+    fn max_delay(&self) -> Option<Duration> {
+        (**self).max_delay()
+    }
+
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        (**self).publish_delayed(dest, body, meta, delay).await
+    }
+}
+
+#[async_trait]
+impl<P: Publisher + ?Sized> Publisher for Box<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        (**self).publish_bytes(dest, body, meta).await
+    }
+
+    fn max_delay(&self) -> Option<Duration> {
+        (**self).max_delay()
+    }
+
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        (**self).publish_delayed(dest, body, meta, delay).await
+    }
+}
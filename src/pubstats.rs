@@ -0,0 +1,308 @@
+//! The pubstats module tracks how publishing is going, per destination, from the producer's side,
+//! so a producing service notices a slow or failing broker before its consumers do.
+//! For each destination PublisherStats keeps the latency and outcome of the last `window` attempts in a fixed ring
+//! (p50/p95/p99 latency and error rate are computed over it) plus running totals of attempts and bytes published.
+//! Recording takes a short lock and never allocates once a destination has been seen.
+//!
+//! Wrap any Publisher in a StatsPublisher to track it; ClientSQS tracks its own publishes (ClientSQS::stats).
+//! Every attempt's latency, and the bytes of each successful publish, are also reported through the metrics facade
+//! (EventfulMetrics::observe_publish_attempt and add_published_bytes).
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use std::time::Duration;
+//! # use eventful::prelude::*;
+//! # use eventful::pubstats::StatsPublisher;
+//! # fn demo() {
+//! let publisher = StatsPublisher::new(FleetNSQ::new_from_env());
+//! let stats = publisher.stats();
+//! // later, i.e. in a periodic check:
+//! if let Some(click) = stats.get("click") {
+//!     if click.p99 > Duration::from_secs(1) || click.error_rate > 0.05 {
+//!         eprintln!("publishing to click is degraded: {:?}", click);
+//!     }
+//! }
+//! # }
+//! # }
+//! ```
+
+use std::{collections::HashMap, future::Future, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use async_trait::async_trait;
+use bytes::Bytes;
+use crate::err::EventfulError;
+use crate::metrics;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+
+
+/// The last `capacity` attempts to one destination, plus running totals
+struct Window {
+    latencies_micros: Vec<u64>,
+    failed: Vec<bool>,
+    next: usize,
+    attempts: u64,
+    errors: u64,
+    bytes_published: u64,
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Window{
+            latencies_micros: Vec::with_capacity(capacity),
+            failed: Vec::with_capacity(capacity),
+            next: 0,
+            attempts: 0,
+            errors: 0,
+            bytes_published: 0,
+        }
+    }
+
+    fn record(&mut self, capacity: usize, latency: Duration, bytes: usize, ok: bool) {
+        let micros = latency.as_micros() as u64;
+        if self.latencies_micros.len() < capacity {
+            self.latencies_micros.push(micros);
+            self.failed.push(!ok);
+        } else {
+            self.latencies_micros[self.next] = micros;
+            self.failed[self.next] = !ok;
+        }
+        self.next = (self.next + 1) % capacity;
+        self.attempts += 1;
+        match ok {
+            true => self.bytes_published += bytes as u64,
+            false => self.errors += 1,
+        }
+    }
+
+    fn stats(&self) -> DestinationStats {
+        let mut sorted = self.latencies_micros.clone();
+        sorted.sort_unstable();
+        let failures = self.failed.iter().filter(|failed| **failed).count();
+        let error_rate = match self.failed.len() {
+            0 => 0.0,
+            len => failures as f64 / len as f64,
+        };
+        DestinationStats{
+            p50: quantile(&sorted, 0.50),
+            p95: quantile(&sorted, 0.95),
+            p99: quantile(&sorted, 0.99),
+            error_rate,
+            window_len: sorted.len(),
+            attempts: self.attempts,
+            errors: self.errors,
+            bytes_published: self.bytes_published,
+        }
+    }
+}
+
+/// The nearest-rank quantile q (0.0 to 1.0) of sorted latencies in microseconds
+fn quantile(sorted_micros: &[u64], q: f64) -> Duration {
+    if sorted_micros.is_empty() {
+        return Duration::ZERO
+    }
+    let rank = ((q.clamp(0.0, 1.0) * sorted_micros.len() as f64).ceil() as usize).max(1);
+    Duration::from_micros(sorted_micros[rank - 1])
+}
+
+
+/// Publish statistics for one destination
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DestinationStats {
+    /// Latency quantiles over the window (successful and failed attempts alike)
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    /// The fraction of attempts in the window which failed
+    pub error_rate: f64,
+    /// How many attempts the window holds (at most the window size)
+    pub window_len: usize,
+    /// Every attempt since the stats were created
+    pub attempts: u64,
+    pub errors: u64,
+    /// Body bytes of every successful publish
+    pub bytes_published: u64,
+}
+
+
+/// Per-destination publish statistics, shared by every clone of the publisher that records them
+pub struct PublisherStats {
+    window: usize,
+    destinations: Mutex<HashMap<String, Window>>,
+}
+
+impl Default for PublisherStats {
+    fn default() -> Self {
+        PublisherStats::new(1024)
+    }
+}
+
+impl PublisherStats {
+    /// Keep the last `window` attempts per destination (default 1024)
+    pub fn new(window: usize) -> Self {
+        PublisherStats{window: window.max(1), destinations: Mutex::new(HashMap::new())}
+    }
+
+    /// Record one publish attempt to a destination (its topic name or queue URL)
+    pub fn record(&self, dest: &str, latency: Duration, bytes: usize, ok: bool) {
+        let mut destinations = self.destinations.lock().unwrap();
+        match destinations.get_mut(dest) {
+            Some(window) => window.record(self.window, latency, bytes, ok),
+            None => {
+                let mut window = Window::new(self.window);
+                window.record(self.window, latency, bytes, ok);
+                destinations.insert(dest.to_string(), window);
+            },
+        }
+        drop(destinations);
+        let metrics = metrics::global();
+        metrics.observe_publish_attempt(dest, latency);
+        if ok {
+            metrics.add_published_bytes(dest, bytes as u64);
+        }
+    }
+
+    /// Time a publish and record it
+    pub(crate) async fn track<T, F: Future<Output = Result<T, EventfulError>>>(&self, dest: &str, bytes: usize, publish: F) -> Result<T, EventfulError> {
+        let started = Instant::now();
+        let result = publish.await;
+        self.record(dest, started.elapsed(), bytes, result.is_ok());
+        result
+    }
+
+    /// The stats for one destination, if anything has been published to it
+    pub fn get(&self, dest: &str) -> Option<DestinationStats> {
+        self.destinations.lock().unwrap().get(dest).map(|window| window.stats())
+    }
+
+    /// The stats for every destination
+    pub fn snapshot(&self) -> HashMap<String, DestinationStats> {
+        self.destinations.lock().unwrap().iter()
+            .map(|(dest, window)| (dest.clone(), window.stats()))
+            .collect()
+    }
+}
+
+
+/// A StatsPublisher records every publish made through it in a PublisherStats
+pub struct StatsPublisher<P> {
+    inner: P,
+    stats: Arc<PublisherStats>,
+}
+
+impl<P: Publisher> StatsPublisher<P> {
+    pub fn new(inner: P) -> Self {
+        StatsPublisher{inner, stats: Arc::new(PublisherStats::default())}
+    }
+
+    /// Record into existing stats, i.e. to share them between publishers or use a different window
+    pub fn with_stats(inner: P, stats: Arc<PublisherStats>) -> Self {
+        StatsPublisher{inner, stats}
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// A handle to the stats, which stays valid while the publisher is used
+    pub fn stats(&self) -> Arc<PublisherStats> {
+        self.stats.clone()
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for StatsPublisher<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let bytes = body.len();
+        self.stats.track(dest.name(), bytes, self.inner.publish_bytes(dest, body, meta)).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::EventfulMetrics;
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn quantiles_of_synthetic_latencies() {
+        let stats = PublisherStats::new(1000);
+        // 1ms to 100ms, recorded out of order
+        for ms in (1..=100).rev() {
+            stats.record("click", millis(ms), 10, true);
+        }
+        let click = stats.get("click").unwrap();
+        assert_eq!((click.p50, click.p95, click.p99), (millis(50), millis(95), millis(99)));
+        assert_eq!((click.window_len, click.attempts, click.errors, click.bytes_published), (100, 100, 0, 1000));
+        assert_eq!(click.error_rate, 0.0);
+        assert_eq!(stats.get("other"), None);
+    }
+
+    #[test]
+    fn quantiles_cover_only_the_window() {
+        let stats = PublisherStats::new(10);
+        // ten slow failures, then ten fast successes which push them all out of the window
+        for _ in 0..10 {
+            stats.record("click", millis(900), 10, false);
+        }
+        assert_eq!(stats.get("click").unwrap().error_rate, 1.0);
+        for ms in 1..=10 {
+            stats.record("click", millis(ms), 10, true);
+        }
+        let click = stats.get("click").unwrap();
+        assert_eq!((click.p50, click.p99), (millis(5), millis(10)));
+        assert_eq!(click.error_rate, 0.0);
+        // the totals still count everything
+        assert_eq!((click.window_len, click.attempts, click.errors, click.bytes_published), (10, 20, 10, 100));
+    }
+
+    #[test]
+    fn error_rate_and_a_single_outlier() {
+        let stats = PublisherStats::new(100);
+        for i in 0..100 {
+            let latency = if i == 42 { millis(5000) } else { millis(20) };
+            stats.record("click", latency, 1, i % 4 != 0);
+        }
+        let click = stats.get("click").unwrap();
+        // one slow attempt in a hundred only shows at the very top
+        assert_eq!((click.p50, click.p95, click.p99), (millis(20), millis(20), millis(20)));
+        assert_eq!(quantile(&[20_000; 99].into_iter().chain([5_000_000]).collect::<Vec<u64>>(), 1.0), millis(5000));
+        assert_eq!(click.error_rate, 0.25);
+        assert_eq!(quantile(&[], 0.5), Duration::ZERO);
+    }
+
+    /// Collects what PublisherStats reports for one destination, so other tests sharing the global metrics don't interfere
+    #[derive(Default)]
+    struct Reported(Mutex<(Vec<Duration>, u64)>);
+
+    impl EventfulMetrics for Reported {
+        fn observe_publish_attempt(&self, topic: &str, duration: Duration) {
+            if topic == "pubstats-metrics" {
+                self.0.lock().unwrap().0.push(duration);
+            }
+        }
+
+        fn add_published_bytes(&self, topic: &str, bytes: u64) {
+            if topic == "pubstats-metrics" {
+                self.0.lock().unwrap().1 += bytes;
+            }
+        }
+    }
+
+    #[test]
+    fn latencies_and_bytes_are_reported_through_the_metrics_facade() {
+        let reported = Arc::new(Reported::default());
+        let previous = metrics::global();
+        metrics::set_global(reported.clone());
+        let stats = PublisherStats::default();
+        stats.record("pubstats-metrics", millis(12), 100, true);
+        stats.record("pubstats-metrics", millis(340), 50, false);
+        metrics::set_global(previous);
+        // a failed attempt's latency is reported, but not its bytes
+        assert_eq!(*reported.0.lock().unwrap(), (vec![millis(12), millis(340)], 100));
+    }
+}
@@ -0,0 +1,414 @@
+//! The quarantine module lets engineers triage dead letters: list them, look inside, and then retry or discard
+//! them one at a time. A Quarantine reads the NSQ topic or SQS queue that an NsqTopicSink or SqsQueueSink writes to.
+//!
+//! Neither backend can read a message without receiving it, so list() and inspect() peek: every message received is
+//! released straight back (NSQ REQ with no delay, or SQS visibility 0) once it has been looked at.
+//! retry() and discard() receive messages the same way until they find the id, and only that message is acked.
+//!
+//! # Limitations
+//! - NSQ: read from a durable channel which exists before messages arrive, i.e. "quarantine". An ephemeral channel
+//!   only sees messages published after it is created, so it would list nothing. Every peek is a delivery,
+//!   so the attempt count nsqd reports grows, and messages in flight to another consumer of the channel aren't seen.
+//!   A requeued message goes to the back of the channel, so a scan stops once it sees an id for the second time.
+//! - SQS: peeked messages are held (invisible) until the scan ends, then released together. The scan ends when a
+//!   receive comes back empty, so a queue which is still filling up may never look empty. Holding messages from a
+//!   FIFO queue blocks their groups until the scan ends.
+//! - Both: a scan reads at most max_messages, and the DLQ is only as ordered as the backend makes it.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::bridge::NsqSource;
+//! # use eventful::quarantine::{Quarantine, QuarantineFilter};
+//! # async fn demo(daemon: Daemon) -> Result<(), EventfulError> {
+//! let quarantine = Quarantine::nsq(NsqSource::new("orders_dlq", "quarantine", vec![daemon.clone()]), Arc::new(daemon));
+//! let filter = QuarantineFilter{error_contains: Some("timeout".to_string()), ..Default::default()};
+//! for message in quarantine.list(&filter).await? {
+//!     println!("{} {} {}", message.id, message.attempt, message.record.map(|r| r.last_error).unwrap_or_default());
+//! }
+//! quarantine.retry("0a1b2c3d4e5f6a7b").await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+#[cfg(feature = "nsq")]
+use crate::bridge::NsqSource;
+use crate::codec::{Codec, JsonCodec};
+use crate::deadletter::DeadLetterRecord;
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Publisher};
+#[cfg(feature = "sqs")]
+use crate::sqs::{SqsApi, SubscriptionSQS};
+use crate::subscriber::{Delivery, Subscriber};
+
+
+/// One message sitting in the quarantine
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuarantinedMessage {
+    /// The id the backend assigned to the dead letter, used by inspect, retry, and discard
+    pub id: String,
+    /// How many times the dead letter itself has been delivered, including peeks
+    pub attempt: u32,
+    /// The dead letter as written by a DeadLetterSink, or None if the body is something else
+    /// (i.e. a message moved by an SQS redrive policy)
+    pub record: Option<DeadLetterRecord>,
+    /// The original message body decoded as JSON, when it is JSON
+    pub payload: Option<Value>,
+    /// The raw body of the dead letter
+    pub body: Bytes,
+}
+
+impl QuarantinedMessage {
+    fn from_delivery(delivery: &Delivery) -> Self {
+        let record: Option<DeadLetterRecord> = JsonCodec.decode(&delivery.body).ok();
+        let original = record.as_ref().map(|record| &record.original_body).unwrap_or(&delivery.body);
+        QuarantinedMessage{
+            id: delivery.message_id.clone().unwrap_or_default(),
+            attempt: delivery.attempt,
+            payload: serde_json::from_slice(original).ok(),
+            record,
+            body: delivery.body.clone(),
+        }
+    }
+}
+
+
+/// Which quarantined messages list() returns. Fields left as None match everything
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuarantineFilter {
+    /// Only dead letters consumed from this destination
+    pub source: Option<Destination>,
+    /// Only dead letters whose last_error contains this text
+    pub error_contains: Option<String>,
+    /// Only dead letters dead-lettered at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Return at most this many messages
+    pub limit: Option<usize>,
+}
+
+impl QuarantineFilter {
+    pub fn matches(&self, message: &QuarantinedMessage) -> bool {
+        let record = message.record.as_ref();
+        let source = match &self.source {
+            Some(source) => record.map(|record| &record.source == source).unwrap_or(false),
+            None => true,
+        };
+        let error = match &self.error_contains {
+            Some(text) => record.map(|record| record.last_error.contains(text.as_str())).unwrap_or(false),
+            None => true,
+        };
+        let since = match self.since {
+            Some(since) => record.map(|record| record.first_seen >= since).unwrap_or(false),
+            None => true,
+        };
+        source && error && since
+    }
+}
+
+
+type Open = Box<dyn Fn() -> Result<Box<dyn Subscriber>, EventfulError> + Send + Sync>;
+
+/// Messages peeked during a scan, and the one it was looking for (if found)
+struct Scan {
+    seen: Vec<QuarantinedMessage>,
+    found: Option<Delivery>,
+}
+
+
+/// A Quarantine inspects, retries, and discards the dead letters in one DLQ topic or queue
+pub struct Quarantine {
+    open: Open,
+    publisher: Arc<dyn Publisher>,
+    /// Hold peeked messages until the scan ends (SQS) rather than releasing each at once (NSQ)
+    hold: bool,
+    idle: Duration,
+    max_messages: usize,
+}
+
+impl Quarantine {
+    /// Read dead letters through subscriptions made by open, and retry them through publisher.
+    /// Peeked messages are released as soon as they are seen
+    pub fn new<F>(open: F, publisher: Arc<dyn Publisher>) -> Self
+    where
+        F: Fn() -> Result<Box<dyn Subscriber>, EventfulError> + Send + Sync + 'static,
+    {
+        Quarantine{open: Box::new(open), publisher, hold: false, idle: Duration::from_secs(2), max_messages: 1_000}
+    }
+
+    /// Read the topic an NsqTopicSink publishes to, from the source's (durable) channel
+    #[cfg(feature = "nsq")]
+    pub fn nsq(source: NsqSource, publisher: Arc<dyn Publisher>) -> Self {
+        Quarantine::new(move || source.subscribe(), publisher)
+    }
+
+    /// Read the queue an SqsQueueSink publishes to (or an SQS redrive DLQ)
+    #[cfg(feature = "sqs")]
+    pub fn sqs(api: Arc<dyn SqsApi>, queue_url: &str, publisher: Arc<dyn Publisher>) -> Self {
+        let queue_url = queue_url.to_string();
+        // one message per receive: a scan which stops at its target would otherwise leave the rest of the batch invisible
+        let open = move || -> Result<Box<dyn Subscriber>, EventfulError> {
+            Ok(Box::new(SubscriptionSQS::from_api(api.clone(), &queue_url).wait_time_seconds(1).max_messages(1)))
+        };
+        Quarantine{hold: true, ..Quarantine::new(open, publisher)}
+    }
+
+    /// A scan ends when no message arrives for this long (default 2 seconds)
+    pub fn idle(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// A scan reads at most this many messages (default 1,000)
+    pub fn max_messages(mut self, max: usize) -> Self {
+        self.max_messages = max.max(1);
+        self
+    }
+
+    /// Peek at the quarantined messages which match the filter, leaving them all in place
+    pub async fn list(&self, filter: &QuarantineFilter) -> Result<Vec<QuarantinedMessage>, EventfulError> {
+        let scan = self.scan(None).await?;
+        let matching = scan.seen.into_iter().filter(|message| filter.matches(message));
+        Ok(match filter.limit {
+            Some(limit) => matching.take(limit).collect(),
+            None => matching.collect(),
+        })
+    }
+
+    /// Peek at one quarantined message, leaving it in place
+    pub async fn inspect(&self, id: &str) -> Result<Option<QuarantinedMessage>, EventfulError> {
+        let scan = self.scan(None).await?;
+        Ok(scan.seen.into_iter().find(|message| message.id == id))
+    }
+
+    /// Republish a quarantined message's original body to where it was consumed from, then remove it from the quarantine.
+    /// If the publish fails the message stays quarantined
+    pub async fn retry(&self, id: &str) -> Result<(), EventfulError> {
+        let delivery = self.take(id).await?;
+        let record = match JsonCodec.decode::<DeadLetterRecord>(&delivery.body) {
+            Ok(record) => record,
+            Err(_) => {
+                delivery.nack(Duration::ZERO).await?;
+                return Err(EventfulError::Destination(format!("quarantined message {} is not a DeadLetterRecord, so where to retry it is unknown", id)))
+            },
+        };
+        match self.publisher.publish_bytes(&record.source, record.original_body.clone(), &record.metadata).await {
+            Ok(_) => delivery.ack().await,
+            Err(err) => {
+                delivery.nack(Duration::ZERO).await?;
+                Err(err)
+            },
+        }
+    }
+
+    /// Remove a quarantined message for good
+    pub async fn discard(&self, id: &str) -> Result<(), EventfulError> {
+        self.take(id).await?.ack().await
+    }
+
+    async fn take(&self, id: &str) -> Result<Delivery, EventfulError> {
+        self.scan(Some(id)).await?.found
+            .ok_or_else(|| EventfulError::Destination(format!("no quarantined message has id {}", id)))
+    }
+
+    /// Receive messages until the target id turns up, the DLQ goes quiet, or a message comes round again,
+    /// releasing everything except the target
+    async fn scan(&self, target: Option<&str>) -> Result<Scan, EventfulError> {
+        let mut subscriber = (self.open)()?;
+        let mut scan = Scan{seen: Vec::new(), found: None};
+        let mut ids = HashSet::new();
+        let mut held = Vec::new();
+        let result = loop {
+            if scan.seen.len() >= self.max_messages {
+                break Ok(())
+            }
+            let delivery = match tokio::time::timeout(self.idle, subscriber.next()).await {
+                Ok(Ok(Some(delivery))) => delivery,
+                Ok(Ok(None)) | Err(_) => break Ok(()),
+                Ok(Err(err)) => break Err(err),
+            };
+            let message = QuarantinedMessage::from_delivery(&delivery);
+            if target == Some(message.id.as_str()) {
+                scan.found = Some(delivery);
+                break Ok(())
+            }
+            let repeat = !ids.insert(message.id.clone());
+            match self.hold {
+                true => held.push(delivery),
+                false => delivery.nack(Duration::ZERO).await?,
+            }
+            if repeat {
+                break Ok(())
+            }
+            scan.seen.push(message);
+        };
+        for delivery in held {
+            let _ = delivery.nack(Duration::ZERO).await;
+        }
+        result.map(|_| scan)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deadletter::{DeadLetterSink, NsqTopicSink};
+    use crate::memory::MemoryBroker;
+    use crate::publisher::Metadata;
+
+    fn orders() -> Destination {
+        Destination::NsqTopic("orders".to_string())
+    }
+
+    fn record(order_id: u32, last_error: &str) -> DeadLetterRecord {
+        DeadLetterRecord{
+            source: orders(),
+            original_body: Bytes::from(format!(r#"{{"order_id":{}}}"#, order_id)),
+            attempts: 5,
+            last_error: last_error.to_string(),
+            first_seen: Utc::now(),
+            metadata: Metadata{group_id: Some(format!("customer-{}", order_id)), ..Default::default()},
+        }
+    }
+
+    /// A MemoryBroker whose orders_dlq topic holds dead letters for orders 1 (a timeout), 2 (bad data), and 3 (a timeout),
+    /// and a Quarantine reading it which retries to the same broker
+    async fn quarantined() -> (MemoryBroker, Quarantine) {
+        let broker = MemoryBroker::new();
+        let sink = NsqTopicSink::new(Arc::new(broker.clone()), "orders_dlq");
+        for (order_id, error) in [(1, "timeout calling payments"), (2, "missing field `sku`"), (3, "timeout calling stock")] {
+            sink.send(record(order_id, error)).await.unwrap();
+        }
+        let dlq = broker.clone();
+        let open = move || -> Result<Box<dyn Subscriber>, EventfulError> {
+            Ok(Box::new(dlq.subscribe(&Destination::NsqTopic("orders_dlq".to_string()), "quarantine")))
+        };
+        let quarantine = Quarantine::new(open, Arc::new(broker.clone())).idle(Duration::from_millis(100));
+        (broker, quarantine)
+    }
+
+    /// The order ids in the messages, sorted, since a requeued NSQ message goes to the back of the channel
+    fn order_ids(messages: &[QuarantinedMessage]) -> Vec<u64> {
+        let mut ids: Vec<u64> = messages.iter().map(|message| message.payload.as_ref().unwrap()["order_id"].as_u64().unwrap()).collect();
+        ids.sort();
+        ids
+    }
+
+    async fn all(quarantine: &Quarantine) -> Vec<QuarantinedMessage> {
+        quarantine.list(&QuarantineFilter::default()).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn listing_filters_and_leaves_every_message_in_place() {
+        let (_, quarantine) = quarantined().await;
+        let listed = all(&quarantine).await;
+        assert_eq!(order_ids(&listed), vec![1, 2, 3]);
+        assert_eq!(listed[1].record.as_ref().unwrap().last_error, "missing field `sku`");
+        assert!(listed.iter().all(|message| message.attempt == 1));
+
+        let timeouts = QuarantineFilter{error_contains: Some("timeout".to_string()), ..Default::default()};
+        assert_eq!(order_ids(&quarantine.list(&timeouts).await.unwrap()), vec![1, 3]);
+        let first = QuarantineFilter{limit: Some(1), ..Default::default()};
+        assert_eq!(quarantine.list(&first).await.unwrap().len(), 1);
+        let elsewhere = QuarantineFilter{source: Some(Destination::NsqTopic("refunds".to_string())), ..Default::default()};
+        assert!(quarantine.list(&elsewhere).await.unwrap().is_empty());
+
+        // every peek is a delivery, and every message is still there
+        let relisted = all(&quarantine).await;
+        assert_eq!(order_ids(&relisted), vec![1, 2, 3]);
+        assert!(relisted.iter().all(|message| message.attempt > 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn inspect_finds_a_message_by_id() {
+        let (_, quarantine) = quarantined().await;
+        let id = all(&quarantine).await[2].id.clone();
+        let message = quarantine.inspect(&id).await.unwrap().unwrap();
+        assert_eq!(message.record.unwrap().last_error, "timeout calling stock");
+        assert_eq!(quarantine.inspect("no-such-id").await.unwrap(), None);
+        assert_eq!(all(&quarantine).await.len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_republishes_the_original_and_releases_it_from_quarantine() {
+        let (broker, quarantine) = quarantined().await;
+        let mut orders = broker.subscribe(&orders(), "fulfilment");
+        let id = all(&quarantine).await[1].id.clone();
+
+        quarantine.retry(&id).await.unwrap();
+        let retried = orders.next().await.unwrap().unwrap();
+        assert_eq!(retried.body, record(2, "").original_body);
+        assert_eq!(retried.meta, record(2, "").metadata);
+        assert_eq!(order_ids(&all(&quarantine).await), vec![1, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_retry_leaves_the_message_quarantined() {
+        let (broker, quarantine) = quarantined().await;
+        let id = all(&quarantine).await[0].id.clone();
+        broker.fail_next_publish();
+        assert!(quarantine.retry(&id).await.is_err());
+        assert!(broker.published_to("orders").is_empty());
+        assert_eq!(order_ids(&all(&quarantine).await), vec![1, 2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discard_removes_a_message_without_republishing_it() {
+        let (broker, quarantine) = quarantined().await;
+        let id = all(&quarantine).await[0].id.clone();
+        quarantine.discard(&id).await.unwrap();
+        assert!(broker.published_to("orders").is_empty());
+        assert_eq!(order_ids(&all(&quarantine).await), vec![2, 3]);
+        assert!(matches!(quarantine.discard(&id).await, Err(EventfulError::Destination(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_message_which_is_not_a_dead_letter_record_can_be_discarded_but_not_retried() {
+        let broker = MemoryBroker::new();
+        let dlq = Destination::NsqTopic("orders_dlq".to_string());
+        broker.publish_bytes(&dlq, Bytes::from(r#"{"order_id":9}"#), &Metadata::default()).await.unwrap();
+        let reader = broker.clone();
+        let open = move || -> Result<Box<dyn Subscriber>, EventfulError> { Ok(Box::new(reader.subscribe(&dlq, "quarantine"))) };
+        let quarantine = Quarantine::new(open, Arc::new(broker.clone())).idle(Duration::from_millis(100));
+
+        let listed = all(&quarantine).await;
+        assert_eq!((listed[0].record.clone(), order_ids(&listed)), (None, vec![9]));
+        assert!(matches!(quarantine.retry(&listed[0].id).await, Err(EventfulError::Destination(_))));
+        assert_eq!(all(&quarantine).await.len(), 1);
+        quarantine.discard(&listed[0].id).await.unwrap();
+        assert!(all(&quarantine).await.is_empty());
+    }
+
+    #[cfg(all(feature = "sqs", feature = "testing"))]
+    #[tokio::test(start_paused = true)]
+    async fn sqs_holds_peeked_messages_until_the_scan_ends_then_deletes_what_it_retries() {
+        use crate::deadletter::SqsQueueSink;
+        use crate::testing::FakeSqs;
+
+        const DLQ: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/orders_dlq";
+        let sqs = FakeSqs::new();
+        let sink = SqsQueueSink::new(Arc::new(sqs.clone()), DLQ);
+        for (order_id, error) in [(1, "timeout calling payments"), (2, "missing field `sku`")] {
+            sink.send(record(order_id, error)).await.unwrap();
+        }
+        let broker = MemoryBroker::new();
+        let quarantine = Quarantine::sqs(Arc::new(sqs.clone()), DLQ, Arc::new(broker.clone())).idle(Duration::from_millis(100));
+
+        let listed = all(&quarantine).await;
+        assert_eq!(order_ids(&listed), vec![1, 2]);
+        assert_eq!(sqs.in_flight(DLQ), 0);
+
+        quarantine.retry(&listed[0].id).await.unwrap();
+        assert_eq!(broker.published_to("orders"), vec![record(1, "").original_body]);
+        assert_eq!(sqs.deleted(DLQ), 1);
+        assert_eq!(order_ids(&all(&quarantine).await), vec![2]);
+    }
+}
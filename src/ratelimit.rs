@@ -0,0 +1,356 @@
+//! The ratelimit module keeps publishers within a destination's budget, i.e. an SQS queue shared with other teams
+//! or a downstream consumer which can only take so many events per second.
+//!
+//! RateLimitedPublisher wraps any Publisher with a token bucket per destination. Each bucket holds up to burst tokens
+//! and refills at per_second tokens a second; every publish takes one. Destinations without an override share the
+//! default limit (each with its own bucket). When a bucket is empty the publisher either waits for a token
+//! (WhenLimited::Wait, the default) or fails immediately with EventfulError::RateLimited. Either way the throttled
+//! publish is counted with the metrics' inc_throttled. Clones share their buckets, so one limit can be enforced across
+//! every task publishing through them.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "sqs")]
+//! # mod example {
+//! # use std::time::Duration;
+//! # use eventful::prelude::*;
+//! # use eventful::ratelimit::{RateLimitedPublisher, WhenLimited};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct OrderPlaced { order_id: u64 }
+//! # struct Outbox;
+//! # impl Outbox { async fn defer(&self, _order: OrderPlaced, _after: Duration) -> Result<(), EventfulError> { Ok(()) } }
+//! # async fn demo(sqs: ClientSQS, partner_queue: String, dest: Destination, order: OrderPlaced, outbox: Outbox) -> Result<(), EventfulError> {
+//! let publisher = RateLimitedPublisher::new(sqs, 100.0, 20)
+//!     .limit(Destination::SqsQueue(partner_queue), 5.0, 1)
+//!     .when_limited(WhenLimited::Reject);
+//! match publisher.publish_event(&dest, &order).await {
+//!     Err(EventfulError::RateLimited{retry_after, ..}) => outbox.defer(order, retry_after).await?,
+//!     other => { other?; },
+//! }
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::time::Instant;
+use crate::err::EventfulError;
+use crate::metrics;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+
+
+/// What to do with a publish when its destination's bucket is empty
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WhenLimited {
+    /// Wait for a token, then publish
+    #[default]
+    Wait,
+    /// Fail with EventfulError::RateLimited, saying when a token will be available
+    Reject,
+}
+
+
+/// A sustained rate, and how many publishes can go at once after a quiet spell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl Limit {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Limit{per_second: per_second.max(f64::EPSILON), burst: burst.max(1)}
+    }
+}
+
+
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(limit: Limit, now: Instant) -> Self {
+        Bucket{limit, tokens: limit.burst as f64, refilled_at: now}
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.refilled_at = now;
+    }
+
+    /// How long until the bucket holds a whole token
+    fn wait(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.limit.per_second).max(0.0))
+    }
+}
+
+
+struct Limiter {
+    default: Limit,
+    overrides: HashMap<Destination, Limit>,
+    when_limited: WhenLimited,
+    buckets: Mutex<HashMap<Destination, Bucket>>,
+}
+
+impl Limiter {
+    /// Take a token, returning how long the caller must wait before using it.
+    /// Waiting callers take their token up front (the bucket goes negative), so they are served in order
+    fn acquire(&self, dest: &Destination) -> Result<Duration, EventfulError> {
+        let now = Instant::now();
+        let limit = self.overrides.get(dest).copied().unwrap_or(self.default);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(dest.clone()).or_insert_with(|| Bucket::new(limit, now));
+        bucket.refill(now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(Duration::ZERO)
+        }
+        let wait = bucket.wait();
+        if self.when_limited == WhenLimited::Reject {
+            return Err(EventfulError::RateLimited{destination: dest.to_string(), retry_after: wait})
+        }
+        bucket.tokens -= 1.0;
+        Ok(wait)
+    }
+}
+
+
+/// A RateLimitedPublisher forwards to an inner publisher no faster than each destination's token bucket allows
+pub struct RateLimitedPublisher<P> {
+    inner: Arc<P>,
+    limiter: Arc<Limiter>,
+}
+
+/// Clones share the inner publisher and the buckets
+impl<P> Clone for RateLimitedPublisher<P> {
+    fn clone(&self) -> Self {
+        RateLimitedPublisher{inner: self.inner.clone(), limiter: self.limiter.clone()}
+    }
+}
+
+impl<P: Publisher> RateLimitedPublisher<P> {
+    /// Limit every destination to per_second publishes a second, with bursts of up to burst
+    pub fn new(inner: P, per_second: f64, burst: u32) -> Self {
+        RateLimitedPublisher{
+            inner: Arc::new(inner),
+            limiter: Arc::new(Limiter{
+                default: Limit::new(per_second, burst),
+                overrides: HashMap::new(),
+                when_limited: WhenLimited::default(),
+                buckets: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Give one destination its own limit instead of the default. Call before cloning or publishing
+    pub fn limit(mut self, dest: Destination, per_second: f64, burst: u32) -> Self {
+        self.limiter_mut().overrides.insert(dest, Limit::new(per_second, burst));
+        self
+    }
+
+    /// Wait for a token (the default) or reject when a bucket is empty
+    pub fn when_limited(mut self, when_limited: WhenLimited) -> Self {
+        self.limiter_mut().when_limited = when_limited;
+        self
+    }
+
+    /// Wait for (or fail to get) a token for the destination
+    async fn throttle(&self, dest: &Destination) -> Result<(), EventfulError> {
+        let wait = match self.limiter.acquire(dest) {
+            Ok(wait) => wait,
+            Err(err) => {
+                metrics::global().inc_throttled(dest.name());
+                return Err(err)
+            },
+        };
+        if !wait.is_zero() {
+            metrics::global().inc_throttled(dest.name());
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    /// Builders run before the limiter is shared, so it can be changed in place; after that it is copied
+    fn limiter_mut(&mut self) -> &mut Limiter {
+        if Arc::get_mut(&mut self.limiter).is_none() {
+            let limiter = &self.limiter;
+            self.limiter = Arc::new(Limiter{
+                default: limiter.default,
+                overrides: limiter.overrides.clone(),
+                when_limited: limiter.when_limited,
+                buckets: Mutex::new(HashMap::new()),
+            });
+        }
+        Arc::get_mut(&mut self.limiter).expect("the limiter was just copied")
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for RateLimitedPublisher<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        self.throttle(dest).await?;
+        self.inner.publish_bytes(dest, body, meta).await
+    }
+
+    fn max_delay(&self) -> Option<Duration> {
+        self.inner.max_delay()
+    }
+
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        self.throttle(dest).await?;
+        self.inner.publish_delayed(dest, body, meta, delay).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBroker;
+    use crate::metrics::EventfulMetrics;
+
+    fn queue(name: &str) -> Destination {
+        Destination::SqsQueue(name.to_string())
+    }
+
+    async fn publish<P: Publisher>(publisher: &P, dest: &Destination) -> Result<Receipt, EventfulError> {
+        let meta = Metadata::default();
+        publisher.publish_bytes(dest, Bytes::from_static(b"{}"), &meta).await
+    }
+
+    /// Assert a paused clock moved by `expected`, give or take a millisecond of rounding in the token arithmetic
+    fn assert_elapsed(started: Instant, expected: Duration) {
+        let elapsed = started.elapsed();
+        assert!(elapsed + Duration::from_millis(1) >= expected && elapsed <= expected + Duration::from_millis(1),
+            "expected {:?}, took {:?}", expected, elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_immediate_then_the_sustained_rate_holds() {
+        let broker = MemoryBroker::new();
+        let publisher = RateLimitedPublisher::new(broker.clone(), 10.0, 5);
+        let started = Instant::now();
+        for _ in 0..5 {
+            publish(&publisher, &queue("orders")).await.unwrap();
+        }
+        assert_elapsed(started, Duration::ZERO);
+        for _ in 0..20 {
+            publish(&publisher, &queue("orders")).await.unwrap();
+        }
+        // 20 more at 10 a second
+        assert_elapsed(started, Duration::from_secs(2));
+        assert_eq!(broker.published_to("orders").len(), 25);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_quiet_spell_refills_only_up_to_the_burst() {
+        let publisher = RateLimitedPublisher::new(MemoryBroker::new(), 10.0, 5);
+        for _ in 0..5 {
+            publish(&publisher, &queue("orders")).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        let started = Instant::now();
+        for _ in 0..10 {
+            publish(&publisher, &queue("orders")).await.unwrap();
+        }
+        assert_elapsed(started, Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clones_share_their_buckets() {
+        let broker = MemoryBroker::new();
+        let publisher = RateLimitedPublisher::new(broker.clone(), 20.0, 5);
+        let started = Instant::now();
+        let tasks = (0..4).map(|_| {
+            let publisher = publisher.clone();
+            tokio::spawn(async move {
+                for _ in 0..10 {
+                    publish(&publisher, &queue("orders")).await.unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // 40 publishes, 5 of them from the burst, the rest at 20 a second across all the clones
+        assert_elapsed(started, Duration::from_millis(1750));
+        assert_eq!(broker.published_to("orders").len(), 40);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reject_says_when_to_retry() {
+        let broker = MemoryBroker::new();
+        let publisher = RateLimitedPublisher::new(broker.clone(), 4.0, 2).when_limited(WhenLimited::Reject);
+        publish(&publisher, &queue("orders")).await.unwrap();
+        publish(&publisher, &queue("orders")).await.unwrap();
+        let retry_after = match publish(&publisher, &queue("orders")).await {
+            Err(EventfulError::RateLimited{destination, retry_after}) => {
+                assert_eq!(destination, queue("orders").to_string());
+                retry_after
+            },
+            other => panic!("expected RateLimited, got {:?}", other),
+        };
+        assert_eq!(retry_after, Duration::from_millis(250));
+        assert_eq!(broker.published_to("orders").len(), 2);
+        tokio::time::sleep(retry_after).await;
+        publish(&publisher, &queue("orders")).await.unwrap();
+        assert_eq!(broker.published_to("orders").len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn overrides_apply_only_to_their_destination() {
+        let publisher = RateLimitedPublisher::new(MemoryBroker::new(), 100.0, 10)
+            .limit(queue("partner"), 1.0, 1)
+            .when_limited(WhenLimited::Reject);
+        publish(&publisher, &queue("partner")).await.unwrap();
+        assert!(matches!(publish(&publisher, &queue("partner")).await, Err(EventfulError::RateLimited{..})));
+        for _ in 0..10 {
+            publish(&publisher, &queue("orders")).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn builders_after_cloning_leave_the_clone_alone() {
+        let publisher = RateLimitedPublisher::new(MemoryBroker::new(), 10.0, 5);
+        let clone = publisher.clone();
+        let publisher = publisher.when_limited(WhenLimited::Reject);
+        assert_eq!(publisher.limiter.when_limited, WhenLimited::Reject);
+        assert_eq!(clone.limiter.when_limited, WhenLimited::Wait);
+    }
+
+    /// Counts throttled publishes to one topic, so other tests sharing the global metrics don't interfere
+    struct CountThrottled(&'static str, Mutex<u32>);
+
+    impl EventfulMetrics for CountThrottled {
+        fn inc_throttled(&self, topic: &str) {
+            if topic == self.0 {
+                *self.1.lock().unwrap() += 1;
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_publishes_are_counted() {
+        let counter = Arc::new(CountThrottled("ratelimit-metrics", Mutex::new(0)));
+        metrics::set_global(counter.clone());
+        let dest = queue("ratelimit-metrics");
+        let waiting = RateLimitedPublisher::new(MemoryBroker::new(), 10.0, 1);
+        for _ in 0..3 {
+            publish(&waiting, &dest).await.unwrap();
+        }
+        let rejecting = RateLimitedPublisher::new(MemoryBroker::new(), 10.0, 1).when_limited(WhenLimited::Reject);
+        publish(&rejecting, &dest).await.unwrap();
+        assert!(publish(&rejecting, &dest).await.is_err());
+        // two waits and one rejection
+        assert_eq!(*counter.1.lock().unwrap(), 3);
+    }
+}
@@ -0,0 +1,341 @@
+//! The recording module captures a slice of real published traffic and plays it back elsewhere, i.e. into a test environment.
+//! A RecordingPublisher wraps any Publisher and records each successful publish as an ArchivedEvent (in memory, and
+//! optionally appended to an ndjson file in the format replay_ndjson reads, on a background thread so publishing never
+//! waits on the disk). A redaction hook sees every event before it
+//! is kept or written, so PII can be masked or removed. replay_recording() republishes a Recording with the original gaps
+//! between events, scaled by a speed factor.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::recording::{replay_recording, RecordingPublisher};
+//! # async fn demo(fleet: FleetNSQ, staging_publisher: Arc<dyn Publisher>) -> Result<(), EventfulError> {
+//! let recorder = Arc::new(RecordingPublisher::new(fleet)
+//!     .max_events(5_000)
+//!     .ndjson_file("orders-sample.ndjson")
+//!     .redact(|event| { event.metadata.headers.remove("customer_email"); }));
+//! // publish through recorder for a while...
+//! recorder.stop();
+//! let recording = recorder.export();
+//! // later, in the test environment, at ten times the original pace
+//! replay_recording(&recording, &staging_publisher, 10.0).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::Utc;
+use tokio::time::Instant;
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::replay::{ArchivedEvent, ReplayReport};
+use crate::writer::FileWriter;
+
+
+/// A recorded slice of traffic, oldest first
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recording {
+    pub events: Vec<ArchivedEvent>,
+}
+
+impl Recording {
+    /// Read a recording from an ndjson file, i.e. one written by RecordingPublisher::ndjson_file or the audit sink
+    pub fn from_ndjson<P: AsRef<Path>>(path: P) -> Result<Self, EventfulError> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut events = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            events.push(serde_json::from_str(line)?);
+        }
+        Ok(Recording{events})
+    }
+
+    /// Write the recording as ndjson, replacing the file
+    pub fn to_ndjson<P: AsRef<Path>>(&self, path: P) -> Result<(), EventfulError> {
+        let mut contents = Vec::new();
+        for event in &self.events {
+            contents.extend(event.to_line()?);
+        }
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+
+type Redactor = Box<dyn Fn(&mut ArchivedEvent) + Send + Sync>;
+
+/// A RecordingPublisher forwards to an inner publisher and records every successful publish.
+/// It records from creation until stop() (and again after start()), and stops by itself once max_events are held
+pub struct RecordingPublisher<P> {
+    inner: P,
+    max_events: usize,
+    path: Option<PathBuf>,
+    redact: Option<Redactor>,
+    recording: AtomicBool,
+    events: Mutex<Vec<ArchivedEvent>>,
+    writer: Option<FileWriter>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<P: Publisher> RecordingPublisher<P> {
+    /// Record up to 10,000 events in memory
+    pub fn new(inner: P) -> Self {
+        RecordingPublisher{
+            inner,
+            max_events: 10_000,
+            path: None,
+            redact: None,
+            recording: AtomicBool::new(true),
+            events: Mutex::new(Vec::new()),
+            writer: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Stop recording once this many events are held (default 10,000)
+    pub fn max_events(mut self, max: usize) -> Self {
+        self.max_events = max;
+        self
+    }
+
+    /// Also append each recorded event to this ndjson file (see flush)
+    pub fn ndjson_file<T: Into<PathBuf>>(mut self, path: T) -> Self {
+        self.path = Some(path.into());
+        let dropped = self.dropped.clone();
+        self.writer = Some(FileWriter::start("eventful-recording", move |_| {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }));
+        self
+    }
+
+    /// Called on every event before it is kept or written, i.e. to mask fields of the body or drop headers
+    pub fn redact<F: Fn(&mut ArchivedEvent) + Send + Sync + 'static>(mut self, redact: F) -> Self {
+        self.redact = Some(Box::new(redact));
+        self
+    }
+
+    /// Resume recording
+    pub fn start(&self) {
+        self.recording.store(true, Ordering::Relaxed);
+    }
+
+    /// Pause recording. Publishing carries on as normal
+    pub fn stop(&self) {
+        self.recording.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Relaxed)
+    }
+
+    /// A copy of what has been recorded so far
+    pub fn export(&self) -> Recording {
+        Recording{events: self.events.lock().unwrap().clone()}
+    }
+
+    /// Forget what has been recorded in memory (the ndjson file is left alone)
+    pub fn clear(&self) {
+        self.events.lock().unwrap().clear();
+    }
+
+    /// How many events were published while recording but not recorded because the recording was full,
+    /// plus how many could not be written to the ndjson file
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Wait until every event recorded so far has been written to the ndjson file
+    pub async fn flush(&self) {
+        if let Some(writer) = &self.writer {
+            writer.flush().await;
+        }
+    }
+
+    fn record(&self, dest: &Destination, body: Bytes, meta: &Metadata) {
+        if !self.is_recording() {
+            return
+        }
+        let mut event = ArchivedEvent{topic: dest.clone(), body, metadata: meta.clone(), original_timestamp: Utc::now()};
+        if let Some(redact) = &self.redact {
+            redact(&mut event);
+        }
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= self.max_events {
+            self.stop();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return
+        }
+        if let (Some(path), Some(writer)) = (&self.path, &self.writer) {
+            // handed over while the events lock is held, so the file is in the same order as the recording
+            match event.to_line() {
+                Ok(line) => writer.append(path.clone(), Bytes::from(line)),
+                Err(_) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return
+                },
+            }
+        }
+        events.push(event);
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for RecordingPublisher<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let receipt = self.inner.publish_bytes(dest, body.clone(), meta).await?;
+        self.record(dest, body, meta);
+        Ok(receipt)
+    }
+}
+
+
+/// Republish a recording through a publisher, keeping the original gaps between events divided by speed
+/// (2.0 replays twice as fast as real time). A speed of zero or less, or infinity, sends everything without waiting.
+/// Each send is scheduled from the start of the replay, so slow publishes don't push later events back
+pub async fn replay_recording(recording: &Recording, publisher: &dyn Publisher, speed: f64) -> Result<ReplayReport, EventfulError> {
+    let mut report = ReplayReport::default();
+    let first = match recording.events.first() {
+        Some(event) => event.original_timestamp,
+        None => return Ok(report),
+    };
+    let paced = speed > 0.0 && speed.is_finite();
+    let started = Instant::now();
+    for event in &recording.events {
+        if paced {
+            let offset = (event.original_timestamp - first).to_std().unwrap_or(Duration::ZERO);
+            tokio::time::sleep_until(started + offset.div_f64(speed)).await;
+        }
+        match publisher.publish_bytes(&event.topic, event.body.clone(), &event.metadata).await {
+            Ok(_) => report.sent += 1,
+            Err(_) => report.failed += 1,
+        }
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+
+    /// Notes when each publish started, relative to its creation, and takes `latency` to finish
+    struct Timed {
+        started: Instant,
+        latency: Duration,
+        sent: Mutex<Vec<(Duration, Bytes)>>,
+    }
+
+    impl Timed {
+        fn new(latency: Duration) -> Self {
+            Timed{started: Instant::now(), latency, sent: Mutex::new(Vec::new())}
+        }
+
+        fn offsets_ms(&self) -> Vec<u128> {
+            self.sent.lock().unwrap().iter().map(|(offset, _)| offset.as_millis()).collect()
+        }
+    }
+
+    #[async_trait]
+    impl Publisher for Timed {
+        async fn publish_bytes(&self, _dest: &Destination, body: Bytes, _meta: &Metadata) -> Result<Receipt, EventfulError> {
+            self.sent.lock().unwrap().push((self.started.elapsed(), body));
+            tokio::time::sleep(self.latency).await;
+            Ok(Receipt::default())
+        }
+    }
+
+    fn recording(offsets_ms: &[i64]) -> Recording {
+        let start = Utc::now();
+        let events = offsets_ms.iter().map(|offset| ArchivedEvent{
+            topic: Destination::NsqTopic("click".to_string()),
+            body: Bytes::from(offset.to_string()),
+            metadata: Metadata::default(),
+            original_timestamp: start + chrono::Duration::milliseconds(*offset),
+        }).collect();
+        Recording{events}
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replay_keeps_the_original_gaps_divided_by_speed() {
+        let publisher = Timed::new(Duration::ZERO);
+        let report = replay_recording(&recording(&[0, 1000, 3000]), &publisher, 2.0).await.unwrap();
+        assert_eq!(report.sent, 3);
+        assert_eq!(publisher.offsets_ms(), vec![0, 500, 1500]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_publishes_do_not_push_later_events_back() {
+        let publisher = Timed::new(Duration::from_millis(400));
+        replay_recording(&recording(&[0, 1000, 1200, 2000]), &publisher, 1.0).await.unwrap();
+        // 1200 was due while the publish at 1000 was still running, so it goes as soon as that finishes
+        assert_eq!(publisher.offsets_ms(), vec![0, 1000, 1400, 2000]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_speed_of_zero_or_infinity_sends_without_waiting() {
+        for speed in [0.0, -1.0, f64::INFINITY] {
+            let publisher = Timed::new(Duration::ZERO);
+            replay_recording(&recording(&[0, 60_000, 120_000]), &publisher, speed).await.unwrap();
+            assert_eq!(publisher.offsets_ms(), vec![0, 0, 0]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn records_redacted_events_until_full() {
+        let recorder = RecordingPublisher::new(Timed::new(Duration::ZERO))
+            .max_events(2)
+            .redact(|event| event.body = Bytes::from("redacted"));
+        let dest = Destination::NsqTopic("click".to_string());
+        for body in ["a", "b", "c"] {
+            recorder.publish_bytes(&dest, Bytes::from(body), &Metadata::default()).await.unwrap();
+        }
+        assert_eq!(recorder.inner().sent.lock().unwrap().len(), 3);
+        let recording = recorder.export();
+        assert_eq!(recording.events.iter().map(|event| event.body.clone()).collect::<Vec<_>>(), vec![Bytes::from("redacted"); 2]);
+        assert!(!recorder.is_recording());
+        assert_eq!(recorder.dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn the_ndjson_file_matches_the_recording_once_flushed() {
+        let path = std::env::temp_dir().join(format!("eventful-recording-{}.ndjson", rand::random::<u32>()));
+        let recorder = RecordingPublisher::new(Timed::new(Duration::ZERO)).ndjson_file(&path);
+        let dest = Destination::NsqTopic("click".to_string());
+        for n in 0..50 {
+            recorder.publish_bytes(&dest, Bytes::from(n.to_string()), &Metadata::default()).await.unwrap();
+        }
+        recorder.flush().await;
+        assert_eq!(Recording::from_ndjson(&path).unwrap(), recorder.export());
+        assert_eq!(recorder.dropped(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_unwritable_file_counts_as_dropped_without_failing_the_publish() {
+        let path = std::env::temp_dir().join(format!("eventful-recording-missing-{}", rand::random::<u32>())).join("sample.ndjson");
+        let recorder = RecordingPublisher::new(Timed::new(Duration::ZERO)).ndjson_file(&path);
+        let dest = Destination::NsqTopic("click".to_string());
+        recorder.publish_bytes(&dest, Bytes::from("a"), &Metadata::default()).await.unwrap();
+        recorder.flush().await;
+        assert_eq!(recorder.dropped(), 1);
+        assert_eq!(recorder.export().len(), 1);
+    }
+}
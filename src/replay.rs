@@ -0,0 +1,317 @@
+//! The replay module republishes archived events, i.e. after a consumer bug.  
+//! Archives are newline-delimited JSON, one ArchivedEvent per line: the shape written by the audit sink and the
+//! failure journal, so anything they record can be replayed.
+//! 
+//! Replays can be resumed: with a checkpoint file set, the byte offset of the next unread line is saved as
+//! the replay progresses, and a later replay with the same checkpoint file starts from there.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use eventful::prelude::*;
+//! # use eventful::replay::{replay_ndjson, ReplayOptions};
+//! # async fn demo(publisher: Box<dyn Publisher>) -> Result<(), Box<dyn std::error::Error>> {
+//! let file = tokio::fs::File::open("clicks-2023-03-14.ndjson").await?;
+//! let options = ReplayOptions::default()
+//!     .rate(200.0)?
+//!     .filter(|event| event.topic == Destination::NsqTopic("click".to_string()))
+//!     .checkpoint("clicks-2023-03-14.checkpoint");
+//! let report = replay_ndjson(file, &publisher, options).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{io::SeekFrom, path::PathBuf, time::Duration};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncSeek, AsyncSeekExt, BufReader}, time::Instant};
+use crate::codec;
+use crate::err::EventfulError;
+use crate::publisher::{Destination, Metadata, Publisher};
+
+
+/// One line of an ndjson archive
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedEvent {
+    /// Where the event was originally published
+    pub topic: Destination,
+    /// The body as it was published (base64 encoded when serialized)
+    #[serde(with = "codec::base64_bytes")]
+    pub body: Bytes,
+    #[serde(default)]
+    pub metadata: Metadata,
+    /// When the event was originally published
+    pub original_timestamp: DateTime<Utc>,
+}
+
+impl ArchivedEvent {
+    /// Encode as one line of ndjson, including the trailing newline
+    pub fn to_line(&self) -> Result<Vec<u8>, EventfulError> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+
+/// What a replay did, also passed to the on_progress callback as it goes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Events republished successfully
+    pub sent: u64,
+    /// Events whose publish failed, plus lines which could not be parsed
+    pub failed: u64,
+    /// Events rejected by the filter
+    pub skipped: u64,
+}
+
+
+type Filter = Box<dyn Fn(&ArchivedEvent) -> bool + Send + Sync>;
+type Progress = Box<dyn Fn(&ReplayReport, u64) + Send + Sync>;
+
+/// Options for replay_ndjson
+pub struct ReplayOptions {
+    rate: Option<f64>,
+    time_compression: Option<f64>,
+    filter: Option<Filter>,
+    on_progress: Option<Progress>,
+    checkpoint: Option<PathBuf>,
+    checkpoint_every: u64,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions{rate: None, time_compression: None, filter: None, on_progress: None, checkpoint: None, checkpoint_every: 100}
+    }
+}
+
+impl ReplayOptions {
+    /// Publish at most this many events per second. The rate must be positive (and not so small that the gap
+    /// between events can't be represented), otherwise this fails with Config
+    pub fn rate(mut self, events_per_sec: f64) -> Result<Self, EventfulError> {
+        let valid = events_per_sec.is_finite() && events_per_sec > 0.0 && Duration::try_from_secs_f64(1.0 / events_per_sec).is_ok();
+        if !valid {
+            return Err(EventfulError::Config(format!("replay rate must be a positive number of events per second, not {}", events_per_sec)))
+        }
+        self.rate = Some(events_per_sec);
+        Ok(self)
+    }
+
+    /// Reproduce the original gaps between events, divided by this factor (2.0 replays twice as fast as real time).
+    /// The factor must be positive and finite, otherwise this fails with Config
+    pub fn time_compression(mut self, factor: f64) -> Result<Self, EventfulError> {
+        if !(factor.is_finite() && factor > 0.0) {
+            return Err(EventfulError::Config(format!("replay time compression must be a positive factor, not {}", factor)))
+        }
+        self.time_compression = Some(factor);
+        Ok(self)
+    }
+
+    /// Only replay events for which the filter returns true, i.e. by topic or time range
+    pub fn filter<F: Fn(&ArchivedEvent) -> bool + Send + Sync + 'static>(mut self, filter: F) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Called after each line with the report so far and the byte offset reached
+    pub fn on_progress<F: Fn(&ReplayReport, u64) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Save progress to this file, and resume from it if it already exists
+    pub fn checkpoint<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// How many lines between checkpoint saves (default 100). The checkpoint is always saved at the end
+    pub fn checkpoint_every(mut self, lines: u64) -> Self {
+        self.checkpoint_every = lines.max(1);
+        self
+    }
+}
+
+
+pub(crate) async fn read_checkpoint(path: &PathBuf) -> Result<u64, EventfulError> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents.trim().parse::<u64>()
+            .map_err(|_| EventfulError::Config(format!("checkpoint file {:?} does not contain a byte offset", path))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write the checkpoint to a temporary file and rename it into place, so a crash never leaves a torn checkpoint
+pub(crate) async fn write_checkpoint(path: &PathBuf, offset: u64) -> Result<(), EventfulError> {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, offset.to_string()).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+
+/// Republish every event in an ndjson archive through a publisher.
+/// Lines which can't be parsed are counted as failed and skipped over
+pub async fn replay_ndjson<R>(reader: R, publisher: &dyn Publisher, options: ReplayOptions) -> Result<ReplayReport, EventfulError>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut offset = match &options.checkpoint {
+        Some(path) => read_checkpoint(path).await?,
+        None => 0,
+    };
+    reader.seek(SeekFrom::Start(offset)).await?;
+
+    let mut report = ReplayReport::default();
+    let mut line = Vec::new();
+    let mut lines_since_checkpoint = 0;
+    let mut next_send = Instant::now();
+    let mut previous_timestamp: Option<DateTime<Utc>> = None;
+    loop {
+        line.clear();
+        let read = reader.read_until(b'\n', &mut line).await?;
+        if read == 0 {
+            break
+        }
+        offset += read as u64;
+        match std::str::from_utf8(&line) {
+            Ok(line) if line.trim().is_empty() => {},
+            Ok(line) => replay_line(line, publisher, &options, &mut report, &mut next_send, &mut previous_timestamp).await,
+            // not text, so it can't be an ArchivedEvent
+            Err(_) => report.failed += 1,
+        }
+        if let Some(callback) = &options.on_progress {
+            callback(&report, offset);
+        }
+        lines_since_checkpoint += 1;
+        if let Some(path) = &options.checkpoint {
+            if lines_since_checkpoint >= options.checkpoint_every {
+                write_checkpoint(path, offset).await?;
+                lines_since_checkpoint = 0;
+            }
+        }
+    }
+    if let Some(path) = &options.checkpoint {
+        write_checkpoint(path, offset).await?;
+    }
+    Ok(report)
+}
+
+
+/// The longest replay_line waits between two events, about 30 years
+const LONGEST_WAIT: Duration = Duration::from_secs(86400 * 365 * 30);
+
+async fn replay_line(line: &str, publisher: &dyn Publisher, options: &ReplayOptions, report: &mut ReplayReport, next_send: &mut Instant, previous_timestamp: &mut Option<DateTime<Utc>>) {
+    let event: ArchivedEvent = match serde_json::from_str(line) {
+        Ok(event) => event,
+        Err(_) => {
+            report.failed += 1;
+            return
+        },
+    };
+    if let Some(filter) = &options.filter {
+        if !filter(&event) {
+            report.skipped += 1;
+            return
+        }
+    }
+    // wait for whichever is later: the rate limit, or the compressed original gap
+    let mut wait_until = *next_send;
+    if let (Some(factor), Some(previous)) = (options.time_compression, *previous_timestamp) {
+        let gap = (event.original_timestamp - previous).to_std().unwrap_or(Duration::ZERO);
+        // a tiny factor can stretch a gap past what a Duration or Instant holds, so saturate at a very long wait
+        let gap = Duration::try_from_secs_f64(gap.as_secs_f64() / factor).unwrap_or(Duration::MAX);
+        let now = Instant::now();
+        wait_until = wait_until.max(now.checked_add(gap).unwrap_or_else(|| now + LONGEST_WAIT));
+    }
+    tokio::time::sleep_until(wait_until).await;
+    if let Some(rate) = options.rate {
+        // ReplayOptions::rate made sure the gap fits in a Duration
+        let now = Instant::now();
+        *next_send = now.checked_add(Duration::from_secs_f64(1.0 / rate)).unwrap_or_else(|| now + LONGEST_WAIT);
+    }
+    *previous_timestamp = Some(event.original_timestamp);
+    match publisher.publish_bytes(&event.topic, event.body, &event.metadata).await {
+        Ok(_) => report.sent += 1,
+        Err(_) => report.failed += 1,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::memory::MemoryBroker;
+
+    fn event(n: u32, at: DateTime<Utc>) -> ArchivedEvent {
+        ArchivedEvent{topic: Destination::NsqTopic("click".to_string()), body: Bytes::from(n.to_string()), metadata: Metadata::default(), original_timestamp: at}
+    }
+
+    fn archive(events: &[ArchivedEvent]) -> Vec<u8> {
+        events.iter().flat_map(|event| event.to_line().unwrap()).collect()
+    }
+
+    fn checkpoint_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("eventful-replay-{}-{}.checkpoint", name, rand::random::<u32>()))
+    }
+
+    #[test]
+    fn rejects_rates_and_compressions_that_are_not_positive() {
+        for bad in [0.0, -1.0, f64::NAN, f64::INFINITY, f64::MIN_POSITIVE] {
+            assert!(matches!(ReplayOptions::default().rate(bad), Err(EventfulError::Config(_))), "rate {}", bad);
+        }
+        for bad in [0.0, -2.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(ReplayOptions::default().time_compression(bad), Err(EventfulError::Config(_))), "factor {}", bad);
+        }
+        assert!(ReplayOptions::default().rate(200.0).unwrap().time_compression(0.5).is_ok());
+    }
+
+    #[tokio::test]
+    async fn unparseable_and_non_utf8_lines_are_counted_as_failed() {
+        let broker = MemoryBroker::new();
+        let now = Utc::now();
+        let mut data = archive(&[event(0, now)]);
+        data.extend_from_slice(b"\xff\xfe not text\n");
+        data.extend_from_slice(b"{\"not\": \"an event\"}\n\n");
+        data.extend(archive(&[event(1, now)]));
+
+        let report = replay_ndjson(Cursor::new(data), &broker, ReplayOptions::default()).await.unwrap();
+        assert_eq!(report, ReplayReport{sent: 2, failed: 2, skipped: 0});
+        assert_eq!(broker.published_to("click"), vec![Bytes::from("0"), Bytes::from("1")]);
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_checkpoint() {
+        let now = Utc::now();
+        let events: Vec<_> = (0..5).map(|n| event(n, now)).collect();
+        let data = archive(&events);
+        let checkpoint = checkpoint_path("resume");
+        // a replay stopped after the first two lines
+        write_checkpoint(&checkpoint, archive(&events[..2]).len() as u64).await.unwrap();
+
+        let resumed = MemoryBroker::new();
+        let report = replay_ndjson(Cursor::new(data.clone()), &resumed, ReplayOptions::default().checkpoint(&checkpoint)).await.unwrap();
+        assert_eq!(report.sent, 3);
+        assert_eq!(resumed.published_to("click"), vec![Bytes::from("2"), Bytes::from("3"), Bytes::from("4")]);
+        assert_eq!(read_checkpoint(&checkpoint).await.unwrap(), data.len() as u64);
+
+        // finished, so running again replays nothing
+        let again = replay_ndjson(Cursor::new(data), &resumed, ReplayOptions::default().checkpoint(&checkpoint)).await.unwrap();
+        assert_eq!(again, ReplayReport::default());
+        tokio::fs::remove_file(&checkpoint).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn time_compression_divides_the_original_gaps() {
+        let broker = MemoryBroker::new();
+        let start = Utc::now();
+        let data = archive(&[event(0, start), event(1, start + chrono::Duration::seconds(10))]);
+        let began = Instant::now();
+        replay_ndjson(Cursor::new(data), &broker, ReplayOptions::default().time_compression(2.0).unwrap()).await.unwrap();
+        assert_eq!(began.elapsed().as_secs(), 5);
+    }
+}
@@ -0,0 +1,457 @@
+//! The retry module holds the one RetryPolicy used wherever eventful retries something:
+//! publishing, requeueing, SQS throttling, the outbox relay, the bridge...  
+//! execute_with_retry runs any async operation returning `Result<T, EventfulError>` under a policy.
+//! How long a policy waits between attempts is its Backoff, which can also be used on its own and read from config files.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use bytes::Bytes;
+//! # use eventful::prelude::*;
+//! # use eventful::retry::execute_with_retry;
+//! # async fn demo(publisher: &dyn Publisher, dest: Destination, body: Bytes, meta: Metadata, config: String) -> Result<(), Box<dyn std::error::Error>> {
+//! let policy = RetryPolicy::default().max_attempts(5);
+//! let receipt = execute_with_retry(&policy, || publisher.publish_bytes(&dest, body.clone(), &meta)).await?;
+//!
+//! // {"exponential_jitter": {"base": 100, "factor": 2.0, "max": 10000, "jitter": "full"}}
+//! let backoff: Backoff = serde_json::from_str(&config)?;
+//! let policy = RetryPolicy::default().backoff(backoff);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+use rand::{Rng, RngCore};
+use serde::{Serialize, Deserialize};
+use crate::codec::duration_millis;
+use crate::err::EventfulError;
+use crate::rng::SharedRng;
+
+
+/// How much randomness to add to each delay, so many clients retrying at once don't stay in lockstep
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Jitter {
+    /// Use the computed delay as-is
+    None,
+    /// Pick uniformly between zero and the computed delay
+    Full,
+    /// Pick uniformly between half the computed delay and the computed delay
+    Equal,
+}
+
+impl Jitter {
+    fn apply<R: Rng + ?Sized>(&self, delay: Duration, rng: &mut R) -> Duration {
+        match self {
+            Jitter::None => delay,
+            Jitter::Full => delay.mul_f64(rng.gen_range(0.0..=1.0)),
+            Jitter::Equal => delay.mul_f64(rng.gen_range(0.5..=1.0)),
+        }
+    }
+}
+
+
+/// A Backoff decides how long to wait before each retry. Durations are whole milliseconds when serialized,
+/// and variants are written in snake_case, i.e. `{"fixed": 500}` or `{"exponential": {"base": 100, "factor": 2.0, "max": 10000}}`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backoff {
+    /// The same delay every time
+    Fixed(#[serde(with = "duration_millis")] Duration),
+    /// base, then base * factor, base * factor^2... up to max
+    Exponential {
+        #[serde(with = "duration_millis")]
+        base: Duration,
+        factor: f64,
+        #[serde(with = "duration_millis")]
+        max: Duration,
+    },
+    /// Exponential, with jitter applied to each delay
+    ExponentialJitter {
+        #[serde(with = "duration_millis")]
+        base: Duration,
+        factor: f64,
+        #[serde(with = "duration_millis")]
+        max: Duration,
+        jitter: Jitter,
+    },
+    /// "Decorrelated jitter": each delay is picked between base and three times the previous delay, up to max.
+    /// It depends on the previous delay, so delays() follows it exactly while delay(attempt) picks between base and
+    /// the largest delay the sequence could have reached by that attempt
+    Decorrelated {
+        #[serde(with = "duration_millis")]
+        base: Duration,
+        #[serde(with = "duration_millis")]
+        max: Duration,
+    },
+}
+
+impl Default for Backoff {
+    /// Start at 100ms, double each time up to 10 seconds, with full jitter
+    fn default() -> Self {
+        Backoff::ExponentialJitter{base: Duration::from_millis(100), factor: 2.0, max: Duration::from_secs(10), jitter: Jitter::Full}
+    }
+}
+
+impl Backoff {
+    /// The delay before the next attempt, once `attempt` attempts have failed (so attempt starts at 1)
+    pub fn delay<R: Rng + ?Sized>(&self, attempt: u32, rng: &mut R) -> Duration {
+        match self {
+            Backoff::Fixed(_) | Backoff::Exponential{..} => self.nominal(attempt),
+            Backoff::ExponentialJitter{jitter, ..} => jitter.apply(self.nominal(attempt), rng),
+            Backoff::Decorrelated{base, max} => between((*base).min(*max), self.nominal(attempt), rng),
+        }
+    }
+
+    /// The delay for an attempt before any jitter, which is also the most delay() can return for it
+    pub fn nominal(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential{base, factor, max} | Backoff::ExponentialJitter{base, factor, max, ..} => scale(base, factor.powi(exponent), max),
+            Backoff::Decorrelated{base, max} => scale(base, 3f64.powi(exponent), max),
+        }
+    }
+
+    /// No delay is longer than this
+    pub fn max(&self) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential{max, ..} | Backoff::ExponentialJitter{max, ..} | Backoff::Decorrelated{max, ..} => max,
+        }
+    }
+
+    /// An endless iterator over the delays for attempts 1, 2, 3...
+    pub fn delays<R: Rng>(&self, rng: R) -> Delays<R> {
+        Delays{backoff: *self, rng, attempt: 0, previous: None}
+    }
+
+    fn with_base(self, delay: Duration) -> Self {
+        match self {
+            Backoff::Fixed(_) => Backoff::Fixed(delay),
+            Backoff::Exponential{factor, max, ..} => Backoff::Exponential{base: delay, factor, max},
+            Backoff::ExponentialJitter{factor, max, jitter, ..} => Backoff::ExponentialJitter{base: delay, factor, max, jitter},
+            Backoff::Decorrelated{max, ..} => Backoff::Decorrelated{base: delay, max},
+        }
+    }
+
+    fn with_max(self, delay: Duration) -> Self {
+        match self {
+            Backoff::Fixed(fixed) => Backoff::Fixed(fixed.min(delay)),
+            Backoff::Exponential{base, factor, ..} => Backoff::Exponential{base, factor, max: delay},
+            Backoff::ExponentialJitter{base, factor, jitter, ..} => Backoff::ExponentialJitter{base, factor, max: delay, jitter},
+            Backoff::Decorrelated{base, ..} => Backoff::Decorrelated{base, max: delay},
+        }
+    }
+
+    fn with_jitter(self, jitter: Jitter) -> Self {
+        match (self, jitter) {
+            (Backoff::Exponential{base, factor, max}, Jitter::Full | Jitter::Equal) => Backoff::ExponentialJitter{base, factor, max, jitter},
+            (Backoff::ExponentialJitter{base, factor, max, ..}, Jitter::None) => Backoff::Exponential{base, factor, max},
+            (Backoff::ExponentialJitter{base, factor, max, ..}, jitter) => Backoff::ExponentialJitter{base, factor, max, jitter},
+            (backoff, _) => backoff,
+        }
+    }
+}
+
+/// base * factor, capped at max (and never below zero)
+fn scale(base: Duration, factor: f64, max: Duration) -> Duration {
+    let secs = base.as_secs_f64() * factor;
+    match secs.is_finite() && secs < max.as_secs_f64() {
+        true => Duration::from_secs_f64(secs.max(0.0)),
+        false => max,
+    }
+}
+
+/// A uniformly random duration between low and high (or low, if high is not above it)
+fn between<R: Rng + ?Sized>(low: Duration, high: Duration, rng: &mut R) -> Duration {
+    match high > low {
+        true => low + (high - low).mul_f64(rng.gen_range(0.0..=1.0)),
+        false => low,
+    }
+}
+
+
+/// The iterator returned by Backoff::delays()
+pub struct Delays<R> {
+    backoff: Backoff,
+    rng: R,
+    attempt: u32,
+    previous: Option<Duration>,
+}
+
+impl<R: Rng> Iterator for Delays<R> {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        self.attempt = self.attempt.saturating_add(1);
+        let delay = match self.backoff {
+            Backoff::Decorrelated{base, max} => {
+                let high = self.previous.map(|previous| previous.saturating_mul(3)).unwrap_or(base).min(max);
+                between(base.min(max), high, &mut self.rng)
+            },
+            backoff => backoff.delay(self.attempt, &mut self.rng),
+        };
+        self.previous = Some(delay);
+        Some(delay)
+    }
+}
+
+
+/// A RetryPolicy decides how many times to try, how long to wait in between, and which errors are worth retrying
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// The total number of attempts, including the first (default 3)
+    pub max_attempts: u32,
+    /// How long to wait between attempts (default Backoff::default())
+    pub backoff: Backoff,
+    retry_on: Arc<dyn Fn(&EventfulError) -> bool + Send + Sync>,
+    rng: SharedRng,
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("rng", &self.rng)
+            .finish()
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy{
+            max_attempts: 3,
+            backoff: Backoff::default(),
+            retry_on: Arc::new(EventfulError::is_retryable),
+            rng: SharedRng::default(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which tries exactly once
+    pub fn never() -> Self {
+        RetryPolicy::default().max_attempts(1)
+    }
+
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Change the backoff's first delay
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.backoff = self.backoff.with_base(delay);
+        self
+    }
+
+    /// Change the backoff's longest delay
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.backoff = self.backoff.with_max(delay);
+        self
+    }
+
+    /// Change the jitter of an exponential backoff (other backoffs are left alone)
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.backoff = self.backoff.with_jitter(jitter);
+        self
+    }
+
+    /// Draw jitter from this generator instead of thread_rng. Clones of the policy share it
+    pub fn with_rng<R: RngCore + Send + 'static>(mut self, rng: R) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+
+    /// Draw jitter from a generator seeded with `seed`, so the delays are the same on every run
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = SharedRng::seeded(seed);
+        self
+    }
+
+    /// Replace the default predicate (EventfulError::is_retryable) deciding which errors are retried
+    pub fn retry_on<F: Fn(&EventfulError) -> bool + Send + Sync + 'static>(mut self, predicate: F) -> Self {
+        self.retry_on = Arc::new(predicate);
+        self
+    }
+
+    pub fn should_retry(&self, err: &EventfulError) -> bool {
+        (self.retry_on)(err)
+    }
+
+    /// The delay before the next attempt, once `attempt` attempts have failed (so attempt starts at 1), before jitter
+    pub fn base_delay_for(&self, attempt: u32) -> Duration {
+        self.backoff.nominal(attempt)
+    }
+
+    /// The delay before the next attempt, once `attempt` attempts have failed, with jitter applied
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        self.rng.with(|rng| self.backoff.delay(attempt, rng))
+    }
+}
+
+
+/// Run an operation until it succeeds, fails with an error the policy won't retry, or runs out of attempts.
+/// Running out of attempts returns EventfulError::RetriesExhausted wrapping the last error
+pub async fn execute_with_retry<T, F, Fut>(policy: &RetryPolicy, mut op: F) -> Result<T, EventfulError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, EventfulError>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let err = match op().await {
+            Ok(val) => return Ok(val),
+            Err(err) => err,
+        };
+        if !policy.should_retry(&err) {
+            return Err(err)
+        }
+        if attempt >= policy.max_attempts {
+            return Err(EventfulError::RetriesExhausted{attempts: attempt, last_error: Box::new(err)})
+        }
+        tokio::time::sleep(policy.delay_for(attempt)).await;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::time::Instant;
+
+    fn flaky(failures: u32, calls: &AtomicU32) -> impl Future<Output = Result<u32, EventfulError>> + '_ {
+        let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            match call <= failures {
+                true => Err(EventfulError::Http("503".to_string())),
+                false => Ok(call),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_the_backoff_between_attempts() {
+        let policy = RetryPolicy::default().max_attempts(5).backoff(Backoff::Exponential{
+            base: Duration::from_secs(1), factor: 2.0, max: Duration::from_secs(3),
+        });
+        let calls = AtomicU32::new(0);
+        let started = Instant::now();
+        assert_eq!(execute_with_retry(&policy, || flaky(3, &calls)).await.unwrap(), 4);
+        // 1s, then 2s, then 3s (capped)
+        assert_eq!(started.elapsed(), Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_attempts() {
+        let policy = RetryPolicy::default().max_attempts(3).backoff(Backoff::Fixed(Duration::from_millis(500)));
+        let calls = AtomicU32::new(0);
+        let started = Instant::now();
+        match execute_with_retry(&policy, || flaky(10, &calls)).await {
+            Err(EventfulError::RetriesExhausted{attempts, last_error}) => {
+                assert_eq!(attempts, 3);
+                assert!(matches!(*last_error, EventfulError::Http(_)));
+            },
+            other => panic!("expected RetriesExhausted, got {:?}", other),
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(started.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn errors_the_predicate_rejects_are_not_retried() {
+        let calls = AtomicU32::new(0);
+        let started = Instant::now();
+        let result: Result<(), EventfulError> = execute_with_retry(&RetryPolicy::default(), || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Err(EventfulError::Config("bad".to_string())) }
+        }).await;
+        assert!(matches!(result, Err(EventfulError::Config(_))));
+        assert_eq!((calls.load(Ordering::SeqCst), started.elapsed()), (1, Duration::ZERO));
+
+        let policy = RetryPolicy::default().retry_on(|_| false);
+        let calls = AtomicU32::new(0);
+        assert!(matches!(execute_with_retry(&policy, || flaky(1, &calls)).await, Err(EventfulError::Http(_))));
+    }
+
+    #[test]
+    fn seeded_policies_repeat_their_delays() {
+        let delays = |seed| (1..10).map(|attempt| RetryPolicy::default().seed(seed).delay_for(attempt)).collect::<Vec<Duration>>();
+        assert_eq!(delays(7), delays(7));
+        let policy = RetryPolicy::default().seed(7);
+        let first: Vec<Duration> = (1..10).map(|attempt| policy.delay_for(attempt)).collect();
+        let again = RetryPolicy::default().seed(7);
+        assert_eq!(first, (1..10).map(|attempt| again.delay_for(attempt)).collect::<Vec<Duration>>());
+    }
+
+    #[test]
+    fn backoff_reads_from_config() {
+        let backoff: Backoff = serde_json::from_str(r#"{"exponential_jitter": {"base": 100, "factor": 2.0, "max": 10000, "jitter": "full"}}"#).unwrap();
+        assert_eq!(backoff, Backoff::default());
+        assert_eq!(serde_json::from_str::<Backoff>(r#"{"fixed": 500}"#).unwrap(), Backoff::Fixed(Duration::from_millis(500)));
+    }
+}
+
+
+#[cfg(all(test, feature = "proptest"))]
+mod properties {
+    use super::*;
+    use proptest::prelude::*;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    fn backoffs() -> impl Strategy<Value = Backoff> {
+        let ms = |range: std::ops::Range<u64>| range.prop_map(Duration::from_millis);
+        let jitter = prop_oneof![Just(Jitter::None), Just(Jitter::Full), Just(Jitter::Equal)];
+        prop_oneof![
+            ms(0..10_000).prop_map(Backoff::Fixed),
+            (ms(1..10_000), 1.0..4.0f64, ms(1..100_000)).prop_map(|(base, factor, max)| Backoff::Exponential{base, factor, max}),
+            (ms(1..10_000), 1.0..4.0f64, ms(1..100_000), jitter).prop_map(|(base, factor, max, jitter)| Backoff::ExponentialJitter{base, factor, max, jitter}),
+            (ms(1..10_000), ms(1..100_000)).prop_map(|(base, max)| Backoff::Decorrelated{base, max}),
+        ]
+    }
+
+    /// Durations go through f64 seconds, so allow for a nanosecond of rounding
+    fn at_most(delay: Duration, limit: Duration) -> bool {
+        delay <= limit + Duration::from_nanos(1)
+    }
+
+    proptest! {
+        #[test]
+        fn delays_never_pass_nominal_or_max(backoff in backoffs(), seed in any::<u64>(), attempt in 1..200u32) {
+            let delay = backoff.delay(attempt, &mut StdRng::seed_from_u64(seed));
+            prop_assert!(at_most(delay, backoff.nominal(attempt)), "{:?} > nominal {:?}", delay, backoff.nominal(attempt));
+            prop_assert!(at_most(backoff.nominal(attempt), backoff.max()));
+        }
+
+        #[test]
+        fn nominal_delays_never_shrink(backoff in backoffs(), attempt in 1..200u32) {
+            prop_assert!(at_most(backoff.nominal(attempt), backoff.nominal(attempt + 1)));
+        }
+
+        #[test]
+        fn equal_jitter_keeps_at_least_half(base in 1..10_000u64, max in 1..100_000u64, seed in any::<u64>(), attempt in 1..64u32) {
+            let backoff = Backoff::ExponentialJitter{base: Duration::from_millis(base), factor: 2.0, max: Duration::from_millis(max), jitter: Jitter::Equal};
+            let delay = backoff.delay(attempt, &mut StdRng::seed_from_u64(seed));
+            prop_assert!(at_most(backoff.nominal(attempt) / 2, delay));
+        }
+
+        #[test]
+        fn delay_sequences_stay_in_bounds(backoff in backoffs(), seed in any::<u64>()) {
+            let low = match backoff {
+                Backoff::Decorrelated{base, max} => base.min(max),
+                _ => Duration::ZERO,
+            };
+            for delay in backoff.delays(StdRng::seed_from_u64(seed)).take(100) {
+                prop_assert!(at_most(low, delay) && at_most(delay, backoff.max()), "{:?} outside {:?}..{:?}", delay, low, backoff.max());
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! The rng module holds SharedRng, the source of randomness for choosing a daemon from a fleet and for retry jitter.
+//! By default it draws from thread_rng. A seeded SharedRng produces the same sequence on every run, so tests of
+//! failure paths can assert exactly which daemon was picked and how long each backoff was.
+//!
+//! # Examples:
+//! ```
+//! # #[cfg(feature = "nsq")]
+//! # {
+//! use rand::{SeedableRng, rngs::StdRng};
+//! use eventful::{nsq::{Daemon, FleetNSQ}, retry::RetryPolicy};
+//!
+//! let fleet = FleetNSQ::new(Daemon::new("nsqd1", 4151, 4150), Daemon::new("nsqd2", 4151, 4150), Daemon::new("nsqd3", 4151, 4150))
+//!     .with_rng(StdRng::seed_from_u64(42));
+//! let policy = RetryPolicy::default().seed(42);
+//! # }
+//! ```
+
+use std::{fmt, sync::{Arc, Mutex}};
+use rand::{Rng, RngCore, SeedableRng, distributions::uniform::{SampleRange, SampleUniform}, rngs::StdRng};
+
+
+/// A cloneable handle on a random number generator. Clones share the same sequence
+#[derive(Clone, Default)]
+pub struct SharedRng {
+    rng: Option<Arc<Mutex<Box<dyn RngCore + Send>>>>,
+}
+
+impl fmt::Debug for SharedRng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.rng {
+            Some(_) => write!(f, "SharedRng(custom)"),
+            None => write!(f, "SharedRng(thread_rng)"),
+        }
+    }
+}
+
+impl SharedRng {
+    /// Draw from any generator, i.e. StdRng::seed_from_u64(42)
+    pub fn new<R: RngCore + Send + 'static>(rng: R) -> Self {
+        SharedRng{rng: Some(Arc::new(Mutex::new(Box::new(rng))))}
+    }
+
+    /// Draw from a StdRng seeded with `seed`
+    pub fn seeded(seed: u64) -> Self {
+        SharedRng::new(StdRng::seed_from_u64(seed))
+    }
+
+    /// Run f with the generator
+    pub fn with<T, F: FnOnce(&mut dyn RngCore) -> T>(&self, f: F) -> T {
+        match &self.rng {
+            Some(rng) => f(&mut **rng.lock().unwrap()),
+            None => f(&mut rand::thread_rng()),
+        }
+    }
+
+    pub fn gen_range<T: SampleUniform, R: SampleRange<T>>(&self, range: R) -> T {
+        self.with(|rng| rng.gen_range(range))
+    }
+}
@@ -0,0 +1,318 @@
+//! The shutdown module drains a whole process in order when it is asked to stop (i.e. on SIGTERM during a deploy).
+//! Components register with a Coordinator under the Stage they belong to:
+//!
+//! 1. Intake: consumers select on coordinator.intake() and stop taking new messages once it is cancelled.
+//!    Anything registered at this stage (i.e. closing listeners) runs first.
+//! 2. InFlight: consumer and supervisor tasks, which finish the handlers already running and then return.
+//! 3. Flush: buffered and spooled publishers, which push out whatever they still hold.
+//!
+//! begin_shutdown() cancels intake, then awaits each stage's drain futures (concurrently within a stage) before
+//! moving to the next. Each future gets its own timeout, capped by what is left of the grace period; one which
+//! doesn't finish in time is abandoned. The ShutdownReport says which components drained, failed, or were abandoned.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use std::{sync::Arc, time::Duration};
+//! # use eventful::prelude::*;
+//! # use eventful::{consumer, shutdown::{Coordinator, Stage}, spool::{SpoolConfig, SpoolingPublisher}};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct UserClickedSomething { user_id: i32 }
+//! # async fn handle_click(_click: UserClickedSomething) -> Result<(), EventfulError> { Ok(()) }
+//! # async fn demo(subscriber: Box<dyn Subscriber>, options: ConsumerOptions, fleet: FleetNSQ) -> Result<(), Box<dyn std::error::Error>> {
+//! let coordinator = Coordinator::new();
+//! let intake = coordinator.intake();
+//! let worker = tokio::spawn(async move {
+//!     tokio::select! {
+//!         result = consumer::run(subscriber, &options, handle_click) => result,
+//!         _ = intake.cancelled() => Ok(()),
+//!     }
+//! });
+//! coordinator.register_task("clicks", worker);
+//! let spool = Arc::new(SpoolingPublisher::new(fleet, SpoolConfig::new("/var/spool/eventful"))?);
+//! coordinator.register("spool", Stage::Flush, { let spool = spool.clone(); async move { spool.drain().await.map(|_| ()) } });
+//! let report = coordinator.install_signal_handlers(Duration::from_secs(25)).await?;
+//! println!("{:?}", report);
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{future::Future, sync::{Arc, Mutex}, time::Duration};
+use futures::future::{self, BoxFuture};
+use tokio::{task::JoinHandle, time::Instant};
+use tokio_util::sync::CancellationToken;
+use crate::err::EventfulError;
+
+
+/// When a component drains, relative to the others
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    Intake,
+    InFlight,
+    Flush,
+}
+
+const STAGES: [Stage; 3] = [Stage::Intake, Stage::InFlight, Stage::Flush];
+
+
+/// How one component's drain ended
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DrainOutcome {
+    Drained,
+    /// The drain future returned an error
+    Failed(String),
+    /// The drain future didn't finish within its timeout (or the grace period ran out first)
+    Abandoned,
+}
+
+
+/// What begin_shutdown() did, component by component in the order they drained
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub components: Vec<(String, Stage, DrainOutcome)>,
+    pub elapsed: Duration,
+}
+
+impl ShutdownReport {
+    /// True when every component drained
+    pub fn is_clean(&self) -> bool {
+        self.components.iter().all(|(_, _, outcome)| *outcome == DrainOutcome::Drained)
+    }
+
+    /// The names of components which drained
+    pub fn drained(&self) -> Vec<&str> {
+        self.named(|outcome| *outcome == DrainOutcome::Drained)
+    }
+
+    /// The names of components which failed or were abandoned
+    pub fn abandoned(&self) -> Vec<&str> {
+        self.named(|outcome| *outcome != DrainOutcome::Drained)
+    }
+
+    fn named<F: Fn(&DrainOutcome) -> bool>(&self, keep: F) -> Vec<&str> {
+        self.components.iter().filter(|(_, _, outcome)| keep(outcome)).map(|(name, _, _)| name.as_str()).collect()
+    }
+}
+
+
+struct Component {
+    name: String,
+    stage: Stage,
+    timeout: Option<Duration>,
+    drain: BoxFuture<'static, Result<(), EventfulError>>,
+}
+
+
+/// The Coordinator holds every registered component until begin_shutdown() drains them. Clones share registrations
+#[derive(Clone, Default)]
+pub struct Coordinator {
+    intake: CancellationToken,
+    components: Arc<Mutex<Vec<Component>>>,
+}
+
+impl Coordinator {
+    pub fn new() -> Self {
+        Coordinator::default()
+    }
+
+    /// Cancelled when shutdown begins. Consumers (and supervisors) should stop taking new work once it is
+    pub fn intake(&self) -> CancellationToken {
+        self.intake.child_token()
+    }
+
+    /// Has shutdown begun?
+    pub fn is_shutting_down(&self) -> bool {
+        self.intake.is_cancelled()
+    }
+
+    /// Register a drain future, which is not polled until its stage drains. Its timeout is whatever is left of the grace period
+    pub fn register<F>(&self, name: &str, stage: Stage, drain: F)
+    where
+        F: Future<Output = Result<(), EventfulError>> + Send + 'static,
+    {
+        self.push(name, stage, None, Box::pin(drain));
+    }
+
+    /// Like register(), but the component is abandoned after timeout even if grace remains
+    pub fn register_timeout<F>(&self, name: &str, stage: Stage, timeout: Duration, drain: F)
+    where
+        F: Future<Output = Result<(), EventfulError>> + Send + 'static,
+    {
+        self.push(name, stage, Some(timeout), Box::pin(drain));
+    }
+
+    /// Register a spawned consumer or supervisor, which drains (InFlight) once the task returns.
+    /// The task should watch intake() so it returns after finishing its in-flight work
+    pub fn register_task(&self, name: &str, task: JoinHandle<Result<(), EventfulError>>) {
+        let drain = async move {
+            match task.await {
+                Ok(result) => result,
+                Err(err) => Err(EventfulError::Destination(format!("the task panicked or was cancelled: {}", err))),
+            }
+        };
+        self.register(name, Stage::InFlight, drain);
+    }
+
+    fn push(&self, name: &str, stage: Stage, timeout: Option<Duration>, drain: BoxFuture<'static, Result<(), EventfulError>>) {
+        self.components.lock().unwrap().push(Component{name: name.to_string(), stage, timeout, drain});
+    }
+
+    /// Cancel intake and drain every registered component, stage by stage, within grace.
+    /// Components registered after this is called are not drained
+    pub async fn begin_shutdown(&self, grace: Duration) -> ShutdownReport {
+        let started = Instant::now();
+        let deadline = started + grace;
+        self.intake.cancel();
+        let mut components = std::mem::take(&mut *self.components.lock().unwrap());
+        let mut report = ShutdownReport::default();
+        for stage in STAGES {
+            let (draining, rest): (Vec<Component>, Vec<Component>) = components.into_iter().partition(|component| component.stage == stage);
+            components = rest;
+            let drains = draining.into_iter().map(|component| async move {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                let timeout = component.timeout.map(|timeout| timeout.min(remaining)).unwrap_or(remaining);
+                let outcome = match tokio::time::timeout(timeout, component.drain).await {
+                    Ok(Ok(())) => DrainOutcome::Drained,
+                    Ok(Err(err)) => DrainOutcome::Failed(err.to_string()),
+                    Err(_) => DrainOutcome::Abandoned,
+                };
+                (component.name, stage, outcome)
+            });
+            report.components.extend(future::join_all(drains).await);
+        }
+        report.elapsed = started.elapsed();
+        report
+    }
+
+    /// Wait for SIGTERM or SIGINT (ctrl-c), then begin_shutdown(grace)
+    pub fn install_signal_handlers(&self, grace: Duration) -> JoinHandle<ShutdownReport> {
+        let coordinator = self.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            coordinator.begin_shutdown(grace).await
+        })
+    }
+}
+
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = terminate.recv() => {},
+                _ = tokio::signal::ctrl_c() => {},
+            }
+        },
+        Err(_) => { let _ = tokio::signal::ctrl_c().await; },
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn stages_drain_in_order_and_slow_components_are_abandoned() {
+        let coordinator = Coordinator::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let step = |name: &'static str, took: Duration, result: Result<(), EventfulError>| {
+            let order = order.clone();
+            async move {
+                tokio::time::sleep(took).await;
+                order.lock().unwrap().push(name);
+                result
+            }
+        };
+        coordinator.register("spool", Stage::Flush, step("spool", Duration::from_secs(1), Ok(())));
+        coordinator.register("clicks", Stage::InFlight, step("clicks", Duration::from_secs(2), Ok(())));
+        coordinator.register_timeout("stuck", Stage::InFlight, Duration::from_secs(3), step("stuck", Duration::from_secs(60), Ok(())));
+        coordinator.register("listener", Stage::Intake, step("listener", Duration::ZERO, Err(EventfulError::Http("already closed".to_string()))));
+        let intake = coordinator.intake();
+
+        let report = coordinator.begin_shutdown(Duration::from_secs(30)).await;
+        assert!(intake.is_cancelled() && coordinator.is_shutting_down());
+        assert_eq!(*order.lock().unwrap(), vec!["listener", "clicks", "spool"]);
+        assert_eq!(report.components, vec![
+            ("listener".to_string(), Stage::Intake, DrainOutcome::Failed(EventfulError::Http("already closed".to_string()).to_string())),
+            ("clicks".to_string(), Stage::InFlight, DrainOutcome::Drained),
+            ("stuck".to_string(), Stage::InFlight, DrainOutcome::Abandoned),
+            ("spool".to_string(), Stage::Flush, DrainOutcome::Drained),
+        ]);
+        assert_eq!(report.elapsed, Duration::from_secs(4));
+        assert_eq!(report.drained(), vec!["clicks", "spool"]);
+        assert_eq!(report.abandoned(), vec!["listener", "stuck"]);
+        assert!(!report.is_clean());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_grace_period_caps_every_stage() {
+        let coordinator = Coordinator::new();
+        coordinator.register("slow", Stage::InFlight, async {
+            tokio::time::sleep(Duration::from_secs(20)).await;
+            Ok(())
+        });
+        // by the time the flush stage starts the grace period has run out
+        coordinator.register("spool", Stage::Flush, async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        });
+        let report = coordinator.begin_shutdown(Duration::from_secs(10)).await;
+        assert_eq!(report.abandoned(), vec!["slow", "spool"]);
+        assert_eq!(report.elapsed, Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn a_panicked_task_is_reported_as_failed() {
+        let coordinator = Coordinator::new();
+        coordinator.register_task("clicks", tokio::spawn(async { panic!("handler bug") }));
+        let report = coordinator.begin_shutdown(Duration::from_secs(5)).await;
+        assert!(matches!(&report.components[0].2, DrainOutcome::Failed(message) if message.contains("panicked")));
+    }
+}
+
+
+/// Drains a SpoolingPublisher into a MockDaemon, as a service shutting down mid-outage would
+#[cfg(all(test, feature = "testing", feature = "nsq"))]
+mod integration {
+    use bytes::Bytes;
+    use super::*;
+    use crate::publisher::{Destination, Metadata, Publisher};
+    use crate::spool::{SpoolConfig, SpoolingPublisher};
+    use crate::testing::MockDaemon;
+
+    #[tokio::test]
+    async fn spooled_events_reach_the_daemon_before_shutdown_finishes() {
+        let dir = std::env::temp_dir().join(format!("eventful-shutdown-{}", rand::random::<u32>()));
+        let mock = MockDaemon::start().await.unwrap();
+        // nsqd is down for the first publish, so it and everything after it is spooled
+        mock.respond_with_500_times(1);
+        let spool = Arc::new(SpoolingPublisher::new(mock.daemon(), SpoolConfig::new(&dir)).unwrap());
+        let clicks = Destination::NsqTopic("clicks".to_string());
+        for n in 0..10 {
+            spool.publish_bytes(&clicks, Bytes::from(n.to_string()), &Metadata::default()).await.unwrap();
+        }
+        assert_eq!(spool.depth(), 10);
+        assert!(mock.bodies_on("clicks").is_empty());
+
+        let coordinator = Coordinator::new();
+        coordinator.register("spool", Stage::Flush, {
+            let spool = spool.clone();
+            async move { spool.drain().await.map(|_| ()) }
+        });
+        let report = coordinator.begin_shutdown(Duration::from_secs(10)).await;
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(mock.bodies_on("clicks"), (0..10).map(|n| Bytes::from(n.to_string())).collect::<Vec<_>>());
+        assert_eq!(spool.depth(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
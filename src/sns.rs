@@ -0,0 +1,219 @@
+//! The sns module publishes events to [AWS SNS](https://aws.amazon.com/sns/) topics,
+//! which typically fan out to several SQS queues.
+//! 
+//! Unless a subscription has raw message delivery enabled, SNS wraps each message in a JSON notification envelope
+//! before delivering it to SQS. Call SubscriptionSQS::unwrap_sns(true), or set ReceiveOptions::unwrap_sns,
+//! to have the envelope removed before decoding.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use eventful::prelude::*;
+//! # use eventful::sns::{ClientSNS, EventSNS};
+//! # const ORDERS_QUEUE_URL: &str = "https://sqs.us-east-1.amazonaws.com/123456789012/orders.fifo";
+//! # #[derive(Serialize, Deserialize)]
+//! # struct OrderPlaced { customer_id: u64 }
+//! impl EventSNS for OrderPlaced {
+//!     fn topic_arn() -> &'static str {
+//!         "arn:aws:sns:us-east-1:123456789012:orders.fifo"
+//!     }
+//!     fn group_id(&self) -> Option<String> {
+//!         Some(self.customer_id.to_string())
+//!     }
+//! }
+//!
+//! # async fn demo(sqs: ClientSQS, order: OrderPlaced) -> Result<(), EventfulError> {
+//! let sns = ClientSNS::new("us-east-1").await;
+//! sns.publish(&order).await?;
+//!
+//! let subscription = sqs.subscribe(ORDERS_QUEUE_URL).unwrap_sns(true);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use async_trait::async_trait;
+pub use aws_sdk_sns::Client;
+use aws_sdk_sns::{Region, model::MessageAttributeValue};
+use bytes::Bytes;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::err::EventfulError;
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+
+
+/// Implement EventSNS on a struct to publish it to a topic
+pub trait EventSNS: Serialize + DeserializeOwned {
+    fn topic_arn() -> &'static str;
+
+    /// The message group id, required by FIFO topics
+    fn group_id(&self) -> Option<String> {
+        None
+    }
+
+    /// The deduplication id for FIFO topics. Not needed if the topic has content-based deduplication enabled
+    fn dedup_id(&self) -> Option<String> {
+        None
+    }
+
+    /// String message attributes, which subscriptions can use in filter policies
+    fn message_attributes(&self) -> HashMap<String, String> {
+        HashMap::new()
+    }
+}
+
+
+#[derive(Clone)]
+pub struct ClientSNS {
+    client: Client,
+}
+
+impl ClientSNS {
+    pub async fn new(region: &'static str) -> Self {
+        let config = aws_config::from_env().region(Region::new(region)).load().await;
+        ClientSNS{client: Client::new(&config)}
+    }
+
+    /// The underlying aws_sdk_sns Client, for operations eventful does not wrap
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Publish a UTF-8 body to a topic. meta.headers become String message attributes
+    pub async fn publish_raw(&self, topic_arn: &str, body: String, meta: &Metadata) -> Result<Option<String>, EventfulError> {
+        let mut request = self.client.publish()
+            .topic_arn(topic_arn)
+            .message(body)
+            .set_message_group_id(meta.group_id.clone())
+            .set_message_deduplication_id(meta.dedup_id.clone());
+        for (key, value) in &meta.headers {
+            let attribute = MessageAttributeValue::builder().data_type("String").string_value(value).build();
+            request = request.message_attributes(key, attribute);
+        }
+        let output = request.send().await.map_err(|err| EventfulError::SNS(format!("{:?}", err)))?;
+        Ok(output.message_id)
+    }
+
+    /// Serialize an event and publish it to its topic, returning the message id
+    pub async fn publish<T: EventSNS>(&self, event: &T) -> Result<Option<String>, EventfulError> {
+        let body = serde_json::to_string(event)?;
+        let meta = Metadata{group_id: event.group_id(), dedup_id: event.dedup_id(), headers: event.message_attributes()};
+        self.publish_raw(<T as EventSNS>::topic_arn(), body, &meta).await
+    }
+}
+
+#[async_trait]
+impl Publisher for ClientSNS {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let topic_arn = match dest {
+            Destination::SnsTopic(arn) => arn,
+            _ => return Err(publisher::unsupported("ClientSNS", dest)),
+        };
+        let body = String::from_utf8(body.to_vec())
+            .map_err(|_| EventfulError::SNS("SNS message bodies must be valid UTF-8".to_string()))?;
+        let message_id = self.publish_raw(topic_arn, body, meta).await?;
+        Ok(Receipt{message_id})
+    }
+}
+
+
+/// A message unwrapped from an SNS notification envelope
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+    /// The Message field, which is the body that was published
+    pub message: String,
+    /// The MessageId SNS assigned
+    pub message_id: Option<String>,
+    pub topic_arn: Option<String>,
+    /// The Subject it was published with, if any
+    pub subject: Option<String>,
+    /// String (and Number) message attributes
+    pub attributes: HashMap<String, String>,
+}
+
+/// If body is an SNS notification envelope (`"Type": "Notification"` with `Message`, `TopicArn` and `MessageId` fields), unwrap it.
+/// Anything else, i.e. a message from a raw delivery subscription, returns None
+pub fn unwrap_notification(body: &str) -> Option<Notification> {
+    let envelope: serde_json::Value = serde_json::from_str(body).ok()?;
+    if envelope.get("Type")?.as_str()? != "Notification" {
+        return None
+    }
+    let message = envelope.get("Message")?.as_str()?.to_string();
+    let field = |name: &str| envelope.get(name).and_then(|val| val.as_str()).map(|val| val.to_string());
+    // a raw event of our own could have a Type and Message, but not these too
+    let (message_id, topic_arn) = (field("MessageId")?, field("TopicArn")?);
+    let attributes = envelope.get("MessageAttributes")
+        .and_then(|attrs| attrs.as_object())
+        .map(|attrs| attrs.iter()
+            .filter(|(_, attr)| attr.get("Type").and_then(|kind| kind.as_str()) != Some("Binary"))
+            .filter_map(|(key, attr)| attr.get("Value").and_then(|val| val.as_str()).map(|val| (key.clone(), val.to_string())))
+            .collect())
+        .unwrap_or_default();
+    Some(Notification{message, message_id: Some(message_id), topic_arn: Some(topic_arn), subject: field("Subject"), attributes})
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A notification as SNS delivers it to an SQS queue without raw message delivery
+    const NOTIFICATION: &str = r#"{
+        "Type" : "Notification",
+        "MessageId" : "22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324",
+        "TopicArn" : "arn:aws:sns:us-east-1:123456789012:orders",
+        "Subject" : "order placed",
+        "Message" : "{\"id\":1,\"total\":\"9.99\"}",
+        "Timestamp" : "2023-03-01T12:00:00.000Z",
+        "SignatureVersion" : "1",
+        "Signature" : "EXAMPLEpH+DcEwjAPg8O9mY8dReBSwksfg2S7WKQcikcNKWLQjwu6A4VbeS0QHVCkhRS7fUQvi2egU3N858fiTDN6bkkOxYDVrY0Ad8L10Hs3zH81mtnPk5uvvolIC1CXGu43obcgFxeL3khZl8IKvO61GWB6jI9b5+gLPoBc1Q=",
+        "SigningCertURL" : "https://sns.us-east-1.amazonaws.com/SimpleNotificationService-f3ecfb7224c7233fe7bb5f59f96de52f.pem",
+        "UnsubscribeURL" : "https://sns.us-east-1.amazonaws.com/?Action=Unsubscribe&SubscriptionArn=arn:aws:sns:us-east-1:123456789012:orders:2bcfbf39",
+        "MessageAttributes" : {
+            "tenant" : {"Type" : "String", "Value" : "acme"},
+            "priority" : {"Type" : "Number", "Value" : "5"},
+            "thumbnail" : {"Type" : "Binary", "Value" : "aGVsbG8="}
+        }
+    }"#;
+
+    /// SNS sends this (with a SubscribeURL) when a queue is first subscribed
+    const SUBSCRIPTION_CONFIRMATION: &str = r#"{
+        "Type" : "SubscriptionConfirmation",
+        "MessageId" : "165545c9-2a5c-472c-8df2-7ff2be2b3b1b",
+        "Token" : "2336412f37",
+        "TopicArn" : "arn:aws:sns:us-east-1:123456789012:orders",
+        "Message" : "You have chosen to subscribe to the topic arn:aws:sns:us-east-1:123456789012:orders.",
+        "SubscribeURL" : "https://sns.us-east-1.amazonaws.com/?Action=ConfirmSubscription&TopicArn=arn:aws:sns:us-east-1:123456789012:orders&Token=2336412f37"
+    }"#;
+
+    #[test]
+    fn unwraps_a_notification() {
+        let notification = unwrap_notification(NOTIFICATION).unwrap();
+        assert_eq!(notification.message, r#"{"id":1,"total":"9.99"}"#);
+        assert_eq!(notification.message_id.as_deref(), Some("22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324"));
+        assert_eq!(notification.topic_arn.as_deref(), Some("arn:aws:sns:us-east-1:123456789012:orders"));
+        assert_eq!(notification.subject.as_deref(), Some("order placed"));
+        // binary attributes aren't headers
+        let expected: HashMap<String, String> = [("tenant", "acme"), ("priority", "5")].iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(notification.attributes, expected);
+    }
+
+    #[test]
+    fn subject_and_attributes_are_optional() {
+        let body = r#"{"Type":"Notification","MessageId":"m-1","TopicArn":"arn:aws:sns:us-east-1:123456789012:orders","Message":"hello"}"#;
+        let notification = unwrap_notification(body).unwrap();
+        assert_eq!(notification.message, "hello");
+        assert_eq!(notification.subject, None);
+        assert!(notification.attributes.is_empty());
+    }
+
+    #[test]
+    fn leaves_everything_else_alone() {
+        assert_eq!(unwrap_notification(SUBSCRIPTION_CONFIRMATION), None);
+        // raw message delivery: the body is the event itself
+        assert_eq!(unwrap_notification(r#"{"id":1,"total":"9.99"}"#), None);
+        // an event of our own which happens to have Type and Message, but isn't an envelope
+        assert_eq!(unwrap_notification(r#"{"Type":"Notification","Message":"hello"}"#), None);
+        assert_eq!(unwrap_notification(r#"{"Type":"Notification","MessageId":"m-1","TopicArn":"arn","Message":{"id":1}}"#), None);
+        assert_eq!(unwrap_notification("not json"), None);
+        assert_eq!(unwrap_notification(""), None);
+    }
+}
@@ -0,0 +1,602 @@
+//! The spool module keeps events on local disk while a publisher's backend is down, and sends them once it recovers.
+//!
+//! SpoolingPublisher wraps any Publisher. When a publish fails with a retryable error the event is appended to a spool
+//! file for its destination and the publish returns Ok, since the event is now durable locally. While a destination has
+//! anything spooled, later events for it are spooled too, so they can't overtake the earlier ones. A drainer
+//! (drain(), or run_until() in the background) republishes each destination's spool in order, and only moves past an
+//! entry once the inner publisher has accepted it. An entry the inner publisher rejects outright (a non-retryable error)
+//! is appended to the destination's dead-letter file ("<spool>.dead.ndjson", readable with deadletter::FileReader) and
+//! skipped. Publishes to one destination are serialized, since each must check the spool before it sends. Delivery is at least once: a crash between the publish and saving
+//! the spool's offset sends that entry again.
+//!
+//! Each record is a 4 byte length, a 4 byte CRC32 of the payload, then the ArchivedEvent as JSON.
+//! On startup every spool file is scanned, and anything after the last intact record (i.e. a write cut short by a crash)
+//! is truncated away.
+//!
+//! depth() counts what is waiting, and the observer's spool_stalled callback fires once a destination has had events
+//! waiting for longer than alert_after.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "sqs")]
+//! # mod example {
+//! # use std::sync::Arc;
+//! # use eventful::prelude::*;
+//! # use eventful::spool::{SpoolConfig, SpoolingPublisher};
+//! # use tokio_util::sync::CancellationToken;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct OrderPlaced { order_id: u64 }
+//! # async fn demo(sqs: ClientSQS, shutdown: CancellationToken, dest: Destination, order: OrderPlaced) -> Result<(), EventfulError> {
+//! let publisher = Arc::new(SpoolingPublisher::new(sqs, SpoolConfig::new("/var/lib/orders/spool"))?);
+//! tokio::spawn({
+//!     let publisher = publisher.clone();
+//!     async move { publisher.run_until(shutdown).await }
+//! });
+//! publisher.publish_event(&dest, &order).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::HashMap, fs::{File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::Utc;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use crate::deadletter::{DeadLetterRecord, DeadLetterSink, FileSink};
+use crate::err::EventfulError;
+use crate::observer::{self, EventfulObserver, SpoolStalled};
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+use crate::replay::{self, ArchivedEvent};
+
+
+/// The length and checksum before each record
+const HEADER_BYTES: usize = 8;
+
+
+/// When spool writes are flushed to disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// fsync after every record, so a spooled event survives a power cut
+    EveryRecord,
+    /// Leave it to the OS, so a spooled event survives the process crashing but not the machine
+    Os,
+}
+
+
+/// Where and how much to spool
+#[derive(Clone, Debug)]
+pub struct SpoolConfig {
+    /// Spool files are kept here, one per destination
+    pub dir: PathBuf,
+    /// Once the spool files add up to this many bytes, nothing more is spooled and publishes fail as they would without a spool
+    pub max_bytes: u64,
+    pub flush_policy: FlushPolicy,
+    /// How often run_until() tries to drain
+    pub drain_interval: Duration,
+    /// How long a destination can have events waiting before spool_stalled is reported
+    pub alert_after: Duration,
+}
+
+impl SpoolConfig {
+    /// Up to 1GiB in dir, fsynced, drained every second, reported as stalled after 5 minutes
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        SpoolConfig{
+            dir: dir.into(),
+            max_bytes: 1024 * 1024 * 1024,
+            flush_policy: FlushPolicy::EveryRecord,
+            drain_interval: Duration::from_secs(1),
+            alert_after: Duration::from_secs(300),
+        }
+    }
+}
+
+
+/// One destination's spool file
+struct DestSpool {
+    dest: Destination,
+    path: PathBuf,
+    offset_path: PathBuf,
+    /// Where the first undelivered record starts
+    offset: u64,
+    /// Where the last intact record ends
+    len: u64,
+    pending: u64,
+    /// When events started waiting
+    since: Option<Instant>,
+    alerted: bool,
+}
+
+
+/// A SpoolingPublisher forwards to an inner publisher, spooling events to disk when it fails
+pub struct SpoolingPublisher<P> {
+    inner: P,
+    config: SpoolConfig,
+    observer: Option<Arc<dyn EventfulObserver>>,
+    spools: Mutex<HashMap<Destination, Arc<tokio::sync::Mutex<DestSpool>>>>,
+    bytes: AtomicU64,
+    depth: AtomicU64,
+}
+
+impl<P: Publisher> SpoolingPublisher<P> {
+    /// Open (or create) the spool directory, recovering whatever was spooled before a restart
+    pub fn new(inner: P, config: SpoolConfig) -> Result<Self, EventfulError> {
+        std::fs::create_dir_all(&config.dir)?;
+        let mut spools = HashMap::new();
+        let mut bytes = 0;
+        let mut depth = 0;
+        for entry in std::fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext != "spool").unwrap_or(true) {
+                continue
+            }
+            if let Some(spool) = recover(&path)? {
+                bytes += spool.len;
+                depth += spool.pending;
+                spools.insert(spool.dest.clone(), Arc::new(tokio::sync::Mutex::new(spool)));
+            }
+        }
+        Ok(SpoolingPublisher{inner, config, observer: None, spools: Mutex::new(spools), bytes: AtomicU64::new(bytes), depth: AtomicU64::new(depth)})
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Report stalled spools to this observer instead of the global one
+    pub fn observer(mut self, observer: Arc<dyn EventfulObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// How many events are waiting in the spool, across every destination
+    pub fn depth(&self) -> u64 {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    fn spool_for(&self, dest: &Destination) -> Arc<tokio::sync::Mutex<DestSpool>> {
+        let mut spools = self.spools.lock().unwrap();
+        spools.entry(dest.clone()).or_insert_with(|| {
+            let path = self.config.dir.join(spool_file_name(dest));
+            let offset_path = path.with_extension("offset");
+            Arc::new(tokio::sync::Mutex::new(DestSpool{dest: dest.clone(), path, offset_path, offset: 0, len: 0, pending: 0, since: None, alerted: false}))
+        }).clone()
+    }
+
+    async fn append(&self, spool: &mut DestSpool, event: &ArchivedEvent) -> Result<(), EventfulError> {
+        let record = encode_record(event)?;
+        if self.bytes.load(Ordering::Relaxed) + record.len() as u64 > self.config.max_bytes {
+            return Err(EventfulError::IO(std::io::Error::other("the spool is full")))
+        }
+        let (path, len, flush_policy) = (spool.path.clone(), spool.len, self.config.flush_policy);
+        let record_len = record.len() as u64;
+        tokio::task::spawn_blocking(move || -> Result<(), EventfulError> {
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let written = file.write_all(&record).and_then(|_| match flush_policy {
+                FlushPolicy::EveryRecord => file.sync_data(),
+                FlushPolicy::Os => Ok(()),
+            });
+            if let Err(err) = written {
+                // don't leave half a record for the next append to follow
+                let _ = file.set_len(len);
+                return Err(err.into())
+            }
+            Ok(())
+        }).await.map_err(|err| EventfulError::IO(std::io::Error::other(err)))??;
+        spool.len += record_len;
+        spool.pending += 1;
+        spool.since.get_or_insert_with(Instant::now);
+        self.bytes.fetch_add(record_len, Ordering::Relaxed);
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Republish spooled events, in order per destination, until each spool is empty or the inner publisher fails.
+    /// An event the inner publisher rejects outright (a non-retryable error) is moved to the destination's dead-letter file,
+    /// so it can't hold up the events behind it. A destination whose spool can't be read is skipped (and the error logged)
+    /// without holding up the others. Returns how many were sent
+    pub async fn drain(&self) -> Result<u64, EventfulError> {
+        let spools: Vec<_> = self.spools.lock().unwrap().values().cloned().collect();
+        let mut sent = 0;
+        for spool in spools {
+            let mut spool = spool.lock().await;
+            match self.drain_one(&mut spool).await {
+                Ok(count) => sent += count,
+                Err(err) => crate::trace::record_error(&err),
+            }
+            self.check_stalled(&mut spool);
+        }
+        Ok(sent)
+    }
+
+    /// Drain one destination's spool, returning how many were sent
+    async fn drain_one(&self, spool: &mut DestSpool) -> Result<u64, EventfulError> {
+        let mut sent = 0;
+        while spool.pending > 0 {
+            let (event, next) = match read_record_at(&spool.path, spool.offset)? {
+                Some(record) => record,
+                // the file no longer matches what was counted; nothing more can be read
+                None => {
+                    self.depth.fetch_sub(spool.pending, Ordering::Relaxed);
+                    spool.pending = 0;
+                    break
+                },
+            };
+            match self.inner.publish_bytes(&event.topic, event.body.clone(), &event.metadata).await {
+                Ok(_) => sent += 1,
+                Err(err) if err.is_retryable() => break,
+                Err(err) => {
+                    let record = DeadLetterRecord{
+                        source: event.topic,
+                        original_body: event.body,
+                        attempts: 1,
+                        last_error: err.to_string(),
+                        first_seen: Utc::now(),
+                        metadata: event.metadata,
+                    };
+                    FileSink::new(dead_letter_path(&spool.path)).send(record).await?;
+                },
+            }
+            spool.offset = next;
+            spool.pending -= 1;
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            replay::write_checkpoint(&spool.offset_path, spool.offset).await?;
+        }
+        if spool.pending == 0 && spool.len > 0 {
+            remove_if_exists(&spool.path)?;
+            remove_if_exists(&spool.offset_path)?;
+            self.bytes.fetch_sub(spool.len, Ordering::Relaxed);
+            spool.len = 0;
+            spool.offset = 0;
+        }
+        if spool.pending == 0 {
+            spool.since = None;
+            spool.alerted = false;
+        }
+        Ok(sent)
+    }
+
+    fn check_stalled(&self, spool: &mut DestSpool) {
+        let waiting = match spool.since {
+            Some(since) => since.elapsed(),
+            None => return,
+        };
+        if waiting >= self.config.alert_after && !spool.alerted {
+            spool.alerted = true;
+            observer::or_global(&self.observer).spool_stalled(&SpoolStalled{dest: spool.dest.clone(), depth: spool.pending, waiting});
+        }
+    }
+
+    /// Drain every drain_interval until shutdown is cancelled
+    pub async fn run_until(&self, shutdown: CancellationToken) {
+        let mut ticker = tokio::time::interval(self.config.drain_interval);
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = ticker.tick() => {},
+            }
+            let _ = self.drain().await;
+        }
+    }
+}
+
+#[async_trait]
+impl<P: Publisher> Publisher for SpoolingPublisher<P> {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let spool = self.spool_for(dest);
+        let event = || ArchivedEvent{topic: dest.clone(), body: body.clone(), metadata: meta.clone(), original_timestamp: Utc::now()};
+        // held across the check and the send, so no other publish to dest can overtake an event on its way into the spool
+        let mut spool = spool.lock().await;
+        if spool.pending > 0 {
+            self.append(&mut spool, &event()).await?;
+            return Ok(Receipt::default())
+        }
+        match self.inner.publish_bytes(dest, body.clone(), meta).await {
+            Err(err) if err.is_retryable() => match self.append(&mut spool, &event()).await {
+                Ok(()) => Ok(Receipt::default()),
+                // the spool is full or unwritable, so fail as if there were no spool
+                Err(_) => Err(err),
+            },
+            other => other,
+        }
+    }
+}
+
+
+/// i.e. "sqs___queue_url-1a2b3c4d.spool": readable, with a hash of the full name so sanitizing can't cause collisions
+fn spool_file_name(dest: &Destination) -> String {
+    let name = dest.to_string();
+    let readable: String = name.chars().take(64).map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    format!("{}-{:08x}.spool", readable, crc32fast::hash(name.as_bytes()))
+}
+
+fn encode_record(event: &ArchivedEvent) -> Result<Vec<u8>, EventfulError> {
+    let payload = serde_json::to_vec(event)?;
+    let mut record = Vec::with_capacity(HEADER_BYTES + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    record.extend_from_slice(&payload);
+    Ok(record)
+}
+
+/// The event at the start of buf and the size of its record, if it is intact
+fn decode_record(buf: &[u8]) -> Option<(ArchivedEvent, usize)> {
+    if buf.len() < HEADER_BYTES {
+        return None
+    }
+    let len = u32::from_be_bytes(buf[0..4].try_into().ok()?) as usize;
+    let crc = u32::from_be_bytes(buf[4..8].try_into().ok()?);
+    let payload = buf.get(HEADER_BYTES..HEADER_BYTES + len)?;
+    if crc32fast::hash(payload) != crc {
+        return None
+    }
+    let event = serde_json::from_slice(payload).ok()?;
+    Some((event, HEADER_BYTES + len))
+}
+
+fn read_record_at(path: &Path, offset: u64) -> Result<Option<(ArchivedEvent, u64)>, EventfulError> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut header = [0u8; HEADER_BYTES];
+    if file.read_exact(&mut header).is_err() {
+        return Ok(None)
+    }
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let mut record = header.to_vec();
+    record.resize(HEADER_BYTES + len, 0);
+    if file.read_exact(&mut record[HEADER_BYTES..]).is_err() {
+        return Ok(None)
+    }
+    Ok(decode_record(&record).map(|(event, size)| (event, offset + size as u64)))
+}
+
+/// Scan a spool file, truncating anything after the last intact record.
+/// Returns None (and removes the files) when nothing in it is still waiting
+fn recover(path: &Path) -> Result<Option<DestSpool>, EventfulError> {
+    let offset_path = path.with_extension("offset");
+    let offset = match std::fs::read_to_string(&offset_path) {
+        Ok(contents) => contents.trim().parse::<u64>().unwrap_or(0),
+        Err(_) => 0,
+    };
+    let data = std::fs::read(path)?;
+    let mut pos = 0;
+    let mut pending = 0;
+    let mut dest = None;
+    while let Some((event, size)) = decode_record(&data[pos..]) {
+        if pos as u64 >= offset {
+            pending += 1;
+            dest.get_or_insert(event.topic);
+        }
+        pos += size;
+    }
+    if pos < data.len() {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(pos as u64)?;
+        file.sync_all()?;
+    }
+    let dest = match dest {
+        Some(dest) => dest,
+        None => {
+            remove_if_exists(path)?;
+            remove_if_exists(&offset_path)?;
+            return Ok(None)
+        },
+    };
+    Ok(Some(DestSpool{
+        dest,
+        path: path.to_path_buf(),
+        offset_path,
+        offset: offset.min(pos as u64),
+        len: pos as u64,
+        pending,
+        since: Some(Instant::now()),
+        alerted: false,
+    }))
+}
+
+/// Events the inner publisher rejected while draining are appended here, as deadletter::FileSink records
+pub fn dead_letter_path(spool_path: &Path) -> PathBuf {
+    spool_path.with_extension("dead.ndjson")
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), EventfulError> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use crate::deadletter::{DeadLetterReader, FileReader};
+    use crate::memory::MemoryBroker;
+
+    /// A MemoryBroker which can be taken down, and which rejects any body of "poison" outright
+    #[derive(Clone, Default)]
+    struct Flaky {
+        broker: MemoryBroker,
+        down: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Publisher for Flaky {
+        async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(EventfulError::Http("broker is down".to_string()))
+            }
+            if body == "poison" {
+                return Err(EventfulError::Destination("rejected".to_string()))
+            }
+            self.broker.publish_bytes(dest, body, meta).await
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("eventful-spool-{}-{}", name, rand::random::<u32>()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn dest(name: &str) -> Destination {
+        Destination::NsqTopic(name.to_string())
+    }
+
+    async fn publish_all(publisher: &SpoolingPublisher<Flaky>, dest: &Destination, bodies: std::ops::Range<u32>) {
+        for n in bodies {
+            publisher.publish_bytes(dest, Bytes::from(n.to_string()), &Metadata::default()).await.unwrap();
+        }
+    }
+
+    fn numbers(bodies: Vec<Bytes>) -> Vec<u32> {
+        bodies.iter().map(|body| std::str::from_utf8(body).unwrap().parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn nothing_is_lost_or_reordered_while_the_broker_is_down() {
+        let dir = test_dir("outage");
+        let flaky = Flaky::default();
+        let publisher = SpoolingPublisher::new(flaky.clone(), SpoolConfig::new(&dir)).unwrap();
+        publish_all(&publisher, &dest("click"), 0..5).await;
+        flaky.down.store(true, Ordering::SeqCst);
+        publish_all(&publisher, &dest("click"), 5..10).await;
+        assert_eq!(publisher.depth(), 5);
+
+        // back up, but 10.. must still wait behind what is spooled
+        flaky.down.store(false, Ordering::SeqCst);
+        publish_all(&publisher, &dest("click"), 10..15).await;
+        assert_eq!(publisher.depth(), 10);
+        assert_eq!(publisher.drain().await.unwrap(), 10);
+        assert_eq!(publisher.depth(), 0);
+        assert_eq!(numbers(flaky.broker.published_to("click")), (0..15).collect::<Vec<_>>());
+
+        // with the spool empty, publishes go straight through again
+        publish_all(&publisher, &dest("click"), 15..16).await;
+        assert_eq!(numbers(flaky.broker.published_to("click")).last(), Some(&15));
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_publishes_cannot_overtake_the_spool() {
+        let dir = test_dir("concurrent");
+        let flaky = Flaky::default();
+        let publisher = Arc::new(SpoolingPublisher::new(flaky.clone(), SpoolConfig::new(&dir)).unwrap());
+        flaky.down.store(true, Ordering::SeqCst);
+        let mut tasks = Vec::new();
+        for task in 0..4u32 {
+            let (publisher, flaky) = (publisher.clone(), flaky.clone());
+            tasks.push(tokio::spawn(async move {
+                for n in 0..25 {
+                    if task == 0 && n == 10 {
+                        flaky.down.store(false, Ordering::SeqCst);
+                    }
+                    publisher.publish_bytes(&dest("click"), Bytes::from((task * 100 + n).to_string()), &Metadata::default()).await.unwrap();
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        publisher.drain().await.unwrap();
+        let sent = numbers(flaky.broker.published_to("click"));
+        assert_eq!(sent.len(), 100);
+        // each task's events arrive in the order it published them
+        for task in 0..4 {
+            let mine: Vec<_> = sent.iter().filter(|n| **n / 100 == task).collect();
+            assert!(mine.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovers_from_a_spool_truncated_by_a_crash() {
+        let dir = test_dir("crash");
+        let flaky = Flaky::default();
+        flaky.down.store(true, Ordering::SeqCst);
+        let path = {
+            let publisher = SpoolingPublisher::new(flaky.clone(), SpoolConfig::new(&dir)).unwrap();
+            publish_all(&publisher, &dest("click"), 0..3).await;
+            dir.join(spool_file_name(&dest("click")))
+        };
+        // the process died halfway through writing a fourth record
+        let partial = encode_record(&ArchivedEvent{topic: dest("click"), body: Bytes::from("3"), metadata: Metadata::default(), original_timestamp: Utc::now()}).unwrap();
+        let intact = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&partial[..partial.len() / 2]).unwrap();
+
+        flaky.down.store(false, Ordering::SeqCst);
+        let publisher = SpoolingPublisher::new(flaky.clone(), SpoolConfig::new(&dir)).unwrap();
+        assert_eq!(publisher.depth(), 3);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), intact);
+        assert_eq!(publisher.drain().await.unwrap(), 3);
+        assert_eq!(numbers(flaky.broker.published_to("click")), vec![0, 1, 2]);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_saved_offset_after_a_restart() {
+        let dir = test_dir("restart");
+        let flaky = Flaky::default();
+        flaky.down.store(true, Ordering::SeqCst);
+        let publisher = SpoolingPublisher::new(flaky.clone(), SpoolConfig::new(&dir)).unwrap();
+        publish_all(&publisher, &dest("click"), 0..4).await;
+        let spool = publisher.spool_for(&dest("click"));
+        // pretend the first two were drained before the process stopped
+        let second = {
+            let spool = spool.lock().await;
+            let (_, first) = read_record_at(&spool.path, 0).unwrap().unwrap();
+            read_record_at(&spool.path, first).unwrap().unwrap().1
+        };
+        replay::write_checkpoint(&dir.join(spool_file_name(&dest("click"))).with_extension("offset"), second).await.unwrap();
+        drop(publisher);
+
+        flaky.down.store(false, Ordering::SeqCst);
+        let publisher = SpoolingPublisher::new(flaky.clone(), SpoolConfig::new(&dir)).unwrap();
+        assert_eq!(publisher.depth(), 2);
+        publisher.drain().await.unwrap();
+        assert_eq!(numbers(flaky.broker.published_to("click")), vec![2, 3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_rejected_event_is_dead_lettered_and_does_not_block_the_rest() {
+        let dir = test_dir("poison");
+        let flaky = Flaky::default();
+        let publisher = SpoolingPublisher::new(flaky.clone(), SpoolConfig::new(&dir)).unwrap();
+        flaky.down.store(true, Ordering::SeqCst);
+        for body in ["0", "poison", "1"] {
+            publisher.publish_bytes(&dest("click"), Bytes::from(body), &Metadata::default()).await.unwrap();
+        }
+        flaky.down.store(false, Ordering::SeqCst);
+        assert_eq!(publisher.drain().await.unwrap(), 2);
+        assert_eq!(publisher.depth(), 0);
+        assert_eq!(numbers(flaky.broker.published_to("click")), vec![0, 1]);
+
+        let mut reader = FileReader::open(dead_letter_path(&dir.join(spool_file_name(&dest("click"))))).await.unwrap();
+        let record = reader.next_record().await.unwrap().unwrap();
+        assert_eq!((record.source, record.original_body), (dest("click"), Bytes::from("poison")));
+        assert!(reader.next_record().await.unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_spool_does_not_hold_up_other_destinations() {
+        let dir = test_dir("unreadable");
+        let flaky = Flaky::default();
+        let publisher = SpoolingPublisher::new(flaky.clone(), SpoolConfig::new(&dir)).unwrap();
+        flaky.down.store(true, Ordering::SeqCst);
+        publish_all(&publisher, &dest("click"), 0..2).await;
+        publish_all(&publisher, &dest("view"), 0..2).await;
+        std::fs::remove_file(dir.join(spool_file_name(&dest("click")))).unwrap();
+
+        flaky.down.store(false, Ordering::SeqCst);
+        assert_eq!(publisher.drain().await.unwrap(), 2);
+        assert_eq!(numbers(flaky.broker.published_to("view")), vec![0, 1]);
+        assert_eq!(publisher.depth(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
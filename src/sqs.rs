@@ -1,120 +1,5443 @@
-use std::vec::Vec;
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, fmt, future::Future, ops::Deref, sync::{Arc, Mutex, OnceLock}, time::{Duration, Instant}, vec::Vec};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream::{self, FuturesUnordered, Stream}, FutureExt, StreamExt};
+use chrono::{DateTime, TimeZone, Utc};
 pub use aws_config;
 pub use aws_sdk_sqs::{model::Message, Client, Region};
-use serde::{Serialize, de::DeserializeOwned};
+use aws_sdk_sqs::model::{DeleteMessageBatchRequestEntry, MessageAttributeValue, MessageSystemAttributeName, QueueAttributeName, SendMessageBatchRequestEntry};
+use aws_sdk_sqs::types::{Blob, SdkError};
+use aws_config::sts::AssumeRoleProvider;
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use aws_smithy_types::retry::ProvideErrorKind;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use md5::Md5;
+use sha2::{Digest, Sha256};
 use serde_json;
+use tokio::{sync::{OwnedSemaphorePermit, Semaphore}, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use crate::consumer::{self, ConsumerOptions, OnDecodeError};
+use crate::deadletter::DeadLetterRecord;
 use crate::err::EventfulError;
+use crate::idempotency::IdempotencyGuard;
+use crate::metrics;
+use crate::observer::{self, ConsumerIdle, ConsumerReconnected, ConsumerStarted, ReceiptsNotDeleted, VisibilityExtensionFailed};
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+use crate::pubstats::PublisherStats;
+use crate::retry::{Backoff, RetryPolicy, execute_with_retry};
+use crate::sns;
+use crate::subscriber::{Ack, Delivery, OnDrop, Subscriber};
+use crate::trace;
+use crate::workers::ShardedPool;
+/// Derive Event, with the queue and the FIFO group and dedup keys given as attributes.
+///
+/// # Examples:
+/// ```
+/// # use eventful::prelude::*;
+/// # use eventful::sqs::SqsEvent;
+/// #[derive(Serialize, Deserialize, SqsEvent)]
+/// #[eventful(queue_env = "ORDERS_QUEUE_URL", group_key = "customer_id")]
+/// struct OrderPlaced {
+///     customer_id: String,
+///     total_cents: u64,
+/// }
+/// # let order = OrderPlaced{customer_id: "c-17".to_string(), total_cents: 2500};
+/// # assert_eq!(order.group_id(), Some("c-17".to_string()));
+/// ```
+#[cfg(feature = "derive")]
+pub use eventful_derive::SqsEvent;
 
 
 pub trait Event: Serialize + DeserializeOwned {
-    fn queue_url() -> &'static str;
+    /// A queue URL, or just the queue's name, which ClientSQS resolves to this account and region's queue at first use.
+    /// Implement either this or queue_env_var(). Call try_queue_url() instead of this: when only queue_env_var() is implemented
+    /// this is the URL from the variable, or empty if it can't be resolved, where try_queue_url() says why
+    #[deprecated(since = "0.1.1", note = "call try_queue_url(), which fails instead of returning an empty URL; implementing queue_url() still names the queue")]
+    fn queue_url() -> &'static str {
+        match Self::queue_env_var() {
+            Some(var) => queue_url_from_env(std::any::type_name::<Self>(), var).unwrap_or_default(),
+            None => "",
+        }
+    }
+    /// An env var holding the queue URL, so the same binary can run against dev and prod queues. When Some, the variable
+    /// is read and validated as a QueueUrl the first time it is needed, then cached. #[derive(SqsEvent)]'s queue_env sets it
+    fn queue_env_var() -> Option<&'static str> {
+        None
+    }
+    /// Like queue_url(), but an EventfulError::Config when the URL can't be resolved (i.e. queue_env_var()'s variable is
+    /// unset or isn't a queue URL, or neither is implemented). eventful publishes and receives through this,
+    /// so override it whenever a hand-written queue_url() can fail
+    fn try_queue_url() -> Result<&'static str, EventfulError> {
+        #[allow(deprecated)]
+        match (Self::queue_env_var(), Self::queue_url()) {
+            (Some(var), _) => queue_url_from_env(std::any::type_name::<Self>(), var),
+            (None, "") => Err(EventfulError::Config(format!("{} implements neither queue_url() nor queue_env_var()", std::any::type_name::<Self>()))),
+            (None, queue_url) => Ok(queue_url),
+        }
+    }
+    /// The queue URL, resolved and validated
+    fn queue() -> Result<QueueUrl, EventfulError> {
+        QueueUrl::try_from(Self::try_queue_url()?.to_string())
+    }
+    /// The queue as a URL or a name, depending on what try_queue_url() returns
+    fn queue_ref() -> Result<QueueRef, EventfulError> {
+        QueueRef::parse(Self::try_queue_url()?)
+    }
+    /// The queue this event is published to, which is queue_ref() unless overridden, i.e. to shard one type across
+    /// several queues by tenant tier. Consumers of the other queues name them explicitly (see ClientSQS::receive_at)
+    fn queue_for(&self) -> Result<QueueRef, EventfulError> {
+        Self::queue_ref()
+    }
     /// Messages that belong to the same message group are always processed one by one.  
     /// [Read more](https://docs.aws.amazon.com/AWSSimpleQueueService/latest/SQSDeveloperGuide/using-messagegroupid-property.html) on docs.aws.amazon.com 
     fn group_id(&self) -> Option<String> {
         None
     }
+    /// Sent as the MessageDeduplicationId, which FIFO queues use to drop a repeat of the same message within 5 minutes.
+    /// Only for FIFO queues: publishing to a standard queue with one fails before anything is sent.
+    /// When it returns None, ClientSQS's FifoOptions decide what is sent instead (see DedupStrategy)
+    fn dedup_id(&self) -> Option<String> {
+        None
+    }
+    /// Message attributes to send with every publish of the event, i.e. a tenant to route by
+    fn attributes(&self) -> Attributes {
+        Attributes::default()
+    }
 }
 
 
+/// The longest DelaySeconds SQS accepts
+pub const MAX_DELAY: Duration = Duration::from_secs(900);
 
-pub struct ClientSQS {
-    client: Client,
+/// The longest a received message can be kept invisible, counted from when it was received
+pub const MAX_VISIBILITY: Duration = Duration::from_secs(43_200);
+
+/// The largest message body SQS accepts (256 KiB)
+pub const MAX_MESSAGE_BYTES: usize = 256 * 1024;
+
+/// When set (and not empty), ClientSQS::new and ClientSQSBuilder send requests to this endpoint unless one is given explicitly,
+/// i.e. EVENTFUL_SQS_ENDPOINT=http://localhost:4566 to run against localstack without changing code
+pub const ENDPOINT_ENV_VAR: &str = "EVENTFUL_SQS_ENDPOINT";
+
+fn endpoint_from_env() -> Option<String> {
+    endpoint_from(std::env::var(ENDPOINT_ENV_VAR).ok())
 }
 
-impl ClientSQS {
+/// An empty EVENTFUL_SQS_ENDPOINT counts as unset
+fn endpoint_from(value: Option<String>) -> Option<String> {
+    value.filter(|endpoint| !endpoint.is_empty())
+}
 
-    /// Instantiate a new messenger
-    pub async fn new(region: &'static str) -> Self {
-        let config = aws_config::from_env().region(Region::new(region)).load().await;
-        let client = Client::new(&config);
-        ClientSQS{client}
+/// The queue URL in an env var, which is read and validated the first time it is asked for and then kept.
+/// Failures aren't cached, so a variable set later is still picked up
+fn queue_url_from_env(event: &str, var: &'static str) -> Result<&'static str, EventfulError> {
+    static QUEUE_URLS: OnceLock<Mutex<HashMap<&'static str, &'static str>>> = OnceLock::new();
+    let mut queue_urls = QUEUE_URLS.get_or_init(Default::default).lock().unwrap();
+    if let Some(queue_url) = queue_urls.get(var) {
+        return Ok(queue_url)
     }
+    let queue_url = std::env::var(var)
+        .map_err(|_| EventfulError::Config(format!("the queue URL for {} comes from the env var {}, which is not set", event, var)))?;
+    if let Err(err) = QueueUrl::parse(&queue_url) {
+        return Err(EventfulError::Config(format!("the env var {} (the queue URL for {}) is not a queue URL: {}", var, event, err)))
+    }
+    let queue_url: &'static str = Box::leak(queue_url.into_boxed_str());
+    queue_urls.insert(var, queue_url);
+    Ok(queue_url)
+}
 
-    pub async fn poll_messages(&self, queue_url: &str, delete_on_receipt: bool) -> Result<Vec<Message>, EventfulError> {
-        let message_batch = self.client
-            .receive_message()
-            .queue_url(queue_url)
-            .send().await?;
 
-        let messages = message_batch.messages.unwrap_or_default();
-        
-        if delete_on_receipt {
-            for message in &messages {
-                let receipt_handle = match &message.receipt_handle {
-                    Some(val) => val,
-                    None => continue,
-                };
-                let _ = &self.client.delete_message()
-                    .queue_url(queue_url)
-                    .receipt_handle(receipt_handle)
-                    .send().await?;
+/// A validated SQS queue URL, i.e. "https://sqs.us-east-1.amazonaws.com/123456789012/orders.fifo".
+/// The path must be a 12 digit account id and a queue name (up to 80 of [A-Za-z0-9_-], plus .fifo for FIFO queues);
+/// any http(s) host is accepted, so localstack URLs are fine, including its path-style ones
+/// ("http://localhost:4566/queue/us-east-1/000000000000/orders"). It derefs to &str, so it can be passed anywhere a queue URL is
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct QueueUrl {
+    url: String,
+    /// Where the account id starts in url
+    path: usize,
+}
+
+impl QueueUrl {
+    pub fn parse(url: &str) -> Result<Self, EventfulError> {
+        QueueUrl::try_from(url.to_string())
+    }
+
+    /// FIFO queue names end in .fifo
+    pub fn is_fifo(&self) -> bool {
+        self.queue_name().ends_with(".fifo")
+    }
+
+    pub fn queue_name(&self) -> &str {
+        self.url.rsplit('/').next().unwrap_or_default()
+    }
+
+    pub fn account_id(&self) -> &str {
+        self.url[self.path..].split('/').next().unwrap_or_default()
+    }
+
+    /// The region, when the host names one (sqs.<region>.amazonaws.com, or the legacy <region>.queue.amazonaws.com)
+    /// or the URL is path-style (<host>/queue/<region>/<account id>/<queue name>)
+    pub fn region(&self) -> Option<&str> {
+        let mut prefix = self.url[..self.path].trim_end_matches('/').rsplit('/');
+        if let (Some(region), Some("queue")) = (prefix.next(), prefix.next()) {
+            return Some(region)
+        }
+        let host = self.host();
+        let host = host.split(':').next().unwrap_or(host);
+        match host.split('.').collect::<Vec<&str>>().as_slice() {
+            ["sqs", region, _, ..] => Some(*region),
+            [region, "queue", "amazonaws", "com"] => Some(*region),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.url
+    }
+
+    fn host(&self) -> &str {
+        let after_scheme = self.url.split_once("://").map(|(_, rest)| rest).unwrap_or(&self.url);
+        after_scheme.split('/').next().unwrap_or_default()
+    }
+}
+
+impl TryFrom<String> for QueueUrl {
+    type Error = EventfulError;
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        let invalid = |problem: &str| Err(EventfulError::InvalidQueueUrl(format!("{}: {}", url, problem)));
+        let rest = match url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")) {
+            Some(rest) => rest,
+            None => return invalid("must start with https:// or http://"),
+        };
+        let segments: Vec<&str> = rest.trim_end_matches('/').split('/').collect();
+        let (host, region, account, name) = match segments.as_slice() {
+            [host, account, name] => (*host, None, *account, *name),
+            [host, "queue", region, account, name] => (*host, Some(*region), *account, *name),
+            _ => return invalid("expected https://<host>/<account id>/<queue name>, which may be a queue name rather than a URL"),
+        };
+        if host.is_empty() {
+            return invalid("the host is empty")
+        }
+        if account.len() != 12 || !account.bytes().all(|b| b.is_ascii_digit()) {
+            return invalid("the account id must be 12 digits")
+        }
+        let base = name.strip_suffix(".fifo").unwrap_or(name);
+        if name.is_empty() || name.len() > 80 || !base.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return invalid("the queue name must be up to 80 of [A-Za-z0-9_-], optionally ending in .fifo")
+        }
+        if region == Some("") {
+            return invalid("the region is empty")
+        }
+        let path = url.len() - rest.len() + host.len() + 1 + region.map(|region| "queue/".len() + region.len() + 1).unwrap_or(0);
+        let url = url.trim_end_matches('/').to_string();
+        Ok(QueueUrl{url, path})
+    }
+}
+
+impl From<QueueUrl> for String {
+    fn from(queue_url: QueueUrl) -> Self {
+        queue_url.url
+    }
+}
+
+impl std::str::FromStr for QueueUrl {
+    type Err = EventfulError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        QueueUrl::parse(url)
+    }
+}
+
+impl Deref for QueueUrl {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.url
+    }
+}
+
+impl AsRef<str> for QueueUrl {
+    fn as_ref(&self) -> &str {
+        &self.url
+    }
+}
+
+impl fmt::Display for QueueUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.url)
+    }
+}
+
+
+/// A queue given either by URL, or by name to be looked up with GetQueueUrl (which ClientSQS caches)
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum QueueRef {
+    Url(QueueUrl),
+    Name(String),
+}
+
+impl QueueRef {
+    /// Anything starting with http:// or https:// must be a valid QueueUrl, and anything else a valid queue name
+    pub fn parse(queue: &str) -> Result<Self, EventfulError> {
+        if queue.starts_with("https://") || queue.starts_with("http://") {
+            return Ok(QueueRef::Url(QueueUrl::parse(queue)?))
+        }
+        let base = queue.strip_suffix(".fifo").unwrap_or(queue);
+        if queue.is_empty() || queue.len() > 80 || !base.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+            return Err(EventfulError::InvalidQueueUrl(format!("{}: not a URL, and not a queue name of up to 80 of [A-Za-z0-9_-], optionally ending in .fifo", queue)))
+        }
+        Ok(QueueRef::Name(queue.to_string()))
+    }
+}
+
+impl From<QueueUrl> for QueueRef {
+    fn from(queue_url: QueueUrl) -> Self {
+        QueueRef::Url(queue_url)
+    }
+}
+
+impl fmt::Display for QueueRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueRef::Url(url) => f.write_str(url),
+            QueueRef::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+
+/// How long ClientSQS remembers the URL GetQueueUrl returned for a name
+pub const QUEUE_URL_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// A queue's name and owner account id
+type QueueKey = (String, Option<String>);
+
+/// Queue URLs by (name, owner account id), each with when it was looked up
+#[derive(Default)]
+struct QueueUrlCache {
+    entries: Mutex<HashMap<QueueKey, (QueueUrl, Instant)>>,
+}
+
+impl QueueUrlCache {
+    fn get(&self, name: &str, owner: Option<&str>) -> Option<QueueUrl> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(&(name.to_string(), owner.map(|owner| owner.to_string())))
+            .filter(|(_, looked_up)| looked_up.elapsed() < QUEUE_URL_TTL)
+            .map(|(url, _)| url.clone())
+    }
+
+    fn insert(&self, name: &str, owner: Option<&str>, url: QueueUrl) {
+        self.entries.lock().unwrap().insert((name.to_string(), owner.map(|owner| owner.to_string())), (url, Instant::now()));
+    }
+
+    /// Forget every URL looked up for the name, whichever account owns it
+    fn forget(&self, name: &str) {
+        self.entries.lock().unwrap().retain(|(cached, _), _| cached != name);
+    }
+}
+
+
+/// SQS's codes for a queue which doesn't exist. SendMessage and ReceiveMessage still return the legacy one
+fn is_missing_queue(code: Option<&str>) -> bool {
+    matches!(code, Some("QueueDoesNotExist") | Some("AWS.SimpleQueueService.NonExistentQueue"))
+}
+
+
+/// Catch what SQS would reject with a 400 before sending: FIFO queues need a group id,
+/// and standard queues take neither group nor deduplication ids. FIFO-ness comes from the .fifo suffix, as SQS requires
+pub(crate) fn check_fifo(queue_url: &str, group_id: Option<&str>, dedup_id: Option<&str>) -> Result<(), EventfulError> {
+    let fifo = queue_url.trim_end_matches('/').ends_with(".fifo");
+    if fifo && group_id.is_none() {
+        return Err(EventfulError::FifoMismatch(format!("{} is a FIFO queue, so every message needs a group id", queue_url)))
+    }
+    if !fifo && group_id.is_some() {
+        return Err(EventfulError::FifoMismatch(format!("{} is a standard queue, which does not take group ids", queue_url)))
+    }
+    if !fifo && dedup_id.is_some() {
+        return Err(EventfulError::FifoMismatch(format!("{} is a standard queue, which does not take deduplication ids", queue_url)))
+    }
+    Ok(())
+}
+
+
+/// The longest MessageDeduplicationId SQS accepts
+pub const MAX_DEDUP_ID_LEN: usize = 128;
+
+/// How a message published to a FIFO queue gets its deduplication id. An id from Event::dedup_id() always wins.
+/// Standard queues take no id, so the strategy is ignored for them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupStrategy {
+    /// Send no id when the event has none, leaving deduplication to the queue's ContentBasedDeduplication attribute
+    /// (SQS rejects the message if the queue doesn't have it on)
+    #[default]
+    ContentBased,
+    /// Every event must have a dedup_id(); one without fails with FifoMismatch before anything is sent
+    Explicit,
+    /// Use dedup_hash of the serialized body when the event has no dedup_id(), so identical bodies are deduplicated
+    /// within SQS's 5 minute window whatever the queue's attributes
+    HashBody,
+}
+
+impl DedupStrategy {
+    /// The deduplication id to send with body to queue_url, given the event's own dedup_id()
+    pub fn dedup_id(&self, queue_url: &str, explicit: Option<String>, body: &str) -> Result<Option<String>, EventfulError> {
+        if explicit.is_some() || !queue_url.trim_end_matches('/').ends_with(".fifo") {
+            return Ok(explicit)
+        }
+        match self {
+            DedupStrategy::ContentBased => Ok(None),
+            DedupStrategy::Explicit => Err(EventfulError::FifoMismatch(format!(
+                "{} is a FIFO queue and the dedup strategy is Explicit, but the event has no dedup_id()", queue_url))),
+            DedupStrategy::HashBody => Ok(Some(dedup_hash(body))),
+        }
+    }
+}
+
+/// The hex SHA-256 of a message body, as a deduplication id. It is 64 characters, within MAX_DEDUP_ID_LEN
+pub fn dedup_hash(body: &str) -> String {
+    Sha256::digest(body.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+/// How ClientSQS publishes to FIFO queues
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FifoOptions {
+    pub dedup: DedupStrategy,
+}
+
+impl FifoOptions {
+    pub fn dedup(mut self, dedup: DedupStrategy) -> Self {
+        self.dedup = dedup;
+        self
+    }
+}
+
+
+/// What SQS returned for a published event
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublishReceiptSqs {
+    pub message_id: String,
+    /// The message's position within its group, only returned by FIFO queues
+    pub sequence_number: Option<String>,
+    /// The MD5 digest (hex) of the body SQS stored, which has been checked against the body sent
+    pub md5_of_body: String,
+    pub queue: QueueUrl,
+    /// The size of the body sent
+    pub bytes: usize,
+}
+
+#[deprecated(since = "0.1.1", note = "renamed PublishReceiptSqs")]
+pub type PublishReceipt = PublishReceiptSqs;
+
+impl PublishReceiptSqs {
+    /// Build a receipt from what SendMessage (or a SendMessageBatch entry) returned for a body with this digest and size
+    fn verify(queue: &QueueUrl, digest: String, bytes: usize, message_id: Option<String>, sequence_number: Option<String>, md5_of_body: Option<String>)
+        -> Result<Self, EventfulError>
+    {
+        let message_id = message_id.ok_or_else(|| EventfulError::MissingField(format!("MessageId, publishing to {}", queue)))?;
+        let md5_of_body = md5_of_body.ok_or_else(|| EventfulError::MissingField(format!("MD5OfMessageBody, publishing {} to {}", message_id, queue)))?;
+        if !md5_of_body.eq_ignore_ascii_case(&digest) {
+            return Err(EventfulError::ChecksumMismatch{message_id, expected: digest, returned: md5_of_body})
+        }
+        Ok(PublishReceiptSqs{message_id, sequence_number, md5_of_body, queue: queue.clone(), bytes})
+    }
+}
+
+/// The MD5 digest of a body as hex, which is how SQS returns MD5OfMessageBody
+pub(crate) fn md5_hex(body: &str) -> String {
+    Md5::digest(body.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+/// The most messages one SendMessageBatch call may carry
+pub const MAX_BATCH_ENTRIES: usize = 10;
+
+
+/// Why one entry of a batch call failed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchEntryError {
+    /// The SQS error code, or MessageTooLarge / FifoMismatch when an event was rejected before sending
+    pub code: String,
+    pub message: String,
+    /// True when the entry itself is at fault, so sending it again won't help
+    pub sender_fault: bool,
+}
+
+impl BatchEntryError {
+    /// True when a receipt handle was malformed or has expired (i.e. the message was received again since), so it can never be deleted with it
+    pub fn is_invalid_handle(&self) -> bool {
+        self.code == "ReceiptHandleIsInvalid" || (self.code == "InvalidParameterValue" && self.message.contains("ReceiptHandle"))
+    }
+}
+
+impl fmt::Display for BatchEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+
+/// What a failed entry sent, kept so BatchResult::retry_failed can send it again
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum BatchEntry {
+    Send(OutgoingSQS),
+    Delete(ReceiptHandle),
+}
+
+
+/// The outcome of ClientSQS::publish_batch (`BatchResult<PublishReceiptSqs>`) or ack_batch (`BatchResult<()>`).
+/// Entries are identified by their index in what was passed in, however they were split into calls of MAX_BATCH_ENTRIES,
+/// and both lists are in index order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BatchResult<TOk> {
+    pub succeeded: Vec<(usize, TOk)>,
+    pub failed: Vec<(usize, BatchEntryError)>,
+    /// Failed entries which weren't the sender's fault, with the queue URL they were for
+    retryable: BTreeMap<usize, (String, BatchEntry)>,
+}
+
+impl<TOk> BatchResult<TOk> {
+    fn new() -> Self {
+        BatchResult{succeeded: Vec::new(), failed: Vec::new(), retryable: BTreeMap::new()}
+    }
+
+    /// True when every entry succeeded
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
 
+    /// The error for the entry at index, if it failed
+    pub fn error(&self, index: usize) -> Option<&BatchEntryError> {
+        self.failed.iter().find(|(i, _)| *i == index).map(|(_, err)| err)
+    }
+
+    /// The indexes of failed entries which retry_failed would send again
+    pub fn retryable(&self) -> impl Iterator<Item = usize> + '_ {
+        self.retryable.keys().copied()
+    }
+
+    fn succeed(&mut self, index: usize, ok: TOk) {
+        self.succeeded.push((index, ok));
+    }
+
+    /// Record a failure, keeping what the entry sent to queue_url if it is worth retrying
+    fn fail(&mut self, index: usize, err: BatchEntryError, retry: Option<(&str, BatchEntry)>) {
+        if let (false, Some((queue_url, entry))) = (err.sender_fault, retry) {
+            self.retryable.insert(index, (queue_url.to_string(), entry));
+        }
+        self.failed.push((index, err));
+    }
+
+    /// Remove the retryable entries from failed, to send them again, by queue URL
+    fn take_retryable(&mut self) -> BTreeMap<String, Vec<(usize, BatchEntry)>> {
+        let retryable = std::mem::take(&mut self.retryable);
+        self.failed.retain(|(i, _)| !retryable.contains_key(i));
+        let mut by_queue: BTreeMap<String, Vec<(usize, BatchEntry)>> = BTreeMap::new();
+        for (i, (queue_url, entry)) in retryable {
+            by_queue.entry(queue_url).or_default().push((i, entry));
+        }
+        by_queue
+    }
+
+    fn sort(&mut self) {
+        self.succeeded.sort_by_key(|(i, _)| *i);
+        self.failed.sort_by_key(|(i, _)| *i);
+    }
+}
+
+impl BatchResult<PublishReceiptSqs> {
+    /// Send the failed events which weren't the sender's fault again, in rounds until they are all sent or client's retry policy
+    /// runs out of attempts, waiting its backoff before each round. Returns the combined result, still by the original indexes.
+    /// A call which fails outright is returned as an error (self is unchanged, so it can be retried again)
+    pub async fn retry_failed(&self, client: &ClientSQS) -> Result<Self, EventfulError> {
+        let mut result = self.clone();
+        for attempt in 1..=client.retry.max_attempts {
+            if result.retryable.is_empty() {
+                break
+            }
+            tokio::time::sleep(client.retry.delay_for(attempt)).await;
+            for (queue_url, entries) in result.take_retryable() {
+                let messages = entries.into_iter()
+                    .filter_map(|(i, entry)| match entry {
+                        BatchEntry::Send(message) => Some((i, message)),
+                        BatchEntry::Delete(_) => None,
+                    })
+                    .collect();
+                client.send_entries(&queue_url, messages, &mut result).await?;
             }
         }
-        Ok(messages)
-        
+        result.sort();
+        Ok(result)
     }
+}
 
-    
-    /// Return the body of messages as strings
-    pub async fn poll_strings(&self, queue_url: &str, delete_on_receipt: bool) -> Result<Vec<String>, EventfulError> {
-        let messages = self.poll_messages(queue_url, delete_on_receipt).await?;
-        let mut resp = Vec::new();
-        for message in messages {
-            let body = &message.body.unwrap_or_default();
-            resp.push(body.clone());
+impl BatchResult<()> {
+    /// The indexes of handles which were invalid or had expired
+    pub fn invalid_handles(&self) -> impl Iterator<Item = usize> + '_ {
+        self.failed.iter().filter(|(_, err)| err.is_invalid_handle()).map(|(i, _)| *i)
+    }
+
+    /// Delete the messages which failed for reasons other than the sender's fault again, in rounds as
+    /// BatchResult<PublishReceiptSqs>::retry_failed does
+    pub async fn retry_failed(&self, client: &ClientSQS) -> Result<Self, EventfulError> {
+        let mut result = self.clone();
+        for attempt in 1..=client.retry.max_attempts {
+            if result.retryable.is_empty() {
+                break
+            }
+            tokio::time::sleep(client.retry.delay_for(attempt)).await;
+            for (queue_url, entries) in result.take_retryable() {
+                let handles = entries.into_iter()
+                    .filter_map(|(i, entry)| match entry {
+                        BatchEntry::Delete(handle) => Some((i, handle)),
+                        BatchEntry::Send(_) => None,
+                    })
+                    .collect();
+                client.delete_entries(&queue_url, handles, &mut result).await?;
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
+}
+
+
+/// Remove the entry a batch response's id refers to, if it is one of this call's
+fn take_entry<E>(pending: &mut HashMap<usize, E>, id: Option<&str>) -> Option<(usize, E)> {
+    let index = id?.parse::<usize>().ok()?;
+    pending.remove(&index).map(|entry| (index, entry))
+}
+
+
+/// The most message attributes SQS accepts on one message
+pub const MAX_ATTRIBUTES: usize = 10;
+
+
+/// The value of one message attribute
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttributeValue {
+    String(String),
+    /// Kept as the decimal string SQS sends, so no precision is lost
+    Number(String),
+    Binary(Vec<u8>),
+}
+
+impl AttributeValue {
+    fn to_sdk(&self) -> MessageAttributeValue {
+        match self {
+            AttributeValue::String(value) => MessageAttributeValue::builder().data_type("String").string_value(value).build(),
+            AttributeValue::Number(value) => MessageAttributeValue::builder().data_type("Number").string_value(value).build(),
+            AttributeValue::Binary(value) => MessageAttributeValue::builder().data_type("Binary").binary_value(Blob::new(value.clone())).build(),
         }
-        Ok(resp)
     }
 
+    /// Custom data types (i.e. "Number.int" or "String.tenant") map to their base type
+    fn from_sdk(value: &MessageAttributeValue) -> Option<Self> {
+        let data_type = value.data_type.as_deref().unwrap_or_default();
+        match data_type.split('.').next() {
+            Some("String") => value.string_value.clone().map(AttributeValue::String),
+            Some("Number") => value.string_value.clone().map(AttributeValue::Number),
+            Some("Binary") => value.binary_value.as_ref().map(|blob| AttributeValue::Binary(blob.as_ref().to_vec())),
+            _ => None,
+        }
+    }
+}
 
-    /// Return the body of messages as deserializable structs
-    pub async fn poll<T: DeserializeOwned>(&self, queue_url: &str, delete_on_receipt: bool) -> Result<Vec<T>, EventfulError> {
-        let messages = self.poll_messages(queue_url, delete_on_receipt).await?;
-        let mut resp = Vec::new();
-        for message in messages {
-            let body = &message.body.unwrap_or_default();
-            let jz: T = serde_json::from_str(body)?; /* {
-                Ok(val) => val,
-                Err(_) => {
-                    return Err(EventfulError{msg:"JSON dserialization error".to_string()}.into())
-                }
-            };*/
-            resp.push(jz)
+
+/// Message attributes by name, sent alongside the body and readable without decoding it.
+/// Built with `Attributes::new().string("tenant", "acme").number("priority", 5)`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Attributes(BTreeMap<String, AttributeValue>);
+
+impl Attributes {
+    pub fn new() -> Self {
+        Attributes::default()
+    }
+
+    pub fn string(mut self, name: &str, value: &str) -> Self {
+        self.0.insert(name.to_string(), AttributeValue::String(value.to_string()));
+        self
+    }
+
+    pub fn number<N: fmt::Display>(mut self, name: &str, value: N) -> Self {
+        self.0.insert(name.to_string(), AttributeValue::Number(value.to_string()));
+        self
+    }
+
+    pub fn binary(mut self, name: &str, value: Vec<u8>) -> Self {
+        self.0.insert(name.to_string(), AttributeValue::Binary(value));
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: AttributeValue) -> Option<AttributeValue> {
+        self.0.insert(name.to_string(), value)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AttributeValue> {
+        self.0.get(name)
+    }
+
+    /// The value of a String attribute
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match self.0.get(name) {
+            Some(AttributeValue::String(value)) => Some(value),
+            _ => None,
         }
-        Ok(resp)
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &AttributeValue)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value))
+    }
 
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 
-    /// publish a message (could be a string or serializable struct) to the queue with a given group_id
-    pub async fn publish<T: Event>(&self, event: &T) -> Result<String, EventfulError> {
-        let body = serde_json::to_string(event)?;
-        let send_msg = match event.group_id() {
-            Some(_group_id) => { self.client
-                .send_message()
-                .queue_url(<T as Event>::queue_url())
-                .message_body(body)
-                .message_group_id("abc".to_string())},
-            None => {self.client
-                .send_message()
-                .queue_url(<T as Event>::queue_url())
-                .message_body(body)
-            },
-        };
-        let output = send_msg.send().await?;
-        let message_id = output
-            .message_id.unwrap();
-            //.ok_or(EventfulError{msg: "push request did not return a message_id!".to_string()})?;  
-        Ok(message_id)
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Add other's attributes, replacing any with the same name
+    pub fn merge(mut self, other: &Attributes) -> Self {
+        self.0.extend(other.0.iter().map(|(name, value)| (name.clone(), value.clone())));
+        self
+    }
+
+    /// Check the attributes are ones SQS accepts: at most 10, with names of up to 256 of [A-Za-z0-9_.-] which don't
+    /// start with the reserved AWS. or Amazon. prefixes, and numbers which parse
+    pub fn validate(&self) -> Result<(), EventfulError> {
+        if self.0.len() > MAX_ATTRIBUTES {
+            return Err(EventfulError::InvalidAttributes(format!("there are {} attributes, but SQS accepts at most {}", self.0.len(), MAX_ATTRIBUTES)))
+        }
+        for (name, value) in &self.0 {
+            let invalid = |problem: &str| Err(EventfulError::InvalidAttributes(format!("{}: {}", name, problem)));
+            let lower = name.to_ascii_lowercase();
+            if lower.starts_with("aws.") || lower.starts_with("amazon.") {
+                return invalid("names starting with AWS. or Amazon. are reserved")
+            }
+            if name.is_empty() || name.len() > 256 || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.') {
+                return invalid("names must be up to 256 of [A-Za-z0-9_.-]")
+            }
+            if name.starts_with('.') || name.ends_with('.') || name.contains("..") {
+                return invalid("names can't start or end with a period, or have two in a row")
+            }
+            if let AttributeValue::Number(number) = value {
+                if number.parse::<f64>().is_err() {
+                    return invalid("the value is not a number")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn to_sdk(&self) -> Option<HashMap<String, MessageAttributeValue>> {
+        match self.0.is_empty() {
+            true => None,
+            false => Some(self.0.iter().map(|(name, value)| (name.clone(), value.to_sdk())).collect()),
+        }
+    }
+
+    fn from_message(message: &Message) -> Self {
+        let attributes = message.message_attributes.as_ref().map(|attrs| attrs.iter()
+            .filter_map(|(name, value)| Some((name.clone(), AttributeValue::from_sdk(value)?)))
+            .collect());
+        Attributes(attributes.unwrap_or_default())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::env;
-    use tokio::runtime::Runtime;
 
+/// The receipt handle ReceiveMessage returned for a message, which is what deletes it (ClientSQS::ack, ack_batch)
+/// or changes its visibility (ClientSQS::set_visibility). Each receive gets a new one, and only the latest works
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ReceiptHandle(String);
 
+impl ReceiptHandle {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for ReceiptHandle {
+    fn from(handle: String) -> Self {
+        ReceiptHandle(handle)
+    }
+}
+
+impl From<&str> for ReceiptHandle {
+    fn from(handle: &str) -> Self {
+        ReceiptHandle(handle.to_string())
+    }
+}
+
+impl From<ReceiptHandle> for String {
+    fn from(handle: ReceiptHandle) -> Self {
+        handle.0
+    }
+}
+
+impl Deref for ReceiptHandle {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for ReceiptHandle {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ReceiptHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+
+/// How ClientSQS::poll_messages (and poll_strings and poll) receives. The default long polls for up to 20 seconds
+/// and takes up to 10 messages, so override only what you need:
+/// `ReceiveOptions{visibility_timeout: Some(60), ..Default::default()}`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReceiveOptions {
+    /// 0 to 20. 0 short polls, which returns straight away but often empty
+    pub wait_time_seconds: i32,
+    /// 1 to 10
+    pub max_messages: i32,
+    /// Overrides the queue's visibility timeout for these messages, 0 to 43200 seconds
+    pub visibility_timeout: Option<i32>,
+    /// System attributes to return, i.e. QueueAttributeName::All
+    pub attribute_names: Vec<QueueAttributeName>,
+    /// Message attributes to return, or "All"
+    pub message_attribute_names: Vec<String>,
+    /// Decode the Message inside SNS notification envelopes, for queues subscribed to a topic without raw message delivery.
+    /// Bodies which aren't envelopes are decoded as they are
+    pub unwrap_sns: bool,
+    /// Keep messages whose attributes don't match from every caller, settling them as the filter says.
+    /// The attributes it tests are requested even if message_attribute_names doesn't name them
+    pub filter: Option<ReceiveFilter>,
+}
+
+impl Default for ReceiveOptions {
+    fn default() -> Self {
+        ReceiveOptions{
+            wait_time_seconds: 20,
+            max_messages: 10,
+            visibility_timeout: None,
+            attribute_names: Vec::new(),
+            message_attribute_names: Vec::new(),
+            unwrap_sns: false,
+            filter: None,
+        }
+    }
+}
+
+impl ReceiveOptions {
+    /// Check the values are in the ranges ReceiveMessage accepts
+    pub fn validate(&self) -> Result<(), EventfulError> {
+        if !(0..=20).contains(&self.wait_time_seconds) {
+            return Err(EventfulError::Config(format!("wait_time_seconds is {}, but must be 0 to 20", self.wait_time_seconds)))
+        }
+        if !(1..=10).contains(&self.max_messages) {
+            return Err(EventfulError::Config(format!("max_messages is {}, but must be 1 to 10", self.max_messages)))
+        }
+        if let Some(visibility_timeout) = self.visibility_timeout {
+            if !(0..=43200).contains(&visibility_timeout) {
+                return Err(EventfulError::Config(format!("visibility_timeout is {}, but must be 0 to 43200", visibility_timeout)))
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Client-side filtering of received messages by their message attributes, for a queue shared by several kinds of event:
+/// `ReceiveFilter::new().equals("kind", "refund")`. A message passes when every predicate matches.  
+/// This is a fallback for when separate queues (or SNS filter policies) aren't an option: each rejected message still costs
+/// a receive and a visibility change or delete, and with several filtered workers a message may be received (and released)
+/// many times before the right worker gets it, counting towards the queue's maxReceiveCount each time.
+/// On a FIFO queue, a message which is released holds up the rest of its group until it is received again, and
+/// OnMismatch::Drop deletes it out of order, so a group should only ever hold events one worker wants
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReceiveFilter {
+    predicates: Vec<AttributePredicate>,
+    on_mismatch: OnMismatch,
+}
+
+impl ReceiveFilter {
+    pub fn new() -> Self {
+        ReceiveFilter::default()
+    }
+
+    /// The attribute has this value
+    pub fn equals(mut self, name: &str, value: &str) -> Self {
+        self.predicates.push(AttributePredicate::Equals(name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The attribute has one of these values
+    pub fn one_of<S: Into<String>>(mut self, name: &str, values: impl IntoIterator<Item = S>) -> Self {
+        self.predicates.push(AttributePredicate::OneOf(name.to_string(), values.into_iter().map(Into::into).collect()));
+        self
+    }
+
+    /// The attribute is present, with any value
+    pub fn exists(mut self, name: &str) -> Self {
+        self.predicates.push(AttributePredicate::Exists(name.to_string()));
+        self
+    }
+
+    /// What to do with messages which don't match. Defaults to releasing them straight away
+    pub fn on_mismatch(mut self, on_mismatch: OnMismatch) -> Self {
+        self.on_mismatch = on_mismatch;
+        self
+    }
+
+    pub fn matches(&self, attributes: &Attributes) -> bool {
+        self.predicates.iter().all(|predicate| predicate.matches(attributes))
+    }
+
+    fn attribute_names(&self) -> impl Iterator<Item = &str> {
+        self.predicates.iter().map(|predicate| predicate.name())
+    }
+}
+
+
+/// One test of a ReceiveFilter. String and Number attributes are compared by their text, so a Number sent as "5" doesn't equal "5.0"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttributePredicate {
+    /// (name, value)
+    Equals(String, String),
+    /// (name, values)
+    OneOf(String, Vec<String>),
+    Exists(String),
+}
+
+impl AttributePredicate {
+    fn name(&self) -> &str {
+        match self {
+            AttributePredicate::Equals(name, _) | AttributePredicate::OneOf(name, _) | AttributePredicate::Exists(name) => name,
+        }
+    }
+
+    pub fn matches(&self, attributes: &Attributes) -> bool {
+        let text = match attributes.get(self.name()) {
+            Some(AttributeValue::String(text)) | Some(AttributeValue::Number(text)) => Some(text.as_str()),
+            Some(AttributeValue::Binary(_)) => None,
+            None => return false,
+        };
+        match self {
+            AttributePredicate::Equals(_, value) => text == Some(value.as_str()),
+            AttributePredicate::OneOf(_, values) => text.is_some_and(|text| values.iter().any(|value| value == text)),
+            AttributePredicate::Exists(_) => true,
+        }
+    }
+}
+
+
+/// What a ReceiveFilter does with a message which doesn't match
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnMismatch {
+    /// Make it visible again after this long, for a worker with another filter to receive
+    Release(Duration),
+    /// Delete it
+    Drop,
+}
+
+impl Default for OnMismatch {
+    fn default() -> Self {
+        OnMismatch::Release(Duration::ZERO)
+    }
+}
+
+
+/// One message to send through SqsApi
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutgoingSQS {
+    pub body: String,
+    /// Required by FIFO queues
+    pub group_id: Option<String>,
+    pub dedup_id: Option<String>,
+}
+
+
+/// What SQS returned for one message sent through SqsApi, before it is checked against what was sent
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SentMessage {
+    pub message_id: Option<String>,
+    /// FIFO queues only
+    pub sequence_number: Option<String>,
+    /// The MD5 digest (hex) of the body SQS stored
+    pub md5_of_body: Option<String>,
+}
+
+impl SentMessage {
+    /// The receipt for a body with this digest and size, or ChecksumMismatch if SQS stored something else
+    fn verify(self, queue: &QueueUrl, digest: String, bytes: usize) -> Result<PublishReceiptSqs, EventfulError> {
+        PublishReceiptSqs::verify(queue, digest, bytes, self.message_id, self.sequence_number, self.md5_of_body)
+    }
+}
+
+
+/// SqsApi is the set of SQS operations eventful uses. Code which publishes or polls can hold an `Arc<dyn SqsApi>`,
+/// which is a ClientSQS in production and a testing::FakeSqs in tests
+#[async_trait]
+pub trait SqsApi: Send + Sync {
+    /// Send one message as it is, returning what SQS returned for it. SqsApiExt::publish checks the message before
+    /// sending it and verifies the MD5 digest afterwards
+    async fn send_message(&self, queue_url: &str, message: OutgoingSQS) -> Result<SentMessage, EventfulError>;
+
+    /// Send messages (in batches of up to 10), returning what SQS returned for each, in order.
+    /// On an error, messages from earlier batches may already have been sent
+    async fn send_message_batch(&self, queue_url: &str, messages: Vec<OutgoingSQS>) -> Result<Vec<SentMessage>, EventfulError>;
+
+    /// Receive up to max_messages (1 to 10), waiting up to wait_time_seconds (0 to 20) for any to arrive.
+    /// Messages include the ApproximateReceiveCount and MessageGroupId attributes
+    async fn receive_messages(&self, queue_url: &str, max_messages: i32, wait_time_seconds: i32) -> Result<Vec<Message>, EventfulError>;
+
+    async fn delete_message(&self, queue_url: &str, receipt_handle: &str) -> Result<(), EventfulError>;
+
+    /// Make a received message visible again in `seconds`
+    async fn change_visibility(&self, queue_url: &str, receipt_handle: &str, seconds: i32) -> Result<(), EventfulError>;
+
+    /// How events published through SqsApiExt get their deduplication ids on FIFO queues. ClientSQS returns its own FifoOptions
+    fn fifo(&self) -> FifoOptions {
+        FifoOptions::default()
+    }
+
+    /// The body to send for a serialized event, i.e. a pointer to S3 when ClientSQS has an S3Offload and the body is large
+    async fn prepare_body(&self, _queue_url: &str, body: String) -> Result<String, EventfulError> {
+        Ok(body)
+    }
+}
+
+
+/// SqsApiExt is implemented for every SqsApi (including `dyn SqsApi`) and handles encoding.
+/// Its events' queue_for() must be a URL: only ClientSQS's own methods resolve queue names
+#[async_trait]
+pub trait SqsApiExt: SqsApi {
+    /// Publish an event to its queue. It is checked, sent and its MD5 digest verified as ClientSQS::publish does,
+    /// using the SqsApi's fifo() and prepare_body(), but without message attributes
+    async fn publish<T: Event + Sync>(&self, event: &T) -> Result<PublishReceiptSqs, EventfulError> {
+        let body = serde_json::to_string(event)?;
+        self.publish_body(&queue_url_for(event)?, body, event.group_id(), event.dedup_id()).await
+    }
+
+    /// Publish an already serialized event, as publish does
+    async fn publish_body(&self, queue: &QueueUrl, body: String, group_id: Option<String>, dedup_id: Option<String>) -> Result<PublishReceiptSqs, EventfulError> {
+        let message = prepare_outgoing(self, queue, body, group_id, dedup_id).await?.map_err(|(_, err)| err)?;
+        let (digest, bytes) = (md5_hex(&message.body), message.body.len());
+        self.send_message(queue, message).await?.verify(queue, digest, bytes)
+    }
+
+    /// Publish events to their queues, returning their receipts in order. Every event is checked before any is sent;
+    /// after that a failed call, or a digest which doesn't match, is returned as an error, though earlier messages may have been sent
+    async fn publish_batch<T: Event + Sync>(&self, events: &[T]) -> Result<Vec<PublishReceiptSqs>, EventfulError> {
+        let mut queues: Vec<(QueueUrl, Vec<usize>, Vec<OutgoingSQS>)> = Vec::new();
+        for (i, event) in events.iter().enumerate() {
+            let queue_url = queue_url_for(event)?;
+            let body = serde_json::to_string(event)?;
+            let message = prepare_outgoing(self, &queue_url, body, event.group_id(), event.dedup_id()).await?.map_err(|(_, err)| err)?;
+            match queues.iter_mut().find(|(other, _, _)| *other == queue_url) {
+                Some((_, indexes, messages)) => {
+                    indexes.push(i);
+                    messages.push(message);
+                },
+                None => queues.push((queue_url, vec![i], vec![message])),
+            }
+        }
+        let mut receipts: Vec<Option<PublishReceiptSqs>> = vec![None; events.len()];
+        for (queue_url, indexes, messages) in queues {
+            let digests: Vec<(String, usize)> = messages.iter().map(|message| (md5_hex(&message.body), message.body.len())).collect();
+            let sent = self.send_message_batch(&queue_url, messages).await?;
+            if sent.len() != indexes.len() {
+                return Err(EventfulError::MissingField(format!("results for {} of {} messages, publishing to {}", sent.len(), indexes.len(), queue_url)))
+            }
+            for ((i, (digest, bytes)), sent) in indexes.into_iter().zip(digests).zip(sent) {
+                receipts[i] = Some(sent.verify(&queue_url, digest, bytes)?);
+            }
+        }
+        Ok(receipts.into_iter().flatten().collect())
+    }
+
+    /// Receive a batch of messages without waiting and decode them. When delete_on_receipt is true the messages which
+    /// decoded are deleted; a message which doesn't decode is never returned, and is settled by on_decode_error.
+    /// There is no DeadLetterSink here, so OnDecodeError::DeadLetter nacks it with ConsumerOptions' default backoff, as run() does
+    /// without a sink, leaving the queue's redrive policy to move it once it has been received too often
+    async fn poll<T: DeserializeOwned + Send>(&self, queue_url: &str, delete_on_receipt: bool, on_decode_error: OnDecodeError) -> Result<Vec<T>, EventfulError> {
+        let messages = self.receive_messages(queue_url, 10, 0).await?;
+        let mut resp = Vec::with_capacity(messages.len());
+        let mut decoded = Vec::with_capacity(messages.len());
+        let mut undecodable = Vec::new();
+        for message in messages {
+            match serde_json::from_str::<T>(message.body.as_deref().unwrap_or_default()) {
+                Ok(event) => {
+                    resp.push(event);
+                    decoded.extend(message.receipt_handle);
+                },
+                Err(_) => undecodable.push(message),
+            }
+        }
+        if delete_on_receipt {
+            for receipt_handle in &decoded {
+                self.delete_message(queue_url, receipt_handle).await?;
+            }
+        }
+        for message in undecodable {
+            metrics::global().inc_failed(queue_url);
+            let receipt_handle = match message.receipt_handle.as_deref() {
+                Some(receipt_handle) => receipt_handle,
+                None => continue,
+            };
+            match on_decode_error {
+                OnDecodeError::Delete => self.delete_message(queue_url, receipt_handle).await?,
+                OnDecodeError::DeadLetter => {
+                    let delay = ConsumerOptions::default().retry.delay_for(receive_count(&message));
+                    self.change_visibility(queue_url, receipt_handle, delay.as_secs().min(43_200) as i32).await?
+                },
+                OnDecodeError::Leave => {},
+            }
+        }
+        Ok(resp)
+    }
+}
+
+impl<A: SqsApi + ?Sized> SqsApiExt for A {}
+
+
+/// A serialized event as every publish sends it: with the deduplication id api's FifoOptions pick, the body from
+/// prepare_body, and checked for size and against the queue's FIFO-ness. An inner Err is the event's own fault, with the
+/// code publish_batch reports it under; an outer one fails the whole publish
+async fn prepare_outgoing<A: SqsApi + ?Sized>(api: &A, queue_url: &str, body: String, group_id: Option<String>, dedup_id: Option<String>)
+    -> Result<Result<OutgoingSQS, (&'static str, EventfulError)>, EventfulError>
+{
+    let dedup_id = match api.fifo().dedup.dedup_id(queue_url, dedup_id, &body) {
+        Ok(dedup_id) => dedup_id,
+        Err(err) => return Ok(Err(("FifoMismatch", err))),
+    };
+    let body = api.prepare_body(queue_url, body).await?;
+    if body.len() > MAX_MESSAGE_BYTES {
+        let err = EventfulError::Config(format!("the body is {} bytes, but SQS accepts at most {}", body.len(), MAX_MESSAGE_BYTES));
+        return Ok(Err(("MessageTooLarge", err)))
+    }
+    if let Err(err) = check_fifo(queue_url, group_id.as_deref(), dedup_id.as_deref()) {
+        return Ok(Err(("FifoMismatch", err)))
+    }
+    Ok(Ok(OutgoingSQS{body, group_id, dedup_id}))
+}
+
+
+/// The URL of the queue an event is published to, for SqsApi (which can't look up queue names)
+pub(crate) fn queue_url_for<T: Event>(event: &T) -> Result<QueueUrl, EventfulError> {
+    match event.queue_for()? {
+        QueueRef::Url(queue_url) => Ok(queue_url),
+        QueueRef::Name(name) => Err(EventfulError::InvalidQueueUrl(format!("{}: SqsApi needs a queue URL rather than a name", name))),
+    }
+}
+
+
+
+#[derive(Clone)]
+pub struct ClientSQS {
+    client: Client,
+    stats: Arc<PublisherStats>,
+    queue_urls: Arc<QueueUrlCache>,
+    retry: RetryPolicy,
+    fifo: FifoOptions,
+    #[cfg(feature = "s3")]
+    offload: Option<Arc<S3Offload>>,
+}
+
+impl ClientSQS {
+
+    /// Instantiate a new messenger in a region known at compile time; a shortcut for new_with_region
+    pub async fn new(region: &'static str) -> Self {
+        ClientSQS::new_with_region(region).await
+    }
+
+    /// Instantiate a new messenger in this region. Like ClientSQS::builder().region(region).build(),
+    /// it also uses the endpoint in EVENTFUL_SQS_ENDPOINT when that is set
+    pub async fn new_with_region(region: impl Into<String>) -> Self {
+        let config = aws_config::from_env().region(Region::new(region.into())).load().await;
+        let mut sqs_config = aws_sdk_sqs::config::Builder::from(&config);
+        if let Some(endpoint) = endpoint_from_env() {
+            sqs_config = sqs_config.endpoint_url(endpoint);
+        }
+        ClientSQS::from_client(Client::from_conf(sqs_config.build()))
+    }
+
+    /// Like new_with_region(), but sending requests to another endpoint, i.e. "http://localhost:4566" for localstack
+    pub async fn with_endpoint(region: impl Into<String>, endpoint: &str) -> Self {
+        let config = aws_config::from_env().region(Region::new(region.into())).load().await;
+        let sqs_config = aws_sdk_sqs::config::Builder::from(&config).endpoint_url(endpoint).build();
+        ClientSQS::from_client(Client::from_conf(sqs_config))
+    }
+
+    /// Instantiate a messenger in the region found by the SDK's default chain: AWS_REGION, then the profile, then IMDS (on EC2).
+    /// Fails with EventfulError::Config if none of them names a region
+    pub async fn from_env() -> Result<Self, EventfulError> {
+        ClientSQS::builder().build().await
+    }
+
+    /// Configure the region, endpoint, credentials, timeouts, and retries before connecting
+    pub fn builder() -> ClientSQSBuilder {
+        ClientSQSBuilder::default()
+    }
+
+    /// Wrap an aws_sdk_sqs Client which has already been configured
+    pub fn from_client(client: Client) -> Self {
+        ClientSQS{client, stats: Arc::new(PublisherStats::default()), queue_urls: Arc::new(QueueUrlCache::default()), retry: RetryPolicy::default(), fifo: FifoOptions::default(),
+            #[cfg(feature = "s3")]
+            offload: None,
+        }
+    }
+
+    /// How sends, receives, and deletes are retried (default: RetryPolicy::default(), 3 attempts with jittered exponential backoff).
+    /// Throttling, timeouts, 5xx responses, and dropped connections are retried; access denied, a missing queue,
+    /// and other rejected requests fail at once. For a single call, retry a clone: `client.clone().retry(RetryPolicy::never())`.
+    /// This is on top of the SDK's own retries, which ClientSQSBuilder::retry_attempts sets
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// How publish() and publish_batch() pick deduplication ids for FIFO queues (default: DedupStrategy::ContentBased).
+    /// For a single call, set it on a clone. SqsApiExt's publishes use it too; SqsApi::send_message and the Publisher path send the id they are given
+    pub fn fifo_options(mut self, fifo: FifoOptions) -> Self {
+        self.fifo = fifo;
+        self
+    }
+
+    /// Have publish() and publish_batch() store bodies over the S3Offload's threshold in S3, sending a pointer instead,
+    /// and have every receive replace pointers with the stored bodies. SqsApiExt's publishes offload too; SqsApi::send_message
+    /// and the Publisher path send bodies as they are
+    #[cfg(feature = "s3")]
+    pub fn offload(mut self, offload: S3Offload) -> Self {
+        self.offload = Some(Arc::new(offload));
+        self
+    }
+
+    /// Make one SQS call under the RetryPolicy, turning a missing queue into QueueDoesNotExist
+    async fn call<O, E, F, Fut>(&self, queue_url: &str, mut send: F) -> Result<O, EventfulError>
+    where
+        E: ProvideErrorKind + fmt::Debug,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<O, SdkError<E>>>,
+    {
+        execute_with_retry(&self.retry, || {
+            let sent = send();
+            async move {
+                match sent.await {
+                    Ok(output) => Ok(output),
+                    Err(SdkError::ServiceError(context)) if is_missing_queue(context.err().code()) => {
+                        Err(EventfulError::QueueDoesNotExist(queue_url.to_string()))
+                    },
+                    Err(err) => Err(err.into()),
+                }
+            }
+        }).await
+    }
+
+    /// Per-queue publish latency, error rate, and bytes published. Shared by every clone of this client
+    pub fn stats(&self) -> Arc<PublisherStats> {
+        self.stats.clone()
+    }
+
+    /// The underlying aws_sdk_sqs Client, for operations eventful does not wrap
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Run the handler loop from the consumer module over a queue.
+    /// Messages which fail options.retry.max_attempts times go to options.dead_letter
+    pub async fn run<T, H, Fut>(&self, queue_url: &str, options: &ConsumerOptions, handler: H) -> Result<(), EventfulError>
+    where
+        T: DeserializeOwned,
+        H: Fn(T) -> Fut,
+        Fut: Future<Output = Result<(), EventfulError>>,
+    {
+        consumer::run(Box::new(self.subscribe(queue_url)), options, handler).await
+    }
+
+    /// Like run(), but events whose key has already been processed are deleted without calling the handler
+    pub async fn run_idempotent<T, K, H, Fut>(&self, queue_url: &str, options: &ConsumerOptions, guard: &IdempotencyGuard, key: K, handler: H) -> Result<(), EventfulError>
+    where
+        T: DeserializeOwned,
+        K: Fn(&T) -> String,
+        H: Fn(T) -> Fut,
+        Fut: Future<Output = Result<(), EventfulError>>,
+    {
+        consumer::run_idempotent(Box::new(self.subscribe(queue_url)), options, guard, key, handler).await
+    }
+
+    /// Create a SubscriptionSQS, which long-polls a queue and implements the transport-agnostic Subscriber trait
+    pub fn subscribe(&self, queue_url: &str) -> SubscriptionSQS {
+        SubscriptionSQS::from_api(Arc::new(self.clone()), queue_url)
+    }
+
+    /// Receive messages with the default ReceiveOptions, which long poll for up to 20 seconds.
+    /// With delete_on_receipt they are deleted before being returned; any which can't be deleted are returned all the same
+    /// (and received again after their visibility timeout), and reported to the global observer's receipts_not_deleted
+    pub async fn poll_messages(&self, queue_url: &str, delete_on_receipt: bool) -> Result<Vec<Message>, EventfulError> {
+        self.poll_messages_with(queue_url, delete_on_receipt, &ReceiveOptions::default()).await
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.consume", skip(self, options), fields(backend = "sqs", messages = tracing::field::Empty, outcome = tracing::field::Empty)))]
+    pub async fn poll_messages_with(&self, queue_url: &str, delete_on_receipt: bool, options: &ReceiveOptions) -> Result<Vec<Message>, EventfulError> {
+        let result = self.poll_messages_inner(queue_url, delete_on_receipt, options).await;
+        match &result {
+            Ok(messages) => messages.iter().for_each(|_| metrics::global().inc_consumed(queue_url)),
+            Err(_) => metrics::global().inc_failed(queue_url),
+        }
+        #[cfg(feature = "tracing")]
+        if let Ok(messages) = &result {
+            tracing::Span::current().record("messages", messages.len());
+        }
+        trace::record_outcome(&result);
+        result
+    }
+
+    async fn poll_messages_inner(&self, queue_url: &str, delete_on_receipt: bool, options: &ReceiveOptions) -> Result<Vec<Message>, EventfulError> {
+        let (messages, _) = self.receive_filtered(queue_url, delete_on_receipt, options).await?;
+        Ok(messages)
+    }
+
+    /// poll_messages_inner, also returning how many messages options.filter kept back
+    async fn receive_filtered(&self, queue_url: &str, delete_on_receipt: bool, options: &ReceiveOptions) -> Result<(Vec<Message>, u64), EventfulError> {
+        options.validate()?;
+        let mut message_attribute_names = options.message_attribute_names.clone();
+        if let Some(filter) = &options.filter {
+            if !message_attribute_names.iter().any(|name| name == "All") {
+                for name in filter.attribute_names() {
+                    if !message_attribute_names.iter().any(|requested| requested == name) {
+                        message_attribute_names.push(name.to_string());
+                    }
+                }
+            }
+        }
+        let request = self.client
+            .receive_message()
+            .queue_url(queue_url)
+            .wait_time_seconds(options.wait_time_seconds)
+            .max_number_of_messages(options.max_messages)
+            .set_visibility_timeout(options.visibility_timeout)
+            .set_attribute_names(Some(options.attribute_names.clone()).filter(|names| !names.is_empty()))
+            .set_message_attribute_names(Some(message_attribute_names).filter(|names| !names.is_empty()));
+        let message_batch = self.call(queue_url, || request.clone().send()).await?;
+
+        let mut messages = message_batch.messages.unwrap_or_default();
+        #[cfg(feature = "s3")]
+        if let Some(offload) = &self.offload {
+            offload.inline(&mut messages).await?;
+        }
+
+        let mut filtered = 0;
+        if let Some(filter) = &options.filter {
+            let (matched, rejected): (Vec<Message>, Vec<Message>) = messages.into_iter()
+                .partition(|message| filter.matches(&Attributes::from_message(message)));
+            messages = matched;
+            filtered = rejected.len() as u64;
+            self.reject(queue_url, rejected, filter.on_mismatch).await;
+        }
+        
+        if delete_on_receipt {
+            let handles: Vec<ReceiptHandle> = messages.iter().filter_map(|message| message.receipt_handle.clone().map(ReceiptHandle::from)).collect();
+            self.delete_received(queue_url, &handles).await;
+        }
+        Ok((messages, filtered))
+    }
+
+    /// Delete messages received with delete_on_receipt. They have been received either way, so rather than fail the receive,
+    /// any which aren't deleted are reported to the global observer's receipts_not_deleted and are received again later
+    async fn delete_received(&self, queue_url: &str, handles: &[ReceiptHandle]) {
+        if handles.is_empty() {
+            return
+        }
+        let (not_deleted, error) = match self.ack_batch(queue_url, handles).await {
+            Ok(report) => match report.failed.first() {
+                Some((_, err)) => (report.failed.iter().map(|(i, _)| handles[*i].to_string()).collect::<Vec<_>>(), err.to_string()),
+                None => return,
+            },
+            Err(err) => (handles.iter().map(ReceiptHandle::to_string).collect(), err.to_string()),
+        };
+        observer::global().receipts_not_deleted(&ReceiptsNotDeleted{
+            source: Destination::SqsQueue(queue_url.to_string()),
+            error: format!("{} of {} received messages were not deleted: {}", not_deleted.len(), handles.len(), error),
+            receipt_handles: not_deleted,
+        });
+    }
+
+    /// Settle messages a ReceiveFilter didn't match. Failures are ignored: the messages reappear after their visibility timeout
+    /// and are filtered again
+    async fn reject(&self, queue_url: &str, messages: Vec<Message>, on_mismatch: OnMismatch) {
+        let handles: Vec<ReceiptHandle> = messages.into_iter().filter_map(|message| message.receipt_handle.map(ReceiptHandle::from)).collect();
+        for _ in &handles {
+            metrics::global().inc_filtered(queue_url);
+        }
+        if handles.is_empty() {
+            return
+        }
+        match on_mismatch {
+            OnMismatch::Drop => {
+                let _ = self.ack_batch(queue_url, &handles).await;
+            },
+            OnMismatch::Release(visibility) => {
+                for handle in &handles {
+                    let _ = self.set_visibility(queue_url, handle, visibility).await;
+                }
+            },
+        }
+    }
+
+    /// Delete messages with DeleteMessageBatch, in calls of at most 10, returning the result for each handle by its index.
+    /// Handles SQS rejects (i.e. expired ones) are reported individually; a call which fails outright is returned as an error,
+    /// though messages from earlier calls may already have been deleted
+    pub async fn ack_batch(&self, queue_url: &str, handles: &[ReceiptHandle]) -> Result<BatchResult<()>, EventfulError> {
+        let mut result = BatchResult::new();
+        self.delete_entries(queue_url, handles.iter().cloned().enumerate().collect(), &mut result).await?;
+        result.sort();
+        Ok(result)
+    }
+
+    /// Delete messages in DeleteMessageBatch calls of at most 10, using each handle's index as its entry id
+    async fn delete_entries(&self, queue_url: &str, handles: Vec<(usize, ReceiptHandle)>, result: &mut BatchResult<()>) -> Result<(), EventfulError> {
+        for chunk in handles.chunks(MAX_BATCH_ENTRIES) {
+            let entries = chunk.iter()
+                .map(|(i, handle)| DeleteMessageBatchRequestEntry::builder()
+                    .id(i.to_string())
+                    .receipt_handle(handle.as_str())
+                    .build())
+                .collect::<Vec<_>>();
+            let request = self.client
+                .delete_message_batch()
+                .queue_url(queue_url)
+                .set_entries(Some(entries));
+            let output = self.call(queue_url, || request.clone().send()).await?;
+            let mut pending: HashMap<usize, ReceiptHandle> = chunk.iter().cloned().collect();
+            for entry in output.successful.unwrap_or_default() {
+                #[allow(unused_variables)]
+                if let Some((i, handle)) = take_entry(&mut pending, entry.id.as_deref()) {
+                    result.succeed(i, ());
+                    #[cfg(feature = "s3")]
+                    if let Some(offload) = &self.offload {
+                        offload.acked(&handle).await;
+                    }
+                }
+            }
+            for entry in output.failed.unwrap_or_default() {
+                if let Some((i, handle)) = take_entry(&mut pending, entry.id.as_deref()) {
+                    let err = BatchEntryError{
+                        code: entry.code.unwrap_or_else(|| "Unknown".to_string()),
+                        message: entry.message.unwrap_or_default(),
+                        sender_fault: entry.sender_fault,
+                    };
+                    result.fail(i, err, Some((queue_url, BatchEntry::Delete(handle))));
+                }
+            }
+            for (i, handle) in pending {
+                let err = BatchEntryError{
+                    code: "MissingResult".to_string(),
+                    message: "DeleteMessageBatch listed the message as neither successful nor failed".to_string(),
+                    sender_fault: false,
+                };
+                result.fail(i, err, Some((queue_url, BatchEntry::Delete(handle))));
+            }
+        }
+        Ok(())
+    }
+
+    
+    /// Return the body of messages as strings
+    pub async fn poll_strings(&self, queue_url: &str, delete_on_receipt: bool) -> Result<Vec<String>, EventfulError> {
+        self.poll_strings_with(queue_url, delete_on_receipt, &ReceiveOptions::default()).await
+    }
+
+    pub async fn poll_strings_with(&self, queue_url: &str, delete_on_receipt: bool, options: &ReceiveOptions) -> Result<Vec<String>, EventfulError> {
+        let messages = self.poll_messages_with(queue_url, delete_on_receipt, options).await?;
+        let mut resp = Vec::new();
+        for message in messages {
+            let body = &message.body.unwrap_or_default();
+            resp.push(body.clone());
+        }
+        Ok(resp)
+    }
+
+
+    /// Return the body of messages as deserializable structs.  
+    /// The receipt handles are dropped, so the messages can't be deleted afterwards: with delete_on_receipt they are deleted
+    /// before you see them (at most once: a crash while handling them loses them), and without it they are never deleted and
+    /// come back after the visibility timeout. To delete each message once it has been handled, use poll_with_receipts or receive.  
+    /// Messages which don't decode as T are never deleted; they are left on the queue (see OnUndecodable::Leave)
+    pub async fn poll<T: DeserializeOwned>(&self, queue_url: &str, delete_on_receipt: bool) -> Result<Vec<T>, EventfulError> {
+        self.poll_with(queue_url, delete_on_receipt, &ReceiveOptions::default()).await
+    }
+
+    pub async fn poll_with<T: DeserializeOwned>(&self, queue_url: &str, delete_on_receipt: bool, options: &ReceiveOptions) -> Result<Vec<T>, EventfulError> {
+        let (events, _) = self.poll_events(queue_url, delete_on_receipt, options, &OnUndecodable::Leave).await?;
+        Ok(events)
+    }
+
+    /// Like poll_with, applying on_undecodable to messages which don't decode as T.
+    /// With delete_on_receipt, only messages which decoded (and ones dead-lettered) are deleted, once every message has been decoded.
+    /// As with poll_messages, events whose messages can't be deleted are still returned, and reported to receipts_not_deleted
+    pub async fn poll_events<T: DeserializeOwned>(&self, queue_url: &str, delete_on_receipt: bool, options: &ReceiveOptions, on_undecodable: &OnUndecodable)
+        -> Result<(Vec<T>, Vec<UndecodableMessage>), EventfulError>
+    {
+        let messages = self.poll_messages_with(queue_url, false, options).await?;
+        let api: Arc<dyn SqsApi> = Arc::new(self.clone());
+        let (mut events, mut undecodable, mut settled) = (Vec::new(), Vec::new(), Vec::new());
+        for message in messages {
+            let body = message.body.clone().unwrap_or_default();
+            let decoded = match offloaded_error(&body) {
+                Some(err) => Err(err.to_string()),
+                None => serde_json::from_str::<T>(&unwrap_body(&body, options.unwrap_sns).0).map_err(|err| err.to_string()),
+            };
+            let error = match decoded {
+                Ok(event) => {
+                    events.push(event);
+                    settled.extend(message.receipt_handle.map(ReceiptHandle::from));
+                    continue
+                },
+                Err(error) => error,
+            };
+            metrics::global().inc_failed(queue_url);
+            let receipt_handle = match message.receipt_handle.clone() {
+                Some(val) => val,
+                None => continue,
+            };
+            match on_undecodable {
+                OnUndecodable::Leave => {},
+                OnUndecodable::DeadLetter(dead_letter_queue) => {
+                    let record = DeadLetterRecord{
+                        source: Destination::SqsQueue(queue_url.to_string()),
+                        original_body: Bytes::from(body),
+                        attempts: receive_count(&message),
+                        last_error: error.clone(),
+                        first_seen: Utc::now(),
+                        metadata: Metadata::default(),
+                    };
+                    let fifo = dead_letter_queue.is_fifo();
+                    let outgoing = OutgoingSQS{
+                        body: serde_json::to_string(&record)?,
+                        group_id: Some(QueueUrl::parse(queue_url).map(|url| url.queue_name().to_string()).unwrap_or_default()).filter(|_| fifo),
+                        dedup_id: message.message_id.clone().filter(|_| fifo),
+                    };
+                    self.send_message(dead_letter_queue, outgoing).await?;
+                    settled.push(ReceiptHandle::from(receipt_handle));
+                },
+                OnUndecodable::Return => undecodable.push(UndecodableMessage{
+                    receive_count: receive_count(&message),
+                    attributes: Attributes::from_message(&message),
+                    body,
+                    message_id: message.message_id,
+                    error,
+                    handle: ReceivedHandle{api: api.clone(), queue_url: queue_url.to_string(), receipt_handle},
+                }),
+            }
+        }
+        if delete_on_receipt {
+            self.delete_received(queue_url, &settled).await;
+        }
+        Ok((events, undecodable))
+    }
+
+
+
+    /// Receive events from T's queue without deleting them, each with the receipt handle to ack it with once it has been handled.
+    /// Unlike receive(), a message which can't be decoded fails the whole call (and is left on the queue)
+    pub async fn poll_with_receipts<T: Event>(&self, options: &ReceiveOptions) -> Result<Vec<(T, ReceiptHandle)>, EventfulError> {
+        self.poll_with_receipts_at(&<T as Event>::queue_ref()?, options).await
+    }
+
+    /// Like poll_with_receipts, from the given queue rather than T's own
+    pub async fn poll_with_receipts_at<T: Event>(&self, queue: &QueueRef, options: &ReceiveOptions) -> Result<Vec<(T, ReceiptHandle)>, EventfulError> {
+        let queue_url = self.resolve(queue).await?;
+        let result = self.poll_messages_with(&queue_url, false, options).await;
+        let messages = self.forget_if_missing(queue, result)?;
+        let mut resp = Vec::with_capacity(messages.len());
+        for message in messages {
+            if let Some(handle) = message.receipt_handle {
+                let (body, _) = unwrap_body(message.body.as_deref().unwrap_or_default(), options.unwrap_sns);
+                let event: T = serde_json::from_str(&body)?;
+                resp.push((event, ReceiptHandle::from(handle)));
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Delete a received message
+    pub async fn ack(&self, queue_url: &str, handle: &ReceiptHandle) -> Result<(), EventfulError> {
+        self.delete_message(queue_url, handle).await
+    }
+
+    /// Make a received message invisible for this long from now (at most 12 hours after it was received), or visible again with zero
+    pub async fn set_visibility(&self, queue_url: &str, handle: &ReceiptHandle, visibility: Duration) -> Result<(), EventfulError> {
+        let seconds = visibility.min(MAX_VISIBILITY).as_secs() as i32;
+        self.change_visibility(queue_url, handle, seconds).await
+    }
+
+    /// Receive events from T's queue without deleting them: ack each one once it has been handled.
+    /// Messages which fail to decode are returned separately, so they can be acked, nacked, or left to reach a dead letter queue.
+    /// The ApproximateReceiveCount attribute is always requested, as are all message attributes unless options names some
+    pub async fn receive<T: Event>(&self, options: &ReceiveOptions) -> Result<(Vec<ReceivedEvent<T>>, Vec<UndecodableMessage>), EventfulError> {
+        self.receive_at(&<T as Event>::queue_ref()?, options).await
+    }
+
+    /// Like receive, from the given queue rather than T's own, i.e. one of the queues T's queue_for() shards across
+    pub async fn receive_at<T: Event>(&self, queue: &QueueRef, options: &ReceiveOptions) -> Result<(Vec<ReceivedEvent<T>>, Vec<UndecodableMessage>), EventfulError> {
+        let queue_url = self.resolve(queue).await?;
+        let result = self.receive_from(&queue_url, options).await;
+        self.forget_if_missing(queue, result)
+    }
+
+    async fn receive_from<T: Event>(&self, queue_url: &str, options: &ReceiveOptions) -> Result<(Vec<ReceivedEvent<T>>, Vec<UndecodableMessage>), EventfulError> {
+        let mut options = options.clone();
+        if !options.attribute_names.contains(&QueueAttributeName::All) {
+            options.attribute_names.push(QueueAttributeName::All);
+        }
+        if options.message_attribute_names.is_empty() {
+            options.message_attribute_names.push("All".to_string());
+        }
+        let received_at = tokio::time::Instant::now();
+        let messages = self.poll_messages_with(queue_url, false, &options).await?;
+        let api: Arc<dyn SqsApi> = Arc::new(self.clone());
+        let (mut events, mut undecodable) = (Vec::new(), Vec::new());
+        for message in messages {
+            let receipt_handle = match &message.receipt_handle {
+                Some(val) => val.clone(),
+                None => continue,
+            };
+            let receive_count = receive_count(&message);
+            let attributes = Attributes::from_message(&message);
+            let handle = ReceivedHandle{api: api.clone(), queue_url: queue_url.to_string(), receipt_handle};
+            let body = message.body.clone().unwrap_or_default();
+            let (inner, sns) = unwrap_body(&body, options.unwrap_sns);
+            let decoded = match offloaded_error(&body) {
+                Some(err) => Err(err.to_string()),
+                None => serde_json::from_str::<T>(&inner).map_err(|err| err.to_string()),
+            };
+            match decoded {
+                Ok(payload) => events.push(ReceivedEvent{
+                    payload,
+                    sns,
+                    receive_count,
+                    sent_at: timestamp_attribute(&message, MessageSystemAttributeName::SentTimestamp),
+                    first_received_at: timestamp_attribute(&message, MessageSystemAttributeName::ApproximateFirstReceiveTimestamp),
+                    group_id: system_attribute(&message, MessageSystemAttributeName::MessageGroupId).cloned(),
+                    sequence_number: system_attribute(&message, MessageSystemAttributeName::SequenceNumber).cloned(),
+                    message_id: message.message_id,
+                    attributes,
+                    handle,
+                    received_at,
+                    heartbeat: None,
+                }),
+                Err(error) => undecodable.push(UndecodableMessage{body, message_id: message.message_id, receive_count, attributes, error, handle}),
+            }
+        }
+        Ok((events, undecodable))
+    }
+
+
+    /// Receive T's events as a stream, long polling with options until shutdown is cancelled (which also ends a poll in progress).
+    /// A new batch is only received once every event of the last one has been taken, so at most one batch waits unhandled
+    /// while its visibility timeout runs. After an empty receive the next waits for idle_backoff, by how many in a row were empty.
+    /// A failed receive is yielded as an Err and retried after the same backoff, except QueueDoesNotExist, which ends the stream.
+    /// Messages which don't decode are left on the queue, to come back after the visibility timeout (or reach its DLQ)
+    pub fn stream<T: Event>(&self, options: &ReceiveOptions, idle_backoff: Backoff, shutdown: CancellationToken)
+        -> impl Stream<Item = Result<ReceivedEvent<T>, EventfulError>>
+    {
+        match <T as Event>::queue_ref() {
+            Ok(queue) => self.stream_at(queue, options, idle_backoff, shutdown).left_stream(),
+            Err(err) => stream::once(async move { Err(err) }).right_stream(),
+        }
+    }
+
+    /// Like stream, from the given queue rather than T's own
+    pub fn stream_at<T: Event>(&self, queue: QueueRef, options: &ReceiveOptions, idle_backoff: Backoff, shutdown: CancellationToken)
+        -> impl Stream<Item = Result<ReceivedEvent<T>, EventfulError>>
+    {
+        let (client, options) = (self.clone(), options.clone());
+        let receive = move || {
+            let (client, queue, options) = (client.clone(), queue.clone(), options.clone());
+            async move { client.receive_at::<T>(&queue, &options).await }
+        };
+        receive_stream(receive, idle_backoff, shutdown)
+    }
+
+
+    /// publish a message (could be a string or serializable struct) to the queue with a given group_id.
+    /// The receipt has its message id, and its sequence number when the queue is FIFO. The MD5 digest SQS returns is checked
+    /// against the body sent, and a mismatch returned as EventfulError::ChecksumMismatch
+    pub async fn publish<T: Event>(&self, event: &T) -> Result<PublishReceiptSqs, EventfulError> {
+        self.publish_with_attributes(event, &Attributes::default()).await
+    }
+
+    #[deprecated(since = "0.1.1", note = "publish() returns the receipt now")]
+    pub async fn publish_with_receipt<T: Event>(&self, event: &T) -> Result<PublishReceiptSqs, EventfulError> {
+        self.publish(event).await
+    }
+
+    /// Publish with message attributes as well as the event's own (attrs wins where both have a name)
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.publish", skip(self, event, attrs), fields(backend = "sqs", queue_url = <T as Event>::try_queue_url().unwrap_or_default(), payload_bytes = tracing::field::Empty, outcome = tracing::field::Empty)))]
+    pub async fn publish_with_attributes<T: Event>(&self, event: &T, attrs: &Attributes) -> Result<PublishReceiptSqs, EventfulError> {
+        let resolved = match event.queue_for() {
+            Ok(queue) => self.resolve(&queue).await.map(|queue_url| (queue, queue_url)),
+            Err(err) => Err(err),
+        };
+        let (queue, queue_url) = match resolved {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                let result = Err(err);
+                trace::record_outcome(&result);
+                return result
+            },
+        };
+        let result = metrics::time_publish(&queue_url, self.publish_inner(&queue_url, event, event.attributes().merge(attrs))).await;
+        trace::record_outcome(&result);
+        self.forget_if_missing(&queue, result)
+    }
+
+    /// Publish a hand-written JSON value (i.e. from an ops script) without defining an event type. It is prepared as publish()
+    /// prepares an event: the queue URL is validated, the body offloaded or checked for size, and on FIFO queues (which must be
+    /// given a group_id) the deduplication id picked by the client's FifoOptions, all before anything is sent
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.publish", skip(self, value), fields(backend = "sqs", payload_bytes = tracing::field::Empty, outcome = tracing::field::Empty)))]
+    pub async fn publish_json_value(&self, queue_url: &str, value: &serde_json::Value, group_id: Option<&str>) -> Result<PublishReceiptSqs, EventfulError> {
+        let result = match QueueUrl::parse(queue_url) {
+            Ok(queue_url) => {
+                let body = serde_json::to_string(value)?;
+                metrics::time_publish(&queue_url, self.publish_body(&queue_url, body, group_id.map(str::to_string), None, Attributes::default())).await
+            },
+            Err(err) => Err(err),
+        };
+        trace::record_outcome(&result);
+        result
+    }
+
+    /// Publish events to their queue with SendMessageBatch, in calls of at most 10 messages and 256 KiB.
+    /// Events which are too large, or whose group and dedup ids don't suit the queue, are failed without being sent,
+    /// and entries SQS rejects are reported individually (by the event's index) rather than failing the whole batch,
+    /// so BatchResult::retry_failed can send just those again.
+    /// A call which fails outright is returned as an error, though messages from earlier calls may already have been sent
+    pub async fn publish_batch<T: Event>(&self, events: &[T]) -> Result<BatchResult<PublishReceiptSqs>, EventfulError> {
+        // events can route to different queues (Event::queue_for), which are published to one after another
+        let mut queues: Vec<(QueueRef, Vec<(usize, &T)>)> = Vec::new();
+        for (i, event) in events.iter().enumerate() {
+            let queue = event.queue_for()?;
+            match queues.iter_mut().find(|(other, _)| *other == queue) {
+                Some((_, group)) => group.push((i, event)),
+                None => queues.push((queue, vec![(i, event)])),
+            }
+        }
+        let mut result = BatchResult::new();
+        for (queue, group) in queues {
+            let queue_url = self.resolve(&queue).await?;
+            let published = self.publish_batch_to(&queue_url, &group, &mut result).await;
+            self.forget_if_missing(&queue, published)?;
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    /// Publish events (with their index in the caller's slice) to one queue
+    async fn publish_batch_to<T: Event>(&self, queue_url: &str, events: &[(usize, &T)], result: &mut BatchResult<PublishReceiptSqs>) -> Result<(), EventfulError> {
+        let mut messages = Vec::with_capacity(events.len());
+        for &(i, event) in events {
+            let body = serde_json::to_string(event)?;
+            match prepare_outgoing(self, queue_url, body, event.group_id(), event.dedup_id()).await? {
+                Ok(message) => messages.push((i, message)),
+                Err((code, err)) => result.fail(i, BatchEntryError{code: code.to_string(), message: err.to_string(), sender_fault: true}, None),
+            }
+        }
+        self.send_entries(queue_url, messages, result).await
+    }
+
+    /// Send messages in SendMessageBatch calls of at most 10 messages and 256 KiB
+    async fn send_entries(&self, queue_url: &str, messages: Vec<(usize, OutgoingSQS)>, result: &mut BatchResult<PublishReceiptSqs>) -> Result<(), EventfulError> {
+        let mut batch: Vec<(usize, OutgoingSQS)> = Vec::new();
+        let mut batch_bytes = 0;
+        for (i, message) in messages {
+            if batch.len() >= MAX_BATCH_ENTRIES || batch_bytes + message.body.len() > MAX_MESSAGE_BYTES {
+                self.send_batch(queue_url, std::mem::take(&mut batch), result).await?;
+                batch_bytes = 0;
+            }
+            batch_bytes += message.body.len();
+            batch.push((i, message));
+        }
+        if !batch.is_empty() {
+            self.send_batch(queue_url, batch, result).await?;
+        }
+        Ok(())
+    }
+
+    /// Send one SendMessageBatch call, using each message's index in the input as its entry id
+    async fn send_batch(&self, queue_url: &str, batch: Vec<(usize, OutgoingSQS)>, result: &mut BatchResult<PublishReceiptSqs>) -> Result<(), EventfulError> {
+        let bytes = batch.iter().map(|(_, message)| message.body.len()).sum();
+        let entries = batch.iter()
+            .map(|(i, message)| SendMessageBatchRequestEntry::builder()
+                .id(i.to_string())
+                .message_body(message.body.clone())
+                .set_message_group_id(message.group_id.clone())
+                .set_message_deduplication_id(message.dedup_id.clone())
+                .build())
+            .collect::<Vec<_>>();
+        let request = self.client
+            .send_message_batch()
+            .queue_url(queue_url)
+            .set_entries(Some(entries));
+        let output = self.stats.track(queue_url, bytes, self.call(queue_url, || request.clone().send())).await?;
+        let queue = QueueUrl::parse(queue_url)?;
+        // kept until each has a result, so failed ones can be retried
+        let mut pending: HashMap<usize, OutgoingSQS> = batch.into_iter().collect();
+        for entry in output.successful.unwrap_or_default() {
+            if let Some((i, message)) = take_entry(&mut pending, entry.id.as_deref()) {
+                let (digest, bytes) = (md5_hex(&message.body), message.body.len());
+                match PublishReceiptSqs::verify(&queue, digest, bytes, entry.message_id, entry.sequence_number, entry.md5_of_message_body) {
+                    Ok(receipt) => result.succeed(i, receipt),
+                    Err(err) => {
+                        let code = match err {
+                            EventfulError::ChecksumMismatch{..} => "ChecksumMismatch",
+                            _ => "MissingField",
+                        };
+                        let err = BatchEntryError{code: code.to_string(), message: err.to_string(), sender_fault: false};
+                        result.fail(i, err, Some((queue_url, BatchEntry::Send(message))));
+                    },
+                }
+            }
+        }
+        for entry in output.failed.unwrap_or_default() {
+            if let Some((i, message)) = take_entry(&mut pending, entry.id.as_deref()) {
+                let err = BatchEntryError{
+                    code: entry.code.unwrap_or_else(|| "Unknown".to_string()),
+                    message: entry.message.unwrap_or_default(),
+                    sender_fault: entry.sender_fault,
+                };
+                result.fail(i, err, Some((queue_url, BatchEntry::Send(message))));
+            }
+        }
+        for (i, message) in pending {
+            let err = BatchEntryError{
+                code: "MissingResult".to_string(),
+                message: "SendMessageBatch listed the message as neither successful nor failed".to_string(),
+                sender_fault: false,
+            };
+            result.fail(i, err, Some((queue_url, BatchEntry::Send(message))));
+        }
+        Ok(())
+    }
+
+    async fn publish_inner<T: Event>(&self, queue_url: &str, event: &T, attrs: Attributes) -> Result<PublishReceiptSqs, EventfulError> {
+        attrs.validate()?;
+        let body = serde_json::to_string(event)?;
+        self.publish_body(queue_url, body, event.group_id(), event.dedup_id(), attrs).await
+    }
+
+    /// Prepare a serialized event with prepare_outgoing and send it
+    async fn publish_body(&self, queue_url: &str, body: String, group_id: Option<String>, dedup_id: Option<String>, attrs: Attributes) -> Result<PublishReceiptSqs, EventfulError> {
+        let message = prepare_outgoing(self, queue_url, body, group_id, dedup_id).await?.map_err(|(_, err)| err)?;
+        trace::record_payload(message.body.len());
+        self.stats.track(queue_url, message.body.len(), self.send_event(queue_url, message, &attrs)).await
+    }
+
+    async fn send_event(&self, queue_url: &str, message: OutgoingSQS, attrs: &Attributes) -> Result<PublishReceiptSqs, EventfulError> {
+        let queue = QueueUrl::parse(queue_url)?;
+        let (digest, bytes) = (md5_hex(&message.body), message.body.len());
+        let send_msg = self.client
+            .send_message()
+            .queue_url(queue_url)
+            .message_body(message.body)
+            .set_message_group_id(message.group_id)
+            .set_message_deduplication_id(message.dedup_id)
+            .set_message_attributes(attrs.to_sdk());
+        let output = self.call(queue_url, || send_msg.clone().send()).await?;
+        PublishReceiptSqs::verify(&queue, digest, bytes, output.message_id, output.sequence_number, output.md5_of_message_body)
+    }
+}
+
+
+/// Builds a ClientSQS. Anything not set comes from the environment, the same as aws_config::from_env()
+/// (or from the SdkConfig given to sdk_config()), and the endpoint from EVENTFUL_SQS_ENDPOINT when that is set.  
+/// A region is required even with a custom endpoint: the SDK signs every request for one, and queue URLs are resolved in it.
+/// For localstack any region works (it must match the one in the queue URLs you use); for GovCloud give the GovCloud region,
+/// i.e. "us-gov-west-1", along with its endpoint if you need a FIPS one ("https://sqs-fips.us-gov-west-1.amazonaws.com").
+/// ```no_run
+/// # use std::time::Duration;
+/// # use eventful::sqs::ClientSQS;
+/// # async fn demo() -> Result<(), eventful::err::EventfulError> {
+/// let client = ClientSQS::builder()
+///     .region("us-east-1".to_string())
+///     .endpoint_url("http://localhost:4566".to_string())
+///     .static_credentials("test", "test", None)
+///     .operation_timeout(Duration::from_secs(30))
+///     .build().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ClientSQSBuilder {
+    sdk_config: Option<aws_config::SdkConfig>,
+    region: Option<String>,
+    endpoint_url: Option<String>,
+    profile: Option<String>,
+    static_credentials: Option<(String, String, Option<String>)>,
+    assume_role: Option<(String, String)>,
+    operation_timeout: Option<Duration>,
+    retry_attempts: Option<u32>,
+    retry: Option<RetryPolicy>,
+}
+
+impl ClientSQSBuilder {
+    /// Start from an SdkConfig you load yourself instead of the environment. Anything else set on the builder overrides it
+    pub fn sdk_config(mut self, config: aws_config::SdkConfig) -> Self {
+        self.sdk_config = Some(config);
+        self
+    }
+
+    /// The region, or None to find it with the SDK's default chain (AWS_REGION, the profile, then IMDS)
+    pub fn region(mut self, region: impl Into<Option<String>>) -> Self {
+        self.region = region.into();
+        self
+    }
+
+    /// Send requests to another endpoint, i.e. "http://localhost:4566" for localstack. Overrides EVENTFUL_SQS_ENDPOINT
+    pub fn endpoint_url(mut self, endpoint: String) -> Self {
+        self.endpoint_url = Some(endpoint);
+        self
+    }
+
+    /// Take credentials (and the region, unless region() is set) from this profile in ~/.aws/config and ~/.aws/credentials
+    pub fn profile(mut self, profile: String) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Use a fixed access key and secret, i.e. "test"/"test" for localstack, with the session token when they are temporary
+    pub fn static_credentials(mut self, access_key_id: &str, secret_access_key: &str, session_token: Option<&str>) -> Self {
+        self.static_credentials = Some((access_key_id.to_string(), secret_access_key.to_string(), session_token.map(str::to_string)));
+        self
+    }
+
+    /// Assume this role (i.e. in another account) through STS, using the credentials configured otherwise (the environment,
+    /// a profile, or static keys) to call AssumeRole. The temporary credentials are refreshed before they expire
+    pub fn assume_role(mut self, role_arn: &str, session_name: &str) -> Self {
+        self.assume_role = Some((role_arn.to_string(), session_name.to_string()));
+        self
+    }
+
+    /// Give up on an API call (including its retries) after this long
+    pub fn operation_timeout(mut self, timeout: Duration) -> Self {
+        self.operation_timeout = Some(timeout);
+        self
+    }
+
+    /// How many attempts the SDK makes at each API call, including the first
+    pub fn retry_attempts(mut self, attempts: u32) -> Self {
+        self.retry_attempts = Some(attempts);
+        self
+    }
+
+    /// How the client retries sends, receives, and deletes which fail with a throttling, timeout, or server error (see ClientSQS::retry)
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub async fn build(self) -> Result<ClientSQS, EventfulError> {
+        let from_env = std::env::var(ENDPOINT_ENV_VAR).ok();
+        self.build_with_env_endpoint(from_env).await
+    }
+
+    /// build(), given the value of EVENTFUL_SQS_ENDPOINT rather than reading it
+    async fn build_with_env_endpoint(mut self, from_env: Option<String>) -> Result<ClientSQS, EventfulError> {
+        if self.endpoint_url.is_none() {
+            self.endpoint_url = endpoint_from(from_env);
+        }
+        if self.profile.is_some() && self.static_credentials.is_some() {
+            return Err(EventfulError::Config("set either a profile or static credentials for SQS, not both".to_string()))
+        }
+        if let Some(endpoint) = &self.endpoint_url {
+            if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                return Err(EventfulError::Config(format!("the SQS endpoint {} must start with http:// or https://", endpoint)))
+            }
+        }
+        if self.retry_attempts == Some(0) {
+            return Err(EventfulError::Config("SQS retry_attempts must be at least 1".to_string()))
+        }
+        let sdk_config = match self.sdk_config {
+            Some(config) => config,
+            None => {
+                let mut loader = aws_config::from_env();
+                if let Some(profile) = &self.profile {
+                    loader = loader.credentials_provider(aws_config::profile::ProfileFileCredentialsProvider::builder().profile_name(profile).build());
+                    if self.region.is_none() {
+                        loader = loader.region(aws_config::profile::ProfileFileRegionProvider::builder().profile_name(profile).build());
+                    }
+                }
+                loader.load().await
+            },
+        };
+        let mut builder = aws_sdk_sqs::config::Builder::from(&sdk_config);
+        if let Some(region) = &self.region {
+            builder = builder.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint) = self.endpoint_url {
+            builder = builder.endpoint_url(endpoint);
+        }
+        let mut credentials = sdk_config.credentials_provider().cloned();
+        if let Some((key, secret, token)) = self.static_credentials {
+            credentials = Some(SharedCredentialsProvider::new(aws_sdk_sqs::Credentials::new(key, secret, token, None, "eventful")));
+        }
+        if let Some((role_arn, session_name)) = self.assume_role {
+            let base = credentials.ok_or(EventfulError::Config(format!("no credentials to assume {} with", role_arn)))?;
+            let mut provider = AssumeRoleProvider::builder(role_arn).session_name(session_name);
+            if let Some(region) = self.region.clone().map(Region::new).or_else(|| sdk_config.region().cloned()) {
+                provider = provider.region(region);
+            }
+            credentials = Some(SharedCredentialsProvider::new(provider.build(base)));
+        }
+        // resolve the credentials now, so a missing profile or a role which can't be assumed fails here rather than on the first request
+        if let Some(credentials) = &credentials {
+            credentials.provide_credentials().await
+                .map_err(|err| EventfulError::Config(format!("could not resolve AWS credentials for SQS: {}", err)))?;
+            builder = builder.credentials_provider(credentials.clone());
+        }
+        if let Some(timeout) = self.operation_timeout {
+            builder = builder.timeout_config(aws_config::timeout::TimeoutConfig::builder().operation_timeout(timeout).build());
+        }
+        if let Some(attempts) = self.retry_attempts {
+            builder = builder.retry_config(aws_config::retry::RetryConfig::standard().with_max_attempts(attempts));
+        }
+        let config = builder.build();
+        if config.region().is_none() {
+            return Err(EventfulError::Config("no region for SQS: set one on the builder, in AWS_REGION, or in the profile (or run on EC2, where IMDS provides it)".to_string()))
+        }
+        let client = ClientSQS::from_client(Client::from_conf(config));
+        Ok(match self.retry {
+            Some(retry) => client.retry(retry),
+            None => client,
+        })
+    }
+}
+
+
+/// Where a queue sends messages which have been received too many times
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RedrivePolicy {
+    pub dead_letter_target_arn: String,
+    pub max_receive_count: u32,
+}
+
+impl RedrivePolicy {
+    fn to_json(&self) -> String {
+        serde_json::json!({"deadLetterTargetArn": self.dead_letter_target_arn, "maxReceiveCount": self.max_receive_count.to_string()}).to_string()
+    }
+
+    /// Parse the RedrivePolicy attribute. maxReceiveCount comes back as a string, but may be given as a number
+    pub fn parse(policy: &str) -> Option<Self> {
+        let policy: serde_json::Value = serde_json::from_str(policy).ok()?;
+        let max = policy.get("maxReceiveCount")?;
+        Some(RedrivePolicy{
+            dead_letter_target_arn: policy.get("deadLetterTargetArn")?.as_str()?.to_string(),
+            max_receive_count: u32::try_from(max.as_u64().or_else(|| max.as_str().and_then(|max| max.parse().ok()))?).ok()?,
+        })
+    }
+}
+
+
+/// Server-side encryption for a queue
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SseConfig {
+    /// SSE-SQS: encrypted with keys SQS owns and manages
+    Sqs,
+    /// SSE-KMS with a customer managed key. key_id is compared as given, so give it the way the queue was created with
+    /// (i.e. an alias or key ARN). data_key_reuse is how long SQS reuses a data key before asking KMS again
+    /// (SQS's default is 5 minutes); fewer KMS calls are cheaper, more are more secure
+    Kms {
+        key_id: String,
+        data_key_reuse: Option<Duration>,
+    },
+}
+
+impl SseConfig {
+    pub fn kms(key_id: &str) -> Self {
+        SseConfig::Kms{key_id: key_id.to_string(), data_key_reuse: None}
+    }
+
+    /// Set the data key reuse period of a Kms config. SQS accepts 1 minute to 24 hours
+    pub fn data_key_reuse(self, period: Duration) -> Self {
+        match self {
+            SseConfig::Kms{key_id, ..} => SseConfig::Kms{key_id, data_key_reuse: Some(period)},
+            sqs => sqs,
+        }
+    }
+}
+
+
+/// A queue's access policy, built from the statements this crate needs rather than arbitrary IAM JSON
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccessPolicy {
+    queue_arn: String,
+    sns_topics: Vec<String>,
+}
+
+impl AccessPolicy {
+    /// An empty policy for the queue with this ARN (i.e. QueueStats::queue_arn)
+    pub fn new(queue_arn: &str) -> Self {
+        AccessPolicy{queue_arn: queue_arn.to_string(), sns_topics: Vec::new()}
+    }
+
+    /// Allow an SNS topic to SendMessage to the queue, which a topic subscribed to the queue needs
+    pub fn allow_sns_topic(mut self, topic_arn: &str) -> Self {
+        if !self.sns_topics.iter().any(|topic| topic == topic_arn) {
+            self.sns_topics.push(topic_arn.to_string());
+        }
+        self
+    }
+
+    fn to_value(&self) -> serde_json::Value {
+        let statements: Vec<serde_json::Value> = self.sns_topics.iter().enumerate().map(|(i, topic_arn)| serde_json::json!({
+            "Sid": format!("AllowSnsTopic{}", i),
+            "Effect": "Allow",
+            "Principal": {"Service": "sns.amazonaws.com"},
+            "Action": "sqs:SendMessage",
+            "Resource": self.queue_arn,
+            "Condition": {"ArnEquals": {"aws:SourceArn": topic_arn}},
+        })).collect();
+        serde_json::json!({"Version": "2012-10-17", "Statement": statements})
+    }
+
+    /// The policy document, as the Policy attribute takes it
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    /// Whether a Policy attribute is this policy. The JSON is compared parsed, since SQS doesn't return it byte for byte
+    fn matches(&self, policy: &str) -> bool {
+        serde_json::from_str::<serde_json::Value>(policy).map(|policy| policy == self.to_value()).unwrap_or(false)
+    }
+}
+
+
+/// A queue's depth and configuration, from GetQueueAttributes. The counts are approximate, as SQS says
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// ApproximateNumberOfMessages: waiting to be received
+    pub visible: u64,
+    /// ApproximateNumberOfMessagesNotVisible: received but not yet deleted
+    pub not_visible: u64,
+    /// ApproximateNumberOfMessagesDelayed: sent with a delay which has not passed yet
+    pub delayed: u64,
+    pub queue_arn: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub last_modified_at: Option<DateTime<Utc>>,
+    pub visibility_timeout: Option<Duration>,
+    pub is_fifo: bool,
+    pub redrive_policy: Option<RedrivePolicy>,
+}
+
+impl QueueStats {
+    /// Read the stats out of GetQueueAttributes' attributes. Missing or malformed counts are 0
+    pub fn from_attributes(attrs: &HashMap<QueueAttributeName, String>) -> Self {
+        let count = |name: QueueAttributeName| attrs.get(&name).and_then(|val| val.parse::<u64>().ok()).unwrap_or(0);
+        let timestamp = |name: QueueAttributeName| attrs.get(&name)
+            .and_then(|val| val.parse::<i64>().ok())
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single());
+        QueueStats{
+            visible: count(QueueAttributeName::ApproximateNumberOfMessages),
+            not_visible: count(QueueAttributeName::ApproximateNumberOfMessagesNotVisible),
+            delayed: count(QueueAttributeName::ApproximateNumberOfMessagesDelayed),
+            queue_arn: attrs.get(&QueueAttributeName::QueueArn).cloned(),
+            created_at: timestamp(QueueAttributeName::CreatedTimestamp),
+            last_modified_at: timestamp(QueueAttributeName::LastModifiedTimestamp),
+            visibility_timeout: attrs.get(&QueueAttributeName::VisibilityTimeout)
+                .and_then(|val| val.parse::<u64>().ok())
+                .map(Duration::from_secs),
+            is_fifo: attrs.get(&QueueAttributeName::FifoQueue).map(|val| val == "true").unwrap_or(false),
+            redrive_policy: attrs.get(&QueueAttributeName::RedrivePolicy).and_then(|policy| RedrivePolicy::parse(policy)),
+        }
+    }
+}
+
+
+/// How many messages ClientSQS::manual_redrive moved, and how many it could not republish or delete (which stay on the DLQ)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RedriveReport {
+    pub moved: u64,
+    pub failed: u64,
+}
+
+
+/// The attributes ClientSQS::create_queue creates a queue with. Settings left as None keep SQS's defaults
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueSettings {
+    /// The queue name must end in .fifo when this is set
+    pub fifo: bool,
+    /// FIFO only: deduplicate by a hash of the body when a message has no dedup id
+    pub content_based_dedup: bool,
+    pub visibility_timeout: Option<Duration>,
+    pub message_retention: Option<Duration>,
+    /// The default long polling wait for ReceiveMessage calls which don't set one
+    pub receive_wait_time: Option<Duration>,
+    pub redrive: Option<RedrivePolicy>,
+    pub sse: Option<SseConfig>,
+    pub access_policy: Option<AccessPolicy>,
+}
+
+impl QueueSettings {
+    pub fn fifo(mut self) -> Self {
+        self.fifo = true;
+        self
+    }
+
+    pub fn content_based_dedup(mut self) -> Self {
+        self.content_based_dedup = true;
+        self
+    }
+
+    pub fn visibility_timeout(mut self, timeout: Duration) -> Self {
+        self.visibility_timeout = Some(timeout);
+        self
+    }
+
+    pub fn message_retention(mut self, retention: Duration) -> Self {
+        self.message_retention = Some(retention);
+        self
+    }
+
+    pub fn receive_wait_time(mut self, wait: Duration) -> Self {
+        self.receive_wait_time = Some(wait);
+        self
+    }
+
+    pub fn redrive(mut self, dead_letter_target_arn: &str, max_receive_count: u32) -> Self {
+        self.redrive = Some(RedrivePolicy{dead_letter_target_arn: dead_letter_target_arn.to_string(), max_receive_count});
+        self
+    }
+
+    pub fn sse(mut self, sse: SseConfig) -> Self {
+        self.sse = Some(sse);
+        self
+    }
+
+    pub fn access_policy(mut self, policy: AccessPolicy) -> Self {
+        self.access_policy = Some(policy);
+        self
+    }
+
+    /// The attributes as SQS names them. FifoQueue is left out unless `creating`, since it can't be changed afterwards
+    fn to_attributes(&self, creating: bool) -> HashMap<QueueAttributeName, String> {
+        let mut attrs = HashMap::new();
+        if self.fifo {
+            if creating {
+                attrs.insert(QueueAttributeName::FifoQueue, "true".to_string());
+            }
+            attrs.insert(QueueAttributeName::ContentBasedDeduplication, self.content_based_dedup.to_string());
+        }
+        if let Some(timeout) = self.visibility_timeout {
+            attrs.insert(QueueAttributeName::VisibilityTimeout, timeout.as_secs().to_string());
+        }
+        if let Some(retention) = self.message_retention {
+            attrs.insert(QueueAttributeName::MessageRetentionPeriod, retention.as_secs().to_string());
+        }
+        if let Some(wait) = self.receive_wait_time {
+            attrs.insert(QueueAttributeName::ReceiveMessageWaitTimeSeconds, wait.as_secs().to_string());
+        }
+        if let Some(redrive) = &self.redrive {
+            attrs.insert(QueueAttributeName::RedrivePolicy, redrive.to_json());
+        }
+        match &self.sse {
+            Some(SseConfig::Sqs) => {
+                attrs.insert(QueueAttributeName::SqsManagedSseEnabled, "true".to_string());
+            },
+            Some(SseConfig::Kms{key_id, data_key_reuse}) => {
+                attrs.insert(QueueAttributeName::KmsMasterKeyId, key_id.clone());
+                if let Some(period) = data_key_reuse {
+                    attrs.insert(QueueAttributeName::KmsDataKeyReusePeriodSeconds, period.as_secs().to_string());
+                }
+            },
+            None => {},
+        }
+        if let Some(policy) = &self.access_policy {
+            attrs.insert(QueueAttributeName::Policy, policy.to_json());
+        }
+        attrs
+    }
+
+    /// How an existing queue's attributes differ from these settings. Settings left as None aren't checked
+    fn diff(&self, actual: &HashMap<QueueAttributeName, String>) -> SettingsDiff {
+        let mut deviations = Vec::new();
+        let is_fifo = actual.get(&QueueAttributeName::FifoQueue).map(|val| val == "true").unwrap_or(false);
+        if is_fifo != self.fifo {
+            deviations.push(SettingDeviation{attribute: QueueAttributeName::FifoQueue, expected: self.fifo.to_string(), actual: Some(is_fifo.to_string())});
+        }
+        for (name, expected) in self.to_attributes(false) {
+            let found = actual.get(&name);
+            let same = match name {
+                QueueAttributeName::RedrivePolicy => found.and_then(|policy| RedrivePolicy::parse(policy)) == self.redrive,
+                QueueAttributeName::Policy => found.zip(self.access_policy.as_ref()).map(|(found, policy)| policy.matches(found)).unwrap_or(false),
+                _ => found == Some(&expected),
+            };
+            if !same {
+                deviations.push(SettingDeviation{attribute: name, expected, actual: found.cloned()});
+            }
+        }
+        deviations.sort_by(|a, b| a.attribute.as_str().cmp(b.attribute.as_str()));
+        SettingsDiff{deviations}
+    }
+}
+
+
+/// One attribute whose value isn't what QueueSettings expected. actual is None when the queue doesn't have the attribute
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SettingDeviation {
+    pub attribute: QueueAttributeName,
+    pub expected: String,
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for SettingDeviation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is {}, expected {}", self.attribute.as_str(), self.actual.as_deref().unwrap_or("unset"), self.expected)
+    }
+}
+
+
+/// How a queue differs from the settings it should have, from ClientSQS::verify_queue_settings. Empty means it matches
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SettingsDiff {
+    pub deviations: Vec<SettingDeviation>,
+}
+
+impl SettingsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.deviations.is_empty()
+    }
+}
+
+impl fmt::Display for SettingsDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let deviations: Vec<String> = self.deviations.iter().map(|deviation| deviation.to_string()).collect();
+        write!(f, "{}", deviations.join("; "))
+    }
+}
+
+
+/// Queue lifecycle management, i.e. for integration environments which create queues on the fly
+impl ClientSQS {
+    /// Create a queue, returning its URL. If a queue with the name already exists with the same attributes its URL is returned,
+    /// and if its attributes differ the error is EventfulError::QueueConflict, listing the differences
+    pub async fn create_queue(&self, name: &str, settings: &QueueSettings) -> Result<QueueUrl, EventfulError> {
+        if name.ends_with(".fifo") != settings.fifo {
+            return Err(EventfulError::Config(format!("queue '{}' must end in .fifo if and only if it is a FIFO queue", name)))
+        }
+        if let Some(queue_url) = self.get_queue_url(name).await? {
+            let diff = settings.diff(&self.get_queue_attributes(&queue_url).await?);
+            return match diff.is_empty() {
+                true => QueueUrl::parse(&queue_url),
+                false => Err(EventfulError::QueueConflict(format!("queue '{}' already exists, but {}", name, diff))),
+            }
+        }
+        let output = self.client.create_queue()
+            .queue_name(name)
+            .set_attributes(Some(settings.to_attributes(true)))
+            .send().await?;
+        let queue_url = output.queue_url.ok_or(EventfulError::SQS(format!("CreateQueue for '{}' did not return a queue URL", name)))?;
+        QueueUrl::parse(&queue_url)
+    }
+
+    /// The URL of a queue in this account and region, or None if there is no queue with the name
+    pub async fn get_queue_url(&self, name: &str) -> Result<Option<String>, EventfulError> {
+        match self.client.get_queue_url().queue_name(name).send().await {
+            Ok(output) => Ok(output.queue_url),
+            Err(SdkError::ServiceError(context)) if context.err().is_queue_does_not_exist() => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The URL of the queue with this name in this account and region, looked up with GetQueueUrl and cached for QUEUE_URL_TTL.
+    /// A queue which doesn't exist is EventfulError::QueueDoesNotExist
+    pub async fn queue_url_for_name(&self, name: &str) -> Result<QueueUrl, EventfulError> {
+        self.queue_url_for_owner(name, None).await
+    }
+
+    /// Like queue_url_for_name, for a queue owned by another account (which must have granted this one access)
+    pub async fn queue_url_for_owner(&self, name: &str, owner_account_id: Option<&str>) -> Result<QueueUrl, EventfulError> {
+        if let Some(url) = self.queue_urls.get(name, owner_account_id) {
+            return Ok(url)
+        }
+        let output = self.client.get_queue_url()
+            .queue_name(name)
+            .set_queue_owner_aws_account_id(owner_account_id.map(|owner| owner.to_string()))
+            .send().await;
+        let url = match output {
+            Ok(output) => output.queue_url.ok_or(EventfulError::SQS(format!("GetQueueUrl for '{}' did not return a queue URL", name)))?,
+            Err(SdkError::ServiceError(context)) if context.err().is_queue_does_not_exist() => {
+                return Err(EventfulError::QueueDoesNotExist(name.to_string()))
+            },
+            Err(err) => return Err(err.into()),
+        };
+        let url = QueueUrl::parse(&url)?;
+        self.queue_urls.insert(name, owner_account_id, url.clone());
+        Ok(url)
+    }
+
+    /// The URL for a QueueRef, looking names up as queue_url_for_name does
+    pub async fn resolve(&self, queue: &QueueRef) -> Result<QueueUrl, EventfulError> {
+        match queue {
+            QueueRef::Url(url) => Ok(url.clone()),
+            QueueRef::Name(name) => self.queue_url_for_name(name).await,
+        }
+    }
+
+    /// When a queue looked up by name turns out not to exist (i.e. it was deleted and recreated with a new URL),
+    /// forget its cached URL so the next call looks it up again
+    fn forget_if_missing<T>(&self, queue: &QueueRef, result: Result<T, EventfulError>) -> Result<T, EventfulError> {
+        if let (QueueRef::Name(name), Err(EventfulError::QueueDoesNotExist(_))) = (queue, &result) {
+            self.queue_urls.forget(name);
+        }
+        result
+    }
+
+    /// Every attribute of a queue, i.e. QueueArn
+    pub async fn get_queue_attributes(&self, queue_url: &str) -> Result<HashMap<QueueAttributeName, String>, EventfulError> {
+        let output = self.client.get_queue_attributes()
+            .queue_url(queue_url)
+            .attribute_names(QueueAttributeName::All)
+            .send().await?;
+        Ok(output.attributes.unwrap_or_default())
+    }
+
+    /// A queue's depth and configuration
+    pub async fn queue_stats(&self, queue_url: &str) -> Result<QueueStats, EventfulError> {
+        Ok(QueueStats::from_attributes(&self.get_queue_attributes(queue_url).await?))
+    }
+
+    /// The URL of every queue whose name starts with prefix, following ListQueues' pages
+    pub async fn queues_with_prefix(&self, prefix: &str) -> Result<Vec<QueueUrl>, EventfulError> {
+        let mut queue_urls = Vec::new();
+        let mut next_token = None;
+        loop {
+            let output = self.client.list_queues()
+                .queue_name_prefix(prefix)
+                .max_results(1000)
+                .set_next_token(next_token)
+                .send().await?;
+            for url in output.queue_urls.unwrap_or_default() {
+                queue_urls.push(QueueUrl::parse(&url)?);
+            }
+            next_token = output.next_token;
+            if next_token.is_none() {
+                return Ok(queue_urls)
+            }
+        }
+    }
+
+    /// Point a queue's RedrivePolicy at a dead-letter queue, so messages move there after max_receive_count receives
+    pub async fn set_redrive(&self, source_queue_url: &str, dlq_queue_url: &str, max_receive_count: u32) -> Result<(), EventfulError> {
+        let dlq_arn = self.queue_stats(dlq_queue_url).await?.queue_arn
+            .ok_or(EventfulError::SQS(format!("{} has no QueueArn", dlq_queue_url)))?;
+        let policy = RedrivePolicy{dead_letter_target_arn: dlq_arn, max_receive_count};
+        self.client.set_queue_attributes()
+            .queue_url(source_queue_url)
+            .attributes(QueueAttributeName::RedrivePolicy, policy.to_json())
+            .send().await?;
+        Ok(())
+    }
+
+    /// Move every message on a dead-letter queue back to its source queue, batch_size (1 to 10) at a time, until the DLQ is empty.
+    /// This stands in for SQS's own message move tasks (StartMessageMoveTask), which the aws-sdk-sqs version used here predates.
+    /// Each message is republished with its body, message attributes and group id, and only deleted from the DLQ once that succeeds;
+    /// for FIFO queues its original message id is the dedup id, so rerunning after a crash does not duplicate it.
+    /// Messages which could not be republished stay on the DLQ and are counted as failed, as are messages republished but then
+    /// not deleted: those are sent again if the redrive is rerun (which FIFO source queues deduplicate within five minutes)
+    pub async fn manual_redrive(&self, dlq_queue_url: &str, source_queue_url: &str, batch_size: i32) -> Result<RedriveReport, EventfulError> {
+        let options = ReceiveOptions{
+            wait_time_seconds: 1,
+            max_messages: batch_size.clamp(1, 10),
+            // keeps messages which fail to republish out of sight until the DLQ has been drained
+            visibility_timeout: Some(300),
+            attribute_names: vec![QueueAttributeName::All],
+            message_attribute_names: vec!["All".to_string()],
+            ..Default::default()
+        };
+        let fifo = source_queue_url.trim_end_matches('/').ends_with(".fifo");
+        let mut report = RedriveReport::default();
+        loop {
+            let messages = self.poll_messages_with(dlq_queue_url, false, &options).await?;
+            if messages.is_empty() {
+                return Ok(report)
+            }
+            for message in messages {
+                let receipt_handle = match &message.receipt_handle {
+                    Some(val) => val.clone(),
+                    None => continue,
+                };
+                let group_id = message.attributes.as_ref().and_then(|attrs| attrs.get(&MessageSystemAttributeName::MessageGroupId).cloned());
+                let sent = self.client.send_message()
+                    .queue_url(source_queue_url)
+                    .set_message_body(message.body)
+                    .set_message_attributes(message.message_attributes)
+                    .set_message_group_id(group_id.filter(|_| fifo))
+                    .set_message_deduplication_id(message.message_id.filter(|_| fifo))
+                    .send().await;
+                let moved = match sent {
+                    Ok(_) => self.delete_message(dlq_queue_url, &receipt_handle).await.is_ok(),
+                    Err(_) => false,
+                };
+                match moved {
+                    true => report.moved += 1,
+                    false => report.failed += 1,
+                }
+            }
+        }
+    }
+
+    /// Change a queue's attributes to the settings. Whether it is FIFO can't be changed, so settings.fifo must match the queue
+    pub async fn set_queue_attributes(&self, queue_url: &str, settings: &QueueSettings) -> Result<(), EventfulError> {
+        self.client.set_queue_attributes()
+            .queue_url(queue_url)
+            .set_attributes(Some(settings.to_attributes(false)))
+            .send().await?;
+        Ok(())
+    }
+
+    /// How a queue's attributes differ from the settings, i.e. to audit that queues are encrypted with the right key.
+    /// Nothing is changed; set_queue_attributes applies the settings
+    pub async fn verify_queue_settings(&self, queue_url: &str, expected: &QueueSettings) -> Result<SettingsDiff, EventfulError> {
+        Ok(expected.diff(&self.get_queue_attributes(queue_url).await?))
+    }
+
+    /// Delete a queue and any messages in it. SQS won't create a queue with the same name for 60 seconds afterwards
+    pub async fn delete_queue(&self, queue_url: &str) -> Result<(), EventfulError> {
+        self.client.delete_queue().queue_url(queue_url).send().await?;
+        Ok(())
+    }
+
+    /// Delete every message in a queue. A queue can be purged once every 60 seconds;
+    /// purging again sooner fails with EventfulError::PurgeInProgress
+    pub async fn purge_queue(&self, queue_url: &str) -> Result<(), EventfulError> {
+        match self.client.purge_queue().queue_url(queue_url).send().await {
+            Ok(_) => Ok(()),
+            Err(SdkError::ServiceError(context)) if context.err().is_purge_queue_in_progress() => {
+                Err(EventfulError::PurgeInProgress(format!("{} was purged in the last 60 seconds", queue_url)))
+            },
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+
+/// ClientSQS can act as a Publisher for SQS queues.
+/// SQS message bodies must be text, so the body must be valid UTF-8
+#[async_trait]
+impl Publisher for ClientSQS {
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.publish", skip(self, body, meta), fields(backend = "sqs", dest = %dest, payload_bytes = body.len(), outcome = tracing::field::Empty)))]
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let result = self.send_bytes(dest, body, meta, None).await;
+        trace::record_outcome(&result);
+        result
+    }
+
+    fn max_delay(&self) -> Option<Duration> {
+        Some(MAX_DELAY)
+    }
+
+    /// Sent with DelaySeconds, so the delay is rounded down to whole seconds.
+    /// FIFO queues don't accept a per-message delay, and SQS rejects the send
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "eventful.publish", skip(self, body, meta), fields(backend = "sqs", dest = %dest, payload_bytes = body.len(), delay_secs = delay.as_secs(), outcome = tracing::field::Empty)))]
+    async fn publish_delayed(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay: Duration) -> Result<Receipt, EventfulError> {
+        let result = match publisher::check_delay(self.max_delay(), dest, delay) {
+            Ok(()) => self.send_bytes(dest, body, meta, Some(delay.as_secs() as i32)).await,
+            Err(err) => Err(err),
+        };
+        trace::record_outcome(&result);
+        result
+    }
+}
+
+impl ClientSQS {
+    async fn send_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata, delay_seconds: Option<i32>) -> Result<Receipt, EventfulError> {
+        metrics::time_publish(dest.name(), async {
+            let queue_url = match dest {
+                Destination::SqsQueue(url) => url,
+                _ => return Err(publisher::unsupported("ClientSQS", dest)),
+            };
+            check_fifo(queue_url, meta.group_id.as_deref(), meta.dedup_id.as_deref())?;
+            let body = String::from_utf8(body.to_vec())
+                .map_err(|_| EventfulError::SQS("SQS message bodies must be valid UTF-8".to_string()))?;
+            let bytes = body.len();
+            let send = self.client
+                .send_message()
+                .queue_url(queue_url)
+                .message_body(body)
+                .set_message_group_id(meta.group_id.clone())
+                .set_message_deduplication_id(meta.dedup_id.clone())
+                .set_delay_seconds(delay_seconds);
+            let output = self.stats.track(queue_url, bytes, self.call(queue_url, || send.clone().send())).await?;
+            Ok(Receipt{message_id: output.message_id})
+        }).await
+    }
+}
+
+/// ClientSQS sends and receives through the AWS API, tracking sends in stats()
+#[async_trait]
+impl SqsApi for ClientSQS {
+    async fn send_message(&self, queue_url: &str, message: OutgoingSQS) -> Result<SentMessage, EventfulError> {
+        check_fifo(queue_url, message.group_id.as_deref(), message.dedup_id.as_deref())?;
+        let bytes = message.body.len();
+        let send = self.client
+            .send_message()
+            .queue_url(queue_url)
+            .message_body(message.body)
+            .set_message_group_id(message.group_id)
+            .set_message_deduplication_id(message.dedup_id);
+        let output = self.stats.track(queue_url, bytes, self.call(queue_url, || send.clone().send())).await?;
+        Ok(SentMessage{message_id: output.message_id, sequence_number: output.sequence_number, md5_of_body: output.md5_of_message_body})
+    }
+
+    async fn send_message_batch(&self, queue_url: &str, messages: Vec<OutgoingSQS>) -> Result<Vec<SentMessage>, EventfulError> {
+        for message in &messages {
+            check_fifo(queue_url, message.group_id.as_deref(), message.dedup_id.as_deref())?;
+        }
+        let mut sent = Vec::with_capacity(messages.len());
+        for chunk in messages.chunks(10) {
+            let entries = chunk.iter().enumerate()
+                .map(|(i, message)| SendMessageBatchRequestEntry::builder()
+                    .id(i.to_string())
+                    .message_body(&message.body)
+                    .set_message_group_id(message.group_id.clone())
+                    .set_message_deduplication_id(message.dedup_id.clone())
+                    .build())
+                .collect::<Vec<_>>();
+            let request = self.client
+                .send_message_batch()
+                .queue_url(queue_url)
+                .set_entries(Some(entries));
+            let output = self.call(queue_url, || request.clone().send()).await?;
+            let failed = output.failed.unwrap_or_default();
+            if let Some(failure) = failed.first() {
+                return Err(EventfulError::SQS(format!("{} of {} messages in a batch were not sent: {}",
+                    failed.len(), chunk.len(), failure.message.as_deref().unwrap_or("no reason given"))))
+            }
+            let mut successful = output.successful.unwrap_or_default();
+            successful.sort_by_key(|entry| entry.id.as_deref().and_then(|id| id.parse::<usize>().ok()));
+            sent.extend(successful.into_iter().map(|entry| SentMessage{
+                message_id: entry.message_id,
+                sequence_number: entry.sequence_number,
+                md5_of_body: entry.md5_of_message_body,
+            }));
+        }
+        Ok(sent)
+    }
+
+    async fn receive_messages(&self, queue_url: &str, max_messages: i32, wait_time_seconds: i32) -> Result<Vec<Message>, EventfulError> {
+        let request = self.client
+            .receive_message()
+            .queue_url(queue_url)
+            .wait_time_seconds(wait_time_seconds.clamp(0, 20))
+            .max_number_of_messages(max_messages.clamp(1, 10))
+            .attribute_names(QueueAttributeName::All);
+        let output = self.call(queue_url, || request.clone().send()).await?;
+        Ok(output.messages.unwrap_or_default())
+    }
+
+    async fn delete_message(&self, queue_url: &str, receipt_handle: &str) -> Result<(), EventfulError> {
+        let request = self.client.delete_message()
+            .queue_url(queue_url)
+            .receipt_handle(receipt_handle);
+        self.call(queue_url, || request.clone().send()).await?;
+        #[cfg(feature = "s3")]
+        if let Some(offload) = &self.offload {
+            offload.acked(receipt_handle).await;
+        }
+        Ok(())
+    }
+
+    async fn change_visibility(&self, queue_url: &str, receipt_handle: &str, seconds: i32) -> Result<(), EventfulError> {
+        let request = self.client.change_message_visibility()
+            .queue_url(queue_url)
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(seconds);
+        self.call(queue_url, || request.clone().send()).await?;
+        Ok(())
+    }
+
+    fn fifo(&self) -> FifoOptions {
+        self.fifo.clone()
+    }
+
+    #[cfg(feature = "s3")]
+    async fn prepare_body(&self, queue_url: &str, body: String) -> Result<String, EventfulError> {
+        match &self.offload {
+            Some(offload) => offload.store(queue_url, body).await,
+            None => Ok(body),
+        }
+    }
+}
+
+/// A SubscriptionSQS long-polls a queue and yields a Delivery for each message received.
+/// Messages are received in batches of up to 10 and buffered until they are handed out
+pub struct SubscriptionSQS {
+    api: Arc<dyn SqsApi>,
+    queue_url: String,
+    buffer: VecDeque<Message>,
+    wait_time_seconds: i32,
+    max_messages: i32,
+    unwrap_sns: bool,
+}
+
+impl SubscriptionSQS {
+    pub fn new(client: Client, queue_url: &str) -> Self {
+        SubscriptionSQS::from_api(Arc::new(ClientSQS::from_client(client)), queue_url)
+    }
+
+    /// Subscribe through any SqsApi, i.e. a testing::FakeSqs
+    pub fn from_api(api: Arc<dyn SqsApi>, queue_url: &str) -> Self {
+        SubscriptionSQS{api, queue_url: queue_url.to_string(), buffer: VecDeque::new(), wait_time_seconds: 20, max_messages: 10, unwrap_sns: false}
+    }
+
+    /// When true, bodies which are SNS notification envelopes are unwrapped to the published message,
+    /// and the envelope's message attributes become headers. Other bodies (i.e. from raw message delivery) pass through unchanged
+    pub fn unwrap_sns(mut self, unwrap_sns: bool) -> Self {
+        self.unwrap_sns = unwrap_sns;
+        self
+    }
+
+    /// How long each ReceiveMessage call waits for messages to arrive (0 to 20 seconds, default 20)
+    pub fn wait_time_seconds(mut self, seconds: i32) -> Self {
+        self.wait_time_seconds = seconds.clamp(0, 20);
+        self
+    }
+
+    /// The most messages received by each ReceiveMessage call (1 to 10, default 10)
+    pub fn max_messages(mut self, max: i32) -> Self {
+        self.max_messages = max.clamp(1, 10);
+        self
+    }
+}
+
+#[async_trait]
+impl Subscriber for SubscriptionSQS {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        loop {
+            if let Some(message) = self.buffer.pop_front() {
+                match delivery_for(&self.api, &self.queue_url, message, self.unwrap_sns, None) {
+                    Some(delivery) => return Ok(Some(delivery)),
+                    None => continue,
+                }
+            }
+            let messages = self.api.receive_messages(&self.queue_url, self.max_messages, self.wait_time_seconds).await?;
+            self.buffer.extend(messages);
+        }
+    }
+
+    fn describe(&self) -> String {
+        Destination::SqsQueue(self.queue_url.clone()).to_string()
+    }
+}
+
+
+/// The QueueConsumer trait is the SQS counterpart of nsq::ChannelConsumer: implement it on a struct to run a handler over T's queue.
+/// run_until long polls, hands each message to the handler (several at once, up to concurrency()), and then acks, nacks or dead-letters
+/// it exactly as consumer::run does, with the same ConsumerStats and observer callbacks. A failed message is nacked by setting its
+/// visibility timeout to options.retry's backoff. Once idle_after() receives in a row come back empty, or after a failed receive,
+/// it waits idle_backoff() before receiving again. The current idle level is in options.stats (idle_receives and idle_wait),
+/// and the observer's consumer_dormant and consumer_awake callbacks report the wait reaching its cap and messages arriving again.
+/// Under a shutdown::Coordinator, pass its intake() as the shutdown token and register the task, so a deploy drains the handlers
+/// already running and hands back (rather than strands) the messages of any which don't finish within drain_timeout().
+/// # Examples:
+/// ```no_run
+/// # use std::time::Duration;
+/// # use eventful::prelude::*;
+/// # use eventful::shutdown::Coordinator;
+/// # #[derive(Serialize, Deserialize)]
+/// # struct OrderPlaced { order_id: u64 }
+/// # impl Event for OrderPlaced { fn queue_env_var() -> Option<&'static str> { Some("ORDERS_QUEUE_URL") } }
+/// # async fn fulfil(order: OrderPlaced) -> Result<(), EventfulError> { Ok(()) }
+/// struct OrderConsumer;
+///
+/// impl QueueConsumer<OrderPlaced> for OrderConsumer {
+///     fn concurrency(&self) -> usize {
+///         32
+///     }
+/// }
+///
+/// # async fn demo(client: ClientSQS) -> Result<(), Box<dyn std::error::Error>> {
+/// let coordinator = Coordinator::new();
+/// let intake = coordinator.intake();
+/// coordinator.register_task("orders", tokio::spawn(async move {
+///     OrderConsumer.run_until(&client, &ConsumerOptions::default(), |order: OrderPlaced| async move {
+///         fulfil(order).await
+///     }, intake).await
+/// }));
+/// let report = coordinator.install_signal_handlers(Duration::from_secs(30)).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[async_trait]
+pub trait QueueConsumer<T: Event> {
+    /// The queue to consume, which is T's own unless overridden (i.e. one of the queues T's queue_for() shards across)
+    fn queue(&self) -> Result<QueueRef, EventfulError> {
+        <T as Event>::queue_ref()
+    }
+
+    /// How each ReceiveMessage call waits and how many messages it takes. ApproximateReceiveCount is always requested
+    fn receive_options(&self) -> ReceiveOptions {
+        ReceiveOptions::default()
+    }
+
+    /// The most messages handled at once, across batches. Another batch is only received once there is room for all of it
+    fn concurrency(&self) -> usize {
+        10
+    }
+
+    /// The wait before receiving again, by how many receives in a row have come back empty (past idle_after()) or failed.
+    /// Each empty long poll already costs a ReceiveMessage request every wait_time_seconds, so a fleet of consumers on quiet queues
+    /// can save most of its requests with a cap of minutes (i.e. `max: Duration::from_secs(300)`). The cost is latency:
+    /// a message which arrives during a wait isn't received until the wait ends, so the cap is the worst-case delay after a quiet spell
+    fn idle_backoff(&self) -> Backoff {
+        Backoff::Exponential{base: Duration::from_secs(1), factor: 2.0, max: Duration::from_secs(30)}
+    }
+
+    /// How many receives in a row must come back empty before idle_backoff() waits begin; until then each receive follows
+    /// the last straight away. The wait is reset as soon as a message arrives
+    fn idle_after(&self) -> u32 {
+        1
+    }
+
+    fn on_decode_error(&self) -> OnDecodeError {
+        OnDecodeError::default()
+    }
+
+    /// Unwrap SNS notification envelopes, as SubscriptionSQS::unwrap_sns does
+    fn unwrap_sns(&self) -> bool {
+        false
+    }
+
+    /// How long run_until waits, once shutdown is cancelled, for the handlers already running. Any still running then are dropped,
+    /// and their messages made visible again straight away, so another consumer can take them instead of waiting out the
+    /// visibility timeout. A receive under way at shutdown is awaited (for at most its wait_time_seconds) and anything it took
+    /// is released the same way. Keep it shorter than a Coordinator's grace period, or the Coordinator abandons the loop first
+    fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(20)
+    }
+
+    /// Consume until shutdown is cancelled, then wait up to drain_timeout() for the messages being handled to be settled.
+    /// Failed receives are retried after idle_backoff(), except when the queue does not exist.
+    /// An error settling a message (i.e. deleting it) stops the loop, as it does consumer::run, releasing the messages of the other handlers
+    async fn run_until<H, Fut>(&self, client: &ClientSQS, options: &ConsumerOptions, handler: H, shutdown: CancellationToken) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        let queue_url = client.resolve(&self.queue()?).await?.to_string();
+        let concurrency = self.concurrency().max(1);
+        let mut receive_options = self.receive_options();
+        receive_options.max_messages = receive_options.max_messages.min(concurrency as i32);
+        if !receive_options.attribute_names.contains(&QueueAttributeName::All) {
+            receive_options.attribute_names.push(QueueAttributeName::All);
+        }
+        receive_options.validate()?;
+        let settings = ConsumeSettings{
+            queue_url: queue_url.clone(),
+            concurrency,
+            max_messages: receive_options.max_messages as usize,
+            idle_after: self.idle_after().max(1),
+            idle_backoff: self.idle_backoff(),
+            on_decode_error: self.on_decode_error(),
+            unwrap_sns: self.unwrap_sns(),
+            drain_timeout: self.drain_timeout(),
+        };
+        let receive = || client.poll_messages_inner(&queue_url, false, &receive_options);
+        consume_until(settings, Arc::new(client.clone()), receive, options, &handler, shutdown).await
+    }
+}
+
+
+/// What QueueConsumer::run_until's loop takes from the QueueConsumer
+struct ConsumeSettings {
+    queue_url: String,
+    concurrency: usize,
+    /// The most messages one receive returns
+    max_messages: usize,
+    idle_after: u32,
+    idle_backoff: Backoff,
+    on_decode_error: OnDecodeError,
+    unwrap_sns: bool,
+    drain_timeout: Duration,
+}
+
+/// QueueConsumer::run_until's loop, receiving batches with receive and settling messages through api.
+/// Whenever it stops, the messages it holds are released rather than left to their visibility timeout:
+/// those of handlers abandoned at the drain deadline or when settling fails, and those of a receive under way at shutdown
+async fn consume_until<T, H, Fut, R, RFut>(settings: ConsumeSettings, api: Arc<dyn SqsApi>, mut receive: R, options: &ConsumerOptions, handler: &H, shutdown: CancellationToken)
+    -> Result<(), EventfulError>
+where
+    T: DeserializeOwned,
+    H: Fn(T) -> Fut,
+    Fut: Future<Output = Result<(), EventfulError>>,
+    R: FnMut() -> RFut,
+    RFut: Future<Output = Result<Vec<Message>, EventfulError>>,
+{
+    let ConsumeSettings{queue_url, concurrency, max_messages, idle_after, idle_backoff: backoff, on_decode_error, unwrap_sns, drain_timeout} = settings;
+    let observer = observer::or_global(&options.observer);
+    observer.consumer_started(&ConsumerStarted{source: queue_url.clone()});
+
+    let mut in_flight = FuturesUnordered::new();
+    let mut receiving: Option<std::pin::Pin<Box<RFut>>> = None;
+    let mut idle_until: Option<tokio::time::Instant> = None;
+    let (mut empty_receives, mut failed_receives) = (0, 0);
+    let mut dormant = false;
+    // the receipt handles of messages whose handlers are running, to release if they are abandoned
+    let mut unsettled: HashSet<String> = HashSet::new();
+    let mut drain_deadline: Option<tokio::time::Instant> = None;
+    loop {
+        if shutdown.is_cancelled() && drain_deadline.is_none() {
+            idle_until = None;
+            drain_deadline = Some(tokio::time::Instant::now() + drain_timeout);
+        }
+        let room = in_flight.len() + max_messages <= concurrency;
+        if receiving.is_none() && idle_until.is_none() && room && !shutdown.is_cancelled() {
+            receiving = Some(Box::pin(receive()));
+        }
+        if shutdown.is_cancelled() && receiving.is_none() && in_flight.is_empty() {
+            return Ok(())
+        }
+        tokio::select! {
+            _ = shutdown.cancelled(), if drain_deadline.is_none() => {},
+            Some((receipt_handle, settled)) = in_flight.next(), if !in_flight.is_empty() => {
+                unsettled.remove(&receipt_handle);
+                if let Err(err) = settled {
+                    // the loop stops as consumer::run does, and the other handlers with it
+                    drop(std::mem::take(&mut in_flight));
+                    release(&api, &queue_url, unsettled.drain()).await;
+                    if let Some(receiving) = receiving.take() {
+                        release_received(&api, &queue_url, receiving.await).await;
+                    }
+                    return Err(err)
+                }
+            },
+            _ = async { tokio::time::sleep_until(drain_deadline.unwrap()).await }, if drain_deadline.is_some() && !in_flight.is_empty() => {
+                drop(std::mem::take(&mut in_flight));
+                release(&api, &queue_url, unsettled.drain()).await;
+                if let Some(receiving) = receiving.take() {
+                    release_received(&api, &queue_url, receiving.await).await;
+                }
+                return Ok(())
+            },
+            received = async { receiving.as_mut().unwrap().await }, if receiving.is_some() => {
+                receiving = None;
+                if shutdown.is_cancelled() {
+                    // received while shutting down, so hand the messages straight back rather than start on them
+                    release_received(&api, &queue_url, received).await;
+                    continue
+                }
+                match received {
+                    Ok(messages) => {
+                        if failed_receives > 0 {
+                            observer.consumer_reconnected(&ConsumerReconnected{source: queue_url.clone(), failed_attempts: failed_receives});
+                            failed_receives = 0;
+                        }
+                        let previous = empty_receives;
+                        empty_receives = match messages.is_empty() {
+                            true => empty_receives + 1,
+                            false => 0,
+                        };
+                        let mut wait = Duration::ZERO;
+                        if empty_receives >= idle_after {
+                            let attempt = empty_receives - idle_after + 1;
+                            wait = backoff.delay(attempt, &mut rand::thread_rng());
+                            idle_until = Some(tokio::time::Instant::now() + wait);
+                            if !dormant && backoff.nominal(attempt) >= backoff.max() {
+                                dormant = true;
+                                observer.consumer_dormant(&ConsumerIdle{source: queue_url.clone(), empty_receives, wait});
+                            }
+                        }
+                        if dormant && empty_receives == 0 {
+                            dormant = false;
+                            observer.consumer_awake(&ConsumerIdle{source: queue_url.clone(), empty_receives: previous, wait});
+                        }
+                        options.stats.set_idle(empty_receives, wait);
+                        for message in messages {
+                            let receipt_handle = message.receipt_handle.clone().unwrap_or_default();
+                            if let Some(delivery) = delivery_for(&api, &queue_url, message, unwrap_sns, None) {
+                                unsettled.insert(receipt_handle.clone());
+                                in_flight.push(consumer::process(delivery, options, on_decode_error, handler).map(move |settled| (receipt_handle, settled)));
+                            }
+                        }
+                    },
+                    Err(err @ EventfulError::QueueDoesNotExist(_)) => return Err(err),
+                    Err(_) => {
+                        failed_receives += 1;
+                        let delay = backoff.delay(failed_receives, &mut rand::thread_rng());
+                        idle_until = Some(tokio::time::Instant::now() + delay);
+                    },
+                }
+            },
+            _ = async { tokio::time::sleep_until(idle_until.unwrap()).await }, if idle_until.is_some() => {
+                idle_until = None;
+            },
+            else => return Ok(()),
+        }
+    }
+}
+
+/// Make messages visible again straight away, so another consumer can take them.
+/// Failures are left to the visibility timeout, as they would have been without the release
+async fn release(api: &Arc<dyn SqsApi>, queue_url: &str, receipt_handles: impl IntoIterator<Item = String>) {
+    let releases = receipt_handles.into_iter()
+        .map(|receipt_handle| async move {
+            let _ = api.change_visibility(queue_url, &receipt_handle, 0).await;
+        })
+        .collect::<Vec<_>>();
+    futures::future::join_all(releases).await;
+}
+
+/// Release whatever a receive took, when it finished after the loop stopped wanting it
+async fn release_received(api: &Arc<dyn SqsApi>, queue_url: &str, received: Result<Vec<Message>, EventfulError>) {
+    let messages = received.unwrap_or_default();
+    release(api, queue_url, messages.into_iter().filter_map(|message| message.receipt_handle)).await;
+}
+
+
+/// Options for a QueueWorkerPool
+#[derive(Clone)]
+pub struct WorkerPoolOptions {
+    /// How many loops receive at once (default 2)
+    pub pollers: usize,
+    /// How many messages are handled at once (default 8). Messages of a FIFO group always go to the same worker, in order
+    pub workers: usize,
+    /// The most messages received but not yet settled, across pollers and workers (default 100).
+    /// A poller only receives once there is room for a whole batch
+    pub max_in_flight: usize,
+    pub receive: ReceiveOptions,
+    /// Extend each message's visibility by .1 every .0 until it is settled (default every 20s by 60s); None to leave it
+    pub heartbeat: Option<(Duration, Duration)>,
+    /// The wait before a poller receives again, by how many of its receives in a row were empty or failed
+    pub idle_backoff: Backoff,
+    pub on_decode_error: OnDecodeError,
+    /// Retry, dead-lettering, observer, and stats, as for consumer::run
+    pub consumer: ConsumerOptions,
+    /// The queue to consume, when it isn't T's own (default None)
+    pub queue: Option<QueueRef>,
+}
+
+impl Default for WorkerPoolOptions {
+    fn default() -> Self {
+        WorkerPoolOptions{
+            pollers: 2,
+            workers: 8,
+            max_in_flight: 100,
+            receive: ReceiveOptions::default(),
+            heartbeat: Some((Duration::from_secs(20), Duration::from_secs(60))),
+            idle_backoff: Backoff::Exponential{base: Duration::from_secs(1), factor: 2.0, max: Duration::from_secs(30)},
+            on_decode_error: OnDecodeError::default(),
+            consumer: ConsumerOptions::default(),
+            queue: None,
+        }
+    }
+}
+
+impl WorkerPoolOptions {
+    pub fn pollers(mut self, pollers: usize) -> Self {
+        self.pollers = pollers;
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    pub fn receive(mut self, receive: ReceiveOptions) -> Self {
+        self.receive = receive;
+        self
+    }
+
+    pub fn heartbeat(mut self, heartbeat: Option<(Duration, Duration)>) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn idle_backoff(mut self, idle_backoff: Backoff) -> Self {
+        self.idle_backoff = idle_backoff;
+        self
+    }
+
+    pub fn on_decode_error(mut self, on_decode_error: OnDecodeError) -> Self {
+        self.on_decode_error = on_decode_error;
+        self
+    }
+
+    pub fn consumer(mut self, consumer: ConsumerOptions) -> Self {
+        self.consumer = consumer;
+        self
+    }
+
+    pub fn queue(mut self, queue: QueueRef) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+}
+
+
+/// A QueueWorkerPool consumes T's queue with several pollers feeding a workers::ShardedPool, so throughput isn't capped
+/// by one receive-handle-ack loop. Messages are sharded by their FIFO group id (round robin on standard queues),
+/// so each group is handled in order. Every message is handled as consumer::run would, with the stats of options.consumer.
+/// # Examples:
+/// ```no_run
+/// # use eventful::prelude::*;
+/// # use eventful::sqs::{QueueWorkerPool, WorkerPoolOptions};
+/// # #[derive(Serialize, Deserialize)]
+/// # struct OrderPlaced { order_id: u64 }
+/// # impl Event for OrderPlaced { fn queue_env_var() -> Option<&'static str> { Some("ORDERS_QUEUE_URL") } }
+/// # async fn fulfil(order: OrderPlaced) -> Result<(), EventfulError> { Ok(()) }
+/// # async fn demo(client: ClientSQS) -> Result<(), Box<dyn std::error::Error>> {
+/// let pool = QueueWorkerPool::spawn::<OrderPlaced, _, _>(&client, WorkerPoolOptions::default().pollers(4).workers(32), |order| async move {
+///     fulfil(order).await
+/// }).await?;
+/// let stats = pool.stats();
+/// tokio::signal::ctrl_c().await?;
+/// pool.shutdown().await?;
+/// println!("{:?}", stats.snapshot());
+/// # Ok(())
+/// # }
+/// ```
+pub struct QueueWorkerPool {
+    shutdown: CancellationToken,
+    task: JoinHandle<Result<(), EventfulError>>,
+    stats: Arc<consumer::ConsumerStats>,
+}
+
+impl QueueWorkerPool {
+    /// Resolve the queue (options.queue, else T's) and start the pollers and workers. Must be called within a tokio runtime
+    pub async fn spawn<T, H, Fut>(client: &ClientSQS, options: WorkerPoolOptions, handler: H) -> Result<QueueWorkerPool, EventfulError>
+    where
+        T: Event + Send + 'static,
+        H: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), EventfulError>> + Send + 'static,
+    {
+        let queue = match &options.queue {
+            Some(queue) => queue.clone(),
+            None => <T as Event>::queue_ref()?,
+        };
+        let queue_url = client.resolve(&queue).await?.to_string();
+        let mut receive = options.receive.clone();
+        receive.max_messages = receive.max_messages.min(options.max_in_flight.max(1) as i32);
+        if !receive.attribute_names.contains(&QueueAttributeName::All) {
+            receive.attribute_names.push(QueueAttributeName::All);
+        }
+        receive.validate()?;
+        let receive_batch: ReceiveBatch = {
+            let (client, queue_url, receive) = (client.clone(), queue_url.clone(), receive.clone());
+            Arc::new(move || {
+                let (client, queue_url, receive) = (client.clone(), queue_url.clone(), receive.clone());
+                async move { client.receive_filtered(&queue_url, false, &receive).await }.boxed()
+            })
+        };
+        Ok(QueueWorkerPool::start(Arc::new(client.clone()), queue_url, receive, receive_batch, options, handler))
+    }
+
+    /// Start the pollers and workers on a resolved queue, receiving batches with receive_batch and settling messages through api
+    fn start<T, H, Fut>(api: Arc<dyn SqsApi>, queue_url: String, receive: ReceiveOptions, receive_batch: ReceiveBatch, options: WorkerPoolOptions, handler: H) -> QueueWorkerPool
+    where
+        T: DeserializeOwned + Send + 'static,
+        H: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), EventfulError>> + Send + 'static,
+    {
+        let shutdown = CancellationToken::new();
+        let stats = options.consumer.stats.clone();
+        observer::or_global(&options.consumer.observer).consumer_started(&ConsumerStarted{source: queue_url.clone()});
+
+        let (consumer_options, on_decode_error, handler) = (Arc::new(options.consumer.clone()), options.on_decode_error, Arc::new(handler));
+        let workers = ShardedPool::new(options.workers, options.max_in_flight, move |(delivery, permit): (Delivery, OwnedSemaphorePermit)| {
+            let (options, handler) = (consumer_options.clone(), handler.clone());
+            async move {
+                let _ = consumer::process::<T, _, _>(delivery, &options, on_decode_error, handler.as_ref()).await;
+                drop(permit);
+            }
+        });
+        let workers = Arc::new(workers);
+        let in_flight = Arc::new(Semaphore::new(options.max_in_flight.max(1)));
+        let pollers = (0..options.pollers.max(1)).map(|_| Poller{
+            api: api.clone(),
+            queue_url: queue_url.clone(),
+            receive: receive.clone(),
+            receive_batch: receive_batch.clone(),
+            heartbeat: options.heartbeat,
+            idle_backoff: options.idle_backoff,
+            in_flight: in_flight.clone(),
+            workers: workers.clone(),
+            stats: stats.clone(),
+            shutdown: shutdown.clone(),
+        }.run()).collect::<Vec<_>>();
+        let task = tokio::spawn(async move {
+            let result = futures::future::join_all(pollers).await.into_iter().collect::<Result<(), EventfulError>>();
+            // every poller has stopped, so the pool is the last holder of the workers
+            if let Ok(workers) = Arc::try_unwrap(workers) {
+                workers.join().await;
+            }
+            result
+        });
+        QueueWorkerPool{shutdown, task, stats}
+    }
+
+    /// Handled, failed, and dead-lettered counts across every poller and worker
+    pub fn stats(&self) -> Arc<consumer::ConsumerStats> {
+        self.stats.clone()
+    }
+
+    /// Stop receiving, then wait for every message already received to be handled and settled.
+    /// Returns the error which stopped the pool early, if any (i.e. the queue was deleted)
+    pub async fn shutdown(self) -> Result<(), EventfulError> {
+        self.shutdown.cancel();
+        self.join().await
+    }
+
+    /// Wait for the pool to stop, which it only does on its own after an error
+    pub async fn join(self) -> Result<(), EventfulError> {
+        self.task.await.map_err(|err| EventfulError::Config(format!("the worker pool task panicked: {}", err)))?
+    }
+}
+
+
+/// Receives one batch for a QueueWorkerPool's pollers, with how many messages options.filter kept back
+type ReceiveBatch = Arc<dyn Fn() -> BoxFuture<'static, Result<(Vec<Message>, u64), EventfulError>> + Send + Sync>;
+
+/// One of a QueueWorkerPool's receive loops
+struct Poller {
+    api: Arc<dyn SqsApi>,
+    queue_url: String,
+    receive: ReceiveOptions,
+    receive_batch: ReceiveBatch,
+    heartbeat: Option<(Duration, Duration)>,
+    idle_backoff: Backoff,
+    in_flight: Arc<Semaphore>,
+    workers: Arc<ShardedPool<(Delivery, OwnedSemaphorePermit)>>,
+    stats: Arc<consumer::ConsumerStats>,
+    shutdown: CancellationToken,
+}
+
+impl Poller {
+    async fn run(self) -> Result<(), EventfulError> {
+        let mut idle = 0;
+        loop {
+            // wait for room for a whole batch, so received messages never sit waiting for a permit
+            let mut permits = Vec::with_capacity(self.receive.max_messages as usize);
+            while permits.len() < self.receive.max_messages as usize {
+                tokio::select! {
+                    _ = self.shutdown.cancelled() => return Ok(()),
+                    permit = self.in_flight.clone().acquire_owned() => permits.push(permit.expect("the semaphore is never closed")),
+                }
+            }
+            let received = tokio::select! {
+                // messages from an abandoned receive reappear once their visibility timeout lapses
+                _ = self.shutdown.cancelled() => return Ok(()),
+                received = (self.receive_batch)() => received,
+            };
+            let messages = match received {
+                Ok((messages, filtered)) => {
+                    self.stats.add_filtered(filtered);
+                    messages
+                },
+                Err(err @ EventfulError::QueueDoesNotExist(_)) => {
+                    self.shutdown.cancel();
+                    return Err(err)
+                },
+                Err(_) => Vec::new(),
+            };
+            idle = match messages.is_empty() {
+                true => idle + 1,
+                false => 0,
+            };
+            for message in messages {
+                let delivery = match delivery_for(&self.api, &self.queue_url, message, self.receive.unwrap_sns, self.heartbeat) {
+                    Some(delivery) => delivery,
+                    None => continue,
+                };
+                let permit = permits.pop().expect("a permit was taken for every message");
+                let group_id = delivery.meta.group_id.clone();
+                if let Err(err) = self.workers.send(group_id.as_deref(), (delivery, permit)).await {
+                    self.shutdown.cancel();
+                    return Err(err)
+                }
+            }
+            drop(permits);
+            if idle > 0 {
+                let delay = self.idle_backoff.delay(idle, &mut rand::thread_rng());
+                tokio::select! {
+                    _ = self.shutdown.cancelled() => return Ok(()),
+                    _ = tokio::time::sleep(delay) => {},
+                }
+            }
+        }
+    }
+}
+
+
+/// A Delivery for a received message, whose ack deletes it. None if it has no receipt handle.
+/// With a heartbeat (interval, extension), the message's visibility is extended until the delivery is settled
+fn delivery_for(api: &Arc<dyn SqsApi>, queue_url: &str, message: Message, unwrap_sns: bool, heartbeat: Option<(Duration, Duration)>) -> Option<Delivery> {
+    let receipt_handle = message.receipt_handle.clone()?;
+    let attempt = receive_count(&message);
+    let mut meta = Metadata{
+        group_id: system_attribute(&message, MessageSystemAttributeName::MessageGroupId).cloned(),
+        ..Default::default()
+    };
+    let mut body = message.body.unwrap_or_default();
+    if unwrap_sns {
+        if let Some(notification) = sns::unwrap_notification(&body) {
+            body = notification.message;
+            meta.headers = notification.attributes;
+        }
+    }
+    let source = Destination::SqsQueue(queue_url.to_string());
+    let heartbeat = heartbeat.map(|(interval, extension)| {
+        let handle = ReceivedHandle{api: api.clone(), queue_url: queue_url.to_string(), receipt_handle: receipt_handle.clone()};
+        spawn_heartbeat(handle, message.message_id.clone(), tokio::time::Instant::now(), interval, extension)
+    });
+    let ack = AckSQS{api: api.clone(), queue_url: queue_url.to_string(), receipt_handle, heartbeat};
+    // a dropped delivery reappears once the visibility timeout lapses
+    Some(Delivery::new(source, Bytes::from(body), meta, message.message_id, attempt, Box::new(ack)).on_drop(OnDrop::Nothing))
+}
+
+
+/// ack() deletes the message, nack(delay) changes its visibility timeout to the delay
+struct AckSQS {
+    api: Arc<dyn SqsApi>,
+    queue_url: String,
+    receipt_handle: String,
+    /// Stopped before the message is settled
+    heartbeat: Option<Heartbeat>,
+}
+
+#[async_trait]
+impl Ack for AckSQS {
+    async fn ack(mut self: Box<Self>) -> Result<(), EventfulError> {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.stop().await;
+        }
+        self.api.delete_message(&self.queue_url, &self.receipt_handle).await
+    }
+
+    async fn nack(mut self: Box<Self>, delay: Duration) -> Result<(), EventfulError> {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.stop().await;
+        }
+        // SQS caps the visibility timeout at 12 hours
+        let seconds = delay.as_secs().min(43_200) as i32;
+        self.api.change_visibility(&self.queue_url, &self.receipt_handle, seconds).await
+    }
+}
+
+
+/// The ApproximateReceiveCount attribute, or 1 when it was not requested
+fn receive_count(message: &Message) -> u32 {
+    system_attribute(message, MessageSystemAttributeName::ApproximateReceiveCount)
+        .and_then(|count| count.parse::<u32>().ok())
+        .unwrap_or(1)
+}
+
+fn system_attribute(message: &Message, name: MessageSystemAttributeName) -> Option<&String> {
+    message.attributes.as_ref().and_then(|attrs| attrs.get(&name))
+}
+
+/// A timestamp attribute (i.e. SentTimestamp), which SQS gives in epoch milliseconds
+fn timestamp_attribute(message: &Message, name: MessageSystemAttributeName) -> Option<DateTime<Utc>> {
+    system_attribute(message, name)
+        .and_then(|millis| millis.parse::<i64>().ok())
+        .and_then(|millis| Utc.timestamp_millis_opt(millis).single())
+}
+
+
+/// Where a received message is, so it can be deleted or have its visibility changed
+struct ReceivedHandle {
+    api: Arc<dyn SqsApi>,
+    queue_url: String,
+    receipt_handle: String,
+}
+
+impl ReceivedHandle {
+    async fn ack(&self) -> Result<(), EventfulError> {
+        self.api.delete_message(&self.queue_url, &self.receipt_handle).await
+    }
+
+    async fn set_visibility(&self, visibility: Duration) -> Result<(), EventfulError> {
+        // SQS caps the visibility timeout at 12 hours
+        let seconds = visibility.as_secs().min(43_200) as i32;
+        self.api.change_visibility(&self.queue_url, &self.receipt_handle, seconds).await
+    }
+}
+
+
+/// Extend a message's visibility every interval until the Heartbeat is stopped or dropped, an extension fails, or MAX_VISIBILITY is reached
+fn spawn_heartbeat(handle: ReceivedHandle, message_id: Option<String>, received_at: tokio::time::Instant, interval: Duration, extension: Duration) -> Heartbeat {
+    let cancel = CancellationToken::new();
+    let stopped = cancel.clone();
+    let task = tokio::spawn(async move {
+        let mut extensions = 0;
+        loop {
+            tokio::select! {
+                _ = stopped.cancelled() => return,
+                _ = tokio::time::sleep(interval) => {},
+            }
+            let remaining = MAX_VISIBILITY.saturating_sub(received_at.elapsed());
+            if remaining < Duration::from_secs(1) {
+                return
+            }
+            if let Err(err) = handle.set_visibility(extension.min(remaining)).await {
+                observer::global().visibility_extension_failed(&VisibilityExtensionFailed{
+                    source: Destination::SqsQueue(handle.queue_url.clone()),
+                    message_id,
+                    extensions,
+                    error: err.to_string(),
+                });
+                return
+            }
+            extensions += 1;
+        }
+    });
+    Heartbeat{cancel, task: Some(task)}
+}
+
+
+/// A task extending a message's visibility, cancelled when dropped
+struct Heartbeat {
+    cancel: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// Cancel the task and wait for it, so no extension is in flight afterwards
+    async fn stop(mut self) {
+        self.cancel.cancel();
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+
+/// An event returned by ClientSQS::receive. It stays on the queue until ack() deletes it,
+/// and reappears once its visibility timeout lapses if it is neither acked nor nacked
+pub struct ReceivedEvent<T> {
+    pub payload: T,
+    pub message_id: Option<String>,
+    /// How many times the message has been received, including this time (ApproximateReceiveCount)
+    pub receive_count: u32,
+    /// When SQS accepted the message (SentTimestamp)
+    pub sent_at: Option<DateTime<Utc>>,
+    /// When the message was first received, which is now unless it has been received before (ApproximateFirstReceiveTimestamp)
+    pub first_received_at: Option<DateTime<Utc>>,
+    /// FIFO queues only (MessageGroupId)
+    pub group_id: Option<String>,
+    /// FIFO queues only (SequenceNumber)
+    pub sequence_number: Option<String>,
+    attributes: Attributes,
+    sns: Option<sns::Notification>,
+    handle: ReceivedHandle,
+    received_at: tokio::time::Instant,
+    heartbeat: Option<Heartbeat>,
+}
+
+impl<T> ReceivedEvent<T> {
+    pub fn receipt_handle(&self) -> &str {
+        &self.handle.receipt_handle
+    }
+
+    /// The message attributes it was published with
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    /// The SNS notification the event was unwrapped from (with its topic ARN, SNS message id, subject and message attributes),
+    /// when it was received with ReceiveOptions::unwrap_sns and came through a topic without raw message delivery
+    pub fn sns(&self) -> Option<&sns::Notification> {
+        self.sns.as_ref()
+    }
+
+    /// Delete the message, once it has been handled
+    pub async fn ack(mut self) -> Result<(), EventfulError> {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.stop().await;
+        }
+        self.handle.ack().await
+    }
+
+    /// Give the message back, to reappear after `visibility` (i.e. Duration::ZERO to retry straight away)
+    pub async fn nack(mut self, visibility: Duration) -> Result<(), EventfulError> {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.stop().await;
+        }
+        self.handle.set_visibility(visibility).await
+    }
+
+    /// Every `interval`, make the message invisible for another `extension`, so a slow handler keeps it to itself.
+    /// The heartbeat stops when the event is acked, nacked or dropped, when an extension fails (which the global observer's
+    /// visibility_extension_failed callback is told about), or once MAX_VISIBILITY since the message was received is reached.
+    /// interval should be comfortably shorter than both extension and the queue's visibility timeout.
+    /// Starting a heartbeat replaces any earlier one. Must be called from within a tokio runtime
+    pub fn start_heartbeat(&mut self, interval: Duration, extension: Duration) {
+        let handle = ReceivedHandle{
+            api: self.handle.api.clone(),
+            queue_url: self.handle.queue_url.clone(),
+            receipt_handle: self.handle.receipt_handle.clone(),
+        };
+        self.heartbeat = Some(spawn_heartbeat(handle, self.message_id.clone(), self.received_at, interval, extension));
+    }
+
+    /// Keep the message hidden for `duration` from now, while a slow handler is still working on it
+    pub async fn extend(&self, duration: Duration) -> Result<(), EventfulError> {
+        self.handle.set_visibility(duration).await
+    }
+}
+
+
+/// What ClientSQS::poll_events does with a message which doesn't decode. It is never deleted before this is decided
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum OnUndecodable {
+    /// Leave it on the queue: it is received again after the visibility timeout, and reaches the queue's own DLQ if it has a redrive policy
+    #[default]
+    Leave,
+    /// Send a DeadLetterRecord holding the raw body and the error to this queue, then delete it
+    DeadLetter(QueueUrl),
+    /// Hand it back as an UndecodableMessage, to ack or nack
+    Return,
+}
+
+
+/// The state of ClientSQS::stream between events
+struct ReceiveStream<T, F> {
+    /// Receives the next batch
+    receive: F,
+    idle_backoff: Backoff,
+    shutdown: CancellationToken,
+    buffer: VecDeque<ReceivedEvent<T>>,
+    /// Empty or failed receives in a row
+    idle: u32,
+    /// How long to wait before the next receive
+    pause: Option<Duration>,
+    done: bool,
+}
+
+/// ClientSQS::stream's loop, receiving batches with receive
+fn receive_stream<T, F, Fut>(receive: F, idle_backoff: Backoff, shutdown: CancellationToken) -> impl Stream<Item = Result<ReceivedEvent<T>, EventfulError>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(Vec<ReceivedEvent<T>>, Vec<UndecodableMessage>), EventfulError>>,
+{
+    let state = ReceiveStream{
+        receive,
+        idle_backoff,
+        shutdown,
+        buffer: VecDeque::new(),
+        idle: 0,
+        pause: None,
+        done: false,
+    };
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(event) = state.buffer.pop_front() {
+                return Some((Ok(event), state))
+            }
+            if state.done || state.shutdown.is_cancelled() {
+                return None
+            }
+            if let Some(pause) = state.pause.take() {
+                tokio::select! {
+                    _ = state.shutdown.cancelled() => return None,
+                    _ = tokio::time::sleep(pause) => {},
+                }
+            }
+            let receiving = (state.receive)();
+            let received = tokio::select! {
+                _ = state.shutdown.cancelled() => return None,
+                received = receiving => received,
+            };
+            match received {
+                Ok((events, undecodable)) => {
+                    state.idle = match events.is_empty() && undecodable.is_empty() {
+                        true => state.idle + 1,
+                        false => 0,
+                    };
+                    if state.idle > 0 {
+                        state.pause = Some(state.idle_backoff.delay(state.idle, &mut rand::thread_rng()));
+                    }
+                    state.buffer.extend(events);
+                },
+                Err(err) => {
+                    state.done = matches!(err, EventfulError::QueueDoesNotExist(_));
+                    state.idle += 1;
+                    state.pause = Some(state.idle_backoff.delay(state.idle, &mut rand::thread_rng()));
+                    return Some((Err(err), state))
+                },
+            }
+        }
+    })
+}
+
+
+/// A message ClientSQS::receive (or poll_events) could not decode. It can be acked to drop it or nacked to leave it for another consumer
+pub struct UndecodableMessage {
+    pub body: String,
+    pub message_id: Option<String>,
+    pub receive_count: u32,
+    /// Why decoding failed
+    pub error: String,
+    attributes: Attributes,
+    handle: ReceivedHandle,
+}
+
+impl UndecodableMessage {
+    pub fn receipt_handle(&self) -> &str {
+        &self.handle.receipt_handle
+    }
+
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    pub async fn ack(self) -> Result<(), EventfulError> {
+        self.handle.ack().await
+    }
+
+    pub async fn nack(self, visibility: Duration) -> Result<(), EventfulError> {
+        self.handle.set_visibility(visibility).await
+    }
+
+    pub async fn extend(&self, duration: Duration) -> Result<(), EventfulError> {
+        self.handle.set_visibility(duration).await
+    }
+}
+
+
+/// The body to decode, which is the Message inside an SNS notification envelope when unwrap_sns is set and body is one
+fn unwrap_body(body: &str, unwrap_sns: bool) -> (std::borrow::Cow<'_, str>, Option<sns::Notification>) {
+    match unwrap_sns.then(|| sns::unwrap_notification(body)).flatten() {
+        Some(notification) => (notification.message.clone().into(), Some(notification)),
+        None => (body.into(), None),
+    }
+}
+
+
+/// The error for a body which is still an S3 pointer after receiving: its object was missing,
+/// or the receiving client has no S3Offload to fetch it with
+fn offloaded_error(body: &str) -> Option<EventfulError> {
+    let pointer = S3Pointer::parse(body)?;
+    Some(EventfulError::OffloadMissing(format!("s3://{}/{} ({} bytes)", pointer.bucket, pointer.key, pointer.size)))
+}
+
+
+/// Where a body stored in S3 is. It is published as {"eventful_s3_pointer": {"bucket": .., "key": .., "size": ..}}
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct S3Pointer {
+    pub bucket: String,
+    pub key: String,
+    /// The size of the stored body in bytes
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PointerMessage {
+    eventful_s3_pointer: S3Pointer,
+}
+
+impl S3Pointer {
+    /// The pointer in a message body, if that is all the body is
+    pub fn parse(body: &str) -> Option<Self> {
+        if !body.trim_start().starts_with("{\"eventful_s3_pointer\"") {
+            return None
+        }
+        serde_json::from_str::<PointerMessage>(body).ok().map(|message| message.eventful_s3_pointer)
+    }
+
+    pub fn to_body(&self) -> Result<String, EventfulError> {
+        Ok(serde_json::to_string(&PointerMessage{eventful_s3_pointer: self.clone()})?)
+    }
+}
+
+
+#[cfg(feature = "s3")]
+pub use self::offload::S3Offload;
+
+#[cfg(feature = "s3")]
+mod offload {
+    use std::{collections::HashMap, sync::Mutex, time::Instant};
+    use aws_sdk_s3::{types::{ByteStream, SdkError}, Client};
+    use chrono::Utc;
+    use rand::Rng;
+    use crate::err::EventfulError;
+    use super::{Message, QueueUrl, S3Pointer, MAX_MESSAGE_BYTES, MAX_VISIBILITY};
+
+    /// The extended client pattern: bodies over the threshold are stored in S3 as {prefix}{queue name}/{timestamp}-{random},
+    /// and a small S3Pointer is sent in their place. A received pointer is replaced by the stored body before decoding.
+    /// A failed upload fails the publish. A pointer whose object is missing is left in place, so it fails to decode with
+    /// EventfulError::OffloadMissing and goes wherever undecodable messages go; any other S3 failure fails the receive,
+    /// leaving the messages to reappear. Objects are deleted once their message is acked through the same client, unless
+    /// delete_after_ack(false): give the bucket a lifecycle rule for the objects of messages which are never acked
+    pub struct S3Offload {
+        client: Client,
+        bucket: String,
+        prefix: String,
+        threshold: usize,
+        delete_after_ack: bool,
+        /// The pointers of received messages by receipt handle, until they are acked or MAX_VISIBILITY passes
+        received: Mutex<HashMap<String, (S3Pointer, Instant)>>,
+    }
+
+    impl S3Offload {
+        pub fn new(client: Client, bucket: &str, prefix: &str) -> Self {
+            S3Offload{
+                client,
+                bucket: bucket.to_string(),
+                prefix: prefix.to_string(),
+                threshold: MAX_MESSAGE_BYTES,
+                delete_after_ack: true,
+                received: Mutex::new(HashMap::new()),
+            }
+        }
+
+        /// Store bodies longer than this many bytes (default MAX_MESSAGE_BYTES, so only ones SQS would reject).
+        /// Leave room under MAX_MESSAGE_BYTES for message attributes, which count towards SQS's limit
+        pub fn threshold(mut self, threshold: usize) -> Self {
+            self.threshold = threshold.min(MAX_MESSAGE_BYTES);
+            self
+        }
+
+        /// Delete an object once its message is acked (default true)
+        pub fn delete_after_ack(mut self, delete_after_ack: bool) -> Self {
+            self.delete_after_ack = delete_after_ack;
+            self
+        }
+
+        /// The body to send: body itself, or a pointer to where it was stored when it is over the threshold
+        pub(crate) async fn store(&self, queue_url: &str, body: String) -> Result<String, EventfulError> {
+            if body.len() <= self.threshold {
+                return Ok(body)
+            }
+            let queue_name = QueueUrl::parse(queue_url).map(|url| url.queue_name().to_string()).unwrap_or_else(|_| "queue".to_string());
+            let suffix: u64 = rand::thread_rng().gen();
+            let key = format!("{}{}/{}-{:016x}", self.prefix, queue_name, Utc::now().format("%Y%m%dT%H%M%S%.fZ"), suffix);
+            let pointer = S3Pointer{bucket: self.bucket.clone(), key, size: body.len()};
+            self.client.put_object()
+                .bucket(&pointer.bucket)
+                .key(&pointer.key)
+                .content_type("application/json")
+                .body(ByteStream::from(body.into_bytes()))
+                .send().await
+                .map_err(|err| EventfulError::S3(format!("{:?}", err)))?;
+            pointer.to_body()
+        }
+
+        /// Replace each pointer body with the body it points to
+        pub(crate) async fn inline(&self, messages: &mut [Message]) -> Result<(), EventfulError> {
+            for message in messages.iter_mut() {
+                let pointer = match message.body.as_deref().and_then(S3Pointer::parse) {
+                    Some(pointer) => pointer,
+                    None => continue,
+                };
+                let output = match self.client.get_object().bucket(&pointer.bucket).key(&pointer.key).send().await {
+                    Ok(output) => output,
+                    Err(SdkError::ServiceError(context)) if context.err().is_no_such_key() => continue,
+                    Err(err) => return Err(EventfulError::S3(format!("{:?}", err))),
+                };
+                let bytes = output.body.collect().await
+                    .map_err(|err| EventfulError::S3(format!("{:?}", err)))?
+                    .into_bytes();
+                let body = String::from_utf8(bytes.to_vec())
+                    .map_err(|_| EventfulError::S3(format!("s3://{}/{} is not valid UTF-8", pointer.bucket, pointer.key)))?;
+                message.body = Some(body);
+                if let (true, Some(handle)) = (self.delete_after_ack, &message.receipt_handle) {
+                    let mut received = self.received.lock().unwrap();
+                    received.retain(|_, (_, at)| at.elapsed() < MAX_VISIBILITY);
+                    received.insert(handle.clone(), (pointer, Instant::now()));
+                }
+            }
+            Ok(())
+        }
+
+        /// Delete the object of a message which has been acked. A failure only leaves the object behind, so it is ignored
+        pub(crate) async fn acked(&self, receipt_handle: &str) {
+            let pointer = self.received.lock().unwrap().remove(receipt_handle);
+            if let Some((pointer, _)) = pointer {
+                let _ = self.client.delete_object().bucket(&pointer.bucket).key(&pointer.key).send().await;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use tokio::runtime::Runtime;
+    use crate::err::SqsFailure;
+    use crate::httpstub::HttpStub;
+
+    fn invalid(url: &str) -> bool {
+        matches!(QueueUrl::parse(url), Err(EventfulError::InvalidQueueUrl(_)))
+    }
+
+    #[test]
+    fn parses_aws_queue_urls() {
+        let url = QueueUrl::parse("https://sqs.us-east-1.amazonaws.com/123456789012/orders.fifo").unwrap();
+        assert!(url.is_fifo());
+        assert_eq!(url.queue_name(), "orders.fifo");
+        assert_eq!(url.account_id(), "123456789012");
+        assert_eq!(url.region(), Some("us-east-1"));
+        assert_eq!(url.as_str(), "https://sqs.us-east-1.amazonaws.com/123456789012/orders.fifo");
+
+        let legacy = QueueUrl::parse("https://us-west-2.queue.amazonaws.com/123456789012/orders").unwrap();
+        assert!(!legacy.is_fifo());
+        assert_eq!(legacy.queue_name(), "orders");
+        assert_eq!(legacy.region(), Some("us-west-2"));
+    }
+
+    #[test]
+    fn parses_localstack_queue_urls() {
+        let url = QueueUrl::parse("http://localhost:4566/000000000000/orders/").unwrap();
+        assert_eq!(url.as_str(), "http://localhost:4566/000000000000/orders");
+        assert_eq!(url.account_id(), "000000000000");
+        assert_eq!(url.queue_name(), "orders");
+        assert_eq!(url.region(), None);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_queue_url() {
+        // a queue name where a URL was expected
+        assert!(invalid("orders"));
+        assert!(invalid("ftp://sqs.us-east-1.amazonaws.com/123456789012/orders"));
+        assert!(invalid("https:///123456789012/orders"));
+        assert!(invalid("https://sqs.us-east-1.amazonaws.com/12345678901/orders"));
+        assert!(invalid("https://sqs.us-east-1.amazonaws.com/12345678901x/orders"));
+        assert!(invalid("https://sqs.us-east-1.amazonaws.com/123456789012/orders.json"));
+        assert!(invalid("https://sqs.us-east-1.amazonaws.com/123456789012/"));
+        assert!(invalid(&format!("https://sqs.us-east-1.amazonaws.com/123456789012/{}", "a".repeat(81))));
+        assert!(invalid("https://sqs.us-east-1.amazonaws.com/123456789012/orders/extra"));
+        assert!(invalid("http://localhost:4566/queue//000000000000/orders"));
+    }
+
+    #[test]
+    fn queue_urls_round_trip_through_strings_and_serde() {
+        let raw = "https://sqs.eu-west-1.amazonaws.com/123456789012/clicks";
+        let url: QueueUrl = raw.parse().unwrap();
+        assert_eq!(url.to_string(), raw);
+        assert_eq!(&*url, raw);
+        assert_eq!(String::from(url.clone()), raw);
+        assert_eq!(serde_json::to_string(&url).unwrap(), format!("\"{}\"", raw));
+        assert_eq!(serde_json::from_str::<QueueUrl>(&format!("\"{}\"", raw)).unwrap(), url);
+        assert!(serde_json::from_str::<QueueUrl>("\"clicks\"").is_err());
+    }
+
+    #[test]
+    fn queue_refs_are_urls_or_names() {
+        let raw = "https://sqs.eu-west-1.amazonaws.com/123456789012/clicks";
+        assert_eq!(QueueRef::parse(raw).unwrap(), QueueRef::Url(QueueUrl::parse(raw).unwrap()));
+        assert_eq!(QueueRef::parse("clicks.fifo").unwrap(), QueueRef::Name("clicks.fifo".to_string()));
+        assert!(QueueRef::parse("").is_err());
+        assert!(QueueRef::parse("clicks!").is_err());
+        // a URL must be a valid one, rather than falling back to a name
+        assert!(QueueRef::parse("https://sqs.eu-west-1.amazonaws.com/clicks").is_err());
+    }
+
+    #[test]
+    fn check_fifo_covers_every_combination() {
+        let fifo = "https://sqs.us-east-1.amazonaws.com/123456789012/orders.fifo";
+        let standard = "https://sqs.us-east-1.amazonaws.com/123456789012/orders";
+        let cases = [
+            (fifo, None, None, false),
+            (fifo, None, Some("d"), false),
+            (fifo, Some("g"), None, true),
+            (fifo, Some("g"), Some("d"), true),
+            (standard, None, None, true),
+            (standard, None, Some("d"), false),
+            (standard, Some("g"), None, false),
+            (standard, Some("g"), Some("d"), false),
+        ];
+        for (queue_url, group_id, dedup_id, ok) in cases {
+            match check_fifo(queue_url, group_id, dedup_id) {
+                Ok(()) => assert!(ok, "{} accepted group {:?} and dedup {:?}", queue_url, group_id, dedup_id),
+                Err(EventfulError::FifoMismatch(_)) => assert!(!ok, "{} rejected group {:?} and dedup {:?}", queue_url, group_id, dedup_id),
+                Err(err) => panic!("expected FifoMismatch, got {:?}", err),
+            }
+        }
+    }
+
+    const DLQ_ARN: &str = "arn:aws:sqs:us-east-1:123456789012:orders-dlq";
+
+    #[test]
+    fn redrive_policy_parses_the_count_as_a_string_or_a_number() {
+        let expected = RedrivePolicy{dead_letter_target_arn: DLQ_ARN.to_string(), max_receive_count: 5};
+        // what GetQueueAttributes returns
+        let as_string = format!(r#"{{"deadLetterTargetArn":"{}","maxReceiveCount":"5"}}"#, DLQ_ARN);
+        assert_eq!(RedrivePolicy::parse(&as_string), Some(expected.clone()));
+        // what people write by hand, i.e. in CloudFormation
+        let as_number = format!(r#"{{"deadLetterTargetArn":"{}","maxReceiveCount":5}}"#, DLQ_ARN);
+        assert_eq!(RedrivePolicy::parse(&as_number), Some(expected.clone()));
+        assert_eq!(RedrivePolicy::parse(&expected.to_json()), Some(expected));
+    }
+
+    #[test]
+    fn redrive_policy_rejects_malformed_policies() {
+        assert_eq!(RedrivePolicy::parse(""), None);
+        assert_eq!(RedrivePolicy::parse("not json"), None);
+        assert_eq!(RedrivePolicy::parse(r#"{"maxReceiveCount":"5"}"#), None);
+        assert_eq!(RedrivePolicy::parse(&format!(r#"{{"deadLetterTargetArn":"{}"}}"#, DLQ_ARN)), None);
+        assert_eq!(RedrivePolicy::parse(&format!(r#"{{"deadLetterTargetArn":"{}","maxReceiveCount":"five"}}"#, DLQ_ARN)), None);
+        assert_eq!(RedrivePolicy::parse(&format!(r#"{{"deadLetterTargetArn":"{}","maxReceiveCount":-1}}"#, DLQ_ARN)), None);
+        assert_eq!(RedrivePolicy::parse(&format!(r#"{{"deadLetterTargetArn":"{}","maxReceiveCount":5000000000}}"#, DLQ_ARN)), None);
+        assert_eq!(RedrivePolicy::parse(r#"{"deadLetterTargetArn":7,"maxReceiveCount":"5"}"#), None);
+    }
+
+    const QUEUE_ARN: &str = "arn:aws:sqs:us-east-1:123456789012:orders";
+    const TOPIC_ARN: &str = "arn:aws:sns:us-east-1:123456789012:orders";
+
+    /// The policy AccessPolicy::new(QUEUE_ARN).allow_sns_topic(TOPIC_ARN) should produce, formatted as SQS returns it
+    const POLICY: &str = r#"{
+      "Version": "2012-10-17",
+      "Statement": [{
+        "Sid": "AllowSnsTopic0",
+        "Effect": "Allow",
+        "Principal": {"Service": "sns.amazonaws.com"},
+        "Action": "sqs:SendMessage",
+        "Resource": "arn:aws:sqs:us-east-1:123456789012:orders",
+        "Condition": {"ArnEquals": {"aws:SourceArn": "arn:aws:sns:us-east-1:123456789012:orders"}}
+      }]
+    }"#;
+
+    fn orders_settings() -> QueueSettings {
+        QueueSettings::default()
+            .visibility_timeout(Duration::from_secs(30))
+            .message_retention(Duration::from_secs(4 * 86_400))
+            .receive_wait_time(Duration::from_secs(20))
+            .redrive(DLQ_ARN, 5)
+            .sse(SseConfig::kms("alias/orders").data_key_reuse(Duration::from_secs(300)))
+            .access_policy(AccessPolicy::new(QUEUE_ARN).allow_sns_topic(TOPIC_ARN))
+    }
+
+    /// GetQueueAttributes for a queue created with orders_settings()
+    fn orders_attributes() -> HashMap<QueueAttributeName, String> {
+        [
+            (QueueAttributeName::QueueArn, QUEUE_ARN),
+            (QueueAttributeName::ApproximateNumberOfMessages, "3"),
+            (QueueAttributeName::VisibilityTimeout, "30"),
+            (QueueAttributeName::MessageRetentionPeriod, "345600"),
+            (QueueAttributeName::ReceiveMessageWaitTimeSeconds, "20"),
+            (QueueAttributeName::RedrivePolicy, r#"{"deadLetterTargetArn":"arn:aws:sqs:us-east-1:123456789012:orders-dlq","maxReceiveCount":5}"#),
+            (QueueAttributeName::KmsMasterKeyId, "alias/orders"),
+            (QueueAttributeName::KmsDataKeyReusePeriodSeconds, "300"),
+            (QueueAttributeName::SqsManagedSseEnabled, "false"),
+            (QueueAttributeName::Policy, POLICY),
+        ].into_iter().map(|(name, value)| (name, value.to_string())).collect()
+    }
+
+    #[test]
+    fn access_policy_json_matches_the_fixture() {
+        let policy = AccessPolicy::new(QUEUE_ARN).allow_sns_topic(TOPIC_ARN);
+        let fixture: serde_json::Value = serde_json::from_str(POLICY).unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&policy.to_json()).unwrap(), fixture);
+        assert!(policy.matches(POLICY));
+        assert!(policy.matches(&fixture.to_string()));
+        // a topic added twice is one statement
+        assert_eq!(policy.clone().allow_sns_topic(TOPIC_ARN), policy);
+
+        let other_topic = "arn:aws:sns:us-east-1:123456789012:refunds";
+        let both = policy.clone().allow_sns_topic(other_topic);
+        let statements: serde_json::Value = serde_json::from_str(&both.to_json()).unwrap();
+        assert_eq!(statements["Statement"][1]["Sid"], "AllowSnsTopic1");
+        assert_eq!(statements["Statement"][1]["Condition"]["ArnEquals"]["aws:SourceArn"], other_topic);
+        assert!(!both.matches(POLICY));
+        assert!(!policy.matches(""));
+        assert!(!policy.matches("{}"));
+
+        let empty: serde_json::Value = serde_json::from_str(&AccessPolicy::new(QUEUE_ARN).to_json()).unwrap();
+        assert_eq!(empty, serde_json::json!({"Version": "2012-10-17", "Statement": []}));
+    }
+
+    #[test]
+    fn queue_settings_become_attributes() {
+        let attrs = orders_settings().to_attributes(true);
+        assert_eq!(attrs.len(), 7);
+        assert_eq!(attrs[&QueueAttributeName::VisibilityTimeout], "30");
+        assert_eq!(attrs[&QueueAttributeName::MessageRetentionPeriod], "345600");
+        assert_eq!(attrs[&QueueAttributeName::KmsDataKeyReusePeriodSeconds], "300");
+        // maxReceiveCount is sent as a string, as SQS documents it
+        assert_eq!(attrs[&QueueAttributeName::RedrivePolicy], format!(r#"{{"deadLetterTargetArn":"{}","maxReceiveCount":"5"}}"#, DLQ_ARN));
+
+        let fifo = QueueSettings::default().fifo().sse(SseConfig::Sqs);
+        let created = fifo.to_attributes(true);
+        assert_eq!(created[&QueueAttributeName::FifoQueue], "true");
+        assert_eq!(created[&QueueAttributeName::ContentBasedDeduplication], "false");
+        assert_eq!(created[&QueueAttributeName::SqsManagedSseEnabled], "true");
+        // FifoQueue can't be changed after creation, so it is only sent then
+        assert!(!fifo.to_attributes(false).contains_key(&QueueAttributeName::FifoQueue));
+        assert!(QueueSettings::default().to_attributes(true).is_empty());
+    }
+
+    #[test]
+    fn settings_diff_ignores_formatting_and_unset_settings() {
+        assert!(orders_settings().diff(&orders_attributes()).is_empty());
+        assert!(QueueSettings::default().diff(&orders_attributes()).is_empty());
+        assert!(QueueSettings::default().visibility_timeout(Duration::from_secs(30)).diff(&orders_attributes()).is_empty());
+    }
+
+    #[test]
+    fn settings_diff_lists_each_deviation() {
+        let mut attrs = orders_attributes();
+        attrs.insert(QueueAttributeName::FifoQueue, "true".to_string());
+        attrs.insert(QueueAttributeName::VisibilityTimeout, "60".to_string());
+        attrs.remove(&QueueAttributeName::KmsDataKeyReusePeriodSeconds);
+        attrs.insert(QueueAttributeName::RedrivePolicy, format!(r#"{{"deadLetterTargetArn":"{}","maxReceiveCount":"10"}}"#, DLQ_ARN));
+        attrs.insert(QueueAttributeName::Policy, POLICY.replace("sns:us-east-1:123456789012:orders", "sns:us-east-1:123456789012:refunds"));
+
+        let diff = orders_settings().diff(&attrs);
+        let names: Vec<&str> = diff.deviations.iter().map(|deviation| deviation.attribute.as_str()).collect();
+        assert_eq!(names, vec!["FifoQueue", "KmsDataKeyReusePeriodSeconds", "Policy", "RedrivePolicy", "VisibilityTimeout"]);
+        assert_eq!(diff.deviations[0].to_string(), "FifoQueue is true, expected false");
+        assert_eq!(diff.deviations[1].to_string(), "KmsDataKeyReusePeriodSeconds is unset, expected 300");
+        assert_eq!(diff.deviations[3].actual.as_deref(), attrs.get(&QueueAttributeName::RedrivePolicy).map(String::as_str));
+        assert_eq!(diff.deviations[4].to_string(), "VisibilityTimeout is 60, expected 30");
+        assert!(diff.to_string().starts_with("FifoQueue is true, expected false; KmsDataKeyReusePeriodSeconds is unset, expected 300; Policy is "));
+
+        // a policy the queue doesn't have is a deviation too
+        attrs.remove(&QueueAttributeName::Policy);
+        let diff = QueueSettings::default().access_policy(AccessPolicy::new(QUEUE_ARN)).diff(&attrs);
+        assert!(diff.deviations.iter().any(|deviation| deviation.attribute == QueueAttributeName::Policy && deviation.actual.is_none()));
+    }
+
+    #[test]
+    fn dedup_hash_is_the_hex_sha256_of_the_body() {
+        assert_eq!(dedup_hash(""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(dedup_hash("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let long = "x".repeat(MAX_MESSAGE_BYTES);
+        assert_eq!(dedup_hash(&long).len(), 64);
+        assert_eq!(dedup_hash(r#"{"id":1}"#), dedup_hash(r#"{"id":1}"#));
+        assert_ne!(dedup_hash(r#"{"id":1}"#), dedup_hash(r#"{"id":2}"#));
+    }
+
+    #[test]
+    fn dedup_strategies_pick_the_id() {
+        let fifo = "https://sqs.us-east-1.amazonaws.com/123456789012/orders.fifo";
+        let standard = "https://sqs.us-east-1.amazonaws.com/123456789012/orders";
+        let body = r#"{"id":1}"#;
+        for strategy in [DedupStrategy::ContentBased, DedupStrategy::Explicit, DedupStrategy::HashBody] {
+            // the event's own id always wins, and standard queues are left alone
+            assert_eq!(strategy.dedup_id(fifo, Some("order-1".to_string()), body).unwrap(), Some("order-1".to_string()));
+            assert_eq!(strategy.dedup_id(standard, None, body).unwrap(), None);
+        }
+        assert_eq!(DedupStrategy::ContentBased.dedup_id(fifo, None, body).unwrap(), None);
+        assert_eq!(DedupStrategy::HashBody.dedup_id(fifo, None, body).unwrap(), Some(dedup_hash(body)));
+        assert!(matches!(DedupStrategy::Explicit.dedup_id(fifo, None, body), Err(EventfulError::FifoMismatch(_))));
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct FromEnv;
+
+    impl Event for FromEnv {
+        fn queue_env_var() -> Option<&'static str> {
+            Some("EVENTFUL_TEST_FROM_ENV_QUEUE_URL")
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Nowhere;
+
+    impl Event for Nowhere {}
+
+    fn config_error(result: Result<&'static str, EventfulError>, containing: &str) -> bool {
+        matches!(result, Err(EventfulError::Config(message)) if message.contains(containing))
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn queue_urls_come_from_the_env_var_without_panicking() {
+        let var = "EVENTFUL_TEST_FROM_ENV_QUEUE_URL";
+        let url = "https://sqs.us-east-1.amazonaws.com/123456789012/orders";
+
+        env::remove_var(var);
+        assert_eq!(FromEnv::queue_url(), "");
+        assert!(config_error(FromEnv::try_queue_url(), "which is not set"));
+        assert!(FromEnv::queue().is_err());
+
+        env::set_var(var, "not a queue url");
+        assert_eq!(FromEnv::queue_url(), "");
+        assert!(config_error(FromEnv::try_queue_url(), "is not a queue URL"));
+        assert!(FromEnv::queue_ref().is_err());
+
+        // failures aren't cached, and once read the URL is kept
+        env::set_var(var, url);
+        assert_eq!(FromEnv::try_queue_url().unwrap(), url);
+        env::set_var(var, "https://sqs.us-east-1.amazonaws.com/123456789012/refunds");
+        assert_eq!(FromEnv::queue_url(), url);
+        assert_eq!(FromEnv::queue().unwrap().queue_name(), "orders");
+        env::remove_var(var);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn an_event_without_a_queue_does_not_panic() {
+        assert_eq!(Nowhere::queue_url(), "");
+        assert!(config_error(Nowhere::try_queue_url(), "neither queue_url() nor queue_env_var()"));
+        assert!(matches!(Nowhere::queue_ref(), Err(EventfulError::Config(_))));
+        assert!(matches!(Nowhere.queue_for(), Err(EventfulError::Config(_))));
+    }
+
+    #[test]
+    fn receive_filter_predicates() {
+        let attrs = Attributes::new()
+            .string("kind", "refund")
+            .number("priority", 5)
+            .binary("signature", vec![1, 2, 3]);
+
+        assert!(ReceiveFilter::new().matches(&attrs));
+        assert!(ReceiveFilter::new().matches(&Attributes::new()));
+        assert!(ReceiveFilter::new().equals("kind", "refund").matches(&attrs));
+        assert!(!ReceiveFilter::new().equals("kind", "order").matches(&attrs));
+        assert!(!ReceiveFilter::new().equals("tenant", "acme").matches(&attrs));
+
+        // numbers are compared by their text
+        assert!(ReceiveFilter::new().equals("priority", "5").matches(&attrs));
+        assert!(!ReceiveFilter::new().equals("priority", "5.0").matches(&attrs));
+        assert!(ReceiveFilter::new().one_of("priority", ["1", "5"]).matches(&attrs));
+
+        assert!(ReceiveFilter::new().one_of("kind", ["order", "refund"]).matches(&attrs));
+        assert!(!ReceiveFilter::new().one_of("kind", ["order", "invoice"]).matches(&attrs));
+        assert!(!ReceiveFilter::new().one_of("kind", Vec::<String>::new()).matches(&attrs));
+
+        // binary values never equal anything, but they do exist
+        assert!(!ReceiveFilter::new().equals("signature", "\u{1}\u{2}\u{3}").matches(&attrs));
+        assert!(!ReceiveFilter::new().one_of("signature", ["\u{1}\u{2}\u{3}"]).matches(&attrs));
+        assert!(ReceiveFilter::new().exists("signature").matches(&attrs));
+        assert!(!ReceiveFilter::new().exists("tenant").matches(&attrs));
+
+        // every predicate has to match
+        assert!(ReceiveFilter::new().equals("kind", "refund").exists("signature").matches(&attrs));
+        assert!(!ReceiveFilter::new().equals("kind", "refund").exists("tenant").matches(&attrs));
+    }
+
+    #[test]
+    fn receive_filter_mismatch_policies() {
+        assert_eq!(ReceiveFilter::new().on_mismatch, OnMismatch::Release(Duration::ZERO));
+        let filter = ReceiveFilter::new().equals("kind", "refund").on_mismatch(OnMismatch::Drop);
+        assert_eq!(filter.on_mismatch, OnMismatch::Drop);
+        assert_eq!(filter.attribute_names().collect::<Vec<_>>(), vec!["kind"]);
+    }
+
+    #[test]
+    fn receive_filter_reads_received_message_attributes() {
+        let value = |data_type: &str, text: &str| MessageAttributeValue::builder().data_type(data_type).string_value(text).build();
+        let refund = Message::builder()
+            .message_attributes("kind", value("String", "refund"))
+            .message_attributes("priority", value("Number.int", "5"))
+            .build();
+        let order = Message::builder().message_attributes("kind", value("String", "order")).build();
+        let untagged = Message::builder().body("{}").build();
+        let unknown_type = Message::builder().message_attributes("kind", value("Custom", "refund")).build();
+
+        let filter = ReceiveFilter::new().equals("kind", "refund");
+        let (matched, rejected): (Vec<Message>, Vec<Message>) = vec![refund.clone(), order, untagged, unknown_type].into_iter()
+            .partition(|message| filter.matches(&Attributes::from_message(message)));
+        assert_eq!(matched, vec![refund.clone()]);
+        assert_eq!(rejected.len(), 3);
+
+        // custom data types map to their base type
+        assert!(ReceiveFilter::new().equals("priority", "5").matches(&Attributes::from_message(&refund)));
+    }
+
+    fn entry_error(code: &str, sender_fault: bool) -> BatchEntryError {
+        BatchEntryError{code: code.to_string(), message: String::new(), sender_fault}
+    }
+
+    fn send(body: &str) -> BatchEntry {
+        BatchEntry::Send(OutgoingSQS{body: body.to_string(), ..OutgoingSQS::default()})
+    }
+
+    #[test]
+    fn batch_entries_are_found_by_their_index() {
+        let mut pending: HashMap<usize, &str> = [(3, "a"), (12, "b")].into_iter().collect();
+        assert_eq!(take_entry(&mut pending, Some("12")), Some((12, "b")));
+        // taken once only
+        assert_eq!(take_entry(&mut pending, Some("12")), None);
+        // ids which aren't this call's are ignored
+        assert_eq!(take_entry(&mut pending, Some("4")), None);
+        assert_eq!(take_entry(&mut pending, Some("three")), None);
+        assert_eq!(take_entry(&mut pending, None), None);
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[test]
+    fn batch_results_keep_only_retryable_failures() {
+        let orders = "https://sqs.us-east-1.amazonaws.com/123456789012/orders";
+        let refunds = "https://sqs.us-east-1.amazonaws.com/123456789012/refunds";
+        let mut result: BatchResult<()> = BatchResult::new();
+        result.fail(7, entry_error("InternalError", false), Some((refunds, send("7"))));
+        result.succeed(4, ());
+        result.fail(5, entry_error("MessageTooLarge", true), Some((orders, send("5"))));
+        result.fail(2, entry_error("ServiceUnavailable", false), Some((orders, send("2"))));
+        result.fail(9, entry_error("FifoMismatch", true), None);
+        result.fail(6, entry_error("InternalError", false), None);
+        result.succeed(0, ());
+        result.fail(3, entry_error("ServiceUnavailable", false), Some((orders, send("3"))));
+        result.sort();
+
+        assert_eq!(result.succeeded, vec![(0, ()), (4, ())]);
+        assert_eq!(result.failed.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![2, 3, 5, 6, 7, 9]);
+        assert!(!result.is_complete());
+        assert_eq!(result.error(5).map(|err| err.code.as_str()), Some("MessageTooLarge"));
+        assert_eq!(result.error(4), None);
+        // sender faults, and failures with nothing kept to send, aren't retried
+        assert_eq!(result.retryable().collect::<Vec<_>>(), vec![2, 3, 7]);
+
+        let by_queue = result.take_retryable();
+        assert_eq!(by_queue.len(), 2);
+        assert_eq!(by_queue[orders], vec![(2, send("2")), (3, send("3"))]);
+        assert_eq!(by_queue[refunds], vec![(7, send("7"))]);
+        assert_eq!(result.failed.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![5, 6, 9]);
+        assert_eq!(result.retryable().count(), 0);
+    }
+
+    #[test]
+    fn invalid_handles_are_reported() {
+        let mut result: BatchResult<()> = BatchResult::new();
+        result.fail(0, entry_error("ReceiptHandleIsInvalid", true), None);
+        result.fail(1, BatchEntryError{code: "InvalidParameterValue".to_string(), message: "Value for parameter ReceiptHandle is invalid".to_string(), sender_fault: true}, None);
+        result.fail(2, entry_error("InvalidParameterValue", true), None);
+        result.fail(3, entry_error("InternalError", false), None);
+        assert_eq!(result.invalid_handles().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn retry_failed_with_nothing_retryable_sends_nothing() {
+        // nothing listens here, so any call would fail
+        let client = ClientSQS::builder()
+            .region("us-east-1".to_string())
+            .endpoint_url("http://127.0.0.1:9".to_string())
+            .static_credentials("test", "test", None)
+            .build().await.unwrap();
+        let mut result: BatchResult<()> = BatchResult::new();
+        result.succeed(1, ());
+        result.fail(0, entry_error("ReceiptHandleIsInvalid", true), Some(("https://sqs.us-east-1.amazonaws.com/123456789012/orders", BatchEntry::Delete(ReceiptHandle::from("expired")))));
+        assert_eq!(result.retry_failed(&client).await.unwrap(), result);
+    }
+
+    #[cfg(feature = "testing")]
+    mod fake {
+        use super::*;
+        use crate::observer::{ConsumerIdle, EventfulObserver};
+        use crate::testing::FakeSqs;
+
+        const QUEUE: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/clicks";
+
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+        struct Click {
+            id: u32,
+        }
+
+        impl Event for Click {
+            fn queue_url() -> &'static str {
+                QUEUE
+            }
+        }
+
+        const FIFO_QUEUE: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/taps.fifo";
+
+        #[derive(Serialize, Deserialize)]
+        struct Tap {
+            id: u32,
+            group: Option<String>,
+        }
+
+        impl Event for Tap {
+            fn queue_url() -> &'static str {
+                FIFO_QUEUE
+            }
+            fn group_id(&self) -> Option<String> {
+                self.group.clone()
+            }
+        }
+
+        /// A FakeSqs holding one click and one body which isn't a click
+        async fn valid_and_garbage() -> FakeSqs {
+            let sqs = FakeSqs::new();
+            sqs.send_message(QUEUE, OutgoingSQS{body: r#"{"id":1}"#.to_string(), group_id: None, dedup_id: None}).await.unwrap();
+            sqs.send_message(QUEUE, OutgoingSQS{body: "garbage".to_string(), group_id: None, dedup_id: None}).await.unwrap();
+            sqs
+        }
+
+        type Batch = Result<(Vec<ReceivedEvent<Click>>, Vec<UndecodableMessage>), EventfulError>;
+
+        fn handle(sqs: &FakeSqs, receipt_handle: &str) -> ReceivedHandle {
+            ReceivedHandle{api: Arc::new(sqs.clone()), queue_url: QUEUE.to_string(), receipt_handle: receipt_handle.to_string()}
+        }
+
+        fn received(sqs: &FakeSqs, id: u32) -> ReceivedEvent<Click> {
+            ReceivedEvent{
+                payload: Click{id},
+                message_id: None,
+                receive_count: 1,
+                sent_at: None,
+                first_received_at: None,
+                group_id: None,
+                sequence_number: None,
+                attributes: Attributes::new(),
+                sns: None,
+                handle: handle(sqs, &id.to_string()),
+                received_at: tokio::time::Instant::now(),
+                heartbeat: None,
+            }
+        }
+
+        /// A receive which returns script's batches in turn (then empty ones), logging when it was called
+        fn scripted(script: Vec<Batch>) -> (impl FnMut() -> futures::future::Ready<Batch>, Arc<Mutex<Vec<Duration>>>) {
+            let start = tokio::time::Instant::now();
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let mut script = VecDeque::from(script);
+            let log = calls.clone();
+            let receive = move || {
+                log.lock().unwrap().push(start.elapsed());
+                futures::future::ready(script.pop_front().unwrap_or_else(|| Ok((Vec::new(), Vec::new()))))
+            };
+            (receive, calls)
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn stream_backs_off_while_idle() {
+            let sqs = FakeSqs::new();
+            let empty = || Ok((Vec::new(), Vec::new()));
+            let undecodable = UndecodableMessage{
+                body: "garbage".to_string(),
+                message_id: None,
+                receive_count: 1,
+                error: "expected value".to_string(),
+                attributes: Attributes::new(),
+                handle: handle(&sqs, "garbage"),
+            };
+            let (receive, calls) = scripted(vec![
+                empty(),
+                empty(),
+                empty(),
+                Ok((vec![received(&sqs, 1)], Vec::new())),
+                empty(),
+                Err(EventfulError::Http("connection reset".to_string())),
+                Ok((Vec::new(), vec![undecodable])),
+                empty(),
+                Err(EventfulError::QueueDoesNotExist(QUEUE.to_string())),
+            ]);
+            let backoff = Backoff::Exponential{base: Duration::from_secs(1), factor: 2.0, max: Duration::from_secs(8)};
+            let results: Vec<_> = receive_stream(receive, backoff, CancellationToken::new()).collect().await;
+
+            assert_eq!(results.len(), 3);
+            assert!(matches!(&results[0], Ok(event) if event.payload == Click{id: 1}));
+            assert!(matches!(results[1], Err(EventfulError::Http(_))));
+            // QueueDoesNotExist ends the stream
+            assert!(matches!(results[2], Err(EventfulError::QueueDoesNotExist(_))));
+            // empty receives wait 1s, 2s, 4s; a batch resets the backoff, a failure keeps counting,
+            // and undecodable messages count as a batch
+            let secs: Vec<u64> = calls.lock().unwrap().iter().map(Duration::as_secs).collect();
+            assert_eq!(secs, vec![0, 1, 3, 7, 7, 8, 10, 10, 11]);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn stream_backoff_is_capped_and_shutdown_ends_a_pause() {
+            let (receive, calls) = scripted(Vec::new());
+            let backoff = Backoff::Exponential{base: Duration::from_secs(1), factor: 2.0, max: Duration::from_secs(4)};
+            let shutdown = CancellationToken::new();
+            let cancel = shutdown.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(20)).await;
+                cancel.cancel();
+            });
+            let started = tokio::time::Instant::now();
+            let results: Vec<Result<ReceivedEvent<Click>, EventfulError>> = receive_stream(receive, backoff, shutdown).collect().await;
+            assert!(results.is_empty());
+            assert_eq!(started.elapsed(), Duration::from_secs(20));
+            let secs: Vec<u64> = calls.lock().unwrap().iter().map(Duration::as_secs).collect();
+            assert_eq!(secs, vec![0, 1, 3, 7, 11, 15, 19]);
+        }
+
+        fn is_checksum_mismatch<T>(result: &Result<T, EventfulError>, body: &str) -> bool {
+            match result {
+                Err(EventfulError::ChecksumMismatch{message_id, expected, returned}) =>
+                    message_id.starts_with("fake-") && *expected == md5_hex(body) && returned != expected,
+                _ => false,
+            }
+        }
+
+        #[tokio::test]
+        async fn publish_verifies_the_md5_digest() {
+            let sqs = FakeSqs::new();
+            let receipt = sqs.publish(&Click{id: 1}).await.unwrap();
+            assert_eq!(receipt.md5_of_body, md5_hex(r#"{"id":1}"#));
+            assert_eq!(receipt.queue.as_str(), QUEUE);
+            assert_eq!(receipt.bytes, 8);
+            assert_eq!(receipt.sequence_number, None);
+
+            sqs.corrupt_next(1);
+            assert!(is_checksum_mismatch(&sqs.publish(&Click{id: 2}).await, r#"{"id":2}"#));
+            // SQS has the message, but not what was sent
+            assert_eq!(sqs.bodies(QUEUE).len(), 2);
+            assert!(sqs.publish(&Click{id: 3}).await.is_ok());
+        }
+
+        #[tokio::test]
+        async fn publish_batch_returns_verified_receipts_in_order() {
+            let sqs = FakeSqs::new();
+            let clicks: Vec<Click> = (1..=12).map(|id| Click{id}).collect();
+            let receipts = sqs.publish_batch(&clicks).await.unwrap();
+            assert_eq!(receipts.len(), 12);
+            let bodies = sqs.bodies(QUEUE);
+            for (receipt, body) in receipts.iter().zip(&bodies) {
+                assert_eq!(receipt.md5_of_body, md5_hex(body));
+            }
+            assert_eq!(bodies[11], r#"{"id":12}"#);
+
+            sqs.corrupt_next(1);
+            assert!(is_checksum_mismatch(&sqs.publish_batch(&clicks[..2]).await, r#"{"id":1}"#));
+        }
+
+        #[tokio::test]
+        async fn sqs_api_publishes_check_fifo_before_sending() {
+            let sqs = FakeSqs::new();
+            let grouped = |id| Tap{id, group: Some("a".to_string())};
+            assert!(matches!(sqs.publish(&Tap{id: 1, group: None}).await, Err(EventfulError::FifoMismatch(_))));
+            // one bad event keeps the whole batch from being sent
+            let taps = vec![grouped(1), Tap{id: 2, group: None}];
+            assert!(matches!(sqs.publish_batch(&taps).await, Err(EventfulError::FifoMismatch(_))));
+            assert!(sqs.bodies(FIFO_QUEUE).is_empty());
+
+            let first = sqs.publish(&grouped(1)).await.unwrap();
+            let second = sqs.publish(&grouped(2)).await.unwrap();
+            assert!(first.sequence_number.is_some());
+            assert!(first.sequence_number < second.sequence_number);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn subscription_unwraps_sns_envelopes_when_asked() {
+            let envelope = r#"{"Type":"Notification","MessageId":"m-1","TopicArn":"arn:aws:sns:us-east-1:123456789012:clicks","Message":"{\"id\":1}","MessageAttributes":{"tenant":{"Type":"String","Value":"acme"}}}"#;
+            let sqs = FakeSqs::new();
+            for _ in 0..2 {
+                sqs.send_message(QUEUE, OutgoingSQS{body: envelope.to_string(), group_id: None, dedup_id: None}).await.unwrap();
+            }
+            let unwrapped = sqs.subscribe(QUEUE).max_messages(1).unwrap_sns(true).next().await.unwrap().unwrap();
+            assert_eq!(&unwrapped.body[..], br#"{"id":1}"#);
+            assert_eq!(unwrapped.meta.headers.get("tenant").map(String::as_str), Some("acme"));
+            let raw = sqs.subscribe(QUEUE).max_messages(1).next().await.unwrap().unwrap();
+            assert_eq!(&raw.body[..], envelope.as_bytes());
+            assert!(raw.meta.headers.is_empty());
+            unwrapped.ack().await.unwrap();
+            raw.ack().await.unwrap();
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn poll_deletes_only_what_decoded() {
+            for on_decode_error in [OnDecodeError::DeadLetter, OnDecodeError::Delete, OnDecodeError::Leave] {
+                let sqs = valid_and_garbage().await;
+                let clicks: Vec<Click> = sqs.poll(QUEUE, true, on_decode_error).await.unwrap();
+                assert_eq!(clicks, vec![Click{id: 1}], "{:?}", on_decode_error);
+                match on_decode_error {
+                    OnDecodeError::Delete => assert!(sqs.bodies(QUEUE).is_empty()),
+                    _ => assert_eq!(sqs.bodies(QUEUE), vec!["garbage".to_string()], "{:?}", on_decode_error),
+                }
+            }
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn poll_without_delete_on_receipt_leaves_decoded_messages() {
+            let sqs = valid_and_garbage().await;
+            let clicks: Vec<Click> = sqs.poll(QUEUE, false, OnDecodeError::Delete).await.unwrap();
+            assert_eq!(clicks, vec![Click{id: 1}]);
+            assert_eq!(sqs.bodies(QUEUE), vec![r#"{"id":1}"#.to_string()]);
+            assert_eq!(sqs.in_flight(QUEUE), 1);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn dead_letter_nacks_with_the_consumer_backoff_and_leave_waits_for_the_visibility_timeout() {
+            let nacked = valid_and_garbage().await;
+            let _: Vec<Click> = nacked.poll(QUEUE, true, OnDecodeError::DeadLetter).await.unwrap();
+            let left = valid_and_garbage().await;
+            let _: Vec<Click> = left.poll(QUEUE, true, OnDecodeError::Leave).await.unwrap();
+            assert_eq!((nacked.in_flight(QUEUE), left.in_flight(QUEUE)), (1, 1));
+
+            // the first retry waits at most 10 seconds, well inside the 30 second visibility timeout
+            tokio::time::sleep(Duration::from_secs(11)).await;
+            assert_eq!((nacked.in_flight(QUEUE), left.in_flight(QUEUE)), (0, 1));
+            tokio::time::sleep(Duration::from_secs(20)).await;
+            assert_eq!(left.in_flight(QUEUE), 0);
+        }
+
+        fn settings(concurrency: usize, idle_after: u32, drain_timeout: Duration) -> ConsumeSettings {
+            ConsumeSettings{
+                queue_url: QUEUE.to_string(),
+                concurrency,
+                max_messages: concurrency.min(10),
+                idle_after,
+                idle_backoff: Backoff::Exponential{base: Duration::from_secs(1), factor: 2.0, max: Duration::from_secs(4)},
+                on_decode_error: OnDecodeError::default(),
+                unwrap_sns: false,
+                drain_timeout,
+            }
+        }
+
+        /// Receive from the fake queue as ClientSQS would, long polling for up to wait_time_seconds
+        fn receive_from(sqs: &FakeSqs, wait_time_seconds: i32) -> impl FnMut() -> BoxFuture<'static, Result<Vec<Message>, EventfulError>> {
+            let sqs = sqs.clone();
+            move || {
+                let sqs = sqs.clone();
+                async move { sqs.receive_messages(QUEUE, 10, wait_time_seconds).await }.boxed()
+            }
+        }
+
+        async fn send_click(sqs: &FakeSqs, id: u32) {
+            sqs.send_message(QUEUE, OutgoingSQS{body: format!(r#"{{"id":{}}}"#, id), group_id: None, dedup_id: None}).await.unwrap();
+        }
+
+        /// Cancel the token after `after`
+        async fn cancel_after(shutdown: &CancellationToken, after: Duration) {
+            tokio::time::sleep(after).await;
+            shutdown.cancel();
+        }
+
+        type Handled = Arc<Mutex<Vec<u32>>>;
+
+        /// Handles clicks by sleeping for `takes` of the click's id, logging ids as they finish. Fails the first attempt at ids in fail_once
+        fn click_handler(takes: fn(u32) -> Duration, fail_once: &'static [u32]) -> (impl Fn(Click) -> BoxFuture<'static, Result<(), EventfulError>>, Handled) {
+            let handled = Arc::new(Mutex::new(Vec::new()));
+            let log = handled.clone();
+            let handler = move |click: Click| {
+                let log = log.clone();
+                async move {
+                    tokio::time::sleep(takes(click.id)).await;
+                    let mut log = log.lock().unwrap();
+                    let first = !log.contains(&click.id);
+                    log.push(click.id);
+                    match first && fail_once.contains(&click.id) {
+                        true => Err(EventfulError::Http(format!("click {} failed", click.id))),
+                        false => Ok(()),
+                    }
+                }.boxed()
+            };
+            (handler, handled)
+        }
+
+        #[derive(Default)]
+        struct IdleLog(Mutex<Vec<String>>);
+
+        impl EventfulObserver for IdleLog {
+            fn consumer_dormant(&self, ctx: &ConsumerIdle) {
+                self.0.lock().unwrap().push(format!("dormant after {} waiting {:?}", ctx.empty_receives, ctx.wait));
+            }
+            fn consumer_awake(&self, ctx: &ConsumerIdle) {
+                self.0.lock().unwrap().push(format!("awake after {}", ctx.empty_receives));
+            }
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn queue_consumer_handles_retries_and_stops_on_shutdown() {
+            let sqs = FakeSqs::new();
+            for id in 1..=3 {
+                send_click(&sqs, id).await;
+            }
+            let options = ConsumerOptions::default().retry(RetryPolicy::default().max_attempts(5).backoff(Backoff::Fixed(Duration::from_secs(5))));
+            let (handler, handled) = click_handler(|_| Duration::from_millis(100), &[2]);
+            let shutdown = CancellationToken::new();
+            let started = tokio::time::Instant::now();
+            let (result, _) = tokio::join!(
+                consume_until(settings(10, 1, Duration::from_secs(20)), Arc::new(sqs.clone()), receive_from(&sqs, 1), &options, &handler, shutdown.clone()),
+                cancel_after(&shutdown, Duration::from_secs(10)),
+            );
+            result.unwrap();
+            // nothing was running at shutdown, so the loop stopped straight away
+            assert!(started.elapsed() < Duration::from_secs(11), "stopped after {:?}", started.elapsed());
+            let mut handled = handled.lock().unwrap().clone();
+            // 2 failed, was nacked for the 5 second backoff, and was handled again after
+            assert_eq!(handled.pop(), Some(2));
+            handled.sort();
+            assert_eq!(handled, vec![1, 2, 3]);
+            assert_eq!(sqs.deleted(QUEUE), 3);
+            let stats = options.stats.snapshot();
+            assert_eq!((stats.consumed, stats.failed), (3, 1));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn queue_consumer_shutdown_releases_what_is_still_running() {
+            let sqs = FakeSqs::new();
+            send_click(&sqs, 1).await;
+            send_click(&sqs, 2).await;
+            let options = ConsumerOptions::default();
+            // 1 finishes within the drain timeout, 2 doesn't
+            let (handler, handled) = click_handler(|id| Duration::from_secs(if id == 1 { 2 } else { 60 }), &[]);
+            let shutdown = CancellationToken::new();
+            let started = tokio::time::Instant::now();
+            let (result, _) = tokio::join!(
+                consume_until(settings(10, 1, Duration::from_secs(5)), Arc::new(sqs.clone()), receive_from(&sqs, 1), &options, &handler, shutdown.clone()),
+                cancel_after(&shutdown, Duration::from_secs(1)),
+            );
+            result.unwrap();
+            assert_eq!(started.elapsed(), Duration::from_secs(6));
+            assert_eq!(*handled.lock().unwrap(), vec![1]);
+            assert_eq!(sqs.deleted(QUEUE), 1);
+
+            // 2 was released, so another consumer has it straight away rather than after the 30 second visibility timeout
+            assert_eq!(sqs.in_flight(QUEUE), 0);
+            let redelivered = sqs.subscribe(QUEUE).wait_time_seconds(0).next().await.unwrap().unwrap();
+            assert_eq!((&redelivered.body[..], redelivered.attempt), (&br#"{"id":2}"#[..], 2));
+            assert_eq!(started.elapsed(), Duration::from_secs(6));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn queue_consumer_shutdown_releases_what_a_receive_under_way_takes() {
+            let sqs = FakeSqs::new();
+            let options = ConsumerOptions::default();
+            let (handler, handled) = click_handler(|_| Duration::ZERO, &[]);
+            let shutdown = CancellationToken::new();
+            let late = async {
+                // the first receive is long polling when shutdown comes, and takes this message during the drain
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                send_click(&sqs, 1).await;
+            };
+            let (result, _, _) = tokio::join!(
+                consume_until(settings(10, 1, Duration::from_secs(20)), Arc::new(sqs.clone()), receive_from(&sqs, 20), &options, &handler, shutdown.clone()),
+                cancel_after(&shutdown, Duration::from_secs(1)),
+                late,
+            );
+            result.unwrap();
+            assert!(handled.lock().unwrap().is_empty());
+            assert_eq!(sqs.in_flight(QUEUE), 0);
+            let redelivered = sqs.subscribe(QUEUE).wait_time_seconds(0).next().await.unwrap().unwrap();
+            assert_eq!(redelivered.attempt, 2);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn queue_consumer_releases_running_handlers_when_settling_fails() {
+            let sqs = FakeSqs::new();
+            send_click(&sqs, 1).await;
+            send_click(&sqs, 2).await;
+            let options = ConsumerOptions::default();
+            let (handler, _) = click_handler(|id| Duration::from_secs(if id == 1 { 1 } else { 60 }), &[]);
+            let failing = async {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                // no receive happens while a full batch is in flight, so this fails the delete of 1
+                sqs.fail_next(1);
+            };
+            let started = tokio::time::Instant::now();
+            let (result, _) = tokio::join!(
+                consume_until(settings(10, 1, Duration::from_secs(20)), Arc::new(sqs.clone()), receive_from(&sqs, 1), &options, &handler, CancellationToken::new()),
+                failing,
+            );
+            assert!(matches!(result, Err(EventfulError::SQS(_))), "{:?}", result);
+            assert_eq!(started.elapsed(), Duration::from_secs(1));
+            // 2's handler was dropped with the loop and its message handed back; 1 waits out its visibility timeout
+            assert_eq!(sqs.in_flight(QUEUE), 1);
+            let released = sqs.subscribe(QUEUE).wait_time_seconds(0).next().await.unwrap().unwrap();
+            assert_eq!((&released.body[..], released.attempt), (&br#"{"id":2}"#[..], 2));
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn queue_consumer_backs_off_while_idle_and_resets_when_a_message_arrives() {
+            let sqs = FakeSqs::new();
+            send_click(&sqs, 1).await;
+            // empty receives, except the sixth, which takes the click
+            let start = tokio::time::Instant::now();
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let receive = {
+                let (sqs, calls) = (sqs.clone(), calls.clone());
+                move || {
+                    let (sqs, calls) = (sqs.clone(), calls.clone());
+                    async move {
+                        let call = {
+                            let mut calls = calls.lock().unwrap();
+                            calls.push(start.elapsed());
+                            calls.len()
+                        };
+                        match call {
+                            6 => sqs.receive_messages(QUEUE, 10, 0).await,
+                            _ => Ok(Vec::new()),
+                        }
+                    }
+                }
+            };
+            let observed = Arc::new(IdleLog::default());
+            let options = ConsumerOptions::default().observer(observed.clone());
+            let (handler, handled) = click_handler(|_| Duration::ZERO, &[]);
+            let shutdown = CancellationToken::new();
+            let idle_at_cap = async {
+                tokio::time::sleep(Duration::from_secs(9)).await;
+                let stats = options.stats.snapshot();
+                tokio::time::sleep(Duration::from_secs(4)).await;
+                shutdown.cancel();
+                stats
+            };
+            let (result, _) = tokio::join!(
+                consume_until(settings(10, 2, Duration::from_secs(20)), Arc::new(sqs.clone()), receive, &options, &handler, shutdown.clone()),
+                async {
+                    let at_cap = idle_at_cap.await;
+                    assert_eq!((at_cap.idle_receives, at_cap.idle_wait), (5, Duration::from_secs(4)));
+                },
+            );
+            result.unwrap();
+            assert_eq!(*handled.lock().unwrap(), vec![1]);
+            // two empty receives before waiting, then 1s, 2s, 4s, capped at 4s; the click resets the wait to nothing
+            let secs: Vec<u64> = calls.lock().unwrap().iter().map(Duration::as_secs).collect();
+            assert_eq!(secs, vec![0, 0, 1, 3, 7, 11, 11, 11, 12]);
+            assert_eq!(*observed.0.lock().unwrap(), vec!["dormant after 4 waiting 4s".to_string(), "awake after 5".to_string()]);
+        }
+
+        type Finished<T> = Arc<Mutex<Vec<(Duration, T)>>>;
+
+        /// A worker pool on the fake queue, with no heartbeat. Handled events are logged by how long after the start they finished
+        fn fake_pool<T, F, Fut>(sqs: &FakeSqs, queue_url: &str, options: WorkerPoolOptions, handler: F) -> (QueueWorkerPool, Finished<T>)
+        where
+            T: DeserializeOwned + Clone + Send + 'static,
+            F: Fn(T) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = ()> + Send + 'static,
+        {
+            let receive = options.receive.clone();
+            let receive_batch: ReceiveBatch = {
+                let (sqs, queue_url, max_messages) = (sqs.clone(), queue_url.to_string(), receive.max_messages);
+                Arc::new(move || {
+                    let (sqs, queue_url) = (sqs.clone(), queue_url.clone());
+                    async move { Ok((sqs.receive_messages(&queue_url, max_messages, 1).await?, 0)) }.boxed()
+                })
+            };
+            let (log, start) = (Arc::new(Mutex::new(Vec::new())), tokio::time::Instant::now());
+            let handled = log.clone();
+            let handler = Arc::new(handler);
+            let pool = QueueWorkerPool::start(Arc::new(sqs.clone()), queue_url.to_string(), receive, receive_batch, options.heartbeat(None), move |event: T| {
+                let (log, handler) = (log.clone(), handler.clone());
+                async move {
+                    handler(event.clone()).await;
+                    log.lock().unwrap().push((start.elapsed(), event));
+                    Ok(())
+                }
+            });
+            (pool, handled)
+        }
+
+        async fn until_consumed(pool: &QueueWorkerPool, count: u64) {
+            while pool.stats().snapshot().consumed < count {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn worker_pool_throughput_grows_with_its_workers() {
+            let mut took = Vec::new();
+            for workers in [1, 4, 16] {
+                let sqs = FakeSqs::new().visibility_timeout(Duration::from_secs(300));
+                for id in 0..32 {
+                    send_click(&sqs, id).await;
+                }
+                let options = WorkerPoolOptions::default().pollers(2).workers(workers);
+                let (pool, handled) = fake_pool(&sqs, QUEUE, options, |_: Click| tokio::time::sleep(Duration::from_secs(1)));
+                until_consumed(&pool, 32).await;
+                pool.shutdown().await.unwrap();
+                assert_eq!(sqs.deleted(QUEUE), 32);
+                took.push(handled.lock().unwrap().iter().map(|(at, _)| *at).max().unwrap().as_secs());
+            }
+            // a second per event, spread over the workers
+            assert_eq!(took, vec![32, 8, 2]);
+        }
+
+        #[derive(Clone, Debug, Deserialize)]
+        struct Step {
+            group: String,
+            n: u64,
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn worker_pool_handles_each_fifo_group_in_order() {
+            let sqs = FakeSqs::new();
+            for n in 1..=5 {
+                for group in ["a", "b", "c"] {
+                    let body = format!(r#"{{"group":"{}","n":{}}}"#, group, n);
+                    let outgoing = OutgoingSQS{body, group_id: Some(group.to_string()), dedup_id: Some(format!("{}{}", group, n))};
+                    sqs.send_message(FIFO_QUEUE, outgoing).await.unwrap();
+                }
+            }
+            // the earlier steps take longest, so a group handled concurrently would finish out of order
+            let options = WorkerPoolOptions::default().pollers(1).workers(8);
+            let (pool, handled) = fake_pool(&sqs, FIFO_QUEUE, options, |step: Step| tokio::time::sleep(Duration::from_millis(600 - 100 * step.n)));
+            until_consumed(&pool, 15).await;
+            pool.shutdown().await.unwrap();
+
+            let handled = handled.lock().unwrap();
+            for group in ["a", "b", "c"] {
+                let steps: Vec<u64> = handled.iter().filter(|(_, step)| step.group == group).map(|(_, step)| step.n).collect();
+                assert_eq!(steps, vec![1, 2, 3, 4, 5], "group {}", group);
+            }
+        }
+
+        /// Receive the one click on the fake queue, as a ReceivedEvent
+        async fn receive_click(sqs: &FakeSqs) -> ReceivedEvent<Click> {
+            let message = sqs.receive_messages(QUEUE, 1, 0).await.unwrap().pop().unwrap();
+            let mut event = received(sqs, 1);
+            event.handle = handle(sqs, message.receipt_handle().unwrap());
+            event
+        }
+
+        async fn redelivered(sqs: &FakeSqs) -> bool {
+            !sqs.receive_messages(QUEUE, 10, 0).await.unwrap().is_empty()
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn a_heartbeat_keeps_a_slow_handlers_message_from_being_redelivered() {
+            let sqs = FakeSqs::new();
+            send_click(&sqs, 1).await;
+            let mut event = receive_click(&sqs).await;
+            event.start_heartbeat(Duration::from_secs(10), Duration::from_secs(30));
+            // well past the 30 second visibility timeout
+            tokio::time::sleep(Duration::from_secs(300)).await;
+            assert!(!redelivered(&sqs).await);
+            event.ack().await.unwrap();
+            assert_eq!(sqs.deleted(QUEUE), 1);
+
+            // without one, the message comes back
+            send_click(&sqs, 2).await;
+            let _event = receive_click(&sqs).await;
+            tokio::time::sleep(Duration::from_secs(31)).await;
+            assert!(redelivered(&sqs).await);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn a_heartbeat_stops_when_an_extension_fails() {
+            let sqs = FakeSqs::new();
+            send_click(&sqs, 1).await;
+            let mut event = receive_click(&sqs).await;
+            event.start_heartbeat(Duration::from_secs(10), Duration::from_secs(30));
+            // the first extension, at 10 seconds, fails
+            sqs.fail_next(1);
+            // had the heartbeat carried on, the extension at 20 seconds would hide the message until 50
+            tokio::time::sleep(Duration::from_secs(31)).await;
+            assert!(redelivered(&sqs).await);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn a_heartbeat_stops_at_the_twelve_hour_cap() {
+            let sqs = FakeSqs::new();
+            send_click(&sqs, 1).await;
+            let mut event = receive_click(&sqs).await;
+            event.start_heartbeat(Duration::from_secs(600), Duration::from_secs(3600));
+            tokio::time::sleep(MAX_VISIBILITY - Duration::from_secs(60)).await;
+            assert!(!redelivered(&sqs).await);
+            // the last extension only reached the cap, after which SQS would refuse any more
+            tokio::time::sleep(Duration::from_secs(61)).await;
+            assert!(redelivered(&sqs).await);
+        }
+    }
+
+    #[test]
+    fn localstack_path_style_urls_round_trip() {
+        let raw = "http://localhost:4566/queue/us-east-1/000000000000/orders.fifo";
+        let url = QueueUrl::parse(raw).unwrap();
+        assert_eq!(url.as_str(), raw);
+        assert_eq!(url.region(), Some("us-east-1"));
+        assert_eq!(url.account_id(), "000000000000");
+        assert_eq!(url.queue_name(), "orders.fifo");
+        assert!(url.is_fifo());
+        let displayed = url.to_string();
+        assert_eq!(QueueUrl::parse(&displayed).unwrap(), url);
+        assert_eq!(serde_json::from_str::<QueueUrl>(&serde_json::to_string(&url).unwrap()).unwrap(), url);
+        assert_eq!(QueueRef::parse(raw).unwrap(), QueueRef::Url(url));
+    }
+
+    #[test]
+    fn builder_falls_back_to_the_endpoint_env_var() {
+        let build = |builder: ClientSQSBuilder, from_env: &str| Runtime::new().unwrap()
+            .block_on(builder.build_with_env_endpoint(Some(from_env.to_string())))
+            .err().map(|err| err.to_string());
+        // an endpoint without a scheme is rejected before any AWS config is loaded, which shows which endpoint was used
+        let from_env = build(ClientSQS::builder().region("us-east-1".to_string()), "localhost:4566");
+        let explicit = build(ClientSQS::builder().region("us-east-1".to_string()).endpoint_url("localhost:4567".to_string()), "localhost:4566");
+        assert!(from_env.unwrap().contains("localhost:4566"));
+        assert!(explicit.unwrap().contains("localhost:4567"));
+        assert_eq!(endpoint_from(Some(String::new())), None);
+        assert_eq!(endpoint_from(None), None);
+    }
+
+    #[tokio::test]
+    async fn a_client_sends_to_the_endpoint_from_the_env_var() {
+        let body = r#"{"order":7}"#;
+        let response = format!(
+            "<SendMessageResponse><SendMessageResult><MessageId>m-1</MessageId><MD5OfMessageBody>{}</MD5OfMessageBody></SendMessageResult>\
+            <ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></SendMessageResponse>",
+            md5_hex(body));
+        let stub = HttpStub::start(vec![(200, response)]).await;
+        // with the region given and static credentials, loading the config doesn't reach out to IMDS
+        let config = aws_config::from_env().region(Region::new("us-east-1")).load().await;
+        let client = ClientSQS::builder()
+            .sdk_config(config)
+            .static_credentials("test", "test", None)
+            .build_with_env_endpoint(Some(stub.url())).await.unwrap();
+        let queue_url = format!("{}/000000000000/orders", stub.url());
+        let message = OutgoingSQS{body: body.to_string(), group_id: None, dedup_id: None};
+        let sent = client.send_message(&queue_url, message).await.unwrap();
+        let receipt = sent.verify(&QueueUrl::parse(&queue_url).unwrap(), md5_hex(body), body.len()).unwrap();
+        assert_eq!(receipt.message_id, "m-1");
+
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!((requests[0].method.as_str(), requests[0].path.as_str()), ("POST", "/"));
+        assert_eq!(requests[0].headers["content-type"], "application/x-www-form-urlencoded");
+        assert!(requests[0].body_str().contains("Action=SendMessage"));
+        assert!(requests[0].body_str().contains("QueueUrl=http%3A%2F%2F127.0.0.1"));
+    }
+
+    /// A client of the stub, which answers as SQS would
+    async fn stub_client(stub: &HttpStub) -> ClientSQS {
+        ClientSQS::builder()
+            .region("us-east-1".to_string())
+            .endpoint_url(stub.url())
+            .static_credentials("test", "test", None)
+            .build().await.unwrap()
+    }
+
+    fn receive_response(bodies: &[&str]) -> String {
+        let messages: String = bodies.iter().enumerate()
+            .map(|(i, body)| format!(
+                "<Message><MessageId>m-{i}</MessageId><ReceiptHandle>h-{i}</ReceiptHandle><MD5OfBody>{}</MD5OfBody><Body>{}</Body></Message>",
+                md5_hex(body), body))
+            .collect();
+        format!("<ReceiveMessageResponse><ReceiveMessageResult>{}</ReceiveMessageResult>\
+            <ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></ReceiveMessageResponse>", messages)
+    }
+
+    fn error_response(code: &str) -> String {
+        format!("<ErrorResponse><Error><Type>Sender</Type><Code>{}</Code><Message>stubbed</Message></Error>\
+            <RequestId>r-1</RequestId></ErrorResponse>", code)
+    }
+
+    /// A client of the stub which leaves retrying to the RetryPolicy, three attempts a millisecond apart
+    async fn retrying_client(stub: &HttpStub) -> ClientSQS {
+        ClientSQS::builder()
+            .region("us-east-1".to_string())
+            .endpoint_url(stub.url())
+            .static_credentials("test", "test", None)
+            .retry_attempts(1)
+            .retry(RetryPolicy::default().max_attempts(3).backoff(Backoff::Fixed(Duration::from_millis(1))))
+            .build().await.unwrap()
+    }
+
+    async fn send_to(client: &ClientSQS, stub: &HttpStub) -> Result<SentMessage, EventfulError> {
+        let queue_url = format!("{}/000000000000/orders", stub.url());
+        client.send_message(&queue_url, OutgoingSQS{body: r#"{"order":7}"#.to_string(), group_id: None, dedup_id: None}).await
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_throttling_are_retried() {
+        let sent = format!(
+            "<SendMessageResponse><SendMessageResult><MessageId>m-1</MessageId><MD5OfMessageBody>{}</MD5OfMessageBody></SendMessageResult>\
+            <ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></SendMessageResponse>",
+            md5_hex(r#"{"order":7}"#));
+        let stub = HttpStub::start(vec![(500, error_response("InternalError")), (400, error_response("RequestThrottled")), (200, sent)]).await;
+        let client = retrying_client(&stub).await;
+        let sent = send_to(&client, &stub).await.unwrap();
+        assert_eq!(sent.message_id.as_deref(), Some("m-1"));
+        assert_eq!(stub.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn access_denied_is_not_retried() {
+        let stub = HttpStub::start(vec![(403, error_response("AccessDenied"))]).await;
+        let client = retrying_client(&stub).await;
+        match send_to(&client, &stub).await {
+            Err(EventfulError::SqsRequest{failure, code, ..}) => {
+                assert_eq!(failure, SqsFailure::Client);
+                assert_eq!(code.as_deref(), Some("AccessDenied"));
+            },
+            other => panic!("expected a client failure, got {:?}", other),
+        }
+        assert_eq!(stub.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn throttling_is_retried_until_the_attempts_run_out() {
+        let stub = HttpStub::start(vec![(400, error_response("RequestThrottled"))]).await;
+        let client = retrying_client(&stub).await;
+        match send_to(&client, &stub).await {
+            Err(EventfulError::RetriesExhausted{attempts, last_error}) => {
+                assert_eq!(attempts, 3);
+                assert!(matches!(*last_error, EventfulError::SqsRequest{failure: SqsFailure::Throttled, ..}), "{:?}", last_error);
+            },
+            other => panic!("expected the retries to run out, got {:?}", other),
+        }
+        assert_eq!(stub.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn a_missing_queue_is_not_retried() {
+        let stub = HttpStub::start(vec![(400, error_response("AWS.SimpleQueueService.NonExistentQueue"))]).await;
+        let client = retrying_client(&stub).await;
+        assert!(matches!(send_to(&client, &stub).await, Err(EventfulError::QueueDoesNotExist(_))));
+        assert_eq!(stub.requests().len(), 1);
+    }
+
+    fn sent_response(body: &str) -> (u16, String) {
+        (200, format!(
+            "<SendMessageResponse><SendMessageResult><MessageId>m-1</MessageId><MD5OfMessageBody>{}</MD5OfMessageBody></SendMessageResult>\
+            <ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></SendMessageResponse>",
+            md5_hex(body)))
+    }
+
+    fn deleted_response() -> (u16, String) {
+        (200, "<DeleteMessageResponse><ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></DeleteMessageResponse>".to_string())
+    }
+
+    fn actions(stub: &HttpStub) -> Vec<String> {
+        stub.requests().iter()
+            .filter_map(|request| request.body_str().split('&').find_map(|param| param.strip_prefix("Action=")).map(str::to_string))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn manual_redrive_only_deletes_what_it_republished() {
+        let stub = HttpStub::start(vec![
+            (200, receive_response(&[r#"{"id":1}"#, r#"{"id":2}"#])),
+            sent_response(r#"{"id":1}"#),
+            deleted_response(),
+            (400, error_response("InvalidMessageContents")),
+            (200, receive_response(&[])),
+        ]).await;
+        let client = retrying_client(&stub).await;
+        let (dlq, source) = (format!("{}/000000000000/orders-dlq", stub.url()), format!("{}/000000000000/orders", stub.url()));
+        let report = client.manual_redrive(&dlq, &source, 10).await.unwrap();
+        assert_eq!(report, RedriveReport{moved: 1, failed: 1});
+        assert_eq!(actions(&stub), vec!["ReceiveMessage", "SendMessage", "DeleteMessage", "SendMessage", "ReceiveMessage"]);
+        let requests = stub.requests();
+        assert!(requests[2].body_str().contains("ReceiptHandle=h-0"));
+    }
+
+    #[tokio::test]
+    async fn manual_redrive_counts_a_failed_delete_and_carries_on() {
+        let stub = HttpStub::start(vec![
+            (200, receive_response(&[r#"{"id":1}"#, r#"{"id":2}"#])),
+            sent_response(r#"{"id":1}"#),
+            (403, error_response("AccessDenied")),
+            sent_response(r#"{"id":2}"#),
+            deleted_response(),
+            (200, receive_response(&[])),
+        ]).await;
+        let client = retrying_client(&stub).await;
+        let (dlq, source) = (format!("{}/000000000000/orders-dlq", stub.url()), format!("{}/000000000000/orders", stub.url()));
+        let report = client.manual_redrive(&dlq, &source, 10).await.unwrap();
+        assert_eq!(report, RedriveReport{moved: 1, failed: 1});
+        assert_eq!(actions(&stub), vec!["ReceiveMessage", "SendMessage", "DeleteMessage", "SendMessage", "DeleteMessage", "ReceiveMessage"]);
+    }
+
+    /// The URL the stub reports for the orders queue; requests go to the stub's endpoint whatever the queue URL
+    const ORDERS: &str = "http://localhost:4566/000000000000/orders";
+
+    fn queue_url_response(queue_url: &str) -> (u16, String) {
+        (200, format!("<GetQueueUrlResponse><GetQueueUrlResult><QueueUrl>{}</QueueUrl></GetQueueUrlResult>\
+            <ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></GetQueueUrlResponse>", queue_url))
+    }
+
+    fn attributes_response(attributes: &[(&str, &str)]) -> (u16, String) {
+        let attributes: String = attributes.iter()
+            .map(|(name, value)| format!("<Attribute><Name>{}</Name><Value>{}</Value></Attribute>", name, value))
+            .collect();
+        (200, format!("<GetQueueAttributesResponse><GetQueueAttributesResult>{}</GetQueueAttributesResult>\
+            <ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></GetQueueAttributesResponse>", attributes))
+    }
+
+    fn visibility_of(seconds: u64) -> QueueSettings {
+        QueueSettings{visibility_timeout: Some(Duration::from_secs(seconds)), ..QueueSettings::default()}
+    }
+
+    #[tokio::test]
+    async fn create_queue_returns_an_existing_queue_with_the_same_settings() {
+        let stub = HttpStub::start(vec![queue_url_response(ORDERS), attributes_response(&[("VisibilityTimeout", "60")])]).await;
+        let client = retrying_client(&stub).await;
+        assert_eq!(client.create_queue("orders", &visibility_of(60)).await.unwrap().as_str(), ORDERS);
+        // nothing was created
+        assert_eq!(actions(&stub), vec!["GetQueueUrl", "GetQueueAttributes"]);
+    }
+
+    #[tokio::test]
+    async fn create_queue_refuses_an_existing_queue_with_other_settings() {
+        let stub = HttpStub::start(vec![queue_url_response(ORDERS), attributes_response(&[("VisibilityTimeout", "30")])]).await;
+        let client = retrying_client(&stub).await;
+        match client.create_queue("orders", &visibility_of(60)).await {
+            Err(EventfulError::QueueConflict(diff)) => assert!(diff.ends_with("VisibilityTimeout is 30, expected 60"), "{}", diff),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(actions(&stub), vec!["GetQueueUrl", "GetQueueAttributes"]);
+    }
+
+    #[tokio::test]
+    async fn create_queue_creates_a_missing_queue() {
+        let created = format!("<CreateQueueResponse><CreateQueueResult><QueueUrl>{}</QueueUrl></CreateQueueResult>\
+            <ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></CreateQueueResponse>", ORDERS);
+        let stub = HttpStub::start(vec![(400, error_response("AWS.SimpleQueueService.NonExistentQueue")), (200, created)]).await;
+        let client = retrying_client(&stub).await;
+        assert_eq!(client.create_queue("orders", &visibility_of(60)).await.unwrap().as_str(), ORDERS);
+        assert_eq!(actions(&stub), vec!["GetQueueUrl", "CreateQueue"]);
+        assert!(stub.requests()[1].body_str().contains("VisibilityTimeout"));
+    }
+
+    #[tokio::test]
+    async fn queue_urls_are_cached_until_the_queue_turns_out_to_be_missing() {
+        #[derive(Serialize, Deserialize)]
+        struct Order {}
+
+        impl Event for Order {
+            fn queue_url() -> &'static str {
+                "orders"
+            }
+        }
+
+        let recreated = "http://localhost:4566/000000000000/orders-recreated";
+        let stub = HttpStub::start(vec![
+            queue_url_response(ORDERS),
+            (400, error_response("AWS.SimpleQueueService.NonExistentQueue")),
+            queue_url_response(recreated),
+        ]).await;
+        let client = retrying_client(&stub).await;
+        assert_eq!(client.queue_url_for_name("orders").await.unwrap().as_str(), ORDERS);
+        assert_eq!(client.queue_url_for_name("orders").await.unwrap().as_str(), ORDERS);
+        assert_eq!(actions(&stub), vec!["GetQueueUrl"]);
+
+        // the cached URL is used until SQS says the queue is gone, and then forgotten
+        let options = ReceiveOptions{wait_time_seconds: 0, ..ReceiveOptions::default()};
+        let missing = client.poll_with_receipts::<Order>(&options).await;
+        assert!(matches!(missing, Err(EventfulError::QueueDoesNotExist(_))), "{:?}", missing.err());
+        assert_eq!(client.queue_url_for_name("orders").await.unwrap().as_str(), recreated);
+        assert_eq!(actions(&stub), vec!["GetQueueUrl", "ReceiveMessage", "GetQueueUrl"]);
+    }
+
+    fn region_of(client: &ClientSQS) -> Option<String> {
+        client.client().conf().region().map(|region| region.to_string())
+    }
+
+    #[tokio::test]
+    async fn a_client_takes_its_region_from_the_builder_then_the_environment() {
+        // the only test which changes these, so no other test sees them set
+        env::set_var("AWS_EC2_METADATA_DISABLED", "true");
+        env::set_var("AWS_CONFIG_FILE", "/nonexistent/eventful/config");
+        env::set_var("AWS_REGION", "eu-west-1");
+
+        let explicit = ClientSQS::builder().region("ap-south-1".to_string()).static_credentials("test", "test", None).build().await.unwrap();
+        assert_eq!(region_of(&explicit).as_deref(), Some("ap-south-1"));
+        assert_eq!(region_of(&ClientSQS::new_with_region("ca-central-1".to_string()).await).as_deref(), Some("ca-central-1"));
+        let from_env = ClientSQS::builder().static_credentials("test", "test", None).build().await.unwrap();
+        assert_eq!(region_of(&from_env).as_deref(), Some("eu-west-1"));
+
+        env::remove_var("AWS_REGION");
+        let no_region = ClientSQS::builder().static_credentials("test", "test", None).build().await;
+        assert!(matches!(&no_region, Err(EventfulError::Config(message)) if message.starts_with("no region for SQS")), "{:?}", no_region.err());
+        env::remove_var("AWS_CONFIG_FILE");
+        env::remove_var("AWS_EC2_METADATA_DISABLED");
+    }
+
+    #[tokio::test]
+    async fn a_json_value_is_checked_before_it_is_sent() {
+        let stub = HttpStub::start(Vec::new()).await;
+        let client = retrying_client(&stub).await;
+        let (standard, fifo) = (format!("{}/000000000000/orders", stub.url()), format!("{}/000000000000/orders.fifo", stub.url()));
+
+        let oversize = serde_json::json!({"padding": "x".repeat(MAX_MESSAGE_BYTES)});
+        let result = client.publish_json_value(&standard, &oversize, None).await;
+        assert!(matches!(&result, Err(EventfulError::Config(message)) if message.contains("SQS accepts at most")), "{:?}", result);
+        let result = client.publish_json_value(&fifo, &serde_json::json!({"id": 1}), None).await;
+        assert!(matches!(result, Err(EventfulError::FifoMismatch(_))), "{:?}", result);
+        let result = client.publish_json_value(&standard, &serde_json::json!({"id": 1}), Some("customer-42")).await;
+        assert!(matches!(result, Err(EventfulError::FifoMismatch(_))), "{:?}", result);
+        let result = client.publish_json_value("orders", &serde_json::json!({"id": 1}), None).await;
+        assert!(matches!(result, Err(EventfulError::InvalidQueueUrl(_))), "{:?}", result);
+        assert!(stub.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_json_value_gets_the_clients_fifo_dedup_id() {
+        let body = r#"{"id":1}"#;
+        let sent = format!(
+            "<SendMessageResponse><SendMessageResult><MessageId>m-1</MessageId><MD5OfMessageBody>{}</MD5OfMessageBody>\
+            <SequenceNumber>1</SequenceNumber></SendMessageResult><ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></SendMessageResponse>",
+            md5_hex(body));
+        let stub = HttpStub::start(vec![(200, sent)]).await;
+        let client = retrying_client(&stub).await.fifo_options(FifoOptions::default().dedup(DedupStrategy::HashBody));
+        let fifo = format!("{}/000000000000/orders.fifo", stub.url());
+        let receipt = client.publish_json_value(&fifo, &serde_json::json!({"id": 1}), Some("customer-42")).await.unwrap();
+        assert_eq!((receipt.message_id.as_str(), receipt.sequence_number.as_deref()), ("m-1", Some("1")));
+        let request = &stub.requests()[0];
+        assert!(request.body_str().contains("MessageGroupId=customer-42"));
+        assert!(request.body_str().contains(&format!("MessageDeduplicationId={}", dedup_hash(body))));
+    }
+
+    #[test]
+    fn failures_are_classified_by_code_then_status() {
+        assert_eq!(SqsFailure::from_response(Some("ThrottlingException"), 400), SqsFailure::Throttled);
+        assert_eq!(SqsFailure::from_response(None, 429), SqsFailure::Throttled);
+        assert_eq!(SqsFailure::from_response(Some("ServiceUnavailable"), 503), SqsFailure::Server);
+        assert_eq!(SqsFailure::from_response(None, 502), SqsFailure::Server);
+        assert_eq!(SqsFailure::from_response(Some("InvalidParameterValue"), 400), SqsFailure::Client);
+        assert!(SqsFailure::Throttled.is_retryable() && SqsFailure::Server.is_retryable() && SqsFailure::Timeout.is_retryable());
+        assert!(!SqsFailure::Client.is_retryable() && !SqsFailure::Construction.is_retryable());
+    }
+
+    /// Records what receipts_not_deleted reports for one queue, as the global observer
+    struct NotDeleted {
+        queue_url: String,
+        reported: Mutex<Vec<ReceiptsNotDeleted>>,
+    }
+
+    impl observer::EventfulObserver for NotDeleted {
+        fn receipts_not_deleted(&self, ctx: &ReceiptsNotDeleted) {
+            if ctx.source == Destination::SqsQueue(self.queue_url.clone()) {
+                self.reported.lock().unwrap().push(ctx.clone());
+            }
+        }
+    }
+
+    fn observe_not_deleted(queue_url: &str) -> Arc<NotDeleted> {
+        let observed = Arc::new(NotDeleted{queue_url: queue_url.to_string(), reported: Mutex::new(Vec::new())});
+        observer::set_global(observed.clone());
+        observed
+    }
+
+    #[tokio::test]
+    async fn messages_are_returned_when_some_deletes_on_receipt_fail() {
+        let deleted = "<DeleteMessageBatchResponse><DeleteMessageBatchResult>\
+            <DeleteMessageBatchResultEntry><Id>0</Id></DeleteMessageBatchResultEntry>\
+            <BatchResultErrorEntry><Id>1</Id><Code>ReceiptHandleIsInvalid</Code><Message>expired</Message><SenderFault>true</SenderFault></BatchResultErrorEntry>\
+            </DeleteMessageBatchResult><ResponseMetadata><RequestId>r-2</RequestId></ResponseMetadata></DeleteMessageBatchResponse>";
+        let stub = HttpStub::start(vec![(200, receive_response(&[r#"{"id":1}"#, r#"{"id":2}"#])), (200, deleted.to_string())]).await;
+        let client = stub_client(&stub).await;
+        let queue_url = format!("{}/000000000000/partial-delete", stub.url());
+        let observed = observe_not_deleted(&queue_url);
+
+        let messages = client.poll_messages_with(&queue_url, true, &ReceiveOptions{wait_time_seconds: 0, ..ReceiveOptions::default()}).await.unwrap();
+        assert_eq!(messages.iter().filter_map(Message::body).collect::<Vec<_>>(), vec![r#"{"id":1}"#, r#"{"id":2}"#]);
+        let reported = observed.reported.lock().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].receipt_handles, vec!["h-1".to_string()]);
+        assert!(reported[0].error.starts_with("1 of 2 received messages were not deleted"), "{}", reported[0].error);
+    }
+
+    #[tokio::test]
+    async fn events_are_returned_when_deleting_them_on_receipt_fails() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Click {
+            id: u32,
+        }
+
+        let refused = "<ErrorResponse><Error><Type>Sender</Type><Code>AccessDenied</Code><Message>not allowed to delete</Message></Error>\
+            <RequestId>r-2</RequestId></ErrorResponse>";
+        let stub = HttpStub::start(vec![(200, receive_response(&[r#"{"id":1}"#, "garbage"])), (403, refused.to_string())]).await;
+        let client = stub_client(&stub).await;
+        let queue_url = format!("{}/000000000000/refused-delete", stub.url());
+        let observed = observe_not_deleted(&queue_url);
+
+        let options = ReceiveOptions{wait_time_seconds: 0, ..ReceiveOptions::default()};
+        let (events, undecodable) = client.poll_events::<Click>(&queue_url, true, &options, &OnUndecodable::Return).await.unwrap();
+        assert_eq!(events, vec![Click{id: 1}]);
+        assert_eq!(undecodable.len(), 1);
+        // only the decoded message was to be deleted
+        let reported = observed.reported.lock().unwrap();
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].receipt_handles, vec!["h-0".to_string()]);
+    }
+}
+
+
+/// Needs localstack (LOCALSTACK_ENDPOINT, default http://localhost:4566). Run with `cargo test --features testing -- --ignored`
+#[cfg(all(test, feature = "testing"))]
+mod integration {
+    use super::*;
+    use crate::testing::LocalstackSqs;
+
+    #[tokio::test]
+    #[ignore = "needs localstack"]
+    async fn publishes_and_receives_through_a_custom_endpoint() {
+        let localstack = LocalstackSqs::from_env().await.unwrap();
+        let queue = localstack.create_temp_queue("endpoint").await.unwrap();
+        let queue_url = QueueUrl::parse(queue.url()).unwrap();
+
+        let client = ClientSQS::builder()
+            .region("us-east-1".to_string())
+            .endpoint_url(localstack.endpoint().to_string())
+            .static_credentials("test", "test", None)
+            .build().await.unwrap();
+        let body = Bytes::from_static(br#"{"id":1}"#);
+        let receipt = client.publish_bytes(&Destination::SqsQueue(queue_url.to_string()), body, &Metadata::default()).await.unwrap();
+        let received = client.receive_messages(&queue_url, 10, 5).await.unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].message_id(), receipt.message_id.as_deref());
+        assert_eq!(received[0].body(), Some(r#"{"id":1}"#));
+        client.delete_message(&queue_url, received[0].receipt_handle().unwrap()).await.unwrap();
+        assert!(client.receive_messages(&queue_url, 10, 0).await.unwrap().is_empty());
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Numbered {
+        n: usize,
+        padding: String,
+        #[serde(skip)]
+        queue: String,
+    }
+
+    impl Event for Numbered {
+        fn queue_url() -> &'static str {
+            "unused"
+        }
+        fn queue_for(&self) -> Result<QueueRef, EventfulError> {
+            QueueRef::parse(&self.queue)
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs localstack"]
+    async fn publish_batch_reports_entries_by_their_index() {
+        let localstack = LocalstackSqs::from_env().await.unwrap();
+        let client = localstack.client();
+        let queue = localstack.create_temp_queue("batch-index").await.unwrap();
+        let too_large = [3, 17];
+        // 25 events go out in three calls, with two rejected before sending
+        let events: Vec<Numbered> = (0..25)
+            .map(|n| Numbered{
+                n,
+                padding: if too_large.contains(&n) { "x".repeat(MAX_MESSAGE_BYTES) } else { String::new() },
+                queue: queue.url().to_string(),
+            })
+            .collect();
+
+        let result = client.publish_batch(&events).await.unwrap();
+        let sent: Vec<usize> = (0..25).filter(|n| !too_large.contains(n)).collect();
+        assert_eq!(result.succeeded.iter().map(|(i, _)| *i).collect::<Vec<_>>(), sent);
+        assert_eq!(result.failed.iter().map(|(i, _)| *i).collect::<Vec<_>>(), too_large.to_vec());
+        assert!(result.failed.iter().all(|(_, err)| err.code == "MessageTooLarge" && err.sender_fault));
+        assert_eq!(result.retryable().count(), 0);
+        assert_eq!(result.retry_failed(client).await.unwrap(), result);
+
+        // each receipt is for the event at its index
+        let mut ids_by_index = HashMap::new();
+        for (i, receipt) in &result.succeeded {
+            ids_by_index.insert(receipt.message_id.clone(), *i);
+        }
+        let mut received = 0;
+        while received < sent.len() {
+            let messages = client.receive_messages(queue.url(), 10, 1).await.unwrap();
+            assert!(!messages.is_empty(), "only {} of {} messages arrived", received, sent.len());
+            for message in messages {
+                let event: Numbered = serde_json::from_str(message.body().unwrap()).unwrap();
+                assert_eq!(ids_by_index.get(message.message_id().unwrap()), Some(&event.n));
+                client.delete_message(queue.url(), message.receipt_handle().unwrap()).await.unwrap();
+                received += 1;
+            }
+        }
+    }
+
+    async fn send_kinds(localstack: &LocalstackSqs, queue_url: &str, kinds: &[&str]) {
+        for kind in kinds {
+            localstack.client().client().send_message()
+                .queue_url(queue_url)
+                .message_body(format!(r#"{{"kind":"{}"}}"#, kind))
+                .message_attributes("kind", MessageAttributeValue::builder().data_type("String").string_value(*kind).build())
+                .send().await.unwrap();
+        }
+    }
+
+    fn kinds(messages: &[Message]) -> Vec<String> {
+        let mut kinds: Vec<String> = messages.iter()
+            .filter_map(|message| Attributes::from_message(message).get_str("kind").map(str::to_string))
+            .collect();
+        kinds.sort();
+        kinds
+    }
+
+    #[tokio::test]
+    #[ignore = "needs localstack"]
+    async fn receive_filter_releases_or_drops_mismatches() {
+        let localstack = LocalstackSqs::from_env().await.unwrap();
+        let client = localstack.client();
+        let everything = ReceiveOptions { wait_time_seconds: 1, message_attribute_names: vec!["All".to_string()], ..ReceiveOptions::default() };
+
+        // released messages are left for a worker with another filter
+        let queue = localstack.create_temp_queue("filter-release").await.unwrap();
+        send_kinds(&localstack, queue.url(), &["refund", "order"]).await;
+        let refunds = ReceiveOptions {
+            wait_time_seconds: 1,
+            filter: Some(ReceiveFilter::new().equals("kind", "refund")),
+            ..ReceiveOptions::default()
+        };
+        let mut matched = Vec::new();
+        for _ in 0..5 {
+            matched.extend(client.poll_messages_with(queue.url(), true, &refunds).await.unwrap());
+        }
+        assert_eq!(kinds(&matched), vec!["refund"]);
+        let rest = client.poll_messages_with(queue.url(), true, &everything).await.unwrap();
+        assert_eq!(kinds(&rest), vec!["order"]);
+
+        // dropped messages are deleted
+        let queue = localstack.create_temp_queue("filter-drop").await.unwrap();
+        send_kinds(&localstack, queue.url(), &["refund", "order"]).await;
+        let refunds = ReceiveOptions {
+            filter: Some(ReceiveFilter::new().equals("kind", "refund").on_mismatch(OnMismatch::Drop)),
+            ..refunds
+        };
+        let mut matched = Vec::new();
+        for _ in 0..5 {
+            matched.extend(client.poll_messages_with(queue.url(), true, &refunds).await.unwrap());
+        }
+        assert_eq!(kinds(&matched), vec!["refund"]);
+        assert!(client.poll_messages_with(queue.url(), true, &everything).await.unwrap().is_empty());
+    }
+
+    struct SlowConsumer(String);
+
+    impl QueueConsumer<Numbered> for SlowConsumer {
+        fn queue(&self) -> Result<QueueRef, EventfulError> {
+            QueueRef::parse(&self.0)
+        }
+        fn receive_options(&self) -> ReceiveOptions {
+            ReceiveOptions { wait_time_seconds: 1, ..ReceiveOptions::default() }
+        }
+        fn drain_timeout(&self) -> Duration {
+            Duration::from_secs(1)
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs localstack"]
+    async fn shutdown_hands_a_slow_handlers_message_to_the_next_consumer() {
+        let localstack = LocalstackSqs::from_env().await.unwrap();
+        let client = localstack.client();
+        let queue = localstack.create_temp_queue("slow-shutdown").await.unwrap();
+        client.publish(&Numbered{n: 1, padding: String::new(), queue: queue.url().to_string()}).await.unwrap();
+
+        let shutdown = CancellationToken::new();
+        let cancel = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            cancel.cancel();
+        });
+        let handler = |_: Numbered| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(())
+        };
+        SlowConsumer(queue.url().to_string()).run_until(client, &ConsumerOptions::default(), handler, shutdown).await.unwrap();
+
+        // well within the queue's 30 second visibility timeout, the next consumer takes the message on its second attempt
+        let released = client.receive_messages(queue.url(), 10, 1).await.unwrap();
+        assert_eq!(released.len(), 1);
+        assert_eq!(receive_count(&released[0]), 2);
+    }
 }
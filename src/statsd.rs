@@ -0,0 +1,280 @@
+//! The statsd module ships eventful's metrics over UDP in the [DogStatsD](https://docs.datadoghq.com/developers/dogstatsd/datagram_shell/)
+//! format, for services which push to a Datadog (or other StatsD) agent rather than being scraped. It is enabled by the `statsd` feature.
+//!
+//! StatsdMetrics implements EventfulMetrics by formatting each metric as a line and buffering it; a background task
+//! packs the buffered lines into datagrams no larger than the UDP MTU and sends them every interval.
+//! Nothing on the publish or consume path touches the socket: when the buffer is full or a send fails,
+//! the lines are dropped and counted (see dropped()).
+//!
+//! Metrics are {prefix}.published, .publish_latency_ms, .publish_attempt_ms, .published_bytes, .consumed, .failed, .dead_lettered,
+//! .throttled, .backpressure_ms, .inflight, .intake_depth, .lag_waiting, and .lag_in_flight, tagged with topic plus any constant tags.
+//!
+//! # Examples:
+//! ```no_run
+//! # use std::time::Duration;
+//! # use eventful::statsd::StatsdMetrics;
+//! # async fn demo() -> Result<(), eventful::err::EventfulError> {
+//! let statsd = StatsdMetrics::new("eventful")
+//!     .tag("service", "click-api")
+//!     .tag("env", "prod")
+//!     .start("127.0.0.1:8125", Duration::from_secs(1)).await?;
+//! eventful::metrics::set_global(statsd);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{sync::{Arc, Mutex, Weak, atomic::{AtomicU64, Ordering}}, time::Duration};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use crate::err::EventfulError;
+use crate::metrics::EventfulMetrics;
+
+
+/// Formats metrics as DogStatsD lines and sends them in batches from a background task
+pub struct StatsdMetrics {
+    prefix: String,
+    tags: Vec<String>,
+    max_packet_bytes: usize,
+    max_buffered: usize,
+    buffer: Mutex<Vec<String>>,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl StatsdMetrics {
+    /// Every metric name starts with `prefix.`
+    pub fn new(prefix: &str) -> Self {
+        StatsdMetrics{
+            prefix: prefix.trim_end_matches('.').to_string(),
+            tags: Vec::new(),
+            max_packet_bytes: 1432,
+            max_buffered: 10_000,
+            buffer: Mutex::new(Vec::new()),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// A tag added to every metric
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.push(format!("{}:{}", sanitize(key), sanitize(value)));
+        self
+    }
+
+    /// The largest datagram sent (default 1432 bytes, which fits a 1500 byte MTU)
+    pub fn max_packet_bytes(mut self, max: usize) -> Self {
+        self.max_packet_bytes = max.max(64);
+        self
+    }
+
+    /// How many lines can wait for the next flush before new ones are dropped (default 10,000)
+    pub fn max_buffered(mut self, max: usize) -> Self {
+        self.max_buffered = max;
+        self
+    }
+
+    /// Bind a UDP socket, send to the agent at addr, and flush every interval from a background task.
+    /// The task stops once every clone of the returned Arc has been dropped
+    pub async fn start<A: ToSocketAddrs>(self, addr: A, interval: Duration) -> Result<Arc<Self>, EventfulError> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+        let metrics = Arc::new(self);
+        let weak = Arc::downgrade(&metrics);
+        tokio::spawn(flush_loop(weak, socket, interval));
+        Ok(metrics)
+    }
+
+    /// How many lines have been sent
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// How many lines were dropped because the buffer was full or the socket failed
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Format one line, i.e. "eventful.published:1|c|#topic:click,env:prod"
+    pub fn line(&self, name: &str, value: &str, kind: &str, topic: &str) -> String {
+        let mut line = format!("{}.{}:{}|{}|#topic:{}", self.prefix, name, value, kind, sanitize(topic));
+        for tag in &self.tags {
+            line.push(',');
+            line.push_str(tag);
+        }
+        line
+    }
+
+    fn push(&self, line: String) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.len() >= self.max_buffered {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return
+        }
+        buffer.push(line);
+    }
+
+    /// Pack the buffered lines into datagrams of at most max_packet_bytes
+    fn take_packets(&self) -> Vec<(String, u64)> {
+        let lines = std::mem::take(&mut *self.buffer.lock().unwrap());
+        let mut packets = Vec::new();
+        let mut packet = String::new();
+        let mut count = 0;
+        for line in lines {
+            if line.len() > self.max_packet_bytes {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                continue
+            }
+            if !packet.is_empty() && packet.len() + 1 + line.len() > self.max_packet_bytes {
+                packets.push((std::mem::take(&mut packet), count));
+                count = 0;
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+            count += 1;
+        }
+        if !packet.is_empty() {
+            packets.push((packet, count));
+        }
+        packets
+    }
+
+    async fn flush(&self, socket: &UdpSocket) {
+        for (packet, count) in self.take_packets() {
+            match socket.send(packet.as_bytes()).await {
+                Ok(_) => self.sent.fetch_add(count, Ordering::Relaxed),
+                Err(_) => self.dropped.fetch_add(count, Ordering::Relaxed),
+            };
+        }
+    }
+}
+
+async fn flush_loop(metrics: Weak<StatsdMetrics>, socket: UdpSocket, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match metrics.upgrade() {
+            Some(metrics) => metrics.flush(&socket).await,
+            None => return,
+        }
+    }
+}
+
+/// Characters with a meaning in DogStatsD lines are replaced with '_'. That includes ':', which separates
+/// a tag's key from its value, so a topic like an SQS queue URL can't be read as a different tag
+fn sanitize(value: &str) -> String {
+    value.chars().map(|c| if matches!(c, '|' | ',' | '#' | ':' | '\n') { '_' } else { c }).collect()
+}
+
+
+impl EventfulMetrics for StatsdMetrics {
+    fn inc_published(&self, topic: &str) {
+        self.push(self.line("published", "1", "c", topic));
+    }
+
+    fn observe_publish_latency(&self, topic: &str, duration: Duration) {
+        let millis = duration.as_secs_f64() * 1000.0;
+        self.push(self.line("publish_latency_ms", &format!("{:.3}", millis), "h", topic));
+    }
+
+    fn observe_publish_attempt(&self, topic: &str, duration: Duration) {
+        let millis = duration.as_secs_f64() * 1000.0;
+        self.push(self.line("publish_attempt_ms", &format!("{:.3}", millis), "h", topic));
+    }
+
+    fn add_published_bytes(&self, topic: &str, bytes: u64) {
+        self.push(self.line("published_bytes", &bytes.to_string(), "c", topic));
+    }
+
+    fn inc_consumed(&self, topic: &str) {
+        self.push(self.line("consumed", "1", "c", topic));
+    }
+
+    fn inc_failed(&self, topic: &str) {
+        self.push(self.line("failed", "1", "c", topic));
+    }
+
+    fn inc_dead_lettered(&self, topic: &str) {
+        self.push(self.line("dead_lettered", "1", "c", topic));
+    }
+
+    fn set_inflight(&self, topic: &str, count: usize) {
+        self.push(self.line("inflight", &count.to_string(), "g", topic));
+    }
+
+    fn set_lag(&self, source: &str, waiting: u64, in_flight: u64) {
+        self.push(self.line("lag_waiting", &waiting.to_string(), "g", source));
+        self.push(self.line("lag_in_flight", &in_flight.to_string(), "g", source));
+    }
+
+    fn inc_throttled(&self, topic: &str) {
+        self.push(self.line("throttled", "1", "c", topic));
+    }
+
+    fn set_intake_depth(&self, topic: &str, depth: usize) {
+        self.push(self.line("intake_depth", &depth.to_string(), "g", topic));
+    }
+
+    fn add_backpressure_time(&self, topic: &str, waited: Duration) {
+        self.push(self.line("backpressure_ms", &waited.as_millis().to_string(), "c", topic));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_dogstatsd_with_sanitized_tags() {
+        let statsd = StatsdMetrics::new("eventful.").tag("service", "click-api").tag("env|x", "prod:eu,1");
+        assert_eq!(statsd.line("published", "1", "c", "click"), "eventful.published:1|c|#topic:click,service:click-api,env_x:prod_eu_1");
+        assert_eq!(
+            statsd.line("lag_waiting", "7", "g", "https://sqs.us-east-1.amazonaws.com/123/orders#1"),
+            "eventful.lag_waiting:7|g|#topic:https_//sqs.us-east-1.amazonaws.com/123/orders_1,service:click-api,env_x:prod_eu_1",
+        );
+    }
+
+    #[test]
+    fn buffered_lines_are_packed_into_datagrams_up_to_the_limit() {
+        let statsd = StatsdMetrics::new("e").max_packet_bytes(64).max_buffered(5);
+        for _ in 0..6 {
+            statsd.inc_consumed("click");
+        }
+        // "e.consumed:1|c|#topic:click" is 27 bytes, so two fit in each 64 byte datagram
+        let packets = statsd.take_packets();
+        assert_eq!(packets.iter().map(|(_, count)| *count).collect::<Vec<_>>(), vec![2, 2, 1]);
+        assert!(packets.iter().all(|(packet, _)| packet.len() <= 64));
+        assert_eq!(packets[0].0, "e.consumed:1|c|#topic:click\ne.consumed:1|c|#topic:click");
+        assert_eq!(statsd.dropped(), 1);
+        assert!(statsd.take_packets().is_empty());
+    }
+
+    #[tokio::test]
+    async fn metrics_arrive_over_udp() {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let statsd = StatsdMetrics::new("eventful").tag("env", "test")
+            .start(agent.local_addr().unwrap(), Duration::from_millis(10)).await.unwrap();
+        statsd.inc_published("click");
+        statsd.observe_publish_latency("click", Duration::from_micros(1500));
+        statsd.set_lag("orders", 12, 3);
+        statsd.add_backpressure_time("click", Duration::from_millis(250));
+
+        let mut datagram = vec![0; 1500];
+        let len = tokio::time::timeout(Duration::from_secs(5), agent.recv(&mut datagram)).await.unwrap().unwrap();
+        let lines = std::str::from_utf8(&datagram[..len]).unwrap().lines().collect::<Vec<_>>();
+        assert_eq!(lines, vec![
+            "eventful.published:1|c|#topic:click,env:test",
+            "eventful.publish_latency_ms:1.500|h|#topic:click,env:test",
+            "eventful.lag_waiting:12|g|#topic:orders,env:test",
+            "eventful.lag_in_flight:3|g|#topic:orders,env:test",
+            "eventful.backpressure_ms:250|c|#topic:click,env:test",
+        ]);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while statsd.sent() < 5 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await.unwrap();
+        assert_eq!(statsd.dropped(), 0);
+    }
+}
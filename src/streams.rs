@@ -0,0 +1,465 @@
+//! The streams module produces and consumes events with [Redis Streams](https://redis.io/docs/data-types/streams/),
+//! for small deployments already running Redis where NSQ or SQS would be overkill. It is enabled by the `redis` feature.
+//! 
+//! # Delivery guarantees
+//! Like NSQ and SQS, delivery is at-least-once:
+//! - An entry read with XREADGROUP stays in the group's pending entries list until it is acked (XACK).
+//! - If a consumer crashes or nacks, the entry is claimed (XAUTOCLAIM) by a consumer in the group once it has been idle for `claim_idle`,
+//!   so redelivery happens after claim_idle rather than after the nack delay (the delay passed to nack is ignored).
+//! - Unlike SQS there is no receive count limit or redrive to a DLQ; use ConsumerOptions::dead_letter, which counts deliveries via XPENDING.
+//! - Entries are ordered within a stream, like a FIFO queue with a single group id, but redelivered entries arrive out of order.
+//! - Streams live in Redis memory, so they are only as durable as your Redis persistence (AOF/RDB) settings.
+//!   Trim them with Trim::MaxLen or they grow forever.
+//! 
+//! # Examples:
+//! ```no_run
+//! # use eventful::prelude::*;
+//! # use eventful::streams::{EventRedis, PublisherRedis, Trim};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct UserClickedSomething { user_id: i32 }
+//! impl EventRedis for UserClickedSomething {
+//!     fn stream_key() -> &'static str {
+//!         "website_clicks"
+//!     }
+//! }
+//!
+//! # async fn demo(click: UserClickedSomething) -> Result<(), Box<dyn std::error::Error>> {
+//! let conn = redis::Client::open("redis://127.0.0.1/")?.get_connection_manager().await?;
+//! let publisher = PublisherRedis::new(conn.clone()).trim(Trim::MaxLen{len: 100_000, approximate: true});
+//! publisher.publish(&click).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::VecDeque, future::Future, time::{Duration, Instant}};
+use async_trait::async_trait;
+use bytes::Bytes;
+use redis::{FromRedisValue, Value, aio::ConnectionManager, streams::{StreamClaimReply, StreamId, StreamReadReply}};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::codec::{Codec, JsonCodec};
+use crate::consumer::{self, ConsumerOptions};
+use crate::err::EventfulError;
+use crate::idempotency::IdempotencyGuard;
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+use crate::subscriber::{Ack, Delivery, Subscriber};
+
+
+/// The field of each stream entry holding the serialized event
+const BODY_FIELD: &str = "body";
+/// Metadata headers are stored as fields prefixed with this
+const HEADER_PREFIX: &str = "h:";
+
+
+/// Implement EventRedis on a struct to publish it to a stream
+pub trait EventRedis: Serialize + DeserializeOwned {
+    fn stream_key() -> &'static str;
+}
+
+
+/// How XADD trims the stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trim {
+    /// Never trim
+    None,
+    /// Keep about `len` entries. approximate (MAXLEN ~) is much cheaper and usually what you want
+    MaxLen { len: usize, approximate: bool },
+}
+
+
+/// The PublisherRedis appends events to streams with XADD
+#[derive(Clone)]
+pub struct PublisherRedis {
+    conn: ConnectionManager,
+    trim: Trim,
+}
+
+impl PublisherRedis {
+    pub fn new(conn: ConnectionManager) -> Self {
+        PublisherRedis{conn, trim: Trim::None}
+    }
+
+    pub fn trim(mut self, trim: Trim) -> Self {
+        self.trim = trim;
+        self
+    }
+
+    /// Append raw bytes to a stream, returning the entry id
+    pub async fn publish_raw(&self, stream_key: &str, body: &[u8], meta: &Metadata) -> Result<String, EventfulError> {
+        let mut conn = self.conn.clone();
+        let id: String = xadd(stream_key, self.trim, body, meta).query_async(&mut conn).await?;
+        Ok(id)
+    }
+
+    /// Serialize an event and append it to its stream
+    pub async fn publish<T: EventRedis>(&self, event: &T) -> Result<String, EventfulError> {
+        let body = JsonCodec.encode(event)?;
+        self.publish_raw(<T as EventRedis>::stream_key(), &body, &Metadata::default()).await
+    }
+}
+
+/// The XADD appending one entry: the body, then each header as its own field
+fn xadd(stream_key: &str, trim: Trim, body: &[u8], meta: &Metadata) -> redis::Cmd {
+    let mut cmd = redis::cmd("XADD");
+    cmd.arg(stream_key);
+    if let Trim::MaxLen{len, approximate} = trim {
+        cmd.arg("MAXLEN");
+        if approximate {
+            cmd.arg("~");
+        }
+        cmd.arg(len);
+    }
+    cmd.arg("*").arg(BODY_FIELD).arg(body);
+    for (key, value) in &meta.headers {
+        cmd.arg(format!("{}{}", HEADER_PREFIX, key)).arg(value);
+    }
+    cmd
+}
+
+/// The body and headers of an entry written by xadd()
+fn read_entry(entry: &StreamId) -> Result<(Bytes, Metadata), EventfulError> {
+    let mut body = Bytes::new();
+    let mut meta = Metadata::default();
+    for (field, value) in &entry.map {
+        let value: Vec<u8> = FromRedisValue::from_redis_value(value)?;
+        if field == BODY_FIELD {
+            body = Bytes::from(value);
+        } else if let Some(header) = field.strip_prefix(HEADER_PREFIX) {
+            meta.headers.insert(header.to_string(), String::from_utf8_lossy(&value).into_owned());
+        }
+    }
+    Ok((body, meta))
+}
+
+/// XAUTOCLAIM replies with the cursor to continue from, the claimed entries, and (since Redis 7) the ids of deleted entries
+fn parse_autoclaim(reply: &Value) -> Result<(String, Vec<StreamId>), EventfulError> {
+    match reply {
+        Value::Bulk(items) if items.len() >= 2 => {
+            Ok((String::from_redis_value(&items[0])?, StreamClaimReply::from_redis_value(&items[1])?.ids))
+        },
+        _ => Err(EventfulError::Destination("unexpected XAUTOCLAIM reply".to_string())),
+    }
+}
+
+#[async_trait]
+impl Publisher for PublisherRedis {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let stream_key = match dest {
+            Destination::RedisStream(key) => key,
+            _ => return Err(publisher::unsupported("PublisherRedis", dest)),
+        };
+        let id = self.publish_raw(stream_key, &body, meta).await?;
+        Ok(Receipt{message_id: Some(id)})
+    }
+}
+
+
+/// A SubscriptionRedis reads a stream as one consumer in a consumer group, and implements the Subscriber trait.
+/// The group is created (along with the stream) the first time next() is called, if it doesn't already exist
+pub struct SubscriptionRedis {
+    conn: ConnectionManager,
+    stream_key: String,
+    group: String,
+    consumer: String,
+    buffer: VecDeque<(StreamId, u32)>,
+    count: usize,
+    block: Duration,
+    claim_idle: Duration,
+    claim_cursor: String,
+    last_claim: Option<Instant>,
+    group_ready: bool,
+}
+
+impl SubscriptionRedis {
+    /// consumer names must be unique within the group, i.e. the hostname or pod name
+    pub fn new(conn: ConnectionManager, stream_key: &str, group: &str, consumer: &str) -> Self {
+        SubscriptionRedis{
+            conn,
+            stream_key: stream_key.to_string(),
+            group: group.to_string(),
+            consumer: consumer.to_string(),
+            buffer: VecDeque::new(),
+            count: 10,
+            block: Duration::from_secs(5),
+            claim_idle: Duration::from_secs(60),
+            claim_cursor: "0-0".to_string(),
+            last_claim: None,
+            group_ready: false,
+        }
+    }
+
+    /// The most entries read by each XREADGROUP or XAUTOCLAIM (default 10)
+    pub fn count(mut self, count: usize) -> Self {
+        self.count = count.max(1);
+        self
+    }
+
+    /// How long each XREADGROUP blocks waiting for new entries (default 5 seconds)
+    pub fn block(mut self, block: Duration) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// How long an entry must be pending before another consumer claims it (default 60 seconds).
+    /// This should comfortably exceed your slowest handler, or entries will be processed twice
+    pub fn claim_idle(mut self, claim_idle: Duration) -> Self {
+        self.claim_idle = claim_idle;
+        self
+    }
+
+    async fn ensure_group(&mut self) -> Result<(), EventfulError> {
+        if self.group_ready {
+            return Ok(())
+        }
+        let created: Result<(), redis::RedisError> = redis::cmd("XGROUP")
+            .arg("CREATE").arg(&self.stream_key).arg(&self.group).arg("0").arg("MKSTREAM")
+            .query_async(&mut self.conn).await;
+        match created {
+            Ok(()) => {},
+            // the group already exists
+            Err(err) if err.code() == Some("BUSYGROUP") => {},
+            Err(err) => return Err(err.into()),
+        }
+        self.group_ready = true;
+        Ok(())
+    }
+
+    /// Claim entries pending longer than claim_idle, i.e. from a crashed consumer. Runs at most once per claim_idle
+    async fn claim(&mut self) -> Result<(), EventfulError> {
+        if self.last_claim.map(|at| at.elapsed() < self.claim_idle).unwrap_or(false) {
+            return Ok(())
+        }
+        let reply: Value = redis::cmd("XAUTOCLAIM")
+            .arg(&self.stream_key).arg(&self.group).arg(&self.consumer)
+            .arg(self.claim_idle.as_millis() as u64)
+            .arg(&self.claim_cursor)
+            .arg("COUNT").arg(self.count)
+            .query_async(&mut self.conn).await?;
+        let (cursor, entries) = parse_autoclaim(&reply)?;
+        // a full sweep of the pending entries list ends with cursor 0-0
+        if cursor == "0-0" {
+            self.last_claim = Some(Instant::now());
+        }
+        self.claim_cursor = cursor;
+        for entry in entries {
+            let attempt = self.delivery_count(&entry.id).await?;
+            self.buffer.push_back((entry, attempt));
+        }
+        Ok(())
+    }
+
+    /// How many times an entry has been delivered, from XPENDING
+    async fn delivery_count(&mut self, id: &str) -> Result<u32, EventfulError> {
+        let pending: Vec<(String, String, u64, u32)> = redis::cmd("XPENDING")
+            .arg(&self.stream_key).arg(&self.group).arg(id).arg(id).arg(1)
+            .query_async(&mut self.conn).await?;
+        Ok(pending.first().map(|(_, _, _, count)| *count).unwrap_or(1))
+    }
+
+    async fn read_new(&mut self) -> Result<(), EventfulError> {
+        let reply: Option<StreamReadReply> = redis::cmd("XREADGROUP")
+            .arg("GROUP").arg(&self.group).arg(&self.consumer)
+            .arg("COUNT").arg(self.count)
+            .arg("BLOCK").arg(self.block.as_millis() as u64)
+            .arg("STREAMS").arg(&self.stream_key).arg(">")
+            .query_async(&mut self.conn).await?;
+        for key in reply.map(|reply| reply.keys).unwrap_or_default() {
+            self.buffer.extend(key.ids.into_iter().map(|entry| (entry, 1)));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Subscriber for SubscriptionRedis {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        self.ensure_group().await?;
+        loop {
+            if let Some((entry, attempt)) = self.buffer.pop_front() {
+                let (body, meta) = read_entry(&entry)?;
+                let source = Destination::RedisStream(self.stream_key.clone());
+                let ack = AckRedis{conn: self.conn.clone(), stream_key: self.stream_key.clone(), group: self.group.clone(), id: entry.id.clone()};
+                return Ok(Some(Delivery::new(source, body, meta, Some(entry.id), attempt, Box::new(ack))))
+            }
+            self.claim().await?;
+            if self.buffer.is_empty() {
+                self.read_new().await?;
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("{} (group {})", Destination::RedisStream(self.stream_key.clone()), self.group)
+    }
+}
+
+
+/// ack() is XACK. nack() leaves the entry pending, so it is claimed again once idle for claim_idle
+struct AckRedis {
+    conn: ConnectionManager,
+    stream_key: String,
+    group: String,
+    id: String,
+}
+
+#[async_trait]
+impl Ack for AckRedis {
+    async fn ack(mut self: Box<Self>) -> Result<(), EventfulError> {
+        let _: u64 = redis::cmd("XACK").arg(&self.stream_key).arg(&self.group).arg(&self.id).query_async(&mut self.conn).await?;
+        Ok(())
+    }
+
+    async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+        Ok(())
+    }
+}
+
+
+/// The GroupReader trait mirrors ChannelConsumer for a Redis stream consumer group
+#[async_trait]
+pub trait GroupReader<T: EventRedis> {
+
+    /// This method must be implemented to set the consumer group
+    fn group(&self) -> String;
+
+    /// The consumer name within the group, which must be unique per process (default: the HOSTNAME environment variable)
+    fn consumer_name(&self) -> String {
+        std::env::var("HOSTNAME").unwrap_or_else(|_| format!("eventful-{}", std::process::id()))
+    }
+
+    /// Wrap the group in a SubscriptionRedis, which implements the transport-agnostic Subscriber trait
+    fn subscribe(&self, conn: ConnectionManager) -> SubscriptionRedis {
+        SubscriptionRedis::new(conn, <T as EventRedis>::stream_key(), &self.group(), &self.consumer_name())
+    }
+
+    /// Run the handler loop from the consumer module over this group.
+    /// Entries which fail options.retry.max_attempts times go to options.dead_letter
+    async fn run<H, Fut>(&self, conn: ConnectionManager, options: &ConsumerOptions, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        consumer::run(Box::new(self.subscribe(conn)), options, handler).await
+    }
+
+    /// Like run(), but events whose key has already been processed are acked without calling the handler
+    async fn run_idempotent<K, H, Fut>(&self, conn: ConnectionManager, options: &ConsumerOptions, guard: &IdempotencyGuard, key: K, handler: H) -> Result<(), EventfulError>
+    where
+        Self: Sync,
+        T: Send,
+        K: Fn(&T) -> String + Send + Sync,
+        H: Fn(T) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<(), EventfulError>> + Send,
+    {
+        consumer::run_idempotent(Box::new(self.subscribe(conn)), options, guard, key, handler).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(cmd: &redis::Cmd) -> Vec<String> {
+        cmd.args_iter().map(|arg| match arg {
+            redis::Arg::Simple(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            redis::Arg::Cursor => "<cursor>".to_string(),
+        }).collect()
+    }
+
+    fn data(value: &str) -> Value {
+        Value::Data(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn xadd_trims_only_when_asked() {
+        let meta = Metadata::default();
+        assert_eq!(args(&xadd("clicks", Trim::None, b"{}", &meta)), vec!["XADD", "clicks", "*", "body", "{}"]);
+        let approximate = xadd("clicks", Trim::MaxLen{len: 1000, approximate: true}, b"{}", &meta);
+        assert_eq!(args(&approximate), vec!["XADD", "clicks", "MAXLEN", "~", "1000", "*", "body", "{}"]);
+        let exact = xadd("clicks", Trim::MaxLen{len: 10, approximate: false}, b"{}", &meta);
+        assert_eq!(args(&exact), vec!["XADD", "clicks", "MAXLEN", "10", "*", "body", "{}"]);
+    }
+
+    #[test]
+    fn headers_round_trip_through_entry_fields() {
+        let mut meta = Metadata::default();
+        meta.headers.insert("traceparent".to_string(), "00-abc-01".to_string());
+        let cmd = xadd("clicks", Trim::None, br#"{"user_id":5}"#, &meta);
+        assert_eq!(args(&cmd)[5..], ["h:traceparent".to_string(), "00-abc-01".to_string()]);
+        // what XREADGROUP would hand back for that entry, plus a field written by something else
+        let entry = StreamId{id: "1-0".to_string(), map: HashMap::from([
+            ("body".to_string(), data(r#"{"user_id":5}"#)),
+            ("h:traceparent".to_string(), data("00-abc-01")),
+            ("other".to_string(), data("ignored")),
+        ])};
+        let (body, read) = read_entry(&entry).unwrap();
+        assert_eq!(body, Bytes::from_static(br#"{"user_id":5}"#));
+        assert_eq!(read.headers, meta.headers);
+    }
+
+    #[test]
+    fn autoclaim_replies_with_and_without_deleted_ids() {
+        let entry = Value::Bulk(vec![data("1-0"), Value::Bulk(vec![data("body"), data("{}")])]);
+        // Redis 6.2 replies with two elements, Redis 7 adds a third listing deleted entries
+        for reply in [
+            Value::Bulk(vec![data("0-0"), Value::Bulk(vec![entry.clone()])]),
+            Value::Bulk(vec![data("0-0"), Value::Bulk(vec![entry.clone()]), Value::Bulk(vec![])]),
+        ] {
+            let (cursor, entries) = parse_autoclaim(&reply).unwrap();
+            assert_eq!(cursor, "0-0");
+            assert_eq!(entries.iter().map(|entry| entry.id.as_str()).collect::<Vec<_>>(), vec!["1-0"]);
+        }
+        let (cursor, entries) = parse_autoclaim(&Value::Bulk(vec![data("5-3"), Value::Bulk(vec![])])).unwrap();
+        assert_eq!((cursor.as_str(), entries.len()), ("5-3", 0));
+        assert!(matches!(parse_autoclaim(&Value::Nil), Err(EventfulError::Destination(_))));
+    }
+}
+
+
+/// Needs redis (REDIS_URL, default redis://localhost:6379)
+#[cfg(test)]
+mod redis_integration {
+    use super::*;
+
+    async fn connect() -> ConnectionManager {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string());
+        redis::Client::open(url).unwrap().get_connection_manager().await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs redis"]
+    async fn nacked_entries_are_claimed_again_with_their_delivery_count() {
+        let conn = connect().await;
+        let stream_key = format!("eventful-test-{}-{}", std::process::id(), rand::random::<u32>());
+        let publisher = PublisherRedis::new(conn.clone());
+        let mut meta = Metadata::default();
+        meta.headers.insert("traceparent".to_string(), "00-abc-01".to_string());
+        let first = publisher.publish_raw(&stream_key, b"1", &meta).await.unwrap();
+        publisher.publish_raw(&stream_key, b"2", &Metadata::default()).await.unwrap();
+
+        let mut subscription = SubscriptionRedis::new(conn.clone(), &stream_key, "test", "a")
+            .block(Duration::from_millis(100))
+            .claim_idle(Duration::from_millis(200));
+        let delivery = subscription.next().await.unwrap().unwrap();
+        assert_eq!((delivery.body.as_ref(), delivery.message_id.as_deref(), delivery.attempt), (&b"1"[..], Some(first.as_str()), 1));
+        assert_eq!(delivery.meta.headers, meta.headers);
+        delivery.nack(Duration::ZERO).await.unwrap();
+        let delivery = subscription.next().await.unwrap().unwrap();
+        assert_eq!(delivery.body.as_ref(), b"2");
+        delivery.ack().await.unwrap();
+
+        // the nacked entry stays pending until it has been idle for claim_idle
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let redelivered = subscription.next().await.unwrap().unwrap();
+        assert_eq!((redelivered.body.as_ref(), redelivered.attempt), (&b"1"[..], 2));
+        redelivered.ack().await.unwrap();
+
+        let pending: Vec<(String, String, u64, u32)> = redis::cmd("XPENDING").arg(&stream_key).arg("test").arg("-").arg("+").arg(10)
+            .query_async(&mut conn.clone()).await.unwrap();
+        assert!(pending.is_empty());
+        let _: u64 = redis::cmd("DEL").arg(&stream_key).query_async(&mut conn.clone()).await.unwrap();
+    }
+}
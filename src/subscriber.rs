@@ -0,0 +1,336 @@
+//! The subscriber module defines a transport-agnostic Subscriber trait, the consume-side twin of Publisher.
+//! A Subscriber yields Delivery items: the raw body, some metadata, and an Ack handle used to settle the message.
+//! 
+//! # Ack semantics
+//! Every Delivery must be settled exactly once by calling either `.ack()` or `.nack(delay)`.
+//! The two backends map these calls as follows:
+//! 
+//! | call          | NSQ                                                   | SQS                                                                  |
+//! |---------------|-------------------------------------------------------|----------------------------------------------------------------------|
+//! | `ack()`       | FIN: the message is finished and never redelivered    | DeleteMessage: the message is removed from the queue                 |
+//! | `nack(ZERO)`  | REQ with no delay: the message is redelivered at once | ChangeMessageVisibility(0): the message is visible again immediately |
+//! | `nack(delay)` | REQ with a custom delay (nsqd caps this, 15m default) | ChangeMessageVisibility(delay): capped by SQS at 12 hours            |
+//! | neither       | redelivered after nsqd's msg-timeout (60s default)    | redelivered once the queue's visibility timeout lapses               |
+//! 
+//! SQS visibility timeouts have one-second granularity, so sub-second nack delays are rounded down.
+//!
+//! Settling consumes the Delivery, so settling twice doesn't compile. A Delivery dropped without being settled
+//! (a handler returned early, or panicked) is not left for the backend to time out silently: its OnDrop action runs
+//! in the background, leaked_acks() is incremented, and the observer's ack_leaked callback is told.
+//! NSQ deliveries are requeued by default; SQS deliveries are left for the visibility timeout.
+//! ConsumerOptions::on_drop overrides the default for a consumer.
+
+use std::{marker::PhantomData, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use crate::codec::{Codec, JsonCodec};
+use crate::err::EventfulError;
+use crate::observer::{self, AckLeaked};
+use crate::publisher::{Destination, Metadata};
+
+
+/// The Ack handle settles one Delivery.  
+/// Both methods consume the handle so a message can't be settled twice
+#[async_trait]
+pub trait Ack: Send {
+    /// The message was processed: remove it from the backend
+    async fn ack(self: Box<Self>) -> Result<(), EventfulError>;
+    /// The message was not processed: make it available again after the given delay
+    async fn nack(self: Box<Self>, delay: Duration) -> Result<(), EventfulError>;
+}
+
+
+/// What happens to a Delivery which is dropped without being settled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnDrop {
+    /// nack(ZERO): make the message available again at once
+    #[default]
+    Requeue,
+    /// ack(): treat the message as processed
+    Finish,
+    /// Leave it to the backend's own timeout (nsqd's msg-timeout, or the SQS visibility timeout)
+    Nothing,
+}
+
+
+static LEAKED_ACKS: AtomicU64 = AtomicU64::new(0);
+
+/// How many deliveries in this process have been dropped without being settled
+pub fn leaked_acks() -> u64 {
+    LEAKED_ACKS.load(Ordering::Relaxed)
+}
+
+
+/// A Delivery is one message received by a Subscriber
+pub struct Delivery {
+    /// Where the message was consumed from
+    pub source: Destination,
+    /// The raw body of the message
+    pub body: Bytes,
+    /// Metadata that arrived with the message
+    pub meta: Metadata,
+    /// The id assigned by the backend, if the backend exposes one
+    pub message_id: Option<String>,
+    /// How many times this message has been delivered, starting at 1
+    pub attempt: u32,
+    /// None once settled
+    ack: Option<Box<dyn Ack>>,
+    on_drop: OnDrop,
+}
+
+impl Delivery {
+    /// The delivery is requeued if it is dropped without being settled
+    pub fn new(source: Destination, body: Bytes, meta: Metadata, message_id: Option<String>, attempt: u32, ack: Box<dyn Ack>) -> Self {
+        Delivery{source, body, meta, message_id, attempt, ack: Some(ack), on_drop: OnDrop::default()}
+    }
+
+    /// What to do if the delivery is dropped without being settled
+    pub fn on_drop(mut self, on_drop: OnDrop) -> Self {
+        self.on_drop = on_drop;
+        self
+    }
+
+    /// Acknowledge that the message was processed. Settling consumes the delivery, so it can't be settled twice:
+    /// ```compile_fail
+    /// # async fn settle(delivery: eventful::subscriber::Delivery) {
+    /// delivery.ack().await.unwrap();
+    /// delivery.ack().await.unwrap();
+    /// # }
+    /// ```
+    pub async fn ack(mut self) -> Result<(), EventfulError> {
+        match self.ack.take() {
+            Some(ack) => ack.ack().await,
+            None => Err(EventfulError::Destination("the delivery was already settled".to_string())),
+        }
+    }
+
+    /// Return the message to the backend to be redelivered after a delay
+    pub async fn nack(mut self, delay: Duration) -> Result<(), EventfulError> {
+        match self.ack.take() {
+            Some(ack) => ack.nack(delay).await,
+            None => Err(EventfulError::Destination("the delivery was already settled".to_string())),
+        }
+    }
+
+    /// Give the delivery up without settling it, leaving the message to the backend's own timeout.
+    /// Unlike dropping it, this is deliberate, so it isn't counted or reported as a leak
+    pub fn release(mut self) {
+        self.ack.take();
+    }
+}
+
+/// Runs the OnDrop action in a background task. Outside a tokio runtime the action can't run,
+/// so the message is left to the backend's timeout, but the leak is still counted and reported
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        let ack = match self.ack.take() {
+            Some(ack) => ack,
+            None => return,
+        };
+        LEAKED_ACKS.fetch_add(1, Ordering::Relaxed);
+        let runtime = tokio::runtime::Handle::try_current().ok();
+        let action = match runtime {
+            Some(_) => self.on_drop,
+            None => OnDrop::Nothing,
+        };
+        observer::global().ack_leaked(&AckLeaked{source: self.source.clone(), message_id: self.message_id.clone(), attempt: self.attempt, action});
+        if let Some(runtime) = runtime {
+            match action {
+                OnDrop::Requeue => { runtime.spawn(async move { let _ = ack.nack(Duration::ZERO).await; }); },
+                OnDrop::Finish => { runtime.spawn(async move { let _ = ack.ack().await; }); },
+                OnDrop::Nothing => {},
+            }
+        }
+    }
+}
+
+
+/// The Subscriber trait is object-safe, so workers can hold a `Box<dyn Subscriber>` chosen at runtime
+#[async_trait]
+pub trait Subscriber: Send {
+    /// Wait for the next Delivery. Ok(None) means the subscription has ended
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError>;
+
+    /// What is being consumed, for logs and lifecycle callbacks, i.e. "nsq://click"
+    fn describe(&self) -> String {
+        "subscriber".to_string()
+    }
+}
+
+#[async_trait]
+impl<S: Subscriber + ?Sized> Subscriber for Box<S> {
+    async fn next(&mut self) -> Result<Option<Delivery>, EventfulError> {
+        (**self).next().await
+    }
+
+    fn describe(&self) -> String {
+        (**self).describe()
+    }
+}
+
+
+/// A decoded event along with the Delivery it arrived in
+pub struct TypedDelivery<T> {
+    pub event: T,
+    pub delivery: Delivery,
+}
+
+
+/// What a TypedSubscriber yields: either a decoded event, or a Delivery whose body could not be decoded.  
+/// Undecodable deliveries are handed back unsettled so the caller decides whether to ack or nack them
+pub enum Received<T> {
+    Event(TypedDelivery<T>),
+    Undecodable {
+        delivery: Delivery,
+        error: EventfulError,
+    },
+}
+
+
+/// The TypedSubscriber wraps any Subscriber and decodes each body into T via the JsonCodec
+pub struct TypedSubscriber<T> {
+    inner: Box<dyn Subscriber>,
+    codec: JsonCodec,
+    _event: PhantomData<fn() -> T>,
+}
+
+impl<T: DeserializeOwned> TypedSubscriber<T> {
+    pub fn new(inner: Box<dyn Subscriber>) -> Self {
+        TypedSubscriber{inner, codec: JsonCodec, _event: PhantomData}
+    }
+
+    pub fn describe(&self) -> String {
+        self.inner.describe()
+    }
+
+    /// Wait for the next delivery and decode it
+    pub async fn next(&mut self) -> Result<Option<Received<T>>, EventfulError> {
+        let delivery = match self.inner.next().await? {
+            Some(delivery) => delivery,
+            None => return Ok(None),
+        };
+        match self.codec.decode::<T>(&delivery.body) {
+            Ok(event) => Ok(Some(Received::Event(TypedDelivery{event, delivery}))),
+            Err(error) => Ok(Some(Received::Undecodable{delivery, error})),
+        }
+    }
+}
+
+
+/// Decode every delivery as an arbitrary JSON value, i.e. for inspection tools which don't know the event types.
+/// Each Received::Event still carries its Delivery (source, headers, message id, attempt) and must be acked or nacked
+pub fn consume_json_values(inner: Box<dyn Subscriber>) -> TypedSubscriber<serde_json::Value> {
+    TypedSubscriber::new(inner)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Settled(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Settled {
+        fn calls(&self) -> Vec<&'static str> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    struct RecordingAck(Settled);
+
+    #[async_trait]
+    impl Ack for RecordingAck {
+        async fn ack(self: Box<Self>) -> Result<(), EventfulError> {
+            self.0.0.lock().unwrap().push("ack");
+            Ok(())
+        }
+
+        async fn nack(self: Box<Self>, _delay: Duration) -> Result<(), EventfulError> {
+            self.0.0.lock().unwrap().push("nack");
+            Ok(())
+        }
+    }
+
+    fn delivery(settled: &Settled) -> Delivery {
+        let source = Destination::NsqTopic("click".to_string());
+        Delivery::new(source, Bytes::from_static(b"{}"), Metadata::default(), Some("1".to_string()), 1, Box::new(RecordingAck(settled.clone())))
+    }
+
+    /// Let the OnDrop action spawned by a drop run
+    async fn background() {
+        for _ in 0..5 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// A handler with a path which forgets to settle
+    async fn handle(delivery: Delivery, skip: bool) -> Result<(), EventfulError> {
+        if skip {
+            return Ok(())
+        }
+        delivery.ack().await
+    }
+
+    #[tokio::test]
+    async fn settling_runs_the_ack_once() {
+        let settled = Settled::default();
+        delivery(&settled).ack().await.unwrap();
+        delivery(&settled).nack(Duration::from_secs(1)).await.unwrap();
+        background().await;
+        assert_eq!(settled.calls(), vec!["ack", "nack"]);
+    }
+
+    #[tokio::test]
+    async fn an_early_return_requeues_by_default() {
+        let settled = Settled::default();
+        let leaked = leaked_acks();
+        handle(delivery(&settled), true).await.unwrap();
+        background().await;
+        assert_eq!(settled.calls(), vec!["nack"]);
+        assert!(leaked_acks() > leaked);
+    }
+
+    #[tokio::test]
+    async fn on_drop_chooses_the_action() {
+        let finished = Settled::default();
+        drop(delivery(&finished).on_drop(OnDrop::Finish));
+        let left = Settled::default();
+        drop(delivery(&left).on_drop(OnDrop::Nothing));
+        background().await;
+        assert_eq!(finished.calls(), vec!["ack"]);
+        assert!(left.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_still_settles() {
+        let settled = Settled::default();
+        let delivery = delivery(&settled);
+        let handler = tokio::spawn(async move {
+            let _delivery = delivery;
+            panic!("handler bug");
+        });
+        assert!(handler.await.unwrap_err().is_panic());
+        background().await;
+        assert_eq!(settled.calls(), vec!["nack"]);
+    }
+
+    #[tokio::test]
+    async fn release_is_not_a_leak() {
+        let settled = Settled::default();
+        delivery(&settled).release();
+        background().await;
+        assert!(settled.calls().is_empty());
+    }
+
+    #[test]
+    fn dropped_outside_a_runtime_is_left_to_the_backend() {
+        let settled = Settled::default();
+        let leaked = leaked_acks();
+        drop(delivery(&settled));
+        assert!(settled.calls().is_empty());
+        assert!(leaked_acks() > leaked);
+    }
+}
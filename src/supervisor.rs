@@ -0,0 +1,444 @@
+//! The supervisor module runs all of a service's consumers together, restarting them when they stop.  
+//! Each consumer is registered with a factory which starts it; the factory is called again on every restart.
+//! The SupervisorHealth handle reports each consumer's state, and `healthy()` is suitable for a readiness probe.
+//! 
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use eventful::prelude::*;
+//! # use eventful::consumer;
+//! # use eventful::supervisor::{RestartPolicy, SupervisorBuilder};
+//! # use tokio_util::sync::CancellationToken;
+//! # #[derive(Serialize, Deserialize)]
+//! # struct UserClickedSomething { user_id: i32 }
+//! # impl EventNSQ for UserClickedSomething { fn topic() -> &'static str { "website_clicks" } }
+//! # struct ClickChannel {}
+//! # impl ChannelConsumer<UserClickedSomething> for ClickChannel { fn channel(&self) -> String { "analytics".to_string() } }
+//! # async fn handle_click(_click: UserClickedSomething) -> Result<(), EventfulError> { Ok(()) }
+//! # async fn demo(daemon: Daemon, shutdown_token: CancellationToken) -> Result<(), EventfulError> {
+//! let supervisor = SupervisorBuilder::new()
+//!     .add("clicks", move |token| {
+//!         let daemon = daemon.clone();
+//!         Box::pin(async move {
+//!             let subscriber = Box::new(ClickChannel{}.subscribe(&[&daemon]));
+//!             let options = ConsumerOptions::default();
+//!             tokio::select! {
+//!                 result = consumer::run(subscriber, &options, handle_click) => result,
+//!                 _ = token.cancelled() => Ok(()),
+//!             }
+//!         })
+//!     })
+//!     .restart(RestartPolicy::Always{max_per_minute: 5})
+//!     .on_escalation(|name, err| eprintln!("consumer {} gave up: {}", name, err))
+//!     .build();
+//! let health = supervisor.health();
+//! supervisor.run(shutdown_token).await?;
+//! # Ok(())
+//! # }
+//! # }
+//! ```
+
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use futures::future::BoxFuture;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use crate::err::EventfulError;
+use crate::retry::{Backoff, Jitter, RetryPolicy};
+
+
+/// When a consumer should be restarted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Never restart; a consumer which stops with an error is escalated
+    Never,
+    /// Restart after an error, but not after the consumer returns Ok
+    OnFailure { max_per_minute: usize },
+    /// Restart whenever the consumer stops
+    Always { max_per_minute: usize },
+}
+
+
+/// The state of one supervised consumer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConsumerState {
+    Starting,
+    Running,
+    /// Waiting to be restarted after stopping with this error (or "exited" if it returned Ok)
+    Restarting { last_error: String },
+    /// Exceeded its restart budget, or stopped with RestartPolicy::Never. It will not be restarted
+    Failed { last_error: String },
+    /// Stopped on purpose: returned Ok with no restart wanted, or the supervisor shut down
+    Stopped,
+}
+
+
+/// The state of a consumer and how many times it has been restarted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerHealth {
+    pub state: ConsumerState,
+    pub restarts: u32,
+}
+
+
+/// A handle reporting the health of every supervised consumer, which stays valid while the supervisor runs
+#[derive(Clone, Default)]
+pub struct SupervisorHealth {
+    consumers: Arc<Mutex<HashMap<String, ConsumerHealth>>>,
+}
+
+impl SupervisorHealth {
+    /// True when every consumer is Running
+    pub fn healthy(&self) -> bool {
+        let consumers = self.consumers.lock().unwrap();
+        !consumers.is_empty() && consumers.values().all(|health| health.state == ConsumerState::Running)
+    }
+
+    pub fn get(&self, name: &str) -> Option<ConsumerHealth> {
+        self.consumers.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn all(&self) -> HashMap<String, ConsumerHealth> {
+        self.consumers.lock().unwrap().clone()
+    }
+
+    fn set(&self, name: &str, state: ConsumerState) {
+        let mut consumers = self.consumers.lock().unwrap();
+        let health = consumers.entry(name.to_string()).or_insert(ConsumerHealth{state: ConsumerState::Starting, restarts: 0});
+        if matches!(state, ConsumerState::Restarting{..}) {
+            health.restarts += 1;
+        }
+        health.state = state;
+    }
+}
+
+
+type Factory = Arc<dyn Fn(CancellationToken) -> BoxFuture<'static, Result<(), EventfulError>> + Send + Sync>;
+type EscalationCallback = Arc<dyn Fn(&str, &EventfulError) + Send + Sync>;
+
+struct Entry {
+    name: String,
+    factory: Factory,
+    policy: RestartPolicy,
+}
+
+
+/// Register consumers, then build() a Supervisor
+pub struct SupervisorBuilder {
+    entries: Vec<Entry>,
+    backoff: RetryPolicy,
+    on_escalation: Option<EscalationCallback>,
+    stop_on_escalation: bool,
+}
+
+impl Default for SupervisorBuilder {
+    fn default() -> Self {
+        SupervisorBuilder{
+            entries: Vec::new(),
+            backoff: RetryPolicy::default().backoff(Backoff::ExponentialJitter{base: Duration::from_millis(500), factor: 2.0, max: Duration::from_secs(30), jitter: Jitter::Full}),
+            on_escalation: None,
+            stop_on_escalation: false,
+        }
+    }
+}
+
+impl SupervisorBuilder {
+    pub fn new() -> Self {
+        SupervisorBuilder::default()
+    }
+
+    /// Register a consumer. The factory receives a token which is cancelled when the consumer should stop.
+    /// The default restart policy is Always with at most 5 restarts per minute
+    pub fn add<F>(mut self, name: &str, factory: F) -> Self
+    where
+        F: Fn(CancellationToken) -> BoxFuture<'static, Result<(), EventfulError>> + Send + Sync + 'static,
+    {
+        self.entries.push(Entry{name: name.to_string(), factory: Arc::new(factory), policy: RestartPolicy::Always{max_per_minute: 5}});
+        self
+    }
+
+    /// Set the restart policy of the consumer added most recently
+    pub fn restart(mut self, policy: RestartPolicy) -> Self {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.policy = policy;
+        }
+        self
+    }
+
+    /// The delay before the nth restart within a minute is backoff.delay_for(n)
+    pub fn backoff(mut self, backoff: RetryPolicy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Called with the consumer name and its last error when a consumer will not be restarted
+    pub fn on_escalation<F: Fn(&str, &EventfulError) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_escalation = Some(Arc::new(callback));
+        self
+    }
+
+    /// Shut the whole supervisor down when any consumer escalates (default false)
+    pub fn stop_on_escalation(mut self, stop: bool) -> Self {
+        self.stop_on_escalation = stop;
+        self
+    }
+
+    pub fn build(self) -> Supervisor {
+        let health = SupervisorHealth::default();
+        for entry in &self.entries {
+            health.set(&entry.name, ConsumerState::Starting);
+        }
+        Supervisor{builder: self, health}
+    }
+}
+
+
+/// The Supervisor runs its consumers until shut down
+pub struct Supervisor {
+    builder: SupervisorBuilder,
+    health: SupervisorHealth,
+}
+
+impl Supervisor {
+    pub fn health(&self) -> SupervisorHealth {
+        self.health.clone()
+    }
+
+    /// Start every consumer and supervise them until the shutdown token is cancelled, or a consumer escalates
+    /// with stop_on_escalation set. Consumers are stopped in the reverse of the order they were added,
+    /// each finishing before the next is stopped. Returns the escalated error when stopped by escalation
+    pub async fn run(self, shutdown: CancellationToken) -> Result<(), EventfulError> {
+        let (escalations, mut escalated) = mpsc::unbounded_channel::<(String, EventfulError)>();
+        let mut running: Vec<(CancellationToken, JoinHandle<()>)> = Vec::new();
+        for entry in &self.builder.entries {
+            let token = CancellationToken::new();
+            let supervised = Supervised{
+                name: entry.name.clone(),
+                factory: entry.factory.clone(),
+                policy: entry.policy,
+                backoff: self.builder.backoff.clone(),
+                health: self.health.clone(),
+                on_escalation: self.builder.on_escalation.clone(),
+                escalations: escalations.clone(),
+            };
+            let handle = tokio::spawn(supervised.supervise(token.clone()));
+            running.push((token, handle));
+        }
+        drop(escalations);
+
+        let mut result = Ok(());
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                escalation = escalated.recv() => match escalation {
+                    Some((name, err)) if self.builder.stop_on_escalation => {
+                        result = Err(EventfulError::Config(format!("consumer '{}' escalated: {}", name, err)));
+                        break
+                    },
+                    Some(_) => continue,
+                    // every consumer has stopped for good
+                    None => break,
+                },
+            }
+        }
+        for (token, handle) in running.into_iter().rev() {
+            token.cancel();
+            let _ = handle.await;
+        }
+        result
+    }
+}
+
+
+/// Everything needed to supervise one consumer, moved into its task
+struct Supervised {
+    name: String,
+    factory: Factory,
+    policy: RestartPolicy,
+    backoff: RetryPolicy,
+    health: SupervisorHealth,
+    on_escalation: Option<EscalationCallback>,
+    escalations: mpsc::UnboundedSender<(String, EventfulError)>,
+}
+
+impl Supervised {
+    async fn supervise(self, token: CancellationToken) {
+        let mut recent_restarts: VecDeque<Instant> = VecDeque::new();
+        loop {
+            self.health.set(&self.name, ConsumerState::Running);
+            let result = (self.factory)(token.clone()).await;
+            if token.is_cancelled() {
+                self.health.set(&self.name, ConsumerState::Stopped);
+                return
+            }
+            let (restart, max_per_minute) = match (&result, self.policy) {
+                (_, RestartPolicy::Never) => (false, 0),
+                (Ok(()), RestartPolicy::OnFailure{..}) => (false, 0),
+                (Err(_), RestartPolicy::OnFailure{max_per_minute}) => (true, max_per_minute),
+                (_, RestartPolicy::Always{max_per_minute}) => (true, max_per_minute),
+            };
+            let err = match result {
+                Ok(()) if !restart => {
+                    self.health.set(&self.name, ConsumerState::Stopped);
+                    return
+                },
+                Ok(()) => EventfulError::Config("consumer exited".to_string()),
+                Err(err) => err,
+            };
+            let now = Instant::now();
+            while recent_restarts.front().map(|at| now.duration_since(*at) > Duration::from_secs(60)).unwrap_or(false) {
+                recent_restarts.pop_front();
+            }
+            if !restart || recent_restarts.len() >= max_per_minute {
+                self.health.set(&self.name, ConsumerState::Failed{last_error: err.to_string()});
+                if let Some(callback) = &self.on_escalation {
+                    callback(&self.name, &err);
+                }
+                let _ = self.escalations.send((self.name.clone(), err));
+                return
+            }
+            recent_restarts.push_back(now);
+            self.health.set(&self.name, ConsumerState::Restarting{last_error: err.to_string()});
+            tokio::select! {
+                _ = token.cancelled() => {
+                    self.health.set(&self.name, ConsumerState::Stopped);
+                    return
+                },
+                _ = tokio::time::sleep(self.backoff.delay_for(recent_restarts.len() as u32)) => {},
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use futures::FutureExt;
+
+    fn quick() -> RetryPolicy {
+        RetryPolicy::default().backoff(Backoff::Fixed(Duration::from_millis(1)))
+    }
+
+    /// Fails its first `failures` starts, then runs until cancelled
+    fn failing_then_running(failures: u32) -> impl Fn(CancellationToken) -> BoxFuture<'static, Result<(), EventfulError>> {
+        let starts = Arc::new(AtomicU32::new(0));
+        move |token| {
+            let start = starts.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                if start <= failures {
+                    return Err(EventfulError::Http(format!("start {} failed", start)))
+                }
+                token.cancelled().await;
+                Ok(())
+            }.boxed()
+        }
+    }
+
+    async fn wait_until<F: Fn(&SupervisorHealth) -> bool>(health: &SupervisorHealth, condition: F) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !condition(health) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await.expect("condition never held");
+    }
+
+    #[tokio::test]
+    async fn restarts_are_counted() {
+        let supervisor = SupervisorBuilder::new()
+            .add("clicks", failing_then_running(3))
+            .backoff(quick())
+            .build();
+        let health = supervisor.health();
+        assert!(!health.healthy());
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn(supervisor.run(shutdown.clone()));
+        wait_until(&health, |health| health.get("clicks").map(|h| h.restarts) == Some(3) && health.healthy()).await;
+
+        shutdown.cancel();
+        running.await.unwrap().unwrap();
+        assert_eq!(health.get("clicks"), Some(ConsumerHealth{state: ConsumerState::Stopped, restarts: 3}));
+        assert!(!health.healthy());
+    }
+
+    #[tokio::test]
+    async fn exhausted_budget_escalates() {
+        let escalated = Arc::new(Mutex::new(Vec::new()));
+        let seen = escalated.clone();
+        let supervisor = SupervisorBuilder::new()
+            .add("orders", failing_then_running(u32::MAX))
+            .restart(RestartPolicy::OnFailure{max_per_minute: 2})
+            .add("clicks", failing_then_running(0))
+            .backoff(quick())
+            .on_escalation(move |name, _| seen.lock().unwrap().push(name.to_string()))
+            .build();
+        let health = supervisor.health();
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn(supervisor.run(shutdown.clone()));
+        wait_until(&health, |health| matches!(health.get("orders").map(|h| h.state), Some(ConsumerState::Failed{..}))).await;
+
+        // without stop_on_escalation the other consumers carry on
+        assert_eq!(health.get("orders").unwrap().restarts, 2);
+        assert_eq!(*escalated.lock().unwrap(), vec!["orders".to_string()]);
+        assert_eq!(health.get("clicks").unwrap().state, ConsumerState::Running);
+        assert!(!health.healthy());
+        shutdown.cancel();
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn escalation_can_stop_the_supervisor() {
+        let supervisor = SupervisorBuilder::new()
+            .add("orders", failing_then_running(u32::MAX))
+            .restart(RestartPolicy::Never)
+            .add("clicks", failing_then_running(0))
+            .stop_on_escalation(true)
+            .build();
+        let health = supervisor.health();
+        let result = tokio::time::timeout(Duration::from_secs(5), supervisor.run(CancellationToken::new())).await.unwrap();
+        assert!(matches!(result, Err(EventfulError::Config(_))));
+        assert_eq!(health.get("orders").unwrap().restarts, 0);
+        assert_eq!(health.get("clicks").unwrap().state, ConsumerState::Stopped);
+    }
+
+    #[tokio::test]
+    async fn clean_exit_is_not_restarted_on_failure_policy() {
+        let supervisor = SupervisorBuilder::new()
+            .add("oneshot", |_| async { Ok::<(), EventfulError>(()) }.boxed())
+            .restart(RestartPolicy::OnFailure{max_per_minute: 5})
+            .build();
+        let health = supervisor.health();
+        // the only consumer stopped for good, so run returns without being shut down
+        tokio::time::timeout(Duration::from_secs(5), supervisor.run(CancellationToken::new())).await.unwrap().unwrap();
+        assert_eq!(health.get("oneshot"), Some(ConsumerHealth{state: ConsumerState::Stopped, restarts: 0}));
+    }
+
+    #[tokio::test]
+    async fn consumers_stop_in_reverse_order() {
+        let stopped = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = SupervisorBuilder::new();
+        for name in ["first", "second", "third"] {
+            let stopped = stopped.clone();
+            builder = builder.add(name, move |token| {
+                let stopped = stopped.clone();
+                async move {
+                    token.cancelled().await;
+                    // give a later consumer the chance to finish first, were they stopped together
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    stopped.lock().unwrap().push(name);
+                    Ok(())
+                }.boxed()
+            });
+        }
+        let supervisor = builder.build();
+        let health = supervisor.health();
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn(supervisor.run(shutdown.clone()));
+        wait_until(&health, SupervisorHealth::healthy).await;
+        shutdown.cancel();
+        running.await.unwrap().unwrap();
+        assert_eq!(*stopped.lock().unwrap(), vec!["third", "second", "first"]);
+        assert!(health.all().values().all(|h| h.state == ConsumerState::Stopped));
+    }
+}
@@ -0,0 +1,307 @@
+//! The tap module captures raw message bodies for debugging, i.e. to see exactly what a consumer failed to decode.
+//! A DebugTap copies the first N bodies (or a sampled fraction of them) which match an optional predicate into an
+//! in-memory buffer and/or files under a directory, then detaches itself. Bodies are truncated to a size cap, the total
+//! captured is capped, and the tap expires after a deadline, so it is safe to leave enabled. Files are written on a
+//! background thread, so capturing never blocks the consumer on the disk.
+//!
+//! A DebugTap is an Interceptor, so it attaches to a consumer through InterceptedSubscriber. It can also run standalone
+//! with run(), i.e. on an NSQ ephemeral channel so the real consumers are unaffected.
+//!
+//! # Examples:
+//! ```no_run
+//! # #[cfg(feature = "nsq")]
+//! # mod example {
+//! # use std::{sync::Arc, time::Duration};
+//! # use eventful::prelude::*;
+//! # use eventful::{interceptor::InterceptedSubscriber, tap::DebugTap};
+//! # #[derive(Serialize, Deserialize)]
+//! # struct UserClickedSomething { user_id: i32 }
+//! # impl EventNSQ for UserClickedSomething { fn topic() -> &'static str { "website_clicks" } }
+//! # struct ClickChannel {}
+//! # impl ChannelConsumer<UserClickedSomething> for ClickChannel { fn channel(&self) -> String { "analytics".to_string() } }
+//! # fn demo(daemons: &[&Daemon]) {
+//! let tap = DebugTap::new(20)
+//!     .matching(|body| !body.starts_with(b"{"))
+//!     .dir("/tmp/click-bodies")
+//!     .expire_after(Duration::from_secs(600));
+//! let subscriber = InterceptedSubscriber::new(ClickChannel{}.subscribe(daemons), vec![Arc::new(tap.clone())]);
+//! // ...later
+//! for message in tap.captured() {
+//!     println!("{} attempt {}: {:?}", message.topic, message.attempt, message.body);
+//! }
+//! # }
+//! # }
+//! ```
+
+use std::{collections::VecDeque, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use crate::err::EventfulError;
+use crate::interceptor::{ConsumeCtx, Interceptor};
+use crate::publisher::Destination;
+use crate::subscriber::Subscriber;
+use crate::writer::FileWriter;
+
+
+/// One captured message
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedMessage {
+    pub topic: Destination,
+    pub timestamp: DateTime<Utc>,
+    pub attempt: u32,
+    pub message_id: Option<String>,
+    /// The raw body, cut to max_body_bytes
+    pub body: Bytes,
+    /// Whether the body was cut short
+    pub truncated: bool,
+}
+
+
+type Predicate = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+struct TapState {
+    captured: VecDeque<CapturedMessage>,
+    /// How many messages have been captured, including any no longer held in memory
+    count: usize,
+    bytes: usize,
+    detached: bool,
+}
+
+
+/// A DebugTap captures raw bodies until it has `limit` of them, reaches its byte cap, or expires.
+/// Clones share the same buffer, so keep one to read captured() after handing another to a consumer
+#[derive(Clone)]
+pub struct DebugTap {
+    limit: usize,
+    sample: f64,
+    predicate: Option<Predicate>,
+    dir: Option<PathBuf>,
+    writer: Option<Arc<FileWriter>>,
+    in_memory: bool,
+    max_body_bytes: usize,
+    max_total_bytes: usize,
+    expires_at: Instant,
+    state: Arc<Mutex<TapState>>,
+}
+
+impl DebugTap {
+    /// Capture up to `limit` messages (a limit of 0 captures nothing). By default every message is captured in memory, bodies are cut to 64KiB,
+    /// at most 8MiB is kept in total, and the tap expires after 15 minutes
+    pub fn new(limit: usize) -> Self {
+        DebugTap{
+            limit,
+            sample: 1.0,
+            predicate: None,
+            dir: None,
+            writer: None,
+            in_memory: true,
+            max_body_bytes: 64 * 1024,
+            max_total_bytes: 8 * 1024 * 1024,
+            expires_at: Instant::now() + Duration::from_secs(900),
+            state: Arc::new(Mutex::new(TapState{captured: VecDeque::new(), count: 0, bytes: 0, detached: limit == 0})),
+        }
+    }
+
+    /// Capture only this fraction (0.0 to 1.0) of matching messages, chosen at random
+    pub fn sample(mut self, fraction: f64) -> Self {
+        self.sample = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Capture only bodies for which the predicate returns true
+    pub fn matching<F: Fn(&[u8]) -> bool + Send + Sync + 'static>(mut self, predicate: F) -> Self {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Also write each captured body to its own file under this directory, named {n}-{topic}.bin
+    pub fn dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.dir = Some(dir.into());
+        // a failure to write is ignored, like any other failure to capture
+        let writer = FileWriter::start("eventful-debug-tap", |_err| {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %_err, "debug tap could not write a capture file");
+        });
+        self.writer = Some(Arc::new(writer));
+        self
+    }
+
+    /// When false, bodies are only written to files (default true)
+    pub fn in_memory(mut self, in_memory: bool) -> Self {
+        self.in_memory = in_memory;
+        self
+    }
+
+    /// Cut each body to this many bytes (default 64KiB)
+    pub fn max_body_bytes(mut self, max: usize) -> Self {
+        self.max_body_bytes = max;
+        self
+    }
+
+    /// Detach once this many body bytes have been captured (default 8MiB)
+    pub fn max_total_bytes(mut self, max: usize) -> Self {
+        self.max_total_bytes = max;
+        self
+    }
+
+    /// Detach this long after now, whatever has been captured (default 15 minutes)
+    pub fn expire_after(mut self, duration: Duration) -> Self {
+        self.expires_at = Instant::now() + duration;
+        self
+    }
+
+    /// The messages captured so far, oldest first (empty when in_memory is false)
+    pub fn captured(&self) -> Vec<CapturedMessage> {
+        self.state.lock().unwrap().captured.iter().cloned().collect()
+    }
+
+    /// Wait until every body captured so far has been written to its file
+    pub async fn flush(&self) {
+        if let Some(writer) = &self.writer {
+            writer.flush().await;
+        }
+    }
+
+    /// True once the tap has stopped capturing
+    pub fn is_detached(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.detached && Instant::now() >= self.expires_at {
+            state.detached = true;
+        }
+        state.detached
+    }
+
+    /// Look at one message, capturing it if it qualifies. Files are written in the background (see flush)
+    pub fn observe(&self, topic: &Destination, body: &[u8], attempt: u32, message_id: Option<&str>) -> Result<(), EventfulError> {
+        if self.is_detached() {
+            return Ok(())
+        }
+        if let Some(predicate) = &self.predicate {
+            if !predicate(body) {
+                return Ok(())
+            }
+        }
+        if self.sample < 1.0 && rand::thread_rng().gen::<f64>() >= self.sample {
+            return Ok(())
+        }
+        let truncated = body.len() > self.max_body_bytes;
+        let body = Bytes::copy_from_slice(&body[..body.len().min(self.max_body_bytes)]);
+        let mut state = self.state.lock().unwrap();
+        if state.detached {
+            return Ok(())
+        }
+        state.count += 1;
+        state.bytes += body.len();
+        let n = state.count;
+        if n >= self.limit || state.bytes >= self.max_total_bytes {
+            state.detached = true;
+        }
+        if let (Some(dir), Some(writer)) = (&self.dir, &self.writer) {
+            let name: String = topic.name().chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect();
+            writer.write(dir.join(format!("{:04}-{}.bin", n, name)), body.clone());
+        }
+        if self.in_memory {
+            state.captured.push_back(CapturedMessage{
+                topic: topic.clone(),
+                timestamp: Utc::now(),
+                attempt,
+                message_id: message_id.map(|id| id.to_string()),
+                body,
+                truncated,
+            });
+        }
+        Ok(())
+    }
+
+    /// Consume from a subscriber (i.e. an NSQ ephemeral channel) only to capture, acking everything,
+    /// until the tap detaches or the subscriber ends
+    pub async fn run(&self, mut subscriber: Box<dyn Subscriber>) -> Result<(), EventfulError> {
+        while !self.is_detached() {
+            let remaining = self.expires_at.saturating_duration_since(Instant::now());
+            let delivery = match tokio::time::timeout(remaining, subscriber.next()).await {
+                Ok(next) => match next? {
+                    Some(delivery) => delivery,
+                    None => break,
+                },
+                // expired while waiting
+                Err(_) => break,
+            };
+            self.observe(&delivery.source, &delivery.body, delivery.attempt, delivery.message_id.as_deref())?;
+            delivery.ack().await?;
+        }
+        Ok(())
+    }
+}
+
+/// As an Interceptor the tap sees every consumed body (after any interceptors later in the chain have run).
+/// Capture files are written in the background, so the tap never holds up or rejects a delivery
+impl Interceptor for DebugTap {
+    fn on_consume(&self, ctx: &mut ConsumeCtx) -> Result<(), EventfulError> {
+        let _ = self.observe(&ctx.source, &ctx.body, ctx.attempt, ctx.message_id.as_deref());
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic() -> Destination {
+        Destination::NsqTopic("click".to_string())
+    }
+
+    #[test]
+    fn a_limit_of_zero_captures_nothing() {
+        let tap = DebugTap::new(0);
+        assert!(tap.is_detached());
+        tap.observe(&topic(), b"{}", 1, None).unwrap();
+        assert!(tap.captured().is_empty());
+    }
+
+    #[test]
+    fn captures_matching_bodies_until_the_limit_cutting_long_ones() {
+        let tap = DebugTap::new(2).matching(|body| !body.starts_with(b"{")).max_body_bytes(4);
+        tap.observe(&topic(), b"{\"ok\": true}", 1, Some("a")).unwrap();
+        tap.observe(&topic(), b"not json", 3, Some("b")).unwrap();
+        assert!(!tap.is_detached());
+        tap.observe(&topic(), b"<xml/>", 1, Some("c")).unwrap();
+        assert!(tap.is_detached());
+        tap.observe(&topic(), b"too late", 1, Some("d")).unwrap();
+
+        let captured = tap.captured();
+        let summary = captured.iter().map(|message| (message.message_id.as_deref(), message.attempt, message.body.clone(), message.truncated)).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (Some("b"), 3, Bytes::from("not "), true),
+            (Some("c"), 1, Bytes::from("<xml"), true),
+        ]);
+    }
+
+    #[test]
+    fn detaches_at_the_byte_cap_or_once_expired() {
+        let tap = DebugTap::new(100).max_total_bytes(10);
+        tap.observe(&topic(), b"123456", 1, None).unwrap();
+        assert!(!tap.is_detached());
+        tap.observe(&topic(), b"7890", 1, None).unwrap();
+        assert!(tap.is_detached());
+        assert_eq!(tap.captured().len(), 2);
+
+        let expired = DebugTap::new(100).expire_after(Duration::ZERO);
+        expired.observe(&topic(), b"late", 1, None).unwrap();
+        assert!(expired.captured().is_empty());
+    }
+
+    #[tokio::test]
+    async fn files_are_written_in_the_background() {
+        let dir = std::env::temp_dir().join(format!("eventful-tap-{}", rand::random::<u32>()));
+        let tap = DebugTap::new(5).dir(&dir).in_memory(false);
+        tap.observe(&Destination::NsqTopic("click.events".to_string()), b"first", 1, None).unwrap();
+        tap.observe(&topic(), b"second", 1, None).unwrap();
+        tap.flush().await;
+        assert_eq!(std::fs::read(dir.join("0001-click_events.bin")).unwrap(), b"first");
+        assert_eq!(std::fs::read(dir.join("0002-click.bin")).unwrap(), b"second");
+        assert!(tap.captured().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::err::EventfulError;
+use crate::nsq::{self, Daemon, EventNSQ};
+
+
+/// Consume the first decodable T from an ephemeral channel, finish it, and disconnect.
+/// Returns EventfulError::Timeout if none arrives within `deadline`. See consume_until()
+pub async fn consume_one<T: EventNSQ>(daemons: &[&Daemon], channel: &str, deadline: Duration) -> Result<T, EventfulError> {
+    consume_until(daemons, channel, deadline, |_: &T| true).await
+}
+
+
+/// Consume from an ephemeral channel until a T for which the predicate returns true arrives, then finish it and disconnect.
+/// Every other message (undecodable or not matching) is finished too, so unrelated traffic on a shared topic is skipped.
+/// "#ephemeral" is appended to the channel name if it isn't there already.
+///
+/// An NSQ channel only receives messages published after it exists (unless it is the first channel on its topic),
+/// so start consuming before publishing, i.e. by spawning this and waiting a moment.
+/// Returns EventfulError::Timeout, noting how many messages were skipped, if nothing matches within `deadline`.
+///
+/// # Examples:
+/// ```no_run
+/// # use std::time::Duration;
+/// # use eventful::prelude::*;
+/// # use eventful::testing;
+/// # #[derive(Serialize, Deserialize)]
+/// # struct OrderPlaced { id: u64, amount: u64 }
+/// # impl EventNSQ for OrderPlaced { fn topic() -> &'static str { "orders" } }
+/// # async fn place_order(id: u64) -> Result<(), EventfulError> { Ok(()) }
+/// # async fn demo(daemons: FleetNSQ) -> Result<(), EventfulError> {
+/// let consumed = tokio::spawn(async move {
+///     testing::consume_until(&daemons.as_refs(), "order-test", Duration::from_secs(5), |order: &OrderPlaced| order.id == 42).await
+/// });
+/// tokio::time::sleep(Duration::from_millis(250)).await;
+/// place_order(42).await?;
+/// assert_eq!(consumed.await.unwrap()?.amount, 25);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn consume_until<T, F>(daemons: &[&Daemon], channel: &str, deadline: Duration, predicate: F) -> Result<T, EventfulError>
+where
+    T: EventNSQ,
+    F: Fn(&T) -> bool,
+{
+    let topic = <T as EventNSQ>::topic();
+    let channel = match channel.ends_with("#ephemeral") {
+        true => channel.to_string(),
+        false => format!("{}#ephemeral", channel),
+    };
+    let mut consumer = nsq::consumer_for(topic, &channel, daemons)?;
+    let give_up_at = Instant::now() + deadline;
+    let mut undecodable = 0;
+    let mut not_matching = 0;
+    loop {
+        let message = match tokio::time::timeout_at(give_up_at, consumer.consume_filtered()).await {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err(EventfulError::NSQ),
+            Err(_) => return Err(EventfulError::Timeout(format!(
+                "no matching message on {}/{} within {:?} ({} undecodable, {} not matching)",
+                topic, channel, deadline, undecodable, not_matching))),
+        };
+        let event = serde_json::from_slice::<T>(&message.body);
+        message.finish().await;
+        match event {
+            Ok(event) if predicate(&event) => return Ok(event),
+            Ok(_) => not_matching += 1,
+            Err(_) => undecodable += 1,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use serde::{Serialize, Deserialize};
+    use crate::publisher::{Destination, Metadata, Publisher};
+    use crate::testing::MockDaemon;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct OrderPlaced {
+        id: u64,
+        amount: u64,
+    }
+
+    impl EventNSQ for OrderPlaced {
+        fn topic() -> &'static str {
+            "orders"
+        }
+    }
+
+    /// Start consuming from the mock, then publish the bodies once the consumer has had time to subscribe
+    async fn consume_after_publishing<F>(mock: &MockDaemon, bodies: &[&'static str], deadline: Duration, predicate: F) -> Result<OrderPlaced, EventfulError>
+    where
+        F: Fn(&OrderPlaced) -> bool + Send + 'static,
+    {
+        let daemon = mock.daemon();
+        let consumed = tokio::spawn(async move { consume_until(&[&daemon], "order-test", deadline, predicate).await });
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let orders = Destination::NsqTopic("orders".to_string());
+        for body in bodies {
+            mock.daemon().publish_bytes(&orders, Bytes::from_static(body.as_bytes()), &Metadata::default()).await.unwrap();
+        }
+        consumed.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn skips_messages_until_one_matches() {
+        let mock = MockDaemon::start().await.unwrap();
+        let bodies = ["not json", r#"{"id":7,"amount":10}"#, r#"{"id":42,"amount":25}"#, r#"{"id":43,"amount":30}"#];
+        let order = consume_after_publishing(&mock, &bodies, Duration::from_secs(5), |order| order.id == 42).await.unwrap();
+        assert_eq!(order, OrderPlaced{id: 42, amount: 25});
+    }
+
+    #[tokio::test]
+    async fn consume_one_takes_the_first_decodable_event() {
+        let mock = MockDaemon::start().await.unwrap();
+        let daemon = mock.daemon();
+        let consumed = tokio::spawn(async move { consume_one::<OrderPlaced>(&[&daemon], "order-test#ephemeral", Duration::from_secs(5)).await });
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let orders = Destination::NsqTopic("orders".to_string());
+        for body in [r#"{"id":"not a number"}"#, r#"{"id":1,"amount":5}"#] {
+            mock.daemon().publish_bytes(&orders, Bytes::from_static(body.as_bytes()), &Metadata::default()).await.unwrap();
+        }
+        assert_eq!(consumed.await.unwrap().unwrap(), OrderPlaced{id: 1, amount: 5});
+    }
+
+    #[tokio::test]
+    async fn times_out_saying_how_many_messages_were_skipped() {
+        let mock = MockDaemon::start().await.unwrap();
+        let bodies = ["not json", r#"{"id":7,"amount":10}"#];
+        match consume_after_publishing(&mock, &bodies, Duration::from_millis(500), |order| order.id == 42).await {
+            Err(EventfulError::Timeout(message)) => assert!(message.contains("orders/order-test#ephemeral") && message.contains("(1 undecodable, 1 not matching)"), "{}", message),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+}
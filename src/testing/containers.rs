@@ -0,0 +1,276 @@
+use std::time::{Duration, Instant};
+use rand::{Rng, distributions::Alphanumeric};
+use testcontainers::{ContainerAsync, GenericImage, RunnableImage, core::WaitFor, runners::AsyncRunner};
+use crate::err::EventfulError;
+use crate::nsq::{self, Daemon, FleetNSQ};
+
+
+const NSQ_IMAGE: &str = "nsqio/nsq";
+const NSQ_TAG: &str = "v1.2.1";
+
+
+/// An nsqd running in a docker container (with the `testcontainers` feature), optionally registered with
+/// its own nsqlookupd. The containers are removed when the NsqContainer is dropped.
+///
+/// The daemon() points at the ports docker mapped on 127.0.0.1. nsqd registers its container ports with
+/// nsqlookupd, so consume from the daemon directly rather than through lookupd discovery.
+///
+/// # Examples:
+/// ```no_run
+/// # use eventful::prelude::*;
+/// # use eventful::nsq::post_to;
+/// # use eventful::testing::NsqContainer;
+/// # #[derive(Serialize, Deserialize)]
+/// # struct Click { user_id: u64 }
+/// # impl EventNSQ for Click { fn topic() -> &'static str { "click" } }
+/// # async fn demo(click: Click) -> Result<(), EventfulError> {
+/// let nsq = NsqContainer::builder().with_topic("click").start().await?;
+/// post_to(&click, &nsq.daemon()).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct NsqContainer {
+    daemon: Daemon,
+    lookupd_http_address: Option<String>,
+    _nsqd: ContainerAsync<GenericImage>,
+    _lookupd: Option<ContainerAsync<GenericImage>>,
+}
+
+impl NsqContainer {
+    /// Start a lone nsqd
+    pub async fn start() -> Result<Self, EventfulError> {
+        NsqContainer::builder().start().await
+    }
+
+    pub fn builder() -> NsqContainerBuilder {
+        NsqContainerBuilder{lookupd: false, topics: Vec::new(), ready_timeout: Duration::from_secs(30)}
+    }
+
+    /// Start `n` independent nsqds (at the same time)
+    pub async fn start_many(n: usize) -> Result<Vec<Self>, EventfulError> {
+        futures::future::try_join_all((0..n).map(|_| NsqContainer::start())).await
+    }
+
+    /// Start three nsqds and a FleetNSQ over them. Keep the containers alive as long as the fleet is used
+    pub async fn start_fleet() -> Result<(FleetNSQ, Vec<Self>), EventfulError> {
+        let containers = NsqContainer::start_many(3).await?;
+        let fleet = FleetNSQ::new(containers[0].daemon(), containers[1].daemon(), containers[2].daemon());
+        Ok((fleet, containers))
+    }
+
+    /// The nsqd, at its mapped ports
+    pub fn daemon(&self) -> Daemon {
+        self.daemon.clone()
+    }
+
+    /// The nsqlookupd HTTP address, i.e. "127.0.0.1:32771", when started with_lookupd
+    pub fn lookupd_http_address(&self) -> Option<&str> {
+        self.lookupd_http_address.as_deref()
+    }
+}
+
+
+/// Options for NsqContainer
+pub struct NsqContainerBuilder {
+    lookupd: bool,
+    topics: Vec<String>,
+    ready_timeout: Duration,
+}
+
+impl NsqContainerBuilder {
+    /// Also start an nsqlookupd, with nsqd registered to it
+    pub fn with_lookupd(mut self) -> Self {
+        self.lookupd = true;
+        self
+    }
+
+    /// Create a topic once nsqd is ready. Can be called more than once
+    pub fn with_topic(mut self, topic: &str) -> Self {
+        self.topics.push(topic.to_string());
+        self
+    }
+
+    /// How long to wait for nsqd to answer /ping (default 30 seconds)
+    pub fn ready_timeout(mut self, timeout: Duration) -> Self {
+        self.ready_timeout = timeout;
+        self
+    }
+
+    pub async fn start(self) -> Result<NsqContainer, EventfulError> {
+        let suffix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(8).map(char::from).collect::<String>().to_lowercase();
+        let network = format!("eventful-{}", suffix);
+        let mut nsqd_args = vec!["--broadcast-address=127.0.0.1".to_string()];
+        let mut lookupd = None;
+        let mut lookupd_http_address = None;
+        if self.lookupd {
+            let name = format!("eventful-lookupd-{}", suffix);
+            let image = GenericImage::new(NSQ_IMAGE, NSQ_TAG)
+                .with_entrypoint("/nsqlookupd")
+                .with_exposed_port(4160)
+                .with_exposed_port(4161)
+                .with_wait_for(WaitFor::message_on_stderr("HTTP: listening"));
+            let container = RunnableImage::from((image, Vec::new()))
+                .with_network(network.clone())
+                .with_container_name(name.clone())
+                .start().await;
+            lookupd_http_address = Some(format!("127.0.0.1:{}", container.get_host_port_ipv4(4161).await));
+            nsqd_args.push(format!("--lookupd-tcp-address={}:4160", name));
+            lookupd = Some(container);
+        }
+        let image = GenericImage::new(NSQ_IMAGE, NSQ_TAG)
+            .with_entrypoint("/nsqd")
+            .with_exposed_port(4150)
+            .with_exposed_port(4151)
+            .with_wait_for(WaitFor::message_on_stderr("HTTP: listening"));
+        let mut runnable = RunnableImage::from((image, nsqd_args));
+        if self.lookupd {
+            runnable = runnable.with_network(network);
+        }
+        let nsqd = runnable.start().await;
+        let http_port = nsqd.get_host_port_ipv4(4151).await;
+        let tcp_port = nsqd.get_host_port_ipv4(4150).await;
+        let daemon = Daemon::new("127.0.0.1", http_port, tcp_port);
+        wait_until_ready(&daemon, self.ready_timeout).await?;
+        for topic in &self.topics {
+            nsq::create_topic(&daemon, topic).await?;
+        }
+        Ok(NsqContainer{daemon, lookupd_http_address, _nsqd: nsqd, _lookupd: lookupd})
+    }
+}
+
+
+/// Ping nsqd until it answers or the timeout passes
+async fn wait_until_ready(daemon: &Daemon, timeout: Duration) -> Result<(), EventfulError> {
+    let started = Instant::now();
+    loop {
+        match nsq::ping(daemon).await {
+            Ok(()) => return Ok(()),
+            Err(err) if started.elapsed() >= timeout => {
+                return Err(EventfulError::Unhealthy(format!("nsqd at {} was not ready after {:?}: {}", daemon.pub_url, timeout, err)))
+            },
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpstub::HttpStub;
+
+    #[test]
+    fn builder_defaults() {
+        let builder = NsqContainer::builder();
+        assert!(!builder.lookupd);
+        assert!(builder.topics.is_empty());
+        assert_eq!(builder.ready_timeout, Duration::from_secs(30));
+        let builder = builder.with_lookupd().with_topic("click").with_topic("click_dlq").ready_timeout(Duration::from_secs(5));
+        assert!(builder.lookupd);
+        assert_eq!(builder.topics, vec!["click", "click_dlq"]);
+    }
+
+    #[tokio::test]
+    async fn ready_once_nsqd_answers_ping() {
+        let stub = HttpStub::start(vec![(503, "starting".to_string()), (503, "starting".to_string()), (200, "OK".to_string())]).await;
+        wait_until_ready(&Daemon::new("127.0.0.1", stub.port(), 0), Duration::from_secs(5)).await.unwrap();
+        assert_eq!(stub.requests().len(), 3);
+        assert!(stub.requests().iter().all(|request| request.path == "/ping"));
+    }
+
+    #[tokio::test]
+    async fn not_ready_is_unhealthy_after_the_timeout() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let err = wait_until_ready(&Daemon::new("127.0.0.1", port, 0), Duration::from_millis(250)).await.unwrap_err();
+        assert!(matches!(&err, EventfulError::Unhealthy(message) if message.contains("was not ready after 250ms")), "{}", err);
+    }
+}
+
+
+/// Needs docker. Run with `cargo test --features testcontainers -- --ignored`
+#[cfg(test)]
+mod integration {
+    use std::sync::{Arc, Mutex};
+    use serde::{Deserialize, Serialize};
+    use tokio::sync::mpsc;
+    use super::*;
+    use crate::consumer::ConsumerOptions;
+    use crate::nsq::{ChannelConsumer, EventNSQ};
+    use crate::retry::{Backoff, RetryPolicy};
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Click {
+        n: u32,
+    }
+
+    impl EventNSQ for Click {
+        fn topic() -> &'static str {
+            "container_click"
+        }
+    }
+
+    struct Analytics;
+
+    impl ChannelConsumer<Click> for Analytics {
+        fn channel(&self) -> String {
+            "analytics".to_string()
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs docker"]
+    async fn a_consumer_loop_handles_and_retries_against_a_real_nsqd() {
+        let nsq = NsqContainer::builder().with_topic(Click::topic()).start().await.unwrap();
+        let daemon = nsq.daemon();
+        let (handled, mut received) = mpsc::unbounded_channel();
+        let failed_once = Arc::new(Mutex::new(false));
+        let consumer = {
+            let daemon = daemon.clone();
+            tokio::spawn(async move {
+                let options = ConsumerOptions::default().retry(RetryPolicy::default().max_attempts(3).backoff(Backoff::Fixed(Duration::from_millis(100))));
+                Analytics.run(&[&daemon], &options, |click: Click| {
+                    let (handled, failed_once) = (handled.clone(), failed_once.clone());
+                    async move {
+                        let mut failed_once = failed_once.lock().unwrap();
+                        if click.n == 2 && !*failed_once {
+                            *failed_once = true;
+                            return Err(EventfulError::Http("flaky downstream".to_string()))
+                        }
+                        let _ = handled.send(click.n);
+                        Ok(())
+                    }
+                }).await
+            })
+        };
+        for n in 1..=3 {
+            Click{n}.publish_to(&daemon).await.unwrap();
+        }
+        let mut seen = Vec::new();
+        while seen.len() < 3 {
+            seen.push(tokio::time::timeout(Duration::from_secs(10), received.recv()).await.unwrap().unwrap());
+        }
+        seen.sort();
+        assert_eq!(seen, vec![1, 2, 3]);
+        assert!(*failed_once.lock().unwrap());
+        consumer.abort();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs docker"]
+    async fn nsqd_registers_with_its_lookupd() {
+        let nsq = NsqContainer::builder().with_lookupd().with_topic("registered").start().await.unwrap();
+        let url = format!("http://{}/lookup?topic=registered", nsq.lookupd_http_address().unwrap());
+        let started = Instant::now();
+        // nsqd registers its topics with lookupd shortly after they are created
+        loop {
+            let response = hyper::Client::new().get(url.parse().unwrap()).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            if status.is_success() && String::from_utf8_lossy(&body).contains("\"broadcast_address\":\"127.0.0.1\"") {
+                break
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "nsqd never registered with lookupd: {}", String::from_utf8_lossy(&body));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+}
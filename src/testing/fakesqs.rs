@@ -0,0 +1,422 @@
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex}, time::Duration};
+use async_trait::async_trait;
+use aws_sdk_sqs::model::{Message, MessageSystemAttributeName};
+use bytes::Bytes;
+use tokio::time::Instant;
+use crate::err::EventfulError;
+use crate::publisher::{self, Destination, Metadata, Publisher, Receipt};
+use crate::sqs::{self, OutgoingSQS, SentMessage, SqsApi, SubscriptionSQS};
+
+
+/// FIFO queues drop a message whose dedup_id was seen within this window
+const DEDUP_WINDOW: Duration = Duration::from_secs(300);
+
+
+struct FakeMessage {
+    message_id: String,
+    body: String,
+    group_id: Option<String>,
+    receive_count: u32,
+    visible_at: Instant,
+    /// A new handle is issued each time the message is received
+    receipt_handle: Option<String>,
+}
+
+#[derive(Default)]
+struct FakeQueue {
+    messages: Vec<FakeMessage>,
+    /// dedup_id -> (what was returned for it, when it was sent)
+    dedup: HashMap<String, (SentMessage, Instant)>,
+    deleted: usize,
+}
+
+#[derive(Default)]
+struct FakeState {
+    queues: HashMap<String, FakeQueue>,
+    next_id: u64,
+    /// How many upcoming calls (of any kind) fail
+    fail_next: u32,
+    /// How many upcoming sends return the wrong MD5 digest
+    corrupt_next: u32,
+}
+
+impl FakeState {
+    fn injected_failure(&mut self) -> Result<(), EventfulError> {
+        match self.fail_next {
+            0 => Ok(()),
+            _ => {
+                self.fail_next -= 1;
+                Err(EventfulError::SQS("injected failure".to_string()))
+            },
+        }
+    }
+
+    fn enqueue(&mut self, queue_url: &str, message: OutgoingSQS) -> Result<SentMessage, EventfulError> {
+        let fifo = is_fifo(queue_url);
+        if fifo && message.group_id.is_none() {
+            return Err(EventfulError::SQS(format!("MessageGroupId is required for FIFO queue {}", queue_url)))
+        }
+        let now = Instant::now();
+        self.next_id += 1;
+        let message_id = format!("fake-{:08}", self.next_id);
+        let mut md5_of_body = sqs::md5_hex(&message.body);
+        if self.corrupt_next > 0 {
+            self.corrupt_next -= 1;
+            md5_of_body = sqs::md5_hex(&format!("{} (corrupted)", message.body));
+        }
+        let sent = SentMessage{
+            message_id: Some(message_id.clone()),
+            sequence_number: Some(format!("{:020}", self.next_id)).filter(|_| fifo),
+            md5_of_body: Some(md5_of_body),
+        };
+        let queue = self.queues.entry(queue_url.to_string()).or_default();
+        if let (true, Some(dedup_id)) = (fifo, &message.dedup_id) {
+            queue.dedup.retain(|_, (_, sent)| now.duration_since(*sent) < DEDUP_WINDOW);
+            if let Some((original, _)) = queue.dedup.get(dedup_id) {
+                return Ok(original.clone())
+            }
+            queue.dedup.insert(dedup_id.clone(), (sent.clone(), now));
+        }
+        queue.messages.push(FakeMessage{
+            message_id: message_id.clone(),
+            body: message.body,
+            group_id: message.group_id,
+            receive_count: 0,
+            visible_at: now,
+            receipt_handle: None,
+        });
+        Ok(sent)
+    }
+
+    fn find(&mut self, queue_url: &str, receipt_handle: &str) -> Result<(&mut FakeQueue, usize), EventfulError> {
+        let queue = self.queues.entry(queue_url.to_string()).or_default();
+        match queue.messages.iter().position(|message| message.receipt_handle.as_deref() == Some(receipt_handle)) {
+            Some(i) => Ok((queue, i)),
+            None => Err(EventfulError::SQS(format!("receipt handle {} is not valid for {}", receipt_handle, queue_url))),
+        }
+    }
+}
+
+fn is_fifo(queue_url: &str) -> bool {
+    queue_url.ends_with(".fifo")
+}
+
+
+/// FakeSqs is an in-memory SqsApi. Queues are created on first use, and queues whose URL ends in .fifo behave like FIFO queues.
+///
+/// - A received message is invisible until it is deleted or its visibility timeout passes, after which it is received
+///   again with a higher ApproximateReceiveCount. Time is tokio time, so tests with a paused clock can advance past timeouts.
+/// - In a FIFO queue, messages are received in order within a group and nothing more from a group is received while
+///   an earlier message of it is in flight. A repeated dedup_id within 5 minutes is dropped.
+/// - fail_next(n) makes the next n calls fail, and corrupt_next(n) has the next n sends return an MD5 digest which isn't the body's.
+///
+/// Clones share the same queues.
+///
+/// # Examples:
+/// ```no_run
+/// # use std::{sync::Arc, time::Duration};
+/// # use eventful::prelude::*;
+/// # use eventful::consumer;
+/// # use eventful::sqs::{SqsApi, SqsApiExt};
+/// # use eventful::testing::FakeSqs;
+/// # #[derive(Serialize, Deserialize)]
+/// # struct UserClickedSomething { user_id: i32 }
+/// # impl Event for UserClickedSomething { fn queue_env_var() -> Option<&'static str> { Some("CLICKS_QUEUE_URL") } }
+/// # async fn handle_click(click: UserClickedSomething) -> Result<(), EventfulError> { Ok(()) }
+/// # async fn demo(click: UserClickedSomething, options: ConsumerOptions) -> Result<(), EventfulError> {
+/// let sqs = FakeSqs::new().visibility_timeout(Duration::from_secs(5));
+/// let api: Arc<dyn SqsApi> = Arc::new(sqs.clone());
+/// api.publish(&click).await?;
+/// let queue_url = UserClickedSomething::try_queue_url()?;
+/// let subscriber = sqs.subscribe(queue_url).wait_time_seconds(0);
+/// consumer::run(Box::new(subscriber), &options, handle_click).await?;
+/// assert_eq!(sqs.deleted(queue_url), 1);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct FakeSqs {
+    visibility_timeout: Duration,
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl Default for FakeSqs {
+    fn default() -> Self {
+        FakeSqs::new()
+    }
+}
+
+impl FakeSqs {
+    /// An empty FakeSqs with a 30 second visibility timeout
+    pub fn new() -> Self {
+        FakeSqs{visibility_timeout: Duration::from_secs(30), state: Arc::new(Mutex::new(FakeState::default()))}
+    }
+
+    /// How long a received message stays invisible (default 30 seconds, as in SQS)
+    pub fn visibility_timeout(mut self, timeout: Duration) -> Self {
+        self.visibility_timeout = timeout;
+        self
+    }
+
+    /// A SubscriptionSQS over one of the fake queues
+    pub fn subscribe(&self, queue_url: &str) -> SubscriptionSQS {
+        SubscriptionSQS::from_api(Arc::new(self.clone()), queue_url)
+    }
+
+    /// Fail the next `times` calls (of any kind) with an SQS error
+    pub fn fail_next(&self, times: u32) {
+        self.state.lock().unwrap().fail_next = times;
+    }
+
+    /// Return the wrong MD5OfMessageBody for the next `times` messages sent (the messages are stored as sent),
+    /// as SQS would if a body were corrupted on the way
+    pub fn corrupt_next(&self, times: u32) {
+        self.state.lock().unwrap().corrupt_next = times;
+    }
+
+    /// The bodies of every message not yet deleted from a queue, in flight or not, oldest first
+    pub fn bodies(&self, queue_url: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.queues.get(queue_url)
+            .map(|queue| queue.messages.iter().map(|message| message.body.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// How many messages of a queue have been received and are still invisible
+    pub fn in_flight(&self, queue_url: &str) -> usize {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        state.queues.get(queue_url)
+            .map(|queue| queue.messages.iter().filter(|message| message.receipt_handle.is_some() && message.visible_at > now).count())
+            .unwrap_or_default()
+    }
+
+    /// How many messages have been deleted (acked) from a queue
+    pub fn deleted(&self, queue_url: &str) -> usize {
+        self.state.lock().unwrap().queues.get(queue_url).map(|queue| queue.deleted).unwrap_or_default()
+    }
+
+    /// Receive up to max visible messages, making them invisible
+    fn take_visible(&self, queue_url: &str, max: usize) -> Vec<Message> {
+        let now = Instant::now();
+        let fifo = is_fifo(queue_url);
+        let mut state = self.state.lock().unwrap();
+        let queue = state.queues.entry(queue_url.to_string()).or_default();
+        let mut blocked_groups = HashSet::new();
+        let mut received = Vec::new();
+        for message in queue.messages.iter_mut() {
+            if received.len() >= max {
+                break
+            }
+            let group = message.group_id.as_ref().filter(|_| fifo);
+            if group.map(|group| blocked_groups.contains(group)).unwrap_or(false) {
+                continue
+            }
+            if message.visible_at > now {
+                // an earlier message of this group is in flight
+                if let Some(group) = group {
+                    blocked_groups.insert(group.clone());
+                }
+                continue
+            }
+            message.receive_count += 1;
+            message.visible_at = now + self.visibility_timeout;
+            let receipt_handle = format!("{}-{}", message.message_id, message.receive_count);
+            message.receipt_handle = Some(receipt_handle.clone());
+            let mut builder = Message::builder()
+                .message_id(&message.message_id)
+                .receipt_handle(receipt_handle)
+                .body(&message.body)
+                .attributes(MessageSystemAttributeName::ApproximateReceiveCount, message.receive_count.to_string());
+            if let Some(group_id) = &message.group_id {
+                builder = builder.attributes(MessageSystemAttributeName::MessageGroupId, group_id);
+            }
+            received.push(builder.build());
+        }
+        received
+    }
+}
+
+#[async_trait]
+impl SqsApi for FakeSqs {
+    async fn send_message(&self, queue_url: &str, message: OutgoingSQS) -> Result<SentMessage, EventfulError> {
+        let mut state = self.state.lock().unwrap();
+        state.injected_failure()?;
+        state.enqueue(queue_url, message)
+    }
+
+    async fn send_message_batch(&self, queue_url: &str, messages: Vec<OutgoingSQS>) -> Result<Vec<SentMessage>, EventfulError> {
+        let mut state = self.state.lock().unwrap();
+        state.injected_failure()?;
+        messages.into_iter().map(|message| state.enqueue(queue_url, message)).collect()
+    }
+
+    async fn receive_messages(&self, queue_url: &str, max_messages: i32, wait_time_seconds: i32) -> Result<Vec<Message>, EventfulError> {
+        self.state.lock().unwrap().injected_failure()?;
+        let deadline = Instant::now() + Duration::from_secs(wait_time_seconds.clamp(0, 20) as u64);
+        loop {
+            let received = self.take_visible(queue_url, max_messages.clamp(1, 10) as usize);
+            let now = Instant::now();
+            if !received.is_empty() || now >= deadline {
+                return Ok(received)
+            }
+            tokio::time::sleep((deadline - now).min(Duration::from_millis(10))).await;
+        }
+    }
+
+    async fn delete_message(&self, queue_url: &str, receipt_handle: &str) -> Result<(), EventfulError> {
+        let mut state = self.state.lock().unwrap();
+        state.injected_failure()?;
+        let (queue, i) = state.find(queue_url, receipt_handle)?;
+        queue.messages.remove(i);
+        queue.deleted += 1;
+        Ok(())
+    }
+
+    async fn change_visibility(&self, queue_url: &str, receipt_handle: &str, seconds: i32) -> Result<(), EventfulError> {
+        let mut state = self.state.lock().unwrap();
+        state.injected_failure()?;
+        let (queue, i) = state.find(queue_url, receipt_handle)?;
+        queue.messages[i].visible_at = Instant::now() + Duration::from_secs(seconds.max(0) as u64);
+        Ok(())
+    }
+}
+
+/// FakeSqs can stand in for ClientSQS as a Publisher too
+#[async_trait]
+impl Publisher for FakeSqs {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, meta: &Metadata) -> Result<Receipt, EventfulError> {
+        let queue_url = match dest {
+            Destination::SqsQueue(url) => url,
+            _ => return Err(publisher::unsupported("FakeSqs", dest)),
+        };
+        let body = String::from_utf8(body.to_vec())
+            .map_err(|_| EventfulError::SQS("SQS message bodies must be valid UTF-8".to_string()))?;
+        let message = OutgoingSQS{body, group_id: meta.group_id.clone(), dedup_id: meta.dedup_id.clone()};
+        let sent = self.send_message(queue_url, message).await?;
+        Ok(Receipt{message_id: sent.message_id})
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+    use crate::consumer::{self, ConsumerOptions};
+    use crate::retry::{Backoff, RetryPolicy};
+    use crate::subscriber::Subscriber;
+
+    const QUEUE: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/clicks";
+    const FIFO_QUEUE: &str = "https://sqs.us-east-1.amazonaws.com/000000000000/clicks.fifo";
+
+    type Log = Arc<Mutex<Vec<(Duration, String)>>>;
+
+    async fn send(sqs: &FakeSqs, queue_url: &str, group: &str, n: u32) {
+        let body = json!({"group": group, "n": n}).to_string();
+        let group_id = Some(group.to_string()).filter(|_| is_fifo(queue_url));
+        let dedup_id = group_id.as_ref().map(|group| format!("{}{}", group, n));
+        sqs.send_message(queue_url, OutgoingSQS{body, group_id, dedup_id}).await.unwrap();
+    }
+
+    fn options() -> ConsumerOptions {
+        ConsumerOptions::default().retry(RetryPolicy::default().max_attempts(5).backoff(Backoff::Fixed(Duration::from_secs(5))))
+    }
+
+    /// Run consumer::run over the queue for `run_for` (the subscription never ends on its own), logging each event handled
+    /// as "a1", "b2"... with when it was handled. The handler fails the first attempt at each event in `fail_once`
+    async fn consume(subscriber: SubscriptionSQS, run_for: Duration, fail_once: &'static [&'static str]) -> Vec<(Duration, String)> {
+        let log: Log = Arc::default();
+        let started = Instant::now();
+        let handler_log = log.clone();
+        let handler = move |event: Value| {
+            let log = handler_log.clone();
+            async move {
+                let label = format!("{}{}", event["group"].as_str().unwrap(), event["n"]);
+                let mut log = log.lock().unwrap();
+                let first = !log.iter().any(|(_, seen)| *seen == label);
+                log.push((started.elapsed(), label.clone()));
+                match first && fail_once.contains(&label.as_str()) {
+                    true => Err(EventfulError::Destination(format!("{} failed", label))),
+                    false => Ok(()),
+                }
+            }
+        };
+        let options = options();
+        let ran = tokio::time::timeout(run_for, consumer::run(Box::new(subscriber.wait_time_seconds(1)), &options, handler)).await;
+        assert!(ran.is_err(), "the consumer stopped early: {:?}", ran);
+        let log = log.lock().unwrap().clone();
+        log
+    }
+
+    fn labels(log: &[(Duration, String)]) -> Vec<&str> {
+        log.iter().map(|(_, label)| label.as_str()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handled_messages_are_deleted() {
+        let sqs = FakeSqs::new();
+        for n in 1..=3 {
+            send(&sqs, QUEUE, "a", n).await;
+        }
+        let log = consume(sqs.subscribe(QUEUE), Duration::from_secs(2), &[]).await;
+        assert_eq!(labels(&log), vec!["a1", "a2", "a3"]);
+        assert_eq!(sqs.deleted(QUEUE), 3);
+        assert!(sqs.bodies(QUEUE).is_empty());
+        assert_eq!(sqs.in_flight(QUEUE), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_message_is_nacked_and_redelivered_after_the_backoff() {
+        let sqs = FakeSqs::new();
+        send(&sqs, QUEUE, "a", 1).await;
+        let log = consume(sqs.subscribe(QUEUE), Duration::from_secs(2), &["a1"]).await;
+        assert_eq!(labels(&log), vec!["a1"]);
+        // nacked with the retry backoff, so it stays invisible but isn't deleted
+        assert_eq!(sqs.deleted(QUEUE), 0);
+        assert_eq!(sqs.in_flight(QUEUE), 1);
+
+        let log = consume(sqs.subscribe(QUEUE), Duration::from_secs(5), &[]).await;
+        assert_eq!(labels(&log), vec!["a1"]);
+        // the backoff was 5 seconds, 2 of which passed during the first run
+        assert!(log[0].0 >= Duration::from_secs(3), "redelivered after {:?}", log[0].0);
+        assert_eq!(sqs.deleted(QUEUE), 1);
+        assert_eq!(sqs.in_flight(QUEUE), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unsettled_message_reappears_after_the_visibility_timeout() {
+        let sqs = FakeSqs::new().visibility_timeout(Duration::from_secs(5));
+        send(&sqs, QUEUE, "a", 1).await;
+        let mut subscriber = sqs.subscribe(QUEUE).wait_time_seconds(1);
+        let started = Instant::now();
+        let first = subscriber.next().await.unwrap().unwrap();
+        assert_eq!(first.attempt, 1);
+        let message_id = first.message_id.clone();
+        // dropping an SQS delivery leaves the message to its visibility timeout
+        drop(first);
+        assert_eq!(sqs.in_flight(QUEUE), 1);
+
+        let second = subscriber.next().await.unwrap().unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(5), "reappeared after {:?}", started.elapsed());
+        assert_eq!(second.message_id, message_id);
+        assert_eq!(second.attempt, 2);
+        second.ack().await.unwrap();
+        assert_eq!(sqs.deleted(QUEUE), 1);
+        assert!(sqs.bodies(QUEUE).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fifo_groups_keep_their_order_through_a_retry() {
+        let sqs = FakeSqs::new();
+        for (group, n) in [("a", 1), ("b", 1), ("a", 2), ("b", 2), ("a", 3)] {
+            send(&sqs, FIFO_QUEUE, group, n).await;
+        }
+        // one message per receive, so a1's retry holds back the rest of group a but not group b
+        let log = consume(sqs.subscribe(FIFO_QUEUE).max_messages(1), Duration::from_secs(10), &["a1"]).await;
+        assert_eq!(labels(&log), vec!["a1", "b1", "b2", "a1", "a2", "a3"]);
+        assert!(log[3].0 >= Duration::from_secs(5), "a1 was retried after {:?}", log[3].0);
+        assert!(log[2].0 < Duration::from_secs(5), "group b waited for group a");
+        assert_eq!(sqs.deleted(FIFO_QUEUE), 5);
+    }
+}
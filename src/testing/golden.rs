@@ -0,0 +1,304 @@
+use std::{env, fmt::Write as _, fs, path::{Path, PathBuf}};
+use serde::Serialize;
+use serde_json::Value;
+use crate::codec::{Codec, JsonCodec};
+
+
+/// Replaces ignored fields in both the golden file and the encoded example
+const IGNORED: &str = "<ignored>";
+const HEX_WIDTH: usize = 16;
+
+
+/// Panic unless `example`, encoded as JSON, matches the golden file at path. Run with UPDATE_GOLDEN=1 to (re)write it
+pub fn assert_matches_golden<T: Serialize + ?Sized, P: AsRef<Path>>(path: P, example: &T) {
+    Golden::new(path).assert(example)
+}
+
+
+/// A Golden compares an encoded example against a checked-in file, so changes to an event's wire format show up in review.
+///
+/// JSON (any codec whose content type mentions json) is compared structurally and the file is pretty-printed;
+/// fields named by ignore() are replaced with "<ignored>" on both sides. Anything else is compared byte for byte,
+/// and a mismatch is shown as a hex dump diff. When the UPDATE_GOLDEN environment variable is 1,
+/// the file is written instead of compared.
+///
+/// # Examples:
+/// ```no_run
+/// # use eventful::prelude::*;
+/// # use eventful::testing::Golden;
+/// # #[derive(Serialize, Deserialize)]
+/// # struct OrderPlaced { order_id: u64, placed_at: String }
+/// # impl OrderPlaced { fn example() -> Self { OrderPlaced{order_id: 7, placed_at: "2023-03-14T09:26:53Z".to_string()} } }
+/// Golden::new("tests/golden/order_placed.json")
+///     .ignore("/placed_at")
+///     .ignore("/order_id")
+///     .assert(&OrderPlaced::example());
+/// ```
+pub struct Golden<C: Codec = JsonCodec> {
+    path: PathBuf,
+    codec: C,
+    ignore: Vec<String>,
+}
+
+impl Golden<JsonCodec> {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Golden{path: path.as_ref().to_path_buf(), codec: JsonCodec, ignore: Vec::new()}
+    }
+}
+
+impl<C: Codec> Golden<C> {
+    /// Encode with this codec instead of the JsonCodec
+    pub fn codec<D: Codec>(self, codec: D) -> Golden<D> {
+        Golden{path: self.path, codec, ignore: self.ignore}
+    }
+
+    /// Ignore the field at a JSON pointer, i.e. "/placed_at" or "/items/0/id". Can be called more than once
+    pub fn ignore(mut self, pointer: &str) -> Self {
+        self.ignore.push(pointer.to_string());
+        self
+    }
+
+    /// Panic if the example doesn't match the golden file, showing the differences
+    pub fn assert<T: Serialize + ?Sized>(&self, example: &T) {
+        if let Err(message) = self.check(example) {
+            panic!("{}", message);
+        }
+    }
+
+    /// Like assert(), but returns the failure message instead of panicking
+    pub fn check<T: Serialize + ?Sized>(&self, example: &T) -> Result<(), String> {
+        let encoded = self.codec.encode(example).map_err(|err| format!("could not encode the example: {}", err))?;
+        let json = self.codec.content_type().contains("json");
+        let actual = match json {
+            true => {
+                let mut value: Value = serde_json::from_slice(&encoded).map_err(|err| format!("the codec did not produce JSON: {}", err))?;
+                self.normalize(&mut value);
+                let mut pretty = serde_json::to_string_pretty(&value).unwrap_or_default();
+                pretty.push('\n');
+                pretty.into_bytes()
+            },
+            false => encoded.to_vec(),
+        };
+        if env::var("UPDATE_GOLDEN").map(|update| update == "1").unwrap_or(false) {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir).map_err(|err| format!("could not create {}: {}", dir.display(), err))?;
+            }
+            return fs::write(&self.path, &actual).map_err(|err| format!("could not write {}: {}", self.path.display(), err))
+        }
+        let expected = fs::read(&self.path)
+            .map_err(|err| format!("could not read golden file {} ({}); run with UPDATE_GOLDEN=1 to create it", self.path.display(), err))?;
+        let differences = match json {
+            true => {
+                let mut expected: Value = serde_json::from_slice(&expected)
+                    .map_err(|err| format!("golden file {} is not JSON: {}", self.path.display(), err))?;
+                self.normalize(&mut expected);
+                let actual: Value = serde_json::from_slice(&actual).unwrap_or_default();
+                let mut differences = Vec::new();
+                json_diff("", &expected, &actual, &mut differences);
+                differences
+            },
+            false => hex_diff(&expected, &actual),
+        };
+        if differences.is_empty() {
+            return Ok(())
+        }
+        Err(format!("the example does not match golden file {} (run with UPDATE_GOLDEN=1 if the change is intended):\n{}",
+            self.path.display(), differences.join("\n")))
+    }
+
+    fn normalize(&self, value: &mut Value) {
+        for pointer in &self.ignore {
+            if let Some(field) = value.pointer_mut(pointer) {
+                *field = Value::String(IGNORED.to_string());
+            }
+        }
+    }
+}
+
+
+/// One line per difference, i.e. "  /items/0/price: expected 5, found 6"
+fn json_diff(pointer: &str, expected: &Value, actual: &Value, out: &mut Vec<String>) {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            for (key, expected) in expected {
+                let path = format!("{}/{}", pointer, escape(key));
+                match actual.get(key) {
+                    Some(actual) => json_diff(&path, expected, actual, out),
+                    None => out.push(format!("  {}: missing (expected {})", path, expected)),
+                }
+            }
+            for (key, actual) in actual {
+                if !expected.contains_key(key) {
+                    out.push(format!("  {}/{}: unexpected (found {})", pointer, escape(key), actual));
+                }
+            }
+        },
+        (Value::Array(expected), Value::Array(actual)) => {
+            for (i, (expected, actual)) in expected.iter().zip(actual).enumerate() {
+                json_diff(&format!("{}/{}", pointer, i), expected, actual, out);
+            }
+            if expected.len() != actual.len() {
+                out.push(format!("  {}: expected {} items, found {}", display(pointer), expected.len(), actual.len()));
+            }
+        },
+        (expected, actual) if expected != actual => {
+            out.push(format!("  {}: expected {}, found {}", display(pointer), expected, actual));
+        },
+        _ => {},
+    }
+}
+
+/// Escape a key for a JSON pointer
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn display(pointer: &str) -> &str {
+    match pointer {
+        "" => "/",
+        pointer => pointer,
+    }
+}
+
+/// A hex dump of each 16 byte row which differs, expected (-) above actual (+)
+fn hex_diff(expected: &[u8], actual: &[u8]) -> Vec<String> {
+    let mut out = Vec::new();
+    if expected.len() != actual.len() {
+        out.push(format!("  expected {} bytes, found {}", expected.len(), actual.len()));
+    }
+    let rows = expected.len().max(actual.len()).div_ceil(HEX_WIDTH);
+    for row in 0..rows {
+        let expected = chunk(expected, row);
+        let actual = chunk(actual, row);
+        if expected != actual {
+            out.push(format!("- {:08x}  {}", row * HEX_WIDTH, hex_row(expected)));
+            out.push(format!("+ {:08x}  {}", row * HEX_WIDTH, hex_row(actual)));
+        }
+    }
+    out
+}
+
+fn chunk(bytes: &[u8], row: usize) -> &[u8] {
+    let start = (row * HEX_WIDTH).min(bytes.len());
+    &bytes[start..(start + HEX_WIDTH).min(bytes.len())]
+}
+
+/// "7b 22 69 64 ...  |{"id...|"
+fn hex_row(bytes: &[u8]) -> String {
+    let mut hex = String::new();
+    for i in 0..HEX_WIDTH {
+        match bytes.get(i) {
+            Some(b) => { let _ = write!(hex, "{:02x} ", b); },
+            None => hex.push_str("   "),
+        }
+    }
+    let ascii: String = bytes.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect();
+    format!("{} |{}|", hex, ascii)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+    use crate::err::EventfulError;
+
+    /// Written to a fresh temporary file, removed when dropped
+    struct GoldenFile(PathBuf);
+
+    impl GoldenFile {
+        fn new(contents: &[u8]) -> Self {
+            let path = env::temp_dir().join(format!("eventful-golden-{}.json", rand::random::<u32>()));
+            fs::write(&path, contents).unwrap();
+            GoldenFile(path)
+        }
+    }
+
+    impl Drop for GoldenFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// JSON compact enough to be compared byte for byte, as a binary codec would be
+    struct RawCodec;
+
+    impl Codec for RawCodec {
+        fn content_type(&self) -> &'static str {
+            "application/octet-stream"
+        }
+
+        fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<bytes::Bytes, EventfulError> {
+            Ok(serde_json::to_vec(value)?.into())
+        }
+
+        fn decode<T: serde::de::DeserializeOwned>(&self, body: &[u8]) -> Result<T, EventfulError> {
+            Ok(serde_json::from_slice(body)?)
+        }
+    }
+
+    fn header(file: &GoldenFile) -> String {
+        format!("the example does not match golden file {} (run with UPDATE_GOLDEN=1 if the change is intended):\n", file.0.display())
+    }
+
+    #[test]
+    fn a_json_mismatch_lists_each_difference_by_pointer() {
+        let file = GoldenFile::new(br#"{
+  "a/b": 1,
+  "id": 1,
+  "items": [{"price": 5}],
+  "old": true,
+  "placed_at": "2023-01-01T00:00:00Z"
+}
+"#);
+        let example = json!({"a/b": 3, "id": 2, "items": [{"price": 6}, {"price": 7}], "placed_at": "2024-06-30T12:00:00Z", "new": "x"});
+        let failure = Golden::new(&file.0).ignore("/placed_at").check(&example).unwrap_err();
+        assert_eq!(failure, header(&file) + &[
+            "  /a~1b: expected 1, found 3",
+            "  /id: expected 1, found 2",
+            "  /items/0/price: expected 5, found 6",
+            "  /items: expected 1 items, found 2",
+            "  /old: missing (expected true)",
+            "  /new: unexpected (found \"x\")",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn the_root_is_shown_as_a_slash() {
+        let file = GoldenFile::new(b"5\n");
+        assert_eq!(Golden::new(&file.0).check(&6).unwrap_err(), header(&file) + "  /: expected 5, found 6");
+    }
+
+    #[test]
+    fn a_match_ignores_formatting_and_ignored_fields() {
+        let file = GoldenFile::new(br#"{"id": 1, "placed_at": "<ignored>", "items": []}"#);
+        let golden = Golden::new(&file.0).ignore("/placed_at").ignore("/not/there");
+        golden.check(&json!({"items": [], "id": 1, "placed_at": "2024-06-30T12:00:00Z"})).unwrap();
+        golden.assert(&json!({"id": 1, "placed_at": null, "items": []}));
+    }
+
+    #[test]
+    fn a_binary_mismatch_is_a_hex_dump_of_the_rows_which_differ() {
+        let file = GoldenFile::new(br#""0123456789abcdefXY""#);
+        let failure = Golden::new(&file.0).codec(RawCodec).check("0123456789abcdefX\n").unwrap_err();
+        // 20 bytes against 21 (the newline is escaped): the first row matches, the second does not
+        let expected = [
+            "  expected 20 bytes, found 21".to_string(),
+            format!("- 00000010  66 58 59 22 {} |fXY\"|", "   ".repeat(12)),
+            format!("+ 00000010  66 58 5c 6e 22 {} |fX\\n\"|", "   ".repeat(11)),
+        ];
+        assert_eq!(failure, header(&file) + &expected.join("\n"));
+    }
+
+    #[test]
+    fn hex_rows_show_unprintable_bytes_as_dots() {
+        assert_eq!(hex_row(b"a \x00\xff"), format!("61 20 00 ff {} |a ..|", "   ".repeat(12)));
+        assert!(hex_diff(b"same", b"same").is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "run with UPDATE_GOLDEN=1 to create it")]
+    fn a_missing_golden_file_says_how_to_create_it() {
+        assert_matches_golden(env::temp_dir().join("eventful-golden-missing.json"), &json!({"id": 1}));
+    }
+}
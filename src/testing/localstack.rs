@@ -0,0 +1,266 @@
+use std::time::Duration;
+use aws_sdk_sqs::{Client, Config, Credentials, Region, model::QueueAttributeName};
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::time::Instant;
+use crate::err::EventfulError;
+use crate::sqs::ClientSQS;
+
+
+const REGION: &str = "us-east-1";
+
+/// Where localstack listens by default
+const DEFAULT_ENDPOINT: &str = "http://localhost:4566";
+
+
+/// An SQS client for localstack (or any SQS-compatible endpoint), with static test credentials,
+/// which creates throwaway queues for integration tests.
+///
+/// # Examples:
+/// ```no_run
+/// # use std::time::Duration;
+/// # use bytes::Bytes;
+/// # use eventful::prelude::*;
+/// # use eventful::testing::LocalstackSqs;
+/// # async fn demo(body: Bytes) -> Result<(), EventfulError> {
+/// let localstack = LocalstackSqs::connect("http://localhost:4566").await?;
+/// let queue = localstack.create_temp_queue("clicks").await?;
+/// localstack.client().publish_bytes(&Destination::SqsQueue(queue.url().to_string()), body, &Metadata::default()).await?;
+/// localstack.wait_until_visible(queue.url(), 1, Duration::from_secs(5)).await?;
+/// // the queue is deleted when `queue` is dropped
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LocalstackSqs {
+    endpoint: String,
+    sqs: ClientSQS,
+}
+
+impl LocalstackSqs {
+    /// Build a client for the endpoint, i.e. "http://localhost:4566", and check that it answers
+    pub async fn connect(endpoint: &str) -> Result<Self, EventfulError> {
+        let sqs = ClientSQS::from_client(test_client(endpoint));
+        sqs.client().list_queues().send().await?;
+        Ok(LocalstackSqs{endpoint: endpoint.to_string(), sqs})
+    }
+
+    /// Connect to the endpoint in LOCALSTACK_ENDPOINT, or http://localhost:4566 when it is unset
+    pub async fn from_env() -> Result<Self, EventfulError> {
+        let endpoint = std::env::var("LOCALSTACK_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+        LocalstackSqs::connect(&endpoint).await
+    }
+
+    /// The endpoint requests are sent to
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// The ClientSQS, which publishes, polls, and subscribes as usual
+    pub fn client(&self) -> &ClientSQS {
+        &self.sqs
+    }
+
+    /// Create a standard queue named {prefix}-{random suffix}, deleted when the TempQueue is dropped
+    pub async fn create_temp_queue(&self, prefix: &str) -> Result<TempQueue, EventfulError> {
+        self.create(prefix, false).await
+    }
+
+    /// Create a FIFO queue (with content-based deduplication) named {prefix}-{random suffix}.fifo
+    pub async fn create_temp_fifo_queue(&self, prefix: &str) -> Result<TempQueue, EventfulError> {
+        self.create(prefix, true).await
+    }
+
+    async fn create(&self, prefix: &str, fifo: bool) -> Result<TempQueue, EventfulError> {
+        let suffix = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+        let mut name = format!("{}-{}", prefix, suffix);
+        let mut create = self.sqs.client().create_queue();
+        if fifo {
+            name.push_str(".fifo");
+            create = create
+                .attributes(QueueAttributeName::FifoQueue, "true")
+                .attributes(QueueAttributeName::ContentBasedDeduplication, "true");
+        }
+        let output = create.queue_name(&name).send().await?;
+        let url = output.queue_url.ok_or_else(|| EventfulError::SQS(format!("CreateQueue did not return a URL for {}", name)))?;
+        Ok(TempQueue{endpoint: self.endpoint.clone(), url, deleted: false})
+    }
+
+    /// Wait until at least n messages are visible on a queue (without receiving them),
+    /// returning EventfulError::Timeout if that doesn't happen within the deadline
+    pub async fn wait_until_visible(&self, queue_url: &str, n: u64, deadline: Duration) -> Result<(), EventfulError> {
+        let give_up_at = Instant::now() + deadline;
+        loop {
+            let output = self.sqs.client().get_queue_attributes()
+                .queue_url(queue_url)
+                .attribute_names(QueueAttributeName::ApproximateNumberOfMessages)
+                .send().await?;
+            let visible = output.attributes.unwrap_or_default()
+                .get(&QueueAttributeName::ApproximateNumberOfMessages)
+                .and_then(|count| count.parse::<u64>().ok())
+                .unwrap_or(0);
+            if visible >= n {
+                return Ok(())
+            }
+            if Instant::now() >= give_up_at {
+                return Err(EventfulError::Timeout(format!("{} of {} messages visible on {} after {:?}", visible, n, queue_url, deadline)))
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+
+/// A queue which is purged and deleted when dropped. Call delete() to do so immediately and see any error
+pub struct TempQueue {
+    endpoint: String,
+    url: String,
+    deleted: bool,
+}
+
+impl TempQueue {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Purge (best effort) and delete the queue now
+    pub async fn delete(mut self) -> Result<(), EventfulError> {
+        self.deleted = true;
+        purge_and_delete(&test_client(&self.endpoint), &self.url).await
+    }
+}
+
+/// Deleting needs async requests, so drop runs them on a short-lived runtime in another thread.
+/// Errors are ignored; the queue is a throwaway
+impl Drop for TempQueue {
+    fn drop(&mut self) {
+        if self.deleted {
+            return
+        }
+        let endpoint = self.endpoint.clone();
+        let url = self.url.clone();
+        let cleanup = std::thread::spawn(move || {
+            if let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                let _ = runtime.block_on(purge_and_delete(&test_client(&endpoint), &url));
+            }
+        });
+        let _ = cleanup.join();
+    }
+}
+
+
+/// A client with static test credentials. Built fresh where needed, so it never depends on another runtime
+fn test_client(endpoint: &str) -> Client {
+    let config = Config::builder()
+        .region(Region::new(REGION))
+        .credentials_provider(Credentials::new("test", "test", None, None, "eventful-localstack"))
+        .endpoint_url(endpoint)
+        .build();
+    Client::from_conf(config)
+}
+
+async fn purge_and_delete(client: &Client, queue_url: &str) -> Result<(), EventfulError> {
+    let _ = client.purge_queue().queue_url(queue_url).send().await;
+    client.delete_queue().queue_url(queue_url).send().await?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::httpstub::HttpStub;
+
+    fn response(action: &str, result: &str) -> (u16, String) {
+        (200, format!("<{action}Response>{result}<ResponseMetadata><RequestId>r-1</RequestId></ResponseMetadata></{action}Response>"))
+    }
+
+    fn listed() -> (u16, String) {
+        response("ListQueues", "<ListQueuesResult></ListQueuesResult>")
+    }
+
+    fn created(queue_url: &str) -> (u16, String) {
+        response("CreateQueue", &format!("<CreateQueueResult><QueueUrl>{}</QueueUrl></CreateQueueResult>", queue_url))
+    }
+
+    fn visible(count: u64) -> (u16, String) {
+        response("GetQueueAttributes", &format!("<GetQueueAttributesResult><Attribute><Name>ApproximateNumberOfMessages</Name>\
+            <Value>{}</Value></Attribute></GetQueueAttributesResult>", count))
+    }
+
+    fn error(code: &str) -> (u16, String) {
+        (400, format!("<ErrorResponse><Error><Type>Sender</Type><Code>{}</Code><Message>stubbed</Message></Error>\
+            <RequestId>r-1</RequestId></ErrorResponse>", code))
+    }
+
+    /// The Action and QueueName parameters of each request
+    fn requests(stub: &HttpStub) -> Vec<(String, Option<String>)> {
+        stub.requests().iter().map(|request| {
+            let param = |name: &str| request.body_str().split('&').find_map(|param| param.strip_prefix(name)).map(str::to_string);
+            (param("Action=").unwrap_or_default(), param("QueueName="))
+        }).collect()
+    }
+
+    #[tokio::test]
+    async fn connecting_fails_when_the_endpoint_does_not_answer_like_sqs() {
+        let stub = HttpStub::start(vec![error("AccessDenied")]).await;
+        assert!(LocalstackSqs::connect(&stub.url()).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_temp_queue_gets_a_random_name_and_is_purged_and_deleted_when_dropped() {
+        let stub = HttpStub::start(vec![listed(), created("http://localhost:4566/000000000000/clicks-x"), response("PurgeQueue", ""), response("DeleteQueue", "")]).await;
+        let localstack = LocalstackSqs::connect(&stub.url()).await.unwrap();
+        assert_eq!(localstack.endpoint(), stub.url());
+        let queue = localstack.create_temp_queue("clicks").await.unwrap();
+        assert_eq!(queue.url(), "http://localhost:4566/000000000000/clicks-x");
+        drop(queue);
+
+        let requests = requests(&stub);
+        let actions: Vec<&str> = requests.iter().map(|(action, _)| action.as_str()).collect();
+        assert_eq!(actions, ["ListQueues", "CreateQueue", "PurgeQueue", "DeleteQueue"]);
+        let name = requests[1].1.clone().unwrap();
+        assert!(name.starts_with("clicks-") && name.len() == "clicks-".len() + 8, "{}", name);
+        assert!(!stub.requests()[1].body_str().contains("FifoQueue"));
+    }
+
+    #[tokio::test]
+    async fn a_temp_fifo_queue_uses_content_based_deduplication() {
+        let stub = HttpStub::start(vec![listed(), created("http://localhost:4566/000000000000/orders-x.fifo"), response("PurgeQueue", ""), response("DeleteQueue", "")]).await;
+        let localstack = LocalstackSqs::connect(&stub.url()).await.unwrap();
+        localstack.create_temp_fifo_queue("orders").await.unwrap().delete().await.unwrap();
+
+        let requests = requests(&stub);
+        assert!(requests[1].1.as_deref().unwrap().ends_with(".fifo"));
+        let create = stub.requests()[1].body_str().to_string();
+        assert!(create.contains("FifoQueue") && create.contains("ContentBasedDeduplication"), "{}", create);
+        // deleting explicitly leaves nothing for drop to do
+        assert_eq!(requests.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn deleting_reports_a_failed_delete_but_not_a_failed_purge() {
+        let stub = HttpStub::start(vec![listed(), created("http://localhost:4566/000000000000/gone"), error("PurgeQueueInProgress"), error("QueueDoesNotExist")]).await;
+        let localstack = LocalstackSqs::connect(&stub.url()).await.unwrap();
+        assert!(localstack.create_temp_queue("gone").await.unwrap().delete().await.is_err());
+        let actions: Vec<String> = requests(&stub).into_iter().map(|(action, _)| action).collect();
+        assert_eq!(actions, ["ListQueues", "CreateQueue", "PurgeQueue", "DeleteQueue"]);
+    }
+
+    #[tokio::test]
+    async fn waits_until_enough_messages_are_visible() {
+        let stub = HttpStub::start(vec![listed(), visible(0), visible(1), visible(2)]).await;
+        let localstack = LocalstackSqs::connect(&stub.url()).await.unwrap();
+        localstack.wait_until_visible("http://localhost:4566/000000000000/clicks", 2, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(requests(&stub).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn gives_up_waiting_at_the_deadline() {
+        let stub = HttpStub::start(vec![listed(), visible(1)]).await;
+        let localstack = LocalstackSqs::connect(&stub.url()).await.unwrap();
+        match localstack.wait_until_visible("http://localhost:4566/000000000000/clicks", 2, Duration::from_millis(250)).await {
+            Err(EventfulError::Timeout(message)) => assert!(message.starts_with("1 of 2 messages visible"), "{}", message),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+}
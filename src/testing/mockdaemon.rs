@@ -0,0 +1,458 @@
+use std::{convert::Infallible, net::TcpListener, sync::{Arc, Mutex}, time::Duration};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::Utc;
+use hyper::{Body, Method, Request, Response, StatusCode, service::{make_service_fn, service_fn}};
+use serde::de::DeserializeOwned;
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpStream, tcp::OwnedReadHalf}, sync::{mpsc, oneshot}};
+use tokio_util::sync::CancellationToken;
+use crate::codec::{Codec, JsonCodec};
+use crate::err::EventfulError;
+use crate::nsq::Daemon;
+use super::recorder::{EventRecorder, Recorded};
+
+
+/// One message received by a MockDaemon
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReceivedNSQ {
+    pub topic: String,
+    pub body: Bytes,
+    /// The defer parameter of a /pub request, in milliseconds
+    pub defer: Option<u64>,
+}
+
+
+#[derive(Default)]
+struct MockState {
+    received: Vec<ReceivedNSQ>,
+    /// How many upcoming requests get a 500
+    fail_next: u32,
+    /// Consumer connections which have sent SUB, with their topic
+    subscribers: Vec<(String, mpsc::UnboundedSender<Bytes>)>,
+    /// Ids of the messages consumers have sent FIN for
+    finished: Vec<String>,
+    next_id: u64,
+}
+
+impl MockState {
+    /// Send a message to every connection subscribed to its topic, forgetting connections which have closed
+    fn deliver(&mut self, topic: &str, body: &[u8]) {
+        self.next_id += 1;
+        let frame = message_frame(&format!("{:016x}", self.next_id), body);
+        self.subscribers.retain(|(subscribed, sender)| subscribed != topic || sender.send(frame.clone()).is_ok());
+    }
+}
+
+
+/// A MockDaemon serves the publishing half of nsqd's HTTP API (/pub, /mpub, /ping, /topic/create, /channel/create)
+/// on an ephemeral port, and records every message published to it. It stops serving when dropped.
+///
+/// Its TCP side speaks just enough of the nsqd protocol for a consumer: every connection subscribed to a topic is sent
+/// each message published to it afterwards (after the defer, if one was given), whatever the channel, and FINs are recorded.
+/// RDY counts aren't enforced, and REQ and TOUCH are ignored, so a requeued message is not redelivered.
+///
+/// # Examples:
+/// ```
+/// # use eventful::prelude::*;
+/// # use eventful::nsq::post_to;
+/// # use eventful::testing::MockDaemon;
+/// # #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// # struct UserClickedSomething { user_id: i32 }
+/// # impl EventNSQ for UserClickedSomething { fn topic() -> &'static str { "click" } }
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), EventfulError> {
+/// # let click = UserClickedSomething{user_id: 5};
+/// let mock = MockDaemon::start().await?;
+/// mock.respond_with_500_times(1);
+/// post_to(&click, &mock.daemon()).await.unwrap_err();
+/// post_to(&click, &mock.daemon()).await?;
+/// assert_eq!(mock.received_on::<UserClickedSomething>("click"), vec![click]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct MockDaemon {
+    port: u16,
+    tcp_port: u16,
+    state: Arc<Mutex<MockState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    stop_tcp: CancellationToken,
+}
+
+impl MockDaemon {
+    /// Bind 127.0.0.1 on two ephemeral ports, for HTTP and TCP, and start serving
+    pub async fn start() -> Result<Self, EventfulError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req)))
+            }
+        });
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let server = hyper::Server::from_tcp(listener)?
+            .serve(make_service)
+            .with_graceful_shutdown(async { let _ = stopped.await; });
+        tokio::spawn(server);
+
+        let consumers = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let tcp_port = consumers.local_addr()?.port();
+        let stop_tcp = CancellationToken::new();
+        tokio::spawn(accept_consumers(consumers, state.clone(), stop_tcp.clone()));
+        Ok(MockDaemon{port, tcp_port, state, shutdown: Some(shutdown), stop_tcp})
+    }
+
+    /// A Daemon pointing at the mock
+    pub fn daemon(&self) -> Daemon {
+        Daemon::new("127.0.0.1", self.port, self.tcp_port)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Everything received so far, in order
+    pub fn received(&self) -> Vec<ReceivedNSQ> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// The raw bodies received on a topic, in order
+    pub fn bodies_on(&self, topic: &str) -> Vec<Bytes> {
+        self.state.lock().unwrap().received.iter()
+            .filter(|received| received.topic == topic)
+            .map(|received| received.body.clone())
+            .collect()
+    }
+
+    /// The events received on a topic, decoded as JSON. Panics if a body doesn't decode as T
+    pub fn received_on<T: DeserializeOwned>(&self, topic: &str) -> Vec<T> {
+        self.bodies_on(topic).iter()
+            .map(|body| JsonCodec.decode(body).unwrap_or_else(|err| panic!("a message on {} did not decode: {}", topic, err)))
+            .collect()
+    }
+
+    /// The ids of the messages consumers have finished, in order
+    pub fn finished(&self) -> Vec<String> {
+        self.state.lock().unwrap().finished.clone()
+    }
+
+    /// An EventRecorder over everything this mock receives
+    pub fn recorder(&self) -> EventRecorder {
+        let state = self.state.clone();
+        EventRecorder::from_fn(move || {
+            state.lock().unwrap().received.iter()
+                .map(|received| Recorded{topic: received.topic.clone(), body: received.body.clone()})
+                .collect()
+        })
+    }
+
+    /// Forget everything received and finished so far
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.received.clear();
+        state.finished.clear();
+    }
+
+    /// Answer the next `times` requests (of any kind) with a 500 and record nothing from them
+    pub fn respond_with_500_times(&self, times: u32) {
+        self.state.lock().unwrap().fail_next = times;
+    }
+}
+
+impl Drop for MockDaemon {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        self.stop_tcp.cancel();
+    }
+}
+
+
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri().query()?.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+fn respond(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Split an /mpub body: newline separated, or with binary=true a 4 byte count followed by 4 byte sizes and bodies
+fn split_mpub(body: Bytes, binary: bool) -> Option<Vec<Bytes>> {
+    if !binary {
+        return Some(body.split(|b| *b == b'\n').filter(|line| !line.is_empty()).map(Bytes::copy_from_slice).collect())
+    }
+    let mut buf = body;
+    if buf.remaining() < 4 {
+        return None
+    }
+    let count = buf.get_u32();
+    let mut messages = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if buf.remaining() < 4 {
+            return None
+        }
+        let size = buf.get_u32() as usize;
+        if buf.remaining() < size {
+            return None
+        }
+        messages.push(buf.copy_to_bytes(size));
+    }
+    Some(messages)
+}
+
+async fn handle(state: Arc<Mutex<MockState>>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    {
+        let mut state = state.lock().unwrap();
+        if state.fail_next > 0 {
+            state.fail_next -= 1;
+            return Ok(respond(StatusCode::INTERNAL_SERVER_ERROR, "INJECTED_FAILURE"))
+        }
+    }
+    let path = req.uri().path().to_string();
+    let method = req.method().clone();
+    let topic = query_param(&req, "topic");
+    let defer = query_param(&req, "defer").and_then(|defer| defer.parse().ok());
+    let binary = query_param(&req, "binary").map(|binary| binary == "true").unwrap_or(false);
+    let resp = match (method, path.as_str()) {
+        (Method::GET, "/ping") | (Method::HEAD, "/ping") => respond(StatusCode::OK, "OK"),
+        (Method::POST, "/topic/create") | (Method::POST, "/channel/create") => respond(StatusCode::OK, ""),
+        (Method::POST, "/pub") | (Method::POST, "/mpub") => {
+            let topic = match topic {
+                Some(topic) => topic,
+                None => return Ok(respond(StatusCode::BAD_REQUEST, "MISSING_ARG_TOPIC")),
+            };
+            let body = match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => body,
+                Err(_) => return Ok(respond(StatusCode::BAD_REQUEST, "BAD_BODY")),
+            };
+            let messages = match path.as_str() {
+                "/pub" => Some(vec![body]),
+                _ => split_mpub(body, binary),
+            };
+            match messages {
+                Some(messages) if messages.iter().all(|message| !message.is_empty()) && !messages.is_empty() => {
+                    let mut locked = state.lock().unwrap();
+                    for body in messages {
+                        match defer {
+                            Some(ms) => {
+                                let (state, topic, body) = (state.clone(), topic.clone(), body.clone());
+                                tokio::spawn(async move {
+                                    tokio::time::sleep(Duration::from_millis(ms)).await;
+                                    state.lock().unwrap().deliver(&topic, &body);
+                                });
+                            },
+                            None => locked.deliver(&topic, &body),
+                        }
+                        locked.received.push(ReceivedNSQ{topic: topic.clone(), body, defer});
+                    }
+                    respond(StatusCode::OK, "OK")
+                },
+                _ => respond(StatusCode::BAD_REQUEST, "BAD_MESSAGE"),
+            }
+        },
+        _ => respond(StatusCode::NOT_FOUND, "NOT_FOUND"),
+    };
+    Ok(resp)
+}
+
+
+const FRAME_TYPE_RESPONSE: i32 = 0;
+const FRAME_TYPE_MESSAGE: i32 = 2;
+/// What nsqd answers a feature-negotiating IDENTIFY with, minus the features the mock doesn't have
+const IDENTIFY_RESPONSE: &str = r#"{"max_rdy_count":2500,"version":"1.2.1","max_msg_timeout":900000,"msg_timeout":60000,"tls_v1":false,"deflate":false,"deflate_level":6,"max_deflate_level":6,"snappy":false,"sample_rate":0,"auth_required":false,"output_buffer_size":16384,"output_buffer_timeout":250}"#;
+
+/// A frame: its size, its type, then the body
+fn frame(frame_type: i32, body: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(8 + body.len());
+    frame.put_u32(4 + body.len() as u32);
+    frame.put_i32(frame_type);
+    frame.put_slice(body);
+    frame.freeze()
+}
+
+/// A message frame: timestamp, attempts, a 16 byte id, then the body
+fn message_frame(id: &str, body: &[u8]) -> Bytes {
+    let mut message = BytesMut::with_capacity(26 + body.len());
+    message.put_i64(Utc::now().timestamp_nanos_opt().unwrap_or_default());
+    message.put_u16(1);
+    message.put_slice(id.as_bytes());
+    message.put_slice(body);
+    frame(FRAME_TYPE_MESSAGE, &message)
+}
+
+async fn accept_consumers(listener: tokio::net::TcpListener, state: Arc<Mutex<MockState>>, stop: CancellationToken) {
+    loop {
+        let stream = tokio::select! {
+            _ = stop.cancelled() => return,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(_) => continue,
+            },
+        };
+        let (state, stop) = (state.clone(), stop.clone());
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = stop.cancelled() => {},
+                _ = serve_consumer(stream, state) => {},
+            }
+        });
+    }
+}
+
+/// Answer one consumer connection until it closes. Frames are written by their own task,
+/// so messages can be sent while the connection is waiting for the next command
+async fn serve_consumer(stream: TcpStream, state: Arc<Mutex<MockState>>) -> Result<(), EventfulError> {
+    let (read, mut write) = stream.into_split();
+    let (frames, mut outgoing) = mpsc::unbounded_channel::<Bytes>();
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            if write.write_all(&frame).await.is_err() {
+                break
+            }
+        }
+    });
+    let result = read_commands(BufReader::new(read), &state, frames).await;
+    writer.abort();
+    result
+}
+
+async fn read_commands(mut read: BufReader<OwnedReadHalf>, state: &Arc<Mutex<MockState>>, frames: mpsc::UnboundedSender<Bytes>) -> Result<(), EventfulError> {
+    let mut magic = [0; 4];
+    read.read_exact(&mut magic).await?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if read.read_line(&mut line).await? == 0 {
+            return Ok(())
+        }
+        let mut words = line.trim_end().split(' ');
+        match words.next() {
+            Some("IDENTIFY") => {
+                let size = read.read_u32().await?;
+                let mut body = vec![0; size as usize];
+                read.read_exact(&mut body).await?;
+                let _ = frames.send(frame(FRAME_TYPE_RESPONSE, IDENTIFY_RESPONSE.as_bytes()));
+            },
+            Some("SUB") => {
+                let topic = words.next().unwrap_or_default().to_string();
+                state.lock().unwrap().subscribers.push((topic, frames.clone()));
+                let _ = frames.send(frame(FRAME_TYPE_RESPONSE, b"OK"));
+            },
+            Some("FIN") => state.lock().unwrap().finished.push(words.next().unwrap_or_default().to_string()),
+            Some("CLS") => {
+                let _ = frames.send(frame(FRAME_TYPE_RESPONSE, b"CLOSE_WAIT"));
+            },
+            // RDY, REQ, TOUCH, and NOP need no answer
+            _ => {},
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use rand::{SeedableRng, rngs::StdRng};
+    use crate::nsq::{self, FleetNSQ};
+    use crate::publisher::{Destination, Metadata, Publisher};
+    use crate::retry::{Backoff, RetryPolicy, execute_with_retry};
+
+    fn click() -> Destination {
+        Destination::NsqTopic("click".to_string())
+    }
+
+    fn retry(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::default().max_attempts(max_attempts).backoff(Backoff::Fixed(Duration::from_millis(1)))
+    }
+
+    #[tokio::test]
+    async fn records_publishes_and_defers() {
+        let mock = MockDaemon::start().await.unwrap();
+        let daemon = mock.daemon();
+        nsq::ping(&daemon).await.unwrap();
+        daemon.publish_bytes(&click(), Bytes::from_static(br#"{"id":1}"#), &Metadata::default()).await.unwrap();
+        daemon.publish_delayed(&click(), Bytes::from_static(br#"{"id":2}"#), &Metadata::default(), Duration::from_secs(5)).await.unwrap();
+        let received = mock.received();
+        assert_eq!(received.iter().map(|r| r.defer).collect::<Vec<Option<u64>>>(), vec![None, Some(5000)]);
+        assert_eq!(mock.received_on::<serde_json::Value>("click"), vec![serde_json::json!({"id": 1}), serde_json::json!({"id": 2})]);
+    }
+
+    #[tokio::test]
+    async fn publish_is_retried_past_server_errors() {
+        let mock = MockDaemon::start().await.unwrap();
+        let daemon = mock.daemon();
+        let (dest, meta) = (click(), Metadata::default());
+        mock.respond_with_500_times(2);
+        execute_with_retry(&retry(3), || daemon.publish_bytes(&dest, Bytes::from_static(b"{}"), &meta)).await.unwrap();
+        assert_eq!(mock.bodies_on("click").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_run_out() {
+        let mock = MockDaemon::start().await.unwrap();
+        let daemon = mock.daemon();
+        let (dest, meta) = (click(), Metadata::default());
+        mock.respond_with_500_times(5);
+        match execute_with_retry(&retry(3), || daemon.publish_bytes(&dest, Bytes::from_static(b"{}"), &meta)).await {
+            Err(EventfulError::RetriesExhausted{attempts, ..}) => assert_eq!(attempts, 3),
+            other => panic!("expected RetriesExhausted, got {:?}", other),
+        }
+        assert!(mock.received().is_empty());
+        // the remaining two injected failures are still pending
+        assert!(daemon.publish_bytes(&click(), Bytes::from_static(b"{}"), &Metadata::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn retrying_through_a_fleet_fails_over_to_healthy_daemons() {
+        let mocks = [MockDaemon::start().await.unwrap(), MockDaemon::start().await.unwrap(), MockDaemon::start().await.unwrap()];
+        mocks[1].respond_with_500_times(u32::MAX);
+        let fleet = FleetNSQ::new(mocks[0].daemon(), mocks[1].daemon(), mocks[2].daemon()).with_rng(StdRng::seed_from_u64(42));
+        let (dest, meta) = (click(), Metadata::default());
+        for _ in 0..20 {
+            execute_with_retry(&retry(10), || fleet.publish_bytes(&dest, Bytes::from_static(b"{}"), &meta)).await.unwrap();
+        }
+        assert!(mocks[1].received().is_empty());
+        assert_eq!(mocks[0].bodies_on("click").len() + mocks[2].bodies_on("click").len(), 20);
+    }
+
+    #[tokio::test]
+    async fn consumers_get_what_is_published_after_they_subscribe_and_their_fins_are_recorded() {
+        let mock = MockDaemon::start().await.unwrap();
+        let daemon = mock.daemon();
+        daemon.publish_bytes(&click(), Bytes::from_static(b"before"), &Metadata::default()).await.unwrap();
+        let mut consumer = nsq::consumer_for("click", "test#ephemeral", &[&daemon]).unwrap();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        daemon.publish_delayed(&click(), Bytes::from_static(b"deferred"), &Metadata::default(), Duration::from_millis(100)).await.unwrap();
+        daemon.publish_bytes(&click(), Bytes::from_static(b"now"), &Metadata::default()).await.unwrap();
+
+        let mut bodies = Vec::new();
+        for _ in 0..2 {
+            let message = tokio::time::timeout(Duration::from_secs(5), consumer.consume_filtered()).await.unwrap().unwrap();
+            bodies.push(message.body.clone());
+            message.finish().await;
+        }
+        assert_eq!(bodies, vec![b"now".to_vec(), b"deferred".to_vec()]);
+        // the consumer flushes FINs every 250ms, so keep it connected until they arrive
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(mock.finished().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stops_serving_when_dropped() {
+        let mock = MockDaemon::start().await.unwrap();
+        let daemon = mock.daemon();
+        nsq::ping(&daemon).await.unwrap();
+        drop(mock);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(nsq::ping(&daemon).await.is_err());
+    }
+}
@@ -0,0 +1,41 @@
+//! The testing module holds helpers for testing code which produces and consumes events, without real brokers.
+//! It is enabled by the `testing` feature, which is meant for dev-dependencies. The NSQ helpers need the `nsq` feature
+//! and the SQS helpers the `sqs` feature.
+//! 
+//! - LocalstackSqs connects to localstack with test credentials and creates throwaway queues.
+//! - MockDaemon serves nsqd's HTTP publishing API on an ephemeral port and records everything published to it,
+//!   and hands it on to consumers connected to its TCP side.
+//! - EventRecorder makes fluent assertions about what was published (to itself, a MockDaemon, or a MemoryBroker).
+//! - consume_one and consume_until wait, with a deadline, for an event to arrive on an NSQ topic.
+//! - FakeSqs is an in-memory SqsApi with visibility timeouts, FIFO group ordering, and failure injection.
+//! - Golden compares an event's encoding against a checked-in golden file, to catch wire format drift.
+//! - The roundtrip module (with the `proptest` feature) checks that arbitrary events survive a codec.
+//! - NsqContainer (with the `testcontainers` feature) runs a real nsqd, and optionally nsqlookupd, in docker.
+
+#[cfg(feature = "testcontainers")]
+mod containers;
+#[cfg(feature = "nsq")]
+mod consume;
+#[cfg(feature = "sqs")]
+mod fakesqs;
+mod golden;
+#[cfg(feature = "sqs")]
+mod localstack;
+#[cfg(feature = "nsq")]
+mod mockdaemon;
+mod recorder;
+#[cfg(feature = "proptest")]
+pub mod roundtrip;
+
+#[cfg(feature = "testcontainers")]
+pub use self::containers::{NsqContainer, NsqContainerBuilder};
+#[cfg(feature = "nsq")]
+pub use self::consume::{consume_one, consume_until};
+#[cfg(feature = "sqs")]
+pub use self::fakesqs::FakeSqs;
+pub use self::golden::{Golden, assert_matches_golden};
+#[cfg(feature = "sqs")]
+pub use self::localstack::{LocalstackSqs, TempQueue};
+#[cfg(feature = "nsq")]
+pub use self::mockdaemon::{MockDaemon, ReceivedNSQ};
+pub use self::recorder::{EventRecorder, Expectation, Recorded};
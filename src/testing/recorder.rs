@@ -0,0 +1,368 @@
+use std::{collections::BTreeMap, marker::PhantomData, sync::{Arc, Mutex}};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use crate::err::EventfulError;
+use crate::memory::MemoryBroker;
+use crate::publisher::{Destination, Metadata, Publisher, Receipt};
+
+
+/// One published message, as seen by an EventRecorder
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recorded {
+    /// The topic name or queue URL
+    pub topic: String,
+    pub body: Bytes,
+}
+
+
+type Snapshot = Arc<dyn Fn() -> Vec<Recorded> + Send + Sync>;
+type Predicate<'a, T> = Box<dyn Fn(&T) -> bool + 'a>;
+
+/// An EventRecorder makes assertions about what was published. It reads from wherever its messages are kept:
+///
+/// - EventRecorder::new() is itself a Publisher which records (capture mode): hand a clone to the code under test.
+/// - MockDaemon::recorder() reads what a MockDaemon has received.
+/// - EventRecorder::from_memory() reads what was published to a MemoryBroker.
+///
+/// Reads are live, so a recorder can be created before or after the publishing happens.
+/// Failed assertions panic with a listing of everything published, with JSON bodies pretty-printed.
+///
+/// # Examples:
+/// ```
+/// # use eventful::prelude::*;
+/// # use eventful::testing::EventRecorder;
+/// # #[derive(Serialize, Deserialize)]
+/// # struct OrderPlaced { amount: u64 }
+/// # async fn place_order(publisher: &dyn Publisher, amount: u64) -> Result<(), EventfulError> {
+/// #     publisher.publish_event(&Destination::NsqTopic("orders".to_string()), &OrderPlaced{amount}).await.map(|_| ())
+/// # }
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), EventfulError> {
+/// let recorder = EventRecorder::new();
+/// place_order(&recorder, 25).await?;
+/// recorder.expect::<OrderPlaced>().on_topic("orders").times(1).matching(|e| e.amount > 0).assert();
+/// recorder.assert_nothing_on("refunds");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct EventRecorder {
+    snapshot: Snapshot,
+    captured: Option<Arc<Mutex<Vec<Recorded>>>>,
+}
+
+impl Default for EventRecorder {
+    fn default() -> Self {
+        EventRecorder::new()
+    }
+}
+
+impl EventRecorder {
+    /// A recorder which captures whatever is published to it
+    pub fn new() -> Self {
+        let captured: Arc<Mutex<Vec<Recorded>>> = Arc::new(Mutex::new(Vec::new()));
+        let read = captured.clone();
+        EventRecorder{snapshot: Arc::new(move || read.lock().unwrap().clone()), captured: Some(captured)}
+    }
+
+    /// A recorder over some other source of published messages
+    pub fn from_fn<F: Fn() -> Vec<Recorded> + Send + Sync + 'static>(snapshot: F) -> Self {
+        EventRecorder{snapshot: Arc::new(snapshot), captured: None}
+    }
+
+    /// A recorder over everything published to a MemoryBroker
+    pub fn from_memory(broker: &MemoryBroker) -> Self {
+        let broker = broker.clone();
+        EventRecorder::from_fn(move || {
+            broker.published().into_iter()
+                .map(|(dest, body)| Recorded{topic: dest.name().to_string(), body})
+                .collect()
+        })
+    }
+
+    /// Everything published so far, in order
+    pub fn recorded(&self) -> Vec<Recorded> {
+        (self.snapshot)()
+    }
+
+    /// The bodies published to a topic, in order
+    pub fn published_on(&self, topic: &str) -> Vec<Bytes> {
+        self.recorded().into_iter().filter(|recorded| recorded.topic == topic).map(|recorded| recorded.body).collect()
+    }
+
+    /// Forget what has been captured. Only a recorder made with new() can be cleared
+    pub fn clear(&self) {
+        match &self.captured {
+            Some(captured) => captured.lock().unwrap().clear(),
+            None => panic!("EventRecorder::clear() only works on a recorder made with EventRecorder::new()"),
+        }
+    }
+
+    /// Start an expectation about events of type T
+    pub fn expect<T: DeserializeOwned>(&self) -> Expectation<'_, T> {
+        Expectation{recorder: self, topic: None, count: Count::AtLeast(1), predicate: None, _event: PhantomData}
+    }
+
+    /// Panic unless nothing was published to topic
+    pub fn assert_nothing_on(&self, topic: &str) {
+        let recorded = self.recorded();
+        let count = recorded.iter().filter(|recorded| recorded.topic == topic).count();
+        if count > 0 {
+            panic!("expected nothing on topic \"{}\", found {}\n{}", topic, plural(count, "message"), describe(&recorded));
+        }
+    }
+
+    /// Panic unless nothing was published at all
+    pub fn assert_nothing_published(&self) {
+        let recorded = self.recorded();
+        if !recorded.is_empty() {
+            panic!("expected nothing to be published, found {}\n{}", plural(recorded.len(), "message"), describe(&recorded));
+        }
+    }
+}
+
+#[async_trait]
+impl Publisher for EventRecorder {
+    async fn publish_bytes(&self, dest: &Destination, body: Bytes, _meta: &Metadata) -> Result<Receipt, EventfulError> {
+        match &self.captured {
+            Some(captured) => {
+                captured.lock().unwrap().push(Recorded{topic: dest.name().to_string(), body});
+                Ok(Receipt::default())
+            },
+            None => Err(EventfulError::Config("only a recorder made with EventRecorder::new() can be published to".to_string())),
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+enum Count {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+
+/// A fluent expectation, checked by assert()
+pub struct Expectation<'a, T> {
+    recorder: &'a EventRecorder,
+    topic: Option<String>,
+    count: Count,
+    predicate: Option<Predicate<'a, T>>,
+    _event: PhantomData<T>,
+}
+
+impl<'a, T: DeserializeOwned> Expectation<'a, T> {
+    /// Only look at this topic (by default every topic is searched)
+    pub fn on_topic(mut self, topic: &str) -> Self {
+        self.topic = Some(topic.to_string());
+        self
+    }
+
+    /// Exactly n matching events (by default, at least one)
+    pub fn times(mut self, n: usize) -> Self {
+        self.count = Count::Exactly(n);
+        self
+    }
+
+    /// At least n matching events
+    pub fn at_least(mut self, n: usize) -> Self {
+        self.count = Count::AtLeast(n);
+        self
+    }
+
+    /// No matching events
+    pub fn never(self) -> Self {
+        self.times(0)
+    }
+
+    /// Only count events for which the predicate returns true
+    pub fn matching<F: Fn(&T) -> bool + 'a>(mut self, predicate: F) -> Self {
+        self.predicate = Some(Box::new(predicate));
+        self
+    }
+
+    /// Panic if the expectation isn't met, listing what was published
+    pub fn assert(self) {
+        if let Err(message) = self.check() {
+            panic!("{}", message);
+        }
+    }
+
+    /// Like assert(), but returns the failure message instead of panicking
+    pub fn check(&self) -> Result<(), String> {
+        let recorded = self.recorder.recorded();
+        let mut matched = 0;
+        let mut unmatched = 0;
+        let mut undecodable = 0;
+        for message in recorded.iter().filter(|message| self.topic.as_ref().map(|topic| &message.topic == topic).unwrap_or(true)) {
+            match serde_json::from_slice::<T>(&message.body) {
+                Ok(event) => match self.predicate.as_ref().map(|predicate| predicate(&event)).unwrap_or(true) {
+                    true => matched += 1,
+                    false => unmatched += 1,
+                },
+                Err(_) => undecodable += 1,
+            }
+        }
+        let met = match self.count {
+            Count::Exactly(n) => matched == n,
+            Count::AtLeast(n) => matched >= n,
+        };
+        if met {
+            return Ok(())
+        }
+        let wanted = match self.count {
+            Count::Exactly(n) => format!("exactly {}", n),
+            Count::AtLeast(n) => format!("at least {}", n),
+        };
+        let place = match &self.topic {
+            Some(topic) => format!("on topic \"{}\"", topic),
+            None => "on any topic".to_string(),
+        };
+        let matching = if self.predicate.is_some() { " matching the predicate" } else { "" };
+        let mut message = format!("expected {} {} {}{}, found {}", wanted, short_type_name::<T>(), place, matching, matched);
+        let mut notes = Vec::new();
+        if unmatched > 0 {
+            notes.push(format!("{} decoded as {} but did not match", plural(unmatched, "message"), short_type_name::<T>()));
+        }
+        if undecodable > 0 {
+            notes.push(format!("{} did not decode as {}", plural(undecodable, "message"), short_type_name::<T>()));
+        }
+        if !notes.is_empty() {
+            message.push_str(&format!(" ({})", notes.join("; ")));
+        }
+        message.push('\n');
+        message.push_str(&describe(&recorded));
+        Err(message)
+    }
+}
+
+
+/// "alloc::string::String" -> "String", keeping generic arguments readable
+fn short_type_name<T>() -> &'static str {
+    let name = std::any::type_name::<T>();
+    let base = name.split('<').next().unwrap_or(name);
+    match base.rfind("::") {
+        Some(i) => &name[i + 2..],
+        None => name,
+    }
+}
+
+fn plural(n: usize, noun: &str) -> String {
+    match n {
+        1 => format!("1 {}", noun),
+        n => format!("{} {}s", n, noun),
+    }
+}
+
+/// List everything published, grouped by topic, with bodies pretty-printed when they are JSON
+fn describe(recorded: &[Recorded]) -> String {
+    if recorded.is_empty() {
+        return "what was published: nothing".to_string()
+    }
+    let mut by_topic: BTreeMap<&str, Vec<&Bytes>> = BTreeMap::new();
+    for message in recorded {
+        by_topic.entry(&message.topic).or_default().push(&message.body);
+    }
+    let mut out = String::from("what was published:");
+    for (topic, bodies) in by_topic {
+        out.push_str(&format!("\n  {} ({}):", topic, plural(bodies.len(), "message")));
+        for (i, body) in bodies.iter().enumerate() {
+            let pretty = match serde_json::from_slice::<serde_json::Value>(body) {
+                Ok(value) => serde_json::to_string_pretty(&value).unwrap_or_default(),
+                Err(_) => format!("<{} bytes, not JSON> {:?}", body.len(), String::from_utf8_lossy(body)),
+            };
+            let indented = pretty.lines().collect::<Vec<_>>().join("\n       ");
+            out.push_str(&format!("\n    #{} {}", i + 1, indented));
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use crate::publisher::PublisherExt;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct OrderPlaced {
+        amount: u32,
+    }
+
+    const PUBLISHED: &str = "what was published:
+  orders (2 messages):
+    #1 {
+         \"amount\": 25
+       }
+    #2 {
+         \"amount\": 0
+       }
+  refunds (1 message):
+    #1 <8 bytes, not JSON> \"not json\"";
+
+    async fn recorder() -> EventRecorder {
+        let recorder = EventRecorder::new();
+        let orders = Destination::NsqTopic("orders".to_string());
+        recorder.publish_event(&orders, &OrderPlaced{amount: 25}).await.unwrap();
+        recorder.publish_event(&orders, &OrderPlaced{amount: 0}).await.unwrap();
+        recorder.publish_bytes(&Destination::NsqTopic("refunds".to_string()), Bytes::from("not json"), &Metadata::default()).await.unwrap();
+        recorder
+    }
+
+    #[tokio::test]
+    async fn a_met_expectation_passes() {
+        let recorder = recorder().await;
+        recorder.expect::<OrderPlaced>().on_topic("orders").times(2).assert();
+        recorder.expect::<OrderPlaced>().matching(|order| order.amount == 25).times(1).assert();
+        recorder.expect::<OrderPlaced>().on_topic("shipments").never().assert();
+        assert_eq!(recorder.published_on("refunds"), vec![Bytes::from("not json")]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_predicate_lists_everything_published() {
+        let recorder = recorder().await;
+        let failure = recorder.expect::<OrderPlaced>().on_topic("orders").times(1).matching(|order| order.amount > 100).check().unwrap_err();
+        assert_eq!(failure, format!(
+            "expected exactly 1 OrderPlaced on topic \"orders\" matching the predicate, found 0 (2 messages decoded as OrderPlaced but did not match)\n{}",
+            PUBLISHED,
+        ));
+    }
+
+    #[tokio::test]
+    async fn bodies_which_do_not_decode_are_counted() {
+        let recorder = recorder().await;
+        let failure = recorder.expect::<OrderPlaced>().at_least(3).check().unwrap_err();
+        assert_eq!(failure, format!("expected at least 3 OrderPlaced on any topic, found 2 (1 message did not decode as OrderPlaced)\n{}", PUBLISHED));
+    }
+
+    #[test]
+    fn an_empty_recorder_says_so() {
+        let failure = EventRecorder::new().expect::<Vec<OrderPlaced>>().check().unwrap_err();
+        assert_eq!(failure, "expected at least 1 Vec<eventful::testing::recorder::tests::OrderPlaced> on any topic, found 0\nwhat was published: nothing");
+        EventRecorder::new().assert_nothing_published();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "expected nothing on topic \"refunds\", found 1 message\nwhat was published:\n  orders (2 messages)")]
+    async fn assert_nothing_on_panics_with_the_listing() {
+        recorder().await.assert_nothing_on("refunds");
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "expected exactly 0 OrderPlaced on topic \"orders\", found 2")]
+    async fn never_panics_when_something_matched() {
+        recorder().await.expect::<OrderPlaced>().on_topic("orders").never().assert();
+    }
+
+    #[tokio::test]
+    async fn a_recorder_over_a_memory_broker_is_read_only() {
+        let broker = MemoryBroker::new();
+        let recorder = EventRecorder::from_memory(&broker);
+        broker.publish_event(&Destination::NsqTopic("orders".to_string()), &OrderPlaced{amount: 5}).await.unwrap();
+        recorder.expect::<OrderPlaced>().on_topic("orders").times(1).assert();
+        let published = recorder.publish_bytes(&Destination::NsqTopic("orders".to_string()), Bytes::new(), &Metadata::default()).await;
+        assert!(matches!(published, Err(EventfulError::Config(_))));
+    }
+}
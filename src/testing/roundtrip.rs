@@ -0,0 +1,176 @@
+//! The roundtrip module (enabled by the `proptest` feature) checks that arbitrary values of an event type survive
+//! being encoded and decoded by a codec. Values come from the type's proptest Arbitrary impl (i.e. `#[derive(Arbitrary)]`
+//! from proptest-derive), and a failure is shrunk to a minimal value, which is printed along with its encoded bytes.
+//!
+//! Some values can't survive a codec by design, i.e. JSON has no NaN, so f64::NAN comes back as null.
+//! Leave those out with skip_if(), and say why next to it, rather than weakening the event type.
+//!
+//! # Examples:
+//! ```
+//! # use eventful::prelude::*;
+//! # use eventful::codec::JsonCodec;
+//! # use eventful::testing::roundtrip::Roundtrip;
+//! # use proptest::prelude::*;
+//! # #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! # struct SensorReading { celsius: f64 }
+//! # impl Arbitrary for SensorReading {
+//! #     type Parameters = ();
+//! #     type Strategy = BoxedStrategy<Self>;
+//! #     fn arbitrary_with(_: ()) -> Self::Strategy {
+//! #         prop_oneof![8 => (-500i32..500).prop_map(|tenths| tenths as f64 / 10.0), 1 => Just(f64::NAN), 1 => Just(f64::INFINITY)]
+//! #             .prop_map(|celsius| SensorReading{celsius}).boxed()
+//! #     }
+//! # }
+//! # #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! # struct OrderPlaced { order_id: u64 }
+//! # #[derive(Debug, PartialEq, Serialize, Deserialize)]
+//! # struct UserClickedSomething { user_id: i32 }
+//! Roundtrip::<SensorReading>::new()
+//!     .cases(1000)
+//!     // JSON has no representation for NaN or infinity
+//!     .skip_if(|reading| !reading.celsius.is_finite())
+//!     .check(&JsonCodec);
+//!
+//! // or generate a #[test] per event and codec
+//! eventful::eventful_roundtrip_tests!(OrderPlaced: JsonCodec; UserClickedSomething: JsonCodec);
+//! ```
+
+use std::fmt::{self, Write as _};
+use proptest::{arbitrary::{Arbitrary, any}, test_runner::{Config, TestCaseError, TestError, TestRunner}};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::codec::Codec;
+
+
+type Skip<T> = Box<dyn Fn(&T) -> bool>;
+
+
+/// Check that `cases` arbitrary values of T round trip through the codec, panicking with the minimal failing value if not
+pub fn check_roundtrip<T, C>(codec: &C, cases: u32)
+where
+    T: Serialize + DeserializeOwned + PartialEq + fmt::Debug + Arbitrary,
+    C: Codec,
+{
+    Roundtrip::<T>::new().cases(cases).check(codec)
+}
+
+
+/// A round trip check, with the number of cases and any values to skip
+pub struct Roundtrip<T> {
+    cases: u32,
+    skip: Option<Skip<T>>,
+}
+
+impl<T> Default for Roundtrip<T>
+where
+    T: Serialize + DeserializeOwned + PartialEq + fmt::Debug + Arbitrary,
+{
+    fn default() -> Self {
+        Roundtrip::new()
+    }
+}
+
+impl<T> Roundtrip<T>
+where
+    T: Serialize + DeserializeOwned + PartialEq + fmt::Debug + Arbitrary,
+{
+    /// 256 cases, skipping nothing
+    pub fn new() -> Self {
+        Roundtrip{cases: 256, skip: None}
+    }
+
+    pub fn cases(mut self, cases: u32) -> Self {
+        self.cases = cases;
+        self
+    }
+
+    /// Don't check values for which the predicate returns true, i.e. known-lossy ones such as NaN in JSON.
+    /// If too many values are skipped, proptest gives up and the check fails
+    pub fn skip_if<F: Fn(&T) -> bool + 'static>(mut self, predicate: F) -> Self {
+        self.skip = Some(Box::new(predicate));
+        self
+    }
+
+    /// Panic with the minimal failing value and its encoded bytes if any value doesn't round trip
+    pub fn check<C: Codec>(&self, codec: &C) {
+        if let Err(message) = self.run(codec) {
+            panic!("{}", message);
+        }
+    }
+
+    /// Like check(), but returns the failure message instead of panicking
+    pub fn run<C: Codec>(&self, codec: &C) -> Result<(), String> {
+        let mut runner = TestRunner::new(Config::with_cases(self.cases));
+        let result = runner.run(&any::<T>(), |value| {
+            if self.skip.as_ref().map(|skip| skip(&value)).unwrap_or(false) {
+                return Err(TestCaseError::reject("skipped by skip_if"))
+            }
+            roundtrip(codec, &value).map_err(TestCaseError::fail)
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(TestError::Fail(reason, value)) => Err(format!("{} did not round trip through {}\nminimal failing value: {:#?}\n{}",
+                std::any::type_name::<T>(), codec.content_type(), value, reason)),
+            Err(TestError::Abort(reason)) => Err(format!("the round trip check of {} gave up: {} (are too many values skipped?)",
+                std::any::type_name::<T>(), reason)),
+        }
+    }
+}
+
+
+fn roundtrip<T, C>(codec: &C, value: &T) -> Result<(), String>
+where
+    T: Serialize + DeserializeOwned + PartialEq + fmt::Debug,
+    C: Codec,
+{
+    let encoded = codec.encode(value).map_err(|err| format!("encoding failed: {}", err))?;
+    match codec.decode::<T>(&encoded) {
+        Ok(decoded) if &decoded == value => Ok(()),
+        Ok(decoded) => Err(format!("decoded to a different value: {:#?}\nencoded bytes: {}", decoded, hex(&encoded))),
+        Err(err) => Err(format!("decoding failed: {}\nencoded bytes: {}", err, hex(&encoded))),
+    }
+}
+
+/// "7b 22 61 22 ... ({"a"...)"
+fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 3);
+    for b in bytes {
+        let _ = write!(out, "{:02x} ", b);
+    }
+    format!("{}({})", out, String::from_utf8_lossy(bytes))
+}
+
+
+/// Generate a #[test] checking each event type against each codec with check_roundtrip().
+/// Codecs must implement Default. Use it once per module: the tests go in a module named eventful_roundtrip
+///
+/// # Examples:
+/// ```
+/// # use eventful::prelude::*;
+/// # use eventful::codec::JsonCodec;
+/// # #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// # struct OrderPlaced { order_id: u64 }
+/// # #[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// # struct UserClickedSomething { user_id: i32 }
+/// eventful::eventful_roundtrip_tests!(OrderPlaced: JsonCodec; UserClickedSomething: JsonCodec);
+/// ```
+#[macro_export]
+macro_rules! eventful_roundtrip_tests {
+    ($($event:ident: $($codec:ident),+);+ $(;)?) => {
+        #[cfg(test)]
+        mod eventful_roundtrip {
+            $(
+                #[allow(non_snake_case)]
+                mod $event {
+                    #[allow(unused_imports)]
+                    use super::super::*;
+                    $(
+                        #[test]
+                        fn $codec() {
+                            $crate::testing::roundtrip::check_roundtrip::<$event, _>(&<$codec as ::std::default::Default>::default(), 256);
+                        }
+                    )+
+                }
+            )+
+        }
+    };
+}
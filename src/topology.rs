@@ -0,0 +1,516 @@
+//! The topology module lets a service declare the topics, channels, and queues it needs.  
+//! `apply` creates anything missing (idempotently) and `verify` only checks, returning a diff of what is missing or misconfigured.
+//! They need both the nsq and sqs features; apply_nsq/verify_nsq and apply_sqs/verify_sqs work with either one alone.
+//! 
+//! # Examples:
+//! ```no_run
+//! # #[cfg(all(feature = "nsq", feature = "sqs"))]
+//! # mod example {
+//! # use eventful::{nsq::FleetNSQ, sqs::ClientSQS, topology::Topology};
+//! # async fn demo(fleet: FleetNSQ, sqs_client: ClientSQS) {
+//! let mut topology = Topology::new();
+//! topology.nsq_topic("click").channel("analytics").dlq();
+//! topology.sqs_queue("orders.fifo").fifo().dlq_max_receive(5);
+//!
+//! let report = topology.apply(&fleet.as_refs(), Some(&sqs_client)).await;
+//! for (resource, err) in &report.failed {
+//!     eprintln!("could not create {}: {}", resource, err);
+//! }
+//! # }
+//! # }
+//! ```
+
+use std::fmt;
+#[cfg(feature = "sqs")]
+use std::collections::HashMap;
+#[cfg(feature = "sqs")]
+use aws_sdk_sqs::model::QueueAttributeName;
+use crate::err::EventfulError;
+#[cfg(feature = "nsq")]
+use crate::nsq::{self, Daemon};
+#[cfg(feature = "sqs")]
+use crate::sqs::{ClientSQS, QueueSettings, RedrivePolicy};
+
+
+/// An NSQ topic along with its channels
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NsqTopicSpec {
+    pub name: String,
+    pub channels: Vec<String>,
+    /// Also create "{name}_dlq", the topic NsqTopicSink conventionally dead-letters to
+    pub dlq: bool,
+}
+
+impl NsqTopicSpec {
+    pub fn channel(&mut self, channel: &str) -> &mut Self {
+        self.channels.push(channel.to_string());
+        self
+    }
+
+    pub fn dlq(&mut self) -> &mut Self {
+        self.dlq = true;
+        self
+    }
+
+    pub fn dlq_name(&self) -> String {
+        format!("{}_dlq", self.name)
+    }
+}
+
+
+/// An SQS queue and, optionally, its dead-letter queue
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SqsQueueSpec {
+    /// The queue name (not URL). FIFO queue names must end in ".fifo"
+    pub name: String,
+    pub fifo: bool,
+    /// When set, a dead-letter queue is created and messages move there after this many receives
+    pub dlq_max_receive: Option<u32>,
+}
+
+impl SqsQueueSpec {
+    pub fn fifo(&mut self) -> &mut Self {
+        self.fifo = true;
+        self
+    }
+
+    pub fn dlq_max_receive(&mut self, max_receive_count: u32) -> &mut Self {
+        self.dlq_max_receive = Some(max_receive_count);
+        self
+    }
+
+    /// "orders" has the dead-letter queue "orders-dlq", and "orders.fifo" has "orders-dlq.fifo"
+    pub fn dlq_name(&self) -> String {
+        match self.name.strip_suffix(".fifo") {
+            Some(base) => format!("{}-dlq.fifo", base),
+            None => format!("{}-dlq", self.name),
+        }
+    }
+}
+
+
+/// A JetStream stream, the subjects it captures, and its durable consumers
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NatsStreamSpec {
+    pub name: String,
+    pub subjects: Vec<String>,
+    pub consumers: Vec<String>,
+    /// How long JetStream remembers Nats-Msg-Id values to drop duplicate publishes (default 2 minutes)
+    pub duplicate_window: std::time::Duration,
+}
+
+impl NatsStreamSpec {
+    /// A subject (wildcards allowed, i.e. "clicks.>") captured by the stream
+    pub fn subject(&mut self, subject: &str) -> &mut Self {
+        self.subjects.push(subject.to_string());
+        self
+    }
+
+    /// A durable pull consumer on the stream
+    pub fn consumer(&mut self, durable: &str) -> &mut Self {
+        self.consumers.push(durable.to_string());
+        self
+    }
+
+    pub fn duplicate_window(&mut self, window: std::time::Duration) -> &mut Self {
+        self.duplicate_window = window;
+        self
+    }
+}
+
+
+/// One resource in a topology
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Resource {
+    NsqTopic { daemon: String, topic: String },
+    NsqChannel { daemon: String, topic: String, channel: String },
+    SqsQueue { name: String },
+    NatsStream { name: String },
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resource::NsqTopic{daemon, topic} => write!(f, "NSQ topic '{}' on {}", topic, daemon),
+            Resource::NsqChannel{daemon, topic, channel} => write!(f, "NSQ channel '{}/{}' on {}", topic, channel, daemon),
+            Resource::SqsQueue{name} => write!(f, "SQS queue '{}'", name),
+            Resource::NatsStream{name} => write!(f, "JetStream stream '{}'", name),
+        }
+    }
+}
+
+
+/// What verify() found
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TopologyDiff {
+    pub missing: Vec<Resource>,
+    /// Resources which exist but differ from the declaration, with a description of how
+    pub misconfigured: Vec<(Resource, String)>,
+    /// NSQ daemons (by pub_url) which could not be checked, with the error
+    pub unreachable: Vec<(String, String)>,
+}
+
+impl TopologyDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.misconfigured.is_empty() && self.unreachable.is_empty()
+    }
+}
+
+
+/// What apply() did
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    /// Every resource which exists now (whether or not it existed before)
+    pub applied: Vec<Resource>,
+    pub failed: Vec<(Resource, EventfulError)>,
+}
+
+impl ApplyReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+
+/// Topology declares the NSQ topics and channels, SQS queues, and JetStream streams a service needs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Topology {
+    pub nsq_topics: Vec<NsqTopicSpec>,
+    pub sqs_queues: Vec<SqsQueueSpec>,
+    pub nats_streams: Vec<NatsStreamSpec>,
+}
+
+impl Topology {
+    pub fn new() -> Self {
+        Topology::default()
+    }
+
+    /// Declare an NSQ topic, returning it so channels can be declared
+    pub fn nsq_topic(&mut self, name: &str) -> &mut NsqTopicSpec {
+        self.nsq_topics.push(NsqTopicSpec{name: name.to_string(), ..Default::default()});
+        self.nsq_topics.last_mut().unwrap()
+    }
+
+    /// Declare an SQS queue by name, returning it so it can be configured
+    pub fn sqs_queue(&mut self, name: &str) -> &mut SqsQueueSpec {
+        let fifo = name.ends_with(".fifo");
+        self.sqs_queues.push(SqsQueueSpec{name: name.to_string(), fifo, ..Default::default()});
+        self.sqs_queues.last_mut().unwrap()
+    }
+
+    /// Declare a JetStream stream, returning it so subjects and consumers can be declared.
+    /// Streams are applied with apply_nats (behind the `nats` feature)
+    pub fn nats_stream(&mut self, name: &str) -> &mut NatsStreamSpec {
+        let duplicate_window = std::time::Duration::from_secs(120);
+        self.nats_streams.push(NatsStreamSpec{name: name.to_string(), duplicate_window, ..Default::default()});
+        self.nats_streams.last_mut().unwrap()
+    }
+
+    /// Create or update every declared JetStream stream and its durable consumers
+    #[cfg(feature = "nats")]
+    pub async fn apply_nats(&self, context: &async_nats::jetstream::Context) -> ApplyReport {
+        let mut report = ApplyReport::default();
+        for spec in &self.nats_streams {
+            let resource = Resource::NatsStream{name: spec.name.clone()};
+            match crate::nats::declare_stream(context, spec).await {
+                Ok(()) => report.applied.push(resource),
+                Err(err) => report.failed.push((resource, err)),
+            }
+        }
+        report
+    }
+
+    /// Create every declared resource which is missing. Failures don't stop the rest being applied
+    #[cfg(all(feature = "nsq", feature = "sqs"))]
+    pub async fn apply(&self, daemons: &[&Daemon], sqs: Option<&ClientSQS>) -> ApplyReport {
+        let mut report = self.apply_nsq(daemons).await;
+        if let Some(client) = sqs {
+            let sqs_report = self.apply_sqs(client).await;
+            report.applied.extend(sqs_report.applied);
+            report.failed.extend(sqs_report.failed);
+        }
+        report
+    }
+
+    /// Create every declared NSQ topic and channel which is missing, on each daemon
+    #[cfg(feature = "nsq")]
+    pub async fn apply_nsq(&self, daemons: &[&Daemon]) -> ApplyReport {
+        let mut report = ApplyReport::default();
+        for daemon in daemons {
+            for spec in &self.nsq_topics {
+                apply_nsq_topic(daemon, spec, &mut report).await;
+            }
+        }
+        report
+    }
+
+    /// Create every declared SQS queue (and DLQ) which is missing
+    #[cfg(feature = "sqs")]
+    pub async fn apply_sqs(&self, client: &ClientSQS) -> ApplyReport {
+        let mut report = ApplyReport::default();
+        for spec in &self.sqs_queues {
+            apply_sqs_queue(client, spec, &mut report).await;
+        }
+        report
+    }
+
+    /// Check every declared resource without changing anything
+    #[cfg(all(feature = "nsq", feature = "sqs"))]
+    pub async fn verify(&self, daemons: &[&Daemon], sqs: Option<&ClientSQS>) -> Result<TopologyDiff, EventfulError> {
+        let mut diff = self.verify_nsq(daemons).await?;
+        if let Some(client) = sqs {
+            let sqs_diff = self.verify_sqs(client).await?;
+            diff.missing.extend(sqs_diff.missing);
+            diff.misconfigured.extend(sqs_diff.misconfigured);
+            diff.unreachable.extend(sqs_diff.unreachable);
+        }
+        Ok(diff)
+    }
+
+    /// Check every declared NSQ topic and channel on each daemon.
+    /// A daemon which can't be reached is listed in `unreachable` and the rest are still checked
+    #[cfg(feature = "nsq")]
+    pub async fn verify_nsq(&self, daemons: &[&Daemon]) -> Result<TopologyDiff, EventfulError> {
+        let mut diff = TopologyDiff::default();
+        for daemon in daemons {
+            let stats = match nsq::stats(daemon).await {
+                Ok(stats) => stats,
+                Err(err) => {
+                    diff.unreachable.push((daemon.pub_url.clone(), err.to_string()));
+                    continue
+                },
+            };
+            for spec in &self.nsq_topics {
+                let mut topics = vec![spec.name.clone()];
+                if spec.dlq {
+                    topics.push(spec.dlq_name());
+                }
+                for topic in topics {
+                    if stats.topic(&topic).is_none() {
+                        diff.missing.push(Resource::NsqTopic{daemon: daemon.pub_url.clone(), topic});
+                    }
+                }
+                for channel in &spec.channels {
+                    let exists = stats.topic(&spec.name).and_then(|topic| topic.channel(channel)).is_some();
+                    if !exists {
+                        diff.missing.push(Resource::NsqChannel{daemon: daemon.pub_url.clone(), topic: spec.name.clone(), channel: channel.clone()});
+                    }
+                }
+            }
+        }
+        Ok(diff)
+    }
+
+    /// Check every declared SQS queue (and DLQ)
+    #[cfg(feature = "sqs")]
+    pub async fn verify_sqs(&self, client: &ClientSQS) -> Result<TopologyDiff, EventfulError> {
+        let mut diff = TopologyDiff::default();
+        for spec in &self.sqs_queues {
+            verify_sqs_queue(client, spec, &mut diff).await?;
+        }
+        Ok(diff)
+    }
+}
+
+
+#[cfg(feature = "nsq")]
+async fn apply_nsq_topic(daemon: &Daemon, spec: &NsqTopicSpec, report: &mut ApplyReport) {
+    let mut topics = vec![spec.name.clone()];
+    if spec.dlq {
+        topics.push(spec.dlq_name());
+    }
+    for topic in topics {
+        let resource = Resource::NsqTopic{daemon: daemon.pub_url.clone(), topic: topic.clone()};
+        match nsq::create_topic(daemon, &topic).await {
+            Ok(()) => report.applied.push(resource),
+            Err(err) => report.failed.push((resource, err)),
+        }
+    }
+    for channel in &spec.channels {
+        let resource = Resource::NsqChannel{daemon: daemon.pub_url.clone(), topic: spec.name.clone(), channel: channel.clone()};
+        match nsq::create_channel(daemon, &spec.name, channel).await {
+            Ok(()) => report.applied.push(resource),
+            Err(err) => report.failed.push((resource, err)),
+        }
+    }
+}
+
+
+/// Find the URL of a queue by name, or None if it does not exist
+#[cfg(feature = "sqs")]
+pub(crate) async fn find_queue_url(client: &ClientSQS, name: &str) -> Result<Option<String>, EventfulError> {
+    let output = client.client().list_queues().queue_name_prefix(name).send().await?;
+    let suffix = format!("/{}", name);
+    Ok(output.queue_urls.unwrap_or_default().into_iter().find(|url| url.ends_with(&suffix)))
+}
+
+#[cfg(feature = "sqs")]
+async fn queue_attributes(client: &ClientSQS, queue_url: &str) -> Result<HashMap<QueueAttributeName, String>, EventfulError> {
+    client.get_queue_attributes(queue_url).await
+}
+
+#[cfg(feature = "sqs")]
+async fn create_queue(client: &ClientSQS, name: &str, fifo: bool, redrive: Option<RedrivePolicy>) -> Result<String, EventfulError> {
+    let settings = QueueSettings{fifo, redrive, ..Default::default()};
+    Ok(client.create_queue(name, &settings).await?.to_string())
+}
+
+#[cfg(feature = "sqs")]
+async fn apply_sqs_queue(client: &ClientSQS, spec: &SqsQueueSpec, report: &mut ApplyReport) {
+    let mut policy = None;
+    if let Some(max_receive_count) = spec.dlq_max_receive {
+        let dlq_name = spec.dlq_name();
+        let dlq_resource = Resource::SqsQueue{name: dlq_name.clone()};
+        // create_queue returns the existing queue when the attributes match, so it is safe to repeat
+        let dlq_arn = match create_queue(client, &dlq_name, spec.fifo, None).await {
+            Ok(url) => queue_attributes(client, &url).await
+                .and_then(|attrs| attrs.get(&QueueAttributeName::QueueArn).cloned()
+                    .ok_or(EventfulError::SQS(format!("queue '{}' has no QueueArn", dlq_name)))),
+            Err(err) => Err(err),
+        };
+        match dlq_arn {
+            Ok(arn) => {
+                report.applied.push(dlq_resource);
+                policy = Some(RedrivePolicy{dead_letter_target_arn: arn, max_receive_count});
+            },
+            Err(err) => {
+                // without its dead-letter queue the main queue would be created misconfigured
+                report.failed.push((dlq_resource, err));
+                report.failed.push((Resource::SqsQueue{name: spec.name.clone()}, EventfulError::Config("its dead-letter queue could not be created".to_string())));
+                return
+            },
+        }
+    }
+    let resource = Resource::SqsQueue{name: spec.name.clone()};
+    match create_queue(client, &spec.name, spec.fifo, policy).await {
+        Ok(_) => report.applied.push(resource),
+        Err(err) => report.failed.push((resource, err)),
+    }
+}
+
+#[cfg(feature = "sqs")]
+async fn verify_sqs_queue(client: &ClientSQS, spec: &SqsQueueSpec, diff: &mut TopologyDiff) -> Result<(), EventfulError> {
+    let resource = Resource::SqsQueue{name: spec.name.clone()};
+    let url = match find_queue_url(client, &spec.name).await? {
+        Some(url) => url,
+        None => {
+            diff.missing.push(resource);
+            if spec.dlq_max_receive.is_some() && find_queue_url(client, &spec.dlq_name()).await?.is_none() {
+                diff.missing.push(Resource::SqsQueue{name: spec.dlq_name()});
+            }
+            return Ok(())
+        },
+    };
+    let attrs = queue_attributes(client, &url).await?;
+    let is_fifo = attrs.get(&QueueAttributeName::FifoQueue).map(|val| val == "true").unwrap_or(false);
+    if is_fifo != spec.fifo {
+        diff.misconfigured.push((resource.clone(), format!("FifoQueue is {}, expected {}", is_fifo, spec.fifo)));
+    }
+    if let Some(max_receive_count) = spec.dlq_max_receive {
+        let dlq_name = spec.dlq_name();
+        if find_queue_url(client, &dlq_name).await?.is_none() {
+            diff.missing.push(Resource::SqsQueue{name: dlq_name.clone()});
+        }
+        let policy: Option<serde_json::Value> = attrs.get(&QueueAttributeName::RedrivePolicy)
+            .and_then(|policy| serde_json::from_str(policy).ok());
+        let actual_max = policy.as_ref()
+            .and_then(|policy| policy.get("maxReceiveCount"))
+            .and_then(|max| max.as_u64().or_else(|| max.as_str().and_then(|s| s.parse().ok())));
+        let target_ok = policy.as_ref()
+            .and_then(|policy| policy.get("deadLetterTargetArn"))
+            .and_then(|arn| arn.as_str())
+            .map(|arn| arn.ends_with(&format!(":{}", dlq_name)))
+            .unwrap_or(false);
+        if actual_max != Some(max_receive_count as u64) || !target_ok {
+            diff.misconfigured.push((resource, format!("RedrivePolicy is {:?}, expected {} with maxReceiveCount {}",
+                attrs.get(&QueueAttributeName::RedrivePolicy), dlq_name, max_receive_count)));
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dead_letter_queue_names() {
+        let mut topology = Topology::new();
+        topology.nsq_topic("click").dlq();
+        topology.sqs_queue("orders").dlq_max_receive(5);
+        topology.sqs_queue("payments.fifo").dlq_max_receive(3);
+        assert_eq!(topology.nsq_topics[0].dlq_name(), "click_dlq");
+        assert_eq!(topology.sqs_queues[0].dlq_name(), "orders-dlq");
+        assert!(!topology.sqs_queues[0].fifo);
+        assert_eq!(topology.sqs_queues[1].dlq_name(), "payments-dlq.fifo");
+        assert!(topology.sqs_queues[1].fifo);
+    }
+
+    #[cfg(feature = "nsq")]
+    mod nsq_daemons {
+        use super::*;
+        use crate::httpstub::HttpStub;
+
+        const STATS: &str = r#"{"version": "1.2.1", "topics": [
+            {"topic_name": "click", "depth": 0, "channels": [{"channel_name": "analytics", "depth": 4, "in_flight_count": 1}]}
+        ]}"#;
+
+        fn topology() -> Topology {
+            let mut topology = Topology::new();
+            topology.nsq_topic("click").channel("analytics").channel("billing").dlq();
+            topology
+        }
+
+        /// A daemon whose port nothing is listening on
+        fn unreachable_daemon() -> Daemon {
+            let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            Daemon::new("127.0.0.1", port, 0)
+        }
+
+        #[tokio::test]
+        async fn verify_reports_an_unreachable_daemon_and_checks_the_rest() {
+            let stub = HttpStub::start(vec![(200, STATS.to_string())]).await;
+            let (down, up) = (unreachable_daemon(), Daemon::new("127.0.0.1", stub.port(), 0));
+            let diff = topology().verify_nsq(&[&down, &up]).await.unwrap();
+            assert_eq!(diff.unreachable.len(), 1);
+            assert_eq!(diff.unreachable[0].0, down.pub_url);
+            assert_eq!(diff.missing, vec![
+                Resource::NsqTopic{daemon: up.pub_url.clone(), topic: "click_dlq".to_string()},
+                Resource::NsqChannel{daemon: up.pub_url.clone(), topic: "click".to_string(), channel: "billing".to_string()},
+            ]);
+            assert!(!diff.is_empty());
+            assert_eq!(stub.requests()[0].path, "/stats?format=json");
+        }
+
+        #[tokio::test]
+        async fn verify_is_empty_when_everything_exists() {
+            let stats = r#"{"data": {"topics": [
+                {"topic_name": "click", "channels": [{"channel_name": "analytics"}, {"channel_name": "billing"}]},
+                {"topic_name": "click_dlq", "channels": []}
+            ]}}"#;
+            let stub = HttpStub::start(vec![(200, stats.to_string())]).await;
+            let daemon = Daemon::new("127.0.0.1", stub.port(), 0);
+            assert_eq!(topology().verify_nsq(&[&daemon]).await.unwrap(), TopologyDiff::default());
+        }
+
+        #[tokio::test]
+        async fn apply_creates_topics_then_channels_and_reports_failures() {
+            let stub = HttpStub::start(vec![(200, String::new()), (500, "no".to_string()), (200, String::new())]).await;
+            let (down, up) = (unreachable_daemon(), Daemon::new("127.0.0.1", stub.port(), 0));
+            let report = topology().apply_nsq(&[&up, &down]).await;
+            let paths = stub.requests().into_iter().map(|request| (request.method, request.path)).collect::<Vec<_>>();
+            assert_eq!(paths, vec![
+                ("POST".to_string(), "/topic/create?topic=click".to_string()),
+                ("POST".to_string(), "/topic/create?topic=click_dlq".to_string()),
+                ("POST".to_string(), "/channel/create?topic=click&channel=analytics".to_string()),
+                ("POST".to_string(), "/channel/create?topic=click&channel=billing".to_string()),
+            ]);
+            assert_eq!(report.applied.len(), 3);
+            // the 500 for click_dlq, then all four on the daemon which is down
+            assert_eq!(report.failed.len(), 5);
+            assert_eq!(report.failed[0].0, Resource::NsqTopic{daemon: up.pub_url.clone(), topic: "click_dlq".to_string()});
+            assert!(report.failed[1..].iter().all(|(resource, _)| resource.to_string().ends_with(&down.pub_url)));
+        }
+    }
+}
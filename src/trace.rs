@@ -0,0 +1,83 @@
+//! The trace module holds the helpers behind eventful's `tracing` instrumentation (the default-on `tracing` feature).  
+//! Publishes run in `eventful.publish` spans and each delivery handled by a consumer loop runs in an `eventful.consume` span.
+//! Both carry an `outcome` field, and failures are logged with `tracing::error!` along with the error chain.
+//! Bodies are always skipped, so payloads never reach the logs; only their size (`payload_bytes`) is recorded.
+//! 
+//! Without the feature every helper compiles to nothing.
+//! 
+//! With the `otel` feature, consumed deliveries are also handled in an OpenTelemetry CONSUMER span (see the otel module)
+//! and failures mark the current OTel span as errored.
+
+use std::{error::Error, future::Future};
+use crate::err::EventfulError;
+use crate::subscriber::Delivery;
+
+
+/// Record the outcome of an operation on the current span, logging the error chain on failure
+#[allow(unused_variables)]
+pub(crate) fn record_outcome<T>(result: &Result<T, EventfulError>) {
+    #[cfg(feature = "tracing")]
+    {
+        match result {
+            Ok(_) => {
+                tracing::Span::current().record("outcome", "ok");
+            },
+            Err(err) => record_error(err),
+        }
+    }
+}
+
+/// Record a failure on the current span and log the error chain
+#[allow(unused_variables)]
+pub(crate) fn record_error(err: &EventfulError) {
+    #[cfg(feature = "otel")]
+    crate::otel::record_error(err);
+    #[cfg(feature = "tracing")]
+    {
+        tracing::Span::current().record("outcome", "error");
+        tracing::error!(error = %err, chain = %error_chain(err), "eventful operation failed");
+    }
+}
+
+/// Record the size of the body being published or consumed on the current span
+#[cfg(any(feature = "sqs", feature = "nsq"))]
+#[allow(unused_variables)]
+pub(crate) fn record_payload(bytes: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("payload_bytes", bytes);
+}
+
+/// Run the handling of one delivery inside an eventful.consume span. The span is made up front,
+/// so the future returned doesn't borrow the delivery (which isn't Sync) across its awaits
+pub(crate) fn in_consume_span<F: Future>(delivery: &Delivery, fut: F) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "otel")]
+    let fut = opentelemetry::trace::FutureExt::with_context(fut, crate::otel::consumer_context(delivery));
+    #[cfg(feature = "tracing")]
+    let fut = {
+        use tracing::Instrument;
+        let span = tracing::info_span!(
+            "eventful.consume",
+            source = %delivery.source,
+            message_id = delivery.message_id.as_deref().unwrap_or(""),
+            attempt = delivery.attempt,
+            payload_bytes = delivery.body.len(),
+            outcome = tracing::field::Empty,
+        );
+        fut.instrument(span)
+    };
+    #[cfg(not(feature = "tracing"))]
+    let _ = delivery;
+    fut
+}
+
+/// Every error in the chain, outermost first, separated by ": "
+pub fn error_chain(err: &(dyn Error + 'static)) -> String {
+    let mut chain = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        chain.push_str(": ");
+        chain.push_str(&err.to_string());
+        source = err.source();
+    }
+    chain
+}
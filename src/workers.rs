@@ -0,0 +1,273 @@
+//! The workers module runs a handler over many items concurrently while keeping items with the same key in order.
+//! A ShardedPool has a fixed number of shards, each a bounded queue with one task working through it. Items with a key
+//! always go to the same shard (by hash), so they are handled one at a time in the order they were sent; items without
+//! a key are spread round robin. send() waits while the target shard's queue is full, which pushes back on whoever is
+//! feeding the pool (i.e. a consumer loop stops receiving) instead of buffering without bound.
+//!
+//! A slow key only holds up its own shard: other shards keep going, and the slow shard's queue fills to queue_bound
+//! before send() starts waiting on it. A handler which panics loses the item it was given; its shard carries on.
+//!
+//! # Examples:
+//! ```no_run
+//! # use eventful::{err::EventfulError, workers::ShardedPool};
+//! # struct OrderPlaced { customer_id: String }
+//! # async fn reserve_stock(_order: &OrderPlaced) -> Result<(), EventfulError> { Ok(()) }
+//! # async fn demo(orders: Vec<OrderPlaced>) -> Result<(), EventfulError> {
+//! let pool = ShardedPool::new(8, 100, |order: OrderPlaced| async move {
+//!     let _ = reserve_stock(&order).await;
+//! });
+//! for order in orders {
+//!     let key = order.customer_id.clone();
+//!     pool.send(Some(&key), order).await?;
+//! }
+//! println!("{:?}", pool.depths());
+//! pool.join().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{collections::hash_map::DefaultHasher, future::Future, hash::{Hash, Hasher}, panic::AssertUnwindSafe, sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+use futures::FutureExt;
+use tokio::{sync::mpsc, task::JoinHandle};
+use crate::err::EventfulError;
+
+
+/// A pool of shards, each handling its items in order. See the module docs
+pub struct ShardedPool<T> {
+    senders: Vec<mpsc::Sender<T>>,
+    workers: Vec<JoinHandle<()>>,
+    depths: Arc<Vec<AtomicUsize>>,
+    next: AtomicUsize,
+    capacity: usize,
+}
+
+impl<T: Send + 'static> ShardedPool<T> {
+    /// Start shards tasks, each queueing up to queue_bound items. Must be called within a tokio runtime
+    pub fn new<H, Fut>(shards: usize, queue_bound: usize, handler: H) -> Self
+    where
+        H: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let shards = shards.max(1);
+        let queue_bound = queue_bound.max(1);
+        let handler = Arc::new(handler);
+        let depths: Arc<Vec<AtomicUsize>> = Arc::new((0..shards).map(|_| AtomicUsize::new(0)).collect());
+        let mut senders = Vec::with_capacity(shards);
+        let mut workers = Vec::with_capacity(shards);
+        for shard in 0..shards {
+            let (sender, mut receiver) = mpsc::channel::<T>(queue_bound);
+            let handler = handler.clone();
+            let depths = depths.clone();
+            workers.push(tokio::spawn(async move {
+                while let Some(item) = receiver.recv().await {
+                    // a panicking handler loses its own item, not the shard: the task keeps working through the queue
+                    let _ = AssertUnwindSafe(async { handler(item).await }).catch_unwind().await;
+                    depths[shard].fetch_sub(1, Ordering::Relaxed);
+                }
+            }));
+            senders.push(sender);
+        }
+        ShardedPool{senders, workers, depths, next: AtomicUsize::new(0), capacity: shards * queue_bound}
+    }
+
+    pub fn shards(&self) -> usize {
+        self.senders.len()
+    }
+
+    /// The shard a key's items go to
+    pub fn shard_for(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.senders.len() as u64) as usize
+    }
+
+    /// The shard an item with this key (or without a key, the next shard round robin) goes to
+    pub fn pick(&self, key: Option<&str>) -> usize {
+        match key {
+            Some(key) => self.shard_for(key),
+            None => self.next.fetch_add(1, Ordering::Relaxed) % self.senders.len(),
+        }
+    }
+
+    /// Queue an item on its key's shard (or the next shard round robin, without a key),
+    /// waiting while that shard's queue is full
+    pub async fn send(&self, key: Option<&str>, item: T) -> Result<(), EventfulError> {
+        self.send_to(self.pick(key), item).await
+    }
+
+    /// Queue an item on a shard chosen with pick(), waiting while its queue is full
+    pub async fn send_to(&self, shard: usize, item: T) -> Result<(), EventfulError> {
+        self.depths[shard].fetch_add(1, Ordering::Relaxed);
+        if self.senders[shard].send(item).await.is_err() {
+            self.depths[shard].fetch_sub(1, Ordering::Relaxed);
+            return Err(EventfulError::Destination(format!("shard {} of the pool has stopped", shard)))
+        }
+        Ok(())
+    }
+
+    /// Queue an item on a shard chosen with pick() without waiting.
+    /// Returns Ok(Some(item)) if the shard's queue is full, so the caller can decide how to wait
+    pub fn try_send_to(&self, shard: usize, item: T) -> Result<Option<T>, EventfulError> {
+        self.depths[shard].fetch_add(1, Ordering::Relaxed);
+        match self.senders[shard].try_send(item) {
+            Ok(()) => Ok(None),
+            Err(err) => {
+                self.depths[shard].fetch_sub(1, Ordering::Relaxed);
+                match err {
+                    mpsc::error::TrySendError::Full(item) => Ok(Some(item)),
+                    mpsc::error::TrySendError::Closed(_) => Err(EventfulError::Destination(format!("shard {} of the pool has stopped", shard))),
+                }
+            },
+        }
+    }
+
+    /// How many items the pool can hold before every shard is full
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// How many items the pool holds in total, queued or being handled
+    pub fn depth(&self) -> usize {
+        self.depths.iter().map(|depth| depth.load(Ordering::Relaxed)).sum()
+    }
+
+    /// How many items each shard holds, queued or being handled
+    pub fn depths(&self) -> Vec<usize> {
+        self.depths.iter().map(|depth| depth.load(Ordering::Relaxed)).collect()
+    }
+
+    /// Stop taking items and wait for every shard to finish what it holds
+    pub async fn join(self) {
+        drop(self.senders);
+        for worker in self.workers {
+            let _ = worker.await;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, sync::Mutex, time::Duration};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use tokio::sync::Semaphore;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn keys_stay_in_order_under_random_interleavings() {
+        let handled: Arc<Mutex<HashMap<String, Vec<u32>>>> = Arc::default();
+        let pool = Arc::new(ShardedPool::new(4, 8, {
+            let handled = handled.clone();
+            move |(key, seq): (String, u32)| {
+                let handled = handled.clone();
+                async move {
+                    // a random number of yields, so shards and producers interleave differently each item
+                    let yields = StdRng::seed_from_u64(seq as u64 * 31 + key.len() as u64).gen_range(0..4);
+                    for _ in 0..yields {
+                        tokio::task::yield_now().await;
+                    }
+                    handled.lock().unwrap().entry(key).or_default().push(seq);
+                }
+            }
+        }));
+        // each producer owns its keys, as a consumer loop owns the order it receives in
+        let producers = (0..4).map(|producer| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let mut rng = StdRng::seed_from_u64(producer);
+                let keys = (0..5).map(|k| format!("p{}-key{}", producer, k)).collect::<Vec<_>>();
+                let mut next = vec![0u32; keys.len()];
+                for _ in 0..500 {
+                    let k = rng.gen_range(0..keys.len());
+                    pool.send(Some(&keys[k]), (keys[k].clone(), next[k])).await.unwrap();
+                    next[k] += 1;
+                }
+            })
+        }).collect::<Vec<_>>();
+        for producer in producers {
+            producer.await.unwrap();
+        }
+        match Arc::try_unwrap(pool) {
+            Ok(pool) => pool.join().await,
+            Err(_) => panic!("the producers still hold the pool"),
+        }
+
+        let handled = handled.lock().unwrap();
+        assert_eq!(handled.values().map(Vec::len).sum::<usize>(), 2000);
+        for (key, seqs) in handled.iter() {
+            assert!(seqs.windows(2).all(|pair| pair[0] + 1 == pair[1]) && seqs[0] == 0, "{} was handled out of order: {:?}", key, seqs);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_slow_key_only_holds_up_its_own_shard() {
+        let gate = Arc::new(Semaphore::new(0));
+        let handled = Arc::new(AtomicUsize::new(0));
+        let pool = ShardedPool::new(2, 3, {
+            let (gate, handled) = (gate.clone(), handled.clone());
+            move |key: String| {
+                let (gate, handled) = (gate.clone(), handled.clone());
+                async move {
+                    if key == "slow" {
+                        gate.acquire().await.unwrap().forget();
+                    }
+                    handled.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+        let slow = pool.shard_for("slow");
+        let fast = (0..).map(|i| format!("fast-{}", i)).find(|key| pool.shard_for(key) != slow).unwrap();
+
+        // one being handled, then queue_bound more queued behind it
+        for _ in 0..4 {
+            pool.send(Some("slow"), "slow".to_string()).await.unwrap();
+        }
+        tokio::task::yield_now().await;
+        assert!(matches!(pool.try_send_to(slow, "slow".to_string()), Ok(Some(_))), "the slow shard should be full");
+        assert_eq!(pool.depths()[slow], 4);
+
+        // the other shard keeps going the whole time
+        let sent = tokio::time::timeout(Duration::from_secs(5), async {
+            for _ in 0..100 {
+                pool.send(Some(&fast), fast.clone()).await.unwrap();
+            }
+            while handled.load(Ordering::SeqCst) < 100 {
+                tokio::task::yield_now().await;
+            }
+        }).await;
+        assert!(sent.is_ok(), "the fast shard was held up by the slow one");
+        assert_eq!(pool.depths()[1 - slow], 0);
+
+        gate.add_permits(4);
+        pool.join().await;
+        assert_eq!(handled.load(Ordering::SeqCst), 104);
+    }
+
+    #[tokio::test]
+    async fn a_panicking_handler_does_not_stop_its_shard() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let pool = ShardedPool::new(1, 4, {
+            let handled = handled.clone();
+            move |n: u32| {
+                let handled = handled.clone();
+                async move {
+                    if n == 1 {
+                        panic!("handler failed on {}", n);
+                    }
+                    handled.lock().unwrap().push(n);
+                }
+            }
+        });
+        for n in 0..3 {
+            pool.send(Some("key"), n).await.unwrap();
+        }
+        while *handled.lock().unwrap() != vec![0, 2] {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(pool.depth(), 0);
+        // the shard is still taking items
+        pool.send(Some("key"), 3).await.unwrap();
+        pool.join().await;
+        assert_eq!(*handled.lock().unwrap(), vec![0, 2, 3]);
+    }
+}
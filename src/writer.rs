@@ -0,0 +1,77 @@
+//! A FileWriter does file writes on its own thread, so code on a consume or publish path hands the bytes over
+//! and carries on instead of blocking on the disk. Writes happen in the order they were handed over.
+
+use std::{collections::{HashMap, hash_map::Entry}, fs::{File, OpenOptions}, io::Write, path::PathBuf, sync::mpsc, thread};
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+
+type OnError = Box<dyn Fn(std::io::Error) + Send>;
+
+enum Job {
+    /// Create (or replace) a file, creating its directory if needed
+    Write { path: PathBuf, bytes: Bytes },
+    /// Append to a file, which is kept open for the next append
+    Append { path: PathBuf, bytes: Bytes },
+    Flush(oneshot::Sender<()>),
+}
+
+
+/// The thread stops once the FileWriter is dropped and the writes already handed over are done
+pub(crate) struct FileWriter {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl FileWriter {
+    /// Start the writer thread. A write which fails is passed to on_error
+    pub fn start<F: Fn(std::io::Error) + Send + 'static>(name: &str, on_error: F) -> Self {
+        let (jobs, receiver) = mpsc::channel();
+        let on_error: OnError = Box::new(on_error);
+        thread::Builder::new().name(name.to_string())
+            .spawn(move || run(receiver, on_error))
+            .expect("failed to spawn a file writer thread");
+        FileWriter{jobs}
+    }
+
+    pub fn write(&self, path: PathBuf, bytes: Bytes) {
+        let _ = self.jobs.send(Job::Write{path, bytes});
+    }
+
+    pub fn append(&self, path: PathBuf, bytes: Bytes) {
+        let _ = self.jobs.send(Job::Append{path, bytes});
+    }
+
+    /// Wait until everything handed over before now has been written (or has failed)
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.jobs.send(Job::Flush(done)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+fn run(receiver: mpsc::Receiver<Job>, on_error: OnError) {
+    let mut appending: HashMap<PathBuf, File> = HashMap::new();
+    for job in receiver {
+        let result = match job {
+            Job::Write{path, bytes} => path.parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(&path, &bytes)),
+            Job::Append{path, bytes} => {
+                let file = match appending.entry(path) {
+                    Entry::Occupied(entry) => Ok(entry.into_mut()),
+                    Entry::Vacant(entry) => OpenOptions::new().create(true).append(true).open(entry.key())
+                        .map(|file| entry.insert(file)),
+                };
+                file.and_then(|file| file.write_all(&bytes))
+            },
+            Job::Flush(done) => {
+                let _ = done.send(());
+                Ok(())
+            },
+        };
+        if let Err(err) = result {
+            on_error(err);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! #[derive(SqsEvent)], which needs the "derive" feature: `cargo test --features derive --test derive`
+#![cfg(feature = "derive")]
+
+use eventful::{err::EventfulError, sqs::{Event, SqsEvent}};
+use serde::{Deserialize, Serialize};
+
+
+#[derive(Serialize, Deserialize, SqsEvent)]
+#[eventful(queue_env = "EVENTFUL_TEST_DERIVE_QUEUE_URL", group_key = "customer_id")]
+struct OrderPlaced {
+    customer_id: u64,
+    total_cents: u64,
+}
+
+#[derive(Serialize, Deserialize, SqsEvent)]
+#[eventful(queue_url = "https://sqs.us-east-1.amazonaws.com/123456789012/refunds")]
+struct RefundIssued {
+    order_id: u64,
+}
+
+
+#[test]
+#[allow(deprecated)]
+fn queue_env_is_resolved_at_first_use() {
+    let var = "EVENTFUL_TEST_DERIVE_QUEUE_URL";
+    let url = "https://sqs.us-east-1.amazonaws.com/123456789012/orders.fifo";
+    assert_eq!(OrderPlaced::queue_env_var(), Some(var));
+
+    std::env::remove_var(var);
+    assert!(matches!(OrderPlaced::try_queue_url(), Err(EventfulError::Config(message)) if message.contains(var)));
+    assert_eq!(OrderPlaced::queue_url(), "");
+
+    std::env::set_var(var, url);
+    assert_eq!(OrderPlaced::try_queue_url().unwrap(), url);
+    assert!(OrderPlaced::queue().unwrap().is_fifo());
+    // the URL is kept once read
+    std::env::remove_var(var);
+    assert_eq!(OrderPlaced::queue_url(), url);
+
+    let order = OrderPlaced{customer_id: 42, total_cents: 1250};
+    assert_eq!(order.group_id(), Some("42".to_string()));
+}
+
+#[test]
+fn queue_url_is_a_literal() {
+    assert_eq!(RefundIssued::queue_env_var(), None);
+    assert_eq!(RefundIssued::try_queue_url().unwrap(), "https://sqs.us-east-1.amazonaws.com/123456789012/refunds");
+    assert_eq!(RefundIssued{order_id: 7}.group_id(), None);
+}
+
+#[test]
+fn attribute_misuse_fails_to_compile() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
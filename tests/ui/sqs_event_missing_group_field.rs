@@ -0,0 +1,9 @@
+use eventful::sqs::SqsEvent;
+
+#[derive(SqsEvent)]
+#[eventful(queue_env = "ORDERS_QUEUE_URL", group_key = "customer")]
+struct OrderPlaced {
+    customer_id: String,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use eventful::sqs::SqsEvent;
+
+#[derive(SqsEvent)]
+#[eventful(group_key = "customer_id")]
+struct OrderPlaced {
+    customer_id: String,
+}
+
+fn main() {}
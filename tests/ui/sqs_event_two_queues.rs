@@ -0,0 +1,9 @@
+use eventful::sqs::SqsEvent;
+
+#[derive(SqsEvent)]
+#[eventful(queue_env = "ORDERS_QUEUE_URL", queue_url = "https://sqs.us-east-1.amazonaws.com/123456789012/orders")]
+struct OrderPlaced {
+    customer_id: String,
+}
+
+fn main() {}